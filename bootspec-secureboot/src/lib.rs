@@ -0,0 +1,220 @@
+//! A single dependency for embedding this tooling: it re-exports the workspace's crates (and the
+//! `bootspec` they're built against) behind feature flags, so their versions always match.
+//!
+//! - `synthesize`: [`bootspec`], for reading bootspec documents and synthesizing them for
+//!   generations that don't have one
+//! - `generate`: [`generator`], for generating bootloader entries from generations
+//!
+//! The installer is only a binary so far, so there's nothing of it to re-export.
+
+#[cfg(feature = "synthesize")]
+pub use bootspec;
+#[cfg(feature = "generate")]
+pub use generator;
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "synthesize")]
+    use std::path::Path;
+
+    /// Writes a generation (with a `gui` specialisation) to `generation`, as far as synthesizing a
+    /// bootspec document for it goes.
+    #[cfg(feature = "synthesize")]
+    fn write_generation(generation: &Path) {
+        for path in [
+            generation.to_path_buf(),
+            generation.join("specialisation/gui"),
+        ] {
+            std::fs::create_dir_all(&path).unwrap();
+            for (name, contents) in [
+                ("kernel", ""),
+                ("initrd", ""),
+                ("init", ""),
+                ("nixos-version", "23.05"),
+                ("system", "x86_64-linux"),
+                ("kernel-params", "quiet"),
+            ] {
+                std::fs::write(path.join(name), contents).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "synthesize")]
+    #[test]
+    fn test_synthesize() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generation = tempdir.path();
+        self::write_generation(generation);
+
+        let json = crate::bootspec::v1::GenerationV1::synthesize(generation).unwrap();
+        assert_eq!(json.label, "23.05");
+    }
+
+    /// One `path: type` line for every value in `value`, e.g. `kernelParams[]: string`.
+    #[cfg(feature = "synthesize")]
+    fn shape(value: &serde_json::Value, path: &str, lines: &mut Vec<String>) {
+        use serde_json::Value;
+
+        match value {
+            Value::Object(object) if !object.is_empty() => {
+                for (key, value) in object {
+                    let path = match path {
+                        "" => key.clone(),
+                        path => format!("{}.{}", path, key),
+                    };
+                    self::shape(value, &path, lines);
+                }
+            }
+            Value::Array(array) if !array.is_empty() => {
+                for value in array {
+                    self::shape(value, &format!("{}[]", path), lines);
+                }
+            }
+            Value::Object(_) => lines.push(format!("{}: {{}}", path)),
+            Value::Array(_) => lines.push(format!("{}: []", path)),
+            Value::Null => lines.push(format!("{}: null", path)),
+            Value::Bool(_) => lines.push(format!("{}: bool", path)),
+            Value::Number(_) => lines.push(format!("{}: number", path)),
+            Value::String(_) => lines.push(format!("{}: string", path)),
+        }
+    }
+
+    /// The fields of the documents embedders read and write, with their JSON types: renaming,
+    /// adding, or removing one changes the golden file, and is a breaking change.
+    #[cfg(feature = "synthesize")]
+    #[test]
+    fn test_bootspec_shape_golden() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generation = tempdir.path();
+        self::write_generation(generation);
+
+        let json = crate::bootspec::v1::GenerationV1::synthesize(generation).unwrap();
+        let mut lines = Vec::new();
+        self::shape(&serde_json::to_value(&json).unwrap(), "", &mut lines);
+        lines.dedup();
+
+        golden::assert_golden!("api/bootspec-v1.txt", lines.join("\n") + "\n");
+    }
+
+    /// How embedders use the re-exported `bootspec`: if any of this stops compiling (e.g. a field
+    /// changes its type, or the specialisations stop being a map keyed by name), it's a breaking
+    /// change for them too.
+    #[cfg(feature = "synthesize")]
+    #[test]
+    fn test_bootspec_usage() {
+        use std::path::PathBuf;
+
+        use crate::bootspec::v1::GenerationV1;
+        use crate::bootspec::{
+            BootJson, SpecialisationName, SystemConfigurationRoot, JSON_FILENAME,
+        };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let generation = tempdir.path();
+        self::write_generation(generation);
+        let json: BootJson = GenerationV1::synthesize(generation).unwrap();
+
+        // reading fields
+        let _: &str = JSON_FILENAME;
+        let label: &String = &json.label;
+        let _: &PathBuf = &json.kernel;
+        let kernel_params: &Vec<String> = &json.kernel_params;
+        let _: &PathBuf = &json.init;
+        let _: &PathBuf = &json.initrd;
+        let _: &PathBuf = &json.toplevel.0;
+        assert_eq!(label, "23.05");
+        assert_eq!(kernel_params, &[String::from("quiet")]);
+
+        let gui = SpecialisationName(String::from("gui"));
+        let specialisation: Option<&BootJson> = json.specialisation.get(&gui);
+        assert_eq!(
+            specialisation.map(|desc| desc.label.as_str()),
+            Some("23.05")
+        );
+        for (name, desc) in &json.specialisation {
+            let _: (&String, &BootJson) = (&name.0, desc);
+        }
+
+        // constructing one from another
+        let relabeled = BootJson {
+            label: String::from("relabeled"),
+            toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/relabeled")),
+            ..json
+        };
+
+        // serde round-trip
+        let serialized = serde_json::to_string(&relabeled).unwrap();
+        let deserialized: BootJson = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.label, "relabeled");
+        assert_eq!(
+            serde_json::to_value(&deserialized).unwrap(),
+            serde_json::to_value(&relabeled).unwrap()
+        );
+    }
+
+    /// The `path: signature` lines of the items in `$check`s, which have to coerce to the signature
+    /// they're listed with.
+    #[cfg(all(feature = "generate", feature = "synthesize"))]
+    macro_rules! api {
+        ($($line:literal => $check:expr;)*) => {{
+            $(let _ = $check;)*
+            [$($line),*]
+        }};
+    }
+
+    /// The public API of the re-exported `generator` that embedders build against. Changing an
+    /// item's signature stops this from compiling, and adding, removing, or renaming one changes
+    /// the golden file, so either shows up in review as the breaking change it is.
+    #[cfg(all(feature = "generate", feature = "synthesize"))]
+    #[test]
+    fn test_api_golden() {
+        use std::path::{Path, PathBuf};
+
+        use crate::bootspec::BootJson;
+        use crate::generator::bootable::Bootable;
+        use crate::generator::{Generation, Result};
+
+        let lines = api! {
+            "generator::Generation { index: usize, profile: Option<String>, bootspec: BootJson }" =>
+                |index: usize, profile: Option<String>, bootspec: BootJson| Generation {
+                    index,
+                    profile,
+                    bootspec,
+                };
+            "generator::get_json: fn(PathBuf) -> Result<BootJson>" =>
+                crate::generator::get_json as fn(PathBuf) -> Result<BootJson>;
+            "generator::resolve_json_path: fn(&Path) -> Result<Option<PathBuf>>" =>
+                crate::generator::resolve_json_path as fn(&Path) -> Result<Option<PathBuf>>;
+            "generator::strip_init_params: fn(&mut BootJson)" =>
+                crate::generator::strip_init_params as fn(&mut BootJson);
+            "generator::parse_generation: fn(&str) -> Result<(usize, Option<String>)>" =>
+                crate::generator::parse_generation as fn(&str) -> Result<(usize, Option<String>)>;
+            "generator::grub::ROOT: &str" => crate::generator::grub::ROOT as &str;
+            "generator::grub::FRAGMENT: &str" => crate::generator::grub::FRAGMENT as &str;
+            "generator::grub::fragment: fn(&[Bootable], Option<&[String]>) -> Result<String>" =>
+                crate::generator::grub::fragment
+                    as fn(&[Bootable], Option<&[String]>) -> Result<String>;
+            "generator::manifest::MANIFEST: &str" => crate::generator::manifest::MANIFEST as &str;
+            "generator::manifest::VERSION: &str" => crate::generator::manifest::VERSION as &str;
+            "generator::manifest::sha256_file: fn(&Path) -> Result<String>" =>
+                crate::generator::manifest::sha256_file as fn(&Path) -> Result<String>;
+            "generator::systemd_boot::ROOT: &str" => crate::generator::systemd_boot::ROOT as &str;
+            "generator::systemd_boot::MACHINE_ID_PLACEHOLDER: &str" =>
+                crate::generator::systemd_boot::MACHINE_ID_PLACEHOLDER as &str;
+            "generator::systemd_boot::parse_machine_id: fn(&str) -> Result<String, String>" =>
+                crate::generator::systemd_boot::parse_machine_id
+                    as fn(&str) -> Result<String, String>;
+        };
+
+        golden::assert_golden!("api/generator.txt", lines.join("\n") + "\n");
+    }
+
+    #[cfg(feature = "generate")]
+    #[test]
+    fn test_generate() {
+        assert_eq!(
+            crate::generator::parse_generation("/nix/var/nix/profiles/system-42-link").unwrap(),
+            (42, None)
+        );
+    }
+}
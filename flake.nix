@@ -31,6 +31,8 @@
             nixpkgs-fmt
             rustfmt
           ];
+
+          SBATTACH_PATH = "${self.packages.${system}.sbattach}/bin/sbattach";
         });
 
       packages = forAllSystems
@@ -0,0 +1,72 @@
+//! How long rendering the entries for a large number of generations takes.
+//!
+//! Run with `cargo bench -p generator`, and compare against a run on the parent commit to see
+//! what a change does to it.
+
+use std::fs;
+use std::path::PathBuf;
+
+use bootspec::{SpecialisationName, SystemConfigurationRoot};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use generator::bootable::{Bootable, BootableToplevel, EfiProgram};
+use generator::systemd_boot;
+use generator::target::Target;
+
+const GENERATIONS: usize = 1000;
+
+fn render_entries(c: &mut Criterion) {
+    // versions are dated by the toplevel's ctime, so it has to exist
+    let tempdir = tempfile::tempdir().unwrap();
+    let toplevel = tempdir
+        .path()
+        .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+    fs::create_dir(&toplevel).unwrap();
+
+    let bootables = (0..GENERATIONS)
+        .map(|generation| {
+            let toplevel = BootableToplevel {
+                label: String::from("23.05"),
+                kernel: PathBuf::from(
+                    "/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1/bzImage",
+                ),
+                kernel_params: vec![String::from("loglevel=4")],
+                init: PathBuf::from(
+                    "/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system/init",
+                ),
+                initrd: PathBuf::from(
+                    "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1/initrd",
+                ),
+                toplevel: SystemConfigurationRoot(toplevel.clone()),
+                specialisation_name: if generation % 4 == 0 {
+                    Some(SpecialisationName(String::from("gui")))
+                } else {
+                    None
+                },
+                generation_index: generation,
+                ..Default::default()
+            };
+
+            if generation % 2 == 0 {
+                Bootable::Efi(EfiProgram::new(toplevel))
+            } else {
+                Bootable::Linux(toplevel)
+            }
+        })
+        .collect::<Vec<_>>();
+    let target = Target {
+        extra_kernel_params: vec![String::from("console=ttyS0")],
+        ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+    };
+
+    c.bench_function("render 1000 entries", |b| {
+        b.iter(|| {
+            for planned in systemd_boot::plan(black_box(&bootables), "EFI/nixos").unwrap() {
+                black_box(systemd_boot::entry(&planned, &target, &[]).unwrap());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, render_entries);
+criterion_main!(benches);
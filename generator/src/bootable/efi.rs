@@ -1,12 +1,31 @@
+use std::ffi::OsString;
+use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use tempfile::NamedTempFile;
 
-use super::BootableToplevel;
+use super::ukify::{self, Ukify};
+use super::{uki, BootableToplevel};
+use crate::cmdline;
+use crate::command;
+use crate::context::Context;
+use crate::target::Target;
+use crate::util;
 use crate::Result;
 
+/// What builds unified EFI files out of the systemd-boot EFI stub (see `--uki-builder`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UkiBuilder {
+    /// `objcopy`, at this path
+    Objcopy(PathBuf),
+    /// The generator itself, which appends the sections to the stub the way ukify lays them out
+    Native,
+    /// systemd's `ukify` (see [`ukify::choose`])
+    Ukify(Ukify),
+}
+
 pub struct EfiProgram {
     pub source: BootableToplevel,
 }
@@ -16,44 +35,108 @@ impl EfiProgram {
         Self { source }
     }
 
-    pub fn write_unified_efi(&self, objcopy: &Path, outpath: &Path, stub: &Path) -> Result<()> {
+    /// The kernel command line embedded in the unified EFI file for `target`, with its
+    /// `extra_kernel_params` appended to the generation's own (see [`Target::kernel_params`]).
+    pub fn cmdline(&self, target: &Target) -> Result<String> {
+        let init = format!("init={}", util::utf8(&self.source.init)?);
+        let (params, _) = target.kernel_params(
+            std::iter::once(init.as_str())
+                .chain(self.source.kernel_params.iter().map(String::as_str)),
+        );
+
+        cmdline::render(params)
+    }
+
+    pub fn write_unified_efi(
+        &self,
+        builder: &UkiBuilder,
+        outpath: &Path,
+        stub: &Path,
+        target: &Target,
+    ) -> Result<()> {
         let generation_path = &self.source.toplevel.0;
         let mut kernel_params = NamedTempFile::new()?;
 
-        write!(
-            kernel_params,
-            "init={} {}",
-            self.source.init.display(),
-            self.source.kernel_params.join(" ")
-        )?;
+        write!(kernel_params, "{}", self.cmdline(target)?)
+            .with_path_context(kernel_params.path())?;
+
+        let initrd = generation_path.join("initrd");
+        let compressed_initrd = NamedTempFile::new()?;
+        let compression = self.source.initrd_compression;
+        let initrd = if compression.is_none() {
+            initrd
+        } else {
+            compression.compress_file(&initrd, compressed_initrd.path())?;
+            compressed_initrd.path().to_path_buf()
+        };
+
+        let objcopy = match builder {
+            UkiBuilder::Objcopy(objcopy) => objcopy,
+            UkiBuilder::Ukify(ukify) => {
+                let cmdline = self.cmdline(target)?;
+                let inputs = ukify::Inputs {
+                    stub,
+                    linux: &generation_path.join("kernel"),
+                    initrd: &initrd,
+                    cmdline: &cmdline,
+                    cmdline_file: kernel_params.path(),
+                    os_release: &generation_path.join("etc/os-release"),
+                    output: outpath,
+                };
+
+                return ukify::build(ukify, &inputs);
+            }
+            UkiBuilder::Native => {
+                let osrel = generation_path.join("etc/os-release");
+                let kernel = generation_path.join("kernel");
+                let read = |path: &Path| fs::read(path).with_path_context(path);
+                let sections = [
+                    (".osrel", read(&osrel)?),
+                    (".cmdline", self.cmdline(target)?.into_bytes()),
+                    (".linux", read(&kernel)?),
+                    (".initrd", read(&initrd)?),
+                ];
+
+                let image = uki::build(&read(stub)?, &sections)
+                    .map_err(|e| format!("stub '{}': {}", stub.display(), e))?;
+                fs::write(outpath, image).with_path_context(outpath)?;
+
+                return Ok(());
+            }
+        };
 
+        // The paths are passed as they are, so that one that isn't valid UTF-8 isn't mangled.
+        let section = |section: &str, path: &Path| {
+            let mut arg = OsString::from(format!("{}=", section));
+            arg.push(path);
+            arg
+        };
         // Offsets taken from one of systemd's EFI tests:
         // https://github.com/systemd/systemd/blob/01d0123f044d6c090b6ac2f6d304de2bdb19ae3b/test/test-efi-create-disk.sh#L32-L38
-        let status = Command::new(objcopy)
-            .args(&[
-                "--add-section",
-                &format!(".osrel={}/etc/os-release", generation_path.display()),
-                "--change-section-vma",
-                ".osrel=0x20000",
-                "--add-section",
-                &format!(".cmdline={}", kernel_params.path().display()),
-                "--change-section-vma",
-                ".cmdline=0x30000",
-                "--add-section",
-                &format!(".linux={}/kernel", generation_path.display()),
-                "--change-section-vma",
-                ".linux=0x2000000",
-                "--add-section",
-                &format!(".initrd={}/initrd", generation_path.display()),
-                "--change-section-vma",
-                ".initrd=0x3000000",
-                &stub.display().to_string(),
-                &outpath.display().to_string(),
-            ])
-            .status()?;
+        let args = &[
+            OsString::from("--add-section"),
+            section(".osrel", &generation_path.join("etc/os-release")),
+            OsString::from("--change-section-vma"),
+            OsString::from(".osrel=0x20000"),
+            OsString::from("--add-section"),
+            section(".cmdline", kernel_params.path()),
+            OsString::from("--change-section-vma"),
+            OsString::from(".cmdline=0x30000"),
+            OsString::from("--add-section"),
+            section(".linux", &generation_path.join("kernel")),
+            OsString::from("--change-section-vma"),
+            OsString::from(".linux=0x2000000"),
+            OsString::from("--add-section"),
+            section(".initrd", &initrd),
+            OsString::from("--change-section-vma"),
+            OsString::from(".initrd=0x3000000"),
+            OsString::from(stub),
+            OsString::from(outpath),
+        ];
+        let status = command::status(Command::new(objcopy).args(args))?;
 
         if !status.success() {
-            return Err("failed to write unified efi".into());
+            return Err(format!("failed to write unified efi '{}'", outpath.display()).into());
         }
 
         Ok(())
@@ -1,13 +1,16 @@
-use std::io::{self, Write};
-
 use bootspec::SpecialisationName;
+use glob::Pattern;
+use log::info;
 
+use crate::recompress::Compression;
 use crate::{Generation, Result};
 
 mod efi;
 mod toplevel;
+mod uki;
+pub mod ukify;
 
-pub use efi::EfiProgram;
+pub use efi::{EfiProgram, UkiBuilder};
 pub use toplevel::BootableToplevel;
 
 pub enum Bootable {
@@ -15,6 +18,51 @@ pub enum Bootable {
     Efi(EfiProgram),
 }
 
+impl Bootable {
+    /// The toplevel that the bootable boots.
+    pub fn toplevel(&self) -> &BootableToplevel {
+        match self {
+            Bootable::Linux(toplevel) => toplevel,
+            Bootable::Efi(efi) => &efi.source,
+        }
+    }
+}
+
+/// Which specialisations get entries: with `--include-specialisation`, only the ones it matches;
+/// and none that `--exclude-specialisation` matches. Both take glob patterns (e.g. `test-*`), which
+/// are matched against the name of each specialisation, nested ones included.
+#[derive(Debug, Clone, Default)]
+pub struct SpecialisationFilter {
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+}
+
+impl SpecialisationFilter {
+    pub fn new(exclude: &[String], include: &[String]) -> Result<Self> {
+        let patterns = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Pattern::new(pattern).map_err(|e| {
+                        format!("invalid specialisation pattern '{}': {}", pattern, e).into()
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            exclude: patterns(exclude)?,
+            include: patterns(include)?,
+        })
+    }
+
+    /// Whether the specialisation called `name` gets entries.
+    pub fn includes(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+            && !self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
 /// `flatten` takes in a list of [`Generation`]s and returns a list of [`BootableToplevel`]s by:
 ///
 /// 1. transforming each [`Generation`] into a [`BootableToplevel`]; and
@@ -24,39 +72,84 @@ pub enum Bootable {
 /// This makes it easy to create boot entries for all possible [`BootableToplevel`]s (both the
 /// "system profile" as well as its many possible specialisations), while also ensuring we encounter
 /// potential infinite recursion as early as possible.
+///
+/// A specialisation is labeled with the label from its own bootspec document, or, if that's empty,
+/// with its parent's label and its name (e.g. `23.05 (gui)`).
 pub fn flatten(inputs: Vec<Generation>) -> Result<Vec<BootableToplevel>> {
-    self::flatten_impl(inputs, None)
+    let (toplevels, _) = self::flatten_filtered(inputs, &SpecialisationFilter::default())?;
+
+    Ok(toplevels)
 }
 
+/// Like [`flatten`], but leaves out the specialisations that `filter` doesn't include (along with
+/// the specialisations nested in them), which are returned separately.
+pub fn flatten_filtered(
+    inputs: Vec<Generation>,
+    filter: &SpecialisationFilter,
+) -> Result<(Vec<BootableToplevel>, Vec<BootableToplevel>)> {
+    let mut excluded = Vec::new();
+    let toplevels = self::flatten_impl(inputs, None, filter, &mut excluded)?;
+
+    Ok((toplevels, excluded))
+}
+
+/// `parent` is the name of the specialisation that `inputs` are, and the label of their parent.
 fn flatten_impl(
     inputs: Vec<Generation>,
-    specialisation_name: Option<SpecialisationName>,
+    parent: Option<(SpecialisationName, &str)>,
+    filter: &SpecialisationFilter,
+    excluded: &mut Vec<BootableToplevel>,
 ) -> Result<Vec<BootableToplevel>> {
     let mut toplevels = Vec::new();
 
-    for input in inputs {
+    for mut input in inputs {
+        // Documents shouldn't contain `init=` in their kernel params, but strip it just in case:
+        // we add our own from `init`.
+        crate::strip_init_params(&mut input.bootspec);
+
         let toplevel = input.bootspec.toplevel.clone();
+        let (specialisation_name, label) = match &parent {
+            Some((name, parent_label)) if input.bootspec.label.is_empty() => {
+                (Some(name.clone()), format!("{} ({})", parent_label, name.0))
+            }
+            Some((name, _)) => (Some(name.clone()), input.bootspec.label),
+            None => (None, input.bootspec.label),
+        };
 
-        toplevels.push(BootableToplevel {
-            label: input.bootspec.label,
+        let bootable = BootableToplevel {
+            label: label.clone(),
             kernel: input.bootspec.kernel,
             kernel_params: input.bootspec.kernel_params,
             init: input.bootspec.init,
             initrd: input.bootspec.initrd,
             toplevel,
-            specialisation_name: specialisation_name.clone(),
+            specialisation_name,
             generation_index: input.index,
             profile_name: input.profile.clone(),
-        });
+            variant_name: None,
+            initrd_compression: Compression::None,
+        };
+
+        if let Some(name) = &bootable.specialisation_name {
+            if !filter.includes(&name.0) {
+                info!(
+                    "excluding specialisation '{name}' of generation {generation}",
+                    name = name.0,
+                    generation = input.index
+                );
+                excluded.push(bootable);
+                continue;
+            }
+        }
+        toplevels.push(bootable);
 
         for (name, desc) in input.bootspec.specialisation {
-            writeln!(
-                io::stderr(),
-                "Flattening specialisation '{name}' of toplevel {toplevel}: {path}",
+            info!(
+                "flattening specialisation '{name}' of toplevel {toplevel}: {path}",
                 toplevel = input.bootspec.toplevel.0.display(),
                 name = name.0,
                 path = desc.toplevel.0.display()
-            )?;
+            );
 
             let gen = Generation {
                 index: input.index,
@@ -64,9 +157,213 @@ fn flatten_impl(
                 bootspec: desc,
             };
 
-            toplevels.extend(self::flatten_impl(vec![gen], Some(name))?);
+            toplevels.extend(self::flatten_impl(
+                vec![gen],
+                Some((name, &label)),
+                filter,
+                excluded,
+            )?);
         }
     }
 
     Ok(toplevels)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Mutex;
+
+    use log::{Log, Metadata, Record};
+
+    lazy_static::lazy_static! {
+        static ref RECORDS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+    }
+
+    /// Keeps the target and message of every record, so tests can check what was logged.
+    struct CapturingLogger;
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS
+                .lock()
+                .unwrap()
+                .push((record.target().to_string(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger;
+
+    #[test]
+    fn test_flatten_logs_specialisations() {
+        // Only the first test to get here can set the logger.
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = |path: &std::path::Path| {
+            fs::create_dir_all(path).unwrap();
+            fs::write(path.join("kernel"), "").unwrap();
+            fs::write(path.join("initrd"), "").unwrap();
+            fs::write(path.join("init"), "").unwrap();
+            fs::write(path.join("nixos-version"), "23.05").unwrap();
+            fs::write(path.join("system"), "x86_64-linux").unwrap();
+            fs::write(path.join("kernel-params"), "quiet").unwrap();
+        };
+        let generation = tempdir.path().join("system-1-link");
+        toplevel(&generation);
+        toplevel(&generation.join("specialisation/gui"));
+
+        let toplevels = flatten(vec![Generation {
+            index: 1,
+            profile: None,
+            bootspec: crate::get_json(generation).unwrap(),
+        }])
+        .unwrap();
+        assert_eq!(toplevels.len(), 2);
+
+        let records = RECORDS.lock().unwrap();
+        assert!(records.iter().any(|(target, message)| {
+            target == "generator::bootable"
+                && message.starts_with("flattening specialisation 'gui'")
+        }));
+    }
+
+    #[test]
+    fn test_flatten_filtered() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generation = tempdir.path().join("system-1-link");
+        for path in [
+            generation.clone(),
+            generation.join("specialisation/gui"),
+            generation.join("specialisation/gui/specialisation/debug"),
+            generation.join("specialisation/test"),
+        ] {
+            fs::create_dir_all(&path).unwrap();
+            for (name, contents) in [
+                ("kernel", ""),
+                ("initrd", ""),
+                ("init", ""),
+                ("nixos-version", "23.05"),
+                ("system", "x86_64-linux"),
+                ("kernel-params", "quiet"),
+            ] {
+                fs::write(path.join(name), contents).unwrap();
+            }
+        }
+        let names = |toplevels: &[BootableToplevel]| {
+            let mut names = toplevels
+                .iter()
+                .map(|t| t.specialisation_name.as_ref().map(|name| name.0.as_str()))
+                .map(|name| name.unwrap_or("").to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        // (excluded, included, the toplevels that are kept, the ones that are excluded)
+        for (exclude, include, kept, left_out) in [
+            (vec![], vec![], vec!["", "debug", "gui", "test"], vec![]),
+            (vec!["test"], vec![], vec!["", "debug", "gui"], vec!["test"]),
+            (vec!["t*"], vec![], vec!["", "debug", "gui"], vec!["test"]),
+            // what's nested in an excluded specialisation goes with it
+            (vec!["gui"], vec![], vec!["", "test"], vec!["gui"]),
+            (vec![], vec!["gui"], vec!["", "gui"], vec!["debug", "test"]),
+            (
+                vec!["debug"],
+                vec!["*"],
+                vec!["", "gui", "test"],
+                vec!["debug"],
+            ),
+            // the generation itself is always kept
+            (vec!["*"], vec![], vec![""], vec!["gui", "test"]),
+        ] {
+            let to_strings =
+                |names: Vec<&str>| names.into_iter().map(String::from).collect::<Vec<_>>();
+            let filter =
+                SpecialisationFilter::new(&to_strings(exclude), &to_strings(include)).unwrap();
+
+            let (toplevels, excluded) = flatten_filtered(
+                vec![Generation {
+                    index: 1,
+                    profile: None,
+                    bootspec: crate::get_json(generation.clone()).unwrap(),
+                }],
+                &filter,
+            )
+            .unwrap();
+            assert_eq!(names(&toplevels), kept);
+            assert_eq!(names(&excluded), left_out);
+        }
+
+        assert!(SpecialisationFilter::new(&[String::from("[")], &[]).is_err());
+    }
+
+    #[test]
+    fn test_flatten_specialisation_labels() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generation = tempdir.path().join("system-1-link");
+        for path in [generation.clone(), generation.join("specialisation/gui")] {
+            fs::create_dir_all(&path).unwrap();
+            for (name, contents) in [
+                ("kernel", ""),
+                ("initrd", ""),
+                ("init", ""),
+                ("nixos-version", "23.05"),
+                ("system", "x86_64-linux"),
+                ("kernel-params", "quiet"),
+            ] {
+                fs::write(path.join(name), contents).unwrap();
+            }
+        }
+
+        // (the specialisation's own label, the label it gets)
+        for (label, expected) in [("Y", "Y"), ("", "X (gui)")] {
+            let mut bootspec = crate::get_json(generation.clone()).unwrap();
+            bootspec.label = String::from("X");
+            for desc in bootspec.specialisation.values_mut() {
+                desc.label = String::from(label);
+            }
+
+            let toplevels = flatten(vec![Generation {
+                index: 1,
+                profile: None,
+                bootspec,
+            }])
+            .unwrap();
+            assert_eq!(toplevels.len(), 2);
+
+            let (parent, specialisation) = (&toplevels[0], &toplevels[1]);
+            assert_eq!(parent.label, "X");
+            assert!(parent.specialisation_name.is_none());
+            assert_eq!(parent.title(), "NixOS");
+            assert!(parent
+                .version()
+                .unwrap()
+                .starts_with("Generation 1 X, Built on "));
+
+            assert_eq!(specialisation.label, expected);
+            assert_eq!(
+                specialisation
+                    .specialisation_name
+                    .as_ref()
+                    .map(|name| name.0.as_str()),
+                Some("gui")
+            );
+            assert_eq!(specialisation.title(), "NixOS – spec: gui");
+            let version = specialisation.version().unwrap();
+            assert!(
+                version.starts_with(&format!("Generation 1~gui {}, Built on ", expected)),
+                "{}",
+                version
+            );
+        }
+    }
+}
@@ -1,13 +1,14 @@
 use std::fs;
-use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 
 use bootspec::{SpecialisationName, SystemConfigurationRoot};
 use chrono::{Local, TimeZone};
 
+use crate::context::Context;
+use crate::recompress::Compression;
 use crate::Result;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct BootableToplevel {
     /// NixOS version
     pub label: String,
@@ -27,22 +28,56 @@ pub struct BootableToplevel {
     pub generation_index: usize,
     /// Generation profile
     pub profile_name: Option<String>,
+    /// Entry variant name (if an extra entry for the generation, see `--extra-entry-variant`)
+    pub variant_name: Option<String>,
+    /// How the initrd is compressed on its way to the ESP (see `--recompress-initrd`)
+    pub initrd_compression: Compression,
 }
 
 impl BootableToplevel {
+    /// The entry's title, which tells specialisations apart from their parent at a glance (e.g.
+    /// `NixOS – spec: gui`).
     pub fn title(&self) -> String {
+        self.elided_title(usize::MAX)
+    }
+
+    /// The entry's [`title`](Self::title), with the middle of the specialisation's (or variant's)
+    /// name elided to fit `max_len` characters (see `--max-label-len`).
+    pub fn elided_title(&self, max_len: usize) -> String {
+        let (prefix, name, suffix) = if let Some(ref specialisation) = self.specialisation_name {
+            ("NixOS – spec: ", specialisation.0.as_str(), "")
+        } else if let Some(ref variant) = self.variant_name {
+            ("NixOS (", variant.as_str(), ")")
+        } else {
+            return String::from("NixOS");
+        };
+
         format!(
-            "NixOS{}",
-            if let Some(ref specialisation) = self.specialisation_name {
-                format!(" ({})", specialisation.0)
-            } else {
-                String::new()
-            }
+            "{}{}{}",
+            prefix,
+            self::elide(name, self::budget(max_len, prefix, suffix)),
+            suffix
         )
     }
 
+    /// The entry's version, which systemd-boot orders the entries with the same sort-key by (newest
+    /// first).
+    ///
+    /// A specialisation's generation number is suffixed with `~<name>`, which sorts right below the
+    /// bare generation number (see `strverscmp_improved()`): in a group shared with its parent (see
+    /// `--group-specialisations`), it's listed right after the parent, and before the previous
+    /// generation, with the specialisations of a generation in reverse order of their names.
     pub fn version(&self) -> Result<String> {
-        let ctime = fs::metadata(&self.toplevel.0)?.ctime();
+        self.elided_version(usize::MAX)
+    }
+
+    /// The entry's [`version`](Self::version), with the middle of the label elided to fit `max_len`
+    /// characters (see `--max-label-len`): systemd-boot cuts off what doesn't fit on the screen, so
+    /// long labels (e.g. with a flake revision) would otherwise all look the same. The generation
+    /// number, the variant, and the date are always kept whole, as is the end of the label.
+    pub fn elided_version(&self, max_len: usize) -> Result<String> {
+        let metadata = fs::metadata(&self.toplevel.0).with_path_context(&self.toplevel.0)?;
+        let ctime = self::ctime(&metadata);
         let date = Local
             .timestamp_opt(ctime, 0)
             .earliest()
@@ -51,23 +86,143 @@ impl BootableToplevel {
                 std::io::ErrorKind::Other,
                 "could not convert toplevel ctime to timestamp",
             ))?;
-        let description = format!(
-            "{label}{specialisation}, Built on {date}",
-            specialisation = if let Some(ref specialisation) = self.specialisation_name {
-                format!(", Specialisation {}", specialisation.0)
+        let generation = match &self.specialisation_name {
+            Some(specialisation) => format!("{}~{}", self.generation_index, specialisation.0),
+            None => self.generation_index.to_string(),
+        };
+
+        let prefix = format!("Generation {generation} ", generation = generation);
+        let suffix = format!(
+            "{variant}, Built on {date}",
+            variant = if let Some(ref variant) = self.variant_name {
+                format!(", Variant {}", variant)
             } else {
                 format!("")
             },
-            label = self.label,
             date = date,
         );
+        let label = self::elide(&self.label, self::budget(max_len, &prefix, &suffix));
+
+        Ok(format!("{}{}{}", prefix, label, suffix))
+    }
+}
+
+/// How many characters are left of `max_len` for what goes between `prefix` and `suffix`, which
+/// is at least one (for the `…`), so that they're kept whole however short `max_len` is.
+fn budget(max_len: usize, prefix: &str, suffix: &str) -> usize {
+    max_len
+        .saturating_sub(prefix.chars().count() + suffix.chars().count())
+        .max(1)
+}
+
+/// `s`, with its middle elided (as `…`) if it's longer than `max_len` characters, keeping as much of
+/// its start and end as fits; the end gets the odd character, since that's where what tells
+/// similar labels apart (e.g. a revision) tends to be.
+pub fn elide(s: &str, max_len: usize) -> String {
+    let len = s.chars().count();
+    if len <= max_len {
+        return s.to_string();
+    }
+    if max_len == 0 {
+        return String::new();
+    }
+
+    let head = (max_len - 1) / 2;
+    let tail = max_len - 1 - head;
+    let mut elided = s.chars().take(head).collect::<String>();
+    elided.push('…');
+    elided.extend(s.chars().skip(len - tail));
+
+    elided
+}
+
+/// When the toplevel was built: its ctime, since the store resets mtimes (or, where there's no
+/// ctime, e.g. on a Windows build host, its mtime).
+fn ctime(metadata: &fs::Metadata) -> i64 {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::MetadataExt::ctime(metadata)
+    }
+    #[cfg(not(unix))]
+    {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |mtime| mtime.as_secs() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elide() {
+        assert_eq!(elide("22.05", 120), "22.05");
+        assert_eq!(elide("abcdefghij", 10), "abcdefghij");
+        assert_eq!(elide("abcdefghij", 9), "abcd…ghij");
+        assert_eq!(elide("abcdefghij", 6), "ab…hij");
+        assert_eq!(elide("abcdefghij", 1), "…");
+        assert_eq!(elide("abcdefghij", 0), "");
+        // characters, not bytes
+        assert_eq!(elide("ääääää", 5), "ää…ää");
+    }
+
+    #[test]
+    fn test_elided_labels() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = |label: &str, specialisation: Option<&str>| BootableToplevel {
+            label: label.to_string(),
+            toplevel: SystemConfigurationRoot(tempdir.path().to_path_buf()),
+            specialisation_name: specialisation.map(|name| SpecialisationName(name.to_string())),
+            generation_index: 412,
+            ..Default::default()
+        };
+        let label = |rev: &str| {
+            format!(
+                "24.05.20240612.{} (Uakari), Linux 6.6.32, flake git+https://example.com/infra?ref=main&rev={}",
+                "0123456789abcdef".repeat(2),
+                rev
+            )
+        };
+
+        // short labels are left alone
+        let short = toplevel("22.05", None);
+        assert_eq!(short.elided_version(120).unwrap(), short.version().unwrap());
+
+        let versions = ["aaaa1111", "bbbb2222"]
+            .iter()
+            .map(|rev| toplevel(&label(*rev), None).elided_version(80).unwrap())
+            .collect::<Vec<_>>();
+        for (version, rev) in versions.iter().zip(["aaaa1111", "bbbb2222"]) {
+            assert_eq!(version.chars().count(), 80, "{}", version);
+            assert!(version.starts_with("Generation 412 24.05."), "{}", version);
+            assert!(version.contains('…'), "{}", version);
+            // what tells them apart, and the date, survive
+            assert!(
+                version.contains(&format!("rev={}, Built on ", rev)),
+                "{}",
+                version
+            );
+        }
+        assert_ne!(versions[0], versions[1]);
 
-        let version = format!(
-            "Generation {generation} {description}",
-            generation = self.generation_index,
-            description = description
+        // however little room is left, the generation number and the date are kept whole
+        let version = toplevel(&label("aaaa1111"), Some("gui"))
+            .elided_version(10)
+            .unwrap();
+        assert!(
+            version.starts_with("Generation 412~gui …, Built on "),
+            "{}",
+            version
         );
 
-        Ok(version)
+        let long = toplevel("", Some(&"very-long-specialisation-".repeat(8)));
+        let title = long.elided_title(50);
+        assert_eq!(title.chars().count(), 50);
+        assert!(title.starts_with("NixOS – spec: very-long"), "{}", title);
+        assert!(title.ends_with("specialisation-"), "{}", title);
+        assert_eq!(long.elided_title(usize::MAX), long.title());
     }
 }
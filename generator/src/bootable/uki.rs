@@ -0,0 +1,490 @@
+//! Builds unified kernel images without objcopy or ukify, for the common case of a stub with
+//! `.osrel`, `.cmdline`, `.linux`, and `.initrd` sections (and nothing signed or measured by the
+//! builder).
+//!
+//! The sections are appended to the stub's PE image the way ukify lays them out: each one starts
+//! at the first section-aligned virtual address past the previous one (the first one past the
+//! stub's own sections), and its raw data at the first file-aligned offset past the previous one.
+//! The size of the image and the checksum are then updated to match, and the stub's signature (if
+//! any) is dropped, since it no longer matches.
+
+use std::convert::TryFrom;
+
+use crate::Result;
+
+const SECTION_HEADER_LEN: usize = 40;
+/// What sections are named in, NUL-padded.
+const SECTION_NAME_LEN: usize = 8;
+/// `IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ`, what ukify gives its sections too.
+const SECTION_CHARACTERISTICS: u32 = 0x4000_0040;
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+/// The index of the certificate table (the signature) in the optional header's data directories.
+const CERTIFICATE_TABLE: usize = 4;
+
+/// The stub with `sections` (e.g. `(".linux", kernel)`) appended.
+pub(crate) fn build(stub: &[u8], sections: &[(&str, Vec<u8>)]) -> Result<Vec<u8>> {
+    let headers = Headers::parse(stub)?;
+    let existing = (0..headers.section_count)
+        .map(|i| Section::parse(stub, headers.section_table + i * SECTION_HEADER_LEN))
+        .collect::<Result<Vec<_>>>()?;
+
+    let section_table_end =
+        headers.section_table + (existing.len() + sections.len()) * SECTION_HEADER_LEN;
+    if section_table_end > headers.size_of_headers as usize {
+        return Err(format!(
+            "there's no room for {} more section headers",
+            sections.len()
+        )
+        .into());
+    }
+
+    // Whatever follows the last section's raw data (i.e. the signature) is left behind.
+    let raw_end = existing
+        .iter()
+        .map(|section| section.raw_offset as u64 + section.raw_size as u64)
+        .fold(headers.size_of_headers as u64, u64::max);
+    let mut virtual_end = existing
+        .iter()
+        .map(|section| {
+            section.virtual_address as u64 + section.virtual_size.max(section.raw_size) as u64
+        })
+        .fold(headers.size_of_headers as u64, u64::max);
+
+    let mut image = stub
+        .get(..raw_end as usize)
+        .ok_or("the last section is truncated")?
+        .to_vec();
+    let mut header_offset = headers.section_table + existing.len() * SECTION_HEADER_LEN;
+
+    for (name, data) in sections {
+        if name.len() > SECTION_NAME_LEN {
+            return Err(format!("section name '{}' is longer than 8 bytes", name).into());
+        }
+
+        let virtual_address = self::align(virtual_end, headers.section_alignment);
+        let raw_offset = self::align(image.len() as u64, headers.file_alignment);
+        let raw_size = self::align(data.len() as u64, headers.file_alignment);
+        image.resize(raw_offset as usize, 0);
+        image.extend_from_slice(data);
+        image.resize((raw_offset + raw_size) as usize, 0);
+
+        let section = Section {
+            virtual_size: self::to_u32(data.len() as u64, name)?,
+            virtual_address: self::to_u32(virtual_address, name)?,
+            raw_size: self::to_u32(raw_size, name)?,
+            raw_offset: self::to_u32(raw_offset, name)?,
+        };
+        section.write(&mut image[header_offset..], name);
+
+        header_offset += SECTION_HEADER_LEN;
+        virtual_end = virtual_address + data.len() as u64;
+    }
+
+    let section_count = u16::try_from(existing.len() + sections.len())
+        .map_err(|_| "there are too many sections")?;
+    let size_of_image = self::to_u32(
+        self::align(virtual_end, headers.section_alignment),
+        "the image",
+    )?;
+    self::put_u16(&mut image, headers.coff + 2, section_count);
+    self::put_u32(&mut image, headers.optional + 56, size_of_image);
+    if let Some(certificate_table) = headers.certificate_table {
+        image[certificate_table..certificate_table + 8].fill(0);
+    }
+
+    self::put_u32(&mut image, headers.optional + 64, 0);
+    let checksum = self::checksum(&image);
+    self::put_u32(&mut image, headers.optional + 64, checksum);
+
+    Ok(image)
+}
+
+/// Where the headers of a PE image are, and what they say about its layout.
+struct Headers {
+    /// The offset of the COFF file header
+    coff: usize,
+    /// The offset of the optional header
+    optional: usize,
+    /// The offset of the section table
+    section_table: usize,
+    section_count: usize,
+    section_alignment: u32,
+    file_alignment: u32,
+    size_of_headers: u32,
+    /// The offset of the certificate table's data directory, if the image has one
+    certificate_table: Option<usize>,
+}
+
+impl Headers {
+    fn parse(image: &[u8]) -> Result<Self> {
+        if image.get(..2) != Some(&b"MZ"[..]) {
+            return Err("missing PE magic".into());
+        }
+
+        let pe = self::u32_at(image, 0x3c)? as usize;
+        if image.get(pe..pe + 4) != Some(&b"PE\0\0"[..]) {
+            return Err("missing PE signature".into());
+        }
+
+        let coff = pe + 4;
+        let section_count = self::u16_at(image, coff + 2)? as usize;
+        let optional_len = self::u16_at(image, coff + 16)? as usize;
+        let optional = coff + 20;
+        // PE32+ (64-bit) images have a wider optional header, but the fields up to the checksum are
+        // at the same offsets.
+        let directory_count_offset = match self::u16_at(image, optional)? {
+            PE32_MAGIC => optional + 92,
+            PE32_PLUS_MAGIC => optional + 108,
+            magic => return Err(format!("unknown optional header magic {:#x}", magic).into()),
+        };
+        let directory_count = self::u32_at(image, directory_count_offset)? as usize;
+        let certificate_table = if directory_count > CERTIFICATE_TABLE {
+            Some(directory_count_offset + 4 + CERTIFICATE_TABLE * 8)
+        } else {
+            None
+        };
+
+        let headers = Self {
+            coff,
+            optional,
+            section_table: optional + optional_len,
+            section_count,
+            section_alignment: self::u32_at(image, optional + 32)?,
+            file_alignment: self::u32_at(image, optional + 36)?,
+            size_of_headers: self::u32_at(image, optional + 60)?,
+            certificate_table,
+        };
+        if !headers.section_alignment.is_power_of_two() || !headers.file_alignment.is_power_of_two()
+        {
+            return Err("the section or file alignment isn't a power of two".into());
+        }
+
+        Ok(headers)
+    }
+}
+
+/// The parts of a section header that the layout depends on.
+struct Section {
+    virtual_size: u32,
+    virtual_address: u32,
+    raw_size: u32,
+    raw_offset: u32,
+}
+
+impl Section {
+    fn parse(image: &[u8], offset: usize) -> Result<Self> {
+        Ok(Self {
+            virtual_size: self::u32_at(image, offset + 8)?,
+            virtual_address: self::u32_at(image, offset + 12)?,
+            raw_size: self::u32_at(image, offset + 16)?,
+            raw_offset: self::u32_at(image, offset + 20)?,
+        })
+    }
+
+    /// Writes the header of this section, called `name`, to the start of `header` (which is
+    /// zeroed, as the unused part of the headers is).
+    fn write(&self, header: &mut [u8], name: &str) {
+        header[..SECTION_HEADER_LEN].fill(0);
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        self::put_u32(header, 8, self.virtual_size);
+        self::put_u32(header, 12, self.virtual_address);
+        self::put_u32(header, 16, self.raw_size);
+        self::put_u32(header, 20, self.raw_offset);
+        self::put_u32(header, 36, SECTION_CHARACTERISTICS);
+    }
+}
+
+/// The PE checksum of `image` (whose checksum field has to be zero): the sum of its 16-bit words,
+/// with the carries folded back in, plus its length.
+fn checksum(image: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    for word in image.chunks(2) {
+        sum += u16::from_le_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum.wrapping_add(image.len() as u32)
+}
+
+fn align(n: u64, alignment: u32) -> u64 {
+    let alignment = alignment as u64;
+    (n + alignment - 1) & !(alignment - 1)
+}
+
+fn to_u32(n: u64, what: &str) -> Result<u32> {
+    u32::try_from(n).map_err(|_| format!("{} is too large for a PE image", what).into())
+}
+
+fn u16_at(image: &[u8], offset: usize) -> Result<u16> {
+    let bytes = image
+        .get(offset..offset + 2)
+        .ok_or("the headers are truncated")?;
+
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(image: &[u8], offset: usize) -> Result<u32> {
+    let bytes = image
+        .get(offset..offset + 4)
+        .ok_or("the headers are truncated")?;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn put_u16(image: &mut [u8], offset: usize, n: u16) {
+    image[offset..offset + 2].copy_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(image: &mut [u8], offset: usize, n: u32) {
+    image[offset..offset + 4].copy_from_slice(&n.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    use bootspec::SystemConfigurationRoot;
+
+    use crate::bootable::{BootableToplevel, EfiProgram, UkiBuilder};
+    use crate::target::Target;
+    use crate::validate;
+
+    /// A PE32+ stub with a `.text` section, and a signature after it.
+    fn stub() -> Vec<u8> {
+        let mut out = vec![0; 0x600];
+        out[..2].copy_from_slice(b"MZ");
+        put_u32(&mut out, 0x3c, 0x80);
+        out[0x80..0x84].copy_from_slice(b"PE\0\0");
+        // COFF header: x86_64, one section, an optional header with 16 data directories
+        put_u16(&mut out, 0x84, 0x8664);
+        put_u16(&mut out, 0x86, 1);
+        put_u16(&mut out, 0x94, 112 + 16 * 8);
+        // optional header: SectionAlignment, FileAlignment, SizeOfImage, SizeOfHeaders
+        put_u16(&mut out, 0x98, PE32_PLUS_MAGIC);
+        put_u32(&mut out, 0x98 + 32, 0x1000);
+        put_u32(&mut out, 0x98 + 36, 0x200);
+        put_u32(&mut out, 0x98 + 56, 0x2000);
+        put_u32(&mut out, 0x98 + 60, 0x400);
+        put_u32(&mut out, 0x98 + 108, 16);
+        // the certificate table
+        put_u32(&mut out, 0x98 + 112 + 4 * 8, 0x600);
+        put_u32(&mut out, 0x98 + 112 + 4 * 8 + 4, 0x10);
+        Section {
+            virtual_size: 0x10,
+            virtual_address: 0x1000,
+            raw_size: 0x200,
+            raw_offset: 0x400,
+        }
+        .write(&mut out[0x188..], ".text");
+        out[0x400..0x410].copy_from_slice(b"stub entry point");
+        out.extend_from_slice(b"signature of it!");
+
+        out
+    }
+
+    fn sections(image: &[u8]) -> Vec<(String, Section)> {
+        let headers = Headers::parse(image).unwrap();
+        (0..headers.section_count)
+            .map(|i| {
+                let offset = headers.section_table + i * SECTION_HEADER_LEN;
+                let name = image[offset..offset + SECTION_NAME_LEN]
+                    .split(|b| *b == 0)
+                    .next()
+                    .unwrap();
+                (
+                    String::from_utf8(name.to_vec()).unwrap(),
+                    Section::parse(image, offset).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("uki.efi");
+        let sections = [
+            (".osrel", b"ID=nixos\n".to_vec()),
+            (".cmdline", b"init=/init quiet".to_vec()),
+            (".linux", vec![0x4c; 0x1234]),
+            (".initrd", vec![0x49; 0x200]),
+        ];
+        let image = build(&stub(), &sections).unwrap();
+        fs::write(&path, &image).unwrap();
+
+        // what `validate` reads back (e.g. for the manifest) is what was put in
+        validate::validate_kernel(&path).unwrap();
+        for (name, data) in &sections {
+            assert_eq!(
+                validate::pe_section(&path, name).unwrap().as_ref(),
+                Some(data),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            validate::pe_section(&path, ".text").unwrap().unwrap(),
+            b"stub entry point"
+        );
+
+        let layout = self::sections(&image)
+            .into_iter()
+            .map(|(name, section)| (name, section.virtual_address, section.raw_offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layout,
+            [
+                (String::from(".text"), 0x1000, 0x400),
+                (String::from(".osrel"), 0x2000, 0x600),
+                (String::from(".cmdline"), 0x3000, 0x800),
+                (String::from(".linux"), 0x4000, 0xa00),
+                (String::from(".initrd"), 0x6000, 0x1e00),
+            ]
+        );
+
+        let headers = Headers::parse(&image).unwrap();
+        assert_eq!(u32_at(&image, headers.optional + 56).unwrap(), 0x7000);
+        // the signature is gone, along with its entry
+        assert_eq!(image.len(), 0x2000);
+        let certificate_table = headers.certificate_table.unwrap();
+        assert_eq!(image[certificate_table..certificate_table + 8], [0; 8]);
+
+        let mut unchecked = image.clone();
+        put_u32(&mut unchecked, headers.optional + 64, 0);
+        assert_eq!(
+            u32_at(&image, headers.optional + 64).unwrap(),
+            checksum(&unchecked)
+        );
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let err = |stub: &[u8], sections: &[(&str, Vec<u8>)]| {
+            build(stub, sections).unwrap_err().to_string()
+        };
+
+        assert!(err(b"not a PE image", &[]).contains("missing PE magic"));
+        assert!(err(&stub()[..0x90], &[]).contains("truncated"));
+        assert!(err(&stub(), &[(".initrd-extra", vec![])]).contains("longer than 8 bytes"));
+
+        // the section table would run into the stub's first section
+        let sections = vec![(".initrd", Vec::new()); 15];
+        assert!(err(&stub(), &sections).contains("no room for 15 more section headers"));
+        assert!(build(&stub(), &sections[..14]).is_ok());
+    }
+
+    #[test]
+    fn test_checksum() {
+        // the carries are folded back in, and the length is added
+        assert_eq!(checksum(&[0xff, 0xff, 0x02, 0x00]), 0x0002 + 4);
+        // an odd trailing byte counts as the low half of a word
+        assert_eq!(checksum(&[0x01, 0x00, 0x02]), 0x0003 + 3);
+    }
+
+    /// A toplevel with a kernel, an initrd, and an os-release.
+    fn toplevel(dir: &Path) -> BootableToplevel {
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir_all(toplevel.join("etc")).unwrap();
+        fs::write(toplevel.join("kernel"), validate::tests::bzimage(0x100)).unwrap();
+        fs::write(
+            toplevel.join("initrd"),
+            validate::tests::cpio(&[("init", b"#!/bin/sh\n")]),
+        )
+        .unwrap();
+        fs::write(toplevel.join("etc/os-release"), "ID=nixos\n").unwrap();
+
+        BootableToplevel {
+            kernel: toplevel.join("kernel"),
+            initrd: toplevel.join("initrd"),
+            kernel_params: vec![String::from("quiet")],
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_write_unified_efi() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let stub = dir.join("linuxx64.efi.stub");
+        fs::write(&stub, self::stub()).unwrap();
+        let efi = EfiProgram::new(self::toplevel(dir));
+
+        let outpath = dir.join("uki.efi");
+        let target = Target {
+            extra_kernel_params: vec![String::from("console=ttyS0")],
+            ..Target::local(String::new())
+        };
+        efi.write_unified_efi(&UkiBuilder::Native, &outpath, &stub, &target)
+            .unwrap();
+
+        let section = |name: &str| validate::pe_section(&outpath, name).unwrap().unwrap();
+        assert_eq!(section(".cmdline"), b"init=/init quiet console=ttyS0");
+        assert_eq!(section(".osrel"), b"ID=nixos\n");
+        assert_eq!(section(".linux"), fs::read(&efi.source.kernel).unwrap());
+        assert_eq!(section(".initrd"), fs::read(&efi.source.initrd).unwrap());
+
+        let err = efi
+            .write_unified_efi(&UkiBuilder::Native, &outpath, &efi.source.kernel, &target)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing PE magic"), "{}", err);
+    }
+
+    /// Compares what the native builder puts in the sections with what ukify does, if it (and a
+    /// stub, from `UKIFY_TEST_STUB` or systemd's usual place) is around.
+    #[test]
+    fn test_against_ukify() {
+        let stub = std::env::var_os("UKIFY_TEST_STUB")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/usr/lib/systemd/boot/efi/linuxx64.efi.stub"));
+        let ukify = Command::new("ukify").arg("--version").output();
+        if !stub.exists() || !ukify.map_or(false, |output| output.status.success()) {
+            eprintln!("skipping: ukify or '{}' isn't available", stub.display());
+            return;
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let efi = EfiProgram::new(self::toplevel(dir));
+        let target = Target::local(String::new());
+        let cmdline = efi.cmdline(&target).unwrap();
+        let osrel = efi.source.toplevel.0.join("etc/os-release");
+
+        let native = dir.join("native.efi");
+        efi.write_unified_efi(&UkiBuilder::Native, &native, &stub, &target)
+            .unwrap();
+
+        let ukified = dir.join("ukify.efi");
+        let status = Command::new("ukify")
+            .arg("build")
+            .arg("--stub")
+            .arg(&stub)
+            .arg("--linux")
+            .arg(&efi.source.kernel)
+            .arg("--initrd")
+            .arg(&efi.source.initrd)
+            .arg(format!("--cmdline={}", cmdline))
+            .arg(format!("--os-release=@{}", osrel.display()))
+            .arg("--output")
+            .arg(&ukified)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        validate::validate_kernel(&native).unwrap();
+        for name in [".osrel", ".cmdline", ".linux", ".initrd"] {
+            assert_eq!(
+                validate::pe_section(&native, name).unwrap(),
+                validate::pe_section(&ukified, name).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+}
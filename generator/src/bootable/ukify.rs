@@ -0,0 +1,376 @@
+//! Builds unified kernel images with systemd's ukify (`--uki-builder ukify`), whose command line
+//! depends on its version: 253 and 254 take the kernel and initrd as positional arguments (and the
+//! command line as text), and 255 and later take everything as options of its `build` verb (and
+//! the command line from a file, as `@path`).
+//!
+//! A ukify that's missing, or older than [`MIN_VERSION`] (i.e. from before it was released), isn't
+//! worth failing the whole run over if there's an objcopy to build them with instead.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::debug;
+
+use super::UkiBuilder;
+use crate::command;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// The first release of systemd with ukify.
+pub const MIN_VERSION: u32 = 253;
+/// The first release of systemd whose ukify has the `build` verb.
+const BUILD_VERB_VERSION: u32 = 255;
+
+/// A ukify, and the version of systemd it's from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ukify {
+    pub path: PathBuf,
+    pub version: u32,
+}
+
+/// What goes into a unified kernel image.
+pub(crate) struct Inputs<'a> {
+    pub stub: &'a Path,
+    pub linux: &'a Path,
+    pub initrd: &'a Path,
+    pub cmdline: &'a str,
+    /// A file with just `cmdline` in it
+    pub cmdline_file: &'a Path,
+    pub os_release: &'a Path,
+    pub output: &'a Path,
+}
+
+impl Ukify {
+    /// The arguments that build `inputs` with this ukify.
+    pub(crate) fn args(&self, inputs: &Inputs) -> Vec<OsString> {
+        // The paths are passed as they are, so that one that isn't valid UTF-8 isn't mangled.
+        let arg = |prefix: &str, path: &Path| {
+            let mut arg = OsString::from(prefix);
+            arg.push(path);
+            arg
+        };
+
+        if self.version >= BUILD_VERB_VERSION {
+            vec![
+                OsString::from("build"),
+                arg("--stub=", inputs.stub),
+                arg("--linux=", inputs.linux),
+                arg("--initrd=", inputs.initrd),
+                arg("--cmdline=@", inputs.cmdline_file),
+                arg("--os-release=@", inputs.os_release),
+                arg("--output=", inputs.output),
+            ]
+        } else {
+            vec![
+                OsString::from("--stub"),
+                OsString::from(inputs.stub),
+                OsString::from("--cmdline"),
+                OsString::from(inputs.cmdline),
+                OsString::from("--os-release"),
+                arg("@", inputs.os_release),
+                OsString::from("--output"),
+                OsString::from(inputs.output),
+                OsString::from(inputs.linux),
+                OsString::from(inputs.initrd),
+            ]
+        }
+    }
+}
+
+/// The version of systemd in the output of `ukify --version` (e.g. `ukify 255.4`, or `254.5` on
+/// its own), which is the first number in it.
+pub fn parse_version(output: &str) -> Option<u32> {
+    output
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|number| !number.is_empty())?
+        .parse()
+        .ok()
+}
+
+/// The version of systemd that `ukify` is from.
+pub fn version(ukify: &Path) -> Result<u32> {
+    let output = command::output(Command::new(ukify).arg("--version"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    if !output.status.success() {
+        return Err(format!(
+            "`{} --version` failed ({}): {}",
+            ukify.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    self::parse_version(&stdout).ok_or_else(|| {
+        format!(
+            "couldn't tell the version of '{}' from `{}`",
+            ukify.display(),
+            stdout.trim()
+        )
+        .into()
+    })
+}
+
+/// What builds unified kernel images with `--uki-builder ukify`: `ukify` itself, unless it's
+/// missing or too old, in which case it's `objcopy` (with a warning), if there is one.
+pub fn choose(ukify: &Path, objcopy: Option<PathBuf>) -> Result<UkiBuilder> {
+    let problem = match self::version(ukify) {
+        Ok(version) if version >= MIN_VERSION => {
+            debug!("building unified EFI files with ukify {}", version);
+
+            return Ok(UkiBuilder::Ukify(Ukify {
+                path: ukify.to_path_buf(),
+                version,
+            }));
+        }
+        Ok(version) => format!(
+            "'{}' is from systemd {}, but at least {} is needed",
+            ukify.display(),
+            version,
+            MIN_VERSION
+        ),
+        Err(e) => e.to_string(),
+    };
+
+    match objcopy {
+        Some(objcopy) => {
+            warnings::emit(
+                Kind::UkifyFallback,
+                format!(
+                    "{}; building unified EFI files with '{}' instead",
+                    problem,
+                    objcopy.display()
+                ),
+            );
+
+            Ok(UkiBuilder::Objcopy(objcopy))
+        }
+        None => Err(format!("{} (and there's no --objcopy to fall back to)", problem).into()),
+    }
+}
+
+/// Builds `inputs` with `ukify`.
+pub(crate) fn build(ukify: &Ukify, inputs: &Inputs) -> Result<()> {
+    let args = ukify.args(inputs);
+    let output = command::output(Command::new(&ukify.path).args(&args))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "failed to write unified efi '{}' with '{}': {}",
+            inputs.output.display(),
+            ukify.path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// The stub ukifies these tests run are shell scripts.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A ukify that prints `version` for `--version`, and otherwise logs its arguments (one per
+    /// line) to `ukify.log` next to it.
+    fn stub_ukify(dir: &Path, version: &str) -> PathBuf {
+        let ukify = dir.join("ukify");
+        fs::write(
+            &ukify,
+            format!(
+                r#"#!/bin/sh
+if [ "$1" = --version ]; then
+  echo '{version}'
+  exit
+fi
+for arg; do
+  echo "$arg" >> {log}
+done
+"#,
+                version = version,
+                log = dir.join("ukify.log").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&ukify, fs::Permissions::from_mode(0o755)).unwrap();
+
+        ukify
+    }
+
+    fn inputs(output: &Path) -> Inputs {
+        Inputs {
+            stub: Path::new("/stub.efi"),
+            linux: Path::new("/toplevel/kernel"),
+            initrd: Path::new("/toplevel/initrd"),
+            cmdline: "init=/init quiet",
+            cmdline_file: Path::new("/tmp/cmdline"),
+            os_release: Path::new("/toplevel/etc/os-release"),
+            output,
+        }
+    }
+
+    #[test]
+    fn test_parse_version() {
+        for (output, expected) in [
+            ("ukify 253.5\n", Some(253)),
+            ("255.4", Some(255)),
+            ("systemd 254 (254.6-2)\n+PAM +AUDIT", Some(254)),
+            ("ukify\n", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_version(output), expected, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        let inputs = self::inputs(Path::new("/out/uki.efi"));
+        let args = |version| {
+            Ukify {
+                path: PathBuf::from("ukify"),
+                version,
+            }
+            .args(&inputs)
+        };
+
+        let legacy = [
+            "--stub",
+            "/stub.efi",
+            "--cmdline",
+            "init=/init quiet",
+            "--os-release",
+            "@/toplevel/etc/os-release",
+            "--output",
+            "/out/uki.efi",
+            "/toplevel/kernel",
+            "/toplevel/initrd",
+        ];
+        assert_eq!(args(253), legacy);
+        assert_eq!(args(254), legacy);
+
+        let build = [
+            "build",
+            "--stub=/stub.efi",
+            "--linux=/toplevel/kernel",
+            "--initrd=/toplevel/initrd",
+            "--cmdline=@/tmp/cmdline",
+            "--os-release=@/toplevel/etc/os-release",
+            "--output=/out/uki.efi",
+        ];
+        assert_eq!(args(255), build);
+        assert_eq!(args(256), build);
+    }
+
+    #[test]
+    fn test_choose() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let objcopy = PathBuf::from("/bin/objcopy");
+
+        // (what `ukify --version` prints, what builds them)
+        for (version, expected) in [
+            ("ukify 253.5", Some(253)),
+            ("255.4", Some(255)),
+            ("ukify 252.4", None),
+            ("ukify", None),
+        ] {
+            let ukify = self::stub_ukify(dir, version);
+            let builder = choose(&ukify, Some(objcopy.clone())).unwrap();
+            let expected = match expected {
+                Some(version) => UkiBuilder::Ukify(Ukify {
+                    path: ukify.clone(),
+                    version,
+                }),
+                None => UkiBuilder::Objcopy(objcopy.clone()),
+            };
+            assert_eq!(builder, expected, "{}", version);
+        }
+
+        assert!(warnings::emitted(Kind::UkifyFallback)
+            .iter()
+            .any(|message| message.contains("is from systemd 252")));
+
+        // too old, with nothing to fall back to
+        let ukify = self::stub_ukify(dir, "ukify 252.4");
+        let err = choose(&ukify, None).unwrap_err().to_string();
+        assert!(err.contains("at least 253"), "{}", err);
+        assert!(err.contains("no --objcopy"), "{}", err);
+
+        // missing
+        let missing = dir.join("missing/ukify");
+        assert_eq!(
+            choose(&missing, Some(objcopy.clone())).unwrap(),
+            UkiBuilder::Objcopy(objcopy)
+        );
+        let err = choose(&missing, None).unwrap_err().to_string();
+        assert!(err.contains("failed to run"), "{}", err);
+    }
+
+    #[test]
+    fn test_build() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let output = dir.join("uki.efi");
+        let inputs = self::inputs(&output);
+
+        for (version, first) in [("ukify 253.5", "--stub"), ("ukify 255.4", "build")] {
+            let ukify = self::stub_ukify(dir, version);
+            let _ = fs::remove_file(dir.join("ukify.log"));
+            let ukify = match choose(&ukify, None).unwrap() {
+                UkiBuilder::Ukify(ukify) => ukify,
+                builder => panic!("{:?}", builder),
+            };
+
+            build(&ukify, &inputs).unwrap();
+            let log = fs::read_to_string(dir.join("ukify.log")).unwrap();
+            assert_eq!(log.lines().next(), Some(first));
+            assert_eq!(
+                log.lines().collect::<Vec<_>>(),
+                ukify
+                    .args(&inputs)
+                    .iter()
+                    .map(|arg| arg.to_str().unwrap())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        let failing = dir.join("failing-ukify");
+        fs::write(&failing, "#!/bin/sh\necho 'no such stub' >&2\nexit 1\n").unwrap();
+        fs::set_permissions(&failing, fs::Permissions::from_mode(0o755)).unwrap();
+        let ukify = Ukify {
+            path: failing,
+            version: 255,
+        };
+        let err = build(&ukify, &inputs).unwrap_err().to_string();
+        assert!(err.contains("failed to write unified efi"), "{}", err);
+        assert!(err.contains("no such stub"), "{}", err);
+    }
+
+    #[test]
+    fn test_build_sanitizes_env() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let log = dir.join("env.log");
+        let path = dir.join("ukify");
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho \"PYTHONPATH=${{PYTHONPATH-unset}}\" > {}\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // e.g. a user's, which could change what ukify imports
+        std::env::set_var("PYTHONPATH", "/home/user/.local/lib/python3/site-packages");
+        let ukify = Ukify { path, version: 255 };
+        build(&ukify, &self::inputs(&dir.join("uki.efi"))).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "PYTHONPATH=unset\n");
+    }
+}
@@ -0,0 +1,358 @@
+//! Rendering kernel params into a kernel command line.
+//!
+//! The kernel splits its command line on whitespace outside of double quotes, and strips the quotes
+//! around a param (`"name=a value"`) or its value (`name="a value"`); there is no way to escape a
+//! quote. A unified EFI file's `.cmdline` section is handed to the kernel as is, but an entry's
+//! `options` line is parsed by systemd-boot first: it ends at the first newline, and systemd-boot
+//! strips one pair of double quotes around the whole value.
+
+use std::borrow::Cow;
+
+use crate::Result;
+
+/// Quotes `param` if it contains whitespace, as `name="a value"` (or `"a value"` if it has no
+/// name).
+///
+/// Params that are already quoted are left alone. Params the command line can't represent are
+/// rejected: those with control characters (e.g. a newline, which would end an entry's `options`
+/// line early and turn the rest of the param into a line of its own), with unbalanced quotes, or
+/// with both whitespace and quotes.
+pub fn quote(param: &str) -> Result<Cow<'_, str>> {
+    if let Some(c) = param.chars().find(|c| c.is_control()) {
+        return Err(format!(
+            "kernel param {:?} contains the control character {:?}",
+            param, c
+        )
+        .into());
+    }
+
+    let quotes = param.matches('"').count();
+    if quotes % 2 != 0 {
+        return Err(format!("kernel param {:?} has unbalanced quotes", param).into());
+    }
+
+    if !param.contains(char::is_whitespace) {
+        return Ok(Cow::Borrowed(param));
+    }
+
+    if quotes != 0 {
+        return Err(format!(
+            "kernel param {:?} contains both whitespace and quotes, which can't be escaped",
+            param
+        )
+        .into());
+    }
+
+    Ok(match param.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Cow::Owned(format!("{}=\"{}\"", name, value)),
+        _ => Cow::Owned(format!("\"{}\"", param)),
+    })
+}
+
+/// Splits `cmdline` into params the way the kernel does: on whitespace outside of double quotes,
+/// stripping the quotes. It's the inverse of [`render`].
+///
+/// `cmdline` is taken literally: nothing in it (e.g. `$VAR` or `$(...)`) is expanded.
+pub fn split(cmdline: &str) -> Result<Vec<String>> {
+    let mut params = Vec::new();
+    let mut param = String::new();
+    let mut in_quote = false;
+
+    for c in cmdline.chars() {
+        match c {
+            '"' => in_quote = !in_quote,
+            c if c.is_whitespace() && !in_quote => {
+                if !param.is_empty() {
+                    params.push(std::mem::take(&mut param));
+                }
+            }
+            c => param.push(c),
+        }
+    }
+
+    if in_quote {
+        return Err(format!("command line {:?} has unbalanced quotes", cmdline).into());
+    }
+    if !param.is_empty() {
+        params.push(param);
+    }
+
+    Ok(params)
+}
+
+/// Renders `params` as a command line, e.g. for a unified EFI file's `.cmdline` section.
+pub fn render<'a>(params: impl IntoIterator<Item = &'a str>) -> Result<String> {
+    let params = params
+        .into_iter()
+        .map(self::quote)
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(params.join(" "))
+}
+
+/// Renders `params` as the value of an entry's `options` line.
+///
+/// If the command line starts and ends with a quote, systemd-boot would strip them, so it's wrapped
+/// in another pair.
+pub fn options<'a>(params: impl IntoIterator<Item = &'a str>) -> Result<String> {
+    let cmdline = self::render(params)?;
+
+    if cmdline.len() > 1 && cmdline.starts_with('"') && cmdline.ends_with('"') {
+        Ok(format!("\"{}\"", cmdline))
+    } else {
+        Ok(cmdline)
+    }
+}
+
+/// Renders `params` as the values of an entry's `options` lines, each at most `max_len` bytes long
+/// (unless a single param is longer than that), splitting only between params: some versions of
+/// systemd-boot truncate long lines, but every version joins an entry's `options` lines with
+/// spaces.
+pub fn folded_options<'a>(
+    params: impl IntoIterator<Item = &'a str>,
+    max_len: usize,
+) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+
+    for param in params {
+        line.push(param);
+        if line.len() > 1 && self::options(line.iter().copied())?.len() > max_len {
+            line.pop();
+            lines.push(std::mem::replace(&mut line, vec![param]));
+        }
+    }
+    lines.push(line);
+
+    lines.into_iter().map(self::options).collect()
+}
+
+/// Collapses `params` the kernel would only take one of (see `--dedupe-kernel-params`): of the
+/// params with the same name (the part before `=`, where `-` and `_` are the same), only the last is
+/// kept, where it is, since that's the one the kernel goes by. The rest of the params keep their
+/// order.
+///
+/// Params named in `exclude` (e.g. `console`, which can be given several times to log to several
+/// consoles), and everything from a `--` on (which the kernel passes to init), are left alone.
+///
+/// Returns the params that are kept, and those that were dropped.
+pub fn dedupe<'a>(params: &[&'a str], exclude: &[String]) -> (Vec<&'a str>, Vec<&'a str>) {
+    let end = params
+        .iter()
+        .position(|param| *param == "--")
+        .unwrap_or(params.len());
+    let excluded = |name: &str| exclude.iter().any(|e| self::param_name(e) == name);
+
+    let mut kept = Vec::new();
+    let mut dropped = Vec::new();
+    for (i, param) in params.iter().enumerate() {
+        let name = self::param_name(param);
+        let overridden = i < end
+            && !excluded(&name)
+            && params[i + 1..end]
+                .iter()
+                .any(|later| self::param_name(later) == name);
+
+        if overridden {
+            dropped.push(*param);
+        } else {
+            kept.push(*param);
+        }
+    }
+
+    (kept, dropped)
+}
+
+/// The name of `param` as the kernel matches it: the part before `=` (or all of it, for a flag),
+/// with `-` and `_` the same.
+fn param_name(param: &str) -> String {
+    let name = param.split('=').next().unwrap_or_default();
+
+    name.replace('-', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What systemd-boot makes of an `options` line's value.
+    fn unquote_options(value: &str) -> &str {
+        let value = value.trim();
+        if value.len() > 1 && value.starts_with('"') && value.ends_with('"') {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        }
+    }
+
+    #[test]
+    fn test_quote() {
+        for (param, expected) in [
+            ("quiet", Ok("quiet")),
+            ("loglevel=4", Ok("loglevel=4")),
+            ("#comment", Ok("#comment")),
+            ("dyndbg=\"file x.c +p\"", Err("whitespace and quotes")),
+            ("foo=\"bar\"", Ok("foo=\"bar\"")),
+            ("foo=\"bar", Err("unbalanced quotes")),
+            ("foo=bar baz", Ok("foo=\"bar baz\"")),
+            ("foo=", Ok("foo=")),
+            ("=a b", Ok("\"=a b\"")),
+            ("a b", Ok("\"a b\"")),
+            ("foo=a=b c", Ok("foo=\"a=b c\"")),
+            ("foo=bar\n#", Err("control character '\\n'")),
+            ("foo=bar\r", Err("control character '\\r'")),
+            ("foo=\tbar", Err("control character '\\t'")),
+            ("foo=\u{7f}", Err("control character '\\u{7f}'")),
+        ] {
+            match (quote(param), expected) {
+                (Ok(quoted), Ok(expected)) => assert_eq!(quoted, expected, "{:?}", param),
+                (Err(e), Err(expected)) => {
+                    assert!(e.to_string().contains(expected), "{:?}: {}", param, e)
+                }
+                (actual, expected) => panic!(
+                    "{:?}: expected {:?}, got {:?}",
+                    param,
+                    expected,
+                    actual.map_err(|e| e.to_string())
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split("  init=/init\tfoo=\"a b\"\n\"c d\" $(reboot)\n").unwrap(),
+            vec!["init=/init", "foo=a b", "c d", "$(reboot)"]
+        );
+        assert_eq!(split("").unwrap(), Vec::<String>::new());
+        assert!(split("foo=\"a b")
+            .unwrap_err()
+            .to_string()
+            .contains("unbalanced quotes"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for params in [
+            &["init=/init", "quiet"][..],
+            &["init=/init", "foo=bar baz", "console=ttyS0"],
+            &["init=/init", "a b"],
+            &["a b"],
+            &["a b", "c d"],
+            &["foo=a=b c", "#comment"],
+        ] {
+            let cmdline = render(params.iter().copied()).unwrap();
+            assert_eq!(split(&cmdline).unwrap(), params, "{:?}", cmdline);
+
+            let options = options(params.iter().copied()).unwrap();
+            assert!(!options.contains('\n'));
+            assert_eq!(
+                split(unquote_options(&options)).unwrap(),
+                params,
+                "{:?}",
+                options
+            );
+        }
+
+        // the one case where the two differ
+        assert_eq!(render(["a b", "c d"]).unwrap(), r#""a b" "c d""#);
+        assert_eq!(options(["a b", "c d"]).unwrap(), r#"""a b" "c d"""#);
+
+        assert!(render(["init=/init", "foo=bar\n#"]).is_err());
+        assert!(options(["init=/init", "foo=bar\n#"]).is_err());
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let console = [String::from("console")];
+        for (params, kept, dropped) in [
+            (
+                &["init=/init", "loglevel=4", "quiet", "loglevel=7"][..],
+                &["init=/init", "quiet", "loglevel=7"][..],
+                &["loglevel=4"][..],
+            ),
+            // flags are kept once, as late as they come
+            (
+                &["quiet", "splash", "quiet"],
+                &["splash", "quiet"],
+                &["quiet"],
+            ),
+            // `-` and `_` are the same in names, but not in values
+            (
+                &["sys-rq=1", "sys_rq=0", "foo=a-b", "bar=a_b"],
+                &["sys_rq=0", "foo=a-b", "bar=a_b"],
+                &["sys-rq=1"],
+            ),
+            // every console is logged to
+            (
+                &["console=tty0", "quiet", "console=ttyS0,115200", "quiet"],
+                &["console=tty0", "console=ttyS0,115200", "quiet"],
+                &["quiet"],
+            ),
+            // init gets what comes after `--` as it is
+            (
+                &["quiet", "--", "quiet", "single", "single"],
+                &["quiet", "--", "quiet", "single", "single"],
+                &[],
+            ),
+            (
+                &["foo=1", "--", "foo=2", "foo=3"],
+                &["foo=1", "--", "foo=2", "foo=3"],
+                &[],
+            ),
+        ] {
+            let (actual_kept, actual_dropped) = dedupe(params, &console);
+            assert_eq!(actual_kept, kept, "{:?}", params);
+            assert_eq!(actual_dropped, dropped, "{:?}", params);
+        }
+
+        // without the exclusion, only the last console is kept
+        assert_eq!(
+            dedupe(&["console=tty0", "console=ttyS0"], &[]),
+            (vec!["console=ttyS0"], vec!["console=tty0"])
+        );
+    }
+
+    #[test]
+    fn test_folded_options() {
+        // some 6 KB of params, with a few that need quoting
+        let params = (0..300)
+            .map(|i| match i % 50 {
+                0 => format!("dyndbg=file drivers/{}.c +p", i),
+                _ => format!("module{}.option=value{}", i, i),
+            })
+            .collect::<Vec<_>>();
+        let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+        assert!(params.iter().map(|param| param.len() + 1).sum::<usize>() > 6 * 1024);
+
+        let lines = folded_options(params.iter().copied(), 512).unwrap();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= 512), "{:?}", lines);
+        // what systemd-boot makes of the lines is the params, in order
+        let joined = lines
+            .iter()
+            .map(|line| unquote_options(line))
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(split(&joined).unwrap(), params);
+
+        // short enough for one line
+        assert_eq!(
+            folded_options(["init=/init", "quiet"], 512).unwrap(),
+            ["init=/init quiet"]
+        );
+        assert_eq!(folded_options([], 512).unwrap(), [""]);
+        // a param that's too long on its own gets a line of its own
+        assert_eq!(
+            folded_options(["init=/init", "a=0123456789", "quiet"], 10).unwrap(),
+            ["init=/init", "a=0123456789", "quiet"]
+        );
+        // each line is quoted for systemd-boot on its own
+        assert_eq!(
+            folded_options(["init=/init", "a b", "c d"], 13).unwrap(),
+            ["init=/init", r#"""a b" "c d"""#]
+        );
+        assert!(folded_options(["init=/init", "foo=bar\n#"], 512).is_err());
+    }
+}
@@ -0,0 +1,508 @@
+//! Runs external commands (e.g. `ukify` and `objcopy` for the generator, `bootctl` and `sbsign` for
+//! the installer), killing them if they take longer than `--command-timeout`: a hung command would
+//! otherwise block the whole activation.
+//!
+//! Commands run in a sanitized environment, so that they behave the same whether the generator and
+//! the installer are run interactively or during activation: e.g. a user's `PYTHONPATH` could
+//! change what ukify imports, and `SBSIGN_OPTS` or the locale how the other tools behave.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{trace, warn};
+
+use crate::context::Context;
+use crate::Result;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a running command is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The timeout for every command, in milliseconds (set once from `--command-timeout`).
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT.as_millis() as u64);
+
+/// The variables commands inherit from the environment they're run from, besides
+/// `--passthrough-env`.
+const INHERITED_ENV: &[&str] = &["PATH", "TMPDIR"];
+/// The variables every command gets, whatever the environment they're run from has (unless they're
+/// passed through).
+const PINNED_ENV: &[(&str, &str)] = &[("LC_ALL", "C")];
+
+lazy_static::lazy_static! {
+    /// The variables commands inherit on top of [`INHERITED_ENV`] (set once from
+    /// `--passthrough-env`).
+    static ref PASSTHROUGH_ENV: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+/// A command that was killed for taking longer than the timeout.
+#[derive(Debug)]
+pub struct TimeoutError {
+    pub program: PathBuf,
+    pub args: Vec<OsString>,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` with args `{:?}` timed out after {:?} and was killed (see --command-timeout)",
+            self.program.display(),
+            self.args,
+            self.timeout
+        )
+    }
+}
+
+impl Error for TimeoutError {}
+
+/// Sets the timeout for every command (see `--command-timeout`).
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+fn timeout() -> Duration {
+    Duration::from_millis(TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+/// Sets the variables commands inherit on top of [`INHERITED_ENV`] (see `--passthrough-env`).
+pub fn set_passthrough_env(vars: Vec<String>) {
+    *PASSTHROUGH_ENV.write().unwrap_or_else(|e| e.into_inner()) = vars;
+}
+
+/// Parses the name of an environment variable (e.g. for `--passthrough-env`).
+pub fn parse_env_var_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains('=') || s.contains('\0') {
+        return Err(format!(
+            "'{}' is not the name of an environment variable (it must be non-empty and not contain '=')",
+            s
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+/// Parses the generator's `--command-timeout`, in whole seconds, rejecting a timeout of 0 (which
+/// would kill every command right away).
+pub fn parse_timeout(s: &str) -> Result<Duration, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err(String::from(
+            "the command timeout must be at least 1 second",
+        )),
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(_) => Err(format!(
+            "invalid command timeout '{}' (expected a whole number of seconds)",
+            s
+        )),
+    }
+}
+
+/// The environment commands run in: the [`PINNED_ENV`], and the [`INHERITED_ENV`] and `passthrough`
+/// variables that `lookup` (the environment they're run from) has.
+fn environment(
+    passthrough: &[String],
+    lookup: impl Fn(&str) -> Option<OsString>,
+) -> BTreeMap<OsString, OsString> {
+    let mut env = PINNED_ENV
+        .iter()
+        .map(|(var, value)| (OsString::from(var), OsString::from(value)))
+        .collect::<BTreeMap<_, _>>();
+
+    for var in INHERITED_ENV
+        .iter()
+        .copied()
+        .chain(passthrough.iter().map(String::as_str))
+    {
+        if let Some(value) = lookup(var) {
+            env.insert(OsString::from(var), value);
+        }
+    }
+
+    env
+}
+
+/// Clears `cmd`'s environment but for the sanitized [`environment`], and what was set on `cmd`
+/// itself.
+fn sanitize_env(cmd: &mut Command) {
+    let passthrough = PASSTHROUGH_ENV.read().unwrap_or_else(|e| e.into_inner());
+    let mut env = self::environment(&passthrough, |var| env::var_os(var));
+
+    for (var, value) in cmd.get_envs() {
+        match value {
+            Some(value) => env.insert(var.to_owned(), value.to_owned()),
+            None => env.remove(var),
+        };
+    }
+
+    trace!(
+        "running `{}` with environment {:?}",
+        cmd.get_program().to_string_lossy(),
+        env
+    );
+    cmd.env_clear().envs(env);
+}
+
+/// Like [`Command::status`], but with the timeout.
+pub fn status(cmd: &mut Command) -> Result<ExitStatus> {
+    self::run(cmd, false, None, self::timeout()).map(|output| output.status)
+}
+
+/// Like [`Command::output`], but with the timeout.
+pub fn output(cmd: &mut Command) -> Result<Output> {
+    self::run(cmd, true, None, self::timeout())
+}
+
+/// Like [`output`], with `input` on the command's stdin.
+pub fn output_with_input(cmd: &mut Command, input: &[u8]) -> Result<Output> {
+    self::run(cmd, true, Some(input), self::timeout())
+}
+
+/// Runs `cmd` to completion, capturing its stdout and stderr if `capture` (with `input`, if any, on
+/// its stdin), and killing it if it takes longer than `timeout` (which is reported as a
+/// [`TimeoutError`]).
+fn run(
+    cmd: &mut Command,
+    capture: bool,
+    input: Option<&[u8]>,
+    timeout: Duration,
+) -> Result<Output> {
+    let program = PathBuf::from(cmd.get_program());
+    let args = cmd.get_args().map(ToOwned::to_owned).collect::<Vec<_>>();
+
+    self::sanitize_env(cmd);
+    if capture {
+        cmd.stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    }
+    let mut child = cmd.spawn().with_cmd_context(&program, &args)?;
+
+    // Written on a thread of its own too, and a command that doesn't read all of it (closing the
+    // pipe early) isn't an error.
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_vec();
+            Some(thread::spawn(move || match stdin.write_all(&input) {
+                Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e),
+                _ => Ok(()),
+            }))
+        }
+        _ => None,
+    };
+
+    // Read on threads of their own, so the command doesn't block on a full pipe.
+    let read = |pipe: Option<Box<dyn Read + Send>>| {
+        pipe.map(|mut pipe| {
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                pipe.read_to_end(&mut buf).map(|_| buf)
+            })
+        })
+    };
+    let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().with_cmd_context(&program, &args)? {
+            break status;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            if let Err(e) = child.kill().and_then(|_| child.wait()) {
+                warn!("couldn't kill `{}`: {}", program.display(), e);
+            }
+
+            return Err(Box::new(TimeoutError {
+                program,
+                args,
+                timeout,
+            }));
+        }
+
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    };
+
+    let join = |reader: Option<thread::JoinHandle<std::io::Result<Vec<u8>>>>| -> Result<Vec<u8>> {
+        match reader {
+            Some(reader) => Ok(reader
+                .join()
+                .map_err(|_| "the thread reading the command's output panicked")?
+                .with_cmd_context(&program, &args)?),
+            None => Ok(Vec::new()),
+        }
+    };
+
+    if let Some(writer) = writer {
+        writer
+            .join()
+            .map_err(|_| "the thread writing the command's input panicked")?
+            .with_cmd_context(&program, &args)?;
+    }
+
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// How a command that was [`watch`]ed ended.
+#[derive(Debug, PartialEq)]
+pub enum Watched {
+    /// What it was watched for happened, and it was killed
+    Done,
+    /// It exited on its own first
+    Exited(ExitStatus),
+    /// Neither happened before the timeout, and it was killed
+    TimedOut,
+}
+
+/// Runs `cmd` until `done` (which is checked as it runs, e.g. on a log it writes) returns true, it
+/// exits, or `timeout` passes, killing it if it's still running. Its stdout is discarded.
+pub fn watch(
+    cmd: &mut Command,
+    timeout: Duration,
+    mut done: impl FnMut() -> bool,
+) -> Result<Watched> {
+    let program = PathBuf::from(cmd.get_program());
+    let args = cmd.get_args().map(ToOwned::to_owned).collect::<Vec<_>>();
+
+    self::sanitize_env(cmd);
+    cmd.stdin(Stdio::null()).stdout(Stdio::null());
+    let mut child = cmd.spawn().with_cmd_context(&program, &args)?;
+
+    let deadline = Instant::now() + timeout;
+    let watched = loop {
+        if let Some(status) = child.try_wait().with_cmd_context(&program, &args)? {
+            return Ok(Watched::Exited(status));
+        }
+        if done() {
+            break Watched::Done;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break Watched::TimedOut;
+        }
+
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    };
+
+    if let Err(e) = child.kill().and_then(|_| child.wait()) {
+        warn!("couldn't kill `{}`: {}", program.display(), e);
+    }
+
+    Ok(watched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let output = run(
+            Command::new("sh").args(&["-c", "echo out; echo err >&2; exit 3"]),
+            true,
+            None,
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        let err = run(
+            Command::new("/nonexistent/bootctl").arg("status"),
+            false,
+            None,
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/bootctl"));
+        assert!(!err.is::<TimeoutError>());
+    }
+
+    #[test]
+    fn test_run_with_input() {
+        let output = run(
+            Command::new("sh").args(&["-c", "tr a-z A-Z"]),
+            true,
+            Some(&b"plan\n"[..]),
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"PLAN\n");
+
+        // a command that doesn't read its input
+        let input = vec![b'x'; 1 << 20];
+        let output = run(
+            Command::new("true"),
+            true,
+            Some(input.as_slice()),
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn test_environment() {
+        let caller_env = |var: &str| match var {
+            "PATH" => Some(OsString::from("/run/current-system/sw/bin")),
+            "PYTHONPATH" => Some(OsString::from(
+                "/home/user/.local/lib/python3/site-packages",
+            )),
+            "SBSIGN_OPTS" => Some(OsString::from("--detached")),
+            "LC_ALL" => Some(OsString::from("de_DE.UTF-8")),
+            _ => None,
+        };
+        let env = |passthrough: &[&str]| {
+            let passthrough = passthrough
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            environment(&passthrough, caller_env)
+                .into_iter()
+                .map(|(var, value)| {
+                    format!("{}={}", var.to_string_lossy(), value.to_string_lossy())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(env(&[]), ["LC_ALL=C", "PATH=/run/current-system/sw/bin"]);
+        assert_eq!(
+            env(&["SBSIGN_OPTS", "UNSET"]),
+            [
+                "LC_ALL=C",
+                "PATH=/run/current-system/sw/bin",
+                "SBSIGN_OPTS=--detached"
+            ]
+        );
+        // the locale can be passed through, too
+        assert_eq!(
+            env(&["LC_ALL"]),
+            ["LC_ALL=de_DE.UTF-8", "PATH=/run/current-system/sw/bin"]
+        );
+    }
+
+    #[test]
+    fn test_parse_env_var_name() {
+        assert_eq!(
+            parse_env_var_name("SBSIGN_OPTS"),
+            Ok(String::from("SBSIGN_OPTS"))
+        );
+        assert!(parse_env_var_name("").is_err());
+        assert!(parse_env_var_name("LC_ALL=C").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("60"), Ok(Duration::from_secs(60)));
+        assert!(parse_timeout("0").unwrap_err().contains("at least 1"));
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("5m").is_err());
+    }
+
+    #[test]
+    fn test_run_sanitizes_env() {
+        let output = run(
+            Command::new("env").env("SET_BY_CALLER", "1"),
+            true,
+            None,
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        let mut vars = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('=').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        vars.sort();
+
+        let mut expected = vec!["LC_ALL", "SET_BY_CALLER"];
+        expected.extend(
+            INHERITED_ENV
+                .iter()
+                .filter(|var| env::var_os(var).is_some()),
+        );
+        expected.sort_unstable();
+        assert_eq!(vars, expected);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let stub = tempdir.path().join("bootctl");
+        std::fs::write(&stub, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&stub, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+
+        for capture in [false, true] {
+            let started = Instant::now();
+            let err = run(
+                Command::new(&stub).arg("status"),
+                capture,
+                None,
+                Duration::from_millis(200),
+            )
+            .unwrap_err();
+
+            assert!(started.elapsed() < Duration::from_secs(10));
+            let err = err.downcast::<TimeoutError>().unwrap();
+            assert_eq!(err.program, stub);
+            assert_eq!(err.args, vec![OsString::from("status")]);
+            assert!(err.to_string().contains(&stub.display().to_string()));
+        }
+    }
+
+    #[test]
+    fn test_watch() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let log = tempdir.path().join("log");
+        let sh = |script: &str, timeout: Duration| {
+            watch(
+                Command::new("sh").arg("-c").arg(script).arg(&log),
+                timeout,
+                || std::fs::read_to_string(&log).map_or(false, |log| log.contains("ready")),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            sh(
+                "echo ready > \"$0\"; exec sleep 30",
+                Duration::from_secs(30)
+            ),
+            Watched::Done
+        );
+        std::fs::remove_file(&log).unwrap();
+        match sh("exit 3", Duration::from_secs(30)) {
+            Watched::Exited(status) => assert_eq!(status.code(), Some(3)),
+            watched => panic!("{:?}", watched),
+        }
+        let started = Instant::now();
+        assert_eq!(
+            sh("exec sleep 30", Duration::from_millis(200)),
+            Watched::TimedOut
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,81 @@
+use std::ffi::OsStr;
+use std::fmt::{Debug, Display};
+use std::path::Path;
+
+use crate::Result;
+
+/// Adds what was being operated on to an error, since e.g. [`std::io::Error`]s don't say which
+/// file or command they came from.
+pub trait Context<T> {
+    /// Prefixes the error with the path that was being operated on.
+    fn with_path_context<P: AsRef<Path>>(self, path: P) -> Result<T>;
+
+    /// Prefixes the error with the source and destination of e.g. a copy or rename.
+    fn with_paths_context<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> Result<T>;
+
+    /// Prefixes the error with the command (and its arguments) that failed to run.
+    fn with_cmd_context<C: AsRef<OsStr>, A: Debug>(self, cmd: C, args: A) -> Result<T>;
+}
+
+impl<T, E: Display> Context<T> for core::result::Result<T, E> {
+    fn with_path_context<P: AsRef<Path>>(self, path: P) -> Result<T> {
+        self.map_err(|e| format!("'{}': {}", path.as_ref().display(), e).into())
+    }
+
+    fn with_paths_context<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> Result<T> {
+        self.map_err(|e| {
+            format!(
+                "'{}' -> '{}': {}",
+                from.as_ref().display(),
+                to.as_ref().display(),
+                e
+            )
+            .into()
+        })
+    }
+
+    fn with_cmd_context<C: AsRef<OsStr>, A: Debug>(self, cmd: C, args: A) -> Result<T> {
+        self.map_err(|e| {
+            format!(
+                "failed to run `{}` with args `{:?}`: {}",
+                Path::new(cmd.as_ref()).display(),
+                args,
+                e
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::Context;
+
+    #[test]
+    fn test_context() {
+        let missing = Path::new("/nonexistent/file");
+
+        let err = fs::read(missing).with_path_context(missing).unwrap_err();
+        assert!(err.to_string().starts_with("'/nonexistent/file': "));
+
+        let err = fs::copy(missing, "/nonexistent/dest")
+            .with_paths_context(missing, "/nonexistent/dest")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("'/nonexistent/file' -> '/nonexistent/dest': "));
+
+        let err = Command::new(missing)
+            .args(["--flag"])
+            .status()
+            .with_cmd_context(missing, ["--flag"])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("failed to run `/nonexistent/file` with args `[\"--flag\"]`: "));
+    }
+}
@@ -0,0 +1,249 @@
+//! Reproducible staging trees (see `--deterministic-staging`): the files are written in a fixed
+//! order, and their mtimes are then fixed, so that two runs over the same inputs produce staging
+//! trees that are identical down to their metadata, and don't churn the snapshots of tools that
+//! back them up.
+
+#[cfg(unix)]
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::context::Context;
+use crate::Result;
+
+/// The environment variable with the mtime to give staged files, in seconds since the Unix epoch
+/// (see <https://reproducible-builds.org/specs/source-date-epoch/>).
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// The mtime to give staged files: `source_date_epoch` (the value of [`SOURCE_DATE_EPOCH`]), or the
+/// epoch if it isn't set.
+pub fn mtime(source_date_epoch: Option<&OsStr>) -> Result<i64> {
+    let source_date_epoch = match source_date_epoch {
+        Some(source_date_epoch) => source_date_epoch,
+        None => return Ok(0),
+    };
+
+    source_date_epoch
+        .to_str()
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|mtime| *mtime >= 0)
+        .ok_or_else(|| {
+            format!(
+                "{} '{}' is not a number of seconds since the Unix epoch",
+                SOURCE_DATE_EPOCH,
+                source_date_epoch.to_string_lossy()
+            )
+            .into()
+        })
+}
+
+/// Sets the mtime (and atime) of everything in the staging tree at `root`, including the
+/// symlinks themselves (rather than the store paths they point to) and `root`, to `mtime`.
+pub fn fix_mtimes(root: &Path, mtime: i64) -> Result<()> {
+    let mut children = fs::read_dir(root)
+        .with_path_context(root)?
+        .map(|entry| Ok(entry.with_path_context(root)?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    children.sort();
+
+    for child in children {
+        let file_type = fs::symlink_metadata(&child)
+            .with_path_context(&child)?
+            .file_type();
+
+        if file_type.is_dir() {
+            self::fix_mtimes(&child, mtime)?;
+        } else {
+            self::set_mtime(&child, mtime)?;
+        }
+    }
+
+    // Only once its children are done, although changing their times doesn't touch it anyway.
+    self::set_mtime(root, mtime)
+}
+
+#[cfg(unix)]
+fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).with_path_context(path)?;
+    let time = libc::timespec {
+        tv_sec: mtime as libc::time_t,
+        tv_nsec: 0,
+    };
+    let times = [time, time];
+
+    // SAFETY: `c_path` is a NUL-terminated string, and `times` has the two timestamps that
+    // `utimensat` reads, both of which outlive the call.
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).with_path_context(path);
+    }
+
+    Ok(())
+}
+
+/// Where there are no symlinks in the staging tree (see [`crate::util::symlink_or_copy`]), the
+/// files and directories themselves are opened to set their times.
+#[cfg(not(unix))]
+fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime as u64);
+    let mut options = fs::OpenOptions::new();
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        // FILE_WRITE_ATTRIBUTES is all it takes, and FILE_FLAG_BACKUP_SEMANTICS is what lets
+        // directories be opened at all.
+        options.access_mode(0x0100).custom_flags(0x0200_0000);
+    }
+    #[cfg(not(windows))]
+    options.write(true);
+
+    let file = options.open(path).with_path_context(path)?;
+    file.set_times(fs::FileTimes::new().set_accessed(time).set_modified(time))
+        .with_path_context(path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use bootspec::SystemConfigurationRoot;
+
+    use crate::bootable::{Bootable, BootableToplevel};
+    use crate::recompress::Compression;
+    use crate::systemd_boot;
+    use crate::target::Target;
+
+    fn mtime_of(metadata: &fs::Metadata) -> Duration {
+        metadata
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+    }
+
+    /// Every name in the tree at `root`, with its contents (or where it links to) and mtime.
+    fn hash_tree(root: &Path) -> String {
+        let mut lines = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let metadata = fs::symlink_metadata(&path).unwrap();
+                let contents = if metadata.file_type().is_symlink() {
+                    format!("-> {}", fs::read_link(&path).unwrap().display())
+                } else if metadata.is_dir() {
+                    dirs.push(path.clone());
+                    String::from("dir")
+                } else {
+                    crate::manifest::sha256_file(&path).unwrap()
+                };
+
+                lines.push(format!(
+                    "{} {} {:?}",
+                    path.strip_prefix(root).unwrap().display(),
+                    contents,
+                    self::mtime_of(&metadata)
+                ));
+            }
+        }
+        lines.push(format!(
+            "{:?}",
+            self::mtime_of(&fs::metadata(root).unwrap())
+        ));
+        lines.sort();
+
+        crate::manifest::sha256(lines.join("\n").as_bytes())
+    }
+
+    #[test]
+    fn test_mtime() {
+        assert_eq!(mtime(None).unwrap(), 0);
+        assert_eq!(mtime(Some(OsStr::new("1700000000"))).unwrap(), 1700000000);
+        assert!(mtime(Some(OsStr::new("yesterday"))).is_err());
+        assert!(mtime(Some(OsStr::new("-1"))).is_err());
+    }
+
+    #[test]
+    fn test_deterministic_staging() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        fs::write(toplevel.join("kernel"), "kernel").unwrap();
+        fs::write(toplevel.join("initrd"), "initrd").unwrap();
+
+        let source = |generation_index, profile: Option<&str>| BootableToplevel {
+            kernel: toplevel.join("kernel"),
+            initrd: toplevel.join("initrd"),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            generation_index,
+            profile_name: profile.map(ToString::to_string),
+            ..Default::default()
+        };
+        let bootables = vec![
+            Bootable::Linux(source(2, None)),
+            Bootable::Linux(source(1, None)),
+            Bootable::Linux(BootableToplevel {
+                initrd_compression: Compression::Gzip,
+                ..source(1, Some("work"))
+            }),
+        ];
+
+        let run = |name: &str| {
+            let target = Target {
+                name: String::from(name),
+                ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+            };
+            let out_dir = dir.join(name);
+            systemd_boot::generate_targets(
+                &bootables,
+                None,
+                None,
+                &out_dir,
+                &[target.clone()],
+                &[],
+            )
+            .unwrap();
+
+            let root = out_dir.join(name);
+            let planned = systemd_boot::plan(&bootables, "EFI/nixos").unwrap();
+            systemd_boot::write_manifest(&root, &planned, &[], &[], &target).unwrap();
+            fix_mtimes(&root, 1700000000).unwrap();
+
+            root
+        };
+
+        let (first, second) = (run("a"), run("b"));
+        assert_eq!(hash_tree(&first), hash_tree(&second));
+
+        let entry = first.join("loader/entries/nixos-work-generation-1.conf");
+        assert_eq!(
+            self::mtime_of(&fs::metadata(&entry).unwrap()),
+            Duration::from_secs(1700000000)
+        );
+        // the store paths that the tree links to are left alone
+        assert_ne!(
+            self::mtime_of(&fs::metadata(toplevel.join("kernel")).unwrap()),
+            Duration::from_secs(1700000000)
+        );
+    }
+}
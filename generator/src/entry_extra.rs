@@ -0,0 +1,237 @@
+//! Extra directives for entries, e.g. a `devicetree-overlay`, or a key that only a fork of
+//! systemd-boot understands (see `--entry-extra`). They're appended after the directives the
+//! generator writes itself, which they can't override.
+
+use crate::bootable::BootableToplevel;
+
+/// The directives the generator writes itself.
+const MANAGED_KEYS: &[&str] = &[
+    "title",
+    "version",
+    "sort-key",
+    "efi",
+    "linux",
+    "initrd",
+    "options",
+    "machine-id",
+];
+
+/// A directive to append to the entries that `selector` matches (or to every entry).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryExtra {
+    pub key: String,
+    pub value: String,
+    pub selector: Option<Selector>,
+}
+
+/// Which entries an [`EntryExtra`] is appended to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// The entries of a profile's generations (`system` for the system profile)
+    Profile(String),
+    /// The entries of a generation (of any profile)
+    Generation(usize),
+    /// The entries of a specialisation (of any generation)
+    Specialisation(String),
+}
+
+/// Parses an `--entry-extra` of the form `key=value[@selector]`, where the selector is one of
+/// `profile:<name>`, `generation:<number>`, or `specialisation:<name>`.
+///
+/// Values can't contain '@', which would be taken for the start of a selector.
+pub fn parse_entry_extra(s: &str) -> Result<EntryExtra, String> {
+    let (directive, selector) = match s.split_once('@') {
+        Some((directive, selector)) => (directive, Some(self::parse_selector(selector)?)),
+        None => (s, None),
+    };
+    let (key, value) = directive
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form key=value[@selector]", s))?;
+
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "'{}' is not a directive key (it must be non-empty and only contain ASCII letters, digits, '-', and '_')",
+            key
+        ));
+    }
+    if MANAGED_KEYS.contains(&key) {
+        return Err(format!(
+            "'{}' is written by the generator itself, and can't be added with --entry-extra",
+            key
+        ));
+    }
+    if value.trim().is_empty() || value.chars().any(char::is_control) {
+        return Err(format!(
+            "the value of '{}' must be non-empty and can't contain control characters",
+            key
+        ));
+    }
+
+    Ok(EntryExtra {
+        key: key.to_string(),
+        value: value.trim().to_string(),
+        selector,
+    })
+}
+
+fn parse_selector(s: &str) -> Result<Selector, String> {
+    let selector = match s.split_once(':') {
+        Some(("profile", profile)) if !profile.is_empty() => Selector::Profile(profile.to_string()),
+        Some(("generation", generation)) => generation
+            .parse()
+            .map(Selector::Generation)
+            .map_err(|_| format!("'{}' is not a generation number", generation))?,
+        Some(("specialisation", specialisation)) if !specialisation.is_empty() => {
+            Selector::Specialisation(specialisation.to_string())
+        }
+        _ => {
+            return Err(format!(
+                "'{}' is not a selector (expected 'profile:<name>', 'generation:<number>', or \
+                 'specialisation:<name>')",
+                s
+            ))
+        }
+    };
+
+    Ok(selector)
+}
+
+impl EntryExtra {
+    /// Whether the directive goes in the entry for `toplevel`.
+    pub fn matches(&self, toplevel: &BootableToplevel) -> bool {
+        match &self.selector {
+            None => true,
+            Some(Selector::Profile(profile)) => {
+                toplevel.profile_name.as_deref().unwrap_or("system") == profile
+            }
+            Some(Selector::Generation(generation)) => toplevel.generation_index == *generation,
+            Some(Selector::Specialisation(specialisation)) => {
+                toplevel.specialisation_name.as_ref().map(|s| &s.0) == Some(specialisation)
+            }
+        }
+    }
+
+    /// The directive, as it's written in entries.
+    pub fn line(&self) -> String {
+        format!("{} {}", self.key, self.value)
+    }
+}
+
+/// The lines of the `entry_extras` that go in the entry for `toplevel`, in the order they were
+/// given.
+pub fn lines(entry_extras: &[EntryExtra], toplevel: &BootableToplevel) -> Vec<String> {
+    entry_extras
+        .iter()
+        .filter(|extra| extra.matches(toplevel))
+        .map(EntryExtra::line)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bootspec::SpecialisationName;
+
+    #[test]
+    fn test_parse_entry_extra() {
+        assert_eq!(
+            parse_entry_extra("devicetree-overlay=/dtbs/uart.dtbo"),
+            Ok(EntryExtra {
+                key: String::from("devicetree-overlay"),
+                value: String::from("/dtbs/uart.dtbo"),
+                selector: None,
+            })
+        );
+        assert_eq!(
+            parse_entry_extra("x_vendor=a=b c@profile:work").unwrap(),
+            EntryExtra {
+                key: String::from("x_vendor"),
+                value: String::from("a=b c"),
+                selector: Some(Selector::Profile(String::from("work"))),
+            }
+        );
+        assert_eq!(
+            parse_entry_extra("architecture=x64@generation:42")
+                .unwrap()
+                .selector,
+            Some(Selector::Generation(42))
+        );
+        assert_eq!(
+            parse_entry_extra("architecture=x64@specialisation:gui")
+                .unwrap()
+                .selector,
+            Some(Selector::Specialisation(String::from("gui")))
+        );
+
+        for arg in [
+            "devicetree-overlay",
+            "=x",
+            "key=",
+            "key= ",
+            "a b=x",
+            "key=a\nlinux /evil.efi",
+            "key=x@",
+            "key=x@profile:",
+            "key=x@generation:latest",
+            "key=x@work",
+            "key=x@user:alice",
+        ] {
+            assert!(parse_entry_extra(arg).is_err(), "{:?}", arg);
+        }
+
+        // the generator's own directives can't be overridden
+        for key in MANAGED_KEYS {
+            let err = parse_entry_extra(&format!("{}=x", key)).unwrap_err();
+            assert!(err.contains("generator itself"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let toplevel = |profile: Option<&str>, generation_index, specialisation: Option<&str>| {
+            BootableToplevel {
+                profile_name: profile.map(ToString::to_string),
+                generation_index,
+                specialisation_name: specialisation.map(|s| SpecialisationName(s.to_string())),
+                ..Default::default()
+            }
+        };
+        let toplevels = [
+            toplevel(None, 1, None),
+            toplevel(None, 2, None),
+            toplevel(None, 2, Some("gui")),
+            toplevel(Some("work"), 1, None),
+        ];
+        let matching = |arg: &str| {
+            let extra = parse_entry_extra(arg).unwrap();
+            toplevels
+                .iter()
+                .map(|toplevel| extra.matches(toplevel))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(matching("k=v"), [true, true, true, true]);
+        assert_eq!(matching("k=v@profile:system"), [true, true, true, false]);
+        assert_eq!(matching("k=v@profile:work"), [false, false, false, true]);
+        assert_eq!(matching("k=v@generation:1"), [true, false, false, true]);
+        assert_eq!(matching("k=v@generation:2"), [false, true, true, false]);
+        assert_eq!(
+            matching("k=v@specialisation:gui"),
+            [false, false, true, false]
+        );
+        assert_eq!(matching("k=v@profile:other"), [false, false, false, false]);
+
+        let extras = ["a=1", "b=2@profile:work", "c=3@generation:1"]
+            .iter()
+            .map(|arg| parse_entry_extra(arg).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines(&extras, &toplevels[0]), ["a 1", "c 3"]);
+        assert_eq!(lines(&extras, &toplevels[3]), ["a 1", "b 2", "c 3"]);
+        assert!(lines(&[], &toplevels[0]).is_empty());
+    }
+}
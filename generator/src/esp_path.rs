@@ -0,0 +1,160 @@
+//! Paths of files on the ESP, which are checked against what FAT (and the firmwares that read it)
+//! can cope with when they're made, so that a profile or specialisation name can't end up in the
+//! name of a file that can't be written, or that the firmware can't find.
+//!
+//! Paths on the ESP are always separated by `/`, whatever the host the generator runs on; they're
+//! only turned into paths on the host (with its separator) where a file is written or read, with
+//! [`join`].
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// Characters that FAT doesn't allow in a name (along with control characters); `/` separates
+/// components.
+const INVALID_CHARS: &[char] = &[':', '*', '?', '"', '<', '>', '|', '\\'];
+/// The longest a component may be, in bytes.
+const MAX_COMPONENT_LEN: usize = 255;
+/// The most components a path may have; some firmwares can't find files nested any deeper.
+const MAX_DEPTH: usize = 8;
+
+/// The path of a file relative to the root of the ESP (e.g. `EFI/nixos/...`), which is known to be
+/// valid on FAT.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EspRelativePath(String);
+
+impl EspRelativePath {
+    /// Checks `path` (which may have the leading `/` that entries write paths with) and makes an
+    /// `EspRelativePath` of it.
+    pub fn new(path: &str) -> Result<Self> {
+        let relative = path.strip_prefix('/').unwrap_or(path);
+
+        let components = relative.split('/').collect::<Vec<_>>();
+        if components.len() > MAX_DEPTH {
+            return Err(format!(
+                "'{}' is nested too deeply for the ESP ({} components, at most {})",
+                path,
+                components.len(),
+                MAX_DEPTH
+            )
+            .into());
+        }
+
+        for component in components {
+            if component.is_empty() || component == "." || component == ".." {
+                return Err(format!("'{}' has an empty, '.', or '..' component", path).into());
+            }
+            if component.len() > MAX_COMPONENT_LEN {
+                return Err(format!(
+                    "'{}' has a component longer than {} bytes",
+                    path, MAX_COMPONENT_LEN
+                )
+                .into());
+            }
+            if let Some(c) = component
+                .chars()
+                .find(|c| INVALID_CHARS.contains(c) || c.is_control())
+            {
+                return Err(format!("'{}' has {:?}, which FAT doesn't allow", path, c).into());
+            }
+        }
+
+        Ok(Self(relative.to_string()))
+    }
+
+    /// The path, relative to the root of the ESP.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The path as entries write it, with a leading `/`.
+    pub fn entry_path(&self) -> String {
+        format!("/{}", self.0)
+    }
+
+    /// The last component of the path.
+    pub fn file_name(&self) -> &str {
+        self.0.rsplit('/').next().unwrap_or(&self.0)
+    }
+}
+
+/// Where the file at `esp_path` (relative to the root of the ESP, e.g. `loader/entries`, or an
+/// [`EspRelativePath`]'s [`as_str`](EspRelativePath::as_str)) is in the ESP or staging tree at
+/// `root`, joined a component at a time, so that the path has the host's separators throughout.
+pub fn join(root: &Path, esp_path: &str) -> PathBuf {
+    esp_path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .fold(root.to_path_buf(), |path, component| path.join(component))
+}
+
+impl fmt::Display for EspRelativePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_esp_relative_path() {
+        let path = EspRelativePath::new("/EFI/nixos/x.efi").unwrap();
+        assert_eq!(path.as_str(), "EFI/nixos/x.efi");
+        assert_eq!(path.entry_path(), "/EFI/nixos/x.efi");
+        assert_eq!(path.file_name(), "x.efi");
+        assert_eq!(path, EspRelativePath::new("EFI/nixos/x.efi").unwrap());
+        assert_eq!(
+            join(Path::new("out"), path.as_str()),
+            Path::new("out").join("EFI").join("nixos").join("x.efi")
+        );
+
+        for invalid in [
+            "",
+            "/",
+            "EFI//x.efi",
+            "EFI/../x.efi",
+            "EFI/./x.efi",
+            "EFI/nixos/",
+            "loader/entries/nixos-a:b.conf",
+            "loader/entries/nixos-a*.conf",
+            "loader/entries/nixos-a?.conf",
+            "loader/entries/nixos-\"a\".conf",
+            "loader/entries/nixos-<a>.conf",
+            "loader/entries/nixos-a|b.conf",
+            "loader/entries/nixos-a\\b.conf",
+            "loader/entries/nixos-a\nb.conf",
+            "a/b/c/d/e/f/g/h/i.efi",
+        ] {
+            assert!(EspRelativePath::new(invalid).is_err(), "{:?}", invalid);
+        }
+        assert!(EspRelativePath::new("a/b/c/d/e/f/g/h.efi").is_ok());
+        assert!(EspRelativePath::new(&format!("EFI/{}", "x".repeat(255))).is_ok());
+        assert!(EspRelativePath::new(&format!("EFI/{}", "x".repeat(256))).is_err());
+    }
+
+    #[test]
+    fn test_join() {
+        let root = Path::new("out");
+        let expected = root.join("loader").join("entries");
+        assert_eq!(join(root, "loader/entries"), expected);
+        assert_eq!(join(root, "/loader/entries/"), expected);
+        assert_eq!(join(root, ""), root);
+
+        // every separator is the host's, never a `/` in the middle of a component
+        let joined = join(root, "EFI/nixos/kernel.efi");
+        assert_eq!(
+            joined
+                .components()
+                .map(|c| c.as_os_str().to_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["out", "EFI", "nixos", "kernel.efi"]
+        );
+        assert_eq!(
+            joined.to_str().unwrap(),
+            ["out", "EFI", "nixos", "kernel.efi"].join(&std::path::MAIN_SEPARATOR.to_string())
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! `--facts`: what the installer found out about the machine on its last successful run (e.g. the
+//! machine-id, or where the ESP's files go), so that it doesn't have to be found out or passed
+//! again. The facts are only defaults: the options that were passed win.
+
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::Path;
+
+use log::debug;
+
+use crate::context::Context;
+use crate::report::Document;
+pub use crate::report::Facts;
+use crate::systemd_boot;
+use crate::target;
+use crate::Result;
+
+/// The options that facts are the defaults of.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Options {
+    pub machine_id: Option<String>,
+    pub entry_path_prefix: Option<String>,
+}
+
+impl Options {
+    /// Fills in the options that weren't passed from `facts` (if there are any), which are checked
+    /// like the options would be.
+    pub fn with_defaults(self, facts: Option<&Facts>) -> Result<Self> {
+        let facts = match facts {
+            Some(facts) => facts,
+            None => return Ok(self),
+        };
+
+        let machine_id = match (self.machine_id, &facts.machine_id) {
+            (Some(machine_id), _) => Some(machine_id),
+            (None, Some(machine_id)) => Some(systemd_boot::parse_machine_id(machine_id)?),
+            (None, None) => None,
+        };
+        let entry_path_prefix = match (self.entry_path_prefix, &facts.entry_path_prefix) {
+            (Some(prefix), _) => Some(prefix),
+            (None, Some(prefix)) => Some(target::parse_entry_path_prefix(prefix)?),
+            (None, None) => None,
+        };
+
+        Ok(Options {
+            machine_id,
+            entry_path_prefix,
+        })
+    }
+}
+
+impl Facts {
+    /// Reads the facts at `path`, if the installer wrote any there yet.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("no facts at '{}' yet", path.display());
+                return Ok(None);
+            }
+            Err(e) => return Err(e).with_path_context(path),
+        };
+
+        Ok(Some(Facts::from_json(&contents).with_path_context(path)?))
+    }
+
+    /// Writes the facts to `path` (creating the directory it's in), atomically: a reader sees
+    /// either the previous facts (if any) or these.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir).with_path_context(dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(dir).with_path_context(dir)?;
+
+        serde_json::to_writer_pretty(&mut tmp, self).with_path_context(path)?;
+        writeln!(tmp).with_path_context(path)?;
+        tmp.persist(path).with_path_context(path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use bootspec::SystemConfigurationRoot;
+
+    use crate::bootable::{Bootable, BootableToplevel};
+    use crate::target::Target;
+
+    const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    fn facts() -> Facts {
+        Facts {
+            format_version: Facts::FORMAT_VERSION,
+            installer_version: String::from("0.1.0"),
+            machine_id: Some(String::from(MACHINE_ID)),
+            efi_arch: Some(String::from("x64")),
+            esps: vec![PathBuf::from("/boot")],
+            xbootldr: None,
+            entry_path_prefix: Some(String::from("nixos")),
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("nixos-bootloader/facts.json");
+        assert_eq!(Facts::load(&path).unwrap(), None);
+
+        facts().write(&path).unwrap();
+        assert_eq!(Facts::load(&path).unwrap(), Some(facts()));
+
+        fs::write(&path, "{").unwrap();
+        assert!(Facts::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_with_defaults() {
+        let passed = Options {
+            machine_id: Some(String::from("fedcba9876543210fedcba9876543210")),
+            entry_path_prefix: Some(String::from("boot")),
+        };
+        let from_facts = Options {
+            machine_id: Some(String::from(MACHINE_ID)),
+            entry_path_prefix: Some(String::from("nixos")),
+        };
+
+        // without facts
+        assert_eq!(
+            Options::default().with_defaults(None).unwrap(),
+            Options::default()
+        );
+        assert_eq!(passed.clone().with_defaults(None).unwrap(), passed);
+
+        // with facts
+        assert_eq!(
+            Options::default().with_defaults(Some(&facts())).unwrap(),
+            from_facts
+        );
+        assert_eq!(
+            Options::default()
+                .with_defaults(Some(&Facts::default()))
+                .unwrap(),
+            Options::default()
+        );
+
+        // partially overridden
+        let options = Options {
+            entry_path_prefix: passed.entry_path_prefix.clone(),
+            ..Options::default()
+        };
+        assert_eq!(
+            options.with_defaults(Some(&facts())).unwrap(),
+            Options {
+                machine_id: from_facts.machine_id,
+                entry_path_prefix: passed.entry_path_prefix.clone(),
+            }
+        );
+        assert_eq!(
+            passed.clone().with_defaults(Some(&facts())).unwrap(),
+            passed
+        );
+
+        // facts are checked like the options
+        for facts in [
+            Facts {
+                machine_id: Some(String::from("not-a-machine-id")),
+                ..facts()
+            },
+            Facts {
+                entry_path_prefix: Some(String::from("../boot")),
+                ..facts()
+            },
+        ] {
+            assert!(Options::default().with_defaults(Some(&facts)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_generate_with_facts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        fs::write(toplevel.join("kernel"), "kernel").unwrap();
+        fs::write(toplevel.join("initrd"), "initrd").unwrap();
+        let bootables = vec![Bootable::Linux(BootableToplevel {
+            kernel: toplevel.join("kernel"),
+            initrd: toplevel.join("initrd"),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            generation_index: 1,
+            ..Default::default()
+        })];
+
+        let generate = |name: &str, options: Options, facts: Option<&Facts>| {
+            let options = options.with_defaults(facts).unwrap();
+            let target = Target {
+                name: String::from(name),
+                ..Target::local(options.machine_id.unwrap_or_default())
+                    .with_entry_path_prefix(&options.entry_path_prefix.unwrap_or_default())
+            };
+            systemd_boot::generate_targets(&bootables, None, None, dir, &[target], &[]).unwrap();
+
+            fs::read_to_string(
+                dir.join(name)
+                    .join("loader/entries/nixos-generation-1.conf"),
+            )
+            .unwrap()
+        };
+
+        let entry = generate("absent", Options::default(), None);
+        assert!(!entry.contains("machine-id"), "{}", entry);
+        assert!(entry.contains("\nlinux /EFI/nixos/"), "{}", entry);
+
+        let entry = generate("present", Options::default(), Some(&facts()));
+        assert!(
+            entry.contains(&format!("\nmachine-id {}\n", MACHINE_ID)),
+            "{}",
+            entry
+        );
+        assert!(entry.contains("\nlinux /nixos/EFI/nixos/"), "{}", entry);
+
+        let options = Options {
+            entry_path_prefix: Some(String::from("boot")),
+            ..Options::default()
+        };
+        let entry = generate("overridden", options, Some(&facts()));
+        assert!(
+            entry.contains(&format!("\nmachine-id {}\n", MACHINE_ID)),
+            "{}",
+            entry
+        );
+        assert!(entry.contains("\nlinux /boot/EFI/nixos/"), "{}", entry);
+    }
+}
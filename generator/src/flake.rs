@@ -0,0 +1,121 @@
+//! Bootspec documents for toplevels built out of tree, e.g. with `nix build
+//! .#nixosConfigurations.foo.config.system.build.toplevel`, which don't have a profile (or a
+//! generation number) to be read from.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use bootspec::BootJson;
+
+use crate::command;
+use crate::Result;
+
+/// The arguments `nix` is run with to build `installable` and print what it built.
+pub fn build_args(installable: &str) -> Vec<&str> {
+    vec!["build", "--no-link", "--print-out-paths", installable]
+}
+
+/// Builds `installable` with `nix` and returns the paths it built.
+pub fn build(nix: &Path, installable: &str) -> Result<Vec<PathBuf>> {
+    let args = self::build_args(installable);
+    let output = command::output(Command::new(nix).args(&args))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "`{} {}` failed ({}): {}",
+            nix.display(),
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let out_paths = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if out_paths.is_empty() {
+        return Err(format!(
+            "`{} {}` didn't build anything",
+            nix.display(),
+            args.join(" ")
+        )
+        .into());
+    }
+
+    Ok(out_paths)
+}
+
+/// The bootspec documents of the toplevels `installable` builds to (synthesized, if they don't
+/// have one), along with their paths.
+pub fn bootspecs(nix: &Path, installable: &str) -> Result<Vec<(PathBuf, BootJson)>> {
+    self::build(nix, installable)?
+        .into_iter()
+        .map(|toplevel| Ok((toplevel.clone(), crate::get_json(toplevel)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A `nix` that logs its arguments and prints `out_path` (if it succeeds).
+    fn stub_nix(dir: &Path, out_path: &Path, status: i32) -> PathBuf {
+        let nix = dir.join("nix");
+        fs::write(
+            &nix,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {log}\necho {out_path}\necho 'oh no' >&2\nexit {status}\n",
+                log = dir.join("nix.log").display(),
+                out_path = out_path.display(),
+                status = status,
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&nix, fs::Permissions::from_mode(0o755)).unwrap();
+
+        nix
+    }
+
+    fn write_toplevel(toplevel: &Path) {
+        fs::create_dir_all(toplevel).unwrap();
+        for (name, contents) in [
+            ("kernel", ""),
+            ("initrd", ""),
+            ("init", ""),
+            ("nixos-version", "23.05"),
+            ("system", "x86_64-linux"),
+            ("kernel-params", "quiet"),
+        ] {
+            fs::write(toplevel.join(name), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bootspecs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        self::write_toplevel(&toplevel);
+        let installable = ".#nixosConfigurations.foo.config.system.build.toplevel";
+
+        let nix = self::stub_nix(dir, &toplevel, 0);
+        let bootspecs = bootspecs(&nix, installable).unwrap();
+        assert_eq!(bootspecs.len(), 1);
+        assert_eq!(bootspecs[0].0, toplevel);
+        assert_eq!(bootspecs[0].1.kernel_params, vec![String::from("quiet")]);
+        assert_eq!(
+            fs::read_to_string(dir.join("nix.log")).unwrap(),
+            format!("build --no-link --print-out-paths {}\n", installable)
+        );
+
+        let nix = self::stub_nix(dir, &toplevel, 1);
+        let err = bootspecs(&nix, installable).unwrap_err().to_string();
+        assert!(err.contains("failed"), "{}", err);
+        assert!(err.contains("oh no"), "{}", err);
+    }
+}
@@ -1,62 +1,504 @@
-use crate::{BootJson, Result};
+//! GRUB menu entries for the same [`Bootable`]s the systemd-boot entries are generated for, as a
+//! `grub.cfg` fragment for the installer (or the real `grub.cfg`, with `source`) to include.
+//!
+//! Like what `install-grub.pl` writes, the default generation gets an entry of its own, `NixOS -
+//! Default`, which is the only one marked `--unrestricted`: with GRUB users set up, it boots without
+//! a password, but the others don't. Then every generation gets an entry (newest first, the system
+//! profile's before the other profiles'), followed by a submenu with the entries of its
+//! specialisations, if it has any.
+//!
+//! The default generation is the newest of the system profile, which is what the system profile
+//! points to unless it was rolled back: the installer, which knows the current configuration,
+//! makes that one the default with [`with_default`].
+//!
+//! The kernels and initrds are booted from the store, so the fragment expects GRUB's `root` to be
+//! the filesystem the store is on (e.g. set with `search` before it's included).
+//!
+//! Each generation's entries (and submenu) are preceded by a [`MARKER`] comment naming it, so that
+//! the installer can leave out the generations it doesn't keep (see [`retain_generations`]).
+//!
+//! The kernel params are the generations' own, collapsed like systemd-boot's with
+//! `--dedupe-kernel-params`. The `--target-spec` targets' extra kernel params aren't added: the
+//! fragment is the local machine's.
 
-// FIXME: placeholder dir
-const ROOT: &str = "grub-entries";
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
 
-pub fn entry(json: &BootJson, generation: usize, profile: &Option<String>) -> Result<()> {
-    entry_impl(json, generation, profile, None)?;
+use crate::bootable::{Bootable, BootableToplevel};
+use crate::cmdline;
+use crate::context::Context;
+use crate::util;
+use crate::Result;
 
-    Ok(())
+// FIXME: placeholder dir
+pub const ROOT: &str = "grub-entries";
+/// The name of the fragment in [`ROOT`].
+pub const FRAGMENT: &str = "grub.cfg";
+/// What the comment in front of a generation's entries starts with, followed by its index and then
+/// (for a profile other than the system profile) the profile's name, e.g. `# nixos-generation 3
+/// work`.
+pub const MARKER: &str = "# nixos-generation ";
+/// What the comment in front of the default entry starts with, followed by the index of the
+/// generation (of the system profile) it boots, e.g. `# nixos-default 3`.
+pub const DEFAULT_MARKER: &str = "# nixos-default ";
+/// The title of the default entry.
+const DEFAULT_TITLE: &str = "NixOS - Default";
+/// How far each level of `submenu` is indented.
+const INDENT: &str = "  ";
+
+/// Writes the fragment with the entries of `bootables` to [`ROOT`]. With `dedupe_kernel_params`,
+/// their kernel params are deduplicated, keeping every one of the params it names (see
+/// `--dedupe-kernel-params-exclude`).
+pub fn generate(bootables: &[Bootable], dedupe_kernel_params: Option<&[String]>) -> Result<()> {
+    self::generate_in(Path::new(self::ROOT), bootables, dedupe_kernel_params)
 }
 
-fn entry_impl(
-    json: &BootJson,
-    generation: usize,
-    profile: &Option<String>,
-    specialisation: Option<&str>,
+/// Writes the fragment with the entries of `bootables` to `root` (see [`generate`]).
+pub fn generate_in(
+    root: &Path,
+    bootables: &[Bootable],
+    dedupe_kernel_params: Option<&[String]>,
 ) -> Result<()> {
-    let _ = (json, generation, profile, specialisation);
-    // TODO: UUID can be retrieved from `lsblk -no UUID {device path}` or `findmnt --first-only --noheadings --output UUID /boot`
-    // TODO: support the xen stuff
-
-    // schema: default entry has `- Default` in name and `--unrestricted`
-    // what install-grub.pl does: create default entry: `"NixOS - Default" --unrestricted`
-    // then create entries for all specialisations: "NixOS - (specialisation - {date} - {version})"
-    // then submenu for all generations: "NixOS - Generation {i} ({date} - {version})" -- notably, no specialisations for prior generations?
-    let data = format!(
-        r#"menuentry "NixOS{}
-        "#,
-        "asdf"
+    let fragment = self::fragment(bootables, dedupe_kernel_params)?;
+    let path = root.join(self::FRAGMENT);
+
+    fs::create_dir_all(root).with_path_context(root)?;
+    fs::write(&path, fragment).with_path_context(&path)
+}
+
+/// The fragment with the entries of `bootables`. A toplevel that's there both as a kernel and
+/// initrd and as a unified EFI file only gets one entry, since GRUB boots it from the store either
+/// way.
+pub fn fragment(bootables: &[Bootable], dedupe_kernel_params: Option<&[String]>) -> Result<String> {
+    // By profile (the system profile first), and generation (newest first), and then by
+    // specialisation and variant (the generation itself, and then its variants, first).
+    let mut generations = BTreeMap::<_, BTreeMap<_, &BootableToplevel>>::new();
+    for toplevel in bootables.iter().map(Bootable::toplevel) {
+        generations
+            .entry((
+                toplevel.profile_name.as_deref(),
+                Reverse(toplevel.generation_index),
+            ))
+            .or_default()
+            .entry((
+                toplevel.specialisation_name.as_ref().map(|s| s.0.as_str()),
+                toplevel.variant_name.as_deref(),
+            ))
+            .or_insert(toplevel);
+    }
+
+    let mut fragment = String::new();
+
+    let default = generations
+        .iter()
+        .find(|((profile, _), _)| profile.is_none())
+        .and_then(|((_, Reverse(generation)), toplevels)| {
+            Some((generation, toplevels.get(&(None, None))?))
+        });
+    if let Some((generation, default)) = default {
+        writeln!(fragment, "{}{}", DEFAULT_MARKER, generation)?;
+        self::write_entry(
+            &mut fragment,
+            "",
+            DEFAULT_TITLE,
+            default,
+            true,
+            dedupe_kernel_params,
+        )?;
+    }
+
+    for ((profile, Reverse(generation)), toplevels) in &generations {
+        match profile {
+            Some(profile) => writeln!(fragment, "{}{} {}", MARKER, generation, profile)?,
+            None => writeln!(fragment, "{}{}", MARKER, generation)?,
+        }
+
+        let profile = match profile {
+            Some(profile) => format!(" - Profile {}", profile),
+            None => String::new(),
+        };
+
+        let (specialisations, toplevels): (Vec<_>, Vec<_>) = toplevels
+            .iter()
+            .partition(|((specialisation, _), _)| specialisation.is_some());
+
+        for (_, toplevel) in toplevels {
+            let title = format!("{}{} - {}", toplevel.title(), profile, toplevel.version()?);
+            self::write_entry(
+                &mut fragment,
+                "",
+                &title,
+                toplevel,
+                false,
+                dedupe_kernel_params,
+            )?;
+        }
+
+        if specialisations.is_empty() {
+            continue;
+        }
+
+        let title = format!(
+            "NixOS{} - Generation {} - Specialisations",
+            profile, generation
+        );
+        writeln!(fragment, "submenu {} {{", self::quote(&title))?;
+        for (_, toplevel) in specialisations {
+            let title = format!("{}{} - {}", toplevel.title(), profile, toplevel.version()?);
+            self::write_entry(
+                &mut fragment,
+                INDENT,
+                &title,
+                toplevel,
+                false,
+                dedupe_kernel_params,
+            )?;
+        }
+        writeln!(fragment, "}}")?;
+    }
+
+    Ok(fragment)
+}
+
+/// The generation (and profile, if it isn't the system profile's) that the [`MARKER`] `line` names.
+pub fn parse_marker(line: &str) -> Option<(usize, Option<&str>)> {
+    let mut words = line.strip_prefix(MARKER)?.split(' ');
+    let generation = words.next()?.parse().ok()?;
+
+    match (words.next(), words.next()) {
+        (None, _) => Some((generation, None)),
+        (Some(profile), None) if !profile.is_empty() => Some((generation, Some(profile))),
+        _ => None,
+    }
+}
+
+/// `fragment` with only the entries of the generations `keep` returns true for (by index and
+/// profile). What comes before the first [`MARKER`] (the default entry) is always kept.
+pub fn retain_generations(fragment: &str, keep: impl Fn(usize, Option<&str>) -> bool) -> String {
+    let mut retained = String::new();
+    let mut keeping = true;
+    for line in fragment.split_inclusive('\n') {
+        if let Some((generation, profile)) = self::parse_marker(line.trim_end()) {
+            keeping = keep(generation, profile);
+        }
+        if keeping {
+            retained.push_str(line);
+        }
+    }
+
+    retained
+}
+
+/// `fragment` with generation `generation` of the system profile as its default entry (see
+/// [`DEFAULT_MARKER`]), e.g. the one the system profile was rolled back to, instead of the newest.
+/// The default entry is a copy of the generation's own, retitled and marked `--unrestricted`.
+pub fn with_default(fragment: &str, generation: usize) -> Result<String> {
+    let mut lines = fragment.split_inclusive('\n');
+    lines
+        .by_ref()
+        .find(|line| self::parse_marker(line.trim_end()) == Some((generation, None)))
+        .ok_or_else(|| format!("generation {} has no GRUB entries", generation))?;
+
+    // The generation's own entry comes first, before the submenu with its specialisations'.
+    let mut default = format!(
+        "{}{}\nmenuentry {} --unrestricted {{\n",
+        DEFAULT_MARKER,
+        generation,
+        self::quote(DEFAULT_TITLE)
     );
+    match lines.next() {
+        Some(line) if line.starts_with("menuentry ") => {}
+        _ => return Err(format!("generation {} has no GRUB entry of its own", generation).into()),
+    }
+    for line in lines {
+        default.push_str(line);
+        if line.trim_end() == "}" {
+            break;
+        }
+    }
+
+    // The default entry that was there is replaced: it's everything up to the first generation's
+    // marker.
+    let mut with_default = String::new();
+    let mut replaced = false;
+    let mut replacing = false;
+    for line in fragment.split_inclusive('\n') {
+        if line.starts_with(DEFAULT_MARKER) {
+            with_default.push_str(&default);
+            replaced = true;
+            replacing = true;
+        } else if self::parse_marker(line.trim_end()).is_some() {
+            replacing = false;
+        }
+
+        if !replacing {
+            with_default.push_str(line);
+        }
+    }
+    if !replaced {
+        with_default.insert_str(0, &default);
+    }
+
+    Ok(with_default)
+}
+
+/// Appends the `menuentry` titled `title` that boots `toplevel` to `fragment`, indented by
+/// `indent`.
+fn write_entry(
+    fragment: &mut String,
+    indent: &str,
+    title: &str,
+    toplevel: &BootableToplevel,
+    unrestricted: bool,
+    dedupe_kernel_params: Option<&[String]>,
+) -> Result<()> {
+    let options = if unrestricted { " --unrestricted" } else { "" };
+    writeln!(
+        fragment,
+        "{}menuentry {}{} {{",
+        indent,
+        self::quote(title),
+        options
+    )?;
+
+    let init = format!("init={}", util::utf8(&toplevel.init)?);
+    let params = std::iter::once(&init)
+        .chain(&toplevel.kernel_params)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let params = match dedupe_kernel_params {
+        Some(exclude) => cmdline::dedupe(&params, exclude).0,
+        None => params,
+    };
+    let mut linux = self::quote(util::utf8(&toplevel.kernel)?);
+    for param in params {
+        linux.push(' ');
+        linux.push_str(&self::quote(param));
+    }
+    writeln!(fragment, "{}{}linux {}", indent, INDENT, linux)?;
+    writeln!(
+        fragment,
+        "{}{}initrd {}",
+        indent,
+        INDENT,
+        self::quote(util::utf8(&toplevel.initrd)?)
+    )?;
 
-    let _ = (data, ROOT);
+    writeln!(fragment, "{}}}", indent)?;
 
     Ok(())
 }
 
-// Generate the entries, but have the installer create the overall grub.cfg
-// write to grub.entries file, pass that to the installer?
-/*
-fn grub_entry(json: &BootJson) {
-    let data = format!(
-        r#"menuentry "NixOS - {profile}" {options} {{
-{search}
-@extraPerEntryConfig@
-multiboot {{xen}} {{xenparams}} if xen
-module {{kernel}} if xen
-module {{initrd}} if xen
-linux {linux} {params}
-initrd {initrd}
-}}
-"#,
-        profile = "Default",
-        options = "--unrestricted",
-        search = "--set=drive1 --fs-uuid ASJD-NLSA",
-        linux = json.kernel,
-        params = json.kernel_params.join(" "),
-        initrd = json.initrd,
-    );
+/// `word` as a single word of `grub.cfg`. GRUB's syntax is like sh's, so a word with anything in
+/// it that GRUB would expand or split on (e.g. `$` or a space) is single-quoted, and a `'` in it
+/// is closed, escaped, and reopened.
+fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use bootspec::{SpecialisationName, SystemConfigurationRoot};
+
+    use crate::bootable::EfiProgram;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("quiet"), "quiet");
+        assert_eq!(quote("console=ttyS0,115200"), "console=ttyS0,115200");
+        assert_eq!(
+            quote("/nix/store/abcd-linux/bzImage"),
+            "/nix/store/abcd-linux/bzImage"
+        );
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("root=$root"), "'root=$root'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_generate() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = |profile: Option<&str>, generation: usize, specialisation: Option<&str>| {
+            let path = dir.join(format!(
+                "{}-{}",
+                generation,
+                specialisation.unwrap_or("system")
+            ));
+            fs::create_dir_all(&path).unwrap();
+
+            BootableToplevel {
+                label: String::from("23.05"),
+                kernel: PathBuf::from("/nix/store/kkkk-linux/bzImage"),
+                kernel_params: vec![String::from("quiet"), String::from("x=$y z")],
+                init: PathBuf::from(format!("/nix/store/{}-system/init", generation)),
+                initrd: PathBuf::from("/nix/store/iiii-initrd/initrd"),
+                toplevel: SystemConfigurationRoot(path),
+                specialisation_name: specialisation.map(|s| SpecialisationName(s.to_string())),
+                generation_index: generation,
+                profile_name: profile.map(ToString::to_string),
+                ..Default::default()
+            }
+        };
+        let bootables = vec![
+            Bootable::Linux(toplevel(None, 1, None)),
+            Bootable::Linux(toplevel(Some("work"), 3, None)),
+            Bootable::Linux(toplevel(None, 2, Some("gui"))),
+            Bootable::Linux(toplevel(None, 2, None)),
+            // the same toplevel, as a unified EFI file
+            Bootable::Efi(EfiProgram::new(toplevel(None, 2, None))),
+        ];
+
+        let root = dir.join("grub-entries");
+        generate_in(&root, &bootables, None).unwrap();
+        let fragment = fs::read_to_string(root.join(FRAGMENT)).unwrap();
+
+        let version = toplevel(None, 1, None).version().unwrap();
+        let date = version.rsplit("Built on ").next().unwrap();
+        let entry = |indent: &str, generation: usize| {
+            format!(
+                "{indent}  linux /nix/store/kkkk-linux/bzImage init=/nix/store/{generation}-system/init quiet 'x=$y z'\n\
+                 {indent}  initrd /nix/store/iiii-initrd/initrd\n\
+                 {indent}}}\n",
+                indent = indent,
+                generation = generation,
+            )
+        };
+        let expected = [
+            String::from("# nixos-default 2\n"),
+            String::from("menuentry 'NixOS - Default' --unrestricted {\n"),
+            entry("", 2),
+            String::from("# nixos-generation 2\n"),
+            String::from("menuentry 'NixOS - Generation 2 23.05, Built on DATE' {\n"),
+            entry("", 2),
+            String::from("submenu 'NixOS - Generation 2 - Specialisations' {\n"),
+            String::from(
+                "  menuentry 'NixOS – spec: gui - Generation 2~gui 23.05, Built on DATE' {\n",
+            ),
+            entry("  ", 2),
+            String::from("}\n"),
+            String::from("# nixos-generation 1\n"),
+            String::from("menuentry 'NixOS - Generation 1 23.05, Built on DATE' {\n"),
+            entry("", 1),
+            String::from("# nixos-generation 3 work\n"),
+            String::from(
+                "menuentry 'NixOS - Profile work - Generation 3 23.05, Built on DATE' {\n",
+            ),
+            entry("", 3),
+        ]
+        .concat();
+        assert_eq!(fragment.replace(date, "DATE"), expected);
+
+        // leaving out generations takes their specialisations with them, but not the default entry
+        let retained = retain_generations(&fragment, |generation, profile| {
+            generation == 1 || profile.is_some()
+        });
+        assert!(retained.contains("'NixOS - Default'"));
+        assert!(retained.contains("'NixOS - Generation 1 "));
+        assert!(retained.contains("'NixOS - Profile work - Generation 3 "));
+        assert!(!retained.contains("'NixOS - Generation 2 "));
+        assert!(!retained.contains("Specialisations"));
+        assert_eq!(retain_generations(&fragment, |_, _| true), fragment);
+
+        // rolled back to generation 1, which becomes the default, and is still listed on its own
+        let rolled_back = with_default(&fragment, 1).unwrap();
+        assert_eq!(
+            rolled_back.replace(date, "DATE"),
+            [
+                String::from("# nixos-default 1\n"),
+                String::from("menuentry 'NixOS - Default' --unrestricted {\n"),
+                entry("", 1),
+            ]
+            .concat()
+                + &expected[expected.find("# nixos-generation 2\n").unwrap()..]
+        );
+        assert_eq!(with_default(&fragment, 2).unwrap(), fragment);
+        assert_eq!(with_default(&rolled_back, 2).unwrap(), fragment);
+        // the other profiles' generations can't be the default
+        assert!(with_default(&fragment, 3).is_err());
+
+        // without a generation of the system profile, there's no default
+        let fragment =
+            super::fragment(&[Bootable::Linux(toplevel(Some("work"), 3, None))], None).unwrap();
+        assert!(!fragment.contains("Default"));
+        assert!(!fragment.contains("--unrestricted"));
+    }
+
+    #[test]
+    fn test_parse_marker() {
+        assert_eq!(parse_marker("# nixos-generation 2"), Some((2, None)));
+        assert_eq!(
+            parse_marker("# nixos-generation 3 work"),
+            Some((3, Some("work")))
+        );
+        assert_eq!(parse_marker("# nixos-generation x"), None);
+        assert_eq!(parse_marker("# nixos-generation 3 work x"), None);
+        assert_eq!(parse_marker("menuentry 'NixOS' {"), None);
+    }
+
+    #[test]
+    fn test_fragment_deduped_kernel_params() {
+        let toplevel = BootableToplevel {
+            kernel: PathBuf::from("/nix/store/kkkk-linux/bzImage"),
+            kernel_params: [
+                "quiet",
+                "console=tty0",
+                "loglevel=4",
+                "console=ttyS0",
+                "quiet",
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+            init: PathBuf::from("/nix/store/ssss-system/init"),
+            initrd: PathBuf::from("/nix/store/iiii-initrd/initrd"),
+            ..Default::default()
+        };
+        let linux = |dedupe_kernel_params: Option<&[String]>| {
+            let mut fragment = String::new();
+            write_entry(
+                &mut fragment,
+                "",
+                "NixOS",
+                &toplevel,
+                false,
+                dedupe_kernel_params,
+            )
+            .unwrap();
+            fragment
+                .lines()
+                .find_map(|line| {
+                    line.trim()
+                        .strip_prefix("linux /nix/store/kkkk-linux/bzImage ")
+                })
+                .unwrap()
+                .to_string()
+        };
 
-    println!("{}", data);
+        assert_eq!(
+            linux(None),
+            "init=/nix/store/ssss-system/init quiet console=tty0 loglevel=4 console=ttyS0 quiet"
+        );
+        // like systemd-boot's, with --dedupe-kernel-params
+        assert_eq!(
+            linux(Some(&[String::from("console")])),
+            "init=/nix/store/ssss-system/init console=tty0 loglevel=4 console=ttyS0 quiet"
+        );
+    }
 }
-*/
@@ -0,0 +1,216 @@
+//! `--incremental`: only staging the kernels, initrds, and entries that changed since a previous
+//! run, going by the manifest it wrote (see `--previous-manifest`).
+//!
+//! The manifest's hashes are only trusted as far as they can be checked: it has to be from this
+//! release of the generator, and only the files that are still where it says (e.g. on the ESP),
+//! hashing to what it says, count as unchanged. Everything else is staged as usual.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::esp_path;
+use crate::manifest::{self, Manifest, ManifestFile, MANIFEST};
+use crate::recompress::Compression;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// The files and entries a previous run staged that are still there as it staged them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreviousRun {
+    /// By path, relative to the root the previous manifest is in
+    files: BTreeMap<String, ManifestFile>,
+    /// What the entries hash to, by path relative to the root the previous manifest is in
+    entries: BTreeMap<String, String>,
+}
+
+impl PreviousRun {
+    /// Reads the previous run's manifest at `path` (at [`MANIFEST`] in e.g. the ESP, or the staging
+    /// tree it was written to), and checks which of the files and entries it records are still
+    /// there.
+    ///
+    /// If the manifest can't be trusted at all, this warns and returns `None`, i.e. everything is
+    /// staged.
+    pub fn load(path: &Path) -> Option<Self> {
+        match self::load_trusted(path) {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                warnings::emit(
+                    Kind::UntrustedPreviousManifest,
+                    format!(
+                        "not generating incrementally, everything is staged instead: {}",
+                        e
+                    ),
+                );
+                None
+            }
+        }
+    }
+
+    /// The previous run's record of the kernel or initrd at `esp_path` (as written in entries,
+    /// e.g. `/EFI/nixos/...`), if it was staged from `source` with `compression`, and is unchanged.
+    pub fn file(
+        &self,
+        esp_path: &str,
+        source: &Path,
+        compression: Compression,
+    ) -> Option<&ManifestFile> {
+        self.files
+            .get(esp_path.trim_start_matches('/'))
+            .filter(|file| match &file.source {
+                Some(recorded) => recorded.path == source && recorded.compression == compression,
+                None => false,
+            })
+    }
+
+    /// What the entry at `conf` (relative to the root of the staging tree) hashes to, if it's
+    /// unchanged.
+    pub fn entry(&self, conf: &str) -> Option<&str> {
+        self.entries.get(conf).map(String::as_str)
+    }
+}
+
+fn load_trusted(path: &Path) -> Result<PreviousRun> {
+    let root = self::root(path)?;
+    let manifest = Manifest::read(path)?;
+    if manifest.version != manifest::VERSION {
+        return Err(format!(
+            "'{}' is from version '{}' of the generator, not {}",
+            path.display(),
+            manifest.version,
+            manifest::VERSION
+        )
+        .into());
+    }
+
+    let mut previous = PreviousRun::default();
+    for file in manifest.files {
+        if self::is_unchanged(&root, &file.path, &file.sha256) {
+            previous.files.insert(file.path.clone(), file);
+        }
+    }
+    for (conf, sha256) in manifest.entries {
+        if self::is_unchanged(&root, &conf, &sha256) {
+            previous.entries.insert(conf, sha256);
+        }
+    }
+
+    Ok(previous)
+}
+
+/// The root the manifest at `path` records files relative to, i.e. what it's at [`MANIFEST`] in.
+fn root(path: &Path) -> Result<PathBuf> {
+    if !path.ends_with(MANIFEST) {
+        return Err(format!(
+            "'{}' isn't at '{}' in an ESP or staging tree, so the files it records can't be found",
+            path.display(),
+            MANIFEST
+        )
+        .into());
+    }
+
+    Ok(path
+        .ancestors()
+        .nth(Path::new(MANIFEST).components().count())
+        .unwrap_or_else(|| Path::new(""))
+        .to_path_buf())
+}
+
+fn is_unchanged(root: &Path, path: &str, sha256: &str) -> bool {
+    let path = esp_path::join(root, path);
+    match manifest::sha256_file(&path) {
+        Ok(found) if found == sha256 => true,
+        Ok(_) => {
+            debug!("'{}' changed since the previous run", path.display());
+            false
+        }
+        Err(e) => {
+            debug!("{}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::manifest::Source;
+    use crate::report::Document;
+
+    #[test]
+    fn test_load() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        let kernel = "EFI/nixos/kernel.efi";
+        let initrd = "EFI/nixos/initrd.efi";
+        let entry = "loader/entries/nixos-generation-1.conf";
+        fs::write(esp.join(kernel), "kernel").unwrap();
+        fs::write(esp.join(initrd), "changed").unwrap();
+        fs::write(esp.join(entry), "title NixOS\n").unwrap();
+
+        let file = |path: &str, contents: &[u8]| ManifestFile {
+            path: path.to_string(),
+            sha256: manifest::sha256(contents),
+            source: Some(Source {
+                path: PathBuf::from("/nix/store/x").join(path),
+                sha256: manifest::sha256(contents),
+                compression: Compression::None,
+            }),
+            sections: BTreeMap::new(),
+        };
+        let write = |version: &str| {
+            Manifest {
+                format_version: Manifest::FORMAT_VERSION,
+                version: version.to_string(),
+                files: vec![file(kernel, b"kernel"), file(initrd, b"initrd")],
+                entries: [(entry.to_string(), manifest::sha256(b"title NixOS\n"))].into(),
+                entry_extras: BTreeMap::new(),
+                deduped_kernel_params: BTreeMap::new(),
+                unchanged: Vec::new(),
+                excluded_specialisations: Vec::new(),
+            }
+            .write(esp)
+            .unwrap();
+        };
+
+        write(manifest::VERSION);
+        let previous = PreviousRun::load(&esp.join(MANIFEST)).unwrap();
+        let store_kernel = Path::new("/nix/store/x").join(kernel);
+        assert!(previous
+            .file(&format!("/{}", kernel), &store_kernel, Compression::None)
+            .is_some());
+        // staged from something else
+        assert!(previous
+            .file(
+                &format!("/{}", kernel),
+                Path::new("/nix/store/y"),
+                Compression::None
+            )
+            .is_none());
+        assert!(previous
+            .file(&format!("/{}", kernel), &store_kernel, Compression::Zstd)
+            .is_none());
+        // not what the previous run staged anymore
+        let store_initrd = Path::new("/nix/store/x").join(initrd);
+        assert!(previous
+            .file(&format!("/{}", initrd), &store_initrd, Compression::None)
+            .is_none());
+        assert_eq!(
+            previous.entry(entry),
+            Some(manifest::sha256(b"title NixOS\n").as_str())
+        );
+
+        // a manifest from another release (or that isn't where its files are) isn't trusted
+        write("0.0.0");
+        assert!(PreviousRun::load(&esp.join(MANIFEST)).is_none());
+        write(manifest::VERSION);
+        fs::copy(esp.join(MANIFEST), esp.join("manifest.json")).unwrap();
+        assert!(PreviousRun::load(&esp.join("manifest.json")).is_none());
+        assert!(PreviousRun::load(&esp.join("missing").join(MANIFEST)).is_none());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use bootspec::BootJson;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::context::Context;
+use crate::{Generation, Result};
+
+/// A generation whose bootspec document is passed inline (see [`read_bootspecs_json`]).
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InlineGeneration {
+    index: usize,
+    #[serde(default)]
+    profile: Option<String>,
+    /// The generation's bootspec, including its specialisations' documents
+    document: BootJson,
+}
+
+/// Reads generations from the JSON array at `path` (or stdin, if `path` is `-`), where each element
+/// is of the form `{ "index": 1, "profile": null, "document": <bootspec> }`.
+///
+/// Unlike generations passed as profile links, these are never looked up on the filesystem.
+pub fn read_bootspecs_json(path: &Path) -> Result<Vec<Generation>> {
+    let contents = if path == Path::new("-") {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(|e| format!("couldn't read bootspecs from stdin: {}", e))?;
+
+        contents
+    } else {
+        fs::read_to_string(path).with_path_context(path)?
+    };
+
+    self::parse_bootspecs_json(&contents).with_path_context(path)
+}
+
+/// Parses the generations in `json`; see [`read_bootspecs_json`].
+pub fn parse_bootspecs_json(json: &str) -> Result<Vec<Generation>> {
+    // Deserialized element by element, so that errors can say which one is wrong.
+    let values: Vec<Value> = serde_json::from_str(json)
+        .map_err(|e| format!("expected an array of generations: {}", e))?;
+    if values.is_empty() {
+        return Err("no generations specified".into());
+    }
+
+    let mut generations = Vec::with_capacity(values.len());
+    let mut seen = HashMap::new();
+
+    for (i, value) in values.into_iter().enumerate() {
+        let inline: InlineGeneration =
+            serde_json::from_value(value).map_err(|e| format!("generations[{}]: {}", i, e))?;
+
+        if let Some(profile) = &inline.profile {
+            if profile.is_empty() || profile.contains('-') || profile.contains('/') {
+                return Err(format!(
+                    "generations[{}]: profile '{}' must be non-empty and not contain '-' or '/'",
+                    i, profile
+                )
+                .into());
+            }
+        }
+
+        if let Some(first) = seen.insert((inline.profile.clone(), inline.index), i) {
+            return Err(format!(
+                "generations[{}]: generation {} of {} was already specified by generations[{}]",
+                i,
+                inline.index,
+                match &inline.profile {
+                    Some(profile) => format!("profile '{}'", profile),
+                    None => String::from("the system profile"),
+                },
+                first
+            )
+            .into());
+        }
+
+        generations.push(Generation {
+            index: inline.index,
+            profile: inline.profile,
+            bootspec: inline.document,
+        });
+    }
+
+    Ok(generations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use crate::bootable;
+
+    /// A bootspec document (with a `gui` specialisation), as a flake evaluation would produce it.
+    fn document(toplevel: &str) -> Value {
+        let tempdir = tempfile::tempdir().unwrap();
+        let write_toplevel = |path: &Path| {
+            fs::create_dir_all(path).unwrap();
+            fs::write(path.join("kernel"), "").unwrap();
+            fs::write(path.join("initrd"), "").unwrap();
+            fs::write(path.join("init"), "").unwrap();
+            fs::write(path.join("nixos-version"), "23.05").unwrap();
+            fs::write(path.join("system"), "x86_64-linux").unwrap();
+            fs::write(path.join("kernel-params"), "quiet").unwrap();
+        };
+        let generation = tempdir.path().join(toplevel);
+        write_toplevel(&generation);
+        write_toplevel(&generation.join("specialisation/gui"));
+
+        serde_json::to_value(crate::get_json(generation).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_bootspecs_json() {
+        let payload = json!([
+            { "index": 1, "document": document("system-1-link") },
+            { "index": 2, "profile": "work", "document": document("work-2-link") },
+        ]);
+
+        let generations = parse_bootspecs_json(&payload.to_string()).unwrap();
+        assert_eq!(
+            generations
+                .iter()
+                .map(|g| (g.index, g.profile.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![(1, None), (2, Some("work"))]
+        );
+
+        // the nested specialisation documents are flattened like on-disk ones
+        let toplevels = bootable::flatten(generations).unwrap();
+        let mut flattened = toplevels
+            .iter()
+            .map(|t| {
+                (
+                    t.generation_index,
+                    t.profile_name.as_deref(),
+                    t.specialisation_name.as_ref().map(|s| s.0.as_str()),
+                )
+            })
+            .collect::<Vec<_>>();
+        flattened.sort();
+        assert_eq!(
+            flattened,
+            vec![
+                (1, None, None),
+                (1, None, Some("gui")),
+                (2, Some("work"), None),
+                (2, Some("work"), Some("gui")),
+            ]
+        );
+        assert!(toplevels
+            .iter()
+            .all(|t| t.kernel_params == vec![String::from("quiet")]));
+    }
+
+    #[test]
+    fn test_read_bootspecs_json() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("bootspecs.json");
+        fs::write(
+            &path,
+            json!([{ "index": 1, "document": document("system-1-link") }]).to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(read_bootspecs_json(&path).unwrap().len(), 1);
+
+        let missing = PathBuf::from("/nonexistent/bootspecs.json");
+        let err = read_bootspecs_json(&missing).unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/bootspecs.json"));
+    }
+
+    #[test]
+    fn test_parse_bootspecs_json_errors() {
+        let doc = document("system-1-link");
+        let err = |payload: Value| {
+            parse_bootspecs_json(&payload.to_string())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(err(json!({ "index": 1, "document": doc })).contains("expected an array"));
+        assert!(err(json!([])).contains("no generations"));
+        assert!(
+            err(json!([{ "index": 1, "document": doc }, { "index": 2 }]))
+                .starts_with("generations[1]: missing field `document`")
+        );
+        assert!(
+            err(json!([{ "index": 1, "document": doc, "path": "/nix" }]))
+                .starts_with("generations[0]: unknown field `path`")
+        );
+        assert!(err(json!([{ "index": 1, "document": { "label": 1 } }]))
+            .starts_with("generations[0]: "));
+        assert!(
+            err(json!([{ "index": 1, "profile": "my-work", "document": doc }]))
+                .starts_with("generations[0]: profile 'my-work'")
+        );
+        assert_eq!(
+            err(json!([
+                { "index": 1, "document": doc },
+                { "index": 1, "profile": "work", "document": doc },
+                { "index": 1, "document": doc },
+            ])),
+            "generations[2]: generation 1 of the system profile was already specified by generations[0]"
+        );
+    }
+}
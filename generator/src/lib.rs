@@ -1,14 +1,38 @@
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use bootspec::v1::GenerationV1;
 use bootspec::{BootJson, JSON_FILENAME};
 use regex::Regex;
 
+use crate::context::Context;
+
 pub mod bootable;
+mod cmdline;
+pub mod command;
+pub mod context;
+pub mod deterministic;
+pub mod entry_extra;
+pub mod esp_path;
+pub mod facts;
+pub mod flake;
 pub mod grub;
+pub mod incremental;
+pub mod inline;
+pub mod logging;
+pub mod manifest;
+pub mod panic_hook;
+pub mod recompress;
+pub mod report;
+pub mod stream;
 pub mod systemd_boot;
+pub mod target;
+mod util;
+pub mod validate;
+pub mod variant;
+pub mod warnings;
 
 #[derive(Debug, Default)]
 pub struct Generation {
@@ -19,6 +43,10 @@ pub struct Generation {
 
 pub type Result<T, E = Box<dyn Error + Send + Sync + 'static>> = core::result::Result<T, E>;
 
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: stream::TrackingAllocator = stream::TrackingAllocator;
+
 lazy_static::lazy_static! {
     static ref SYSTEM_RE: Regex = Regex::new("/profiles/system-(?P<generation>\\d+)-link").unwrap();
     static ref PROFILE_RE: Regex = Regex::new("/system-profiles/(?P<profile>[^-]+)-(?P<generation>\\d+)-link").unwrap();
@@ -28,7 +56,7 @@ pub fn get_json(generation_path: PathBuf) -> Result<BootJson> {
     let json_path = generation_path.join(JSON_FILENAME);
 
     let mut json: Option<BootJson> = None;
-    if json_path.exists() {
+    if let Some(json_path) = self::resolve_json_path(&json_path)? {
         if let Ok(cont) = fs::read_to_string(&json_path) {
             if let Ok(parsed) = serde_json::from_str(&cont) {
                 json = Some(parsed)
@@ -37,12 +65,82 @@ pub fn get_json(generation_path: PathBuf) -> Result<BootJson> {
     }
 
     if json.is_none() {
-        json = Some(GenerationV1::synthesize(&generation_path)?);
+        let mut synthesized =
+            GenerationV1::synthesize(&generation_path).with_path_context(&generation_path)?;
+        self::strip_init_params(&mut synthesized);
+        json = Some(synthesized);
     }
 
     Ok(json.unwrap())
 }
 
+/// Resolves the bootspec document at `path` to the regular file that should be read, or `None` if
+/// there isn't one (in which case the caller should synthesize it).
+///
+/// Depending on the nixpkgs revision, the document may be a regular file, a symlink into the
+/// toplevel, or (in broken cases) a directory that contains the actual document.
+pub fn resolve_json_path(path: &Path) -> Result<Option<PathBuf>> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("couldn't inspect '{}': {}", path.display(), e).into()),
+    };
+
+    let resolved = if metadata.file_type().is_symlink() {
+        fs::canonicalize(path).map_err(|e| {
+            format!(
+                "'{}' is a symlink that couldn't be resolved: {}",
+                path.display(),
+                e
+            )
+        })?
+    } else {
+        path.to_path_buf()
+    };
+    let metadata = fs::metadata(&resolved).with_path_context(&resolved)?;
+
+    if metadata.is_file() {
+        Ok(Some(resolved))
+    } else if metadata.is_dir() {
+        let inner = resolved.join(JSON_FILENAME);
+
+        if inner.is_file() {
+            Ok(Some(inner))
+        } else {
+            Err(format!(
+                "'{}' is a directory (resolved to '{}') that doesn't contain {}",
+                path.display(),
+                resolved.display(),
+                JSON_FILENAME
+            )
+            .into())
+        }
+    } else {
+        Err(format!(
+            "'{}' is neither a file nor a directory (resolved to '{}', {:?})",
+            path.display(),
+            resolved.display(),
+            metadata.file_type()
+        )
+        .into())
+    }
+}
+
+/// Removes any `init=` from the kernel params of `bootspec` and its specialisations.
+///
+/// Some older generations put `init=` in `kernel-params` themselves, but bootspec models `init`
+/// separately (and we add it to the entries ourselves), so keeping it would result in entries
+/// with two conflicting `init=` parameters.
+pub fn strip_init_params(bootspec: &mut BootJson) {
+    bootspec
+        .kernel_params
+        .retain(|param| !param.starts_with("init="));
+
+    for specialisation in bootspec.specialisation.values_mut() {
+        self::strip_init_params(specialisation);
+    }
+}
+
 pub fn parse_generation(generation: &str) -> Result<(usize, Option<String>)> {
     if PROFILE_RE.is_match(generation) {
         let caps = PROFILE_RE.captures(generation).unwrap();
@@ -58,3 +156,128 @@ pub fn parse_generation(generation: &str) -> Result<(usize, Option<String>)> {
         Err("generation wasn't a system or profile generation".into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_resolve_json_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        // file
+        let file = dir.join("file.json");
+        fs::write(&file, "{}").unwrap();
+        assert_eq!(resolve_json_path(&file).unwrap(), Some(file.clone()));
+
+        // symlink to a file
+        let symlink = dir.join("symlink.json");
+        unix::fs::symlink(&file, &symlink).unwrap();
+        assert_eq!(
+            resolve_json_path(&symlink).unwrap(),
+            Some(fs::canonicalize(&file).unwrap())
+        );
+
+        // directory containing the json
+        let directory = dir.join("directory");
+        fs::create_dir(&directory).unwrap();
+        fs::write(directory.join(JSON_FILENAME), "{}").unwrap();
+        assert_eq!(
+            resolve_json_path(&directory).unwrap(),
+            Some(directory.join(JSON_FILENAME))
+        );
+
+        // symlink to a directory containing the json
+        let symlink = dir.join("symlink-directory");
+        unix::fs::symlink(&directory, &symlink).unwrap();
+        assert_eq!(
+            resolve_json_path(&symlink).unwrap(),
+            Some(fs::canonicalize(&directory).unwrap().join(JSON_FILENAME))
+        );
+
+        // missing
+        assert_eq!(resolve_json_path(&dir.join("missing.json")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_synthesized_init_params() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = |path: &Path| {
+            fs::create_dir_all(path).unwrap();
+            fs::write(path.join("kernel"), "").unwrap();
+            fs::write(path.join("initrd"), "").unwrap();
+            fs::write(path.join("init"), "").unwrap();
+            fs::write(path.join("nixos-version"), "23.05").unwrap();
+            fs::write(path.join("system"), "x86_64-linux").unwrap();
+            fs::write(path.join("kernel-params"), "init=/old/path quiet").unwrap();
+        };
+        let generation = tempdir.path().join("system-1-link");
+        toplevel(&generation);
+        toplevel(&generation.join("specialisation/foo"));
+
+        let json = get_json(generation).unwrap();
+        assert_eq!(json.kernel_params, vec![String::from("quiet")]);
+        for specialisation in json.specialisation.values() {
+            assert_eq!(specialisation.kernel_params, vec![String::from("quiet")]);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_synthesize_result_symlink() {
+        // e.g. the `result` of `nix build .#nixosConfigurations.foo.config.system.build.toplevel`
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        for (name, contents) in [
+            ("kernel", ""),
+            ("initrd", ""),
+            ("init", ""),
+            ("nixos-version", "23.05"),
+            ("system", "x86_64-linux"),
+            ("kernel-params", "quiet"),
+        ] {
+            fs::write(toplevel.join(name), contents).unwrap();
+        }
+        let result = tempdir.path().join("result");
+        unix::fs::symlink(&toplevel, &result).unwrap();
+
+        let json = get_json(result).unwrap();
+        assert_eq!(json.kernel_params, vec![String::from("quiet")]);
+    }
+
+    #[test]
+    fn test_synthesize_missing_kernel() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generation = tempdir.path().join("system-1-link");
+        fs::create_dir(&generation).unwrap();
+
+        let err = get_json(generation.clone()).unwrap_err().to_string();
+        assert!(err.contains(&generation.display().to_string()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_resolve_json_path_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        let empty = dir.join("empty");
+        fs::create_dir(&empty).unwrap();
+        let err = resolve_json_path(&empty).unwrap_err().to_string();
+        assert!(err.contains("is a directory"));
+        assert!(err.contains(&empty.display().to_string()));
+
+        let dangling = dir.join("dangling.json");
+        unix::fs::symlink(dir.join("nowhere"), &dangling).unwrap();
+        let err = resolve_json_path(&dangling).unwrap_err().to_string();
+        assert!(err.contains("is a symlink"));
+        assert!(err.contains(&dangling.display().to_string()));
+    }
+}
@@ -0,0 +1,296 @@
+//! The logger that both the generator and the installer set up, so that `-v`, `--quiet`, and
+//! `RUST_LOG` mean the same to both.
+//!
+//! Only the binary's own records are logged (not its dependencies'), at the level that the first
+//! of these sets:
+//! 1. `--quiet`: errors only
+//! 2. `-v`, `-vv`, or `-vvv`: info, debug, or trace
+//! 3. `RUST_LOG`, which refines the default per module (e.g.
+//!    `RUST_LOG=installer::systemd_boot::plan=trace,installer::files=off`)
+//! 4. warnings, by default
+
+use std::env;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use log::LevelFilter;
+
+use crate::Result;
+
+/// How each record is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `LEVEL target: message`
+    Human,
+    /// One JSON object per line, with the `level`, `target`, and `message`
+    Json,
+}
+
+/// Where the records are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    Stderr,
+    Stdout,
+    /// Into the [`LogHandle`], for tests
+    Capture,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The crate whose records are logged (`env!("CARGO_PKG_NAME")`)
+    pub crate_name: &'static str,
+    /// How many times `-v` was passed
+    pub verbosity: usize,
+    pub quiet: bool,
+    /// `RUST_LOG`, if it's set
+    pub rust_log: Option<String>,
+    pub format: Format,
+    pub output: Output,
+}
+
+impl Options {
+    /// Logs `crate_name`'s records for humans on stderr, going by `RUST_LOG` from the environment.
+    pub fn new(crate_name: &'static str, verbosity: usize, quiet: bool) -> Self {
+        Self {
+            crate_name,
+            verbosity,
+            quiet,
+            rust_log: env::var("RUST_LOG").ok(),
+            format: Format::Human,
+            output: Output::Stderr,
+        }
+    }
+
+    /// The `env_logger` filter directives, from the first of `--quiet`, `-v`, and `RUST_LOG` that
+    /// was passed.
+    fn directives(&self) -> String {
+        let level = match self.verbosity {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+
+        match self.rust_log.as_deref().map(str::trim) {
+            _ if self.quiet => format!("{}={}", self.crate_name, LevelFilter::Error),
+            _ if self.verbosity > 0 => format!("{}={}", self.crate_name, level),
+            Some(rust_log) if !rust_log.is_empty() => {
+                format!("{}={},{}", self.crate_name, level, rust_log)
+            }
+            _ => format!("{}={}", self.crate_name, level),
+        }
+    }
+}
+
+/// What [`init`] set up: with [`Output::Capture`], the records that were logged.
+#[derive(Debug, Clone, Default)]
+pub struct LogHandle {
+    captured: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl LogHandle {
+    /// Everything that was logged so far, if it's captured.
+    pub fn captured(&self) -> Option<String> {
+        let captured = self.captured.as_ref()?;
+        let bytes = captured.lock().unwrap_or_else(|e| e.into_inner());
+
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut captured = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        captured.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the logger that `options` describe, without installing it.
+pub fn build(options: &Options) -> (env_logger::Logger, LogHandle) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&options.directives());
+
+    match options.format {
+        Format::Human => builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{:<5} {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        }),
+        Format::Json => builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        }),
+    };
+
+    let mut handle = LogHandle::default();
+    match options.output {
+        Output::Stderr => builder.target(env_logger::Target::Stderr),
+        Output::Stdout => builder.target(env_logger::Target::Stdout),
+        Output::Capture => {
+            let captured = Arc::new(Mutex::new(Vec::new()));
+            handle.captured = Some(Arc::clone(&captured));
+            builder.target(env_logger::Target::Pipe(Box::new(CaptureWriter(captured))))
+        }
+    };
+
+    (builder.build(), handle)
+}
+
+/// Sets up the logger that `options` describe for the rest of the process.
+pub fn init(options: &Options) -> Result<LogHandle> {
+    let (logger, handle) = self::build(options);
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(max_level);
+
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, Log, Metadata, Record};
+
+    fn options(verbosity: usize, quiet: bool, rust_log: Option<&str>) -> Options {
+        Options {
+            crate_name: "installer",
+            verbosity,
+            quiet,
+            rust_log: rust_log.map(ToString::to_string),
+            format: Format::Human,
+            output: Output::Capture,
+        }
+    }
+
+    /// The most verbose level that `target`'s records are logged at.
+    fn level(options: &Options, target: &str) -> Option<Level> {
+        let (logger, _) = build(options);
+
+        [
+            Level::Trace,
+            Level::Debug,
+            Level::Info,
+            Level::Warn,
+            Level::Error,
+        ]
+        .iter()
+        .copied()
+        .find(|level| logger.enabled(&Metadata::builder().level(*level).target(target).build()))
+    }
+
+    #[test]
+    fn test_precedence() {
+        let plan = "installer::systemd_boot::plan";
+        for (verbosity, quiet, rust_log, target, expected) in [
+            // the default
+            (0, false, None, "installer", Some(Level::Warn)),
+            (0, false, Some(""), plan, Some(Level::Warn)),
+            // RUST_LOG refines the default
+            (0, false, Some("installer=debug"), plan, Some(Level::Debug)),
+            (
+                0,
+                false,
+                Some("installer::systemd_boot::plan=trace"),
+                plan,
+                Some(Level::Trace),
+            ),
+            (
+                0,
+                false,
+                Some("installer::systemd_boot::plan=trace"),
+                "installer",
+                Some(Level::Warn),
+            ),
+            (
+                0,
+                false,
+                Some("installer::files=off"),
+                "installer::files",
+                None,
+            ),
+            // -v beats RUST_LOG
+            (1, false, None, plan, Some(Level::Info)),
+            (2, false, Some("installer=trace"), plan, Some(Level::Debug)),
+            (3, false, Some("installer=off"), plan, Some(Level::Trace)),
+            (9, false, None, plan, Some(Level::Trace)),
+            // --quiet beats both
+            (0, true, None, plan, Some(Level::Error)),
+            (3, true, Some("installer=trace"), plan, Some(Level::Error)),
+            // dependencies aren't logged
+            (3, false, None, "walkdir", None),
+        ] {
+            let options = options(verbosity, quiet, rust_log);
+            assert_eq!(
+                level(&options, target),
+                expected,
+                "{:?} {}",
+                options,
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_capture() {
+        let log = |options: &Options| {
+            let (logger, handle) = build(options);
+            for (level, message) in [(Level::Info, "copied"), (Level::Warn, "pruned")] {
+                logger.log(
+                    &Record::builder()
+                        .level(level)
+                        .target("installer::systemd_boot")
+                        .args(format_args!("{}", message))
+                        .build(),
+                );
+            }
+            handle.captured().unwrap()
+        };
+
+        assert_eq!(
+            log(&options(0, false, None)),
+            "WARN  installer::systemd_boot: pruned\n"
+        );
+
+        let options = Options {
+            format: Format::Json,
+            ..options(1, false, None)
+        };
+        let records = log(&options)
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                serde_json::json!({
+                    "level": "INFO",
+                    "target": "installer::systemd_boot",
+                    "message": "copied",
+                }),
+                serde_json::json!({
+                    "level": "WARN",
+                    "target": "installer::systemd_boot",
+                    "message": "pruned",
+                }),
+            ]
+        );
+
+        assert_eq!(LogHandle::default().captured(), None);
+    }
+}
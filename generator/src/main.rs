@@ -1,54 +1,273 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use generator::bootable::{self, Bootable, EfiProgram};
-use generator::{systemd_boot, Generation, Result};
+use generator::bootable::{self, ukify, Bootable, EfiProgram, SpecialisationFilter, UkiBuilder};
+use generator::deterministic::{self, SOURCE_DATE_EPOCH};
+use generator::entry_extra::{self, EntryExtra};
+use generator::facts::{self, Facts};
+use generator::flake;
+use generator::incremental::PreviousRun;
+use generator::recompress::{self, Compression};
+use generator::variant::{self, EntryVariant};
+use generator::{
+    command, grub, inline, logging, panic_hook, systemd_boot, target, validate, Generation, Result,
+};
 use structopt::StructOpt;
 
 #[derive(Default, Debug, StructOpt)]
 struct Args {
-    // TODO: --out-dir?
     /// The systemd-boot EFI stub used to create a unified EFI file
-    #[structopt(long, requires_all = &["objcopy", "unified-efi"])]
+    #[structopt(long, requires = "unified-efi")]
     systemd_efi_stub: Option<PathBuf>,
-    /// The `objcopy` binary
+    /// The `objcopy` binary (needed by `--uki-builder objcopy`)
     #[structopt(long, requires_all = &["systemd-efi-stub", "unified-efi"])]
     objcopy: Option<PathBuf>,
     /// Whether or not to combine the initrd and kernel into a unified EFI file
-    #[structopt(long, requires_all = &["systemd-efi-stub", "objcopy"])]
+    #[structopt(long, requires = "systemd-efi-stub")]
     unified_efi: bool,
-    /// The `systemd-machine-id-setup` binary
-    // TODO: maybe just pass in machine_id as an arg; if empty, omit from configuration?
+    /// What builds unified EFI files: `objcopy`, `native` to have the generator append the
+    /// sections to the stub itself, the way ukify lays them out, or `ukify` (from systemd 253 or
+    /// later; if it's missing or older, `--objcopy` builds them instead, with a warning)
+    #[structopt(long, default_value = "objcopy", possible_values = &["objcopy", "native", "ukify"])]
+    uki_builder: String,
+    /// The `ukify` binary (for `--uki-builder ukify`)
+    #[structopt(long, default_value = "ukify")]
+    ukify: PathBuf,
+    /// The machine-id to write into entries, instead of the one in `/etc/machine-id` (or from
+    /// `--systemd-machine-id-setup`, if that isn't readable)
+    #[structopt(long, conflicts_with_all = &["machine-id-placeholder", "target-spec"], parse(try_from_str = systemd_boot::parse_machine_id))]
+    machine_id: Option<String>,
+    /// The `systemd-machine-id-setup` binary, to print the machine-id if neither `--machine-id` is
+    /// passed nor `/etc/machine-id` is readable (without any of those, entries are written
+    /// without one)
     #[structopt(long)]
-    systemd_machine_id_setup: PathBuf,
+    systemd_machine_id_setup: Option<PathBuf>,
+    /// Whether to write `@MACHINE_ID@` into entries instead of the machine-id of this machine (or of
+    /// every `--target-spec` target), e.g. to share the staging tree: the installer substitutes the
+    /// machine-id of the machine it runs on for it
+    #[structopt(long)]
+    machine_id_placeholder: bool,
+    /// A JSON file describing the machines to generate entries for, instead of this one
+    #[structopt(long, requires = "out-dir")]
+    target_spec: Option<PathBuf>,
+    /// A directory to put in front of the paths in entries (e.g. `/boot`, for `/boot/EFI/nixos/...`),
+    /// for when the files they refer to aren't at the root of the partition systemd-boot reads
+    /// them from; the files are staged under it, too
+    #[structopt(long, parse(try_from_str = target::parse_entry_path_prefix))]
+    entry_path_prefix: Option<String>,
+    /// The directory to write one staging tree per `--target-spec` target into
+    #[structopt(long, requires = "target-spec")]
+    out_dir: Option<PathBuf>,
+    /// Whether or not to sanity-check kernels and initrds (magic bytes, truncation) before
+    /// generating entries for them
+    #[structopt(long)]
+    validate_artifacts: bool,
+    /// An extra entry to generate for every generation, as `name=params-file`: it boots the
+    /// generation's kernel and initrd with the kernel params in `params-file` appended (e.g.
+    /// `recovery=recovery.params` with `systemd.unit=rescue.target`)
+    #[structopt(long, number_of_values = 1, parse(try_from_str = variant::parse_variant_arg))]
+    extra_entry_variant: Vec<(String, PathBuf)>,
+    /// A directive to append to entries, as `key=value[@selector]` (e.g.
+    /// `devicetree-overlay=/dtbs/uart.dtbo@profile:work`), where the selector restricts it to the
+    /// entries of a `profile:<name>` (`system` for the system profile), `generation:<number>`, or
+    /// `specialisation:<name>`; it can't be one of the directives the generator writes itself
+    #[structopt(long, number_of_values = 1, parse(try_from_str = entry_extra::parse_entry_extra))]
+    entry_extra: Vec<EntryExtra>,
+    /// Whether to list specialisations right after their parent generation in systemd-boot's menu
+    /// (sharing its sort-key), instead of in a group of their own after every generation
+    #[structopt(long)]
+    group_specialisations: bool,
+    /// A specialisation not to generate entries for (e.g. one that's only for `nixos-rebuild
+    /// test`), or a glob pattern of them (e.g. `test-*`), along with the specialisations nested in
+    /// it; the entries it would have had are recorded in the manifest, so the installer prunes
+    /// them from the ESP
+    #[structopt(long, number_of_values = 1)]
+    exclude_specialisation: Vec<String>,
+    /// A specialisation to generate entries for, or a glob pattern of them: with any of these, the
+    /// other specialisations are left out like with `--exclude-specialisation`
+    #[structopt(long, number_of_values = 1)]
+    include_specialisation: Vec<String>,
+    /// Whether to collapse duplicate kernel params (e.g. from layered NixOS modules) to the last of
+    /// each, which is the one the kernel goes by, without reordering the rest; what's left out of
+    /// each entry is recorded in the manifest
+    #[structopt(long)]
+    dedupe_kernel_params: bool,
+    /// The name of a kernel param to keep every one of with `--dedupe-kernel-params`, because each
+    /// of them counts (default: console)
+    #[structopt(long, number_of_values = 1, requires = "dedupe-kernel-params")]
+    dedupe_kernel_params_exclude: Vec<String>,
+    /// How long (in bytes) an entry's `options` line may get before the kernel params are folded
+    /// onto several, which systemd-boot joins back together, since some versions of it truncate
+    /// long lines (default: 512)
+    #[structopt(long)]
+    max_options_len: Option<usize>,
+    /// How long (in characters) an entry's title and version may get before the middle of the
+    /// label is elided, keeping the generation number, the end of the label, and the date, since
+    /// systemd-boot cuts off what doesn't fit on the screen; the entry keeps the full ones in a
+    /// comment (default: 120)
+    #[structopt(long)]
+    max_label_len: Option<usize>,
+    /// Whether to also write GRUB menu entries for the generations (and their specialisations, in
+    /// submenus) to `grub-entries/grub.cfg` (in `--out-dir`, if it's given), a fragment for GRUB's
+    /// own `grub.cfg` to `source`; their kernel params are deduplicated with
+    /// `--dedupe-kernel-params`
+    #[structopt(long)]
+    grub: bool,
+    /// Only generate the `--extra-entry-variant` entries for the newest generation of each profile
+    #[structopt(long, requires = "extra-entry-variant")]
+    variant_latest_only: bool,
+    /// Whether to only stage the kernels, initrds, and entries that changed since the run that
+    /// wrote `--previous-manifest`, listing the rest as unchanged in the manifest for the
+    /// installer; unified EFI files are always rebuilt
+    #[structopt(long, requires = "previous-manifest", conflicts_with = "target-spec")]
+    incremental: bool,
+    /// The manifest of the previous run (e.g. `/boot/loader/nixos-manifest.json` on the ESP), whose
+    /// files and entries only count as unchanged if they're still next to it as it records them
+    #[structopt(long, requires = "incremental")]
+    previous_manifest: Option<PathBuf>,
+    /// How to compress initrds that aren't compressed (zstd, gzip, or none) on their way to the
+    /// ESP, to save space there; initrds that are already compressed are left as they are
+    #[structopt(long, default_value = "none")]
+    recompress_initrd: Compression,
+    /// Whether to write the staging tree(s) reproducibly, down to the metadata: every file gets the
+    /// mtime in `SOURCE_DATE_EPOCH` (or the Unix epoch, if it isn't set)
+    #[structopt(long)]
+    deterministic_staging: bool,
+    /// The facts the installer wrote on its last successful run (see its `--facts`, e.g.
+    /// `/var/lib/nixos-bootloader/facts.json`), as the defaults of `--machine-id` and
+    /// `--entry-path-prefix`; there not being any there yet is fine
+    #[structopt(long)]
+    facts: Option<PathBuf>,
+    /// How much to log (`-v` for info, `-vv` for debug, `-vvv` for trace); without it (or
+    /// `--quiet`), `RUST_LOG` refines the default of warnings per module
+    #[structopt(short, long, parse(from_occurrences))]
+    verbosity: usize,
+    /// Only log errors, whatever `-v` or `RUST_LOG` say
+    #[structopt(short, long)]
+    quiet: bool,
+    /// A JSON file (or `-` for stdin) with an array of generations and their bootspec documents,
+    /// e.g. from a flake evaluation, to use instead of reading profile links
+    #[structopt(long, conflicts_with = "generations")]
+    bootspecs_json: Option<PathBuf>,
+    /// A flake installable of a toplevel (e.g.
+    /// `.#nixosConfigurations.foo.config.system.build.toplevel`) to build with `--nix` and print
+    /// the bootspec document of (synthesized, if it doesn't have one), instead of generating any
+    /// entries
+    #[structopt(long, conflicts_with_all = &["generations", "bootspecs-json"])]
+    from_flake: Option<String>,
+    /// The `nix` binary (for `--from-flake`)
+    #[structopt(long, default_value = "nix")]
+    nix: PathBuf,
+    /// How long an external command (e.g. `ukify`, `objcopy`, or `nix`) may run before it's killed
+    /// and the run fails, in seconds
+    #[structopt(long, default_value = "60", parse(try_from_str = command::parse_timeout))]
+    command_timeout: Duration,
+    /// An environment variable for external commands to inherit, on top of `PATH` and `TMPDIR`
+    /// (they run with everything else cleared, and `LC_ALL=C`), e.g. `SOURCE_DATE_EPOCH`
+    #[structopt(long, number_of_values = 1, parse(try_from_str = command::parse_env_var_name))]
+    passthrough_env: Vec<String>,
     /// A list of generations in the form of `/nix/var/nix/profiles/system-*-link`
-    #[structopt(required = true)]
+    #[structopt(required_unless_one = &["bootspecs-json", "from-flake"])]
     generations: Vec<String>,
 }
 
 fn main() -> Result<()> {
     let args = Args::from_args();
 
-    let generations = args
-        .generations
+    logging::init(&logging::Options::new(
+        env!("CARGO_PKG_NAME"),
+        args.verbosity,
+        args.quiet,
+    ))?;
+
+    match panic_hook::catch(|| self::run(args)) {
+        Ok(ret) => ret,
+        Err(record) => {
+            record.emit();
+            std::process::exit(panic_hook::EXIT_INTERNAL_ERROR);
+        }
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    command::set_timeout(args.command_timeout);
+    command::set_passthrough_env(args.passthrough_env.clone());
+
+    if let Some(installable) = &args.from_flake {
+        for (_, bootspec) in flake::bootspecs(&args.nix, installable)? {
+            println!("{}", serde_json::to_string_pretty(&bootspec)?);
+        }
+
+        return Ok(());
+    }
+
+    let mtime = if args.deterministic_staging {
+        let source_date_epoch = std::env::var_os(SOURCE_DATE_EPOCH);
+        Some(deterministic::mtime(source_date_epoch.as_deref())?)
+    } else {
+        None
+    };
+    let facts = match &args.facts {
+        Some(path) => Facts::load(path)?,
+        None => None,
+    };
+    let options = facts::Options {
+        machine_id: args.machine_id,
+        entry_path_prefix: args.entry_path_prefix,
+    }
+    .with_defaults(facts.as_ref())?;
+    let generations = match &args.bootspecs_json {
+        Some(bootspecs_json) => inline::read_bootspecs_json(bootspecs_json)?,
+        None => args
+            .generations
+            .into_iter()
+            .filter_map(|gen| {
+                generator::parse_generation(&gen)
+                    .ok()
+                    .map(|(index, profile)| {
+                        let bootspec = generator::get_json(PathBuf::from(gen));
+
+                        bootspec
+                            .map(|bootspec| Generation {
+                                index,
+                                profile,
+                                bootspec,
+                            })
+                            .ok()
+                    })
+                    .flatten()
+            })
+            .collect::<Vec<_>>(),
+    };
+    let variants = args
+        .extra_entry_variant
         .into_iter()
-        .filter_map(|gen| {
-            generator::parse_generation(&gen)
-                .ok()
-                .map(|(index, profile)| {
-                    let bootspec = generator::get_json(PathBuf::from(gen));
-
-                    bootspec
-                        .map(|bootspec| Generation {
-                            index,
-                            profile,
-                            bootspec,
-                        })
-                        .ok()
-                })
-                .flatten()
-        })
-        .collect::<Vec<_>>();
-    let toplevels = bootable::flatten(generations)?;
+        .map(|(name, path)| EntryVariant::read(name, &path))
+        .collect::<Result<Vec<_>>>()?;
+    let specialisations =
+        SpecialisationFilter::new(&args.exclude_specialisation, &args.include_specialisation)?;
+    let (toplevels, excluded) = bootable::flatten_filtered(generations, &specialisations)?;
+    let mut toplevels = variant::add_variants(toplevels, &variants, args.variant_latest_only)?;
+    recompress::plan_recompression(&mut toplevels, args.recompress_initrd)?;
+
+    if args.validate_artifacts {
+        for toplevel in &toplevels {
+            validate::validate_toplevel(toplevel)?;
+        }
+    }
+
+    let uki_builder = match (args.unified_efi, args.uki_builder.as_str(), args.objcopy) {
+        (false, _, _) => None,
+        (true, "native", _) => Some(UkiBuilder::Native),
+        (true, "ukify", objcopy) => Some(ukify::choose(&args.ukify, objcopy)?),
+        (true, _, Some(objcopy)) => Some(UkiBuilder::Objcopy(objcopy)),
+        (true, _, None) => {
+            return Err(
+                "--unified-efi needs --objcopy, unless it's `--uki-builder native` or `ukify`"
+                    .into(),
+            )
+        }
+    };
     let bootables: Vec<Bootable> = if args.unified_efi {
         toplevels
             .into_iter()
@@ -58,15 +277,104 @@ fn main() -> Result<()> {
         toplevels.into_iter().map(Bootable::Linux).collect()
     };
 
-    systemd_boot::generate(
-        bootables,
-        args.objcopy,
-        args.systemd_efi_stub,
-        args.systemd_machine_id_setup,
-    )?;
+    let entry_path_prefix = options.entry_path_prefix.unwrap_or_default();
+    let max_options_len = args
+        .max_options_len
+        .unwrap_or(target::DEFAULT_MAX_OPTIONS_LEN);
+    let max_label_len = args.max_label_len.unwrap_or(target::DEFAULT_MAX_LABEL_LEN);
+    let dedupe_kernel_params = match (args.dedupe_kernel_params, args.dedupe_kernel_params_exclude)
+    {
+        (false, _) => None,
+        (true, exclude) if exclude.is_empty() => Some(vec![String::from("console")]),
+        (true, exclude) => Some(exclude),
+    };
+    let grub_root = match &args.out_dir {
+        Some(out_dir) => out_dir.join(grub::ROOT),
+        None => PathBuf::from(grub::ROOT),
+    };
+    let grub_dedupe_kernel_params = dedupe_kernel_params.clone();
+    let targets: Vec<(PathBuf, target::Target)> = match (args.target_spec, args.out_dir) {
+        (Some(target_spec), Some(out_dir)) => {
+            let mut targets = target::parse_target_spec(&target_spec)?
+                .into_iter()
+                .map(|target| {
+                    target
+                        .with_entry_path_prefix(&entry_path_prefix)
+                        .with_grouped_specialisations(args.group_specialisations)
+                        .with_deduped_kernel_params(dedupe_kernel_params.clone())
+                        .with_max_options_len(max_options_len)
+                        .with_max_label_len(max_label_len)
+                })
+                .collect::<Vec<_>>();
+            if args.machine_id_placeholder {
+                for target in &mut targets {
+                    target.machine_id = String::from(systemd_boot::MACHINE_ID_PLACEHOLDER);
+                }
+            }
+
+            systemd_boot::generate_targets(
+                &bootables,
+                uki_builder,
+                args.systemd_efi_stub,
+                &out_dir,
+                &targets,
+                &args.entry_extra,
+            )?;
+
+            targets
+                .into_iter()
+                .map(|target| (out_dir.join(&target.name), target))
+                .collect()
+        }
+        _ => {
+            let machine_id = if args.machine_id_placeholder {
+                String::from(systemd_boot::MACHINE_ID_PLACEHOLDER)
+            } else {
+                systemd_boot::resolve_machine_id(
+                    options.machine_id,
+                    Path::new(systemd_boot::MACHINE_ID_FILE),
+                    args.systemd_machine_id_setup.as_deref(),
+                )?
+                .unwrap_or_default()
+            };
+            let previous = match (args.incremental, &args.previous_manifest) {
+                (true, Some(previous_manifest)) => PreviousRun::load(previous_manifest),
+                _ => None,
+            };
+            let target = target::Target::local(machine_id)
+                .with_entry_path_prefix(&entry_path_prefix)
+                .with_grouped_specialisations(args.group_specialisations)
+                .with_deduped_kernel_params(dedupe_kernel_params)
+                .with_max_options_len(max_options_len)
+                .with_max_label_len(max_label_len)
+                .with_previous_run(previous);
+
+            systemd_boot::generate(
+                &bootables,
+                uki_builder,
+                args.systemd_efi_stub,
+                &target,
+                &args.entry_extra,
+            )?;
+
+            vec![(PathBuf::from(systemd_boot::ROOT), target)]
+        }
+    };
+
+    for (root, target) in targets {
+        let planned = systemd_boot::plan(&bootables, &target.efi_dir)?;
+        systemd_boot::write_manifest(&root, &planned, &args.entry_extra, &excluded, &target)?;
+        if let Some(mtime) = mtime {
+            deterministic::fix_mtimes(&root, mtime)?;
+        }
+    }
 
-    // TODO: grub
-    // grub::generate(bootables, args.objcopy)?;
+    if args.grub {
+        grub::generate_in(&grub_root, &bootables, grub_dedupe_kernel_params.as_deref())?;
+        if let Some(mtime) = mtime {
+            deterministic::fix_mtimes(&grub_root, mtime)?;
+        }
+    }
 
     Ok(())
 }
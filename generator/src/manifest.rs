@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::context::Context;
+use crate::esp_path;
+use crate::recompress::Compression;
+use crate::report::Document;
+pub use crate::report::{Manifest, ManifestFile, Source};
+use crate::Result;
+
+/// Where the manifest goes in a staging tree (and so on the ESP).
+pub const MANIFEST: &str = "loader/nixos-manifest.json";
+
+/// The version that manifests are stamped with, which the installer (from the same release) goes by
+/// to tell whether the files on the ESP were put there by a newer release than itself.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+impl Source {
+    pub fn new(path: &Path) -> Result<Self> {
+        Ok(Source {
+            path: path.to_path_buf(),
+            sha256: self::sha256_file(path)?,
+            compression: Compression::None,
+        })
+    }
+
+    pub fn compressed(self, compression: Compression) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_path_context(path)?;
+
+        Manifest::from_json(&contents).with_path_context(path)
+    }
+
+    /// Writes the manifest to [`MANIFEST`] in the staging tree at `root`.
+    pub fn write(&self, root: &Path) -> Result<()> {
+        let path = esp_path::join(root, MANIFEST);
+        let json = serde_json::to_string_pretty(self)?;
+
+        fs::write(&path, json).with_path_context(&path)?;
+
+        Ok(())
+    }
+}
+
+pub fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The SHA-256 of the file at `path`, read a buffer at a time.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    crate::stream::sha256_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
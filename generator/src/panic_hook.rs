@@ -0,0 +1,139 @@
+//! Turns a panic into a final structured error record, instead of leaving whatever runs the
+//! generator with nothing but a backtrace.
+//!
+//! The panic hook only records where the panic happened: the panic is caught with [`catch`], and
+//! the generator exits with [`EXIT_INTERNAL_ERROR`].
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use serde::Serialize;
+
+/// What the generator exits with when it panics (`EX_SOFTWARE` from `sysexits.h`), as opposed to
+/// `1` for the errors it reports itself.
+pub const EXIT_INTERNAL_ERROR: i32 = 70;
+
+thread_local! {
+    /// Where the last panic on this thread happened, set by the hook installed by [`catch`].
+    static LOCATION: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// The record of a panic, written to stderr as a single line of JSON.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PanicRecord {
+    pub level: &'static str,
+    pub kind: &'static str,
+    pub message: String,
+    /// The file, line, and column the panic happened at
+    pub location: Option<String>,
+    /// The last stage reached, for a caller that keeps track (see [`catch_staged`])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<&'static str>,
+}
+
+impl PanicRecord {
+    /// Writes the record to stderr.
+    pub fn emit(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => eprintln!("{}", json),
+            Err(_) => eprintln!("{:?}", self),
+        }
+    }
+}
+
+impl std::fmt::Display for PanicRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "internal error: panicked")?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        if let Some(stage) = &self.stage {
+            write!(f, " during {}", stage)?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Installs the hook recording where panics happen, which then hands them to the hook that was
+/// installed before (printing the message and, with `RUST_BACKTRACE`, the backtrace).
+fn install_hook() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(ToString::to_string);
+            LOCATION.with(|l| *l.borrow_mut() = location);
+
+            previous(info);
+        }));
+    });
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("(a panic payload that isn't a string)")
+    }
+}
+
+/// Runs `f`, turning a panic into a [`PanicRecord`].
+pub fn catch<T>(f: impl FnOnce() -> T) -> Result<T, PanicRecord> {
+    self::catch_staged(|| None, f)
+}
+
+/// Runs `f`, turning a panic into a [`PanicRecord`] that has the stage `last_stage` says was
+/// reached.
+pub fn catch_staged<T>(
+    last_stage: impl FnOnce() -> Option<&'static str>,
+    f: impl FnOnce() -> T,
+) -> Result<T, PanicRecord> {
+    self::install_hook();
+
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| PanicRecord {
+        level: "error",
+        kind: "panic",
+        message: self::message(&*payload),
+        location: LOCATION.with(|l| l.borrow_mut().take()),
+        stage: last_stage(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch() {
+        assert_eq!(catch(|| 42).unwrap(), 42);
+
+        let record = catch::<()>(|| panic!("couldn't {}", "generate")).unwrap_err();
+        assert_eq!(record.message, "couldn't generate");
+        let location = record.location.clone().unwrap();
+        assert!(location.contains("panic_hook.rs:"), "{}", location);
+
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["level"], "error");
+        assert_eq!(json["kind"], "panic");
+        assert_eq!(json["message"], "couldn't generate");
+        assert_eq!(json["location"], location.as_str());
+        assert!(json.get("stage").is_none());
+        assert_eq!(
+            record.to_string(),
+            format!(
+                "internal error: panicked at {}: couldn't generate",
+                location
+            )
+        );
+
+        let record = catch_staged::<()>(|| Some("inline"), || panic!("oops")).unwrap_err();
+        assert_eq!(record.stage, Some("inline"));
+        assert!(record.to_string().ends_with(" during inline: oops"));
+    }
+}
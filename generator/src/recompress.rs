@@ -0,0 +1,256 @@
+//! Compressing uncompressed initrds on their way to the ESP (see `--recompress-initrd`), which can
+//! make them several times smaller.
+//!
+//! Only initrds made up of nothing but cpio archives are compressed: ones that are already
+//! compressed (even if they start with an uncompressed cpio archive, e.g. of early microcode) are
+//! staged as-is.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::bootable::BootableToplevel;
+use crate::context::Context;
+use crate::stream::BUFFER_SIZE;
+use crate::validate;
+use crate::Result;
+
+/// How an initrd is compressed on its way to the ESP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// The initrd is staged as-is
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "invalid compression '{}' (expected 'zstd', 'gzip', or 'none')",
+                s
+            )),
+        }
+    }
+}
+
+impl Compression {
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    /// What's appended to the names of files compressed this way, so they don't collide with the
+    /// same file compressed differently (or not at all).
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => "-gzip",
+            Compression::Zstd => "-zstd",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(ruzstd::encoding::compress_to_vec(
+                data,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.decoder(data)?.read_to_end(&mut out)?;
+
+        Ok(out)
+    }
+
+    /// What decompresses what `reader` reads, as it's read.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(
+                ruzstd::decoding::StreamingDecoder::new(reader)
+                    .map_err(|e| format!("invalid zstd stream: {}", e))?,
+            ),
+        })
+    }
+
+    /// Writes the file at `src`, compressed, to `dest`, a buffer at a time.
+    pub fn compress_file(self, src: &Path, dest: &Path) -> Result<()> {
+        let mut reader =
+            BufReader::with_capacity(BUFFER_SIZE, File::open(src).with_path_context(src)?);
+        let mut writer =
+            BufWriter::with_capacity(BUFFER_SIZE, File::create(dest).with_path_context(dest)?);
+
+        match self {
+            Compression::None => {
+                io::copy(&mut reader, &mut writer).with_paths_context(src, dest)?;
+            }
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(&mut writer, flate2::Compression::default());
+                io::copy(&mut reader, &mut encoder).with_paths_context(src, dest)?;
+                encoder.finish().with_path_context(dest)?;
+            }
+            Compression::Zstd => ruzstd::encoding::compress(
+                reader,
+                &mut writer,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+        }
+        writer.flush().with_path_context(dest)?;
+
+        Ok(())
+    }
+}
+
+/// Sets the `initrd_compression` of every one of `toplevels` whose initrd is uncompressed to
+/// `compression` (see [`validate::is_uncompressed_initrd`]).
+pub fn plan_recompression(
+    toplevels: &mut [BootableToplevel],
+    compression: Compression,
+) -> Result<()> {
+    if compression.is_none() {
+        return Ok(());
+    }
+
+    // Generations (and their specialisations) share initrds.
+    let mut uncompressed = HashMap::new();
+
+    for toplevel in toplevels {
+        let initrd = &toplevel.initrd;
+        let is_uncompressed = match uncompressed.get(initrd) {
+            Some(is_uncompressed) => *is_uncompressed,
+            None => {
+                let is_uncompressed =
+                    validate::is_uncompressed_initrd(initrd).with_path_context(initrd)?;
+                if !is_uncompressed {
+                    debug!("'{}' is already compressed", initrd.display());
+                }
+                uncompressed.insert(initrd.clone(), is_uncompressed);
+
+                is_uncompressed
+            }
+        };
+
+        if is_uncompressed {
+            toplevel.initrd_compression = compression;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::tests::cpio;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd));
+        assert_eq!("gzip".parse(), Ok(Compression::Gzip));
+        assert_eq!("none".parse(), Ok(Compression::None));
+        assert!("xz".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let mut archive = cpio(&[("init", b"#!/bin/sh\n"), ("etc/hostname", b"nixos\n")]);
+        archive.resize(64 * 1024, 0);
+        let src = dir.join("initrd");
+        fs::write(&src, &archive).unwrap();
+
+        for (compression, magic) in [
+            (Compression::Gzip, validate::GZIP_MAGIC),
+            (Compression::Zstd, validate::ZSTD_MAGIC),
+        ] {
+            let dest = dir.join(format!("initrd{}", compression.suffix()));
+            compression.compress_file(&src, &dest).unwrap();
+
+            let compressed = fs::read(&dest).unwrap();
+            assert!(compressed.starts_with(magic), "{:?}", compression);
+            assert!(compressed.len() < archive.len());
+            // what the kernel checks before unpacking it
+            validate::validate_initrd(&dest).unwrap();
+            assert!(!validate::is_uncompressed_initrd(&dest).unwrap());
+
+            assert_eq!(compression.decompress(&compressed).unwrap(), archive);
+        }
+
+        assert!(Compression::Zstd.decompress(&archive).is_err());
+        assert!(Compression::Gzip.decompress(&archive).is_err());
+    }
+
+    #[test]
+    fn test_plan_recompression() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let uncompressed = dir.join("uncompressed");
+        fs::write(&uncompressed, cpio(&[("init", b"#!/bin/sh\n")])).unwrap();
+        let compressed = dir.join("compressed");
+        fs::write(
+            &compressed,
+            Compression::Gzip
+                .compress(&fs::read(&uncompressed).unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+
+        let toplevel = |initrd: &PathBuf| BootableToplevel {
+            initrd: initrd.clone(),
+            ..Default::default()
+        };
+        let mut toplevels = vec![
+            toplevel(&uncompressed),
+            toplevel(&compressed),
+            toplevel(&uncompressed),
+        ];
+
+        plan_recompression(&mut toplevels, Compression::None).unwrap();
+        assert!(toplevels.iter().all(|t| t.initrd_compression.is_none()));
+
+        plan_recompression(&mut toplevels, Compression::Zstd).unwrap();
+        assert_eq!(
+            toplevels
+                .iter()
+                .map(|t| t.initrd_compression)
+                .collect::<Vec<_>>(),
+            vec![Compression::Zstd, Compression::None, Compression::Zstd]
+        );
+
+        let missing = dir.join("missing");
+        let err = plan_recompression(&mut [toplevel(&missing)], Compression::Gzip)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&missing.display().to_string()));
+    }
+}
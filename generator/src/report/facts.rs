@@ -0,0 +1,32 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::Document;
+
+/// What the installer found out about the machine on its last successful run (see its `--facts`),
+/// which the generator's `--facts` takes as the defaults of the corresponding options.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Facts {
+    #[serde(rename = "formatVersion")]
+    pub format_version: u32,
+    /// The release of the installer that wrote the facts
+    pub installer_version: String,
+    /// The machine-id of the machine (the default of `--machine-id`)
+    pub machine_id: Option<String>,
+    /// The firmware's architecture, as systemd-boot names it (e.g. `x64`)
+    pub efi_arch: Option<String>,
+    /// The ESPs that were installed to (after ignoring duplicates)
+    pub esps: Vec<PathBuf>,
+    /// The XBOOTLDR partition that the generations' entries and files were put on, if any
+    pub xbootldr: Option<PathBuf>,
+    /// The directory the kernels, initrds, and unified EFI files are under on the ESP (the default
+    /// of `--entry-path-prefix`)
+    pub entry_path_prefix: Option<String>,
+}
+
+impl Document for Facts {
+    const NAME: &'static str = "facts";
+    const FORMAT_VERSION: u32 = 1;
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::Document;
+use crate::context::Context;
+use crate::Result;
+
+/// The installer's record of its last runs on an ESP, and of which of them last wrote each of the
+/// files it manages there, kept on the ESP itself (see the installer's `history`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct History {
+    #[serde(rename = "formatVersion")]
+    pub format_version: u32,
+    /// The last [`History::MAX_RUNS`] runs, oldest first
+    pub runs: Vec<HistoryRun>,
+    /// The run that last wrote each file, by its path relative to the partition it's on
+    pub files: BTreeMap<PathBuf, FileProvenance>,
+}
+
+impl Document for History {
+    const NAME: &'static str = "history";
+    const FORMAT_VERSION: u32 = 2;
+}
+
+/// One run of the installer on the ESP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct HistoryRun {
+    /// Counts up from 1 with every run on the ESP
+    pub run_id: u64,
+    /// When the run started, in seconds since the Unix epoch
+    pub started_at: u64,
+    pub installer_version: String,
+    /// How many files the run copied to the ESP or pruned from it
+    pub files_changed: usize,
+    /// The `default` in loader.conf after the run
+    pub default_entry: Option<String>,
+    /// Where what the run replaced or pruned was backed up to (see the installer's
+    /// `--backup-dir`), if anywhere
+    #[serde(default)]
+    pub backup: Option<PathBuf>,
+    /// Whether the run was undone by the installer's `rollback`
+    #[serde(default)]
+    pub rolled_back: bool,
+}
+
+/// Where a file on the ESP came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FileProvenance {
+    /// The run that last wrote the file
+    pub run_id: u64,
+    /// The newest generation that needs the file, if any does (e.g. not loader.conf)
+    pub generation: Option<usize>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            format_version: Self::FORMAT_VERSION,
+            runs: Vec::new(),
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+impl History {
+    /// How many runs are kept; older ones are dropped (but the files they wrote keep their run ID).
+    pub const MAX_RUNS: usize = 20;
+
+    /// Reads the history at `path`, or starts an empty one if there's none yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(json) => Self::from_json(&json).with_path_context(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_path_context(path),
+        }
+    }
+
+    /// The run ID the next run recorded gets.
+    pub fn next_run_id(&self) -> u64 {
+        self.runs.last().map_or(1, |last| last.run_id + 1)
+    }
+
+    /// Records `run` (whose `run_id` is replaced by the next one), which `written` the files (along
+    /// with the generation each belongs to) and `pruned` the others, and returns its run ID.
+    pub fn record(
+        &mut self,
+        mut run: HistoryRun,
+        written: BTreeMap<PathBuf, Option<usize>>,
+        pruned: &[PathBuf],
+    ) -> u64 {
+        run.run_id = self.next_run_id();
+        let run_id = run.run_id;
+
+        for path in pruned {
+            self.files.remove(path);
+        }
+        for (path, generation) in written {
+            self.files
+                .insert(path, FileProvenance { run_id, generation });
+        }
+
+        self.runs.push(run);
+        let excess = self.runs.len().saturating_sub(Self::MAX_RUNS);
+        self.runs.drain(..excess);
+
+        run_id
+    }
+
+    /// Writes the history to `path`, atomically, and flushes it to disk (it's written after the
+    /// ESP was synced).
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir).with_path_context(dir)?;
+
+        serde_json::to_writer_pretty(&mut tmp, self).with_path_context(path)?;
+        writeln!(tmp).with_path_context(path)?;
+        tmp.as_file().sync_all().with_path_context(path)?;
+        tmp.persist(path).with_path_context(path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(started_at: u64, files_changed: usize) -> HistoryRun {
+        HistoryRun {
+            run_id: 0,
+            started_at,
+            installer_version: String::from("0.1.0"),
+            files_changed,
+            default_entry: Some(String::from("nixos-generation-2.conf")),
+            backup: None,
+            rolled_back: false,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut history = History::default();
+        assert_eq!(history.next_run_id(), 1);
+        let written = |paths: &[(&str, Option<usize>)]| {
+            paths
+                .iter()
+                .map(|(path, generation)| (PathBuf::from(path), *generation))
+                .collect()
+        };
+
+        let first = history.record(
+            run(100, 3),
+            written(&[
+                ("EFI/nixos/kernel-1.efi", Some(1)),
+                ("loader/entries/nixos-generation-1.conf", Some(1)),
+                ("loader/loader.conf", None),
+            ]),
+            &[],
+        );
+        let second = history.record(
+            run(200, 3),
+            written(&[
+                ("EFI/nixos/kernel-2.efi", Some(2)),
+                ("loader/entries/nixos-generation-2.conf", Some(2)),
+            ]),
+            &[PathBuf::from("EFI/nixos/kernel-1.efi")],
+        );
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(history.next_run_id(), 3);
+        assert_eq!(
+            history
+                .runs
+                .iter()
+                .map(|run| run.run_id)
+                .collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(
+            history.files,
+            [
+                ("EFI/nixos/kernel-2.efi", 2, Some(2)),
+                ("loader/entries/nixos-generation-1.conf", 1, Some(1)),
+                ("loader/entries/nixos-generation-2.conf", 2, Some(2)),
+                ("loader/loader.conf", 1, None),
+            ]
+            .iter()
+            .map(|(path, run_id, generation)| (
+                PathBuf::from(path),
+                FileProvenance {
+                    run_id: *run_id,
+                    generation: *generation
+                }
+            ))
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_record_truncates() {
+        let mut history = History::default();
+        history.record(
+            run(0, 1),
+            std::iter::once((PathBuf::from("loader/loader.conf"), None)).collect(),
+            &[],
+        );
+        for started_at in 1..25 {
+            history.record(run(started_at, 0), BTreeMap::new(), &[]);
+        }
+
+        // the newest runs are kept, and the IDs keep counting up
+        assert_eq!(history.runs.len(), History::MAX_RUNS);
+        assert_eq!(history.runs.first().unwrap().run_id, 6);
+        assert_eq!(history.runs.last().unwrap().run_id, 25);
+        assert_eq!(history.runs.last().unwrap().started_at, 24);
+        // the file keeps the ID of the run that was dropped
+        assert_eq!(history.files[Path::new("loader/loader.conf")].run_id, 1);
+        assert_eq!(history.record(run(25, 0), BTreeMap::new(), &[]), 26);
+    }
+
+    #[test]
+    fn test_load_and_write() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("nixos-history.json");
+        assert_eq!(History::load(&path).unwrap(), History::default());
+
+        let mut history = History::default();
+        history.record(run(100, 0), BTreeMap::new(), &[]);
+        history.write(&path).unwrap();
+        assert_eq!(History::load(&path).unwrap(), history);
+        // nothing but the history is left behind
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+}
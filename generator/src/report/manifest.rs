@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::Document;
+use crate::recompress::Compression;
+
+/// What the kernels, initrds, and unified EFI files in a staging tree hash to, and what they were
+/// made from, so that the installer can check that they made it to the ESP unchanged.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Manifest {
+    #[serde(rename = "formatVersion", default)]
+    pub format_version: u32,
+    /// The release of the generator that wrote the manifest
+    #[serde(default)]
+    pub version: String,
+    pub files: Vec<ManifestFile>,
+    /// What the entries hash to as staged, by entry (relative to the root of the staging tree)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub entries: BTreeMap<String, String>,
+    /// The `--entry-extra` directives appended to entries, by entry (relative to the root of the
+    /// staging tree)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub entry_extras: BTreeMap<String, Vec<String>>,
+    /// The kernel params that were left out of entries (and the unified EFI files they boot)
+    /// because a later one with the same name overrides them (see `--dedupe-kernel-params`), by
+    /// entry (relative to the root of the staging tree)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deduped_kernel_params: BTreeMap<String, Vec<String>>,
+    /// The files and entries that were left out of the staging tree because they are unchanged
+    /// since the previous run (see `--incremental`), relative to the root of the staging tree
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unchanged: Vec<String>,
+    /// The entries that the specialisations left out by `--exclude-specialisation` or
+    /// `--include-specialisation` would have had, relative to the root of the staging tree
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_specialisations: Vec<String>,
+}
+
+impl Document for Manifest {
+    const NAME: &'static str = "manifest";
+    const FORMAT_VERSION: u32 = 3;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ManifestFile {
+    /// The file's path, relative to the root of the staging tree
+    pub path: String,
+    /// The SHA-256 of the file as staged
+    pub sha256: String,
+    /// The store path that was staged as-is (for kernels and initrds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    /// The store paths that were embedded, by PE section (for unified EFI files)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sections: BTreeMap<String, Source>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Source {
+    pub path: PathBuf,
+    pub sha256: String,
+    /// How the store path was compressed on its way into the staging tree (see
+    /// `--recompress-initrd`), which `sha256` is from before
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    pub compression: Compression,
+}
@@ -0,0 +1,203 @@
+//! The JSON documents the generator and installer write for other tools to read: the manifest of a
+//! staging tree (see [`crate::manifest`]), the installer's `--report` of a run, the facts it
+//! leaves for the generator (see [`crate::facts`]), and the history of its runs on an ESP.
+//!
+//! Every document has a `formatVersion`, which is bumped whenever its schema changes, so that
+//! parsers can tell what they're reading. The schema of every format version is committed to
+//! `fixtures/schema/` (generated with the `schemars` feature), and a document of every format
+//! version to `fixtures/report/`, which this release has to keep reading.
+
+use std::convert::TryFrom;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Result;
+
+mod facts;
+mod history;
+mod manifest;
+mod run;
+
+pub use facts::Facts;
+pub use history::{FileProvenance, History, HistoryRun};
+pub use manifest::{Manifest, ManifestFile, Source};
+pub use run::{
+    Change, EspReport, FileChange, FsState, HookReport, InitrdSecretsReport, ManagedFile, Outcome,
+    ResolvedConfig, RunReport, Secrets, StageReport, Summary, Timeout, WarningReport,
+};
+
+/// The field every document has its format version in.
+pub const FORMAT_VERSION_FIELD: &str = "formatVersion";
+
+/// A versioned JSON document.
+pub trait Document: Serialize + DeserializeOwned {
+    /// What the document is called, in errors and the names of its fixtures
+    const NAME: &'static str;
+    /// The format version this release writes, and the newest it reads
+    const FORMAT_VERSION: u32;
+
+    /// Parses a document of this or an earlier format version.
+    fn from_json(json: &str) -> Result<Self> {
+        let document: Value = serde_json::from_str(json)?;
+        Self::check_format_version(&document)?;
+
+        Ok(serde_json::from_value(document)?)
+    }
+
+    /// Refuses documents from a newer format version than this release knows, instead of
+    /// misreading them.
+    fn check_format_version(document: &Value) -> Result<()> {
+        let version = self::format_version(document);
+        if version > Self::FORMAT_VERSION {
+            return Err(format!(
+                "the {} is format version {}, but this release only reads up to {}",
+                Self::NAME,
+                version,
+                Self::FORMAT_VERSION
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "schemars")]
+    fn schema() -> schemars::schema::RootSchema
+    where
+        Self: schemars::JsonSchema,
+    {
+        schemars::schema_for!(Self)
+    }
+}
+
+/// The format version of `document` (0 if it's from before documents had one).
+pub fn format_version(document: &Value) -> u32 {
+    document
+        .get(FORMAT_VERSION_FIELD)
+        // what format version 1 of the run report called it
+        .or_else(|| document.get("schema_version"))
+        .and_then(Value::as_u64)
+        .map(|version| u32::try_from(version).unwrap_or(u32::MAX))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn fixture(dir: &str, name: &str, version: u32) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(dir)
+            .join(format!("{}.v{}.json", name, version))
+    }
+
+    /// Reads the committed documents of every format version, and checks that the current one
+    /// is read and written back unchanged.
+    fn check_fixtures<D: Document>(first_version: u32) {
+        for version in first_version..=D::FORMAT_VERSION {
+            let path = self::fixture("report", D::NAME, version);
+            let json =
+                fs::read_to_string(&path).unwrap_or_else(|e| panic!("'{}': {}", path.display(), e));
+            let document = D::from_json(&json)
+                .unwrap_or_else(|e| panic!("'{}' no longer reads: {}", path.display(), e));
+
+            if version == D::FORMAT_VERSION {
+                assert_eq!(
+                    serde_json::to_value(&document).unwrap(),
+                    serde_json::from_str::<Value>(&json).unwrap(),
+                    "'{}'",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_previous_format_versions() {
+        // manifests were written before they had a format version
+        self::check_fixtures::<Manifest>(0);
+        self::check_fixtures::<RunReport>(1);
+        self::check_fixtures::<Facts>(1);
+        self::check_fixtures::<History>(1);
+    }
+
+    #[test]
+    fn test_format_version() {
+        assert_eq!(format_version(&serde_json::json!({})), 0);
+        assert_eq!(
+            format_version(&serde_json::json!({ "schema_version": 1 })),
+            1
+        );
+        assert_eq!(
+            format_version(&serde_json::json!({ "formatVersion": 2 })),
+            2
+        );
+
+        let newer = serde_json::json!({
+            "formatVersion": Manifest::FORMAT_VERSION + 1,
+            "files": [],
+        });
+        let err = Manifest::from_json(&newer.to_string())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("only reads up to"), "{}", err);
+    }
+
+    #[test]
+    fn test_committed_schemas() {
+        // bumping a format version means committing the new schema (see `test_schemas`)
+        for (name, version) in [
+            (Manifest::NAME, Manifest::FORMAT_VERSION),
+            (RunReport::NAME, RunReport::FORMAT_VERSION),
+            (Facts::NAME, Facts::FORMAT_VERSION),
+            (History::NAME, History::FORMAT_VERSION),
+        ] {
+            let path = self::fixture("schema", name, version);
+            assert!(path.exists(), "'{}' isn't committed", path.display());
+        }
+    }
+
+    /// Compares the schema of `D` against the one committed for its format version, which is only
+    /// ever written once: changing the schema means bumping the format version.
+    #[cfg(feature = "schemars")]
+    fn check_schema<D: Document + schemars::JsonSchema>() {
+        let path = self::fixture("schema", D::NAME, D::FORMAT_VERSION);
+        let schema = serde_json::to_value(D::schema()).unwrap();
+
+        match fs::read_to_string(&path) {
+            Ok(committed) => assert!(
+                serde_json::from_str::<Value>(&committed).unwrap() == schema,
+                "the {} schema changed, but its format version didn't: bump its FORMAT_VERSION, \
+                 and commit the new schema and a document of the new format version (see \
+                 `report`)",
+                D::NAME
+            ),
+            Err(_) if std::env::var_os(golden::REGEN_ENV).is_some() => {
+                let schema = serde_json::to_string_pretty(&schema).unwrap();
+                fs::write(&path, schema + "\n").unwrap();
+            }
+            Err(e) => panic!(
+                "couldn't read the {} schema '{}': {} (run this test with {} set to write the \
+                 schema of a new format version)",
+                D::NAME,
+                path.display(),
+                e,
+                golden::REGEN_ENV
+            ),
+        }
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn test_schemas() {
+        self::check_schema::<Manifest>();
+        self::check_schema::<RunReport>();
+        self::check_schema::<Facts>();
+        self::check_schema::<History>();
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::Document;
+use crate::context::Context;
+use crate::Result;
+
+/// The installer's `--report` of a run, for archiving what it did to the ESP(s).
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RunReport {
+    #[serde(rename = "formatVersion", alias = "schema_version")]
+    pub format_version: u32,
+    pub installer_version: String,
+    /// When the run started, in seconds since the Unix epoch
+    pub started_at: u64,
+    pub duration_ms: u64,
+    /// `None` if the run failed before resolving it
+    pub config: Option<ResolvedConfig>,
+    /// The versions of the tools the run used, e.g. `systemd-boot`
+    pub tools: BTreeMap<String, String>,
+    pub esps: Vec<EspReport>,
+    /// The generations with an `append-initrd-secrets` script, and whether it has to run
+    pub initrd_secrets: Vec<InitrdSecretsReport>,
+    pub summary: Summary,
+    /// The warnings the run emitted, by kind
+    #[serde(default)]
+    pub warnings: Vec<WarningReport>,
+    /// Why the run failed, if it did
+    pub error: Option<String>,
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+}
+
+impl Document for RunReport {
+    const NAME: &'static str = "run-report";
+    // 1 called `formatVersion` `schema_version`, 2 had no `run_id` or `managed_files`, 3 had no
+    // `warnings`, 4 had no `hooks`
+    const FORMAT_VERSION: u32 = 5;
+}
+
+/// The configuration the run ended up with, after applying defaults and overrides.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ResolvedConfig {
+    pub toplevel: PathBuf,
+    pub default_generation: usize,
+    /// The generations that get entries
+    pub wanted_generations: Vec<usize>,
+    /// The ESPs that were updated (after ignoring duplicates)
+    pub esps: Vec<PathBuf>,
+    pub install: bool,
+    pub dry_run: bool,
+    pub ignore_dirty_esp: bool,
+    pub timeout: Option<Timeout>,
+    pub default_entry: Option<String>,
+    pub configuration_limit: Option<usize>,
+    pub editor: bool,
+    pub console_mode: String,
+    pub unified_efi: bool,
+    pub secure_boot: bool,
+    pub chainloads: Vec<String>,
+    pub retired_profiles: Vec<String>,
+}
+
+/// How long the bootloader waits before booting the default entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Timeout {
+    Seconds(u32),
+    Forever,
+}
+
+/// What the run did to one ESP.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct EspReport {
+    pub esp: PathBuf,
+    /// The generation loader.conf defaults to
+    pub loader_default: usize,
+    /// The generation booted once, if one was staged
+    pub oneshot: Option<usize>,
+    /// Whether the ESP's filesystem was cleanly unmounted, checked before anything else
+    pub fs_state: Option<FsState>,
+    /// Every state of the plan, in order, including the ones that didn't run
+    pub stages: Vec<StageReport>,
+    /// The files that were added, replaced, or pruned, relative to the ESP
+    pub files: Vec<FileChange>,
+    /// The run's ID in the ESP's history, if it got as far as recording it
+    #[serde(default)]
+    pub run_id: Option<u64>,
+    /// Every file the installer manages on the ESP (and XBOOTLDR partition) after the run
+    #[serde(default)]
+    pub managed_files: Vec<ManagedFile>,
+    /// The `--hook`s that ran, in order
+    #[serde(default)]
+    pub hooks: Vec<HookReport>,
+}
+
+/// Whether an ESP's filesystem is safe to write to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum FsState {
+    Clean,
+    /// The volume wasn't cleanly unmounted, and should be checked with `fsck.vfat` first
+    Dirty,
+    /// The ESP isn't a FAT filesystem (e.g. in a VM's virtiofs share), so there's nothing to check
+    NotFat,
+    /// The ESP's device couldn't be found or read
+    Unknown,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StageReport {
+    pub stage: String,
+    pub outcome: Outcome,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The stage was only planned (with `--dry-run`)
+    Planned,
+    Succeeded,
+    Failed,
+    /// An earlier stage failed
+    Skipped,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct InitrdSecretsReport {
+    pub generation: usize,
+    pub profile: Option<String>,
+    pub script: PathBuf,
+    pub secrets: Secrets,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Secrets {
+    /// There's no script
+    Absent,
+    /// The script does nothing, so running it is skipped
+    NoOp,
+    /// The script's profile is `--assume-no-secrets-for`, so running it is skipped
+    AssumedNone,
+    /// The script appends secrets (or might), so it has to run
+    Required,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub change: Change,
+    /// Whether the file was signed on its way to the ESP
+    pub signed: bool,
+    pub sha256_before: Option<String>,
+    pub sha256_after: Option<String>,
+}
+
+/// A file the installer manages, and where it came from.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ManagedFile {
+    /// The file's path, relative to the partition it's on
+    pub path: PathBuf,
+    /// When the file last changed, in seconds since the Unix epoch (`None` if it's missing)
+    pub mtime: Option<u64>,
+    /// The run that last wrote the file
+    pub run_id: u64,
+    /// The newest generation that needs the file, if any does
+    pub generation: Option<usize>,
+}
+
+/// A `--hook` that ran on the staged files before they were copied to the ESP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct HookReport {
+    pub hook: PathBuf,
+    /// Whether it exited successfully (a hook that didn't fails the run)
+    pub succeeded: bool,
+    /// What it printed to stdout
+    pub stdout: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Replaced,
+    Pruned,
+}
+
+/// The warnings of one kind that the run emitted.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct WarningReport {
+    /// What the warnings are about, as `--strict` names it (e.g. `dirty-esp`)
+    pub kind: String,
+    pub count: usize,
+    /// Whether `--strict` fails the run over this kind
+    pub strict: bool,
+    /// Every warning of this kind, in the order it was emitted
+    pub messages: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Summary {
+    pub added: usize,
+    pub replaced: usize,
+    pub pruned: usize,
+    pub signed: usize,
+}
+
+impl RunReport {
+    pub fn start(installer_version: &str) -> Self {
+        Self {
+            format_version: Self::FORMAT_VERSION,
+            installer_version: installer_version.to_string(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            duration_ms: 0,
+            config: None,
+            tools: BTreeMap::new(),
+            esps: Vec::new(),
+            initrd_secrets: Vec::new(),
+            summary: Summary::default(),
+            warnings: Vec::new(),
+            error: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records how the run ended, and totals up what it did.
+    pub fn finish(&mut self, ret: &Result<()>) {
+        self.duration_ms = self::millis(self.started.elapsed());
+        self.error = ret.as_ref().err().map(ToString::to_string);
+
+        let mut summary = Summary::default();
+        for file in self.esps.iter().flat_map(|esp| &esp.files) {
+            match file.change {
+                Change::Added => summary.added += 1,
+                Change::Replaced => summary.replaced += 1,
+                Change::Pruned => summary.pruned += 1,
+            }
+            if file.signed {
+                summary.signed += 1;
+            }
+        }
+        self.summary = summary;
+    }
+
+    /// Writes the report to `path`, atomically: a reader sees either the previous report (if any)
+    /// or this one.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir).with_path_context(dir)?;
+
+        serde_json::to_writer_pretty(&mut tmp, self).with_path_context(path)?;
+        writeln!(tmp).with_path_context(path)?;
+        tmp.persist(path).with_path_context(path)?;
+
+        Ok(())
+    }
+}
+
+impl EspReport {
+    /// Records the difference between the ESP's contents `before` and `after` the run, as the
+    /// SHA-256 of every file by its path relative to the ESP. `signed` are the ESP paths of the
+    /// files that were signed.
+    pub fn record_files(
+        &mut self,
+        before: &BTreeMap<PathBuf, String>,
+        after: &BTreeMap<PathBuf, String>,
+        signed: &BTreeSet<PathBuf>,
+    ) {
+        let paths = before.keys().chain(after.keys()).collect::<BTreeSet<_>>();
+
+        for path in paths {
+            let (sha256_before, sha256_after) = (before.get(path), after.get(path));
+            let change = match (sha256_before, sha256_after) {
+                (None, Some(_)) => Change::Added,
+                (Some(a), Some(b)) if a != b => Change::Replaced,
+                (Some(_), None) => Change::Pruned,
+                _ => continue,
+            };
+
+            self.files.push(FileChange {
+                path: path.clone(),
+                change,
+                signed: signed.contains(&self.esp.join(path)),
+                sha256_before: sha256_before.cloned(),
+                sha256_after: sha256_after.cloned(),
+            });
+        }
+    }
+}
+
+impl StageReport {
+    pub fn new(stage: &str, duration: Duration, ret: &Result<()>) -> Self {
+        Self {
+            stage: stage.to_string(),
+            outcome: if ret.is_ok() {
+                Outcome::Succeeded
+            } else {
+                Outcome::Failed
+            },
+            duration_ms: self::millis(duration),
+            error: ret.as_ref().err().map(ToString::to_string),
+        }
+    }
+
+    pub fn not_run(stage: &str, outcome: Outcome) -> Self {
+        Self {
+            stage: stage.to_string(),
+            outcome,
+            duration_ms: 0,
+            error: None,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
@@ -0,0 +1,189 @@
+//! Reading files a fixed-size buffer at a time, for everything that hashes or copies the staged
+//! artifacts: a unified EFI file can be hundreds of MB, more than a recovery environment may have
+//! memory for, so nothing should read one into memory whole.
+//!
+//! `TrackingAllocator` is what tests (of both the generator and the installer) check that with.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::context::Context;
+use crate::Result;
+
+/// How much of a file is read at a time.
+pub const BUFFER_SIZE: usize = 1 << 20;
+
+/// Feeds everything `reader` reads to `f`, a buffer (of at most [`BUFFER_SIZE`] bytes) at a time.
+pub fn for_each_chunk(mut reader: impl Read, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE];
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => f(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The SHA-256 of everything `reader` reads, in hex.
+pub fn sha256(reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    self::for_each_chunk(reader, |chunk| hasher.update(chunk))?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The SHA-256 of the file at `path`, in hex.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let file = File::open(path).with_path_context(path)?;
+
+    self::sha256(file).with_path_context(path)
+}
+
+/// What tests (of both the generator and the installer, which gets it with the `test-support`
+/// feature) check the streaming with, which isn't part of the API.
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub use self::test_support::{largest_allocation, sparse_file, TrackingAllocator};
+
+#[cfg(any(test, feature = "test-support"))]
+mod test_support {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use crate::context::Context;
+    use crate::Result;
+
+    thread_local! {
+        /// The largest allocation this thread made since [`largest_allocation`] last reset it.
+        static LARGEST: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// The system allocator, recording the largest allocation each thread makes (see
+    /// [`largest_allocation`]). Test binaries use it as their global allocator.
+    pub struct TrackingAllocator;
+
+    impl TrackingAllocator {
+        fn record(size: usize) {
+            // The thread may be on its way out.
+            let _ = LARGEST.try_with(|largest| largest.set(largest.get().max(size)));
+        }
+    }
+
+    unsafe impl GlobalAlloc for TrackingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            Self::record(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            Self::record(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            Self::record(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    /// Runs `f`, and returns what it did along with the largest allocation it made on this thread
+    /// (as far as the [`TrackingAllocator`] knows, which is nothing unless it's the global
+    /// allocator).
+    pub fn largest_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        LARGEST.with(|largest| largest.set(0));
+        let ret = f();
+
+        (ret, LARGEST.with(Cell::get))
+    }
+
+    /// A sparse file of `len` bytes at `path`, ending in `tail`, for tests that stream large files
+    /// without writing them out (or taking long to read).
+    pub fn sparse_file(path: &Path, len: u64, tail: &[u8]) -> Result<()> {
+        let tail_start = len
+            .checked_sub(tail.len() as u64)
+            .ok_or_else(|| format!("a tail of {} bytes doesn't fit in {}", tail.len(), len))?;
+
+        let mut file = File::create(path).with_path_context(path)?;
+        file.set_len(len).with_path_context(path)?;
+        file.seek(SeekFrom::Start(tail_start))
+            .with_path_context(path)?;
+        file.write_all(tail).with_path_context(path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+
+    #[test]
+    fn test_for_each_chunk() {
+        let data = (0..BUFFER_SIZE * 2 + 3)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let mut chunks = Vec::new();
+        let mut read = Vec::new();
+        for_each_chunk(&data[..], |chunk| {
+            chunks.push(chunk.len());
+            read.extend_from_slice(chunk);
+        })
+        .unwrap();
+
+        assert_eq!(chunks, [BUFFER_SIZE, BUFFER_SIZE, 3]);
+        assert_eq!(read, data);
+        assert_eq!(sha256(&data[..]).unwrap(), manifest::sha256(&data));
+    }
+
+    #[test]
+    fn test_sha256_file_streams() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("uki.efi");
+        let len = 256 << 20;
+        sparse_file(&path, len, b"the end").unwrap();
+
+        let (sha256, largest) = largest_allocation(|| sha256_file(&path).unwrap());
+        assert_eq!(sha256.len(), 64);
+        assert!(
+            largest <= 2 * BUFFER_SIZE,
+            "hashing a {} byte file allocated {} bytes at once",
+            len,
+            largest
+        );
+
+        // what reading it whole would look like
+        let (buf, largest) = largest_allocation(|| vec![0u8; 3 * BUFFER_SIZE]);
+        assert!(largest >= buf.len());
+    }
+
+    #[test]
+    fn test_sparse_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("uki.efi");
+
+        sparse_file(&path, 8, b"end").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\0\0\0\0\0end");
+
+        // a tail that doesn't fit is an error, not an underflow
+        let err = sparse_file(&path, 2, b"end").unwrap_err().to_string();
+        assert!(err.contains("doesn't fit"), "{}", err);
+    }
+}
@@ -1,238 +1,1599 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
 use std::fs::{self, File};
-use std::io::Write;
-use std::os::unix;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use bootspec::SpecialisationName;
+use log::{debug, warn};
 
-use crate::bootable::{Bootable, BootableToplevel, EfiProgram};
+use crate::bootable::{Bootable, BootableToplevel, EfiProgram, UkiBuilder};
+use crate::cmdline;
+use crate::command;
+use crate::context::Context;
+use crate::entry_extra::{self, EntryExtra};
+use crate::esp_path;
+use crate::manifest::{self, Manifest, ManifestFile, Source};
+use crate::recompress::Compression;
+use crate::report::Document;
+use crate::stream;
+use crate::target::Target;
+use crate::util;
+use crate::validate;
+use crate::warnings::{self, Kind};
 use crate::Result;
 
+mod plan;
+
+pub use plan::{entry_name, plan, ArtifactPlan, Payload, PlannedBootable};
+
 // FIXME: placeholder dir
 pub const ROOT: &str = "systemd-boot-entries";
-const STORE_PATH_PREFIX: &str = "/nix/store/";
-const STORE_HASH_LEN: usize = 32;
+/// What entries have instead of the machine-id with `--machine-id-placeholder`, for the installer
+/// to substitute the machine-id of the machine it runs on for.
+pub const MACHINE_ID_PLACEHOLDER: &str = "@MACHINE_ID@";
+/// Where the machine-id of the machine the generator runs on is read from, unless `--machine-id`
+/// is passed.
+pub const MACHINE_ID_FILE: &str = "/etc/machine-id";
 
 #[derive(Default, Debug)]
 pub struct StorePath(PathBuf);
 #[derive(Default, Debug)]
 pub struct EspPath(String);
 
-#[derive(Default, Debug)]
-pub struct Contents {
-    /// The contents of the generation conf file.
-    pub conf: String,
-    /// The kernel's store path
-    pub kernel_src: Option<PathBuf>,
-    /// The kernel's destination path (inside the ESP)
-    pub kernel_dest: Option<String>,
-    /// The initrd's store path
-    pub initrd_src: Option<PathBuf>,
-    /// The initrd's destination path (inside the ESP)
-    pub initrd_dest: Option<String>,
-    /// The unified EFI file's destination path (inside the ESP)
-    pub unified_dest: Option<String>,
+/// Generates the staging tree at [`ROOT`] for this machine, which `target` describes (see
+/// [`Target::local`] and [`resolve_machine_id`]), with the matching `entry_extras` appended to its
+/// entries.
+pub fn generate(
+    bootables: &[Bootable],
+    uki_builder: Option<UkiBuilder>,
+    systemd_efi_stub: Option<PathBuf>,
+    target: &Target,
+    entry_extras: &[EntryExtra],
+) -> Result<()> {
+    self::generate_tree(
+        Path::new(self::ROOT),
+        target,
+        bootables,
+        uki_builder.as_ref(),
+        systemd_efi_stub.as_deref(),
+        entry_extras,
+        &mut HashMap::new(),
+    )
 }
 
-pub fn generate(
-    bootables: Vec<Bootable>,
-    objcopy: Option<PathBuf>,
+/// Generates a staging tree at `<out_dir>/<target.name>` for each of `targets`, with the matching
+/// `entry_extras` appended to their entries.
+///
+/// Unified EFI files are only built once for every distinct toplevel, command line, and stub; the
+/// targets that share them get hard links (or copies, if that fails) of the first one built.
+pub fn generate_targets(
+    bootables: &[Bootable],
+    uki_builder: Option<UkiBuilder>,
     systemd_efi_stub: Option<PathBuf>,
-    systemd_machine_id_setup: PathBuf,
+    out_dir: &Path,
+    targets: &[Target],
+    entry_extras: &[EntryExtra],
 ) -> Result<()> {
-    let machine_id = self::get_machine_id(&systemd_machine_id_setup)?;
-    let efi_nixos = format!("{}/EFI/nixos", self::ROOT);
-    let loader_entries = format!("{}/loader/entries", self::ROOT);
-    fs::create_dir_all(&efi_nixos)?;
-    fs::create_dir_all(&loader_entries)?;
-
-    for bootable in bootables {
-        match bootable {
-            Bootable::Efi(efi) => {
-                let (path, contents) = self::efi_entry_impl(&efi, &machine_id)?;
-                let mut f = File::create(path)?;
-                write!(f, "{}", contents.conf)?;
-
-                let unified_dest = contents.unified_dest.unwrap();
-                let objcopy = objcopy.as_ref().unwrap();
-                let systemd_efi_stub = systemd_efi_stub.as_ref().unwrap();
-
-                efi.write_unified_efi(objcopy, Path::new(&unified_dest), systemd_efi_stub)?;
-            }
-            Bootable::Linux(toplevel) => {
-                let (path, contents) = self::linux_entry_impl(&toplevel, &machine_id)?;
-                let mut f = File::create(path)?;
-                write!(f, "{}", contents.conf)?;
-
-                let kernel_dest = contents.kernel_dest.unwrap();
-                let kernel_src = contents.kernel_src.unwrap();
-                let initrd_dest = contents.initrd_dest.unwrap();
-                let initrd_src = contents.initrd_src.unwrap();
-
-                if !Path::new(&kernel_dest).exists() {
-                    unix::fs::symlink(kernel_src, kernel_dest)?;
+    let mut built = HashMap::new();
+
+    for target in targets {
+        let root = out_dir.join(&target.name);
+        let stub = target
+            .systemd_efi_stub
+            .as_deref()
+            .or(systemd_efi_stub.as_deref());
+
+        self::generate_tree(
+            &root,
+            target,
+            bootables,
+            uki_builder.as_ref(),
+            stub,
+            entry_extras,
+            &mut built,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The inputs that fully determine the contents of a unified EFI file.
+type UnifiedKey = (PathBuf, String, PathBuf);
+
+fn generate_tree(
+    root: &Path,
+    target: &Target,
+    bootables: &[Bootable],
+    uki_builder: Option<&UkiBuilder>,
+    systemd_efi_stub: Option<&Path>,
+    entry_extras: &[EntryExtra],
+    built: &mut HashMap<UnifiedKey, PathBuf>,
+) -> Result<()> {
+    let mut planned = self::plan(bootables, &target.efi_dir)?;
+    // The same tree is written in the same order, whatever order the generations came in.
+    planned.sort_by(|a, b| a.plan.conf.cmp(&b.plan.conf));
+
+    let efi_dir = esp_path::join(root, &target.efi_dir);
+    let loader_entries = esp_path::join(root, "loader/entries");
+    fs::create_dir_all(&efi_dir).with_path_context(&efi_dir)?;
+    fs::create_dir_all(&loader_entries).with_path_context(&loader_entries)?;
+
+    for planned in &planned {
+        let PlannedBootable { bootable, plan, .. } = planned;
+        let entry = self::entry(planned, target, entry_extras)?;
+        let conf = plan.conf.to_string();
+        let previous_entry = target.previous.as_ref().and_then(|p| p.entry(&conf));
+        if previous_entry == Some(manifest::sha256(entry.as_bytes()).as_str()) {
+            debug!("'{}' is unchanged since the previous run", plan.conf);
+        } else {
+            let path = plan::staged(root, &plan.conf);
+            fs::write(&path, entry).with_path_context(&path)?;
+        }
+
+        match (bootable, &plan.payload) {
+            (Bootable::Efi(efi), Payload::Unified(unified)) => {
+                let unified_dest = plan::staged(root, unified);
+                let uki_builder = uki_builder.unwrap();
+                let systemd_efi_stub = systemd_efi_stub.unwrap();
+
+                let key = (
+                    efi.source.toplevel.0.clone(),
+                    efi.cmdline(target)?,
+                    systemd_efi_stub.to_path_buf(),
+                );
+                match built.get(&key) {
+                    Some(existing) if *existing == unified_dest => {}
+                    Some(existing) => util::link_or_copy(existing, &unified_dest)?,
+                    None => {
+                        efi.write_unified_efi(
+                            uki_builder,
+                            &unified_dest,
+                            systemd_efi_stub,
+                            target,
+                        )?;
+                        built.insert(key, unified_dest);
+                    }
                 }
+            }
+            (Bootable::Linux(toplevel), Payload::Linux { kernel, initrd }) => {
+                for (src, dest, compression) in [
+                    (&toplevel.kernel, kernel, Compression::None),
+                    (&toplevel.initrd, initrd, toplevel.initrd_compression),
+                ] {
+                    if let Some(previous) = &target.previous {
+                        if previous.file(dest.as_str(), src, compression).is_some() {
+                            debug!("'{}' is unchanged since the previous run", dest);
+                            continue;
+                        }
+                    }
 
-                if !Path::new(&initrd_dest).exists() {
-                    unix::fs::symlink(initrd_src, initrd_dest)?;
+                    let dest = plan::staged(root, dest);
+                    if dest.exists() {
+                        continue;
+                    }
+
+                    if compression.is_none() {
+                        util::symlink_or_copy(src, &dest)?;
+                    } else {
+                        compression.compress_file(src, &dest)?;
+                    }
                 }
             }
+            _ => unreachable!("plans match their bootables"),
         }
     }
 
     Ok(())
 }
 
-fn efi_entry_impl(efi: &EfiProgram, machine_id: &str) -> Result<(String, Contents)> {
-    let generation = efi.source.generation_index;
+/// Roughly how long an entry is, so that rendering one doesn't have to grow the string it's
+/// written to.
+const ENTRY_CAPACITY: usize = 512;
+
+/// Renders the entry for `planned`, with `target`'s machine-id (and extra kernel params), followed
+/// by the `entry_extras` that match it.
+pub fn entry(
+    planned: &PlannedBootable,
+    target: &Target,
+    entry_extras: &[EntryExtra],
+) -> Result<String> {
+    let mut data = String::with_capacity(ENTRY_CAPACITY);
+    match planned.bootable {
+        Bootable::Efi(efi) => self::efi_entry_impl(&mut data, efi, &planned.plan, target)?,
+        Bootable::Linux(toplevel) => self::linux_entry_impl(&mut data, toplevel, planned, target)?,
+    }
+    if !target.machine_id.is_empty() {
+        writeln!(data, "machine-id {}", target.machine_id)?;
+    }
+
+    for line in entry_extra::lines(entry_extras, planned.bootable.toplevel()) {
+        data.push_str(&line);
+        data.push('\n');
+    }
+
+    Ok(data)
+}
+
+/// The title and version of `toplevel`'s entry, elided to fit `target`'s `max_label_len`. Whichever
+/// was elided is written to `data` in full first, as a comment (which systemd-boot skips), so that
+/// what it was is still on the ESP.
+fn write_labels(
+    data: &mut String,
+    toplevel: &BootableToplevel,
+    target: &Target,
+) -> Result<(String, String)> {
+    let title = toplevel.elided_title(target.max_label_len);
+    let version = toplevel.elided_version(target.max_label_len)?;
+
+    for (key, elided, full) in [
+        ("title", &title, toplevel.title()),
+        ("version", &version, toplevel.version()?),
+    ] {
+        if *elided != full {
+            writeln!(data, "# {} {}", key, full)?;
+        }
+    }
+
+    Ok((title, version))
+}
+
+/// Renders the entry for `efi`, which boots the unified EFI file in `plan`, into `data`.
+fn efi_entry_impl(
+    data: &mut String,
+    efi: &EfiProgram,
+    plan: &ArtifactPlan,
+    target: &Target,
+) -> Result<()> {
     let profile = &efi.source.profile_name;
     let specialisation = &efi.source.specialisation_name;
-    let unified = format!(
-        "/EFI/nixos/{}.efi",
-        &efi.source
-            .toplevel
-            .0
-            .display()
-            .to_string()
-            .replace(STORE_PATH_PREFIX, "")[..STORE_HASH_LEN]
-    );
+    let unified = match &plan.payload {
+        Payload::Unified(unified) => unified,
+        Payload::Linux { .. } => unreachable!("EFI programs are planned as unified EFI files"),
+    };
 
-    let title = efi.source.title();
-    let version = efi.source.version()?;
-    let data = format!(
+    let (title, version) = self::write_labels(data, &efi.source, target)?;
+    write!(
+        data,
         r#"title {title}
 version {version}
+sort-key {sort_key}
 efi {efi}
-machine-id {machine_id}
-
 "#,
         title = title,
         version = version,
-        efi = unified,
-        machine_id = machine_id,
-    );
+        sort_key = self::sort_key(profile, specialisation, target.group_specialisations),
+        efi = unified.entry_path(),
+    )?;
 
-    let conf_path = self::conf_path(profile, specialisation, generation);
-    let unified_dest = format!("{}/{}", self::ROOT, unified);
-    let entry = (
-        conf_path,
-        Contents {
-            conf: data,
-            unified_dest: Some(unified_dest),
-            ..Default::default()
-        },
-    );
-
-    Ok(entry)
+    Ok(())
 }
 
-fn linux_entry_impl(toplevel: &BootableToplevel, machine_id: &str) -> Result<(String, Contents)> {
-    let generation = toplevel.generation_index;
+/// Renders the entry for `toplevel`, which boots the kernel and initrd `planned` for it with
+/// `target`'s extra kernel params (on as many `options` lines as it takes), into `data`.
+fn linux_entry_impl(
+    data: &mut String,
+    toplevel: &BootableToplevel,
+    planned: &PlannedBootable,
+    target: &Target,
+) -> Result<()> {
     let profile = &toplevel.profile_name;
     let specialisation = &toplevel.specialisation_name;
-    let linux = format!(
-        "/EFI/nixos/{}.efi",
-        toplevel
-            .kernel
-            .display()
-            .to_string()
-            .replace(STORE_PATH_PREFIX, "")
-            .replace("/", "-")
-    );
-    let initrd = format!(
-        "/EFI/nixos/{}.efi",
-        toplevel
-            .initrd
-            .display()
-            .to_string()
-            .replace(STORE_PATH_PREFIX, "")
-            .replace("/", "-")
+    let (linux, initrd) = match &planned.plan.payload {
+        Payload::Linux { kernel, initrd } => (kernel, initrd),
+        Payload::Unified(_) => unreachable!("toplevels are planned as a kernel and an initrd"),
+    };
+
+    let (params, _) = target.kernel_params(
+        std::iter::once(planned.init.as_str())
+            .chain(toplevel.kernel_params.iter().map(String::as_str)),
     );
+    let options = cmdline::folded_options(params, target.max_options_len)?;
 
-    let title = toplevel.title();
-    let version = toplevel.version()?;
-    let data = format!(
+    let (title, version) = self::write_labels(data, toplevel, target)?;
+    write!(
+        data,
         r#"title {title}
 version {version}
+sort-key {sort_key}
 linux {linux}
 initrd {initrd}
-options init={init} {params}
-machine-id {machine_id}
-
 "#,
         title = title,
         version = version,
-        linux = linux,
-        initrd = initrd,
-        init = toplevel.init.display(),
-        params = toplevel.kernel_params.join(" "),
-        machine_id = machine_id,
-    );
+        sort_key = self::sort_key(profile, specialisation, target.group_specialisations),
+        linux = linux.entry_path(),
+        initrd = initrd.entry_path(),
+    )?;
+    for options in options {
+        writeln!(data, "options {}", options)?;
+    }
 
-    let conf_path = self::conf_path(profile, specialisation, generation);
-    let kernel_dest = format!("{}/{}", ROOT, linux);
-    let initrd_dest = format!("{}/{}", ROOT, initrd);
-    let entry = (
-        conf_path,
-        Contents {
-            conf: data,
-            kernel_src: Some(toplevel.kernel.clone()),
-            kernel_dest: Some(kernel_dest),
-            initrd_src: Some(toplevel.initrd.clone()),
-            initrd_dest: Some(initrd_dest),
-            ..Default::default()
-        },
-    );
+    Ok(())
+}
+
+/// Checks that the kernels, initrds, and unified EFI files staged in `root` (where `planned` says)
+/// are what they were made from, and records their hashes in the tree's [`Manifest`] (along with
+/// the `entry_extras` that went in each entry, and the kernel params `target` left out of it).
+///
+/// For unified EFI files, this compares the `.linux` and `.initrd` sections to the kernel and
+/// initrd that were embedded. Recompressed initrds are decompressed before they're compared.
+///
+/// What was left out of the tree because it's unchanged since `target`'s previous run (see
+/// `--incremental`) keeps the previous run's record, and is listed as unchanged. So are the
+/// entries of the `excluded` specialisations (see [`crate::bootable::flatten_filtered`]).
+pub fn write_manifest(
+    root: &Path,
+    planned: &[PlannedBootable],
+    entry_extras: &[EntryExtra],
+    excluded: &[BootableToplevel],
+    target: &Target,
+) -> Result<()> {
+    let previous = target.previous.as_ref();
+    // Generations (and targets) share kernels, initrds, and unified EFI files.
+    let mut files = BTreeMap::new();
+    let mut entries = BTreeMap::new();
+    let mut extras = BTreeMap::new();
+    let mut deduped = BTreeMap::new();
+    let mut unchanged = Vec::new();
+    let excluded_names = plan::deconflicted_specialisations(excluded);
+
+    for PlannedBootable {
+        bootable,
+        plan,
+        init,
+    } in planned
+    {
+        let conf = plan.conf.to_string();
+        let staged = plan::staged(root, &plan.conf);
+        if staged.exists() {
+            entries.insert(conf.clone(), manifest::sha256_file(&staged)?);
+        } else if let Some(sha256) = previous.and_then(|previous| previous.entry(&conf)) {
+            entries.insert(conf.clone(), sha256.to_string());
+            unchanged.push(conf.clone());
+        }
+
+        let lines = entry_extra::lines(entry_extras, bootable.toplevel());
+        if !lines.is_empty() {
+            extras.insert(conf.clone(), lines);
+        }
+
+        let (_, dropped) = target.kernel_params(
+            std::iter::once(init.as_str())
+                .chain(bootable.toplevel().kernel_params.iter().map(String::as_str)),
+        );
+        if !dropped.is_empty() {
+            deduped.insert(conf, dropped.into_iter().map(String::from).collect());
+        }
+
+        match (bootable, &plan.payload) {
+            (Bootable::Efi(efi), Payload::Unified(path)) => {
+                if files.contains_key(path) {
+                    continue;
+                }
+
+                let staged = plan::staged(root, path);
+                let toplevel = &efi.source.toplevel.0;
+                let mut sections = BTreeMap::new();
+
+                // What `EfiProgram::write_unified_efi` embeds
+                for (section, source, compression) in [
+                    (".linux", toplevel.join("kernel"), Compression::None),
+                    (
+                        ".initrd",
+                        toplevel.join("initrd"),
+                        efi.source.initrd_compression,
+                    ),
+                ] {
+                    let source = Source::new(&source)?.compressed(compression);
+                    let embedded = validate::pe_section_reader(&staged, section)
+                        .with_path_context(&staged)?
+                        .ok_or_else(|| {
+                            format!("'{}' has no {} section", staged.display(), section)
+                        })?;
+                    let embedded = compression
+                        .decoder(embedded)
+                        .and_then(|decoder| stream::sha256(decoder).map_err(Into::into))
+                        .map_err(|e| {
+                            format!("the {} section of '{}': {}", section, staged.display(), e)
+                        })?;
+
+                    if embedded != source.sha256 {
+                        return Err(format!(
+                            "the {} section of '{}' doesn't match '{}'",
+                            section,
+                            staged.display(),
+                            source.path.display()
+                        )
+                        .into());
+                    }
+
+                    sections.insert(section.to_string(), source);
+                }
+
+                let sha256 = manifest::sha256_file(&staged)?;
+                files.insert(
+                    path.clone(),
+                    ManifestFile {
+                        path: path.to_string(),
+                        sha256,
+                        source: None,
+                        sections,
+                    },
+                );
+            }
+            (Bootable::Linux(toplevel), Payload::Linux { kernel, initrd }) => {
+                for (source, path, compression) in [
+                    (&toplevel.kernel, kernel, Compression::None),
+                    (&toplevel.initrd, initrd, toplevel.initrd_compression),
+                ] {
+                    if files.contains_key(path) {
+                        continue;
+                    }
+
+                    let staged = plan::staged(root, path);
+                    let recorded =
+                        previous.and_then(|p| p.file(path.as_str(), source, compression));
+                    if let (false, Some(recorded)) = (staged.exists(), recorded) {
+                        files.insert(path.clone(), recorded.clone());
+                        unchanged.push(recorded.path.clone());
+                        continue;
+                    }
 
-    Ok(entry)
+                    let source = Source::new(source)?.compressed(compression);
+                    let sha256 = manifest::sha256_file(&staged)?;
+                    let file = File::open(&staged).with_path_context(&staged)?;
+                    let decompressed = compression
+                        .decoder(file)
+                        .and_then(|decoder| stream::sha256(decoder).map_err(Into::into))
+                        .with_path_context(&staged)?;
+
+                    if decompressed != source.sha256 {
+                        return Err(format!(
+                            "'{}' doesn't match '{}'",
+                            staged.display(),
+                            source.path.display()
+                        )
+                        .into());
+                    }
+
+                    files.insert(
+                        path.clone(),
+                        ManifestFile {
+                            path: path.to_string(),
+                            sha256,
+                            source: Some(source),
+                            sections: BTreeMap::new(),
+                        },
+                    );
+                }
+            }
+            _ => unreachable!("plans match their bootables"),
+        }
+    }
+
+    let manifest = Manifest {
+        format_version: Manifest::FORMAT_VERSION,
+        version: manifest::VERSION.to_string(),
+        files: files.into_values().collect(),
+        entries,
+        entry_extras: extras,
+        deduped_kernel_params: deduped,
+        unchanged,
+        excluded_specialisations: excluded
+            .iter()
+            .map(|toplevel| Ok(plan::conf_path(toplevel, &excluded_names)?.to_string()))
+            .collect::<Result<_>>()?,
+    };
+    manifest.write(root)
 }
 
-fn conf_path(
+/// The `sort-key` that groups all of a profile's generations (and a specialisation's, separately,
+/// unless `group_specialisations`) in systemd-boot's menu.
+fn sort_key(
     profile: &Option<String>,
     specialisation: &Option<SpecialisationName>,
-    generation: usize,
+    group_specialisations: bool,
 ) -> String {
-    let entries_dir = format!("{}/loader/entries", self::ROOT);
-    let infix = if let Some(profile) = profile {
-        format!("-{}", profile)
-    } else {
-        String::new()
-    };
-    let conf_path = if let Some(specialisation) = specialisation {
-        // TODO: the specialisation in filename is required (or it conflicts with other entries), does this mess up sorting?
-        format!(
-            "{}/nixos{}-generation-{}-{}.conf",
-            &entries_dir, infix, generation, specialisation.0
-        )
-    } else {
-        format!(
-            "{}/nixos{}-generation-{}.conf",
-            &entries_dir, infix, generation
+    let mut sort_key = String::from("nixos");
+
+    if let Some(profile) = profile {
+        sort_key.push('-');
+        sort_key.push_str(profile);
+    }
+
+    if let (Some(specialisation), false) = (specialisation, group_specialisations) {
+        sort_key.push('-');
+        sort_key.push_str(&specialisation.0);
+    }
+
+    sort_key
+}
+
+/// The machine-id of the machine the generator runs on, from (in order) `explicit`
+/// (`--machine-id`), `machine_id_file` (i.e. [`MACHINE_ID_FILE`]) if it's readable, and
+/// `systemd-machine-id-setup --print`. Without any of those, entries are written without one
+/// (`None`).
+pub fn resolve_machine_id(
+    explicit: Option<String>,
+    machine_id_file: &Path,
+    systemd_machine_id_setup: Option<&Path>,
+) -> Result<Option<String>> {
+    if explicit.is_some() {
+        return Ok(explicit);
+    }
+
+    match fs::read_to_string(machine_id_file) {
+        Ok(machine_id) if !machine_id.trim().is_empty() => {
+            return Ok(Some(machine_id.trim().to_string()))
+        }
+        Ok(_) => debug!("'{}' is empty", machine_id_file.display()),
+        // e.g. in a build sandbox, where it's there but only root can read it
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied) => {
+            debug!("'{}': {}", machine_id_file.display(), e)
+        }
+        Err(e) => return Err(e).with_path_context(machine_id_file),
+    }
+
+    if let Some(systemd_machine_id_setup) = systemd_machine_id_setup {
+        match self::print_machine_id(systemd_machine_id_setup) {
+            Ok(machine_id) => return Ok(Some(machine_id)),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    warnings::emit(
+        Kind::MissingMachineId,
+        "couldn't find the machine-id (pass --machine-id), so entries are written without one",
+    );
+
+    Ok(None)
+}
+
+fn print_machine_id(systemd_machine_id_setup: &Path) -> Result<String> {
+    let output = command::output(Command::new(systemd_machine_id_setup).arg("--print"))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "execution of `{} --print` failed",
+            systemd_machine_id_setup.display()
         )
-    };
+        .into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Parses `--machine-id`, which has to be 32 hexadecimal characters like `/etc/machine-id`.
+pub fn parse_machine_id(s: &str) -> Result<String, String> {
+    if s.len() != 32 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "invalid machine-id '{}': it has to be 32 hexadecimal characters",
+            s
+        ));
+    }
 
-    conf_path
+    Ok(s.to_ascii_lowercase())
 }
 
-fn get_machine_id(systemd_machine_id_setup: &Path) -> Result<String> {
-    let machine_id = if Path::new("/etc/machine-id").exists() {
-        fs::read_to_string("/etc/machine-id")?
-    } else {
-        let output = Command::new(systemd_machine_id_setup)
-            .arg("--print")
-            .output()?;
-
-        if !output.status.success() {
-            return Err(format!(
-                "execution of `{} --print` failed",
-                systemd_machine_id_setup.display()
+// The stub programs these tests run are shell scripts.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use bootspec::SystemConfigurationRoot;
+
+    use crate::esp_path::EspRelativePath;
+    use crate::incremental::PreviousRun;
+
+    /// An `objcopy` that logs its invocations and writes the `.cmdline` section to its output.
+    fn stub_objcopy(dir: &Path) -> PathBuf {
+        let objcopy = dir.join("objcopy");
+        fs::write(
+            &objcopy,
+            format!(
+                r#"#!/bin/sh
+echo "$@" >> {log}
+for arg; do
+  case "$arg" in
+    .cmdline=/*) cmdline="${{arg#.cmdline=}}" ;;
+  esac
+  out="$arg"
+done
+cp "$cmdline" "$out"
+"#,
+                log = dir.join("objcopy.log").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&objcopy, fs::Permissions::from_mode(0o755)).unwrap();
+
+        objcopy
+    }
+
+    /// A unified EFI file with the given sections (and nothing else that would make it bootable).
+    fn stub_uki(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = vec![0; 0x200];
+        out[..2].copy_from_slice(b"MZ");
+        out[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        out[0x80..0x84].copy_from_slice(b"PE\0\0");
+        out[0x86..0x88].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+
+        for (i, (name, data)) in sections.iter().enumerate() {
+            let header = 0x98 + i * 40;
+            let offset = out.len() as u32;
+            let raw_size = (data.len() as u32 + 0x1ff) & !0x1ff;
+            out[header..header + name.len()].copy_from_slice(name.as_bytes());
+            out[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            out[header + 16..header + 20].copy_from_slice(&raw_size.to_le_bytes());
+            out[header + 20..header + 24].copy_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(data);
+            out.resize((offset + raw_size) as usize, 0);
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_sort_key() {
+        let specialisation = Some(SpecialisationName(String::from("gui")));
+
+        assert_eq!(sort_key(&None, &None, false), "nixos");
+        assert_eq!(
+            sort_key(&Some(String::from("work")), &None, false),
+            "nixos-work"
+        );
+        assert_eq!(sort_key(&None, &specialisation, false), "nixos-gui");
+        assert_eq!(
+            sort_key(&Some(String::from("work")), &specialisation, false),
+            "nixos-work-gui"
+        );
+
+        // grouped with their parents
+        assert_eq!(sort_key(&None, &specialisation, true), "nixos");
+        assert_eq!(
+            sort_key(&Some(String::from("work")), &specialisation, true),
+            "nixos-work"
+        );
+    }
+
+    #[test]
+    fn test_linux_entry_sort_key() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = |generation_index, specialisation: Option<&str>| BootableToplevel {
+            label: String::from("22.05"),
+            kernel: PathBuf::from("/nix/store/kernel/bzImage"),
+            init: PathBuf::from("/init"),
+            initrd: PathBuf::from("/nix/store/initrd/initrd"),
+            toplevel: SystemConfigurationRoot(tempdir.path().to_path_buf()),
+            specialisation_name: specialisation.map(|s| SpecialisationName(s.to_string())),
+            generation_index,
+            profile_name: Some(String::from("work")),
+            ..Default::default()
+        };
+        let target = Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+        let grouped = target.clone().with_grouped_specialisations(true);
+        let line = |target: &Target, toplevel, key: &str| {
+            let bootables = [Bootable::Linux(toplevel)];
+            let planned = plan(&bootables, &target.efi_dir).unwrap();
+            entry(&planned[0], target, &[])
+                .unwrap()
+                .lines()
+                .find(|line| line.starts_with(&format!("{} ", key)))
+                .map(ToString::to_string)
+                .unwrap()
+        };
+        let sort_key = |toplevel| line(&target, toplevel, "sort-key");
+
+        // all of a profile's generations share a sort-key, specialisations get their own
+        assert_eq!(sort_key(toplevel(1, None)), "sort-key nixos-work");
+        assert_eq!(sort_key(toplevel(2, None)), "sort-key nixos-work");
+        assert_eq!(
+            sort_key(toplevel(2, Some("gui"))),
+            "sort-key nixos-work-gui"
+        );
+
+        // ... unless they're grouped with their parents
+        assert_eq!(
+            line(&grouped, toplevel(2, Some("gui")), "sort-key"),
+            "sort-key nixos-work"
+        );
+        assert_eq!(
+            line(&grouped, toplevel(2, None), "sort-key"),
+            "sort-key nixos-work"
+        );
+
+        // either way, specialisations look different from their parent
+        assert_eq!(line(&target, toplevel(2, None), "title"), "title NixOS");
+        assert_eq!(
+            line(&target, toplevel(2, Some("gui")), "title"),
+            "title NixOS – spec: gui"
+        );
+        assert!(line(&target, toplevel(2, None), "version")
+            .starts_with("version Generation 2 22.05, Built on "));
+        assert!(line(&grouped, toplevel(2, Some("gui")), "version")
+            .starts_with("version Generation 2~gui 22.05, Built on "));
+    }
+
+    #[test]
+    fn test_entry_extras() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        let source = |profile: Option<&str>| BootableToplevel {
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            generation_index: 1,
+            profile_name: profile.map(ToString::to_string),
+            ..Default::default()
+        };
+        let bootables = [
+            Bootable::Linux(source(None)),
+            Bootable::Efi(EfiProgram::new(source(None))),
+            Bootable::Linux(source(Some("work"))),
+        ];
+        let target = Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+        let entry_extras = [
+            "devicetree-overlay=/dtbs/uart.dtbo /dtbs/i2c.dtbo",
+            "x-vendor-key=1@profile:work",
+        ]
+        .iter()
+        .map(|arg| entry_extra::parse_entry_extra(arg).unwrap())
+        .collect::<Vec<_>>();
+
+        let entries = plan(&bootables, &target.efi_dir)
+            .unwrap()
+            .iter()
+            .map(|planned| entry(planned, &target, &entry_extras).unwrap())
+            .collect::<Vec<_>>();
+
+        // after the directives the generator writes itself
+        for entry in &entries[..2] {
+            let (managed, extras) = entry.split_once("machine-id ").unwrap();
+            assert!(!managed.contains("devicetree-overlay"), "{}", entry);
+            assert_eq!(
+                extras,
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n\
+                 devicetree-overlay /dtbs/uart.dtbo /dtbs/i2c.dtbo\n"
+            );
+        }
+        assert!(entries[2]
+            .ends_with("devicetree-overlay /dtbs/uart.dtbo /dtbs/i2c.dtbo\nx-vendor-key 1\n"));
+
+        // and without any, the entries are as they were
+        let planned = plan(&bootables, &target.efi_dir).unwrap();
+        assert!(entry(&planned[0], &target, &[])
+            .unwrap()
+            .ends_with("machine-id aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n"));
+    }
+
+    #[test]
+    fn test_entry_kernel_params() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        let target = Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+
+        for (params, options, uki) in [
+            (
+                &["quiet"][..],
+                Ok("options init=/init quiet"),
+                Ok("init=/init quiet"),
+            ),
+            (
+                &["root=LABEL=my root", "quiet"],
+                Ok(r#"options init=/init root="LABEL=my root" quiet"#),
+                Ok(r#"init=/init root="LABEL=my root" quiet"#),
+            ),
+            (
+                &["quiet\n#"],
+                Err("control character"),
+                Err("control character"),
+            ),
+            (
+                &[r#"dyndbg="file x.c +p""#],
+                Err("can't be escaped"),
+                Err("can't be escaped"),
+            ),
+        ] {
+            let source = || BootableToplevel {
+                kernel_params: params.iter().map(ToString::to_string).collect(),
+                init: PathBuf::from("/init"),
+                toplevel: SystemConfigurationRoot(toplevel.clone()),
+                ..Default::default()
+            };
+            let bootables = [
+                Bootable::Linux(source()),
+                Bootable::Efi(EfiProgram::new(source())),
+            ];
+            let planned = plan(&bootables, &target.efi_dir).unwrap();
+
+            let entry = entry(&planned[0], &target, &[]).map(|conf| {
+                conf.lines()
+                    .find(|line| line.starts_with("options "))
+                    .map(ToString::to_string)
+                    .unwrap()
+            });
+            let cmdline = EfiProgram::new(source()).cmdline(&target);
+
+            for (actual, expected) in [(entry, options), (cmdline, uki)] {
+                match (actual, expected) {
+                    (Ok(actual), Ok(expected)) => assert_eq!(actual, expected),
+                    (Err(e), Err(expected)) => assert!(e.to_string().contains(expected), "{}", e),
+                    (actual, expected) => panic!(
+                        "{:?}: expected {:?}, got {:?}",
+                        params,
+                        expected,
+                        actual.map_err(|e| e.to_string())
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_entry_folded_options() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        let target = Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+            .with_max_options_len(32);
+        let source = BootableToplevel {
+            kernel_params: (0..20).map(|i| format!("param{}=value", i)).collect(),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel),
+            ..Default::default()
+        };
+        let cmdline = EfiProgram::new(source.clone()).cmdline(&target).unwrap();
+        let bootables = [Bootable::Linux(source)];
+        let planned = plan(&bootables, &target.efi_dir).unwrap();
+
+        let entry = entry(&planned[0], &target, &[]).unwrap();
+        let options = entry
+            .lines()
+            .filter_map(|line| line.strip_prefix("options "))
+            .collect::<Vec<_>>();
+        assert!(options.len() > 1, "{}", entry);
+        assert!(
+            options.iter().all(|options| options.len() <= 32),
+            "{}",
+            entry
+        );
+        // systemd-boot joins them back into the command line the unified EFI file gets whole
+        assert_eq!(options.join(" "), cmdline);
+        // and they're the last of the lines the generator writes
+        assert!(entry.ends_with(&format!(
+            "options {}\nmachine-id aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n",
+            options.last().unwrap()
+        )));
+    }
+
+    #[test]
+    fn test_entry_elided_labels() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        let target = Target::local(String::new()).with_max_label_len(60);
+        let source = |label: &str| BootableToplevel {
+            label: label.to_string(),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            generation_index: 7,
+            ..Default::default()
+        };
+        let lines = |label: &str| {
+            let bootables = [Bootable::Linux(source(label))];
+            let planned = plan(&bootables, &target.efi_dir).unwrap();
+            let entry = entry(&planned[0], &target, &[]).unwrap();
+            entry.lines().map(ToString::to_string).collect::<Vec<_>>()
+        };
+
+        // short labels are left as they are, without a comment
+        let short = lines("23.05");
+        assert!(short[0].starts_with("title NixOS"), "{:?}", short);
+        assert!(short[1].starts_with("version Generation 7 23.05, Built on "));
+
+        let label = "23.05.20230601.0123456789abcdef0123456789abcdef0123456789abcdef (Stoat) rev-1";
+        let long = lines(label);
+        // the full version is kept in a comment, which systemd-boot skips
+        assert_eq!(
+            long[0],
+            format!("# version {}", source(label).version().unwrap())
+        );
+        assert_eq!(long[1], "title NixOS");
+        let version = long[2].strip_prefix("version ").unwrap();
+        assert_eq!(version.chars().count(), 60, "{}", version);
+        assert!(version.starts_with("Generation 7 23.05."), "{}", version);
+        // what tells it apart from the next generation survives
+        assert!(version.contains("…(Stoat) rev-1, Built on "), "{}", version);
+        assert_ne!(lines(&label.replace("rev-1", "rev-2"))[2], long[2]);
+    }
+
+    #[test]
+    fn test_golden_entries() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        let bootable = |profile: Option<&str>, specialisation: Option<&str>| BootableToplevel {
+            label: String::from("23.05"),
+            kernel: PathBuf::from("/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1/bzImage"),
+            kernel_params: vec![String::from("loglevel=4")],
+            init: PathBuf::from("/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system/init"),
+            initrd: PathBuf::from(
+                "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1/initrd",
+            ),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            specialisation_name: specialisation.map(|s| SpecialisationName(s.to_string())),
+            generation_index: 42,
+            profile_name: profile.map(ToString::to_string),
+            variant_name: None,
+            initrd_compression: Compression::None,
+        };
+        let target = Target {
+            extra_kernel_params: vec![String::from("console=ttyS0")],
+            ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+        };
+        // the build date is the toplevel's ctime, i.e. today
+        let built_on = regex::Regex::new("Built on [0-9]{4}-[0-9]{2}-[0-9]{2}").unwrap();
+        let mut bootables = [(None, None), (Some("work"), None), (None, Some("gui"))]
+            .iter()
+            .map(|&(profile, specialisation)| Bootable::Linux(bootable(profile, specialisation)))
+            .collect::<Vec<_>>();
+        bootables.push(Bootable::Efi(EfiProgram::new(bootable(None, None))));
+        bootables.push(Bootable::Linux(BootableToplevel {
+            kernel_params: vec![
+                String::from("loglevel=4"),
+                String::from("systemd.unit=rescue.target"),
+            ],
+            variant_name: Some(String::from("recovery")),
+            ..bootable(None, None)
+        }));
+
+        for planned in plan(&bootables, &target.efi_dir).unwrap() {
+            let kind = match planned.bootable {
+                Bootable::Linux(_) => "linux",
+                Bootable::Efi(_) => "efi",
+            };
+            let filename = planned.plan.conf.file_name();
+            let contents = entry(&planned, &target, &[]).unwrap();
+
+            // byte-stable: a single trailing newline, and no trailing whitespace
+            assert!(contents.ends_with('\n') && !contents.ends_with("\n\n"));
+            assert!(contents.lines().all(|line| line == line.trim_end()));
+
+            golden::assert_golden!(
+                format!("entries/{}/{}", kind, filename),
+                built_on.replace(&contents, "Built on <date>")
+            );
+        }
+    }
+
+    #[test]
+    fn test_generate_targets() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let objcopy = self::stub_objcopy(dir);
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+
+        let bootables = vec![Bootable::Efi(EfiProgram::new(BootableToplevel {
+            kernel_params: vec![String::from("quiet")],
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel),
+            generation_index: 1,
+            ..Default::default()
+        }))];
+        let target = |name: &str, machine_id: &str| Target {
+            name: name.to_string(),
+            ..Target::local(machine_id.to_string())
+        };
+        let targets = [
+            target("a", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+            target("b", "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"),
+            Target {
+                extra_kernel_params: vec![String::from("console=ttyS0")],
+                ..target("c", "cccccccccccccccccccccccccccccccc")
+            },
+        ];
+
+        let out_dir = dir.join("out");
+        generate_targets(
+            &bootables,
+            Some(UkiBuilder::Objcopy(objcopy)),
+            Some(PathBuf::from("/stub.efi")),
+            &out_dir,
+            &targets,
+            &[],
+        )
+        .unwrap();
+
+        // a and b share a unified EFI file, c has its own command line
+        let log = fs::read_to_string(dir.join("objcopy.log")).unwrap();
+        assert_eq!(log.lines().count(), 2);
+
+        let unified = |name: &str| {
+            fs::read_to_string(
+                out_dir
+                    .join(name)
+                    .join("EFI/nixos/0123456789abcdefghijklmnopqrstuv.efi"),
+            )
+            .unwrap()
+        };
+        assert_eq!(unified("a"), "init=/init quiet");
+        assert_eq!(unified("b"), "init=/init quiet");
+        assert_eq!(unified("c"), "init=/init quiet console=ttyS0");
+
+        let inode = |name: &str| {
+            fs::metadata(
+                out_dir
+                    .join(name)
+                    .join("EFI/nixos/0123456789abcdefghijklmnopqrstuv.efi"),
+            )
+            .unwrap()
+            .ino()
+        };
+        assert_eq!(inode("a"), inode("b"));
+        assert_ne!(inode("a"), inode("c"));
+
+        let conf = |name: &str| {
+            fs::read_to_string(
+                out_dir
+                    .join(name)
+                    .join("loader/entries/nixos-generation-1.conf"),
             )
-            .into());
+            .unwrap()
+        };
+        assert_eq!(
+            conf("a").replace(&targets[0].machine_id, &targets[1].machine_id),
+            conf("b")
+        );
+        assert!(conf("a").contains("machine-id aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n"));
+        assert!(conf("b").contains("machine-id bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n"));
+    }
+
+    #[test]
+    fn test_write_unified_efi_in_parallel() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let objcopy = self::stub_objcopy(dir);
+        let efi_dir = dir.join("EFI/nixos");
+        fs::create_dir_all(&efi_dir).unwrap();
+
+        // both UKIs are written to the same directory at the same time
+        let handles = (0..2)
+            .map(|i| {
+                let objcopy = UkiBuilder::Objcopy(objcopy.clone());
+                let outpath = efi_dir.join(format!("{}.efi", i));
+                let efi = EfiProgram::new(BootableToplevel {
+                    kernel_params: vec![format!("generation={}", i)],
+                    init: PathBuf::from("/init"),
+                    toplevel: SystemConfigurationRoot(dir.join(i.to_string())),
+                    ..Default::default()
+                });
+                let target = Target::local(String::new());
+
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        efi.write_unified_efi(&objcopy, &outpath, Path::new("/stub.efi"), &target)
+                            .unwrap();
+                        // each gets its own `.cmdline`, never the other's
+                        assert_eq!(
+                            fs::read_to_string(&outpath).unwrap(),
+                            format!("init=/init generation={}", i)
+                        );
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
         }
+    }
 
-        String::from_utf8(output.stdout)?
-    };
+    #[test]
+    fn test_machine_id_placeholder() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let bootables = vec![Bootable::Linux(BootableToplevel {
+            kernel: PathBuf::from("/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1/bzImage"),
+            initrd: PathBuf::from(
+                "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1/initrd",
+            ),
+            init: PathBuf::from("/init"),
+            generation_index: 1,
+            ..Default::default()
+        })];
+        let target = Target {
+            name: String::from("shared"),
+            ..Target::local(String::from(MACHINE_ID_PLACEHOLDER))
+        };
+
+        generate_targets(&bootables, None, None, tempdir.path(), &[target], &[]).unwrap();
+
+        let conf = fs::read_to_string(
+            tempdir
+                .path()
+                .join("shared/loader/entries/nixos-generation-1.conf"),
+        )
+        .unwrap();
+        assert!(conf.ends_with("machine-id @MACHINE_ID@\n"));
+        assert_eq!(conf.matches(MACHINE_ID_PLACEHOLDER).count(), 1);
+
+        // without a machine-id, entries leave it out
+        let planned = plan(&bootables, "EFI/nixos").unwrap();
+        let conf = entry(&planned[0], &Target::local(String::new()), &[]).unwrap();
+        assert!(!conf.contains("machine-id"), "{}", conf);
+    }
 
-    Ok(machine_id.trim().to_string())
+    #[test]
+    fn test_resolve_machine_id() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let machine_id_file = tempdir.path().join("machine-id");
+        fs::write(&machine_id_file, "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n").unwrap();
+        let setup = |name: &str, script: &str| {
+            let path = tempdir.path().join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let working = setup("working", "echo bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+        let failing = setup("failing", "exit 1");
+        let resolve = |explicit: Option<&str>, file: &Path, setup: Option<&Path>| {
+            resolve_machine_id(explicit.map(ToString::to_string), file, setup).unwrap()
+        };
+        let (a, b, c) = (
+            Some(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")),
+            Some(String::from("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb")),
+            Some(String::from("cccccccccccccccccccccccccccccccc")),
+        );
+
+        assert_eq!(
+            resolve(c.as_deref(), &machine_id_file, Some(working.as_path())),
+            c
+        );
+        assert_eq!(resolve(None, &machine_id_file, Some(working.as_path())), a);
+
+        // there, but not readable
+        fs::set_permissions(&machine_id_file, fs::Permissions::from_mode(0o000)).unwrap();
+        // (root can read it regardless)
+        if fs::read(&machine_id_file).is_err() {
+            assert_eq!(resolve(None, &machine_id_file, Some(working.as_path())), b);
+            assert_eq!(resolve(None, &machine_id_file, None), None);
+        }
+
+        let missing = tempdir.path().join("missing");
+        assert_eq!(resolve(None, &missing, Some(working.as_path())), b);
+        assert_eq!(resolve(None, &missing, Some(failing.as_path())), None);
+        assert_eq!(resolve(None, &missing, None), None);
+
+        // anything else is still an error
+        assert!(resolve_machine_id(None, tempdir.path(), None).is_err());
+
+        assert_eq!(
+            parse_machine_id("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap(),
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+        );
+        assert!(parse_machine_id("aaaa").is_err());
+        assert!(parse_machine_id("gggggggggggggggggggggggggggggggg").is_err());
+    }
+
+    #[test]
+    fn test_entry_path_prefix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let objcopy = self::stub_objcopy(dir);
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+
+        let linux = BootableToplevel {
+            kernel: PathBuf::from("/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1/bzImage"),
+            initrd: PathBuf::from(
+                "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1/initrd",
+            ),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel),
+            generation_index: 1,
+            ..Default::default()
+        };
+        let bootables = vec![
+            Bootable::Linux(linux.clone()),
+            Bootable::Efi(EfiProgram::new(BootableToplevel {
+                generation_index: 2,
+                ..linux
+            })),
+        ];
+        let target = Target {
+            name: String::from("xbootldr"),
+            ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+        }
+        .with_entry_path_prefix("boot");
+
+        generate_targets(
+            &bootables,
+            Some(UkiBuilder::Objcopy(objcopy)),
+            Some(PathBuf::from("/stub.efi")),
+            dir,
+            &[target],
+            &[],
+        )
+        .unwrap();
+
+        let root = dir.join("xbootldr");
+        let conf = |generation: usize| {
+            fs::read_to_string(root.join(format!(
+                "loader/entries/nixos-generation-{}.conf",
+                generation
+            )))
+            .unwrap()
+        };
+        let kernel = "/boot/EFI/nixos/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1-bzImage.efi";
+        let initrd = "/boot/EFI/nixos/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1-initrd.efi";
+        let unified = "/boot/EFI/nixos/0123456789abcdefghijklmnopqrstuv.efi";
+        assert!(conf(1).contains(&format!("\nlinux {}\n", kernel)));
+        assert!(conf(1).contains(&format!("\ninitrd {}\n", initrd)));
+        assert!(conf(2).contains(&format!("\nefi {}\n", unified)));
+
+        // the files are staged where the entries refer to them
+        for path in [kernel, initrd, unified] {
+            let staged = plan::staged(&root, &EspRelativePath::new(path).unwrap());
+            assert!(staged.starts_with(root.join("boot/EFI/nixos")));
+            assert!(fs::symlink_metadata(&staged).is_ok(), "{}", path);
+        }
+        assert!(!root.join("EFI").exists());
+    }
+
+    #[test]
+    fn test_write_manifest() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        fs::write(toplevel.join("kernel"), "kernel").unwrap();
+        fs::write(toplevel.join("initrd"), "initrd").unwrap();
+
+        let source = |generation_index| BootableToplevel {
+            kernel: toplevel.join("kernel"),
+            initrd: toplevel.join("initrd"),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            generation_index,
+            kernel_params: vec![String::from("quiet"), String::from("loglevel=4")],
+            ..Default::default()
+        };
+        let bootables = vec![
+            Bootable::Linux(source(1)),
+            Bootable::Efi(EfiProgram::new(source(2))),
+        ];
+        let target = Target {
+            name: String::from("a"),
+            extra_kernel_params: vec![String::from("loglevel=7"), String::from("quiet")],
+            ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+        }
+        .with_deduped_kernel_params(Some(vec![String::from("console")]));
+
+        let out_dir = dir.join("out");
+        let root = out_dir.join("a");
+        let unified = root.join("EFI/nixos/0123456789abcdefghijklmnopqrstuv.efi");
+        generate_targets(
+            &bootables[..1],
+            None,
+            None,
+            &out_dir,
+            &[target.clone()],
+            &[],
+        )
+        .unwrap();
+        fs::write(
+            &unified,
+            stub_uki(&[(".linux", b"kernel"), (".initrd", b"initrd")]),
+        )
+        .unwrap();
+
+        let planned = plan(&bootables, "EFI/nixos").unwrap();
+        let entry_extras =
+            [
+                entry_extra::parse_entry_extra("devicetree-overlay=/uart.dtbo@generation:2")
+                    .unwrap(),
+            ];
+        let excluded = [BootableToplevel {
+            specialisation_name: Some(SpecialisationName(String::from("test"))),
+            ..source(2)
+        }];
+        write_manifest(&root, &planned, &entry_extras, &excluded, &target).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(root.join(manifest::MANIFEST)).unwrap())
+                .unwrap();
+        assert_eq!(json["formatVersion"], Manifest::FORMAT_VERSION);
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        let files = json["files"].as_array().unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            json["entry_extras"],
+            serde_json::json!({
+                "loader/entries/nixos-generation-2.conf": ["devicetree-overlay /uart.dtbo"]
+            })
+        );
+        // the later `loglevel` and `quiet` win, where they are
+        let entry =
+            fs::read_to_string(root.join("loader/entries/nixos-generation-1.conf")).unwrap();
+        assert!(
+            entry.contains("\noptions init=/init loglevel=7 quiet\n"),
+            "{}",
+            entry
+        );
+        assert_eq!(
+            json["deduped_kernel_params"],
+            serde_json::json!({
+                "loader/entries/nixos-generation-1.conf": ["quiet", "loglevel=4"],
+                "loader/entries/nixos-generation-2.conf": ["quiet", "loglevel=4"]
+            })
+        );
+
+        assert_eq!(
+            json["excluded_specialisations"],
+            serde_json::json!(["loader/entries/nixos-generation-2-test.conf"])
+        );
+
+        let uki = files
+            .iter()
+            .find(|f| f["path"] == "EFI/nixos/0123456789abcdefghijklmnopqrstuv.efi")
+            .unwrap();
+        assert_eq!(uki["sha256"], manifest::sha256_file(&unified).unwrap());
+        assert_eq!(
+            uki["sections"][".initrd"]["sha256"],
+            manifest::sha256(b"initrd")
+        );
+        assert_eq!(
+            uki["sections"][".linux"]["sha256"],
+            manifest::sha256(b"kernel")
+        );
+        for file in files.iter().filter(|f| f["source"].is_object()) {
+            assert_eq!(
+                file["sha256"],
+                manifest::sha256_file(&root.join(file["path"].as_str().unwrap())).unwrap()
+            );
+        }
+
+        // e.g. a bug in processing the initrd on its way into the unified EFI file
+        fs::write(
+            &unified,
+            stub_uki(&[(".linux", b"kernel"), (".initrd", b"initrd with secrets")]),
+        )
+        .unwrap();
+        let err = write_manifest(&root, &planned, &[], &[], &target)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(".initrd section"));
+        assert!(err.contains(&unified.display().to_string()));
+
+        fs::write(&unified, stub_uki(&[(".linux", b"kernel")])).unwrap();
+        assert!(write_manifest(&root, &planned, &[], &[], &target).is_err());
+    }
+
+    /// Copies the staging tree at `from` to `to` (following the symlinks to the kernels and
+    /// initrds), like the installer does to the ESP.
+    fn install_tree(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let path = entry.unwrap().path();
+            let dest = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                install_tree(&path, &dest);
+            } else {
+                fs::copy(&path, &dest).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        let generation = |index: usize| {
+            let kernel = dir.join(format!("kernel-{}", index));
+            let initrd = dir.join(format!("initrd-{}", index));
+            fs::write(&kernel, format!("kernel {}", index)).unwrap();
+            fs::write(&initrd, format!("initrd {}", index)).unwrap();
+
+            Bootable::Linux(BootableToplevel {
+                kernel,
+                initrd,
+                init: PathBuf::from("/init"),
+                toplevel: SystemConfigurationRoot(toplevel.clone()),
+                generation_index: index,
+                ..Default::default()
+            })
+        };
+        let out_dir = dir.join("out");
+        let esp = dir.join("esp");
+        let run = |bootables: &[Bootable], name: &str, previous: Option<PreviousRun>| {
+            let target = Target {
+                name: name.to_string(),
+                ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+            }
+            .with_previous_run(previous);
+            let root = out_dir.join(name);
+
+            generate_targets(bootables, None, None, &out_dir, &[target.clone()], &[]).unwrap();
+            let planned = plan(bootables, &target.efi_dir).unwrap();
+            write_manifest(&root, &planned, &[], &[], &target).unwrap();
+
+            root
+        };
+        let staged = |root: &Path| {
+            let mut staged = fs::read_dir(root.join("EFI/nixos"))
+                .unwrap()
+                .chain(fs::read_dir(root.join("loader/entries")).unwrap())
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            staged.sort();
+            staged
+        };
+
+        let mut bootables = vec![generation(1)];
+        let first = run(&bootables, "first", None);
+        install_tree(&first, &esp);
+
+        bootables.push(generation(2));
+        let previous = PreviousRun::load(&esp.join(manifest::MANIFEST)).unwrap();
+        let second = run(&bootables, "second", Some(previous));
+
+        // only the new generation's artifacts are staged
+        let tmp = dir
+            .display()
+            .to_string()
+            .trim_start_matches('/')
+            .replace('/', "-");
+        assert_eq!(
+            staged(&second),
+            [
+                format!("-{}-initrd-2.efi", tmp),
+                format!("-{}-kernel-2.efi", tmp),
+                String::from("nixos-generation-2.conf"),
+            ]
+        );
+
+        // and the manifest still has everything, with the rest listed as unchanged
+        let manifest = Manifest::read(&second.join(manifest::MANIFEST)).unwrap();
+        assert_eq!(manifest.files.len(), 4);
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(
+            manifest.unchanged,
+            [
+                String::from("loader/entries/nixos-generation-1.conf"),
+                format!("EFI/nixos/-{}-kernel-1.efi", tmp),
+                format!("EFI/nixos/-{}-initrd-1.efi", tmp),
+            ]
+        );
+        let first_manifest = Manifest::read(&first.join(manifest::MANIFEST)).unwrap();
+        assert!(first_manifest
+            .files
+            .iter()
+            .all(|file| manifest.files.contains(file)));
+
+        // what changed on the ESP since is staged again
+        fs::write(
+            esp.join("loader/entries/nixos-generation-1.conf"),
+            "title Something else\n",
+        )
+        .unwrap();
+        let previous = PreviousRun::load(&esp.join(manifest::MANIFEST)).unwrap();
+        let third = run(&bootables, "third", Some(previous));
+        assert!(third
+            .join("loader/entries/nixos-generation-1.conf")
+            .exists());
+    }
+
+    #[test]
+    fn test_write_manifest_recompressed() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let toplevel = dir.join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        fs::create_dir(&toplevel).unwrap();
+        fs::write(toplevel.join("kernel"), "kernel").unwrap();
+        fs::write(toplevel.join("initrd"), "initrd").unwrap();
+
+        let source = |generation_index| BootableToplevel {
+            kernel: toplevel.join("kernel"),
+            initrd: toplevel.join("initrd"),
+            init: PathBuf::from("/init"),
+            toplevel: SystemConfigurationRoot(toplevel.clone()),
+            generation_index,
+            initrd_compression: Compression::Zstd,
+            ..Default::default()
+        };
+        let bootables = vec![
+            Bootable::Linux(source(1)),
+            Bootable::Efi(EfiProgram::new(source(2))),
+        ];
+        let target = Target {
+            name: String::from("a"),
+            ..Target::local(String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"))
+        };
+
+        let out_dir = dir.join("out");
+        let root = out_dir.join("a");
+        generate_targets(
+            &bootables[..1],
+            None,
+            None,
+            &out_dir,
+            &[target.clone()],
+            &[],
+        )
+        .unwrap();
+        let initrd = root.join("EFI/nixos").join(format!(
+            "{}-initrd-zstd.efi",
+            toplevel.display().to_string().replace('/', "-")
+        ));
+        let staged = fs::read(&initrd).unwrap();
+        assert!(!fs::symlink_metadata(&initrd)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(Compression::Zstd.decompress(&staged).unwrap(), b"initrd");
+
+        let unified = root.join("EFI/nixos/0123456789abcdefghijklmnopqrstuv-zstd.efi");
+        fs::write(
+            &unified,
+            stub_uki(&[(".linux", b"kernel"), (".initrd", &staged)]),
+        )
+        .unwrap();
+
+        let planned = plan(&bootables, "EFI/nixos").unwrap();
+        write_manifest(&root, &planned, &[], &[], &target).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(root.join(manifest::MANIFEST)).unwrap())
+                .unwrap();
+        let files = json["files"].as_array().unwrap();
+        let initrd = files
+            .iter()
+            .find(|f| f["path"].as_str().unwrap().ends_with("-initrd-zstd.efi"))
+            .unwrap();
+        assert_eq!(initrd["sha256"], manifest::sha256(&staged));
+        assert_eq!(initrd["source"]["sha256"], manifest::sha256(b"initrd"));
+        assert_eq!(initrd["source"]["compression"], "zstd");
+        let kernel = files
+            .iter()
+            .find(|f| f["path"].as_str().unwrap().ends_with("-kernel.efi"))
+            .unwrap();
+        assert!(kernel["source"].get("compression").is_none());
+
+        let uki = files
+            .iter()
+            .find(|f| f["path"] == "EFI/nixos/0123456789abcdefghijklmnopqrstuv-zstd.efi")
+            .unwrap();
+        assert_eq!(
+            uki["sections"][".initrd"]["sha256"],
+            manifest::sha256(b"initrd")
+        );
+        assert_eq!(uki["sections"][".initrd"]["compression"], "zstd");
+
+        // an initrd that was embedded as-is
+        fs::write(
+            &unified,
+            stub_uki(&[(".linux", b"kernel"), (".initrd", b"initrd")]),
+        )
+        .unwrap();
+        let err = write_manifest(&root, &planned, &[], &[], &target)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(".initrd section"));
+    }
 }
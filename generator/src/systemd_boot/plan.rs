@@ -0,0 +1,534 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::bootable::{Bootable, BootableToplevel, EfiProgram};
+use crate::esp_path::{self, EspRelativePath};
+use crate::manifest;
+use crate::util;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+const STORE_PATH_PREFIX: &str = "/nix/store/";
+const STORE_HASH_LEN: usize = 32;
+/// How much of the SHA-256 of a specialisation's name is appended to it when it's deconflicted.
+const DECONFLICT_HASH_LEN: usize = 8;
+
+/// The names that specialisations' entries are given, when they aren't the specialisations' own
+/// names (see [`deconflicted_specialisations`]), by profile, generation, and specialisation name.
+pub(super) type Deconflicted = BTreeMap<(Option<String>, usize, String), String>;
+
+/// Where everything generated for one [`Bootable`] goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactPlan {
+    /// The entry's conf file, relative to the root of the staging tree
+    pub conf: EspRelativePath,
+    /// What the entry boots, and where (inside the ESP) it goes
+    pub payload: Payload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// The kernel and initrd, which are symlinked to their store paths (or, for an initrd that is
+    /// recompressed, written out compressed).
+    Linux {
+        kernel: EspRelativePath,
+        initrd: EspRelativePath,
+    },
+    /// The unified EFI file, which is built from the toplevel.
+    Unified(EspRelativePath),
+}
+
+/// A [`Bootable`] and its [`ArtifactPlan`], along with the strings derived from its store paths
+/// that every entry rendered for it needs.
+pub struct PlannedBootable<'a> {
+    pub bootable: &'a Bootable,
+    pub plan: ArtifactPlan,
+    /// The `init=` kernel param, for the entries that boot a kernel and initrd
+    pub init: String,
+}
+
+/// Plans where everything generated for `bootables` goes, with their kernels, initrds, and unified
+/// EFI files in `efi_dir`.
+///
+/// This doesn't touch the filesystem, so the plans can be checked (or shown) before anything is
+/// written.
+pub fn plan<'a>(bootables: &'a [Bootable], efi_dir: &str) -> Result<Vec<PlannedBootable<'a>>> {
+    let deconflicted = self::deconflicted_specialisations(bootables.iter().map(Bootable::toplevel));
+
+    bootables
+        .iter()
+        .map(|bootable| {
+            let (toplevel, payload) = match bootable {
+                Bootable::Efi(efi) => (
+                    &efi.source,
+                    Payload::Unified(self::unified_path(efi, efi_dir)?),
+                ),
+                Bootable::Linux(toplevel) => (
+                    toplevel,
+                    Payload::Linux {
+                        kernel: self::store_file_path(&toplevel.kernel, "", efi_dir)?,
+                        initrd: self::store_file_path(
+                            &toplevel.initrd,
+                            toplevel.initrd_compression.suffix(),
+                            efi_dir,
+                        )?,
+                    },
+                ),
+            };
+
+            Ok(PlannedBootable {
+                bootable,
+                plan: ArtifactPlan {
+                    conf: self::conf_path(toplevel, &deconflicted)?,
+                    payload,
+                },
+                init: format!("init={}", util::utf8(&toplevel.init)?),
+            })
+        })
+        .collect()
+}
+
+/// Where the file at `esp_path` is staged in the tree at `root`.
+pub fn staged(root: &Path, esp_path: &EspRelativePath) -> PathBuf {
+    esp_path::join(root, esp_path.as_str())
+}
+
+/// Where (inside the ESP) the unified EFI file for `efi` goes: it is named after the toplevel's
+/// store hash (and variant, which embeds its own command line, and the initrd's compression).
+fn unified_path(efi: &EfiProgram, efi_dir: &str) -> Result<EspRelativePath> {
+    let toplevel = &efi.source.toplevel.0;
+    let hash = Path::new(util::utf8(toplevel)?)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.get(..STORE_HASH_LEN))
+        .ok_or_else(|| format!("'{}' is not a store path", toplevel.display()))?;
+
+    let compression = efi.source.initrd_compression.suffix();
+
+    let esp_path = match &efi.source.variant_name {
+        Some(variant) => format!("{}/{}-{}{}.efi", efi_dir, hash, variant, compression),
+        None => format!("{}/{}{}.efi", efi_dir, hash, compression),
+    };
+
+    EspRelativePath::new(&esp_path)
+}
+
+/// Where (inside the ESP) the kernel or initrd at `path` goes: it is named after its store path
+/// (and `suffix`, e.g. how it was compressed on its way there).
+fn store_file_path(path: &Path, suffix: &str, efi_dir: &str) -> Result<EspRelativePath> {
+    let path = util::utf8(path)?;
+    let name = path.strip_prefix(STORE_PATH_PREFIX).unwrap_or(path);
+
+    let mut esp_path = String::with_capacity(efi_dir.len() + name.len() + suffix.len() + 5);
+    esp_path.push_str(efi_dir);
+    esp_path.push('/');
+    esp_path.extend(name.chars().map(|c| if c == '/' { '-' } else { c }));
+    esp_path.push_str(suffix);
+    esp_path.push_str(".efi");
+
+    EspRelativePath::new(&esp_path)
+}
+
+/// The specialisations of each generation whose names only differ by case (e.g. `Foo` and `foo`):
+/// they're different directories in the store, but their entries would be the same file on the ESP,
+/// since FAT doesn't tell them apart. The first of each (in sort order) keeps its name, and the
+/// others' entries are named after them with a short hash of their name appended (e.g.
+/// `nixos-generation-12-foo-2c26b46b.conf`), which the manifest then records like any other entry.
+pub(super) fn deconflicted_specialisations<'a>(
+    toplevels: impl IntoIterator<Item = &'a BootableToplevel>,
+) -> Deconflicted {
+    let mut folded = BTreeMap::<_, BTreeSet<&str>>::new();
+    for toplevel in toplevels {
+        if let Some(specialisation) = &toplevel.specialisation_name {
+            folded
+                .entry((
+                    toplevel.profile_name.clone(),
+                    toplevel.generation_index,
+                    specialisation.0.to_lowercase(),
+                ))
+                .or_default()
+                .insert(&specialisation.0);
+        }
+    }
+
+    let mut deconflicted = Deconflicted::new();
+    for ((profile, generation, _), names) in folded {
+        let mut names = names.into_iter();
+        let first = match names.next() {
+            Some(first) => first,
+            None => continue,
+        };
+
+        for name in names {
+            let hash = manifest::sha256(name.as_bytes());
+            let renamed = format!("{}-{}", name, &hash[..DECONFLICT_HASH_LEN]);
+            warnings::emit(
+                Kind::SpecialisationCaseCollision,
+                format!(
+                    "specialisations '{}' and '{}' of generation {}{} only differ by case, which \
+                     the ESP doesn't tell apart: the entry of '{}' is named after '{}' instead",
+                    first,
+                    name,
+                    generation,
+                    profile
+                        .as_deref()
+                        .map(|profile| format!(" of profile '{}'", profile))
+                        .unwrap_or_default(),
+                    name,
+                    renamed
+                ),
+            );
+            deconflicted.insert((profile.clone(), generation, name.to_string()), renamed);
+        }
+    }
+
+    deconflicted
+}
+
+/// Where the entry for `toplevel` goes; its profile, specialisation, and variant names all end up in
+/// the filename, so it's checked like every other path on the ESP. A specialisation in
+/// `deconflicted` is named after its entry there instead.
+pub(super) fn conf_path(
+    toplevel: &BootableToplevel,
+    deconflicted: &Deconflicted,
+) -> Result<EspRelativePath> {
+    let generation = toplevel.generation_index;
+    let profile = toplevel.profile_name.as_deref();
+    let name = if let Some(specialisation) = &toplevel.specialisation_name {
+        let key = (
+            toplevel.profile_name.clone(),
+            generation,
+            specialisation.0.clone(),
+        );
+        let specialisation = deconflicted.get(&key).unwrap_or(&specialisation.0);
+        self::entry_name(profile, generation, Some(specialisation))
+    } else if let Some(variant) = &toplevel.variant_name {
+        let name = self::entry_name(profile, generation, None);
+        let base = name.trim_end_matches(".conf");
+        format!("{}-variant-{}.conf", base, variant)
+    } else {
+        self::entry_name(profile, generation, None)
+    };
+
+    // `/` is fine on FAT, but would put the entry somewhere systemd-boot doesn't look
+    if name.contains('/') {
+        return Err(format!("the name of entry '{}' has a '/'", name).into());
+    }
+
+    EspRelativePath::new(&format!("loader/entries/{}", name))
+}
+
+/// The filename of the entry for `generation` of `profile` (`None` for the system profile), or of
+/// its `specialisation`, e.g. `nixos-work-generation-41-gui.conf`.
+pub fn entry_name(
+    profile: Option<&str>,
+    generation: usize,
+    specialisation: Option<&str>,
+) -> String {
+    let infix = match profile {
+        Some(profile) => format!("-{}", profile),
+        None => String::new(),
+    };
+
+    match specialisation {
+        // TODO: the specialisation in filename is required (or it conflicts with other entries), does this mess up sorting?
+        Some(specialisation) => format!(
+            "nixos{}-generation-{}-{}.conf",
+            infix, generation, specialisation
+        ),
+        None => format!("nixos{}-generation-{}.conf", infix, generation),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bootspec::{SpecialisationName, SystemConfigurationRoot};
+
+    use crate::recompress::Compression;
+
+    fn esp(path: &str) -> EspRelativePath {
+        EspRelativePath::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_plan() {
+        let toplevel = |profile: Option<&str>, specialisation: Option<&str>| BootableToplevel {
+            kernel: PathBuf::from("/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1/bzImage"),
+            initrd: PathBuf::from(
+                "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1/initrd",
+            ),
+            toplevel: SystemConfigurationRoot(PathBuf::from(
+                "/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system",
+            )),
+            specialisation_name: specialisation.map(|s| SpecialisationName(s.to_string())),
+            generation_index: 42,
+            profile_name: profile.map(ToString::to_string),
+            ..Default::default()
+        };
+        let variant = || BootableToplevel {
+            variant_name: Some(String::from("recovery")),
+            ..toplevel(None, None)
+        };
+        let recompressed = || BootableToplevel {
+            initrd_compression: Compression::Zstd,
+            ..toplevel(None, None)
+        };
+        let bootables = vec![
+            Bootable::Linux(toplevel(None, None)),
+            Bootable::Linux(toplevel(Some("work"), Some("gui"))),
+            Bootable::Efi(EfiProgram::new(toplevel(None, Some("gui")))),
+            Bootable::Linux(variant()),
+            Bootable::Efi(EfiProgram::new(variant())),
+            Bootable::Linux(recompressed()),
+            Bootable::Efi(EfiProgram::new(recompressed())),
+        ];
+
+        let plans = plan(&bootables, "EFI/nixos")
+            .unwrap()
+            .into_iter()
+            .map(|planned| planned.plan)
+            .collect::<Vec<_>>();
+        let linux = Payload::Linux {
+            kernel: esp("/EFI/nixos/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1-bzImage.efi"),
+            initrd: esp("/EFI/nixos/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1-initrd.efi"),
+        };
+        assert_eq!(
+            plans,
+            vec![
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-generation-42.conf"),
+                    payload: linux.clone(),
+                },
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-work-generation-42-gui.conf"),
+                    payload: linux.clone(),
+                },
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-generation-42-gui.conf"),
+                    payload: Payload::Unified(esp(
+                        "/EFI/nixos/0123456789abcdefghijklmnopqrstuv.efi"
+                    )),
+                },
+                // variants share the kernel and initrd, but not the unified EFI file
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-generation-42-variant-recovery.conf"),
+                    payload: linux.clone(),
+                },
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-generation-42-variant-recovery.conf"),
+                    payload: Payload::Unified(esp(
+                        "/EFI/nixos/0123456789abcdefghijklmnopqrstuv-recovery.efi"
+                    )),
+                },
+                // a recompressed initrd doesn't replace the one staged as-is
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-generation-42.conf"),
+                    payload: Payload::Linux {
+                        kernel: esp(
+                            "/EFI/nixos/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1-bzImage.efi",
+                        ),
+                        initrd: esp(
+                            "/EFI/nixos/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1-initrd-zstd.efi",
+                        ),
+                    },
+                },
+                ArtifactPlan {
+                    conf: esp("loader/entries/nixos-generation-42.conf"),
+                    payload: Payload::Unified(esp(
+                        "/EFI/nixos/0123456789abcdefghijklmnopqrstuv-zstd.efi"
+                    )),
+                },
+            ]
+        );
+
+        assert_eq!(
+            staged(Path::new("/out/a"), &esp("/EFI/nixos/x.efi")),
+            Path::new("/out/a/EFI/nixos/x.efi")
+        );
+        assert_eq!(entry_name(None, 41, None), "nixos-generation-41.conf");
+        assert_eq!(
+            entry_name(Some("work"), 41, Some("gui")),
+            "nixos-work-generation-41-gui.conf"
+        );
+
+        let not_a_store_path = vec![Bootable::Efi(EfiProgram::new(BootableToplevel {
+            toplevel: SystemConfigurationRoot(PathBuf::from("/short")),
+            ..Default::default()
+        }))];
+        assert!(plan(&not_a_store_path, "EFI/nixos").is_err());
+    }
+
+    #[test]
+    fn test_plan_fuzzed_names() {
+        // xorshift, so that failures are reproducible without pulling in a property testing crate
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet = "abcXYZ019-_. /:*?\"<>|\\\n\té";
+        let alphabet = alphabet.chars().collect::<Vec<_>>();
+        let mut name = |max_len: u64, next: &mut dyn FnMut() -> u64| {
+            let len = next() % max_len;
+            (0..len)
+                .map(|_| alphabet[(next() % alphabet.len() as u64) as usize])
+                .collect::<String>()
+        };
+
+        for _ in 0..1000 {
+            let profile = name(8, &mut next);
+            let long = next() % 16 == 0;
+            let specialisation = name(if long { 300 } else { 8 }, &mut next);
+            let variant = name(8, &mut next);
+            let bootables = [
+                Bootable::Linux(BootableToplevel {
+                    profile_name: Some(profile.clone()),
+                    specialisation_name: Some(SpecialisationName(specialisation.clone())),
+                    ..Default::default()
+                }),
+                Bootable::Efi(EfiProgram::new(BootableToplevel {
+                    toplevel: SystemConfigurationRoot(PathBuf::from(
+                        "/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system",
+                    )),
+                    profile_name: Some(profile.clone()),
+                    variant_name: Some(variant.clone()),
+                    ..Default::default()
+                })),
+            ];
+
+            let valid = |name: &str| {
+                !name
+                    .chars()
+                    .any(|c| "/:*?\"<>|\\".contains(c) || c.is_control())
+            };
+            let conf = entry_name(Some(&profile), 0, Some(&specialisation));
+            let expected =
+                valid(&profile) && valid(&specialisation) && valid(&variant) && conf.len() <= 255;
+
+            match plan(&bootables, "EFI/nixos") {
+                Ok(planned) => {
+                    assert!(expected, "{:?} {:?} {:?}", profile, specialisation, variant);
+                    for plan in planned.into_iter().map(|planned| planned.plan) {
+                        let paths = match &plan.payload {
+                            Payload::Linux { kernel, initrd } => vec![kernel, initrd],
+                            Payload::Unified(unified) => vec![unified],
+                        };
+                        for path in std::iter::once(&plan.conf).chain(paths) {
+                            assert_eq!(&EspRelativePath::new(path.as_str()).unwrap(), path);
+                            assert_eq!(&EspRelativePath::new(&path.entry_path()).unwrap(), path);
+                        }
+                        assert_eq!(
+                            Path::new(plan.conf.as_str()).parent(),
+                            Some(Path::new("loader/entries"))
+                        );
+                    }
+                }
+                Err(_) => assert!(
+                    !expected,
+                    "{:?} {:?} {:?}",
+                    profile, specialisation, variant
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_case_colliding_specialisations() {
+        let toplevel =
+            |profile: Option<&str>, generation: usize, specialisation: &str| BootableToplevel {
+                toplevel: SystemConfigurationRoot(PathBuf::from(
+                    "/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system",
+                )),
+                specialisation_name: Some(SpecialisationName(specialisation.to_string())),
+                generation_index: generation,
+                profile_name: profile.map(ToString::to_string),
+                ..Default::default()
+            };
+        let bootables = vec![
+            Bootable::Linux(toplevel(None, 12, "foo")),
+            Bootable::Efi(EfiProgram::new(toplevel(None, 12, "foo"))),
+            Bootable::Linux(toplevel(None, 12, "Foo")),
+            Bootable::Linux(toplevel(None, 12, "FOO")),
+            // in different generations or profiles, they don't collide
+            Bootable::Linux(toplevel(None, 13, "foo")),
+            Bootable::Linux(toplevel(Some("work"), 12, "foo")),
+        ];
+
+        let confs = plan(&bootables, "EFI/nixos")
+            .unwrap()
+            .into_iter()
+            .map(|planned| planned.plan.conf)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            confs,
+            vec![
+                esp("loader/entries/nixos-generation-12-foo-2c26b46b.conf"),
+                esp("loader/entries/nixos-generation-12-foo-2c26b46b.conf"),
+                esp("loader/entries/nixos-generation-12-Foo-1cbec737.conf"),
+                esp("loader/entries/nixos-generation-12-FOO.conf"),
+                esp("loader/entries/nixos-generation-13-foo.conf"),
+                esp("loader/entries/nixos-work-generation-12-foo.conf"),
+            ]
+        );
+        assert!(warnings::emitted(Kind::SpecialisationCaseCollision)
+            .iter()
+            .any(|message| message.contains("'FOO' and 'foo' of generation 12")));
+
+        // which one keeps its name doesn't depend on the order they're in
+        assert_eq!(
+            deconflicted_specialisations(bootables.iter().map(Bootable::toplevel)),
+            deconflicted_specialisations(bootables.iter().rev().map(Bootable::toplevel))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_plan_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let non_utf8 = |path: &[u8]| PathBuf::from(OsStr::from_bytes(path));
+        let toplevel = || BootableToplevel {
+            kernel: PathBuf::from("/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-6.1/bzImage"),
+            initrd: PathBuf::from(
+                "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-linux-6.1/initrd",
+            ),
+            init: PathBuf::from("/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system/init"),
+            toplevel: SystemConfigurationRoot(PathBuf::from(
+                "/nix/store/0123456789abcdefghijklmnopqrstuv-nixos-system",
+            )),
+            ..Default::default()
+        };
+        assert!(plan(&[Bootable::Linux(toplevel())], "EFI/nixos").is_ok());
+
+        // rather than being mangled into the name of a different file on the ESP, or a different
+        // path in the entry
+        for bootable in [
+            Bootable::Linux(BootableToplevel {
+                kernel: non_utf8(b"/nix/store/kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk-linux-\xff/bzImage"),
+                ..toplevel()
+            }),
+            Bootable::Linux(BootableToplevel {
+                initrd: non_utf8(b"/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-initrd-\xff/initrd"),
+                ..toplevel()
+            }),
+            Bootable::Linux(BootableToplevel {
+                init: non_utf8(b"/nix/store/0123456789abcdefghijklmnopqrstuv-\xff/init"),
+                ..toplevel()
+            }),
+            Bootable::Efi(EfiProgram::new(BootableToplevel {
+                toplevel: SystemConfigurationRoot(non_utf8(
+                    b"/nix/store/0123456789abcdefghijklmnopqrstuv-\xff",
+                )),
+                ..toplevel()
+            })),
+        ] {
+            let err = plan(&[bootable], "EFI/nixos").err().unwrap().to_string();
+            assert!(err.contains("is not valid UTF-8"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::cmdline;
+use crate::context::Context;
+use crate::incremental::PreviousRun;
+use crate::Result;
+
+pub const DEFAULT_EFI_DIR: &str = "EFI/nixos";
+
+/// How long an entry's `options` lines may get before they're folded (see `--max-options-len`).
+pub const DEFAULT_MAX_OPTIONS_LEN: usize = 512;
+/// How long an entry's title and version may get before they're elided (see `--max-label-len`).
+pub const DEFAULT_MAX_LABEL_LEN: usize = 120;
+
+/// A machine to generate boot entries for, as described by `--target-spec`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The name of the target's staging tree (`<out-dir>/<name>`)
+    pub name: String,
+    /// The target's machine-id (empty to leave it out of entries)
+    pub machine_id: String,
+    /// The directory (relative to the ESP) that kernels, initrds, and unified EFI files go in
+    #[serde(default = "default_efi_dir")]
+    pub efi_dir: String,
+    /// The systemd-boot EFI stub for the target's architecture (defaults to
+    /// `--systemd-efi-stub`)
+    #[serde(default)]
+    pub systemd_efi_stub: Option<PathBuf>,
+    /// Kernel parameters to append to those of every generation
+    #[serde(default)]
+    pub extra_kernel_params: Vec<String>,
+    /// Whether specialisations share their parent's sort-key (see `--group-specialisations`)
+    #[serde(skip)]
+    pub group_specialisations: bool,
+    /// What's unchanged since the previous run (see `--incremental`), which isn't staged again
+    #[serde(skip)]
+    pub previous: Option<PreviousRun>,
+    /// The names of the kernel params that are left alone if duplicate kernel params are collapsed
+    /// (see `--dedupe-kernel-params`), or `None` if they aren't
+    #[serde(skip)]
+    pub dedupe_kernel_params: Option<Vec<String>>,
+    /// How long the target's entries' `options` lines may get before they're folded into several
+    /// (see `--max-options-len`)
+    #[serde(skip, default = "default_max_options_len")]
+    pub max_options_len: usize,
+    /// How long (in characters) the target's entries' title and version may get before their
+    /// middle is elided (see `--max-label-len`)
+    #[serde(skip, default = "default_max_label_len")]
+    pub max_label_len: usize,
+}
+
+fn default_efi_dir() -> String {
+    String::from(DEFAULT_EFI_DIR)
+}
+
+fn default_max_options_len() -> usize {
+    DEFAULT_MAX_OPTIONS_LEN
+}
+
+fn default_max_label_len() -> usize {
+    DEFAULT_MAX_LABEL_LEN
+}
+
+impl Target {
+    /// The target describing the machine the generator is running on.
+    pub fn local(machine_id: String) -> Self {
+        Target {
+            name: String::new(),
+            machine_id,
+            efi_dir: self::default_efi_dir(),
+            systemd_efi_stub: None,
+            extra_kernel_params: Vec::new(),
+            group_specialisations: false,
+            previous: None,
+            dedupe_kernel_params: None,
+            max_options_len: self::default_max_options_len(),
+            max_label_len: self::default_max_label_len(),
+        }
+    }
+
+    /// Puts the target's `efi_dir` under `prefix` (see [`parse_entry_path_prefix`]), which is then
+    /// both in front of the paths in its entries and where the files they refer to are staged.
+    pub fn with_entry_path_prefix(mut self, prefix: &str) -> Self {
+        if !prefix.is_empty() {
+            self.efi_dir = format!("{}/{}", prefix, self.efi_dir);
+        }
+
+        self
+    }
+
+    /// Lists the target's specialisations right after their parents in systemd-boot's menu (see
+    /// `--group-specialisations`), instead of in groups of their own.
+    pub fn with_grouped_specialisations(mut self, group: bool) -> Self {
+        self.group_specialisations = group;
+
+        self
+    }
+
+    /// Leaves what's unchanged since `previous` run out of the target's staging tree (see
+    /// `--incremental`).
+    pub fn with_previous_run(mut self, previous: Option<PreviousRun>) -> Self {
+        self.previous = previous;
+
+        self
+    }
+
+    /// Collapses duplicate kernel params, except those named in `exclude`, if `exclude` is given
+    /// (see `--dedupe-kernel-params`).
+    pub fn with_deduped_kernel_params(mut self, exclude: Option<Vec<String>>) -> Self {
+        self.dedupe_kernel_params = exclude;
+
+        self
+    }
+
+    /// Folds the target's entries' `options` into lines of at most `max_len` bytes (see
+    /// `--max-options-len`).
+    pub fn with_max_options_len(mut self, max_len: usize) -> Self {
+        self.max_options_len = max_len;
+
+        self
+    }
+
+    /// Elides the middle of the target's entries' titles and versions to fit `max_len` characters
+    /// (see `--max-label-len`).
+    pub fn with_max_label_len(mut self, max_len: usize) -> Self {
+        self.max_label_len = max_len;
+
+        self
+    }
+
+    /// The kernel params of an entry (or unified EFI file) for the target: `params` with the
+    /// target's `extra_kernel_params` appended, and collapsed if duplicate kernel params are (see
+    /// [`cmdline::dedupe`]). Also returns the params that were dropped.
+    pub fn kernel_params<'a>(
+        &'a self,
+        params: impl IntoIterator<Item = &'a str>,
+    ) -> (Vec<&'a str>, Vec<&'a str>) {
+        let params = params
+            .into_iter()
+            .chain(self.extra_kernel_params.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+
+        match &self.dedupe_kernel_params {
+            Some(exclude) => cmdline::dedupe(&params, exclude),
+            None => (params, Vec::new()),
+        }
+    }
+}
+
+/// Parses `--entry-path-prefix` (e.g. `/boot`), the directory the ESP's files are under as the
+/// entries see them, into the relative path that is put in front of `efi_dir`s.
+pub fn parse_entry_path_prefix(s: &str) -> Result<String, String> {
+    let prefix = s.trim_matches('/');
+
+    if !Path::new(prefix)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(format!(
+            "entry path prefix '{}' must be a path inside the ESP (without '.' or '..')",
+            s
+        ));
+    }
+
+    Ok(prefix.to_string())
+}
+
+/// Reads the list of [`Target`]s from the JSON document at `path`.
+pub fn parse_target_spec(path: &Path) -> Result<Vec<Target>> {
+    let contents = fs::read_to_string(path).with_path_context(path)?;
+    let targets: Vec<Target> = serde_json::from_str(&contents).with_path_context(path)?;
+
+    self::validate_targets(&targets).with_path_context(path)?;
+
+    Ok(targets)
+}
+
+fn validate_targets(targets: &[Target]) -> Result<()> {
+    if targets.is_empty() {
+        return Err("no targets specified".into());
+    }
+
+    let mut names = HashSet::new();
+    for target in targets {
+        let mut components = Path::new(&target.name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(format!(
+                "target name '{}' is not a valid directory name",
+                target.name
+            )
+            .into());
+        }
+
+        if !names.insert(&target.name) {
+            return Err(format!("target name '{}' is used more than once", target.name).into());
+        }
+
+        if target.machine_id.len() != 32
+            || !target.machine_id.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(format!(
+                "machine-id '{}' of target '{}' is not 32 hexadecimal characters",
+                target.machine_id, target.name
+            )
+            .into());
+        }
+
+        if target.efi_dir.is_empty()
+            || !Path::new(&target.efi_dir)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "efi_dir '{}' of target '{}' must be a relative path inside the ESP",
+                target.efi_dir, target.name
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_parse_target_spec() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let spec = tempdir.path().join("targets.json");
+        fs::write(
+            &spec,
+            format!(
+                r#"[
+                    {{ "name": "kiosk-a", "machine_id": "{0}" }},
+                    {{
+                        "name": "kiosk-b",
+                        "machine_id": "{0}",
+                        "efi_dir": "EFI/Linux",
+                        "systemd_efi_stub": "/stubs/linuxaa64.efi.stub",
+                        "extra_kernel_params": ["console=ttyS0"]
+                    }}
+                ]"#,
+                MACHINE_ID
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_target_spec(&spec).unwrap(),
+            vec![
+                Target {
+                    name: String::from("kiosk-a"),
+                    ..Target::local(String::from(MACHINE_ID))
+                },
+                Target {
+                    name: String::from("kiosk-b"),
+                    machine_id: String::from(MACHINE_ID),
+                    efi_dir: String::from("EFI/Linux"),
+                    systemd_efi_stub: Some(PathBuf::from("/stubs/linuxaa64.efi.stub")),
+                    extra_kernel_params: vec![String::from("console=ttyS0")],
+                    group_specialisations: false,
+                    previous: None,
+                    dedupe_kernel_params: None,
+                    max_options_len: DEFAULT_MAX_OPTIONS_LEN,
+                    max_label_len: DEFAULT_MAX_LABEL_LEN,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_targets() {
+        let target = |name: &str, machine_id: &str, efi_dir: &str| Target {
+            name: name.to_string(),
+            machine_id: machine_id.to_string(),
+            efi_dir: efi_dir.to_string(),
+            ..Target::local(String::new())
+        };
+
+        assert!(validate_targets(&[target("a", MACHINE_ID, "EFI/nixos")]).is_ok());
+
+        for invalid in [
+            vec![],
+            vec![target("", MACHINE_ID, "EFI/nixos")],
+            vec![target("a/b", MACHINE_ID, "EFI/nixos")],
+            vec![target("..", MACHINE_ID, "EFI/nixos")],
+            vec![target("a", "not-a-machine-id", "EFI/nixos")],
+            vec![target("a", MACHINE_ID, "/EFI/nixos")],
+            vec![target("a", MACHINE_ID, "EFI/../..")],
+            vec![
+                target("a", MACHINE_ID, "EFI/nixos"),
+                target("a", MACHINE_ID, "EFI/nixos"),
+            ],
+        ] {
+            assert!(validate_targets(&invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_entry_path_prefix() {
+        assert_eq!(parse_entry_path_prefix("/boot").unwrap(), "boot");
+        assert_eq!(parse_entry_path_prefix("/boot/esp/").unwrap(), "boot/esp");
+        assert_eq!(parse_entry_path_prefix("").unwrap(), "");
+        assert_eq!(parse_entry_path_prefix("/").unwrap(), "");
+        assert!(parse_entry_path_prefix("/boot/../..").is_err());
+        assert!(parse_entry_path_prefix("./boot").is_err());
+
+        let target = Target::local(String::from(MACHINE_ID));
+        assert_eq!(
+            target.clone().with_entry_path_prefix("").efi_dir,
+            DEFAULT_EFI_DIR
+        );
+        assert_eq!(
+            target.with_entry_path_prefix("boot").efi_dir,
+            "boot/EFI/nixos"
+        );
+    }
+
+    #[test]
+    fn test_kernel_params() {
+        let target = Target {
+            extra_kernel_params: vec![String::from("console=ttyS0"), String::from("quiet")],
+            ..Target::local(String::from(MACHINE_ID))
+        };
+        let params = ["init=/init", "quiet", "console=tty0"];
+
+        assert_eq!(
+            target.kernel_params(params.iter().copied()),
+            (
+                vec![
+                    "init=/init",
+                    "quiet",
+                    "console=tty0",
+                    "console=ttyS0",
+                    "quiet"
+                ],
+                vec![]
+            )
+        );
+        let target = target.with_deduped_kernel_params(Some(vec![String::from("console")]));
+        assert_eq!(
+            target.kernel_params(params.iter().copied()),
+            (
+                vec!["init=/init", "console=tty0", "console=ttyS0", "quiet"],
+                vec!["quiet"]
+            )
+        );
+    }
+}
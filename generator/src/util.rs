@@ -0,0 +1,104 @@
+use std::fs;
+use std::path::Path;
+
+use crate::context::Context;
+use crate::Result;
+
+/// Hard links `from` to `to`, so that identical files in the staging tree only take up space once.
+///
+/// Falls back to copying when a hard link isn't possible (e.g. when `to` is on another
+/// filesystem, or the filesystem doesn't support hard links). An existing `to` is replaced.
+pub(crate) fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).with_path_context(parent)?;
+    }
+
+    if to.exists() {
+        fs::remove_file(to).with_path_context(to)?;
+    }
+
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to).with_paths_context(from, to)?;
+    }
+
+    Ok(())
+}
+
+/// Symlinks `to` to `from` (a file in the store), which the installer follows when it copies the
+/// staging tree to the ESP. Where there are no symlinks (e.g. staging trees generated on a Windows
+/// build host), `from` is copied instead.
+pub(crate) fn symlink_or_copy(from: &Path, to: &Path) -> Result<()> {
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(from, to).with_paths_context(from, to)?;
+    #[cfg(not(unix))]
+    fs::copy(from, to).with_paths_context(from, to)?;
+
+    Ok(())
+}
+
+/// `path` as a string, for the entries (which have to be valid UTF-8) and the names on the ESP that
+/// are derived from it. A path that isn't valid UTF-8 is an error, rather than being mangled into a
+/// different one.
+pub(crate) fn utf8(path: &Path) -> Result<&str> {
+    match path.to_str() {
+        Some(path) => Ok(path),
+        None => Err(format!(
+            "'{}' is not valid UTF-8, which paths in boot loader entries have to be",
+            path.display()
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_link_or_copy() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let from = dir.join("a/EFI/nixos/kernel.efi");
+        let to = dir.join("b/EFI/nixos/kernel.efi");
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, "kernel").unwrap();
+
+        link_or_copy(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "kernel");
+        #[cfg(unix)]
+        {
+            assert_eq!(
+                fs::metadata(&from).unwrap().ino(),
+                fs::metadata(&to).unwrap().ino()
+            );
+            assert_eq!(fs::metadata(&to).unwrap().nlink(), 2);
+        }
+
+        // an existing file is replaced
+        let other = dir.join("other.efi");
+        fs::write(&other, "other").unwrap();
+        link_or_copy(&other, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "other");
+        assert_eq!(fs::read_to_string(&from).unwrap(), "kernel");
+        #[cfg(unix)]
+        assert_eq!(fs::metadata(&from).unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn test_symlink_or_copy() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let from = dir.join("kernel");
+        let to = dir.join("kernel.efi");
+        fs::write(&from, "kernel").unwrap();
+
+        symlink_or_copy(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "kernel");
+        assert_eq!(
+            fs::symlink_metadata(&to).unwrap().file_type().is_symlink(),
+            cfg!(unix)
+        );
+    }
+}
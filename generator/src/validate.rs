@@ -0,0 +1,553 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::bootable::BootableToplevel;
+use crate::Result;
+
+pub(crate) const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+pub(crate) const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_FOOTER_MAGIC: &[u8] = b"YZ";
+// The kernel only unpacks "newc" cpio archives (with or without checksums).
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_LEN: u64 = 110;
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+const PE_MAGIC: &[u8] = b"MZ";
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+
+/// Checks that the kernel and initrd of `toplevel` look intact, naming the offending artifact and
+/// generation if they don't.
+pub fn validate_toplevel(toplevel: &BootableToplevel) -> Result<()> {
+    let generation = self::describe(toplevel);
+
+    self::validate_kernel(&toplevel.kernel).map_err(|e| {
+        format!(
+            "kernel '{}' of {} failed validation: {}",
+            toplevel.kernel.display(),
+            generation,
+            e
+        )
+    })?;
+    self::validate_initrd(&toplevel.initrd).map_err(|e| {
+        format!(
+            "initrd '{}' of {} failed validation: {}",
+            toplevel.initrd.display(),
+            generation,
+            e
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Checks that `path` starts with either PE or bzImage magic, and that it is at least as long as
+/// its headers claim it to be.
+pub fn validate_kernel(path: &Path) -> Result<()> {
+    let mut f = File::open(path)?;
+    let len = f.metadata()?.len();
+    let header = self::read_at(&mut f, 0, len.min(0x400))?;
+
+    let expected_len = if header.starts_with(PE_MAGIC) {
+        self::pe_image_len(&mut f)?
+    } else if header.get(0x202..0x206) == Some(BZIMAGE_MAGIC) {
+        self::bzimage_len(&header)?
+    } else {
+        return Err("missing bzImage or PE magic".into());
+    };
+
+    if len < expected_len {
+        return Err(format!(
+            "file is truncated (expected at least {} bytes, found {})",
+            expected_len, len
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Checks that `path` is made up of (possibly concatenated) cpio archives, optionally followed by a
+/// gzip-, zstd-, or xz-compressed payload.
+pub fn validate_initrd(path: &Path) -> Result<()> {
+    let mut f = File::open(path)?;
+    let len = f.metadata()?.len();
+
+    if len == 0 {
+        return Err("file is empty".into());
+    }
+
+    let mut offset = 0;
+    while offset < len {
+        let magic = self::read_at(&mut f, offset, (len - offset).min(6))?;
+
+        if magic.starts_with(CPIO_NEWC_MAGIC) || magic.starts_with(CPIO_CRC_MAGIC) {
+            offset = self::skip_cpio(&mut f, offset, len)?;
+            offset = self::skip_padding(&mut f, offset, len)?;
+        } else if magic.starts_with(GZIP_MAGIC) || magic.starts_with(ZSTD_MAGIC) {
+            // Neither format has a trailer we can cheaply check without decompressing.
+            return Ok(());
+        } else if magic.starts_with(XZ_MAGIC) {
+            if len - offset < 12 || self::read_at(&mut f, len - 2, 2)? != XZ_FOOTER_MAGIC {
+                return Err("xz stream is truncated (missing footer)".into());
+            }
+
+            return Ok(());
+        } else {
+            return Err(format!(
+                "unrecognized data at offset {} (expected cpio, gzip, zstd, or xz magic)",
+                offset
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `path` is made up of nothing but (possibly concatenated) cpio archives, i.e. the kernel
+/// would unpack it without decompressing anything.
+pub fn is_uncompressed_initrd(path: &Path) -> Result<bool> {
+    let mut f = File::open(path)?;
+    let len = f.metadata()?.len();
+
+    if len == 0 {
+        return Ok(false);
+    }
+
+    let mut offset = 0;
+    while offset < len {
+        let magic = self::read_at(&mut f, offset, (len - offset).min(6))?;
+
+        if !magic.starts_with(CPIO_NEWC_MAGIC) && !magic.starts_with(CPIO_CRC_MAGIC) {
+            return Ok(false);
+        }
+
+        offset = self::skip_cpio(&mut f, offset, len)?;
+        offset = self::skip_padding(&mut f, offset, len)?;
+    }
+
+    Ok(true)
+}
+
+fn describe(toplevel: &BootableToplevel) -> String {
+    let mut s = format!("generation {}", toplevel.generation_index);
+
+    if let Some(ref profile) = toplevel.profile_name {
+        s.push_str(&format!(" of profile '{}'", profile));
+    }
+    if let Some(ref specialisation) = toplevel.specialisation_name {
+        s.push_str(&format!(" (specialisation '{}')", specialisation.0));
+    }
+
+    s
+}
+
+/// Reads the `len` bytes at `offset` in `f`. Both usually come from headers in the file, so they're
+/// checked against its length before anything is allocated for them.
+fn read_at(f: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let file_len = f.metadata()?.len();
+    if offset.checked_add(len).map_or(true, |end| end > file_len) {
+        return Err(format!(
+            "file is truncated (couldn't read {} bytes at offset {} of {})",
+            len, offset, file_len
+        )
+        .into());
+    }
+
+    let mut buf = vec![0; len as usize];
+
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut buf).map_err(|_| {
+        format!(
+            "file is truncated (couldn't read {} bytes at offset {})",
+            len, offset
+        )
+    })?;
+
+    Ok(buf)
+}
+
+fn u16_le(bytes: &[u8]) -> u64 {
+    u16::from_le_bytes([bytes[0], bytes[1]]) as u64
+}
+
+fn u32_le(bytes: &[u8]) -> u64 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+}
+
+/// Returns the offset of the end of the last section's raw data.
+fn pe_image_len(f: &mut File) -> Result<u64> {
+    let end = self::pe_section_table(f)?
+        .chunks(40)
+        .map(|section| self::u32_le(&section[20..]) + self::u32_le(&section[16..]))
+        .max()
+        .unwrap_or(0);
+
+    Ok(end)
+}
+
+/// Returns the contents of the section called `name` (e.g. a unified EFI file's `.initrd`) of the
+/// PE image at `path`, or `None` if it doesn't have one.
+pub fn pe_section(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let mut section = match self::pe_section_reader(path, name)? {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    let mut buf = Vec::new();
+    section.read_to_end(&mut buf)?;
+
+    Ok(Some(buf))
+}
+
+/// Like [`pe_section`], but reads the section as it's read from, rather than all at once (e.g. to
+/// hash a unified EFI file's `.initrd` a buffer at a time).
+pub fn pe_section_reader(path: &Path, name: &str) -> Result<Option<io::Take<File>>> {
+    let mut f = File::open(path)?;
+
+    if self::read_at(&mut f, 0, 2)? != PE_MAGIC {
+        return Err("missing PE magic".into());
+    }
+
+    for section in self::pe_section_table(&mut f)?.chunks(40) {
+        // Names are NUL-padded to 8 bytes.
+        if section[..8].split(|b| *b == 0).next() != Some(name.as_bytes()) {
+            continue;
+        }
+
+        // The raw data is padded to the file alignment, while the virtual size is the size of
+        // what was actually put in the section.
+        let virtual_size = self::u32_le(&section[8..]);
+        let raw_size = self::u32_le(&section[16..]);
+        let raw_offset = self::u32_le(&section[20..]);
+        let len = match virtual_size {
+            0 => raw_size,
+            virtual_size => virtual_size.min(raw_size),
+        };
+
+        let file_len = f.metadata()?.len();
+        if raw_offset + len > file_len {
+            return Err(format!(
+                "file is truncated (couldn't read {} bytes at offset {})",
+                len, raw_offset
+            )
+            .into());
+        }
+        f.seek(SeekFrom::Start(raw_offset))?;
+
+        return Ok(Some(f.take(len)));
+    }
+
+    Ok(None)
+}
+
+fn pe_section_table(f: &mut File) -> Result<Vec<u8>> {
+    let dos_header = self::read_at(f, 0, 0x40)?;
+    let pe_offset = self::u32_le(&dos_header[0x3c..]);
+    let coff_header = self::read_at(f, pe_offset, 24)?;
+
+    if &coff_header[..4] != b"PE\0\0" {
+        return Err("missing PE signature".into());
+    }
+
+    let section_count = self::u16_le(&coff_header[6..]);
+    let optional_header_len = self::u16_le(&coff_header[20..]);
+
+    self::read_at(f, pe_offset + 24 + optional_header_len, section_count * 40)
+}
+
+/// Returns the size of the real-mode setup code plus the protected-mode kernel, as described by the
+/// Linux x86 boot protocol.
+fn bzimage_len(header: &[u8]) -> Result<u64> {
+    let setup_sects = match header.get(0x1f1).ok_or("boot sector is truncated")? {
+        0 => 4,
+        n => *n as u64,
+    };
+    let syssize = self::u32_le(header.get(0x1f4..0x1f8).ok_or("boot sector is truncated")?);
+
+    Ok((setup_sects + 1) * 512 + syssize * 16)
+}
+
+/// Walks the cpio archive starting at `offset`, returning the offset just past its trailer entry.
+fn skip_cpio(f: &mut File, mut offset: u64, len: u64) -> Result<u64> {
+    let align = |n: u64| (n + 3) & !3;
+    let hex =
+        |bytes: &[u8]| -> Result<u64> { Ok(u64::from_str_radix(std::str::from_utf8(bytes)?, 16)?) };
+
+    loop {
+        let header = self::read_at(f, offset, CPIO_HEADER_LEN)
+            .map_err(|_| "cpio archive is truncated (missing trailer)")?;
+
+        if !header.starts_with(CPIO_NEWC_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(format!("corrupt cpio header at offset {}", offset).into());
+        }
+
+        let file_size = hex(&header[54..62])?;
+        let name_size = hex(&header[94..102])?;
+        let name = self::read_at(f, offset + CPIO_HEADER_LEN, name_size)
+            .map_err(|_| "cpio archive is truncated (missing trailer)")?;
+
+        offset = align(offset + CPIO_HEADER_LEN + name_size);
+        offset = align(offset + file_size);
+
+        if offset > len {
+            return Err("cpio archive is truncated (missing trailer)".into());
+        }
+
+        if name.split(|b| *b == 0).next() == Some(CPIO_TRAILER) {
+            return Ok(offset);
+        }
+    }
+}
+
+/// Skips the NUL padding that may follow a cpio archive's trailer.
+fn skip_padding(f: &mut File, mut offset: u64, len: u64) -> Result<u64> {
+    while offset < len {
+        let chunk = self::read_at(f, offset, (len - offset).min(4096))?;
+
+        match chunk.iter().position(|b| *b != 0) {
+            Some(pos) => return Ok(offset + pos as u64),
+            None => offset += chunk.len() as u64,
+        }
+    }
+
+    Ok(offset)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use bootspec::SpecialisationName;
+
+    pub(crate) fn cpio(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let entries = files
+            .iter()
+            .map(|(name, data)| (*name, *data))
+            .chain(std::iter::once(("TRAILER!!!", &b""[..])));
+
+        for (name, data) in entries {
+            out.extend_from_slice(b"070701");
+            for field in 0..13 {
+                let value = match field {
+                    6 => data.len(),
+                    11 => name.len() + 1,
+                    _ => 0,
+                };
+                out.extend_from_slice(format!("{:08x}", value).as_bytes());
+            }
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            while out.len() % 4 != 0 {
+                out.push(0);
+            }
+            out.extend_from_slice(data);
+            while out.len() % 4 != 0 {
+                out.push(0);
+            }
+        }
+
+        out
+    }
+
+    pub(crate) fn bzimage(syssize: u32) -> Vec<u8> {
+        let mut out = vec![0; (4 + 1) * 512 + syssize as usize * 16];
+        out[0x1f1] = 4;
+        out[0x1f4..0x1f8].copy_from_slice(&syssize.to_le_bytes());
+        out[0x202..0x206].copy_from_slice(b"HdrS");
+
+        out
+    }
+
+    fn pe(section_end: u32) -> Vec<u8> {
+        let mut out = vec![0; section_end as usize];
+        out[..2].copy_from_slice(b"MZ");
+        out[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        out[0x80..0x84].copy_from_slice(b"PE\0\0");
+        // one section, no optional header
+        out[0x86..0x88].copy_from_slice(&1u16.to_le_bytes());
+        out[0x94..0x96].copy_from_slice(&0u16.to_le_bytes());
+        // SizeOfRawData, PointerToRawData
+        out[0x98 + 16..0x98 + 20].copy_from_slice(&(section_end - 0x200).to_le_bytes());
+        out[0x98 + 20..0x98 + 24].copy_from_slice(&0x200u32.to_le_bytes());
+
+        out
+    }
+
+    /// A PE image with the given sections, each padded to a file alignment of 0x200.
+    fn pe_with_sections(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = pe(0x200);
+        out[0x86..0x88].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+
+        for (i, (name, data)) in sections.iter().enumerate() {
+            let header = 0x98 + i * 40;
+            let offset = out.len() as u32;
+            let raw_size = (data.len() as u32 + 0x1ff) & !0x1ff;
+            out[header..header + name.len()].copy_from_slice(name.as_bytes());
+            out[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            out[header + 16..header + 20].copy_from_slice(&raw_size.to_le_bytes());
+            out[header + 20..header + 24].copy_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(data);
+            out.resize((offset + raw_size) as usize, 0);
+        }
+
+        out
+    }
+
+    fn fixture(dir: &Path, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_validate_kernel() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        let image = bzimage(64);
+        assert!(validate_kernel(&fixture(dir, "bzImage", &image)).is_ok());
+        assert!(
+            validate_kernel(&fixture(dir, "bzImage-short", &image[..image.len() - 1])).is_err()
+        );
+
+        let image = pe(0x1000);
+        assert!(validate_kernel(&fixture(dir, "pe", &image)).is_ok());
+        assert!(validate_kernel(&fixture(dir, "pe-short", &image[..0x800])).is_err());
+
+        assert!(validate_kernel(&fixture(dir, "garbage", &[0x42; 0x400])).is_err());
+        assert!(validate_kernel(&fixture(dir, "empty", &[])).is_err());
+    }
+
+    #[test]
+    fn test_pe_section() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        let image = pe_with_sections(&[
+            (".osrel", b"ID=nixos\n"),
+            (".linux", &bzimage(1)),
+            (".initrd", b"initrd"),
+        ]);
+        let uki = fixture(dir, "uki", &image);
+        assert_eq!(pe_section(&uki, ".initrd").unwrap().unwrap(), b"initrd");
+        assert_eq!(pe_section(&uki, ".linux").unwrap().unwrap(), bzimage(1));
+        assert_eq!(pe_section(&uki, ".cmdline").unwrap(), None);
+        assert!(validate_kernel(&uki).is_ok());
+
+        assert!(pe_section(&fixture(dir, "uki-short", &image[..0x600]), ".initrd").is_err());
+        assert!(pe_section(&fixture(dir, "bzImage", &bzimage(1)), ".initrd").is_err());
+    }
+
+    #[test]
+    fn test_validate_initrd() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        let mut archive = cpio(&[("init", b"#!/bin/sh\n"), ("etc/hostname", b"nixos\n")]);
+        assert!(validate_initrd(&fixture(dir, "cpio", &archive)).is_ok());
+        assert!(
+            validate_initrd(&fixture(dir, "cpio-short", &archive[..archive.len() - 8])).is_err()
+        );
+
+        archive.resize(1024, 0);
+        assert!(validate_initrd(&fixture(dir, "cpio-padded", &archive)).is_ok());
+
+        let mut concatenated = archive.clone();
+        concatenated.extend_from_slice(ZSTD_MAGIC);
+        concatenated.extend_from_slice(&[0x42; 32]);
+        assert!(validate_initrd(&fixture(dir, "cpio-zstd", &concatenated)).is_ok());
+
+        let mut concatenated = archive;
+        concatenated.extend_from_slice(&[0x42; 32]);
+        assert!(validate_initrd(&fixture(dir, "cpio-garbage", &concatenated)).is_err());
+
+        let mut gzip = GZIP_MAGIC.to_vec();
+        gzip.extend_from_slice(&[0x42; 32]);
+        assert!(validate_initrd(&fixture(dir, "gzip", &gzip)).is_ok());
+
+        let mut xz = XZ_MAGIC.to_vec();
+        xz.extend_from_slice(&[0x42; 32]);
+        xz.extend_from_slice(XZ_FOOTER_MAGIC);
+        assert!(validate_initrd(&fixture(dir, "xz", &xz)).is_ok());
+        assert!(validate_initrd(&fixture(dir, "xz-short", &xz[..xz.len() - 4])).is_err());
+
+        assert!(validate_initrd(&fixture(dir, "empty", &[])).is_err());
+    }
+
+    #[test]
+    fn test_read_at_beyond_end() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        let mut f = File::open(fixture(dir, "short", &[0x42; 16])).unwrap();
+        assert_eq!(read_at(&mut f, 8, 8).unwrap(), [0x42; 8]);
+        assert!(read_at(&mut f, 8, 9).is_err());
+        assert!(read_at(&mut f, u64::MAX, 1).is_err());
+
+        // sizes in headers that run past the end of the file fail before they're allocated
+        let mut archive = cpio(&[("init", b"#!/bin/sh\n")]);
+        archive[94..102].copy_from_slice(b"ffffffff");
+        assert!(validate_initrd(&fixture(dir, "cpio-huge-name", &archive)).is_err());
+
+        let mut image = pe(0x1000);
+        image[0x86..0x88].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(validate_kernel(&fixture(dir, "pe-huge-section-table", &image)).is_err());
+        let mut image = pe(0x1000);
+        image[0x3c..0x40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(validate_kernel(&fixture(dir, "pe-huge-offset", &image)).is_err());
+    }
+
+    #[test]
+    fn test_is_uncompressed_initrd() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+
+        let mut archive = cpio(&[("init", b"#!/bin/sh\n")]);
+        archive.extend(cpio(&[("etc/hostname", b"nixos\n")]));
+        archive.resize(1024, 0);
+        assert!(is_uncompressed_initrd(&fixture(dir, "cpio", &archive)).unwrap());
+        assert!(is_uncompressed_initrd(&fixture(dir, "cpio-short", &archive[..200])).is_err());
+
+        // e.g. early microcode, followed by the compressed initrd proper
+        let mut concatenated = archive;
+        concatenated.extend_from_slice(GZIP_MAGIC);
+        concatenated.extend_from_slice(&[0x42; 32]);
+        assert!(!is_uncompressed_initrd(&fixture(dir, "cpio-gzip", &concatenated)).unwrap());
+
+        assert!(!is_uncompressed_initrd(&fixture(dir, "gzip", &concatenated[1024..])).unwrap());
+        assert!(!is_uncompressed_initrd(&fixture(dir, "empty", &[])).unwrap());
+    }
+
+    #[test]
+    fn test_validate_toplevel_names_generation() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let image = bzimage(64);
+        let archive = cpio(&[("init", b"#!/bin/sh\n")]);
+
+        let toplevel = BootableToplevel {
+            kernel: fixture(dir, "kernel", &image),
+            initrd: fixture(dir, "initrd", &archive[..archive.len() / 2]),
+            generation_index: 42,
+            profile_name: Some(String::from("work")),
+            specialisation_name: Some(SpecialisationName(String::from("gui"))),
+            ..Default::default()
+        };
+
+        let err = validate_toplevel(&toplevel).unwrap_err().to_string();
+        assert!(err.contains(&toplevel.initrd.display().to_string()));
+        assert!(err.contains("generation 42 of profile 'work' (specialisation 'gui')"));
+
+        let toplevel = BootableToplevel {
+            initrd: fixture(dir, "initrd", &archive),
+            ..toplevel
+        };
+        assert!(validate_toplevel(&toplevel).is_ok());
+    }
+}
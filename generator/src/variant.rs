@@ -0,0 +1,244 @@
+//! Extra entries for a generation that boot its kernel and initrd with more kernel params, e.g. a
+//! recovery entry with `systemd.unit=rescue.target` (see `--extra-entry-variant`).
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bootable::BootableToplevel;
+use crate::cmdline;
+use crate::context::Context;
+use crate::Result;
+
+/// An extra entry to generate for a generation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryVariant {
+    /// The variant's name, which goes in the entry's title and filename
+    pub name: String,
+    /// The kernel params appended to the generation's own
+    pub kernel_params: Vec<String>,
+}
+
+/// Parses an `--extra-entry-variant` of the form `name=params-file`.
+pub fn parse_variant_arg(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form name=params-file", s))?;
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "'{}' is not a variant name (it must be non-empty and only contain ASCII letters, digits, and '_')",
+            name
+        ));
+    }
+    if path.is_empty() {
+        return Err(format!("variant '{}' has no params file", name));
+    }
+
+    Ok((name.to_string(), PathBuf::from(path)))
+}
+
+impl EntryVariant {
+    /// Reads the variant's kernel params from the file at `path`, which is split like a command line
+    /// (on whitespace, including newlines, outside of double quotes).
+    ///
+    /// The file's contents are taken literally, and the params are checked the same way as a
+    /// generation's own (see [`cmdline::quote`]).
+    pub fn read(name: String, path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_path_context(path)?;
+        let kernel_params = cmdline::split(&contents).with_path_context(path)?;
+        cmdline::render(kernel_params.iter().map(String::as_str)).with_path_context(path)?;
+
+        Ok(Self {
+            name,
+            kernel_params,
+        })
+    }
+}
+
+/// Adds an entry for every one of `variants` after each generation in `toplevels` (or only after the
+/// newest generation of each profile, if `latest_only`).
+///
+/// Specialisations don't get variants.
+pub fn add_variants(
+    toplevels: Vec<BootableToplevel>,
+    variants: &[EntryVariant],
+    latest_only: bool,
+) -> Result<Vec<BootableToplevel>> {
+    let mut names = HashSet::new();
+    if let Some(variant) = variants.iter().find(|v| !names.insert(&v.name)) {
+        return Err(format!("variant '{}' is specified more than once", variant.name).into());
+    }
+
+    let mut newest = HashMap::new();
+    for toplevel in &toplevels {
+        let index = newest.entry(&toplevel.profile_name).or_insert(0);
+        *index = toplevel.generation_index.max(*index);
+    }
+    let newest = newest
+        .into_iter()
+        .map(|(profile, index)| (profile.clone(), index))
+        .collect::<HashMap<_, _>>();
+
+    let mut with_variants = Vec::with_capacity(toplevels.len());
+    for toplevel in toplevels {
+        let wants_variants = toplevel.specialisation_name.is_none()
+            && (!latest_only
+                || newest.get(&toplevel.profile_name) == Some(&toplevel.generation_index));
+        let variants = if wants_variants { variants } else { &[] };
+
+        let variant_toplevels = variants
+            .iter()
+            .map(|variant| {
+                let mut kernel_params = toplevel.kernel_params.clone();
+                kernel_params.extend(variant.kernel_params.iter().cloned());
+
+                BootableToplevel {
+                    kernel_params,
+                    variant_name: Some(variant.name.clone()),
+                    ..toplevel.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        with_variants.push(toplevel);
+        with_variants.extend(variant_toplevels);
+    }
+
+    Ok(with_variants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toplevel(
+        profile: Option<&str>,
+        generation_index: usize,
+        specialisation: Option<&str>,
+    ) -> BootableToplevel {
+        BootableToplevel {
+            kernel_params: vec![String::from("quiet")],
+            generation_index,
+            profile_name: profile.map(ToString::to_string),
+            specialisation_name: specialisation
+                .map(|s| bootspec::SpecialisationName(s.to_string())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_arg() {
+        assert_eq!(
+            parse_variant_arg("recovery=/etc/recovery.params"),
+            Ok((
+                String::from("recovery"),
+                PathBuf::from("/etc/recovery.params")
+            ))
+        );
+        assert_eq!(
+            parse_variant_arg("a=b=c"),
+            Ok((String::from("a"), PathBuf::from("b=c")))
+        );
+        for arg in [
+            "recovery",
+            "=x.params",
+            "re-covery=x.params",
+            "a b=x",
+            "recovery=",
+        ] {
+            assert!(parse_variant_arg(arg).is_err(), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn test_read_variant() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("recovery.params");
+
+        fs::write(
+            &path,
+            "systemd.unit=rescue.target\n$(reboot) msg=\"${HOME} x\"\n",
+        )
+        .unwrap();
+        let variant = EntryVariant::read(String::from("recovery"), &path).unwrap();
+        assert_eq!(
+            variant.kernel_params,
+            vec!["systemd.unit=rescue.target", "$(reboot)", "msg=${HOME} x"]
+        );
+
+        for contents in ["foo=\"bar\n", "foo=bar\u{0}"] {
+            fs::write(&path, contents).unwrap();
+            let err = EntryVariant::read(String::from("recovery"), &path)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(&path.display().to_string()), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_add_variants() {
+        let toplevels = vec![
+            toplevel(None, 1, None),
+            toplevel(None, 2, None),
+            toplevel(None, 2, Some("gui")),
+            toplevel(Some("work"), 1, None),
+        ];
+        let variants = vec![EntryVariant {
+            name: String::from("recovery"),
+            kernel_params: vec![String::from("systemd.unit=rescue.target")],
+        }];
+        let summary = |toplevels: Vec<BootableToplevel>| {
+            toplevels
+                .into_iter()
+                .map(|t| {
+                    (
+                        t.profile_name,
+                        t.generation_index,
+                        t.specialisation_name.map(|s| s.0),
+                        t.variant_name,
+                        t.kernel_params.len(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let work = Some(String::from("work"));
+        let gui = Some(String::from("gui"));
+        let recovery = Some(String::from("recovery"));
+
+        let all = add_variants(toplevels.clone(), &variants, false).unwrap();
+        assert_eq!(
+            all[1].kernel_params,
+            vec!["quiet", "systemd.unit=rescue.target"]
+        );
+        assert_eq!(
+            summary(all),
+            vec![
+                (None, 1, None, None, 1),
+                (None, 1, None, recovery.clone(), 2),
+                (None, 2, None, None, 1),
+                (None, 2, None, recovery.clone(), 2),
+                (None, 2, gui.clone(), None, 1),
+                (work.clone(), 1, None, None, 1),
+                (work.clone(), 1, None, recovery.clone(), 2),
+            ]
+        );
+
+        // the newest generation of each profile
+        let latest = add_variants(toplevels.clone(), &variants, true).unwrap();
+        assert_eq!(
+            summary(latest),
+            vec![
+                (None, 1, None, None, 1),
+                (None, 2, None, None, 1),
+                (None, 2, None, recovery.clone(), 2),
+                (None, 2, gui, None, 1),
+                (work.clone(), 1, None, None, 1),
+                (work, 1, None, recovery, 2),
+            ]
+        );
+
+        let twice = vec![variants[0].clone(), variants[0].clone()];
+        assert!(add_variants(toplevels, &twice, false).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! The warnings about something that someone should look into (e.g. an entry on the ESP that the
+//! installer didn't put there), rather than what's only worth logging: every one goes through
+//! [`emit`], which logs it and records it by its [`Kind`], so that the installer's run report can
+//! list them (see [`report`]), and its `--strict` can fail the run over them at its
+//! [`checkpoint`]s: before touching any ESP, before writing to each one, and at the end of the run.
+//! The generator's go through here too, so that they're all of a kind.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::report::WarningReport;
+use crate::Result;
+
+/// What a warning is about, by the name that `--strict=<kind>,...` takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kind {
+    /// An ESP that's on the same filesystem as an earlier one is ignored
+    DuplicateEsp,
+    /// An ESP without an EFI or loader directory is installed to as a new one
+    NewEsp,
+    /// What looks like the ESP is a directory below `--esp`, but `--install` was passed
+    MisplacedEsp,
+    /// An ESP that wasn't cleanly unmounted is written to anyway (`--ignore-dirty-esp`)
+    DirtyEsp,
+    /// Whether an ESP was cleanly unmounted couldn't be checked
+    UnknownEspFs,
+    /// An ESP is managed by a newer release of the installer
+    NewerInstaller,
+    /// loader.conf's default doesn't match any entry
+    UnmatchedDefault,
+    /// An entry left over on the ESP from before `--xbootldr`
+    LeftoverEntry,
+    /// An entry with the name of one of ours, which the installer didn't put there
+    EntryCollision,
+    /// A UKI in `EFI/Linux` with the ID of one of our entries, which the installer didn't put there
+    ForeignUkiCollision,
+    /// A fallback loader that isn't systemd-boot is kept
+    ForeignFallbackLoader,
+    /// A generation's initrd secrets aren't appended to its initrd on the ESP
+    InitrdSecrets,
+    /// The signing cert (and its issuer) isn't enrolled in the firmware's db
+    CertNotEnrolled,
+    /// Whether the signing cert is enrolled couldn't be checked
+    UncheckedEnrollment,
+    /// A signed file on the ESP doesn't verify
+    UnverifiedSignature,
+    /// A file on the ESP differs from the one staged under the same name
+    ModifiedFile,
+    /// The ESP changed between planning and writing (`--ignore-esp-drift`)
+    EspDrift,
+    /// A chainloaded EFI program isn't on the ESP to be signed
+    MissingChainload,
+    /// A pinned generation no longer exists
+    StalePin,
+    /// A generation staged to be booted once isn't promoted
+    OneshotNotPromoted,
+    /// A GRUB config's syntax isn't checked
+    UncheckedGrubConfig,
+    /// Specialisations whose names only differ by case get entries under different names
+    SpecialisationCaseCollision,
+    /// Entries are written without a machine-id
+    MissingMachineId,
+    /// The previous run's manifest can't be trusted, so nothing is generated incrementally
+    UntrustedPreviousManifest,
+    /// ukify is missing or too old, so `--objcopy` builds the unified EFI files instead
+    UkifyFallback,
+    /// A file or a command's output isn't valid UTF-8, and its invalid parts are ignored
+    InvalidUtf8,
+}
+
+/// Every [`Kind`], by its name.
+const KINDS: &[(Kind, &str)] = &[
+    (Kind::DuplicateEsp, "duplicate-esp"),
+    (Kind::NewEsp, "new-esp"),
+    (Kind::MisplacedEsp, "misplaced-esp"),
+    (Kind::DirtyEsp, "dirty-esp"),
+    (Kind::UnknownEspFs, "unknown-esp-fs"),
+    (Kind::NewerInstaller, "newer-installer"),
+    (Kind::UnmatchedDefault, "unmatched-default"),
+    (Kind::LeftoverEntry, "leftover-entry"),
+    (Kind::EntryCollision, "entry-collision"),
+    (Kind::ForeignUkiCollision, "foreign-uki-collision"),
+    (Kind::ForeignFallbackLoader, "foreign-fallback-loader"),
+    (Kind::InitrdSecrets, "initrd-secrets"),
+    (Kind::CertNotEnrolled, "cert-not-enrolled"),
+    (Kind::UncheckedEnrollment, "unchecked-enrollment"),
+    (Kind::UnverifiedSignature, "unverified-signature"),
+    (Kind::ModifiedFile, "modified-file"),
+    (Kind::EspDrift, "esp-drift"),
+    (Kind::MissingChainload, "missing-chainload"),
+    (Kind::StalePin, "stale-pin"),
+    (Kind::OneshotNotPromoted, "oneshot-not-promoted"),
+    (Kind::UncheckedGrubConfig, "unchecked-grub-config"),
+    (
+        Kind::SpecialisationCaseCollision,
+        "specialisation-case-collision",
+    ),
+    (Kind::MissingMachineId, "missing-machine-id"),
+    (
+        Kind::UntrustedPreviousManifest,
+        "untrusted-previous-manifest",
+    ),
+    (Kind::UkifyFallback, "ukify-fallback"),
+    (Kind::InvalidUtf8, "invalid-utf8"),
+];
+
+impl Kind {
+    fn name(self) -> &'static str {
+        KINDS
+            .iter()
+            .find(|(kind, _)| *kind == self)
+            .map(|(_, name)| *name)
+            .expect("every kind has a name")
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KINDS
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(kind, _)| *kind)
+            .ok_or_else(|| {
+                let names = KINDS.iter().map(|(_, name)| *name).collect::<Vec<_>>();
+                format!(
+                    "unknown kind of warning '{}' (expected one of: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Whether `--strict` (`Some` of no kinds for all of them) makes warnings of `kind` fail the run.
+fn is_strict(strict: Option<&[Kind]>, kind: Kind) -> bool {
+    match strict {
+        Some(kinds) => kinds.is_empty() || kinds.contains(&kind),
+        None => false,
+    }
+}
+
+/// The warnings emitted so far, in order.
+#[derive(Debug, Default)]
+struct Registry {
+    warnings: Vec<(Kind, String)>,
+}
+
+impl Registry {
+    fn record(&mut self, kind: Kind, message: String) {
+        self.warnings.push((kind, message));
+    }
+
+    /// Fails if any of the warnings so far are of a kind that `strict` makes fail the run,
+    /// `checkpoint` being when (e.g. "before touching any ESP").
+    fn checkpoint(&self, checkpoint: &str, strict: Option<&[Kind]>) -> Result<()> {
+        let failing = self
+            .warnings
+            .iter()
+            .filter(|(kind, _)| self::is_strict(strict, *kind))
+            .map(|(kind, message)| format!("[{}] {}", kind, message))
+            .collect::<Vec<_>>();
+
+        if failing.is_empty() {
+            return Ok(());
+        }
+
+        Err(format!(
+            "failing the run {} (--strict) over {} warning(s): {}",
+            checkpoint,
+            failing.len(),
+            failing.join("; ")
+        )
+        .into())
+    }
+
+    /// The warnings so far, by kind.
+    fn report(&self, strict: Option<&[Kind]>) -> Vec<WarningReport> {
+        let mut by_kind = BTreeMap::new();
+        for (kind, message) in &self.warnings {
+            by_kind
+                .entry(*kind)
+                .or_insert_with(Vec::new)
+                .push(message.clone());
+        }
+
+        by_kind
+            .into_iter()
+            .map(|(kind, messages)| WarningReport {
+                kind: kind.to_string(),
+                count: messages.len(),
+                strict: self::is_strict(strict, kind),
+                messages,
+            })
+            .collect()
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Every warning the run emitted.
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Logs `message` as a warning, and records it as one of `kind`.
+pub fn emit(kind: Kind, message: impl fmt::Display) {
+    let message = message.to_string();
+    warn!("{} [{}]", message, kind);
+
+    self::registry().record(kind, message);
+}
+
+/// Fails if any of the warnings the run emitted so far are of a kind that `strict` (`--strict`)
+/// makes fail the run, `checkpoint` being when (e.g. "before touching any ESP").
+pub fn checkpoint(checkpoint: &str, strict: Option<&[Kind]>) -> Result<()> {
+    self::registry().checkpoint(checkpoint, strict)
+}
+
+/// Every warning the run emitted, by kind, for the run report.
+pub fn report(strict: Option<&[Kind]>) -> Vec<WarningReport> {
+    self::registry().report(strict)
+}
+
+/// The messages of the warnings of `kind` the run emitted so far (e.g. for tests to check that one
+/// was).
+pub fn emitted(kind: Kind) -> Vec<String> {
+    self::registry()
+        .warnings
+        .iter()
+        .filter(|(k, _)| *k == kind)
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds() {
+        for (kind, name) in KINDS {
+            assert_eq!(kind.to_string(), *name);
+            assert_eq!(name.parse::<Kind>().unwrap(), *kind);
+        }
+
+        let err = "nope".parse::<Kind>().unwrap_err();
+        assert!(err.contains("unknown kind of warning 'nope'"), "{}", err);
+        assert!(err.contains("entry-collision"), "{}", err);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut registry = Registry::default();
+        registry.checkpoint("now", Some(&[])).unwrap();
+
+        registry.record(Kind::EntryCollision, String::from("'a.conf' isn't ours"));
+        registry.record(Kind::DirtyEsp, String::from("'/boot' is dirty"));
+        registry.record(
+            Kind::UkifyFallback,
+            String::from("'ukify' is from systemd 252"),
+        );
+
+        // (--strict, whether the run fails)
+        for (strict, fails) in [
+            (None, false),
+            (Some(&[][..]), true),
+            (Some(&[Kind::EspDrift][..]), false),
+            (Some(&[Kind::EspDrift, Kind::DirtyEsp][..]), true),
+            (Some(&[Kind::UkifyFallback][..]), true),
+            (Some(&[Kind::InvalidUtf8][..]), false),
+        ] {
+            let ret = registry.checkpoint("before writing to '/boot'", strict);
+            assert_eq!(ret.is_err(), fails, "{:?}", strict);
+        }
+
+        let err = registry
+            .checkpoint("before writing to '/boot'", Some(&[Kind::DirtyEsp]))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "failing the run before writing to '/boot' (--strict) over 1 warning(s): [dirty-esp] \
+             '/boot' is dirty"
+        );
+        let err = registry
+            .checkpoint("at the end of the run", Some(&[]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("over 3 warning(s)"), "{}", err);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut registry = Registry::default();
+        assert!(registry.report(None).is_empty());
+
+        registry.record(Kind::EntryCollision, String::from("'a.conf' isn't ours"));
+        registry.record(Kind::DirtyEsp, String::from("'/boot' is dirty"));
+        registry.record(Kind::EntryCollision, String::from("'b.conf' isn't ours"));
+        registry.record(
+            Kind::InvalidUtf8,
+            String::from("'a.conf' isn't valid UTF-8"),
+        );
+
+        assert_eq!(
+            registry.report(Some(&[Kind::DirtyEsp, Kind::InvalidUtf8])),
+            [
+                WarningReport {
+                    kind: String::from("dirty-esp"),
+                    count: 1,
+                    strict: true,
+                    messages: vec![String::from("'/boot' is dirty")],
+                },
+                WarningReport {
+                    kind: String::from("entry-collision"),
+                    count: 2,
+                    strict: false,
+                    messages: vec![
+                        String::from("'a.conf' isn't ours"),
+                        String::from("'b.conf' isn't ours"),
+                    ],
+                },
+                WarningReport {
+                    kind: String::from("invalid-utf8"),
+                    count: 1,
+                    strict: true,
+                    messages: vec![String::from("'a.conf' isn't valid UTF-8")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_emit() {
+        emit(
+            Kind::StalePin,
+            "unpinning generation 999, which no longer exists",
+        );
+        assert!(emitted(Kind::StalePin)
+            .iter()
+            .any(|message| message == "unpinning generation 999, which no longer exists"));
+    }
+}
@@ -0,0 +1,143 @@
+//! Compares test output against the golden files in a crate's `fixtures/golden/` directory.
+//!
+//! Golden files are (re)written by `cargo xtask regen-fixtures`, which runs the tests with
+//! [`REGEN_ENV`] set, and checked by `cargo xtask verify-fixtures`, which also uses [`LOG_ENV`] to
+//! find golden files that no test reads anymore.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// When set, [`check`] writes the golden file instead of comparing against it.
+pub const REGEN_ENV: &str = "REGEN_FIXTURES";
+/// When set to a path, [`check`] appends the path of every golden file it reads to it.
+pub const LOG_ENV: &str = "FIXTURES_LOG";
+
+/// Asserts that `actual` matches the golden file `$CARGO_MANIFEST_DIR/fixtures/golden/<name>`.
+#[macro_export]
+macro_rules! assert_golden {
+    ($name:expr, $actual:expr) => {
+        $crate::check(
+            &::std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures/golden")
+                .join($name),
+            &$actual,
+        )
+    };
+}
+
+/// Asserts that `actual` matches the golden file at `path`, panicking with a diff if it doesn't.
+pub fn check(path: &Path, actual: &str) {
+    if let Some(log) = env::var_os(LOG_ENV) {
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .unwrap_or_else(|e| panic!("couldn't open the fixtures log: {}", e));
+        writeln!(log, "{}", path.display()).unwrap();
+    }
+
+    if env::var_os(REGEN_ENV).is_some() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, actual).unwrap_or_else(|e| panic!("'{}': {}", path.display(), e));
+
+        return;
+    }
+
+    let expected = match fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(e) => panic!(
+            "couldn't read golden file '{}': {}\n\
+             (if this is a new golden file, run `cargo xtask regen-fixtures`)",
+            path.display(),
+            e
+        ),
+    };
+
+    if expected != actual {
+        panic!(
+            "output doesn't match golden file '{}' (- expected, + actual):\n{}\n\
+             (if this change is intentional, run `cargo xtask regen-fixtures` and commit the result)",
+            path.display(),
+            self::diff(&expected, actual)
+        );
+    }
+}
+
+/// A line diff of `expected` and `actual`, with unchanged lines prefixed by a space, removed lines
+/// by `-`, and added lines by `+`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a = expected.lines().collect::<Vec<_>>();
+    let b = actual.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push_str(&format!(" {}\n", a[i]));
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] > lcs[i + 1][j]) {
+            out.push_str(&format!("+{}\n", b[j]));
+            j += 1;
+        } else {
+            out.push_str(&format!("-{}\n", a[i]));
+            i += 1;
+        }
+    }
+
+    if out.lines().all(|line| line.starts_with(' ')) {
+        out.push_str("(the lines are the same, so the line endings must differ)\n");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\nc\n", "a\nx\nc\n"), " a\n-b\n+x\n c\n");
+        assert_eq!(diff("a\nb\n", "a\nb\nc\n"), " a\n b\n+c\n");
+        assert_eq!(diff("a\nb\nc\n", "b\nc\n"), "-a\n b\n c\n");
+        assert_eq!(
+            diff("a\n", "a"),
+            " a\n(the lines are the same, so the line endings must differ)\n"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = env::temp_dir().join(format!("golden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("golden.txt");
+        fs::write(&path, "a\nb\n").unwrap();
+
+        check(&path, "a\nb\n");
+        let err = std::panic::catch_unwind(|| check(&path, "a\nc\n")).unwrap_err();
+        let err = err.downcast_ref::<String>().unwrap();
+        assert!(err.contains(" a\n-b\n+c\n"), "{}", err);
+        assert!(err.contains("cargo xtask regen-fixtures"));
+
+        assert!(std::panic::catch_unwind(|| check(&dir.join("missing.txt"), "")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use std::env;
 use std::error::Error;
 use std::process::Command;
 
@@ -6,19 +7,24 @@ type Result<T, E = Box<dyn Error + Send + Sync + 'static>> = core::result::Resul
 fn main() -> Result<()> {
     // This is to allow nix builds to substitute in the patched sbattach to avoid nix-inside-nix.
     let sbattach_str = String::from("@patched_sbattach@");
-    let sbattach_out = if sbattach_str.starts_with('@') && sbattach_str.ends_with('@') {
-        self::build_patched_sbattach()?
+    let sbattach = if !(sbattach_str.starts_with('@') && sbattach_str.ends_with('@')) {
+        Some(format!("{}/bin/sbattach", sbattach_str))
+    } else if let Ok(sbattach_path) = env::var("SBATTACH_PATH") {
+        Some(sbattach_path)
+    } else if env::var_os("CARGO_FEATURE_EXTERNAL_SBATTACH").is_some() {
+        Some(format!("{}/bin/sbattach", self::build_patched_sbattach()?))
     } else {
-        sbattach_str
+        // Nothing to embed: the patched sbattach has to be passed with `--sbattach` at runtime.
+        None
     };
 
-    println!(
-        "cargo:rustc-env=PATCHED_SBATTACH_BINARY={}/bin/sbattach",
-        sbattach_out
-    );
+    if let Some(sbattach) = sbattach {
+        println!("cargo:rustc-env=PATCHED_SBATTACH_BINARY={}", sbattach);
+    }
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=patched-sbattach.nix");
     println!("cargo:rerun-if-env-changed=PATH");
+    println!("cargo:rerun-if-env-changed=SBATTACH_PATH");
 
     Ok(())
 }
@@ -28,6 +34,15 @@ fn build_patched_sbattach() -> Result<String> {
         .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/patched-sbattach.nix"))
         .arg("--no-out-link")
         .output()?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "failed to build the patched sbattach with nix-build: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
     let stdout = std::str::from_utf8(&output.stdout)?.trim();
 
     Ok(stdout.to_owned())
@@ -0,0 +1,239 @@
+//! Parsers for command line values with units: durations (`30s`, `5m`, `2h`, `1d`) and sizes
+//! (`512MiB`, `1.5GB`).
+//!
+//! Units are spelled out exactly, so that nothing is left to guess: `5M` could be minutes, months,
+//! megabytes, or mebibytes, and is rejected.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DURATION_UNITS: &[(&str, u64)] = &[("s", 1), ("m", 60), ("h", 60 * 60), ("d", 24 * 60 * 60)];
+
+/// Splits `s` into its number and its unit, e.g. `1.5GB` into `1.5` and `GB`.
+fn split_unit(s: &str) -> (&str, &str) {
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+
+    s.split_at(unit_start)
+}
+
+/// Parses a duration: whole seconds (`30`, for compatibility with flags that took bare seconds), or
+/// a whole number of seconds, minutes, hours, or days (`30s`, `5m`, `2h`, `1d`).
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = |why: &str| {
+        format!(
+            "invalid duration '{}': {} (expected e.g. '90', '30s', '5m', '2h', or '1d')",
+            s, why
+        )
+    };
+
+    let (number, unit) = self::split_unit(s);
+    if number.is_empty() {
+        return Err(invalid("it doesn't start with a (non-negative) number"));
+    }
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| invalid("it isn't a whole number"))?;
+
+    let multiplier = match unit {
+        "" => 1,
+        unit => DURATION_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(|| invalid(&format!("unknown unit '{}'", unit)))?,
+    };
+
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| invalid("it's too long"))
+}
+
+/// A size in bytes: a whole number of bytes (`4096`), or a number of decimal (`KB`, `MB`, `GB`,
+/// `TB`) or binary (`KiB`, `MiB`, `GiB`, `TiB`) units that comes to a whole number of bytes
+/// (`512MiB`, `1.5GB`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const SIZE_UNITS: &[(&str, u64)] = &[
+            ("B", 1),
+            ("KB", 1000),
+            ("MB", 1000 * 1000),
+            ("GB", 1000 * 1000 * 1000),
+            ("TB", 1000 * 1000 * 1000 * 1000),
+            ("KiB", 1 << 10),
+            ("MiB", 1 << 20),
+            ("GiB", 1 << 30),
+            ("TiB", 1 << 40),
+        ];
+
+        let invalid = |why: &str| {
+            format!(
+                "invalid size '{}': {} (expected e.g. '4096', '512MiB', or '1.5GB')",
+                s, why
+            )
+        };
+
+        let (number, unit) = self::split_unit(s);
+        let (whole, fraction) = match number.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (number, ""),
+        };
+        if whole.is_empty() || (number.contains('.') && fraction.is_empty()) {
+            return Err(invalid("it doesn't start with a (non-negative) number"));
+        }
+        if fraction.contains('.') {
+            return Err(invalid("it isn't a number"));
+        }
+
+        let multiplier = match unit {
+            "" => 1,
+            unit => match SIZE_UNITS.iter().find(|(name, _)| *name == unit) {
+                Some((_, multiplier)) => *multiplier,
+                None if SIZE_UNITS.iter().any(|(name, _)| {
+                    name.to_ascii_lowercase()
+                        .starts_with(&unit.to_ascii_lowercase())
+                }) =>
+                {
+                    return Err(invalid(&format!(
+                        "ambiguous unit '{}' (use e.g. 'MiB' for 1024 * 1024 bytes, or 'MB' for \
+                         1000 * 1000)",
+                        unit
+                    )));
+                }
+                None => return Err(invalid(&format!("unknown unit '{}'", unit))),
+            },
+        };
+
+        let too_large = || invalid("it's too large");
+        let whole = whole
+            .parse::<u64>()
+            .map_err(|_| too_large())?
+            .checked_mul(multiplier)
+            .ok_or_else(too_large)?;
+
+        // The fraction is scaled exactly, so e.g. `0.1KiB` (102.4 bytes) is rejected rather than
+        // rounded.
+        let mut fraction_bytes: u128 = 0;
+        let mut scale: u128 = 1;
+        for digit in fraction.bytes() {
+            fraction_bytes = fraction_bytes * 10 + u128::from(digit - b'0');
+            scale *= 10;
+            if scale > u128::from(u64::MAX) {
+                return Err(invalid("it has too many decimal places"));
+            }
+        }
+        let fraction_bytes = fraction_bytes * u128::from(multiplier);
+        if fraction_bytes % scale != 0 {
+            return Err(invalid("it isn't a whole number of bytes"));
+        }
+
+        u64::try_from(fraction_bytes / scale)
+            .ok()
+            .and_then(|fraction_bytes| whole.checked_add(fraction_bytes))
+            .map(ByteSize)
+            .ok_or_else(too_large)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}B", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        for (s, seconds) in [
+            ("0", 0),
+            ("90", 90),
+            ("30s", 30),
+            ("5m", 5 * 60),
+            ("2h", 2 * 60 * 60),
+            ("1d", 24 * 60 * 60),
+            ("0d", 0),
+        ] {
+            assert_eq!(parse_duration(s), Ok(Duration::from_secs(seconds)), "{}", s);
+        }
+
+        for s in [
+            "",
+            "s",
+            "-5",
+            "-5m",
+            "+5m",
+            "1.5h",
+            "5 m",
+            " 5m",
+            "5min",
+            "5ms",
+            // minutes? months?
+            "5M",
+            "1w",
+            "18446744073709551615d",
+            "18446744073709551616",
+        ] {
+            assert!(parse_duration(s).is_err(), "{:?}", s);
+        }
+
+        assert!(parse_duration("5M").unwrap_err().contains("'5m'"));
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        for (s, bytes) in [
+            ("0", 0),
+            ("4096", 4096),
+            ("10B", 10),
+            ("512MiB", 512 * 1024 * 1024),
+            ("1KiB", 1024),
+            ("2TiB", 2 << 40),
+            ("1.5GB", 1_500_000_000),
+            ("1.5KiB", 1536),
+            ("0.5MB", 500_000),
+            ("1.000B", 1),
+            ("18446744073709551615", u64::MAX),
+        ] {
+            assert_eq!(s.parse(), Ok(ByteSize(bytes)), "{}", s);
+        }
+
+        for s in [
+            "",
+            "MiB",
+            "-1MiB",
+            "-1",
+            ".5GB",
+            "1.GB",
+            "1.2.3GB",
+            "1 MiB",
+            "0.1KiB",
+            "1.5B",
+            "20000000TiB",
+            "18446744073709551616",
+            "1.000000000000000000000001GB",
+            "5XB",
+        ] {
+            assert!(s.parse::<ByteSize>().is_err(), "{:?}", s);
+        }
+
+        // MiB or MB?
+        for s in ["512M", "512m", "512mib", "1G", "1K", "1kb"] {
+            let err = s.parse::<ByteSize>().unwrap_err();
+            assert!(err.contains("ambiguous"), "{}: {}", s, err);
+        }
+
+        assert_eq!(ByteSize(1024).to_string(), "1024B");
+    }
+}
@@ -0,0 +1,412 @@
+//! Checks that the `--signing-cert` will be accepted by the firmware (see `--verify-cert-enrolled`),
+//! by looking for it (or its issuer) in the `db` EFI variable: files signed with a key whose
+//! certificate isn't enrolled are signed, but won't boot.
+//!
+//! `db` is a list of `EFI_SIGNATURE_LIST`s (UEFI spec, section 32.4.1), each of which holds
+//! signatures of one type. Only X.509 certificates are of interest here, and they're compared by
+//! their DER (and names) rather than parsed in full.
+
+use std::fs;
+use std::path::Path;
+
+use log::debug;
+use sha2::{Digest, Sha256};
+
+use crate::context::Context;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// Where efivarfs exposes `db` (under `EFI_IMAGE_SECURITY_DATABASE_GUID`).
+pub(crate) const DB_EFIVAR: &str =
+    "/sys/firmware/efi/efivars/db-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+/// `EFI_CERT_X509_GUID` (a5c059a1-94e4-4aa7-87b5-ab155c2bf072), as it's laid out in memory.
+const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+/// `SignatureType`, `SignatureListSize`, `SignatureHeaderSize`, and `SignatureSize`
+const SIGNATURE_LIST_HEADER_LEN: usize = 28;
+/// Every signature starts with the GUID of its owner.
+const SIGNATURE_OWNER_LEN: usize = 16;
+/// efivarfs prefixes a variable's contents with its attributes.
+const EFIVAR_ATTRIBUTES_LEN: usize = 4;
+
+/// One `EFI_SIGNATURE_LIST`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SignatureList {
+    pub signature_type: [u8; 16],
+    /// The signatures' data, without their owners
+    pub signatures: Vec<Vec<u8>>,
+}
+
+/// Whether a certificate is enrolled in `db`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Enrollment {
+    /// The certificate itself is enrolled
+    Cert,
+    /// The certificate's issuer is enrolled
+    Issuer,
+    NotEnrolled,
+}
+
+/// Parses the (concatenated) signature lists in `data`.
+pub(crate) fn parse_signature_lists(mut data: &[u8]) -> Result<Vec<SignatureList>> {
+    let u32_at = |data: &[u8], offset: usize| {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize
+    };
+    let mut lists = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < SIGNATURE_LIST_HEADER_LEN {
+            return Err("signature list is truncated".into());
+        }
+
+        let list_size = u32_at(data, 16);
+        let header_size = u32_at(data, 20);
+        let signature_size = u32_at(data, 24);
+        let start = SIGNATURE_LIST_HEADER_LEN
+            .checked_add(header_size)
+            .filter(|start| *start <= list_size && list_size <= data.len())
+            .ok_or_else(|| format!("signature list has an invalid size ({})", list_size))?;
+        if signature_size <= SIGNATURE_OWNER_LEN || (list_size - start) % signature_size != 0 {
+            return Err(format!(
+                "signature list has an invalid signature size ({})",
+                signature_size
+            )
+            .into());
+        }
+
+        let mut signature_type = [0; 16];
+        signature_type.copy_from_slice(&data[..16]);
+        lists.push(SignatureList {
+            signature_type,
+            signatures: data[start..list_size]
+                .chunks(signature_size)
+                .map(|signature| signature[SIGNATURE_OWNER_LEN..].to_vec())
+                .collect(),
+        });
+
+        data = &data[list_size..];
+    }
+
+    Ok(lists)
+}
+
+/// Reads the signature lists of the efivarfs variable at `path`.
+pub(crate) fn read_efivar(path: &Path) -> Result<Vec<SignatureList>> {
+    let data = fs::read(path).with_path_context(path)?;
+    let lists = data
+        .get(EFIVAR_ATTRIBUTES_LEN..)
+        .ok_or("the variable is truncated")
+        .map_err(Into::into)
+        .and_then(self::parse_signature_lists)
+        .with_path_context(path)?;
+
+    Ok(lists)
+}
+
+/// Reads the certificate at `path` as DER, whether it's PEM (like sbsign takes) or DER.
+pub(crate) fn read_cert(path: &Path) -> Result<Vec<u8>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let data = fs::read(path).with_path_context(path)?;
+    let pem = match std::str::from_utf8(&data) {
+        Ok(text) if text.contains(BEGIN) => text,
+        _ => return Ok(data),
+    };
+
+    let base64 = pem
+        .split(BEGIN)
+        .nth(1)
+        .and_then(|rest| rest.split(END).next())
+        .filter(|_| pem.contains(END))
+        .ok_or_else(|| format!("'{}' has an unterminated certificate", path.display()))?;
+
+    self::decode_base64(base64).with_path_context(path)
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buf = 0u32;
+    let mut bits = 0;
+
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return Err(format!("invalid base64 character '{}'", c as char).into()),
+        };
+
+        buf = buf << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+/// A DER element, and what follows it.
+struct DerElement<'a> {
+    tag: u8,
+    /// The whole element, including its tag and length
+    element: &'a [u8],
+    contents: &'a [u8],
+    rest: &'a [u8],
+}
+
+/// Splits the DER element at the start of `data` off of it.
+fn der_element(data: &[u8]) -> Result<DerElement> {
+    let truncated = || "DER element is truncated";
+    let tag = *data.first().ok_or_else(truncated)?;
+    let first = *data.get(1).ok_or_else(truncated)? as usize;
+
+    let (header_len, len) = if first & 0x80 == 0 {
+        (2, first)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return Err("DER element has an unsupported length".into());
+        }
+        let bytes = data.get(2..2 + n).ok_or_else(truncated)?;
+
+        (2 + n, bytes.iter().fold(0, |len, b| len << 8 | *b as usize))
+    };
+
+    let end = header_len + len;
+    if data.len() < end {
+        return Err(truncated().into());
+    }
+
+    Ok(DerElement {
+        tag,
+        element: &data[..end],
+        contents: &data[header_len..end],
+        rest: &data[end..],
+    })
+}
+
+/// The issuer and subject names (as DER) of the X.509 certificate `cert`.
+fn issuer_and_subject(cert: &[u8]) -> Result<(&[u8], &[u8])> {
+    let certificate = self::der_element(cert)?.contents;
+    let tbs_certificate = self::der_element(certificate)?.contents;
+
+    let version = self::der_element(tbs_certificate)?;
+    // The version is optional (and explicitly tagged).
+    let rest = if version.tag == 0xa0 {
+        version.rest
+    } else {
+        tbs_certificate
+    };
+    let serial_number = self::der_element(rest)?;
+    let signature = self::der_element(serial_number.rest)?;
+    let issuer = self::der_element(signature.rest)?;
+    let validity = self::der_element(issuer.rest)?;
+    let subject = self::der_element(validity.rest)?;
+
+    Ok((issuer.element, subject.element))
+}
+
+/// Whether the DER certificate `cert` (or its issuer) is among the X.509 certificates in `db`.
+pub(crate) fn enrollment(cert: &[u8], db: &[SignatureList]) -> Result<Enrollment> {
+    let enrolled = db
+        .iter()
+        .filter(|list| list.signature_type == EFI_CERT_X509_GUID)
+        .flat_map(|list| &list.signatures)
+        .collect::<Vec<_>>();
+
+    let digest = Sha256::digest(cert);
+    if enrolled.iter().any(|e| Sha256::digest(e) == digest) {
+        return Ok(Enrollment::Cert);
+    }
+
+    let (issuer, _) = self::issuer_and_subject(cert)?;
+    let issuer_enrolled = enrolled.iter().any(|e| match self::issuer_and_subject(e) {
+        Ok((_, subject)) => subject == issuer,
+        // e.g. a vendor's malformed certificate, which can't be our issuer anyway
+        Err(_) => false,
+    });
+
+    if issuer_enrolled {
+        Ok(Enrollment::Issuer)
+    } else {
+        Ok(Enrollment::NotEnrolled)
+    }
+}
+
+/// Checks that the certificate at `cert` (or its issuer) is enrolled in the `db` at `db_efivar`,
+/// warning if it isn't (which `--strict` fails the run over).
+///
+/// If `db` can't be read (e.g. efivarfs isn't mounted), there is nothing to check against.
+pub(crate) fn check_cert_enrolled(cert: &Path, db_efivar: &Path) -> Result<()> {
+    if !db_efivar.exists() {
+        warnings::emit(
+            Kind::UncheckedEnrollment,
+            format!(
+                "not checking that '{}' is enrolled: '{}' doesn't exist (are efivars readable?)",
+                cert.display(),
+                db_efivar.display()
+            ),
+        );
+        return Ok(());
+    }
+
+    let db = self::read_efivar(db_efivar)?;
+    let der = self::read_cert(cert)?;
+
+    match self::enrollment(&der, &db).with_path_context(cert)? {
+        Enrollment::Cert => debug!("'{}' is enrolled in db", cert.display()),
+        Enrollment::Issuer => debug!("the issuer of '{}' is enrolled in db", cert.display()),
+        Enrollment::NotEnrolled => {
+            warnings::emit(
+                Kind::CertNotEnrolled,
+                format!(
+                "neither '{}' (SHA-256 {:x}) nor its issuer is enrolled in the firmware's db, so \
+                 the files signed with it won't boot with Secure Boot enabled",
+                cert.display(),
+                Sha256::digest(&der)
+            ),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A `db` with an unrelated vendor's certificate, two SHA-256 hashes, and a CA certificate;
+    /// `signing.pem` is issued by that CA.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/efi-db")
+            .join(name)
+    }
+
+    const EFI_CERT_SHA256_GUID: [u8; 16] = [
+        0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43,
+        0x28,
+    ];
+
+    #[test]
+    fn test_parse_signature_lists() {
+        let db = read_efivar(&fixture("db")).unwrap();
+        assert_eq!(
+            db.iter()
+                .map(|list| (list.signature_type, list.signatures.len()))
+                .collect::<Vec<_>>(),
+            vec![
+                (EFI_CERT_X509_GUID, 1),
+                (EFI_CERT_SHA256_GUID, 2),
+                (EFI_CERT_X509_GUID, 1),
+            ]
+        );
+        assert_eq!(db[1].signatures[0], Sha256::digest(b"a").to_vec());
+        assert_eq!(db[2].signatures[0], read_cert(&fixture("ca.pem")).unwrap());
+
+        let data = fs::read(fixture("db")).unwrap();
+        let lists = &data[EFIVAR_ATTRIBUTES_LEN..];
+        assert_eq!(parse_signature_lists(&[]).unwrap(), vec![]);
+        assert!(parse_signature_lists(&lists[..20]).is_err());
+        assert!(parse_signature_lists(&lists[..lists.len() - 1]).is_err());
+
+        // a signature size that doesn't divide the list
+        let mut corrupt = lists.to_vec();
+        corrupt[24] = corrupt[24].wrapping_add(1);
+        assert!(parse_signature_lists(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_read_cert() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let pem = read_cert(&fixture("signing.pem")).unwrap();
+        assert_eq!(pem[0], 0x30);
+
+        let der = tempdir.path().join("signing.der");
+        fs::write(&der, &pem).unwrap();
+        assert_eq!(read_cert(&der).unwrap(), pem);
+
+        let unterminated = tempdir.path().join("unterminated.pem");
+        fs::write(&unterminated, "-----BEGIN CERTIFICATE-----\nMIIB\n").unwrap();
+        assert!(read_cert(&unterminated).is_err());
+
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\nbG8h").unwrap(), b"hello!");
+        assert!(decode_base64("aGV*").is_err());
+    }
+
+    #[test]
+    fn test_enrollment() {
+        let db = read_efivar(&fixture("db")).unwrap();
+        let cert = |name: &str| read_cert(&fixture(name)).unwrap();
+
+        assert_eq!(enrollment(&cert("ca.pem"), &db).unwrap(), Enrollment::Cert);
+        assert_eq!(
+            enrollment(&cert("other.pem"), &db).unwrap(),
+            Enrollment::Cert
+        );
+        assert_eq!(
+            enrollment(&cert("signing.pem"), &db).unwrap(),
+            Enrollment::Issuer
+        );
+
+        // without the CA
+        let without_ca = &db[..2];
+        assert_eq!(
+            enrollment(&cert("signing.pem"), without_ca).unwrap(),
+            Enrollment::NotEnrolled
+        );
+        assert_eq!(
+            enrollment(&cert("ca.pem"), without_ca).unwrap(),
+            Enrollment::NotEnrolled
+        );
+
+        assert!(enrollment(b"not a certificate", &db).is_err());
+    }
+
+    #[test]
+    fn test_check_cert_enrolled() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let db = fixture("db");
+        let signing = fixture("signing.pem");
+        let other_db = tempdir.path().join("db");
+        // only the unrelated vendor's certificate
+        let data = fs::read(&db).unwrap();
+        let list_size = u32::from_le_bytes([data[20], data[21], data[22], data[23]]) as usize;
+        fs::write(&other_db, &data[..EFIVAR_ATTRIBUTES_LEN + list_size]).unwrap();
+
+        let not_enrolled = || {
+            warnings::emitted(Kind::CertNotEnrolled)
+                .iter()
+                .filter(|message| message.contains(&signing.display().to_string()))
+                .count()
+        };
+
+        check_cert_enrolled(&signing, &db).unwrap();
+        assert_eq!(not_enrolled(), 0);
+        check_cert_enrolled(&signing, &other_db).unwrap();
+        assert_eq!(not_enrolled(), 1);
+
+        // efivars aren't readable
+        let missing = tempdir.path().join("missing");
+        check_cert_enrolled(&signing, &missing).unwrap();
+        assert!(warnings::emitted(Kind::UncheckedEnrollment)
+            .iter()
+            .any(|message| message.contains(&missing.display().to_string())));
+    }
+}
@@ -0,0 +1,31 @@
+//! The extlinux subcommand, reserved for installing extlinux.conf entries once the generator
+//! writes them. Until then, it's hidden from `--help`, takes the arguments it will need, and fails
+//! without touching anything.
+
+use std::path::PathBuf;
+
+use crate::util;
+use crate::{Result, RunArgs};
+
+/// The arguments of `installer extlinux`.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// The directory that the generator created
+    #[clap(long, parse(try_from_str = util::normalize_path))]
+    pub(crate) generated_entries: PathBuf,
+    /// The directory extlinux reads its configuration from, e.g. `/boot/extlinux`
+    #[clap(long, default_value = "/boot/extlinux", parse(try_from_str = util::normalize_path))]
+    pub(crate) extlinux_dir: PathBuf,
+    #[clap(flatten)]
+    pub(crate) run: RunArgs,
+}
+
+/// Fails: extlinux isn't supported until the generator writes extlinux entries.
+pub(crate) fn run(args: &Args) -> Result<()> {
+    Err(format!(
+        "extlinux isn't supported yet: the generator doesn't write extlinux entries, so nothing \
+         was written to '{}'",
+        args.extlinux_dir.display()
+    )
+    .into())
+}
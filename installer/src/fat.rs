@@ -0,0 +1,369 @@
+//! Checks whether an ESP's FAT filesystem was cleanly unmounted, since writing to one that wasn't
+//! can silently corrupt it further.
+//!
+//! A FAT volume records this in two places: the "dirty" bit of its boot sector's state byte, and
+//! the "clean shutdown" bit of the second FAT entry (FAT16 and FAT32 only). Linux sets the former
+//! for as long as the volume is mounted read-write (and leaves it set on unmount if it was already
+//! set at mount), so it only tells anything if the ESP is mounted read-only; the latter is
+//! maintained by other OSes (e.g. Windows, on a dual-boot machine), and left alone by Linux.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::context::Context;
+use crate::Result;
+
+pub(crate) use generator::report::FsState;
+
+pub(crate) const MOUNTINFO: &str = "/proc/self/mountinfo";
+const FAT_STATE_DIRTY: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// The parts of a FAT boot sector needed to check the volume's state.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BootSector {
+    pub fat_type: FatType,
+    /// Where the first FAT starts, in bytes
+    pub fat_offset: u64,
+    /// Whether the state byte's dirty bit is set
+    pub dirty: bool,
+}
+
+/// A line of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MountInfo {
+    pub mount_point: PathBuf,
+    /// The device's `major:minor`
+    pub device: String,
+    pub fs_type: String,
+    pub read_only: bool,
+}
+
+impl BootSector {
+    pub(crate) fn parse(sector: &[u8]) -> Result<Self> {
+        if sector.len() < 512 || sector[510..512] != [0x55, 0xaa] {
+            return Err("not a FAT boot sector (no boot signature)".into());
+        }
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]) as u64;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ]) as u64
+        };
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = u16_at(14);
+        let fats = sector[16] as u64;
+        let root_entries = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            n => n,
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36),
+            n => n,
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err("not a FAT boot sector (invalid BIOS parameter block)".into());
+        }
+
+        // As in the FAT specification, the type is determined by the number of clusters alone.
+        let root_dir_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sectors = total_sectors
+            .checked_sub(reserved_sectors + fats * fat_sectors + root_dir_sectors)
+            .ok_or("not a FAT boot sector (its data region is empty)")?;
+        let fat_type = match data_sectors / sectors_per_cluster {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let state = match fat_type {
+            FatType::Fat12 | FatType::Fat16 => sector[37],
+            FatType::Fat32 => sector[65],
+        };
+
+        Ok(Self {
+            fat_type,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            dirty: state & FAT_STATE_DIRTY != 0,
+        })
+    }
+
+    /// Whether the second FAT entry, which starts `fat` (read from [`BootSector::fat_offset`]),
+    /// says the volume was cleanly unmounted. FAT12 doesn't have this bit.
+    pub(crate) fn clean_shutdown(&self, fat: &[u8]) -> Option<bool> {
+        match self.fat_type {
+            FatType::Fat12 => None,
+            FatType::Fat16 => {
+                let entry = u16::from_le_bytes([*fat.get(2)?, *fat.get(3)?]);
+                Some(entry & 0x8000 != 0)
+            }
+            FatType::Fat32 => {
+                let entry =
+                    u32::from_le_bytes([*fat.get(4)?, *fat.get(5)?, *fat.get(6)?, *fat.get(7)?]);
+                Some(entry & 0x0800_0000 != 0)
+            }
+        }
+    }
+
+    /// Whether the volume wasn't cleanly unmounted, judging by what tells (see the module docs).
+    pub(crate) fn state(&self, fat: &[u8], read_only: bool) -> FsState {
+        if self.clean_shutdown(fat) == Some(false) || (read_only && self.dirty) {
+            FsState::Dirty
+        } else {
+            FsState::Clean
+        }
+    }
+}
+
+/// Parses `/proc/self/mountinfo` (see proc(5)).
+pub(crate) fn parse_mountinfo(contents: &str) -> Result<Vec<MountInfo>> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (mount, sb) = line
+                .split_once(" - ")
+                .ok_or_else(|| format!("malformed mountinfo line {:?}", line))?;
+            let mount = mount.split(' ').collect::<Vec<_>>();
+            let sb = sb.split(' ').collect::<Vec<_>>();
+            if mount.len() < 6 || sb.len() < 3 {
+                return Err(format!("malformed mountinfo line {:?}", line).into());
+            }
+
+            let read_only = |options: &str| options.split(',').any(|o| o == "ro");
+
+            Ok(MountInfo {
+                mount_point: PathBuf::from(self::unescape(mount[4])),
+                device: mount[2].to_string(),
+                fs_type: sb[0].to_string(),
+                read_only: read_only(mount[5]) || read_only(sb[2]),
+            })
+        })
+        .collect()
+}
+
+/// Finds the mount that `path` is on: the one with the longest mount point containing it (the
+/// last one, if several are stacked on the same mount point).
+pub(crate) fn find_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .fold(None, |found: Option<&MountInfo>, mount| match found {
+            Some(found)
+                if found.mount_point.as_os_str().len() > mount.mount_point.as_os_str().len() =>
+            {
+                Some(found)
+            }
+            _ => Some(mount),
+        })
+}
+
+/// Checks the filesystem of the ESP at `esp`, finding its device in `/proc/self/mountinfo`.
+pub(crate) fn check_esp(esp: &Path) -> Result<FsState> {
+    let contents = std::fs::read_to_string(MOUNTINFO).with_path_context(MOUNTINFO)?;
+    let mounts = self::parse_mountinfo(&contents).with_path_context(MOUNTINFO)?;
+    let mount = self::find_mount(&mounts, esp)
+        .ok_or_else(|| format!("couldn't find the mount '{}' is on", esp.display()))?;
+
+    debug!("ESP '{}' is on {:?}", esp.display(), mount);
+    if !matches!(mount.fs_type.as_str(), "vfat" | "msdos") {
+        return Ok(FsState::NotFat);
+    }
+
+    let device = Path::new("/dev/block").join(&mount.device);
+    self::check_device(&device, mount.read_only)
+}
+
+/// Checks the FAT volume on `device` (or in an image).
+pub(crate) fn check_device(device: &Path, read_only: bool) -> Result<FsState> {
+    let mut f = File::open(device).with_path_context(device)?;
+    let mut sector = [0; 512];
+    f.read_exact(&mut sector).with_path_context(device)?;
+    let boot_sector = BootSector::parse(&sector).with_path_context(device)?;
+
+    let mut fat = [0; 8];
+    f.seek(SeekFrom::Start(boot_sector.fat_offset))
+        .with_path_context(device)?;
+    f.read_exact(&mut fat).with_path_context(device)?;
+
+    Ok(boot_sector.state(&fat, read_only))
+}
+
+/// Undoes mountinfo's octal escapes (e.g. `\040` for a space).
+fn unescape(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|octal| u8::from_str_radix(std::str::from_utf8(octal).ok()?, 8).ok());
+
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A FAT image with `clusters` clusters of one 512-byte sector, and the given state byte and
+    /// second FAT entry.
+    fn image(clusters: u64, state: u8, fat1: u32) -> Vec<u8> {
+        let fat32 = clusters >= 65525;
+        let fat_sectors = (clusters + 2) * if fat32 { 4 } else { 2 } / 512 + 1;
+        let root_entries = if fat32 { 0 } else { 512 };
+        let reserved = if fat32 { 32 } else { 1 };
+        let total = reserved + 2 * fat_sectors + root_entries * 32 / 512 + clusters;
+
+        let mut out = vec![0; 512 * reserved as usize + 8];
+        out[11..13].copy_from_slice(&512u16.to_le_bytes());
+        out[13] = 1;
+        out[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        out[16] = 2;
+        out[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+        out[32..36].copy_from_slice(&(total as u32).to_le_bytes());
+        if fat32 {
+            out[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+            out[65] = state;
+        } else {
+            out[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+            out[37] = state;
+        }
+        out[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let fat = 512 * reserved as usize;
+        if fat32 {
+            out[fat + 4..fat + 8].copy_from_slice(&fat1.to_le_bytes());
+        } else {
+            out[fat + 2..fat + 4].copy_from_slice(&(fat1 as u16).to_le_bytes());
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_parse_boot_sector() {
+        for (clusters, fat_type) in [
+            (4084, FatType::Fat12),
+            (4085, FatType::Fat16),
+            (65524, FatType::Fat16),
+            (65525, FatType::Fat32),
+        ] {
+            let boot_sector = BootSector::parse(&image(clusters, 0, 0xffff_ffff)).unwrap();
+            assert_eq!(boot_sector.fat_type, fat_type, "{} clusters", clusters);
+            assert!(!boot_sector.dirty);
+        }
+
+        let boot_sector = BootSector::parse(&image(65525, FAT_STATE_DIRTY, 0)).unwrap();
+        assert!(boot_sector.dirty);
+        assert_eq!(boot_sector.fat_offset, 32 * 512);
+
+        let mut no_signature = image(4085, 0, 0);
+        no_signature[510] = 0;
+        assert!(BootSector::parse(&no_signature).is_err());
+        assert!(BootSector::parse(&[0; 512][..100]).is_err());
+        let mut no_bpb = vec![0; 512];
+        no_bpb[510..512].copy_from_slice(&[0x55, 0xaa]);
+        assert!(BootSector::parse(&no_bpb).is_err());
+    }
+
+    #[test]
+    fn test_fs_state() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("esp.img");
+        let check = |clusters, state, fat1, read_only| {
+            fs::write(&path, image(clusters, state, fat1)).unwrap();
+            check_device(&path, read_only).unwrap()
+        };
+
+        for clusters in [4085, 65525] {
+            assert_eq!(check(clusters, 0, 0xffff_ffff, false), FsState::Clean);
+            // set by Linux for as long as the ESP is mounted read-write
+            assert_eq!(
+                check(clusters, FAT_STATE_DIRTY, 0xffff_ffff, false),
+                FsState::Clean
+            );
+            assert_eq!(
+                check(clusters, FAT_STATE_DIRTY, 0xffff_ffff, true),
+                FsState::Dirty
+            );
+            // the clean shutdown bit is cleared
+            assert_eq!(check(clusters, 0, 0xf7ff_7fff, false), FsState::Dirty);
+        }
+        // FAT12 has no clean shutdown bit
+        assert_eq!(check(4084, 0, 0, false), FsState::Clean);
+
+        assert!(check_device(&tempdir.path().join("missing.img"), false).is_err());
+    }
+
+    #[test]
+    fn test_mountinfo() {
+        let mountinfo = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+25 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+39 22 0:44 / /boot rw,relatime shared:27 - autofs systemd-1 rw,fd=47
+40 39 259:1 / /boot rw,relatime shared:28 - vfat /dev/nvme0n1p1 rw,fmask=0022,dmask=0022,codepage=437,iocharset=iso8859-1,shortname=mixed,errors=remount-ro
+41 22 8:17 / /mnt/my\\040esp ro,relatime - vfat /dev/sdb1 rw
+";
+        let mounts = parse_mountinfo(mountinfo).unwrap();
+        assert_eq!(mounts.len(), 5);
+        assert_eq!(
+            mounts[3],
+            MountInfo {
+                mount_point: PathBuf::from("/boot"),
+                device: String::from("259:1"),
+                fs_type: String::from("vfat"),
+                read_only: false,
+            }
+        );
+        assert!(mounts[4].read_only);
+
+        let find = |path: &str| find_mount(&mounts, Path::new(path)).map(|m| m.device.as_str());
+        // the automounted ESP, rather than the automount point
+        assert_eq!(find("/boot"), Some("259:1"));
+        assert_eq!(find("/boot/EFI"), Some("259:1"));
+        assert_eq!(find("/mnt/my esp"), Some("8:17"));
+        assert_eq!(find("/mnt/my esp/EFI"), Some("8:17"));
+        assert_eq!(find("/mnt/my"), Some("259:2"));
+
+        assert!(parse_mountinfo("22 1 259:2 / / rw\n").is_err());
+    }
+}
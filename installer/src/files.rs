@@ -5,6 +5,10 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use generator::esp_path::EspRelativePath;
+
+use crate::secure_boot::KeyPair;
+use crate::systemd_boot::Layout;
 use crate::Result;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -13,53 +17,52 @@ pub struct FileToReplace {
     pub esp_loc: PathBuf,
 }
 
+/// A file to sign, and the key pair (see `--signing-rule`) to sign it with.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileToSign {
+    pub file: PathBuf,
+    pub pair: KeyPair,
+}
+
 #[derive(Debug, Clone)]
 pub struct IdentifiedFiles {
     pub to_sign: Vec<PathBuf>,
     pub to_replace: Vec<FileToReplace>,
+    /// Where the files that are new go on the ESP (or XBOOTLDR partition)
+    pub to_add: Vec<PathBuf>,
 }
 
 impl IdentifiedFiles {
-    pub fn new(generated_entries: &Path, esp: &Path) -> Result<Self> {
+    /// Sorts the files in `generated_entries` into the ones that replace a file on the ESP (or
+    /// XBOOTLDR partition, see [`Layout`]) and the ones that are new, and picks out the EFI files
+    /// among them to sign.
+    pub fn new(generated_entries: &Path, layout: Layout) -> Result<Self> {
         let mut to_add = Vec::new();
+        let mut new_locs = Vec::new();
         let mut to_replace = Vec::new();
 
         let generated_files = glob::glob(&format!("{}/**/*", generated_entries.display()))?
             .filter_map(|e| e.ok())
             .filter(|e| !e.is_dir())
             .collect::<Vec<_>>();
-        let esp_files = glob::glob(&format!("{}/**/*", esp.display()))?
-            .filter_map(|e| e.ok())
-            .filter(|e| !e.is_dir())
-            .collect::<Vec<_>>();
-
-        let strip_unnecessary_prefix = |path: &Path| -> String {
-            path.display()
-                .to_string()
-                .replace(&generated_entries.display().to_string(), "")
-                .replace(&esp.display().to_string(), "")
-        };
-
-        for file in generated_files.iter().filter(|e1| {
-            !esp_files
-                .iter()
-                .any(|e2| strip_unnecessary_prefix(e1) == strip_unnecessary_prefix(e2))
-        }) {
-            to_add.push(file.to_owned());
-        }
 
         for generated_loc in generated_files {
-            let stripped = strip_unnecessary_prefix(&generated_loc);
+            let relative = generated_loc.strip_prefix(generated_entries)?;
+            // Nothing goes on the ESP under a name that FAT (or the firmware) can't cope with.
+            let utf8 = relative
+                .to_str()
+                .ok_or_else(|| format!("'{}' is not valid UTF-8", generated_loc.display()))?;
+            EspRelativePath::new(utf8)?;
+            let esp_loc = layout.dest(relative);
 
-            if let Some(esp_loc) = esp_files
-                .iter()
-                .find(|e| stripped == strip_unnecessary_prefix(e))
-                .map(ToOwned::to_owned)
-            {
+            if esp_loc.is_file() {
                 to_replace.push(FileToReplace {
                     generated_loc,
                     esp_loc,
                 })
+            } else {
+                to_add.push(generated_loc);
+                new_locs.push(esp_loc);
             }
         }
 
@@ -72,6 +75,7 @@ impl IdentifiedFiles {
         Ok(IdentifiedFiles {
             to_sign,
             to_replace,
+            to_add: new_locs,
         })
     }
 }
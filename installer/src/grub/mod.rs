@@ -1 +1,453 @@
+//! The GRUB installer: it installs the fragment with the entries the generator writes with
+//! `--grub` (see `generator::grub`) as [`ENTRIES`] in GRUB's directory, for its grub.cfg to
+//! `source`. It doesn't assemble a grub.cfg yet, but what it installs has to pass [`check_script`]
+//! first: a syntax error would leave the machine unbootable.
+//!
+//! With `--toplevel`, its generation is made the default entry, like systemd-boot's default, so
+//! that after a rollback GRUB doesn't keep booting the newest generation by default. With
+//! `--configuration-limit`, only the entries of the generations systemd-boot would keep with the
+//! same limit are installed (see [`KeepSet`]), so that with both installed, GRUB doesn't offer
+//! generations systemd-boot has pruned. `--timeout` (or `--grub-timeout`) and `--default-entry` are
+//! set in front of the entries (see [`settings`]).
 
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::command;
+use crate::context::Context;
+use crate::keep_set::KeepSet;
+use crate::options::{self, CommonBootloaderOptions, Timeout};
+use crate::util;
+use crate::warnings::{self, Kind};
+use crate::{Result, RunArgs};
+
+/// What the entries are installed as, in `--grub-dir`.
+pub(crate) const ENTRIES: &str = "nixos-entries.cfg";
+
+/// The arguments of `installer grub`.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// The directory that the generator created with `--grub`
+    #[clap(long, default_value = "grub-entries", parse(try_from_str = util::normalize_path))]
+    pub(crate) generated_entries: PathBuf,
+    /// The directory GRUB reads its configuration from, where the entries are installed as
+    /// `nixos-entries.cfg` (for its grub.cfg to `source`)
+    #[clap(long, default_value = "/boot/grub", parse(try_from_str = util::normalize_path))]
+    pub(crate) grub_dir: PathBuf,
+    /// The grub-script-check binary to check the entries' syntax with before they're installed
+    /// (without it, they aren't checked, with a warning)
+    #[clap(long)]
+    pub(crate) grub_script_check: Option<PathBuf>,
+    /// The path to the default configuration's toplevel: its store path, or its profile link (its
+    /// generation is the default entry, and its entries are installed whatever
+    /// `--configuration-limit` says; without it, the newest generation is the default)
+    #[clap(long)]
+    pub(crate) toplevel: Option<PathBuf>,
+    /// How long GRUB waits before booting the default entry, e.g. `5` (seconds), `30s`, or `2m`, or
+    /// `forever` (omit to leave it up to grub.cfg)
+    #[clap(long)]
+    pub(crate) timeout: Option<options::Timeout>,
+    /// GRUB's own `--timeout`, which takes precedence over it
+    #[clap(long)]
+    pub(crate) grub_timeout: Option<options::Timeout>,
+    /// The entry to boot by default instead of the first one (for GRUB, its title, e.g.
+    /// `NixOS - Default`)
+    #[clap(long)]
+    pub(crate) default_entry: Option<String>,
+    /// The maximum number of generations (of each profile) to install entries for, like
+    /// systemd-boot's
+    #[clap(long, requires = "toplevel", parse(try_from_str = util::parse_configuration_limit))]
+    pub(crate) configuration_limit: Option<usize>,
+    /// Whether to only check the entries, and print where they would be installed
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+    /// How long `grub-script-check` may run before it's killed and the run fails, e.g. `60`
+    /// (seconds), `90s`, or `5m`
+    #[clap(long, default_value = "60", parse(try_from_str = util::parse_command_timeout))]
+    pub(crate) command_timeout: Duration,
+    #[clap(flatten)]
+    pub(crate) run: RunArgs,
+}
+
+/// Installs the generator's GRUB entries in `args.generated_entries` to `args.grub_dir`, once
+/// they've passed [`check_script`]; the entries that were there are replaced atomically.
+pub(crate) fn install(args: &Args) -> Result<()> {
+    self::install_from(args, Path::new(util::PROFILES_DIR))
+}
+
+/// [`install`], with the default entry and the entries kept with `--configuration-limit` picked
+/// from the generations in `profiles_dir`.
+fn install_from(args: &Args, profiles_dir: &Path) -> Result<()> {
+    let generated = args.generated_entries.join(generator::grub::FRAGMENT);
+    if !generated.exists() {
+        return Err(format!(
+            "'{}' doesn't exist: the generator only writes GRUB entries with --grub",
+            generated.display()
+        )
+        .into());
+    }
+
+    let options = self::options(args);
+    let mut contents = fs::read_to_string(&generated).with_path_context(&generated)?;
+    if let Some(toplevel) = &args.toplevel {
+        // Only the generations are of interest, not the names of their files on an ESP, which
+        // unified EFI files' are the quickest to tell.
+        let keep_set = KeepSet::compute(
+            profiles_dir,
+            true,
+            None,
+            options.configuration_limit,
+            toplevel,
+        )?;
+        contents = generator::grub::with_default(&contents, keep_set.default_generation.idx)?;
+        contents = generator::grub::retain_generations(&contents, |idx, profile| {
+            keep_set.keeps(idx, profile)
+        });
+    }
+    contents.insert_str(0, &self::settings(&options));
+
+    // What's installed, which is checked first.
+    let installed_dir = tempfile::tempdir()?;
+    let fragment = installed_dir.path().join(ENTRIES);
+    fs::write(&fragment, contents).with_path_context(&fragment)?;
+
+    command::set_timeout(args.command_timeout);
+    self::check_script(args.grub_script_check.as_deref(), &fragment)?;
+
+    let dest = args.grub_dir.join(ENTRIES);
+    warnings::checkpoint(
+        &format!("before writing to '{}'", args.grub_dir.display()),
+        args.run.strict.as_deref(),
+    )?;
+    if args.dry_run {
+        writeln!(
+            std::io::stdout(),
+            "install '{}' as '{}'",
+            generated.display(),
+            dest.display()
+        )?;
+        return Ok(());
+    }
+
+    util::atomic_tmp_copy_file(&fragment, &dest)?;
+    info!(
+        "installed '{}' as '{}'",
+        generated.display(),
+        dest.display()
+    );
+
+    Ok(())
+}
+
+/// The common options, with `--grub-timeout` taking precedence over `--timeout`.
+pub(crate) fn options(args: &Args) -> CommonBootloaderOptions {
+    CommonBootloaderOptions {
+        timeout: args.timeout,
+        default_entry: args.default_entry.clone(),
+        configuration_limit: args.configuration_limit,
+    }
+    .with_timeout(args.grub_timeout)
+}
+
+/// The `set` commands for `options`, which go in front of the entries. A [`Timeout`] of 0 seconds
+/// boots the default entry without showing the menu, and [`Timeout::Forever`] becomes -1.
+pub(crate) fn settings(options: &CommonBootloaderOptions) -> String {
+    let mut settings = String::new();
+
+    match options.timeout {
+        Some(Timeout::Seconds(seconds)) => settings.push_str(&format!("set timeout={}\n", seconds)),
+        Some(Timeout::Forever) => settings.push_str("set timeout=-1\n"),
+        None => {}
+    }
+    if let Some(default_entry) = &options.default_entry {
+        settings.push_str(&format!(
+            "set default='{}'\n",
+            default_entry.replace('\'', "'\\''")
+        ));
+    }
+
+    settings
+}
+
+/// Checks the syntax of the grub.cfg at `cfg` with `grub-script-check`, failing with what it
+/// printed if there's an error. Without `grub_script_check`, the check is skipped (with a warning).
+pub(crate) fn check_script(grub_script_check: Option<&Path>, cfg: &Path) -> Result<()> {
+    let grub_script_check = match grub_script_check {
+        Some(grub_script_check) => grub_script_check,
+        None => {
+            warnings::emit(
+                Kind::UncheckedGrubConfig,
+                format!(
+                    "not checking the syntax of '{}': no grub-script-check was given",
+                    cfg.display()
+                ),
+            );
+            return Ok(());
+        }
+    };
+
+    debug!("checking the syntax of '{}'", cfg.display());
+    let output = command::output(Command::new(grub_script_check).arg(cfg))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "'{}' failed `{}`: {}",
+            cfg.display(),
+            grub_script_check.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn stub_tool(dir: &Path, name: &str, script: &str) -> PathBuf {
+        let tool = dir.join(name);
+        fs::write(&tool, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        tool
+    }
+
+    #[test]
+    fn test_check_script() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let cfg = dir.join("grub.cfg");
+        fs::write(&cfg, "menuentry \"NixOS\" {\n  linux /kernel\n}\n").unwrap();
+
+        // passes if the tool is happy with the file it was given
+        let tool = stub_tool(dir, "pass", "grep -q '^menuentry' \"$1\"\n");
+        check_script(Some(&tool), &cfg).unwrap();
+
+        let tool = stub_tool(
+            dir,
+            "fail",
+            "echo 'error: syntax error.' >&2\necho 'Syntax error at line 3' >&2\nexit 1\n",
+        );
+        let err = check_script(Some(&tool), &cfg).unwrap_err().to_string();
+        assert!(err.contains(&cfg.display().to_string()), "{}", err);
+        assert!(
+            err.contains("error: syntax error.\nSyntax error at line 3"),
+            "{}",
+            err
+        );
+
+        // skipped without the tool
+        check_script(None, &cfg).unwrap();
+
+        // but a tool that doesn't exist is an error
+        let missing = dir.join("missing");
+        let err = check_script(Some(&missing), &cfg).unwrap_err().to_string();
+        assert!(err.contains(&missing.display().to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_install() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated_entries = dir.join("grub-entries");
+        let grub_dir = dir.join("boot/grub");
+        let args =
+            |grub_script_check: Option<PathBuf>, dry_run: bool, strict: Option<Vec<Kind>>| Args {
+                generated_entries: generated_entries.clone(),
+                grub_dir: grub_dir.clone(),
+                grub_script_check,
+                toplevel: None,
+                timeout: None,
+                grub_timeout: None,
+                default_entry: None,
+                configuration_limit: None,
+                dry_run,
+                command_timeout: command::DEFAULT_TIMEOUT,
+                run: RunArgs {
+                    strict: strict.clone(),
+                    ..Default::default()
+                },
+            };
+
+        // the generator wasn't passed --grub
+        let err = install(&args(None, false, None)).unwrap_err().to_string();
+        assert!(err.contains("--grub"), "{}", err);
+
+        fs::create_dir_all(&generated_entries).unwrap();
+        let fragment = "menuentry 'NixOS - Default' --unrestricted {\n  linux /kernel\n}\n";
+        fs::write(generated_entries.join(generator::grub::FRAGMENT), fragment).unwrap();
+
+        install(&args(None, true, None)).unwrap();
+        assert!(!grub_dir.exists());
+
+        // entries that don't pass the check aren't installed
+        let fail = stub_tool(dir, "fail", "exit 1\n");
+        assert!(install(&args(Some(fail), false, None)).is_err());
+        assert!(!grub_dir.join(ENTRIES).exists());
+
+        // without a check, --strict fails the run before anything is written
+        let strict = Some(vec![Kind::UncheckedGrubConfig]);
+        let err = install(&args(None, false, strict)).unwrap_err().to_string();
+        assert!(err.contains(&grub_dir.display().to_string()), "{}", err);
+        assert!(!grub_dir.join(ENTRIES).exists());
+
+        let pass = stub_tool(dir, "pass", "grep -q -- --unrestricted \"$1\"\n");
+        install(&args(Some(pass), false, None)).unwrap();
+        assert_eq!(
+            fs::read_to_string(grub_dir.join(ENTRIES)).unwrap(),
+            fragment
+        );
+    }
+
+    #[test]
+    fn test_install_with_toplevel() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let profiles_dir = dir.join("profiles");
+        fs::create_dir_all(&profiles_dir).unwrap();
+        for idx in 1..=3 {
+            let toplevel = dir.join(format!(
+                "0123456789abcdefghijklmnopqrstu{}-nixos-system",
+                idx
+            ));
+            fs::create_dir_all(&toplevel).unwrap();
+            std::os::unix::fs::symlink(
+                &toplevel,
+                profiles_dir.join(format!("system-{}-link", idx)),
+            )
+            .unwrap();
+        }
+
+        let generated_entries = dir.join("grub-entries");
+        fs::create_dir_all(&generated_entries).unwrap();
+        let fragment = [
+            "# nixos-default 3\nmenuentry 'NixOS - Default' --unrestricted {\n  linux /kernel-3\n}\n",
+            "# nixos-generation 3\n",
+            "menuentry 'NixOS - Generation 3' {\n  linux /kernel-3\n}\n",
+            "# nixos-generation 2\n",
+            "menuentry 'NixOS - Generation 2' {\n  linux /kernel-2\n}\n",
+            "# nixos-generation 1\n",
+            "menuentry 'NixOS - Generation 1' {\n  linux /kernel-1\n}\n",
+        ];
+        fs::write(
+            generated_entries.join(generator::grub::FRAGMENT),
+            fragment.concat(),
+        )
+        .unwrap();
+
+        // the same generations as systemd-boot keeps with a limit of 1: the newest, and the
+        // `--toplevel`'s, which is the default
+        let grub_dir = dir.join("boot/grub");
+        let rolled_back_default =
+            "# nixos-default 1\nmenuentry 'NixOS - Default' --unrestricted {\n  linux /kernel-1\n}\n";
+        let mut args = Args {
+            generated_entries: generated_entries.clone(),
+            grub_dir: grub_dir.clone(),
+            grub_script_check: Some(stub_tool(dir, "pass", "exit 0\n")),
+            toplevel: Some(profiles_dir.join("system-1-link")),
+            timeout: Some(Timeout::Seconds(5)),
+            grub_timeout: None,
+            default_entry: None,
+            configuration_limit: Some(1),
+            dry_run: false,
+            command_timeout: command::DEFAULT_TIMEOUT,
+            run: RunArgs::default(),
+        };
+        install_from(&args, &profiles_dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(grub_dir.join(ENTRIES)).unwrap(),
+            [
+                "set timeout=5\n",
+                rolled_back_default,
+                fragment[1],
+                fragment[2],
+                fragment[5],
+                fragment[6]
+            ]
+            .concat()
+        );
+
+        // without a limit, every generation is kept, but the `--toplevel`'s is still the default
+        args.configuration_limit = None;
+        args.toplevel = Some(profiles_dir.join("system-2-link"));
+        install_from(&args, &profiles_dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(grub_dir.join(ENTRIES)).unwrap(),
+            [
+                "set timeout=5\n",
+                "# nixos-default 2\nmenuentry 'NixOS - Default' --unrestricted {\n  linux /kernel-2\n}\n",
+                fragment[1..].concat().as_str(),
+            ]
+            .concat()
+        );
+        // and the generator's entries are left as they were
+        assert_eq!(
+            fs::read_to_string(generated_entries.join(generator::grub::FRAGMENT)).unwrap(),
+            fragment.concat()
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings = |timeout: Option<Timeout>, default_entry: Option<&str>| {
+            super::settings(&CommonBootloaderOptions {
+                timeout,
+                default_entry: default_entry.map(ToString::to_string),
+                configuration_limit: None,
+            })
+        };
+
+        assert_eq!(settings(None, None), "");
+        assert_eq!(settings(Some(Timeout::Seconds(0)), None), "set timeout=0\n");
+        assert_eq!(
+            settings(Some(Timeout::Seconds(u32::MAX)), None),
+            "set timeout=4294967295\n"
+        );
+        assert_eq!(settings(Some(Timeout::Forever), None), "set timeout=-1\n");
+        assert_eq!(
+            settings(Some(Timeout::Seconds(5)), Some("NixOS - Bob's")),
+            "set timeout=5\nset default='NixOS - Bob'\\''s'\n"
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let args = |timeout: Option<Timeout>, grub_timeout: Option<Timeout>| Args {
+            generated_entries: PathBuf::from("grub-entries"),
+            grub_dir: PathBuf::from("/boot/grub"),
+            grub_script_check: None,
+            toplevel: None,
+            timeout,
+            grub_timeout,
+            default_entry: Some(String::from("NixOS - Default")),
+            configuration_limit: Some(3),
+            dry_run: false,
+            command_timeout: command::DEFAULT_TIMEOUT,
+            run: RunArgs::default(),
+        };
+
+        let options = options(&args(Some(Timeout::Seconds(5)), None));
+        assert_eq!(options.timeout, Some(Timeout::Seconds(5)));
+        assert_eq!(options.default_entry.as_deref(), Some("NixOS - Default"));
+        assert_eq!(options.configuration_limit, Some(3));
+        // --grub-timeout wins, but only if it's set
+        assert_eq!(
+            super::options(&args(Some(Timeout::Seconds(5)), Some(Timeout::Forever))).timeout,
+            Some(Timeout::Forever)
+        );
+        assert_eq!(
+            super::options(&args(None, Some(Timeout::Seconds(0)))).timeout,
+            Some(Timeout::Seconds(0))
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! NixOS puts an `append-initrd-secrets` script in toplevels, which appends the secrets of
+//! `boot.initrd.secrets` to an initrd. It's there even when there are no secrets, in which case it
+//! does nothing: telling the two apart saves running it for every generation.
+//!
+//! This errs on the side of a script having secrets: it's only taken to be a no-op if it's small
+//! and has nothing but a shebang and an `exit 0` (or its profile is `--assume-no-secrets-for`).
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use generator::report::Secrets;
+use log::debug;
+
+use crate::report::InitrdSecretsReport;
+use crate::util::Generation;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// The script, relative to a toplevel.
+pub(crate) const SCRIPT: &str = "append-initrd-secrets";
+/// Scripts larger than this aren't even read: a no-op is only a few dozen bytes.
+const MAX_NO_OP_SIZE: u64 = 256;
+
+/// Whether (and why not) the `append-initrd-secrets` script of `toplevel` has to run.
+/// `assume_none` skips looking at the script, as long as there is one.
+pub(crate) fn classify(toplevel: &Path, assume_none: bool) -> Result<Secrets> {
+    let script = toplevel.join(SCRIPT);
+
+    let metadata = match fs::metadata(&script) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Secrets::Absent),
+        Err(e) => {
+            debug!(
+                "couldn't stat '{}', so it has to run: {}",
+                script.display(),
+                e
+            );
+            return Ok(Secrets::Required);
+        }
+    };
+
+    if assume_none {
+        return Ok(Secrets::AssumedNone);
+    }
+
+    if !metadata.is_file() || metadata.len() > MAX_NO_OP_SIZE {
+        return Ok(Secrets::Required);
+    }
+
+    match fs::read_to_string(&script) {
+        Ok(contents) if self::is_no_op(&contents) => Ok(Secrets::NoOp),
+        Ok(_) => Ok(Secrets::Required),
+        Err(e) => {
+            debug!(
+                "couldn't read '{}', so it has to run: {}",
+                script.display(),
+                e
+            );
+            Ok(Secrets::Required)
+        }
+    }
+}
+
+/// Whether `contents` is a shell script that does nothing: a `sh` or `bash` shebang, followed by
+/// nothing but comments, blank lines, and `exit 0`.
+fn is_no_op(contents: &str) -> bool {
+    let mut lines = contents.lines();
+
+    let interpreter = lines
+        .next()
+        .and_then(|shebang| shebang.strip_prefix("#!"))
+        .and_then(|shebang| shebang.split_whitespace().next())
+        .and_then(|interpreter| Path::new(interpreter).file_name());
+    if !matches!(interpreter.and_then(|i| i.to_str()), Some("sh" | "bash")) {
+        return false;
+    }
+
+    lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .all(|line| line == "exit 0")
+}
+
+/// Classifies the script of every one of `generations` (of any profile), warning about the ones that have to run
+/// (the installer doesn't append initrd secrets), and returns what it found for the report.
+pub(crate) fn check_generations(
+    generations: &[Generation],
+    assume_no_secrets_for: &[String],
+) -> Result<Vec<InitrdSecretsReport>> {
+    let mut reports = Vec::new();
+
+    for generation in generations {
+        let profile = generation.profile.as_deref().unwrap_or("system");
+        let assume_none = assume_no_secrets_for.iter().any(|p| p == profile);
+        let secrets = self::classify(&generation.path, assume_none)?;
+
+        match secrets {
+            Secrets::Absent => continue,
+            Secrets::NoOp | Secrets::AssumedNone => {
+                debug!(
+                    "not running the {} of generation {}: {:?}",
+                    SCRIPT, generation.idx, secrets
+                );
+            }
+            Secrets::Required => warnings::emit(
+                Kind::InitrdSecrets,
+                format!(
+                    "generation {} of the {} profile has initrd secrets, which aren't appended to \
+                     its initrd on the ESP (pass --assume-no-secrets-for {} if it has none)",
+                    generation.idx, profile, profile
+                ),
+            ),
+        }
+
+        reports.push(InitrdSecretsReport {
+            generation: generation.idx,
+            profile: generation.profile.clone(),
+            script: generation.path.join(SCRIPT),
+            secrets,
+        });
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/initrd-secrets")
+            .join(name)
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&fixture("no-op"), false).unwrap(), Secrets::NoOp);
+        assert_eq!(
+            classify(&fixture("secrets"), false).unwrap(),
+            Secrets::Required
+        );
+        assert_eq!(
+            classify(&fixture("missing"), false).unwrap(),
+            Secrets::Absent
+        );
+    }
+
+    #[test]
+    fn test_classify_fails_safe() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let script = tempdir.path().join(SCRIPT);
+
+        for contents in [
+            // a no-op, as long as nothing follows the exit
+            "#!/bin/sh\nexit 0\n",
+            "#!/bin/sh\n\n# no secrets\nexit 0\n",
+            "#!/bin/sh\n",
+        ] {
+            fs::write(&script, contents).unwrap();
+            assert_eq!(
+                classify(tempdir.path(), false).unwrap(),
+                Secrets::NoOp,
+                "{:?}",
+                contents
+            );
+        }
+
+        for contents in [
+            "",
+            "exit 0\n",
+            "#!/usr/bin/env python3\nexit 0\n",
+            "#!/bin/sh\nexit 0\ncat /etc/secret >> \"$1\"\n",
+            "#!/bin/sh\nexit 1\n",
+        ] {
+            fs::write(&script, contents).unwrap();
+            assert_eq!(
+                classify(tempdir.path(), false).unwrap(),
+                Secrets::Required,
+                "{:?}",
+                contents
+            );
+        }
+
+        // too large to bother reading
+        let padded = format!("#!/bin/sh\n{}\nexit 0\n", "#".repeat(1024));
+        fs::write(&script, padded).unwrap();
+        assert_eq!(classify(tempdir.path(), false).unwrap(), Secrets::Required);
+
+        // not even UTF-8
+        fs::write(&script, b"#!/bin/sh\n\xff\n").unwrap();
+        assert_eq!(classify(tempdir.path(), false).unwrap(), Secrets::Required);
+    }
+
+    #[test]
+    fn test_assume_no_secrets_for() {
+        assert_eq!(
+            classify(&fixture("secrets"), true).unwrap(),
+            Secrets::AssumedNone
+        );
+        // there's still nothing to run without a script
+        assert_eq!(
+            classify(&fixture("missing"), true).unwrap(),
+            Secrets::Absent
+        );
+
+        let generation = |idx: usize, profile: Option<&str>, toplevel: &str| Generation {
+            idx,
+            profile: profile.map(String::from),
+            path: fixture(toplevel),
+            ..Default::default()
+        };
+        let generations = [
+            generation(1, None, "secrets"),
+            generation(2, None, "no-op"),
+            generation(3, Some("work"), "secrets"),
+            generation(4, None, "missing"),
+        ];
+
+        let secrets = |assume_no_secrets_for: &[&str]| {
+            check_generations(
+                &generations,
+                &assume_no_secrets_for
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|report| (report.generation, report.secrets))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            secrets(&[]),
+            vec![
+                (1, Secrets::Required),
+                (2, Secrets::NoOp),
+                (3, Secrets::Required),
+            ]
+        );
+        assert_eq!(
+            secrets(&["system"]),
+            vec![
+                (1, Secrets::AssumedNone),
+                (2, Secrets::AssumedNone),
+                (3, Secrets::Required),
+            ]
+        );
+        assert_eq!(
+            secrets(&["work"]),
+            vec![
+                (1, Secrets::Required),
+                (2, Secrets::NoOp),
+                (3, Secrets::AssumedNone),
+            ]
+        );
+    }
+}
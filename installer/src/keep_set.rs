@@ -0,0 +1,115 @@
+//! The generations the bootloader backends keep entries for. When more than one is installed (e.g.
+//! systemd-boot, with GRUB as a BIOS fallback), they're all pruned to the generations computed here,
+//! from the same profiles and configuration limit, so that neither keeps offering a generation the
+//! other has let go of. Each backend only removes what's in its own directories: systemd-boot its
+//! entries and `EFI/nixos`, and GRUB the entries it installs in its own.
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::util::{self, Generation};
+use crate::Result;
+
+/// The generations to keep, and what they were picked from.
+#[derive(Debug)]
+pub(crate) struct KeepSet {
+    /// Every generation of the system profile, oldest first
+    pub(crate) system_generations: Vec<Generation>,
+    /// The `--toplevel`'s generation, which is kept whatever the configuration limit
+    pub(crate) default_generation: Generation,
+    /// The system profile's generations to keep (see [`util::wanted_generations`])
+    pub(crate) wanted_generations: Vec<Generation>,
+    /// The other profiles' generations to keep (see [`util::profile_generations`])
+    pub(crate) profile_generations: Vec<Generation>,
+}
+
+impl KeepSet {
+    /// The generations in `profiles_dir` to keep with `configuration_limit`, besides the one
+    /// `toplevel` is. `unified` and `wait` are as for [`util::system_generations`].
+    pub(crate) fn compute(
+        profiles_dir: &Path,
+        unified: bool,
+        wait: Option<Duration>,
+        configuration_limit: Option<usize>,
+        toplevel: &Path,
+    ) -> Result<Self> {
+        let system_generations = util::system_generations(profiles_dir, unified, wait)?;
+        let profile_generations =
+            util::profile_generations(profiles_dir, unified, configuration_limit)?;
+        let default_generation =
+            util::default_generation(&system_generations, toplevel)?.to_owned();
+        let wanted_generations = util::wanted_generations(
+            system_generations.clone(),
+            configuration_limit,
+            &default_generation,
+        );
+
+        Ok(Self {
+            system_generations,
+            default_generation,
+            wanted_generations,
+            profile_generations,
+        })
+    }
+
+    /// Whether generation `idx` of `profile` (or of the system profile) is kept.
+    pub(crate) fn keeps(&self, idx: usize, profile: Option<&str>) -> bool {
+        self.wanted_generations
+            .iter()
+            .chain(&self.profile_generations)
+            .any(|g| g.idx == idx && g.profile.as_deref() == profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_compute() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let profiles_dir = tempdir.path().join("profiles");
+        let system_profiles = profiles_dir.join("system-profiles");
+        fs::create_dir_all(&system_profiles).unwrap();
+        for idx in 1..=4 {
+            // (named like a store path, which generations' unified EFI files are named after)
+            let toplevel = tempdir.path().join(format!(
+                "0123456789abcdefghijklmnopqrstu{}-nixos-system",
+                idx
+            ));
+            fs::create_dir_all(&toplevel).unwrap();
+            symlink(&toplevel, profiles_dir.join(format!("system-{}-link", idx))).unwrap();
+            symlink(
+                &toplevel,
+                system_profiles.join(format!("work-{}-link", idx)),
+            )
+            .unwrap();
+        }
+        symlink("work-4-link", system_profiles.join("work")).unwrap();
+
+        // rolled back to generation 1, which is kept despite the limit
+        let toplevel = profiles_dir.join("system-1-link");
+        let keep_set = KeepSet::compute(&profiles_dir, true, None, Some(2), &toplevel).unwrap();
+        assert_eq!(keep_set.system_generations.len(), 4);
+        assert_eq!(keep_set.default_generation.idx, 1);
+        for (idx, profile, kept) in [
+            (1, None, true),
+            (2, None, false),
+            (3, None, true),
+            (4, None, true),
+            (1, Some("work"), false),
+            (2, Some("work"), false),
+            (3, Some("work"), true),
+            (4, Some("work"), true),
+            (4, Some("other"), false),
+        ] {
+            assert_eq!(keep_set.keeps(idx, profile), kept, "{} {:?}", idx, profile);
+        }
+
+        let keep_set = KeepSet::compute(&profiles_dir, true, None, None, &toplevel).unwrap();
+        assert!(keep_set.keeps(2, None));
+        assert!(keep_set.keeps(1, Some("work")));
+    }
+}
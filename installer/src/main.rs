@@ -2,97 +2,983 @@
 
 // NOTE: profile names might have invalid characters? https://github.com/NixOS/nixpkgs/pull/114637
 // TODO: maybe make the installer use the generator directly? e.g. don't write to files, write to a HashMap<String, String>, which maps the file path to its contents
-use std::path::PathBuf;
-use std::{error::Error, io::Write};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use log::LevelFilter;
+use generator::{command, context, logging, target, warnings};
+use log::error;
 
+mod cli_common;
+mod efi_db;
+mod extlinux;
+mod fat;
 mod files;
 mod grub;
+mod initrd_secrets;
+mod keep_set;
+mod manifest;
+mod options;
+mod panic_hook;
+mod report;
 mod secure_boot;
 mod systemd_boot;
 mod util;
+#[cfg(feature = "qemu")]
+mod verify_boot;
 
-// TODO: separate by bootloader using a subcommand?
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: generator::stream::TrackingAllocator = generator::stream::TrackingAllocator;
+
+/// Installs what the generator created for a bootloader.
+#[derive(clap::Parser, Debug)]
+enum Bootloader {
+    /// Installs the generator's systemd-boot staging tree to the ESP(s), or runs one of the
+    /// systemd-boot commands (e.g. `installer systemd-boot set-default`)
+    SystemdBoot(Args),
+    /// Installs the generator's GRUB entries (see its `--grub`), for GRUB's grub.cfg to `source`
+    Grub(grub::Args),
+    /// Reserved for installing extlinux entries: it fails, since the generator doesn't write any
+    /// yet (which is why it's hidden from `--help`)
+    #[clap(hide = true)]
+    Extlinux(extlinux::Args),
+}
+
+impl Bootloader {
+    fn run_args(&self) -> &RunArgs {
+        match self {
+            Bootloader::SystemdBoot(args) => &args.run,
+            Bootloader::Grub(args) => &args.run,
+            Bootloader::Extlinux(args) => &args.run,
+        }
+    }
+}
+
+/// The arguments that every subcommand takes: how much to log, where to write the run report, and
+/// which warnings fail the run.
+#[derive(clap::Args, Default, Debug)]
+pub(crate) struct RunArgs {
+    /// Where to write a JSON report of the run (for systemd-boot: its configuration, the plan and
+    /// how each state went, and every file it changed on the ESP(s), with hashes). It's written
+    /// even if the run fails.
+    #[clap(long, parse(try_from_str = util::normalize_path))]
+    pub(crate) report: Option<PathBuf>,
+    /// How much to log (`-v` for info, `-vv` for debug, `-vvv` for trace); without it (or
+    /// `--quiet`), `RUST_LOG` refines the default of warnings per module, e.g.
+    /// `RUST_LOG=installer::systemd_boot::plan=trace,installer::files=off`
+    #[clap(short, long, parse(from_occurrences))]
+    pub(crate) verbosity: usize,
+    /// Only log errors, whatever `-v` or `RUST_LOG` say
+    #[clap(short, long)]
+    pub(crate) quiet: bool,
+    /// Fail the run over warnings (only those of the given kinds, with e.g.
+    /// `--strict=dirty-esp,entry-collision`): for systemd-boot, before touching any ESP, before
+    /// writing to each one, and at the end of the run; for GRUB, before installing the entries
+    #[clap(
+        long,
+        min_values = 0,
+        require_equals = true,
+        use_value_delimiter = true,
+        value_name = "KIND"
+    )]
+    pub(crate) strict: Option<Vec<warnings::Kind>>,
+}
+
+/// The arguments of `installer systemd-boot`.
 #[derive(clap::Parser, Default, Debug)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
-    /// The path to the default configuration's toplevel.
-    #[clap(long)]
+    /// The path to the default configuration's toplevel: its store path, or its profile link
+    /// (e.g. `/nix/var/nix/profiles/system-42-link`).
+    #[clap(long, parse(try_from_str = util::absolute_path))]
     toplevel: PathBuf,
     /// Whether to actually touch stuff or not
     #[clap(long)]
     dry_run: bool,
+    #[clap(flatten)]
+    run: RunArgs,
+    /// Where to write what the run found out about the machine (e.g.
+    /// `/var/lib/nixos-bootloader/facts.json`), for the generator's `--facts` to take as the
+    /// defaults of its options. It's only written if the run succeeds.
+    #[clap(long, parse(try_from_str = util::normalize_path))]
+    facts: Option<PathBuf>,
+    /// Where to write the kernel, initrd, and command line of the `--toplevel`'s generation (as
+    /// JSON), for switching to it with `kexec` or `systemctl soft-reboot` instead of rebooting
+    /// through the firmware. It's only written if the run succeeds.
+    #[clap(long, parse(try_from_str = util::normalize_path))]
+    write_next_boot: Option<PathBuf>,
     /// The directory that the generator created
-    #[clap(long)]
+    #[clap(long, parse(try_from_str = util::normalize_path))]
     generated_entries: PathBuf,
-    /// TODO
+    /// How long the bootloader waits before booting the default entry, e.g. `5` (seconds), `30s`, or
+    /// `2m`, or `forever` (omit to leave it up to the bootloader)
     #[clap(long)]
-    timeout: Option<usize>,
-    /// TODO
+    timeout: Option<options::Timeout>,
+    /// The entry to boot by default instead of the `--toplevel`'s (for systemd-boot, a pattern
+    /// matching its entry's ID, e.g. `nixos-chainload-windows.conf`)
     #[clap(long)]
-    console_mode: String,
+    default_entry: Option<String>,
     /// TODO
     #[clap(long)]
+    console_mode: String,
+    /// The maximum number of generations to keep entries for (must be at least 1; omit to keep
+    /// all generations)
+    #[clap(long, parse(try_from_str = util::parse_configuration_limit))]
     configuration_limit: Option<usize>,
     /// TODO
     #[clap(long)]
     editor: bool,
-    /// TODO
-    #[clap(short, long, parse(from_occurrences))]
-    verbosity: usize,
+    /// How long an external command (e.g. `bootctl` or `sbsign`) may run before it's killed and the
+    /// run fails, e.g. `60` (seconds), `90s`, or `5m`
+    #[clap(long, default_value = "60", parse(try_from_str = util::parse_command_timeout))]
+    command_timeout: Duration,
+    /// How long to wait for there to be generations in `/nix/var/nix/profiles` before failing, for
+    /// when it's mounted later in boot (e.g. with impermanence), e.g. `30s` or `2m`
+    #[clap(long, parse(try_from_str = cli_common::parse_duration))]
+    wait_for_profiles: Option<Duration>,
+    /// An environment variable for external commands to inherit, on top of `PATH` and `TMPDIR`
+    /// (they run with everything else cleared, and `LC_ALL=C`), e.g. `SOURCE_DATE_EPOCH`
+    #[clap(long, number_of_values = 1, parse(try_from_str = command::parse_env_var_name))]
+    passthrough_env: Vec<String>,
     /// TODO
     #[clap(long)]
     install: bool,
 
     // EFI-specific arguments
-    /// The path to the EFI System Partition(s)
-    #[clap(long)]
+    /// The path to the EFI System Partition(s). Mirrored ESPs must be distinct filesystems: an ESP
+    /// on the same filesystem as an earlier one is ignored
+    #[clap(long, parse(try_from_str = util::normalize_path))]
     esp: Vec<PathBuf>,
+    /// The XBOOTLDR partition (e.g. `/boot`, with the ESP at `/efi`) to put the generations'
+    /// entries, kernels, initrds, and unified EFI files on; the ESP keeps systemd-boot,
+    /// loader.conf, and the `--chainload` entries
+    #[clap(long, parse(try_from_str = util::normalize_path))]
+    xbootldr: Option<PathBuf>,
+    /// Whether to go ahead (with a warning) if an ESP's filesystem wasn't cleanly unmounted, instead
+    /// of refusing to write to it until it has been checked with `fsck.vfat`
+    #[clap(long)]
+    ignore_dirty_esp: bool,
+    /// Whether to prune and replace the files (and entries, and loader.conf) on an ESP that a newer
+    /// release of the installer put there (going by the version its manifest is stamped with), e.g.
+    /// after rolling back the installer; otherwise, they're only added to
+    #[clap(long)]
+    allow_downgrade_management: bool,
+    /// Whether to go ahead (with a warning) if the files on an ESP changed between planning what to
+    /// do with them and doing it, instead of refusing to act on the outdated plan
+    #[clap(long)]
+    ignore_esp_drift: bool,
+    /// Whether to replace an entry on an ESP that has the name of one of ours, but that the installer
+    /// didn't put there (going by its manifest), e.g. one written by hand; otherwise, it's left
+    /// alone (with a warning), and ours is installed next to it as `<entry>-deconflicted.conf`
+    #[clap(long)]
+    adopt_existing_entries: bool,
+    /// Whether to let `bootctl install` replace a removable-media fallback loader
+    /// (`EFI/BOOT/BOOT*.EFI`) that isn't systemd-boot, e.g. rEFInd's or GRUB's, instead of keeping it
+    #[clap(long)]
+    overwrite_fallback_loader: bool,
+    /// The file with the machine-id to substitute for `@MACHINE_ID@` in entries generated with the
+    /// generator's `--machine-id-placeholder`
+    #[clap(long, default_value = "/etc/machine-id", parse(try_from_str = util::normalize_path))]
+    machine_id_file: PathBuf,
+    /// The generator's `--entry-path-prefix` (e.g. `/boot`), which the kernels, initrds, and unified
+    /// EFI files are under (and pruned from) on the ESP
+    #[clap(long, parse(try_from_str = target::parse_entry_path_prefix))]
+    entry_path_prefix: Option<String>,
+    /// The A/B slot (`a` or `b`) to install to, next to the other slot on the same ESP: the
+    /// entries are named `nixos-<slot>-…` and their files go in `EFI/nixos-<slot>`, only the slot's
+    /// files are pruned, and loader.conf's default is left for `activate-slot` to change
+    #[clap(
+        long,
+        conflicts_with_all = &["chainload", "default-entry", "stage-oneshot", "promote-staged"]
+    )]
+    slot: Option<systemd_boot::Slot>,
+    /// Runs only one phase of the install, e.g. to repair an ESP that was left half updated:
+    /// `prune` removes old files; `copy` copies the staged files, first writing loader.conf, the
+    /// chainload entries, and the credentials into them, leaving out the ones that are already on
+    /// the ESP, and signing the rest (with `--signing-key`); `sign` signs systemd-boot and the
+    /// staged files that aren't already on the ESP (and requires `--signing-key`); `loader`
+    /// installs or updates systemd-boot, and signs it (with `--signing-key`). `all` runs them all
+    #[clap(long, default_value = "all")]
+    phase: systemd_boot::Phase,
+    /// How many files to copy to the ESP at a time (the kernels, initrds, and unified EFI files are
+    /// all copied before the entries that refer to them)
+    #[clap(long, default_value = "4", parse(try_from_str = util::parse_copy_jobs))]
+    copy_jobs: usize,
+    /// The most files the installer's files on the ESP (and XBOOTLDR partition) may come to once
+    /// the old generations are pruned, whatever the configuration limit; an install that would
+    /// leave more fails while planning, with what each generation contributes
+    #[clap(long)]
+    max_esp_files: Option<usize>,
+    /// The most bytes the installer's files on the ESP (and XBOOTLDR partition) may come to once
+    /// the old generations are pruned, e.g. `400MiB` (see `--max-esp-files`)
+    #[clap(long)]
+    max_esp_bytes: Option<cli_common::ByteSize>,
     /// Whether or not to touch EFI vars in the NVRAM
     #[clap(long)]
     can_touch_efi_vars: bool,
+    /// systemd-boot's own `--timeout`, which takes precedence over it
+    #[clap(long)]
+    systemd_boot_timeout: Option<options::Timeout>,
     /// TODO: bootctl path
     #[clap(long)]
     bootctl: Option<PathBuf>,
+    /// Whether to skip running `bootctl` entirely (e.g. when systemd-boot is embedded in the
+    /// firmware)
+    #[clap(long, conflicts_with_all = &["install", "can-touch-efi-vars"])]
+    no_bootctl: bool,
+    /// Additional entries that chainload another EFI program on the ESP, as
+    /// `name=title=path` (e.g. `windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi`)
+    #[clap(long)]
+    chainload: Vec<systemd_boot::Chainload>,
+    /// Profiles whose entries to remove, e.g. after renaming a profile (other profiles' entries are
+    /// otherwise left alone). Kernels and initrds that other entries still use are kept.
+    #[clap(long, parse(try_from_str = util::parse_profile_name))]
+    retire_profile: Vec<String>,
+    /// Generations of the system profile to keep whatever the configuration limit, with "(pinned)"
+    /// at the end of their entries' titles, e.g. a known-good one to roll back to. The pins are
+    /// kept on the ESP for later installs (see `pin` and `unpin`).
+    #[clap(long, number_of_values = 1)]
+    pin_generation: Vec<usize>,
+    /// Profiles whose `append-initrd-secrets` scripts are taken to append no secrets without looking
+    /// at them (`system` for the system profile)
+    #[clap(long, parse(try_from_str = util::parse_profile_name))]
+    assume_no_secrets_for: Vec<String>,
+    /// Encrypted credentials for systemd-stub to pass on to the booted system, as `name=path`
+    /// (the `.cred` files an earlier run wrote for the ones that aren't passed anymore are removed)
+    #[clap(long)]
+    credential: Vec<systemd_boot::Credential>,
+    /// Whether the credentials are available to every generation (`global`), or only to the
+    /// default one (`generation`, which requires `--unified-efi`)
+    #[clap(long, default_value = "global")]
+    credential_scope: systemd_boot::CredentialScope,
+    /// Whether to sign the chainloaded EFI programs that exist on the ESP
+    #[clap(long, requires = "signing-key")]
+    sign_chainload: bool,
+    /// Whether to boot the new generation only once (with `bootctl set-oneshot`), keeping the
+    /// previous default generation as the default until it is promoted with `--promote-staged`
+    #[clap(
+        long,
+        requires = "can-touch-efi-vars",
+        conflicts_with = "promote-staged"
+    )]
+    stage_oneshot: bool,
+    /// Whether to make the generation staged by `--stage-oneshot` the default, if the
+    /// `--success-marker` exists
+    #[clap(long, requires = "success-marker")]
+    promote_staged: bool,
+    /// A file that exists once the staged generation has booted successfully
+    #[clap(long, requires = "promote-staged", parse(try_from_str = util::normalize_path))]
+    success_marker: Option<PathBuf>,
     /// Whether to use unified EFI files
     #[clap(long)]
     unified_efi: bool,
     /// The signing key used for Secure Boot
-    #[clap(long, requires_all = &["signing-cert", "sbsign", "sbverify"])]
+    #[clap(
+        long,
+        requires_all = &["signing-cert", "sbsign", "sbverify"],
+        parse(try_from_str = util::normalize_path)
+    )]
     signing_key: Option<PathBuf>,
     /// The signing cert used for Secure Boot
-    #[clap(long, requires_all = &["signing-key", "sbsign", "sbverify"])]
+    #[clap(
+        long,
+        requires_all = &["signing-key", "sbsign", "sbverify"],
+        parse(try_from_str = util::normalize_path)
+    )]
     signing_cert: Option<PathBuf>,
+    /// Files to sign with another key than the `--signing-key`, as `glob=key:cert`, where `glob`
+    /// matches their path on the ESP (e.g. `EFI/systemd/*=org.key:org.crt`). The first rule that
+    /// matches a file wins; files that no rule matches are signed with the `--signing-key`
+    #[clap(long, requires = "signing-key")]
+    signing_rule: Vec<secure_boot::SigningRule>,
     /// The sbsign binary to sign the files for Secure Boot
     #[clap(long, requires_all = &["signing-key", "signing-cert", "sbverify"])]
     sbsign: Option<PathBuf>,
     /// The sbverify binary to sign the files for Secure Boot
     #[clap(long, requires_all = &["signing-key", "signing-cert", "sbsign"])]
     sbverify: Option<PathBuf>,
+    /// Whether to check that the `--signing-cert` and the `--signing-rule`s' certs (or their
+    /// issuers) are enrolled in the firmware's `db`, which it otherwise won't boot with Secure Boot
+    /// enabled (skipped if efivars aren't readable)
+    #[clap(long, requires = "signing-cert")]
+    verify_cert_enrolled: bool,
+    /// Executables to run, in order, once the staged files are signed and before they're copied to
+    /// the ESP (e.g. to upload their hashes to a transparency log), as `<hook> <staging directory>
+    /// <ESP>`, with a JSON description of the plan on their stdin. A hook that fails fails the run
+    /// before anything is copied to the ESP, and what they print is kept in the `--report`
+    #[clap(long)]
+    hook: Vec<PathBuf>,
+    /// Whether `--dry-run` runs the `--hook`s (with `DRY_RUN=1` in their environment), instead of
+    /// only listing them in the plan
+    #[clap(long, requires = "dry-run")]
+    dry_run_hooks: bool,
+    /// Where to back up what each run replaces or prunes on the ESP(s), for `rollback` to restore:
+    /// a directory off the ESP(s), where each run's backup is kept as long as the ESP's history
+    /// has the run
+    #[clap(long, parse(try_from_str = util::normalize_path))]
+    backup_dir: Option<PathBuf>,
+    /// The patched sbattach binary used to compare signed files (defaults to the one embedded at
+    /// build time, if any)
+    #[clap(long)]
+    sbattach: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Checks that everything the install needs is there (bootctl, the signing tools, the ESPs,
+    /// efivarfs, the machine-id), going by the options before `doctor`, without touching anything.
+    /// Fails if something that's needed is missing.
+    Doctor {
+        /// Whether to print the checks as JSON
+        #[clap(long)]
+        json: bool,
+        /// The ukify to check for
+        #[clap(long, default_value = "ukify")]
+        ukify: PathBuf,
+    },
+    /// Makes a generation's entry the one systemd-boot boots by default, e.g. to roll back to it
+    /// after a bad deploy, by only rewriting the `default` in loader.conf: nothing is copied or
+    /// pruned, and bootctl isn't run. Fails without changing anything if the entry, or a file it
+    /// refers to, is missing from an ESP.
+    SetDefault {
+        /// The path to the EFI System Partition(s)
+        #[clap(long, required = true, parse(try_from_str = util::normalize_path))]
+        esp: Vec<PathBuf>,
+        /// The XBOOTLDR partition with the generations' entries, if any
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        xbootldr: Option<PathBuf>,
+        /// The generation whose entry to boot by default
+        #[clap(long)]
+        generation: usize,
+        /// The profile the generation is of (omit for the system profile)
+        #[clap(long, parse(try_from_str = util::parse_profile_name))]
+        profile: Option<String>,
+        /// The generation's specialisation to boot by default, instead of the generation itself
+        #[clap(long)]
+        specialisation: Option<String>,
+        /// Whether to also clear the one-shot entry (see `--stage-oneshot`), which otherwise boots
+        /// next instead of the new default
+        #[clap(long)]
+        clear_oneshot: bool,
+    },
+    /// Makes an A/B slot (see `--slot`) the one systemd-boot boots, by pointing the `default` in
+    /// loader.conf at the slot's newest generation: nothing is copied or pruned, and bootctl isn't
+    /// run. Fails without changing anything if the slot's entry, or a file it refers to, is missing
+    /// from an ESP.
+    ActivateSlot {
+        /// The slot to boot (`a` or `b`)
+        slot: systemd_boot::Slot,
+        /// The path to the EFI System Partition(s)
+        #[clap(long, required = true, parse(try_from_str = util::normalize_path))]
+        esp: Vec<PathBuf>,
+        /// The XBOOTLDR partition with the generations' entries, if any
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        xbootldr: Option<PathBuf>,
+    },
+    /// Pins a generation of the system profile on the ESP(s), so that it's kept whatever the
+    /// configuration limit (see `--pin-generation`). Takes effect at the next install.
+    Pin {
+        /// The generation to pin
+        generation: usize,
+        /// The path to the EFI System Partition(s)
+        #[clap(long, required = true, parse(try_from_str = util::normalize_path))]
+        esp: Vec<PathBuf>,
+    },
+    /// Unpins a generation of the system profile on the ESP(s), so that it's pruned with the rest
+    /// at the next install if it's outside the configuration limit.
+    Unpin {
+        /// The generation to unpin
+        generation: usize,
+        /// The path to the EFI System Partition(s)
+        #[clap(long, required = true, parse(try_from_str = util::normalize_path))]
+        esp: Vec<PathBuf>,
+    },
+    /// Boots an ESP in a transient QEMU VM with OVMF, and passes once one of the markers shows up
+    /// on the serial console, e.g. to check an image's ESP in CI. Fails with the end of the console
+    /// log otherwise.
+    #[cfg(feature = "qemu")]
+    VerifyBoot {
+        /// The ESP to boot, as a directory (which is copied into a FAT image with mtools)
+        #[clap(long, required_unless_present = "image", conflicts_with = "image")]
+        esp: Option<PathBuf>,
+        /// The ESP to boot, as an image
+        #[clap(long)]
+        image: Option<PathBuf>,
+        /// The QEMU to boot it with, e.g. `qemu-system-x86_64`
+        #[clap(long)]
+        qemu: PathBuf,
+        /// OVMF's firmware code and variables, as `code,vars`
+        #[clap(long)]
+        ovmf: verify_boot::Ovmf,
+        /// More arguments for QEMU, e.g. `-enable-kvm`
+        #[clap(long, number_of_values = 1, allow_hyphen_values = true)]
+        qemu_arg: Vec<String>,
+        /// What has to show up on the console for the boot to pass (any of them); by default,
+        /// systemd-boot's menu or the kernel's banner
+        #[clap(long, number_of_values = 1)]
+        marker: Vec<String>,
+        /// How long the boot may take to get there, e.g. `90s` or `5m`
+        #[clap(long, default_value = "5m", parse(try_from_str = cli_common::parse_duration))]
+        boot_timeout: Duration,
+        /// Where to keep the console log
+        #[clap(long)]
+        console_log: Option<PathBuf>,
+    },
+    /// Takes over the entries on an ESP that something else wrote (e.g. NixOS's own systemd-boot
+    /// builder, or someone by hand), for later installs to manage: each is correlated with the
+    /// generation it boots by the store paths it refers to, and recorded as the installer's.
+    /// Prints what each entry was correlated with, or why it couldn't be; those are left alone.
+    Adopt {
+        /// The path to the EFI System Partition
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        esp: PathBuf,
+        /// The XBOOTLDR partition with the generations' entries, if any
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        xbootldr: Option<PathBuf>,
+    },
+    /// Prints the last runs recorded on an ESP, oldest first: when each started, the installer's
+    /// version, how many files it changed, and the entry loader.conf defaulted to after it. Then
+    /// lists the unified kernel images in `EFI/Linux` that other tools (e.g. `kernel-install`) put
+    /// there as unmanaged: the installer never prunes them.
+    History {
+        /// The path to the EFI System Partition
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        esp: PathBuf,
+        /// Whether to print the whole history as JSON, including the run that last wrote each file
+        #[clap(long)]
+        json: bool,
+    },
+    /// Undoes the last run on an ESP that wasn't already, e.g. when its generation doesn't boot,
+    /// from the backup it made (see `--backup-dir`): the files it added are removed, and the ones
+    /// it replaced or pruned are restored, loader.conf and its default among them. The run stays in
+    /// the history, as rolled back. Fails without changing anything if the run wasn't backed up.
+    Rollback {
+        /// The path to the EFI System Partition
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        esp: PathBuf,
+        /// The XBOOTLDR partition with the generations' entries, if any
+        #[clap(long, parse(try_from_str = util::normalize_path))]
+        xbootldr: Option<PathBuf>,
+        /// Whether to only print what would be removed and restored
+        #[clap(long)]
+        dry_run: bool,
+    },
 }
 
 pub(crate) type Result<T, E = Box<dyn Error + Send + Sync + 'static>> = core::result::Result<T, E>;
 
+/// Runs the subcommand, catching a panic (which exits with [`panic_hook::EXIT_INTERNAL_ERROR`]),
+/// and writes the `--report` even if it fails.
 fn main() -> Result<()> {
-    let args: Args = clap::Parser::parse();
-
-    env_logger::Builder::new()
-        .format(|buf, record| writeln!(buf, "{:<5} {}", record.level(), record.args()))
-        .filter(
-            Some(env!("CARGO_PKG_NAME")), // only log for this
-            match args.verbosity {
-                0 => LevelFilter::Warn,
-                1 => LevelFilter::Info,
-                2 => LevelFilter::Debug,
-                _ => LevelFilter::Trace,
-            },
-        )
-        .try_init()?;
+    let bootloader: Bootloader = clap::Parser::parse();
+    let run_args = bootloader.run_args();
+    logging::init(&logging::Options::new(
+        env!("CARGO_PKG_NAME"),
+        run_args.verbosity,
+        run_args.quiet,
+    ))?;
+
+    let report_path = run_args.report.clone();
+    let strict = run_args.strict.clone();
+    let mut run_report = report::RunReport::start(env!("CARGO_PKG_VERSION"));
+    let ctx = panic_hook::RunContext::default();
+    let (ret, panicked) = match panic_hook::catch(&ctx, || match bootloader {
+        Bootloader::SystemdBoot(args) => self::install_systemd_boot(args, &mut run_report, &ctx),
+        Bootloader::Grub(args) => grub::install(&args),
+        Bootloader::Extlinux(args) => extlinux::run(&args),
+    }) {
+        Ok(ret) => (ret, None),
+        Err(record) => (Err(record.to_string().into()), Some(record)),
+    };
+
+    if let Some(path) = report_path {
+        run_report.warnings = warnings::report(strict.as_deref());
+        run_report.finish(&ret);
+        if let Err(e) = run_report.write(&path) {
+            // Don't hide why the run itself failed.
+            if ret.is_err() {
+                error!("couldn't write the report: {}", e);
+            } else {
+                return Err(e);
+            }
+        }
+    }
+
+    if let Some(record) = panicked {
+        record.emit();
+        std::process::exit(panic_hook::EXIT_INTERNAL_ERROR);
+    }
 
-    // TODO: choose which bootloader to install to somehow
-    // (for now, hardcoded to systemd_boot for dogfood purposes)
+    ret
+}
+
+fn install_systemd_boot(
+    args: Args,
+    run_report: &mut report::RunReport,
+    ctx: &panic_hook::RunContext,
+) -> Result<()> {
     // TODO: better error handling (eyre? something with backtraces, preferably...)
-    systemd_boot::install(args)?;
+    command::set_timeout(args.command_timeout);
+    command::set_passthrough_env(args.passthrough_env.clone());
+    if let Some(Command::Doctor { json, ukify }) = &args.command {
+        let system = systemd_boot::System {
+            ukify: Some(ukify.clone()),
+            ..Default::default()
+        };
+        return systemd_boot::doctor(&args, &system, *json);
+    }
+    if let Some(Command::SetDefault {
+        esp,
+        xbootldr,
+        generation,
+        profile,
+        specialisation,
+        clear_oneshot,
+    }) = &args.command
+    {
+        let entry = generator::systemd_boot::entry_name(
+            profile.as_deref(),
+            *generation,
+            specialisation.as_deref(),
+        );
+        let oneshot_efivar = clear_oneshot.then(|| Path::new(systemd_boot::ONESHOT_EFIVAR));
+        return systemd_boot::set_default(esp, xbootldr.as_deref(), &entry, oneshot_efivar);
+    }
+    if let Some(Command::ActivateSlot {
+        slot,
+        esp,
+        xbootldr,
+    }) = &args.command
+    {
+        return systemd_boot::activate_slot(esp, xbootldr.as_deref(), *slot);
+    }
+    if let Some(Command::History { esp, json }) = &args.command {
+        return systemd_boot::print_history(esp, *json);
+    }
+    if let Some(Command::Adopt { esp, xbootldr }) = &args.command {
+        return systemd_boot::adopt(esp, xbootldr.as_deref(), Path::new(util::PROFILES_DIR));
+    }
+    if let Some(Command::Rollback {
+        esp,
+        xbootldr,
+        dry_run,
+    }) = &args.command
+    {
+        return systemd_boot::rollback(esp, xbootldr.as_deref(), *dry_run);
+    }
+    #[cfg(feature = "qemu")]
+    if let Some(Command::VerifyBoot {
+        esp,
+        image,
+        qemu,
+        ovmf,
+        qemu_arg,
+        marker,
+        boot_timeout,
+        console_log,
+    }) = &args.command
+    {
+        let source = match (esp, image) {
+            (_, Some(image)) => verify_boot::Source::Image(image),
+            (Some(esp), None) => verify_boot::Source::Dir(esp),
+            (None, None) => unreachable!("clap requires --esp or --image"),
+        };
+        let markers = if marker.is_empty() {
+            verify_boot::DEFAULT_MARKERS
+                .iter()
+                .map(|marker| marker.to_string())
+                .collect()
+        } else {
+            marker.clone()
+        };
+        let verify = verify_boot::VerifyBoot {
+            qemu,
+            ovmf,
+            qemu_args: qemu_arg,
+            markers: &markers,
+            timeout: *boot_timeout,
+            console_log: console_log.as_deref(),
+        };
+        return verify_boot::verify_boot(source, &verify);
+    }
+    if let Some(Command::Pin { generation, esp }) = &args.command {
+        return systemd_boot::set_pinned(esp, *generation, true);
+    }
+    if let Some(Command::Unpin { generation, esp }) = &args.command {
+        return systemd_boot::set_pinned(esp, *generation, false);
+    }
+
+    systemd_boot::install(args, run_report, ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_bootloader(args: &[&str]) -> Result<Bootloader, clap::Error> {
+        <Bootloader as clap::Parser>::try_parse_from(
+            std::iter::once("installer").chain(args.iter().copied()),
+        )
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let args = ["systemd-boot"]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect::<Vec<_>>();
+        match parse_bootloader(&args)? {
+            Bootloader::SystemdBoot(args) => Ok(args),
+            bootloader => panic!("{:?}", bootloader),
+        }
+    }
+
+    #[test]
+    fn test_bootloader_args() {
+        let args = parse(&[
+            "--toplevel",
+            "/run/current-system",
+            "--generated-entries",
+            "/tmp/generated",
+            "--console-mode",
+            "max",
+            "--esp",
+            "/boot",
+        ])
+        .unwrap();
+        assert_eq!(args.esp, [PathBuf::from("/boot")]);
+
+        match parse_bootloader(&["grub", "--grub-dir", "/boot/grub/"]).unwrap() {
+            Bootloader::Grub(args) => {
+                assert_eq!(args.generated_entries, PathBuf::from("grub-entries"));
+                assert_eq!(args.grub_dir, PathBuf::from("/boot/grub"));
+                assert_eq!(args.grub_script_check, None);
+            }
+            bootloader => panic!("{:?}", bootloader),
+        }
+        match parse_bootloader(&["extlinux", "--generated-entries", "/tmp/generated"]).unwrap() {
+            Bootloader::Extlinux(args) => {
+                assert_eq!(args.extlinux_dir, PathBuf::from("/boot/extlinux"));
+                let err = extlinux::run(&args).unwrap_err().to_string();
+                assert!(err.contains("isn't supported"), "{}", err);
+            }
+            bootloader => panic!("{:?}", bootloader),
+        }
+        // but it isn't offered until it does something
+        let mut help = Vec::new();
+        <Bootloader as clap::CommandFactory>::command()
+            .write_help(&mut help)
+            .unwrap();
+        let help = String::from_utf8(help).unwrap();
+        assert!(help.contains("grub"), "{}", help);
+        assert!(!help.contains("extlinux"), "{}", help);
+
+        // every bootloader takes the logging, report, and --strict options
+        for bootloader in ["grub", "extlinux"] {
+            let bootloader = parse_bootloader(&[
+                bootloader,
+                "--generated-entries",
+                "/tmp/generated",
+                "-vv",
+                "--report",
+                "/tmp/report.json",
+                "--strict",
+            ])
+            .unwrap();
+            let run_args = bootloader.run_args();
+            assert_eq!(run_args.verbosity, 2);
+            assert_eq!(run_args.report, Some(PathBuf::from("/tmp/report.json")));
+            assert_eq!(run_args.strict, Some(vec![]));
+        }
+
+        // there's no default bootloader, and the EFI options are only systemd-boot's
+        assert!(parse_bootloader(&["--esp", "/boot"]).is_err());
+        assert!(parse_bootloader(&["grub", "--esp", "/boot"]).is_err());
+        assert!(
+            parse_bootloader(&["extlinux", "--generated-entries", "/tmp", "--esp", "/boot"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_set_default_args() {
+        let args = parse(&[
+            "set-default",
+            "--esp",
+            "/boot",
+            "--generation",
+            "41",
+            "--profile",
+            "work",
+            "--specialisation",
+            "gui",
+            "--clear-oneshot",
+        ])
+        .unwrap();
+        match args.command {
+            Some(Command::SetDefault {
+                esp,
+                xbootldr,
+                generation,
+                profile,
+                specialisation,
+                clear_oneshot,
+            }) => {
+                assert_eq!(esp, [PathBuf::from("/boot")]);
+                assert_eq!(xbootldr, None);
+                assert_eq!(generation, 41);
+                assert_eq!(profile.as_deref(), Some("work"));
+                assert_eq!(specialisation.as_deref(), Some("gui"));
+                assert!(clear_oneshot);
+            }
+            command => panic!("{:?}", command),
+        }
+
+        assert!(parse(&["set-default", "--generation", "41"]).is_err());
+        assert!(parse(&["set-default", "--esp", "/boot"]).is_err());
+        assert!(parse(&["set-default", "--esp", "/boot", "--generation", "x"]).is_err());
+        assert!(parse(&[
+            "set-default",
+            "--esp",
+            "/boot",
+            "--generation",
+            "41",
+            "--profile",
+            "my-work"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_slot_args() {
+        let args = parse(&["activate-slot", "b", "--esp", "/boot"]).unwrap();
+        match args.command {
+            Some(Command::ActivateSlot {
+                slot,
+                esp,
+                xbootldr,
+            }) => {
+                assert_eq!(slot, systemd_boot::Slot::B);
+                assert_eq!(esp, [PathBuf::from("/boot")]);
+                assert_eq!(xbootldr, None);
+            }
+            command => panic!("{:?}", command),
+        }
+        assert!(parse(&["activate-slot", "c", "--esp", "/boot"]).is_err());
+        assert!(parse(&["activate-slot", "--esp", "/boot"]).is_err());
+
+        let install = [
+            "--toplevel",
+            "/run/current-system",
+            "--generated-entries",
+            "/tmp/generated",
+            "--console-mode",
+            "max",
+            "--slot",
+            "a",
+        ];
+        assert_eq!(parse(&install).unwrap().slot, Some(systemd_boot::Slot::A));
+        for conflicting in [
+            &["--default-entry", "nixos-b-generation-*"][..],
+            &[
+                "--chainload",
+                "windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi",
+            ],
+        ] {
+            let args = install
+                .iter()
+                .chain(conflicting)
+                .copied()
+                .collect::<Vec<_>>();
+            assert!(parse(&args).is_err(), "{:?}", conflicting);
+        }
+    }
+
+    #[test]
+    fn test_history_args() {
+        match parse(&["history", "--esp", "/boot", "--json"])
+            .unwrap()
+            .command
+        {
+            Some(Command::History { esp, json }) => {
+                assert_eq!(esp, PathBuf::from("/boot"));
+                assert!(json);
+            }
+            command => panic!("{:?}", command),
+        }
+        assert!(parse(&["history"]).is_err());
+    }
+
+    #[test]
+    fn test_rollback_args() {
+        match parse(&["rollback", "--esp", "/boot/", "--dry-run"])
+            .unwrap()
+            .command
+        {
+            Some(Command::Rollback {
+                esp,
+                xbootldr,
+                dry_run,
+            }) => {
+                assert_eq!(esp, PathBuf::from("/boot"));
+                assert_eq!(xbootldr, None);
+                assert!(dry_run);
+            }
+            command => panic!("{:?}", command),
+        }
+        assert!(parse(&["rollback"]).is_err());
+    }
+
+    #[test]
+    fn test_adopt_args() {
+        match parse(&["adopt", "--esp", "/boot"]).unwrap().command {
+            Some(Command::Adopt { esp, xbootldr }) => {
+                assert_eq!(esp, PathBuf::from("/boot"));
+                assert_eq!(xbootldr, None);
+            }
+            command => panic!("{:?}", command),
+        }
+        assert!(parse(&["adopt"]).is_err());
+    }
+
+    #[test]
+    fn test_strict_args() {
+        let install = [
+            "--toplevel",
+            "/run/current-system",
+            "--generated-entries",
+            "/tmp/generated",
+            "--console-mode",
+            "max",
+        ];
+        let strict = |extra: &[&str]| {
+            let args = install.iter().chain(extra).copied().collect::<Vec<_>>();
+            parse(&args).map(|args| args.run.strict)
+        };
+
+        assert_eq!(strict(&[]).unwrap(), None);
+        assert_eq!(strict(&["--strict"]).unwrap(), Some(vec![]));
+        assert_eq!(
+            strict(&["--strict=dirty-esp,entry-collision"]).unwrap(),
+            Some(vec![
+                warnings::Kind::DirtyEsp,
+                warnings::Kind::EntryCollision
+            ])
+        );
+        assert!(strict(&["--strict=dirty"]).is_err());
+    }
+
+    #[test]
+    fn test_hook_args() {
+        let install = [
+            "--toplevel",
+            "/run/current-system",
+            "--generated-entries",
+            "/tmp/generated",
+            "--console-mode",
+            "max",
+        ];
+        let hooks = |extra: &[&str]| {
+            let args = install.iter().chain(extra).copied().collect::<Vec<_>>();
+            parse(&args).map(|args| (args.hook, args.dry_run_hooks))
+        };
+
+        assert_eq!(hooks(&[]).unwrap(), (vec![], false));
+        assert_eq!(
+            hooks(&["--hook", "/etc/hooks/attest", "--hook", "upload-hashes"]).unwrap(),
+            (
+                vec![
+                    PathBuf::from("/etc/hooks/attest"),
+                    PathBuf::from("upload-hashes")
+                ],
+                false
+            )
+        );
+        assert_eq!(
+            hooks(&["--hook", "attest", "--dry-run", "--dry-run-hooks"]).unwrap(),
+            (vec![PathBuf::from("attest")], true)
+        );
+        assert!(hooks(&["--hook", "attest", "--dry-run-hooks"]).is_err());
+    }
+
+    #[cfg(feature = "qemu")]
+    #[test]
+    fn test_verify_boot_args() {
+        match parse(&[
+            "verify-boot",
+            "--esp",
+            "/tmp/esp",
+            "--qemu",
+            "qemu-system-x86_64",
+            "--ovmf",
+            "/ovmf/OVMF_CODE.fd,/ovmf/OVMF_VARS.fd",
+            "--qemu-arg",
+            "-enable-kvm",
+        ])
+        .unwrap()
+        .command
+        {
+            Some(Command::VerifyBoot {
+                esp,
+                image,
+                qemu_arg,
+                marker,
+                boot_timeout,
+                ..
+            }) => {
+                assert_eq!(esp, Some(PathBuf::from("/tmp/esp")));
+                assert_eq!(image, None);
+                assert_eq!(qemu_arg, ["-enable-kvm"]);
+                assert!(marker.is_empty());
+                assert_eq!(boot_timeout, Duration::from_secs(300));
+            }
+            command => panic!("{:?}", command),
+        }
+
+        let ovmf = ["--qemu", "qemu", "--ovmf", "code,vars"];
+        let with = |more: &[&str]| {
+            let mut args = vec!["verify-boot"];
+            args.extend(&ovmf);
+            args.extend(more);
+            parse(&args)
+        };
+        assert!(with(&["--image", "/tmp/esp.img"]).is_ok());
+        assert!(with(&[]).is_err());
+        assert!(with(&["--esp", "/tmp/esp", "--image", "/tmp/esp.img"]).is_err());
+    }
+
+    #[test]
+    fn test_pin_args() {
+        match parse(&["pin", "12", "--esp", "/boot"]).unwrap().command {
+            Some(Command::Pin { generation, esp }) => {
+                assert_eq!(generation, 12);
+                assert_eq!(esp, [PathBuf::from("/boot")]);
+            }
+            command => panic!("{:?}", command),
+        }
+        match parse(&["unpin", "12", "--esp", "/boot"]).unwrap().command {
+            Some(Command::Unpin { generation, .. }) => assert_eq!(generation, 12),
+            command => panic!("{:?}", command),
+        }
+        assert!(parse(&["pin", "x", "--esp", "/boot"]).is_err());
+        assert!(parse(&["unpin", "12"]).is_err());
 
-    Ok(())
+        let args = parse(&[
+            "--toplevel",
+            "/run/current-system",
+            "--generated-entries",
+            "/tmp/generated",
+            "--console-mode",
+            "max",
+            "--pin-generation",
+            "3",
+            "--pin-generation",
+            "7",
+        ])
+        .unwrap();
+        assert_eq!(args.pin_generation, [3, 7]);
+    }
 }
@@ -0,0 +1,506 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde_json::Value;
+
+use crate::context::Context;
+use crate::files::FileToReplace;
+use crate::report::Document;
+use crate::Result;
+
+pub use generator::manifest::{Manifest, ManifestFile, MANIFEST, VERSION};
+
+/// What the installer goes by in the generator's [`Manifest`]: whether the staged files made it to
+/// the ESP as the generator staged them, and whether what's on the ESP is a newer release's.
+pub trait ManifestExt: Sized {
+    /// Reads the manifest in `generated_entries`, if the generator wrote one.
+    fn load(generated_entries: &Path) -> Result<Option<Self>>;
+
+    /// Checks that the files that were `copied` to `esp` are what the generator staged.
+    ///
+    /// Files that were `signed` on their way from `generated_entries` to the ESP can't match, so
+    /// they are skipped.
+    fn verify(
+        &self,
+        generated_entries: &Path,
+        esp: &Path,
+        copied: &[PathBuf],
+        signed: &[PathBuf],
+    ) -> Result<()>;
+
+    /// Checks that the files and entries that the generator left out as unchanged are on `root` as
+    /// it recorded them, since they aren't copied there.
+    fn check_left_out(&self, root: &Path) -> Result<()>;
+
+    /// The version the manifest is stamped with, if it's from a newer release (by major or minor
+    /// version) than this one.
+    fn newer_version(&self) -> Result<Option<&str>>;
+
+    /// Checks that none of the files and entries in `to_replace` that the manifest records on
+    /// `root` would be changed, i.e. that what's staged for them hashes to what the manifest's
+    /// generator staged.
+    fn check_unchanged(&self, root: &Path, to_replace: &[FileToReplace]) -> Result<()>;
+
+    /// What the manifest records the file or entry at `relative` (to the root of the staging tree)
+    /// to hash to.
+    fn recorded_sha256(&self, relative: &Path) -> Option<&str>;
+}
+
+impl ManifestExt for Manifest {
+    fn load(generated_entries: &Path) -> Result<Option<Self>> {
+        let path = generated_entries.join(MANIFEST);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path).with_path_context(&path)?;
+        let manifest = Manifest::from_json(&contents).with_path_context(&path)?;
+
+        Ok(Some(manifest))
+    }
+
+    fn verify(
+        &self,
+        generated_entries: &Path,
+        esp: &Path,
+        copied: &[PathBuf],
+        signed: &[PathBuf],
+    ) -> Result<()> {
+        for file in &self.files {
+            let esp_loc = esp.join(&file.path);
+            if !copied.contains(&esp_loc) {
+                continue;
+            }
+
+            if signed.contains(&generated_entries.join(&file.path)) {
+                debug!("not verifying '{}': it was signed", esp_loc.display());
+                continue;
+            }
+
+            let sha256 = self::sha256_file(&esp_loc)?;
+            if sha256 != file.sha256 {
+                return Err(format!(
+                    "'{}' doesn't match what the generator staged (expected SHA-256 {}, found {})",
+                    esp_loc.display(),
+                    file.sha256,
+                    sha256
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_left_out(&self, root: &Path) -> Result<()> {
+        for path in &self.unchanged {
+            let recorded = self.recorded_sha256(Path::new(path));
+            let esp_loc = root.join(path);
+            let found = if esp_loc.exists() {
+                Some(self::sha256_file(&esp_loc)?)
+            } else {
+                None
+            };
+
+            if recorded.is_none() || found.as_deref() != recorded {
+                return Err(format!(
+                    "'{}' was left out of the generated entries as unchanged, but isn't what the \
+                     generator recorded (run the generator without --incremental)",
+                    esp_loc.display()
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn newer_version(&self) -> Result<Option<&str>> {
+        // manifests from before they were stamped have no version
+        if !self.version.is_empty() && self::is_newer_release(&self.version, VERSION)? {
+            Ok(Some(&self.version))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn check_unchanged(&self, root: &Path, to_replace: &[FileToReplace]) -> Result<()> {
+        for file in to_replace {
+            let relative = match file.esp_loc.strip_prefix(root) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            let recorded = match self.recorded_sha256(relative) {
+                Some(recorded) => recorded,
+                None => continue,
+            };
+
+            if self::sha256_file(&file.generated_loc)? != recorded {
+                let version = match self.version.as_str() {
+                    "" => "unknown",
+                    version => version,
+                };
+                return Err(format!(
+                    "refusing to replace '{}', which version {} of the installer put there (pass \
+                     --allow-downgrade-management to replace it anyway)",
+                    file.esp_loc.display(),
+                    version
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recorded_sha256(&self, relative: &Path) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|file| Path::new(&file.path) == relative)
+            .map(|file| file.sha256.as_str())
+            .or_else(|| {
+                relative
+                    .to_str()
+                    .and_then(|relative| self.entries.get(relative))
+                    .map(String::as_str)
+            })
+    }
+}
+
+/// Renames the entry `from` to `to` in the manifest in `generated_entries`, if there is one and it
+/// records `from`, keeping the rest of it as the generator wrote it.
+pub fn rename_entry(generated_entries: &Path, from: &Path, to: &Path) -> Result<()> {
+    let path = generated_entries.join(MANIFEST);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let contents = fs::read_to_string(&path).with_path_context(&path)?;
+    let mut document: Value = serde_json::from_str(&contents).with_path_context(&path)?;
+    let entries = match document.get_mut("entries").and_then(Value::as_object_mut) {
+        Some(entries) => entries,
+        None => return Ok(()),
+    };
+
+    if let Some(sha256) = entries.remove(&from.display().to_string()) {
+        entries.insert(to.display().to_string(), sha256);
+        let contents = serde_json::to_string_pretty(&document)?;
+        fs::write(&path, contents + "\n").with_path_context(&path)?;
+    }
+
+    Ok(())
+}
+
+/// Records `entries` (by their paths relative to `root`, with what they hash to) in the manifest on
+/// `root`, if there is one and it records entries, keeping the rest of it as it is. One that doesn't
+/// record any takes every entry on `root` to be ours already (see `systemd_boot::collision`).
+pub fn record_entries(root: &Path, entries: &BTreeMap<PathBuf, String>) -> Result<()> {
+    let path = root.join(MANIFEST);
+    if entries.is_empty() || !path.exists() {
+        return Ok(());
+    }
+
+    let contents = fs::read_to_string(&path).with_path_context(&path)?;
+    let mut document: Value = serde_json::from_str(&contents).with_path_context(&path)?;
+    let recorded = match document.get_mut("entries").and_then(Value::as_object_mut) {
+        Some(recorded) if !recorded.is_empty() => recorded,
+        _ => return Ok(()),
+    };
+
+    for (entry, sha256) in entries {
+        recorded.insert(entry.display().to_string(), Value::from(sha256.as_str()));
+    }
+    let contents = serde_json::to_string_pretty(&document)?;
+    fs::write(&path, contents + "\n").with_path_context(&path)?;
+
+    Ok(())
+}
+
+/// Whether `version` is from a newer release than `than`, going by their major and minor versions
+/// only (patch releases don't change the conventions for what's on the ESP).
+pub fn is_newer_release(version: &str, than: &str) -> Result<bool> {
+    Ok(self::major_minor(version)? > self::major_minor(than)?)
+}
+
+fn major_minor(version: &str) -> Result<(u64, u64)> {
+    // e.g. 1.2.3, 1.2.3-pre, or 1.2.3+build
+    let core = version.split(&['-', '+'][..]).next().unwrap_or_default();
+    let parts = core
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>();
+
+    match parts.as_deref() {
+        Ok([major, minor, _patch]) => Ok((*major, *minor)),
+        _ => Err(format!("'{}' is not a version", version).into()),
+    }
+}
+
+/// The SHA-256 of the file at `path`, in hex, read a buffer at a time (see [`generator::stream`]).
+pub fn sha256_file(path: &Path) -> Result<String> {
+    generator::stream::sha256_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated_entries = dir.join("generated_entries");
+        let esp = dir.join("esp");
+        fs::create_dir_all(generated_entries.join("loader")).unwrap();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+
+        assert_eq!(Manifest::load(&generated_entries).unwrap(), None);
+
+        fs::write(
+            generated_entries.join(MANIFEST),
+            r#"{
+  "files": [
+    {
+      "path": "EFI/nixos/initrd.efi",
+      "sha256": "09e6c018d2c8c4903308613dd1b72484d57eadf12ec50ddc8f52e5accce470f2",
+      "source": { "path": "/nix/store/initrd", "sha256": "..." }
+    },
+    {
+      "path": "EFI/nixos/unified.efi",
+      "sha256": "0000000000000000000000000000000000000000000000000000000000000000"
+    }
+  ]
+}"#,
+        )
+        .unwrap();
+        let manifest = Manifest::load(&generated_entries).unwrap().unwrap();
+
+        let initrd = esp.join("EFI/nixos/initrd.efi");
+        let unified = esp.join("EFI/nixos/unified.efi");
+        fs::write(&initrd, "initrd").unwrap();
+        fs::write(&unified, "signed unified").unwrap();
+        let signed = [generated_entries.join("EFI/nixos/unified.efi")];
+
+        let copied = [initrd.clone(), unified.clone()];
+        manifest
+            .verify(&generated_entries, &esp, &copied, &signed)
+            .unwrap();
+
+        // e.g. the copy was interrupted
+        fs::write(&initrd, "init").unwrap();
+        let err = manifest
+            .verify(&generated_entries, &esp, &copied, &signed)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&initrd.display().to_string()));
+
+        // files that weren't copied in this run aren't checked
+        manifest
+            .verify(&generated_entries, &esp, &[unified], &signed)
+            .unwrap();
+
+        // nor is a manifest of a newer format version than the generator of this release writes
+        fs::write(
+            generated_entries.join(MANIFEST),
+            r#"{ "formatVersion": 999, "files": [] }"#,
+        )
+        .unwrap();
+        let err = Manifest::load(&generated_entries).unwrap_err().to_string();
+        assert!(err.contains("format version 999"), "{}", err);
+    }
+
+    #[test]
+    fn test_is_newer_release() {
+        assert!(is_newer_release("0.2.0", "0.1.9").unwrap());
+        assert!(is_newer_release("1.0.0", "0.9.0").unwrap());
+        assert!(is_newer_release("0.2.0-pre", "0.1.0").unwrap());
+        assert!(is_newer_release("0.10.0+abcdef", "0.9.3").unwrap());
+        // patch releases don't count
+        assert!(!is_newer_release("0.1.9", "0.1.0").unwrap());
+        assert!(!is_newer_release("0.1.0", "0.1.0").unwrap());
+        assert!(!is_newer_release("0.1.0", "0.2.0").unwrap());
+
+        for version in ["", "1", "1.2", "1.2.3.4", "v1.2.3", "1.x.3"] {
+            assert!(is_newer_release(version, VERSION).is_err(), "{:?}", version);
+        }
+
+        let manifest = |version: Option<&str>| Manifest {
+            version: version.map(ToString::to_string).unwrap_or_default(),
+            ..Default::default()
+        };
+        assert_eq!(manifest(None).newer_version().unwrap(), None);
+        assert_eq!(manifest(Some(VERSION)).newer_version().unwrap(), None);
+        assert_eq!(
+            manifest(Some("999.0.0")).newer_version().unwrap(),
+            Some("999.0.0")
+        );
+    }
+
+    #[test]
+    fn test_check_unchanged() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated_entries = dir.join("generated_entries");
+        let esp = dir.join("esp");
+        fs::create_dir_all(generated_entries.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+
+        // as written by a newer release
+        let newer: Manifest = serde_json::from_str(
+            r#"{
+  "version": "999.0.0",
+  "files": [
+    {
+      "path": "EFI/nixos/initrd.efi",
+      "sha256": "09e6c018d2c8c4903308613dd1b72484d57eadf12ec50ddc8f52e5accce470f2"
+    }
+  ],
+  "entries": {
+    "loader/entries/nixos-generation-1.conf": "f2a871dee1fb2421b2b9e47926fc2e4b479cd189ad7a9237bc6477cadb444eb4"
+  }
+}"#,
+        )
+        .unwrap();
+
+        let replace = |file: &str, contents: &str| {
+            let generated_loc = generated_entries.join(file);
+            fs::write(&generated_loc, contents).unwrap();
+            FileToReplace {
+                generated_loc,
+                esp_loc: esp.join(file),
+            }
+        };
+
+        // the same file, and files the newer release didn't record, can be replaced
+        let to_replace = [
+            replace("EFI/nixos/initrd.efi", "initrd"),
+            replace("EFI/nixos/other.efi", "other"),
+            replace("loader/entries/nixos-generation-1.conf", "title NixOS\n"),
+        ];
+        newer.check_unchanged(&esp, &to_replace).unwrap();
+
+        let to_replace = [replace("EFI/nixos/initrd.efi", "older initrd")];
+        let err = newer
+            .check_unchanged(&esp, &to_replace)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("version 999.0.0"), "{}", err);
+        assert!(err.contains("--allow-downgrade-management"), "{}", err);
+
+        // and so are entries
+        let to_replace = [replace(
+            "loader/entries/nixos-generation-1.conf",
+            "title NixOS (older)\n",
+        )];
+        let err = newer
+            .check_unchanged(&esp, &to_replace)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("nixos-generation-1.conf"), "{}", err);
+
+        // files on another partition aren't the manifest's
+        newer
+            .check_unchanged(&dir.join("xbootldr"), &to_replace)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_check_left_out() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::write(esp.join("EFI/nixos/initrd.efi"), "initrd").unwrap();
+        fs::write(
+            esp.join("loader/entries/nixos-generation-1.conf"),
+            "title NixOS\n",
+        )
+        .unwrap();
+
+        // as written by `--incremental`
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+  "files": [
+    {
+      "path": "EFI/nixos/initrd.efi",
+      "sha256": "09e6c018d2c8c4903308613dd1b72484d57eadf12ec50ddc8f52e5accce470f2"
+    }
+  ],
+  "entries": {
+    "loader/entries/nixos-generation-1.conf": "a91d5a4cf3d9a5a3b9a1e2bc43e8ee4a4bda2f0a52e6d9a2f3a4e8b8e4a5d0c1"
+  },
+  "unchanged": ["EFI/nixos/initrd.efi"]
+}"#,
+        )
+        .unwrap();
+        manifest.check_left_out(esp).unwrap();
+        assert!(Manifest::default().check_left_out(esp).is_ok());
+
+        // e.g. the generated entries were generated against another ESP
+        fs::write(esp.join("EFI/nixos/initrd.efi"), "another initrd").unwrap();
+        let err = manifest.check_left_out(esp).unwrap_err().to_string();
+        assert!(err.contains("--incremental"), "{}", err);
+        fs::remove_file(esp.join("EFI/nixos/initrd.efi")).unwrap();
+        assert!(manifest.check_left_out(esp).is_err());
+
+        // entries are checked against what they hashed to, too
+        let manifest = Manifest {
+            unchanged: vec![String::from("loader/entries/nixos-generation-1.conf")],
+            ..manifest
+        };
+        assert!(manifest.check_left_out(esp).is_err());
+        let manifest = Manifest {
+            entries: [(
+                String::from("loader/entries/nixos-generation-1.conf"),
+                sha256_file(&esp.join("loader/entries/nixos-generation-1.conf")).unwrap(),
+            )]
+            .into(),
+            ..manifest
+        };
+        manifest.check_left_out(esp).unwrap();
+    }
+
+    #[test]
+    fn test_rename_entry() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generated_entries = tempdir.path();
+        let (from, to) = (
+            Path::new("loader/entries/nixos-generation-1.conf"),
+            Path::new("loader/entries/nixos-generation-1-deconflicted.conf"),
+        );
+        // there's nothing to rename
+        rename_entry(generated_entries, from, to).unwrap();
+
+        fs::create_dir_all(generated_entries.join("loader")).unwrap();
+        fs::write(
+            generated_entries.join(MANIFEST),
+            r#"{
+  "formatVersion": 1,
+  "files": [],
+  "entries": { "loader/entries/nixos-generation-1.conf": "abcd" }
+}"#,
+        )
+        .unwrap();
+        rename_entry(generated_entries, from, to).unwrap();
+
+        let manifest = Manifest::load(generated_entries).unwrap().unwrap();
+        assert_eq!(
+            manifest.entries,
+            [(to.display().to_string(), String::from("abcd"))].into()
+        );
+        let document: Value =
+            serde_json::from_str(&fs::read_to_string(generated_entries.join(MANIFEST)).unwrap())
+                .unwrap();
+        assert_eq!(document["formatVersion"], 1);
+
+        // already renamed
+        rename_entry(generated_entries, from, to).unwrap();
+        assert_eq!(
+            Manifest::load(generated_entries).unwrap().unwrap(),
+            manifest
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use generator::report;
+
+use crate::cli_common;
+use crate::Args;
+
+/// How long a bootloader waits before booting the default entry.
+///
+/// Every backend maps this onto its own configuration:
+///
+/// - systemd-boot: `timeout <seconds>` (where 0 hides the menu unless a key is held) or
+///   `timeout menu-force` to wait forever
+/// - GRUB: `set timeout=<seconds>` (where 0 boots without showing the menu) or `set timeout=-1` to
+///   wait forever
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Timeout {
+    Seconds(u32),
+    Forever,
+}
+
+impl FromStr for Timeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "forever" {
+            return Ok(Timeout::Forever);
+        }
+
+        let seconds = cli_common::parse_duration(s)
+            .map_err(|e| format!("{} (or 'forever')", e))?
+            .as_secs();
+
+        u32::try_from(seconds)
+            .map(Timeout::Seconds)
+            .map_err(|_| format!("invalid timeout '{}': it's too long", s))
+    }
+}
+
+impl From<Timeout> for report::Timeout {
+    fn from(timeout: Timeout) -> Self {
+        match timeout {
+            Timeout::Seconds(seconds) => report::Timeout::Seconds(seconds),
+            Timeout::Forever => report::Timeout::Forever,
+        }
+    }
+}
+
+/// The options every bootloader backend understands, from its arguments (systemd-boot's [`Args`],
+/// or `installer grub`'s).
+///
+/// Backends apply their own overrides (e.g. `--systemd-boot-timeout` or `--grub-timeout`) on top
+/// of these.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CommonBootloaderOptions {
+    /// `None` leaves the timeout up to the bootloader
+    pub timeout: Option<Timeout>,
+    /// The entry to boot by default instead of the `--toplevel`'s
+    pub default_entry: Option<String>,
+    /// The maximum number of generations to keep entries for
+    pub configuration_limit: Option<usize>,
+}
+
+impl CommonBootloaderOptions {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        Self {
+            timeout: args.timeout,
+            default_entry: args.default_entry.clone(),
+            configuration_limit: args.configuration_limit,
+        }
+    }
+
+    /// Uses a backend's `timeout`, if it has one, instead of the common one.
+    pub(crate) fn with_timeout(self, timeout: Option<Timeout>) -> Self {
+        Self {
+            timeout: timeout.or(self.timeout),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!("0".parse(), Ok(Timeout::Seconds(0)));
+        assert_eq!("5".parse(), Ok(Timeout::Seconds(5)));
+        assert_eq!("4294967295".parse(), Ok(Timeout::Seconds(u32::MAX)));
+        assert_eq!("forever".parse(), Ok(Timeout::Forever));
+        assert_eq!("30s".parse(), Ok(Timeout::Seconds(30)));
+        assert_eq!("2m".parse(), Ok(Timeout::Seconds(120)));
+        assert!("-1".parse::<Timeout>().unwrap_err().contains("'forever'"));
+        assert!("4294967296".parse::<Timeout>().is_err());
+        assert!("49711d"
+            .parse::<Timeout>()
+            .unwrap_err()
+            .contains("too long"));
+        assert!("5M".parse::<Timeout>().is_err());
+        assert!("".parse::<Timeout>().is_err());
+    }
+
+    #[test]
+    fn test_merge_options() {
+        let args = Args {
+            timeout: Some(Timeout::Seconds(5)),
+            default_entry: Some(String::from("nixos-chainload-windows")),
+            configuration_limit: Some(10),
+            ..Default::default()
+        };
+        let common = CommonBootloaderOptions::from_args(&args);
+        assert_eq!(
+            common,
+            CommonBootloaderOptions {
+                timeout: Some(Timeout::Seconds(5)),
+                default_entry: Some(String::from("nixos-chainload-windows")),
+                configuration_limit: Some(10),
+            }
+        );
+
+        // a backend's override wins, but only if it's set
+        assert_eq!(common.clone().with_timeout(None), common);
+        assert_eq!(
+            common.clone().with_timeout(Some(Timeout::Forever)).timeout,
+            Some(Timeout::Forever)
+        );
+        assert_eq!(
+            CommonBootloaderOptions::default()
+                .with_timeout(Some(Timeout::Seconds(0)))
+                .timeout,
+            Some(Timeout::Seconds(0))
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Turns a panic into a final structured error record (and a `--report`, if one was asked for),
+//! instead of leaving whatever runs the installer with nothing but a backtrace.
+//!
+//! The record and hook are the generator's: the panic is caught with [`catch`], so the run can
+//! still write its report, and `main` exits with [`EXIT_INTERNAL_ERROR`].
+
+use std::sync::Mutex;
+
+pub(crate) use generator::panic_hook::{PanicRecord, EXIT_INTERNAL_ERROR};
+
+use crate::secure_boot::VerifyCache;
+
+/// What the run has gotten up to, for the record of a panic, and what its stages share.
+#[derive(Debug, Default)]
+pub(crate) struct RunContext {
+    stage: Mutex<Option<&'static str>>,
+    /// The files that were already verified, and how that went
+    pub verified: VerifyCache,
+}
+
+impl RunContext {
+    /// Records that the plan state named `stage` is running.
+    pub(crate) fn enter(&self, stage: &'static str) {
+        *self.stage.lock().unwrap_or_else(|e| e.into_inner()) = Some(stage);
+    }
+
+    /// The last plan state that ran, if any did.
+    pub(crate) fn last_stage(&self) -> Option<&'static str> {
+        *self.stage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Runs `f`, turning a panic into a [`PanicRecord`] that has the last stage `ctx` reached.
+pub(crate) fn catch<T>(ctx: &RunContext, f: impl FnOnce() -> T) -> Result<T, PanicRecord> {
+    generator::panic_hook::catch_staged(|| ctx.last_stage(), f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch() {
+        let ctx = RunContext::default();
+        assert_eq!(catch(&ctx, || 42).unwrap(), 42);
+
+        let stub_stage = |ctx: &RunContext| -> crate::Result<()> {
+            ctx.enter("stub");
+            let digest = String::from("abc");
+            // the kind of slicing that has panicked in the wild
+            let _ = &digest[..32];
+
+            Ok(())
+        };
+        let record = catch(&ctx, || stub_stage(&ctx)).unwrap_err();
+
+        assert_eq!(record.stage, Some("stub"));
+        assert!(
+            record.message.contains("out of range"),
+            "{}",
+            record.message
+        );
+        let location = record.location.clone().unwrap();
+        assert!(location.contains("panic_hook.rs:"), "{}", location);
+        assert!(record.to_string().contains("during stub"));
+
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["level"], "error");
+        assert_eq!(json["kind"], "panic");
+        assert_eq!(json["stage"], "stub");
+        assert_eq!(json["message"], record.message.as_str());
+        assert_eq!(json["location"], location.as_str());
+
+        let record = catch::<()>(&RunContext::default(), || panic!("no stage")).unwrap_err();
+        assert_eq!(record.message, "no stage");
+        assert_eq!(record.stage, None);
+    }
+}
@@ -0,0 +1,157 @@
+//! The `--report` written at the end of every run, for archiving what it did to the ESP(s).
+//!
+//! Its models are shared with the generator, in `generator::report`, along with the rules on its
+//! `formatVersion`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub(crate) use generator::report::{
+    Document, EspReport, HookReport, InitrdSecretsReport, Outcome, ResolvedConfig, RunReport,
+    StageReport, WarningReport,
+};
+
+use crate::manifest;
+use crate::options::CommonBootloaderOptions;
+use crate::util::Generation;
+use crate::{Args, Result};
+
+/// The configuration the run ended up with, after applying defaults and overrides.
+pub(crate) fn resolved_config(
+    args: &Args,
+    options: &CommonBootloaderOptions,
+    esps: &[PathBuf],
+    default_generation: &Generation,
+    wanted_generations: &[Generation],
+) -> ResolvedConfig {
+    ResolvedConfig {
+        toplevel: args.toplevel.clone(),
+        default_generation: default_generation.idx,
+        wanted_generations: wanted_generations.iter().map(|g| g.idx).collect(),
+        esps: esps.to_vec(),
+        install: args.install,
+        dry_run: args.dry_run,
+        ignore_dirty_esp: args.ignore_dirty_esp,
+        timeout: options.timeout.map(Into::into),
+        default_entry: options.default_entry.clone(),
+        configuration_limit: options.configuration_limit,
+        editor: args.editor,
+        console_mode: args.console_mode.clone(),
+        unified_efi: args.unified_efi,
+        secure_boot: args.signing_key.is_some(),
+        chainloads: args.chainload.iter().map(|c| c.name.clone()).collect(),
+        retired_profiles: args.retire_profile.clone(),
+    }
+}
+
+/// The SHA-256 of every file in `dir`, by its path relative to `dir`.
+pub(crate) fn hash_tree(dir: &Path) -> Result<BTreeMap<PathBuf, String>> {
+    let mut hashes = BTreeMap::new();
+
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        hashes.insert(
+            path.strip_prefix(dir)?.to_path_buf(),
+            manifest::sha256_file(path)?,
+        );
+    }
+
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use generator::report::{Change, FileChange, Summary};
+
+    #[test]
+    fn test_record_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::write(esp.join("EFI/nixos/kept.efi"), "kept").unwrap();
+        fs::write(esp.join("EFI/nixos/replaced.efi"), "old").unwrap();
+        fs::write(esp.join("EFI/nixos/pruned.efi"), "pruned").unwrap();
+        let before = hash_tree(esp).unwrap();
+
+        fs::write(esp.join("EFI/nixos/replaced.efi"), "new").unwrap();
+        fs::remove_file(esp.join("EFI/nixos/pruned.efi")).unwrap();
+        fs::write(esp.join("EFI/nixos/added.efi"), "added").unwrap();
+        let after = hash_tree(esp).unwrap();
+
+        let mut report = EspReport {
+            esp: esp.to_path_buf(),
+            ..Default::default()
+        };
+        let signed = std::iter::once(esp.join("EFI/nixos/added.efi")).collect();
+        report.record_files(&before, &after, &signed);
+
+        let sha256 = |s: &str| Some(manifest::sha256_file(&esp.join(s)).unwrap());
+        assert_eq!(
+            report.files,
+            vec![
+                FileChange {
+                    path: PathBuf::from("EFI/nixos/added.efi"),
+                    change: Change::Added,
+                    signed: true,
+                    sha256_before: None,
+                    sha256_after: sha256("EFI/nixos/added.efi"),
+                },
+                FileChange {
+                    path: PathBuf::from("EFI/nixos/pruned.efi"),
+                    change: Change::Pruned,
+                    signed: false,
+                    sha256_before: before.get(Path::new("EFI/nixos/pruned.efi")).cloned(),
+                    sha256_after: None,
+                },
+                FileChange {
+                    path: PathBuf::from("EFI/nixos/replaced.efi"),
+                    change: Change::Replaced,
+                    signed: false,
+                    sha256_before: before.get(Path::new("EFI/nixos/replaced.efi")).cloned(),
+                    sha256_after: sha256("EFI/nixos/replaced.efi"),
+                },
+            ]
+        );
+
+        let mut run = RunReport::start(env!("CARGO_PKG_VERSION"));
+        run.esps.push(report);
+        run.finish(&Ok(()));
+        assert_eq!(
+            run.summary,
+            Summary {
+                added: 1,
+                replaced: 1,
+                pruned: 1,
+                signed: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("report.json");
+        fs::write(&path, "previous report").unwrap();
+
+        let mut report = RunReport::start(env!("CARGO_PKG_VERSION"));
+        report.finish(&Err("the ESP is full".into()));
+        report.write(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["formatVersion"], RunReport::FORMAT_VERSION);
+        assert_eq!(json["error"], "the ESP is full");
+        assert_eq!(json["config"], serde_json::Value::Null);
+        assert_eq!(json["esps"], serde_json::json!([]));
+        // nothing but the report is left behind
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+}
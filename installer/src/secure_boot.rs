@@ -1,35 +1,484 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use generator::stream;
+use glob::{MatchOptions, Pattern};
 
 use log::debug;
 
+use crate::command;
+use crate::context::Context;
 use crate::Result;
 
+/// The patched sbattach (see `patched-sbattach.nix`) embedded at build time, if any.
+const EMBEDDED_SBATTACH: Option<&str> = option_env!("PATCHED_SBATTACH_BINARY");
+/// The index of the certificate table, where signatures go, in a PE image's data directories.
+const CERTIFICATE_TABLE: usize = 4;
+/// How much of the start of a PE image is read to find its certificate table and checksum, which are
+/// in its headers.
+const HEADERS_LEN: u64 = 4096;
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct SigningInfo {
+    /// The key for the files that no rule matches
     pub signing_key: PathBuf,
+    /// The certificate that goes with `signing_key`
     pub signing_cert: PathBuf,
+    /// Which files to sign with another key, in order of precedence
+    pub rules: Vec<SigningRule>,
     pub sbsign: PathBuf,
     pub sbverify: PathBuf,
+    /// The patched sbattach, which pads unsigned files so they can be compared to signed ones
+    pub sbattach: PathBuf,
+}
+
+/// A signing key, and the certificate to verify what it signed with.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct KeyPair {
+    pub key: PathBuf,
+    pub cert: PathBuf,
+}
+
+/// A `--signing-rule`: the files whose path on the ESP matches `pattern` are signed with `pair`.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct SigningRule {
+    pub pattern: Pattern,
+    pub pair: KeyPair,
+}
+
+impl FromStr for SigningRule {
+    type Err = String;
+
+    /// Parses `glob=key:cert`, where `glob` matches paths relative to the root of the ESP.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (glob, pair) = s
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form glob=key:cert", s))?;
+        let (key, cert) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("'{}' is not of the form glob=key:cert", s))?;
+
+        let glob = glob.trim_start_matches('/');
+        if glob.is_empty() || key.is_empty() || cert.is_empty() {
+            return Err(format!("'{}' must have a non-empty glob, key, and cert", s));
+        }
+        let pattern =
+            Pattern::new(glob).map_err(|e| format!("'{}' is not a valid glob: {}", glob, e))?;
+
+        Ok(SigningRule {
+            pattern,
+            pair: KeyPair {
+                key: PathBuf::from(key),
+                cert: PathBuf::from(cert),
+            },
+        })
+    }
+}
+
+impl SigningRule {
+    /// Whether the rule applies to `path` (relative to the root of the ESP), ignoring case like
+    /// FAT does.
+    pub fn matches(&self, path: &Path) -> bool {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+
+        self.pattern.matches_path_with(path, options)
+    }
+}
+
+/// The outcomes of verifying files during a run, so that a file that several stages check (e.g.
+/// whether it needs signing, and whether it needs replacing) is only run through sbverify once.
+///
+/// A file is looked up by its size and mtime as well as its path, but the mtime may not change when
+/// a file is rewritten quickly enough, so whatever modifies a file has to [`invalidate`] it too.
+///
+/// [`invalidate`]: VerifyCache::invalidate
+#[derive(Debug, Default)]
+pub struct VerifyCache {
+    outcomes: Mutex<HashMap<VerifyKey, bool>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct VerifyKey {
+    file: PathBuf,
+    size: u64,
+    mtime: SystemTime,
+    cert: PathBuf,
+}
+
+impl VerifyCache {
+    /// Verifies `file` with `cert` like [`SigningInfo::verify_file`], unless it was already
+    /// verified (and hasn't changed since).
+    pub fn verify_file(&self, signing_info: &SigningInfo, file: &Path, cert: &Path) -> Result<()> {
+        let key = match fs::metadata(file).and_then(|m| Ok((m.len(), m.modified()?))) {
+            Ok((size, mtime)) => VerifyKey {
+                file: file.to_path_buf(),
+                size,
+                mtime,
+                cert: cert.to_path_buf(),
+            },
+            // let sbverify report what's wrong with it
+            Err(_) => return signing_info.verify_file(file, cert),
+        };
+
+        let cached = self.lock().get(&key).copied();
+        let verified = match cached {
+            Some(verified) => {
+                debug!(
+                    "'{}' was already verified with '{}'",
+                    file.display(),
+                    cert.display()
+                );
+                verified
+            }
+            None => {
+                // Not holding the lock while sbverify runs, so other files can be looked up.
+                let verified = signing_info.verified(file, cert)?;
+                self.lock().insert(key, verified);
+                verified
+            }
+        };
+
+        if !verified {
+            return Err(format!("{} could not be verified", file.display()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Forgets every outcome for `file`, which was just modified (e.g. signed, or copied over).
+    pub fn invalidate(&self, file: &Path) {
+        self.lock().retain(|key, _| key.file != file);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<VerifyKey, bool>> {
+        self.outcomes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Picks the `--sbattach` passed at runtime, falling back to the one embedded at build time.
+pub fn sbattach_path(sbattach: Option<&Path>) -> Result<PathBuf> {
+    self::resolve_sbattach(sbattach, EMBEDDED_SBATTACH)
+}
+
+fn resolve_sbattach(sbattach: Option<&Path>, embedded: Option<&str>) -> Result<PathBuf> {
+    match (sbattach, embedded) {
+        (Some(sbattach), _) => Ok(sbattach.to_path_buf()),
+        (None, Some(embedded)) => Ok(PathBuf::from(embedded)),
+        (None, None) => Err(
+            "no patched sbattach was embedded at build time; pass one with --sbattach in order to sign files"
+                .into(),
+        ),
+    }
+}
+
+/// Whether `contents` is a signed PE image, i.e. one with a certificate table.
+pub fn is_signed(contents: &[u8]) -> bool {
+    self::certificate_table(contents, contents.len()).is_some()
+}
+
+/// Whether the file at `path` is a signed PE image (see [`is_signed`]), going by its headers
+/// rather than reading it whole.
+pub fn is_signed_file(path: &Path) -> Result<bool> {
+    let (headers, len) =
+        self::headers(&mut File::open(path).with_path_context(path)?).with_path_context(path)?;
+
+    Ok(self::certificate_table(&headers, len).is_some())
+}
+
+/// `contents` of a PE image without what signing it changes, so that a signed image can be compared
+/// to an unsigned one without sbattach (e.g. when nothing is signed anymore, but the ESP still has
+/// files from when something was): the certificate table and its entry, and the checksum, are left
+/// out, and the rest is padded to 8 bytes like sbsign does before appending the table.
+pub fn without_signature(contents: &[u8]) -> Vec<u8> {
+    let mut unsigned = Vec::with_capacity(contents.len() + 7);
+    Unsigned::new(contents, contents.len())
+        .for_each_chunk(contents, |chunk| unsigned.extend_from_slice(chunk))
+        .expect("reading from memory can't fail");
+
+    unsigned
+}
+
+/// Feeds the file at `path` without what signing it changes (see [`without_signature`]) to `f`, a
+/// buffer at a time, so that large images can be compared without reading them whole.
+pub fn for_each_unsigned_chunk(path: &Path, f: impl FnMut(&[u8])) -> Result<()> {
+    let mut file = File::open(path).with_path_context(path)?;
+    let (headers, len) = self::headers(&mut file).with_path_context(path)?;
+    file.seek(SeekFrom::Start(0)).with_path_context(path)?;
+
+    Unsigned::new(&headers, len)
+        .for_each_chunk(file, f)
+        .with_path_context(path)
+}
+
+/// The start of `file` (which has everything a PE image's certificate table and checksum are found
+/// with), and its length.
+fn headers(file: &mut File) -> io::Result<(Vec<u8>, usize)> {
+    let len = file.metadata()?.len() as usize;
+    let mut headers = Vec::new();
+    file.take(HEADERS_LEN).read_to_end(&mut headers)?;
+
+    Ok((headers, len))
+}
+
+/// What's left of a PE image without what signing it changes: its first `len` bytes, with the
+/// `zeroed` ranges zeroed, padded to 8 bytes.
+struct Unsigned {
+    len: usize,
+    zeroed: Vec<Range<usize>>,
+}
+
+impl Unsigned {
+    /// Of the `len` byte PE image that starts with `headers`.
+    fn new(headers: &[u8], len: usize) -> Self {
+        let (len, mut zeroed) = match self::certificate_table(headers, len) {
+            Some(table) => (table.offset, vec![table.entry..table.entry + 8]),
+            None => (len, Vec::new()),
+        };
+        if let Some(checksum) = self::pe_optional_header(headers).map(|optional| optional + 64) {
+            if checksum + 4 <= len {
+                zeroed.push(checksum..checksum + 4);
+            }
+        }
+
+        Self { len, zeroed }
+    }
+
+    /// Feeds what's left of the image `reader` reads (from its start) to `f`, a buffer at a time.
+    fn for_each_chunk(&self, reader: impl Read, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+        let mut offset = 0;
+        stream::for_each_chunk(reader.take(self.len as u64), |chunk| {
+            let mut chunk = Cow::Borrowed(chunk);
+            for range in &self.zeroed {
+                let start = range.start.max(offset);
+                let end = range.end.min(offset + chunk.len());
+                if start < end {
+                    chunk.to_mut()[start - offset..end - offset].fill(0);
+                }
+            }
+            offset += chunk.len();
+            f(&chunk);
+        })?;
+
+        let padding = ((offset + 7) & !7) - offset;
+        if padding > 0 {
+            f(&[0; 8][..padding]);
+        }
+
+        Ok(())
+    }
+}
+
+/// Where a PE image's certificate table is.
+struct CertificateTable {
+    /// The offset of its entry in the data directories
+    entry: usize,
+    /// The offset of the table itself
+    offset: usize,
+}
+
+/// The certificate table of the `len` byte PE image that starts with `headers`, if it's one and it
+/// has a (non-empty) one.
+fn certificate_table(headers: &[u8], len: usize) -> Option<CertificateTable> {
+    let u32_at = |offset: usize| {
+        let bytes = headers.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+
+    let optional = self::pe_optional_header(headers)?;
+    let (count, directories) = match headers.get(optional..optional + 2)? {
+        [0x0b, 0x01] => (optional + 92, optional + 96), // PE32
+        [0x0b, 0x02] => (optional + 108, optional + 112), // PE32+
+        _ => return None,
+    };
+    if u32_at(count)? <= CERTIFICATE_TABLE {
+        return None;
+    }
+
+    let entry = directories + CERTIFICATE_TABLE * 8;
+    // Unlike the other data directories, this one has a file offset rather than an address.
+    let offset = u32_at(entry)?;
+    let size = u32_at(entry + 4)?;
+    if size == 0 || offset == 0 || offset > len {
+        return None;
+    }
+
+    Some(CertificateTable { entry, offset })
+}
+
+/// The offset of the optional header of the PE image `contents`, if it's one.
+fn pe_optional_header(contents: &[u8]) -> Option<usize> {
+    if contents.get(..2)? != b"MZ" {
+        return None;
+    }
+
+    let pe = contents.get(0x3c..0x40)?;
+    let pe = u32::from_le_bytes([pe[0], pe[1], pe[2], pe[3]]) as usize;
+    if contents.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    Some(pe + 24)
+}
+
+/// Builds the smallest PE32+ EFI application that sbsign accepts: headers and a single `.text`
+/// section containing `ret`. It's only ever signed and verified, never run.
+pub(crate) fn test_image() -> Vec<u8> {
+    const FILE_ALIGNMENT: u32 = 0x200;
+    const SECTION_ALIGNMENT: u32 = 0x1000;
+
+    let mut image = vec![0u8; 2 * FILE_ALIGNMENT as usize];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+
+    // DOS header, pointing at the PE header
+    put(0x00, b"MZ");
+    put(0x3c, &0x40u32.to_le_bytes());
+
+    // PE signature and COFF header: x86_64, 1 section, 240 byte optional header,
+    // executable + large address aware
+    put(0x40, b"PE\0\0");
+    put(0x44, &0x8664u16.to_le_bytes());
+    put(0x46, &1u16.to_le_bytes());
+    put(0x54, &240u16.to_le_bytes());
+    put(0x56, &0x0022u16.to_le_bytes());
+
+    // PE32+ optional header
+    let optional = 0x58;
+    put(optional, &0x20bu16.to_le_bytes());
+    put(optional + 4, &FILE_ALIGNMENT.to_le_bytes()); // SizeOfCode
+    put(optional + 16, &SECTION_ALIGNMENT.to_le_bytes()); // AddressOfEntryPoint
+    put(optional + 20, &SECTION_ALIGNMENT.to_le_bytes()); // BaseOfCode
+    put(optional + 32, &SECTION_ALIGNMENT.to_le_bytes());
+    put(optional + 36, &FILE_ALIGNMENT.to_le_bytes());
+    put(optional + 56, &(2 * SECTION_ALIGNMENT).to_le_bytes()); // SizeOfImage
+    put(optional + 60, &FILE_ALIGNMENT.to_le_bytes()); // SizeOfHeaders
+    put(optional + 68, &10u16.to_le_bytes()); // Subsystem: EFI application
+    put(optional + 108, &16u32.to_le_bytes()); // NumberOfRvaAndSizes
+
+    // .text section header: code, executable, readable
+    let section = optional + 240;
+    put(section, b".text\0\0\0");
+    put(section + 8, &FILE_ALIGNMENT.to_le_bytes()); // VirtualSize
+    put(section + 12, &SECTION_ALIGNMENT.to_le_bytes()); // VirtualAddress
+    put(section + 16, &FILE_ALIGNMENT.to_le_bytes()); // SizeOfRawData
+    put(section + 20, &FILE_ALIGNMENT.to_le_bytes()); // PointerToRawData
+    put(section + 36, &0x6000_0020u32.to_le_bytes());
+
+    // ret
+    put(FILE_ALIGNMENT as usize, &[0xc3]);
+
+    image
+}
+
+pub(crate) fn check_executable(tool: &Path, flag: &str) -> Result<()> {
+    let executable = fs::metadata(tool)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false);
+
+    if !executable {
+        return Err(format!("{} '{}' is not executable", flag, tool.display()).into());
+    }
+
+    Ok(())
 }
 
 impl SigningInfo {
-    pub fn sign_file(&self, file: &Path) -> Result<()> {
+    /// Makes sure signing will work before anything is done to the ESP, by signing and verifying a
+    /// test image with every key and certificate, and the provided tools.
+    pub fn preflight(&self) -> Result<()> {
+        self::check_executable(&self.sbsign, "--sbsign")?;
+        self::check_executable(&self.sbverify, "--sbverify")?;
+        self::check_executable(&self.sbattach, "--sbattach")?;
+
+        let tempdir = tempfile::tempdir()?;
+        let image = tempdir.path().join("preflight.efi");
+
+        for pair in self.pairs() {
+            fs::write(&image, self::test_image()).with_path_context(&image)?;
+
+            if let Err(e) = self.sign_file(&image, &pair) {
+                return Err(format!(
+                    "couldn't sign a test image with signing key '{}' and certificate '{}' (do they match?): {}",
+                    pair.key.display(),
+                    pair.cert.display(),
+                    e
+                )
+                .into());
+            }
+
+            if self.verify_file(&image, &pair.cert).is_err() {
+                return Err(format!(
+                    "signing key '{}' and certificate '{}' do not match",
+                    pair.key.display(),
+                    pair.cert.display()
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The `--signing-key` and `--signing-cert`, for the files that no rule matches.
+    pub fn default_pair(&self) -> KeyPair {
+        KeyPair {
+            key: self.signing_key.clone(),
+            cert: self.signing_cert.clone(),
+        }
+    }
+
+    /// Every key pair that files may be signed with, without duplicates.
+    pub fn pairs(&self) -> Vec<KeyPair> {
+        let mut pairs = vec![self.default_pair()];
+        for rule in &self.rules {
+            if !pairs.contains(&rule.pair) {
+                pairs.push(rule.pair.clone());
+            }
+        }
+
+        pairs
+    }
+
+    /// The key pair for `path` (relative to the root of the ESP): the first rule's that matches
+    /// it, or else the default one.
+    pub fn pair_for(&self, path: &Path) -> KeyPair {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path))
+            .map(|rule| rule.pair.clone())
+            .unwrap_or_else(|| self.default_pair())
+    }
+
+    pub fn sign_file(&self, file: &Path, pair: &KeyPair) -> Result<()> {
         let args = &[
             "--key",
-            &self.signing_key.display().to_string(),
+            &pair.key.display().to_string(),
             "--cert",
-            &self.signing_cert.display().to_string(),
+            &pair.cert.display().to_string(),
             "--output",
             &file.display().to_string(),
             &file.display().to_string(),
         ];
         debug!("running `{}` with args `{:?}`", self.sbsign.display(), args);
-        let status = Command::new(&self.sbsign)
-            .args(args)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
+        let status = command::status(
+            Command::new(&self.sbsign)
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?;
 
         if !status.success() {
             return Err(format!("{} could not be signed", file.display()).into());
@@ -38,10 +487,19 @@ impl SigningInfo {
         Ok(())
     }
 
-    pub fn verify_file(&self, file: &Path) -> Result<()> {
+    pub fn verify_file(&self, file: &Path, cert: &Path) -> Result<()> {
+        if !self.verified(file, cert)? {
+            return Err(format!("{} could not be verified", file.display()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Whether `file` verifies with `cert`, or an error if sbverify couldn't be run at all.
+    fn verified(&self, file: &Path, cert: &Path) -> Result<bool> {
         let args = &[
             "--cert",
-            &self.signing_cert.display().to_string(),
+            &cert.display().to_string(),
             &file.display().to_string(),
         ];
         debug!(
@@ -49,16 +507,330 @@ impl SigningInfo {
             self.sbverify.display(),
             args
         );
-        let status = Command::new(&self.sbverify)
-            .args(args)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
+        let status = command::status(
+            Command::new(&self.sbverify)
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?;
+
+        Ok(status.success())
+    }
+
+    pub fn remove_signature(&self, file: &Path) -> Result<()> {
+        let args = &["--remove", &file.display().to_string()];
+        debug!(
+            "running `{}` with args `{:?}`",
+            self.sbattach.display(),
+            args
+        );
+        let status = command::status(
+            Command::new(&self.sbattach)
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?;
 
         if !status.success() {
-            return Err(format!("{} could not be verified", file.display()).into());
+            return Err(format!("failed to remove signature from '{}'", file.display()).into());
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// `image` signed the way sbsign does it: padded to 8 bytes, with a certificate table appended
+    /// (which only has to look like one), and the checksum updated.
+    pub(crate) fn signed(image: &[u8]) -> Vec<u8> {
+        let mut signed = image.to_vec();
+        signed.resize((signed.len() + 7) & !7, 0);
+
+        let optional = pe_optional_header(&signed).unwrap();
+        let entry = optional + 112 + CERTIFICATE_TABLE * 8;
+        let offset = signed.len() as u32;
+        signed[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+        signed[entry + 4..entry + 8].copy_from_slice(&16u32.to_le_bytes());
+        signed[optional + 64..optional + 68].copy_from_slice(&0x1234u32.to_le_bytes());
+        // WIN_CERTIFICATE: its length, revision, and type (PKCS #7), and then the signature
+        signed.extend_from_slice(b"\x10\0\0\0\0\x02\x02\0");
+        signed.extend_from_slice(&[0x30; 8]);
+
+        signed
+    }
+
+    fn stub_signing_info(dir: &Path, sbsign: &str, sbverify: &str) -> SigningInfo {
+        let stub = |name: &str, script: &str| {
+            let path = dir.join(name);
+            fs::write(
+                &path,
+                format!("#!/bin/sh\nfor last; do :; done\n{}\n", script),
+            )
+            .unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+
+        SigningInfo {
+            signing_key: dir.join("db.key"),
+            signing_cert: dir.join("db.crt"),
+            rules: Vec::new(),
+            sbsign: stub("sbsign", sbsign),
+            sbverify: stub("sbverify", sbverify),
+            sbattach: stub("sbattach", "true"),
+        }
+    }
+
+    #[test]
+    fn test_verify_cache() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let log = dir.join("log");
+        let signing_info = stub_signing_info(
+            dir,
+            "true",
+            &format!(
+                r#"echo "$last" >> {}; grep -q SIGNED "$last""#,
+                log.display()
+            ),
+        );
+        let file = dir.join("a.efi");
+        fs::write(&file, "SIGNED\n").unwrap();
+        let runs = || fs::read_to_string(&log).unwrap().lines().count();
+
+        let cache = VerifyCache::default();
+        for _ in 0..2 {
+            cache
+                .verify_file(&signing_info, &file, Path::new("db.crt"))
+                .unwrap();
+        }
+        assert_eq!(runs(), 1);
+
+        // another cert is another outcome
+        cache
+            .verify_file(&signing_info, &file, Path::new("other.crt"))
+            .unwrap();
+        assert_eq!(runs(), 2);
+
+        // so is a failure
+        fs::write(&file, "unsigned\n").unwrap();
+        cache.invalidate(&file);
+        for _ in 0..2 {
+            assert!(cache
+                .verify_file(&signing_info, &file, Path::new("db.crt"))
+                .is_err());
+        }
+        assert_eq!(runs(), 3);
+    }
+
+    #[test]
+    fn test_preflight() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let signed = r#"echo SIGNED >> "$last""#;
+        let verify = r#"grep -q SIGNED "$last""#;
+
+        assert!(stub_signing_info(dir, signed, verify).preflight().is_ok());
+
+        let err = stub_signing_info(dir, "exit 1", verify)
+            .preflight()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("couldn't sign a test image"), "{}", err);
+
+        let err = stub_signing_info(dir, signed, "exit 1")
+            .preflight()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("do not match"), "{}", err);
+
+        let signing_info = stub_signing_info(dir, signed, verify);
+        fs::set_permissions(&signing_info.sbsign, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = signing_info.preflight().unwrap_err().to_string();
+        assert!(err.contains("--sbsign"), "{}", err);
+        assert!(err.contains("is not executable"), "{}", err);
+
+        let signing_info = SigningInfo {
+            sbverify: dir.join("missing"),
+            ..stub_signing_info(dir, signed, verify)
+        };
+        let err = signing_info.preflight().unwrap_err().to_string();
+        assert!(err.contains("--sbverify"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_signing_rule() {
+        let rule: SigningRule = "/EFI/systemd/*=org.key:org.crt".parse().unwrap();
+        assert_eq!(rule.pattern.as_str(), "EFI/systemd/*");
+        assert_eq!(
+            rule.pair,
+            KeyPair {
+                key: PathBuf::from("org.key"),
+                cert: PathBuf::from("org.crt"),
+            }
+        );
+
+        for invalid in [
+            "EFI/systemd/*",
+            "EFI/systemd/*=org.key",
+            "=org.key:org.crt",
+            "EFI/systemd/*=:org.crt",
+            "EFI/systemd/*=org.key:",
+            "EFI/[systemd=org.key:org.crt",
+        ] {
+            assert!(invalid.parse::<SigningRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_pair_for() {
+        let signing_info = SigningInfo {
+            signing_key: PathBuf::from("db.key"),
+            signing_cert: PathBuf::from("db.crt"),
+            rules: vec![
+                "EFI/systemd/*=org.key:org.crt".parse().unwrap(),
+                "EFI/nixos/*=product.key:product.crt".parse().unwrap(),
+                // shadowed by the first rule
+                "EFI/systemd/systemd-bootx64.efi=other.key:other.crt"
+                    .parse()
+                    .unwrap(),
+            ],
+            sbsign: PathBuf::from("sbsign"),
+            sbverify: PathBuf::from("sbverify"),
+            sbattach: PathBuf::from("sbattach"),
+        };
+        let key = |path: &str| signing_info.pair_for(Path::new(path)).key;
+
+        assert_eq!(key("EFI/systemd/systemd-bootx64.efi"), Path::new("org.key"));
+        // FAT doesn't care about case
+        assert_eq!(key("efi/SYSTEMD/systemd-bootx64.efi"), Path::new("org.key"));
+        assert_eq!(key("EFI/nixos/kernel.efi"), Path::new("product.key"));
+        assert_eq!(
+            key("EFI/BOOT/BOOTX64.EFI"),
+            Path::new("/nonexistent/db.key")
+        );
+
+        assert_eq!(
+            signing_info
+                .pairs()
+                .iter()
+                .map(|pair| pair.key.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["db.key", "org.key", "product.key", "other.key"]
+        );
+    }
+
+    #[test]
+    fn test_preflight_every_pair() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let log = dir.join("sbsign.log");
+        let signing_info = SigningInfo {
+            rules: vec![
+                "EFI/systemd/*=org.key:org.crt".parse().unwrap(),
+                "EFI/BOOT/*=org.key:org.crt".parse().unwrap(),
+            ],
+            ..stub_signing_info(
+                dir,
+                &format!(r#"echo "$2" >> {}; echo SIGNED >> "$last""#, log.display()),
+                r#"grep -q SIGNED "$last""#,
+            )
+        };
+
+        signing_info.preflight().unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!("{}\norg.key\n", dir.join("db.key").display())
+        );
+    }
+
+    #[test]
+    fn test_without_signature() {
+        let image = test_image();
+        let signed_image = signed(&image);
+        assert!(is_signed(&signed_image));
+        assert!(!is_signed(&image));
+        assert_eq!(without_signature(&signed_image), without_signature(&image));
+
+        // the padding before the certificate table doesn't count either
+        let mut unaligned = image.clone();
+        unaligned.extend_from_slice(b"abc");
+        assert_eq!(
+            without_signature(&signed(&unaligned)),
+            without_signature(&unaligned)
+        );
+
+        let mut different = image;
+        different[0x200] = 0x90;
+        assert_ne!(
+            without_signature(&signed_image),
+            without_signature(&different)
+        );
+
+        // anything else isn't a signed PE image
+        assert!(!is_signed(b"efi\n"));
+        assert_eq!(without_signature(b"efi\n"), b"efi\n\0\0\0\0");
+    }
+
+    #[test]
+    fn test_for_each_unsigned_chunk() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let image = test_image();
+        let mut unaligned = image.clone();
+        unaligned.extend_from_slice(b"abc");
+
+        for contents in [
+            image.clone(),
+            signed(&image),
+            signed(&unaligned),
+            b"efi\n".to_vec(),
+        ] {
+            let path = tempdir.path().join("image.efi");
+            fs::write(&path, &contents).unwrap();
+
+            let mut unsigned = Vec::new();
+            for_each_unsigned_chunk(&path, |chunk| unsigned.extend_from_slice(chunk)).unwrap();
+            assert_eq!(unsigned, without_signature(&contents));
+            assert_eq!(is_signed_file(&path).unwrap(), is_signed(&contents));
+        }
+    }
+
+    #[test]
+    fn test_test_image() {
+        let image = test_image();
+
+        assert_eq!(image.len(), 0x400);
+        assert_eq!(&image[..2], b"MZ");
+        assert_eq!(&image[0x40..0x44], b"PE\0\0");
+        assert_eq!(&image[0x148..0x14d], b".text");
+    }
+
+    #[test]
+    fn test_resolve_sbattach() {
+        let runtime = Path::new("/runtime/bin/sbattach");
+        let embedded = "/embedded/bin/sbattach";
+
+        // built with an embedded sbattach
+        assert_eq!(
+            super::resolve_sbattach(Some(runtime), Some(embedded)).unwrap(),
+            PathBuf::from(runtime)
+        );
+        assert_eq!(
+            super::resolve_sbattach(None, Some(embedded)).unwrap(),
+            PathBuf::from(embedded)
+        );
+
+        // built without one
+        assert_eq!(
+            super::resolve_sbattach(Some(runtime), None).unwrap(),
+            PathBuf::from(runtime)
+        );
+        assert!(super::resolve_sbattach(None, None)
+            .unwrap_err()
+            .to_string()
+            .contains("--sbattach"));
+    }
+}
@@ -0,0 +1,565 @@
+//! Taking over the entries on an ESP that something else wrote (`adopt`), e.g. NixOS's own
+//! systemd-boot builder, or someone by hand, to bring a machine under the installer's management
+//! without a rebuild.
+//!
+//! Each entry is correlated with the generation it boots by the store paths it refers to: the
+//! toplevel in its `init=` (or the unified EFI file named after it) picks out the generation, and
+//! failing that, its kernel and initrd do, as long as no other generation shares them. The entries
+//! that are correlated are recorded as ours in the ESP's history (see [`super::history`]) and in
+//! the manifest on their partition (see [`crate::manifest`]), and the next install manages them
+//! like the ones it wrote (see [`remove_old`]). The rest are left alone, and listed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use generator::report::History;
+use log::{debug, info};
+
+use super::history::{self, HISTORY};
+use super::sd_boot_model;
+use super::Layout;
+use crate::context::Context;
+use crate::manifest;
+use crate::util::{self, Generation};
+use crate::Result;
+
+/// The length of the hash at the start of a store path's name.
+const STORE_HASH_LEN: usize = 32;
+
+/// The store hashes that an entry of a generation can refer to.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct GenerationHashes {
+    pub profile: Option<String>,
+    pub generation: usize,
+    /// The hashes of its toplevel and its specialisations' toplevels, which are its own
+    pub toplevels: BTreeSet<String>,
+    /// The hashes of its (and its specialisations') kernels and initrds, which other generations
+    /// can share
+    pub boot_files: BTreeSet<String>,
+}
+
+impl GenerationHashes {
+    pub fn new(generation: &Generation) -> Result<Self> {
+        let toplevel = fs::canonicalize(&generation.path).with_path_context(&generation.path)?;
+        let mut toplevels = vec![toplevel.clone()];
+        let specialisations = toplevel.join("specialisation");
+        if specialisations.is_dir() {
+            for entry in fs::read_dir(&specialisations).with_path_context(&specialisations)? {
+                let path = entry.with_path_context(&specialisations)?.path();
+                toplevels.push(fs::canonicalize(&path).with_path_context(&path)?);
+            }
+        }
+
+        let mut hashes = GenerationHashes {
+            profile: generation.profile.clone(),
+            generation: generation.idx,
+            ..Default::default()
+        };
+        for toplevel in toplevels {
+            hashes.toplevels.extend(self::path_hash(&toplevel));
+            for file in ["kernel", "initrd"] {
+                // e.g. a generation without an initrd
+                if let Ok(path) = fs::canonicalize(toplevel.join(file)) {
+                    hashes.boot_files.extend(self::path_hash(&path));
+                }
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    fn describe(&self) -> String {
+        self::describe(self.profile.as_deref(), self.generation)
+    }
+}
+
+/// What an entry boots, as far as its store paths tell.
+#[derive(Debug, PartialEq)]
+pub(super) enum Correlation {
+    Generation {
+        profile: Option<String>,
+        generation: usize,
+    },
+    /// Why it can't be told
+    Uncorrelated(String),
+}
+
+/// Correlates the entry `id` (its filename) with one of `generations`, going by the store paths in
+/// its `contents`. Where several generations fit equally well, the one the entry is named after (if
+/// it's named like ours, e.g. `nixos-generation-12.conf`) is taken, or else the newest of the ones
+/// with its toplevel, which all boot the same system.
+pub(super) fn correlate(id: &str, contents: &str, generations: &[GenerationHashes]) -> Correlation {
+    let hashes = sd_boot_model::key_values(contents)
+        .filter(|(key, _)| matches!(*key, "linux" | "initrd" | "efi" | "options"))
+        .flat_map(|(_, value)| self::store_hashes(&value))
+        .collect::<BTreeSet<_>>();
+    if hashes.is_empty() {
+        return Correlation::Uncorrelated(String::from("it refers to no store paths"));
+    }
+
+    let by_toplevel = generations
+        .iter()
+        .filter(|g| !g.toplevels.is_disjoint(&hashes))
+        .collect::<Vec<_>>();
+    let candidates: Vec<&GenerationHashes> = if by_toplevel.is_empty() {
+        let shared = |g: &GenerationHashes| g.boot_files.intersection(&hashes).count();
+        let most = generations.iter().map(shared).max().unwrap_or_default();
+        if most == 0 {
+            return Correlation::Uncorrelated(String::from(
+                "none of the generations has any of the store paths it refers to",
+            ));
+        }
+
+        generations.iter().filter(|g| shared(*g) == most).collect()
+    } else {
+        by_toplevel.clone()
+    };
+
+    let named = super::ENTRY_RE.captures(id).and_then(|caps| {
+        let profile = caps.name("profile").map(|p| p.as_str().to_string());
+        let generation = caps["generation"].parse::<usize>().ok()?;
+        candidates
+            .iter()
+            .find(|g| g.profile == profile && g.generation == generation)
+    });
+    let correlated = match (candidates.as_slice(), named) {
+        ([only], _) => Some(*only),
+        (_, Some(named)) => Some(*named),
+        _ if !by_toplevel.is_empty() => by_toplevel.iter().max_by_key(|g| g.generation).copied(),
+        _ => None,
+    };
+
+    match correlated {
+        Some(g) => Correlation::Generation {
+            profile: g.profile.clone(),
+            generation: g.generation,
+        },
+        None => Correlation::Uncorrelated(format!(
+            "its kernel and initrd are those of {}",
+            candidates
+                .iter()
+                .map(|g| g.describe())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// The store hashes that `value` (e.g. an entry's `options`, or its `linux` path) refers to, either
+/// in store paths (`/nix/store/<hash>-...`) or in the filenames they're copied to on the ESP (e.g.
+/// `<hash>-linux-6.1-bzImage.efi`, or `<hash>.efi` for a unified EFI file).
+pub(super) fn store_hashes(value: &str) -> BTreeSet<String> {
+    value
+        .split(|c: char| c.is_whitespace() || matches!(c, '/' | '=' | ',' | ':'))
+        .filter_map(self::store_hash)
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// The hash that `name` starts with, if it's the name of something in the store (or a copy of it).
+fn store_hash(name: &str) -> Option<&str> {
+    let hash = name.get(..STORE_HASH_LEN)?;
+    let is_hash = hash
+        .chars()
+        .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase());
+    let rest = &name[STORE_HASH_LEN..];
+
+    (is_hash && (rest.is_empty() || rest.starts_with('-') || rest.starts_with('.'))).then(|| hash)
+}
+
+/// The hash of the store path that `path` is (or is in).
+fn path_hash(path: &Path) -> Option<String> {
+    path.components()
+        .filter_map(|component| component.as_os_str().to_str())
+        .find_map(self::store_hash)
+        .map(ToString::to_string)
+}
+
+fn describe(profile: Option<&str>, generation: usize) -> String {
+    match profile {
+        Some(profile) => format!("generation {} of profile '{}'", generation, profile),
+        None => format!("generation {}", generation),
+    }
+}
+
+/// Every generation of the system profile, and of the profiles in `system-profiles`, in
+/// `profiles_dir`.
+fn generations(profiles_dir: &Path) -> Result<Vec<GenerationHashes>> {
+    let mut generations = Vec::new();
+    for profile in util::profiles(profiles_dir)? {
+        for generation in util::all_generations(profiles_dir, profile, true)? {
+            generations.push(GenerationHashes::new(&generation)?);
+        }
+    }
+
+    Ok(generations)
+}
+
+/// Adopts the entries on the ESP at `esp` (or on the XBOOTLDR partition, if there is one) that can
+/// be correlated with a generation in `profiles_dir`, and prints what each entry was correlated
+/// with, or why it couldn't be. Entries that the ESP's history already records are ours already.
+pub(crate) fn adopt(esp: &Path, xbootldr: Option<&Path>, profiles_dir: &Path) -> Result<()> {
+    let layout = Layout::new(esp, xbootldr);
+    let root = layout.payload_root();
+    let loader_entries = root.join("loader/entries");
+    if !loader_entries.is_dir() {
+        return Err(format!("'{}' has no entries to adopt", loader_entries.display()).into());
+    }
+
+    let generations = self::generations(profiles_dir)?;
+    let owned = History::load(&esp.join(HISTORY))?.files;
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&loader_entries).with_path_context(&loader_entries)? {
+        paths.push(entry.with_path_context(&loader_entries)?.path());
+    }
+    paths.sort();
+
+    let mut adopted = BTreeMap::new();
+    let mut sha256s = BTreeMap::new();
+    let mut stdout = std::io::stdout();
+    for path in paths {
+        let id = match path.file_name().and_then(|name| name.to_str()) {
+            Some(id) if id.ends_with(".conf") && !path.is_dir() => id.to_string(),
+            _ => continue,
+        };
+        let relative = Path::new("loader/entries").join(&id);
+        if owned.contains_key(&relative) {
+            debug!("'{}' is already the installer's", path.display());
+            continue;
+        }
+
+        let contents = util::read_to_string_lossy(&path).with_path_context(&path)?;
+        match self::correlate(&id, &contents, &generations) {
+            Correlation::Generation {
+                profile,
+                generation,
+            } => {
+                writeln!(
+                    stdout,
+                    "adopted {}: {}",
+                    id,
+                    self::describe(profile.as_deref(), generation)
+                )?;
+                // The history only records which generation of the system profile a file belongs
+                // to.
+                let generation = profile.is_none().then(|| generation);
+                sha256s.insert(relative.clone(), manifest::sha256_file(&path)?);
+                adopted.insert(relative, generation);
+            }
+            Correlation::Uncorrelated(reason) => {
+                writeln!(stdout, "couldn't correlate {}: {}", id, reason)?;
+            }
+        }
+    }
+
+    if adopted.is_empty() {
+        return Ok(());
+    }
+
+    manifest::record_entries(root, &sha256s)?;
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let run_id = history::record_adopted(layout, adopted, started_at)?;
+    info!(
+        "recorded the adopted entries as run {} in the history on '{}'",
+        run_id,
+        esp.display()
+    );
+
+    Ok(())
+}
+
+/// Removes the entries on the payload partition in `layout` that the ESP's history records as a
+/// generation's, but that none of `generations` requires, returning their paths. These are the
+/// entries that were adopted under names other than ours (which aren't pruned by name), once their
+/// generation's own entry takes their place or their generation is gone. Only the system profile's
+/// are managed this way.
+pub(super) fn remove_old(layout: Layout, generations: &[Generation]) -> Result<Vec<PathBuf>> {
+    let history = History::load(&layout.esp.join(HISTORY))?;
+    let mut removed = Vec::new();
+
+    for (relative, provenance) in &history.files {
+        let name = match relative.strip_prefix("loader/entries") {
+            Ok(name) if provenance.generation.is_some() => name.as_os_str(),
+            _ => continue,
+        };
+        if generations
+            .iter()
+            .any(|g| g.required_filenames.iter().any(|required| required == name))
+        {
+            continue;
+        }
+
+        let path = layout.payload_root().join(relative);
+        if path.is_file() {
+            debug!("removing adopted entry {:?}", path);
+            fs::remove_file(&path).with_path_context(&path)?;
+            removed.push(path);
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::os::unix::fs::symlink;
+
+    const T1: &str = "1111111111111111111111111111111a";
+    const T2: &str = "2222222222222222222222222222222a";
+    const T3: &str = "3333333333333333333333333333333a";
+    const K1: &str = "1111111111111111111111111111111k";
+    const I1: &str = "1111111111111111111111111111111i";
+    const K2: &str = "2222222222222222222222222222222k";
+    const I2: &str = "2222222222222222222222222222222i";
+
+    fn set(hashes: &[&str]) -> BTreeSet<String> {
+        hashes.iter().map(ToString::to_string).collect()
+    }
+
+    /// Generations 1 and 2 of the system profile, which share a kernel and initrd, and generation 3
+    /// of the `work` profile.
+    fn generations() -> Vec<GenerationHashes> {
+        let generation =
+            |profile: Option<&str>, generation, toplevel, boot_files: &[&str]| GenerationHashes {
+                profile: profile.map(ToString::to_string),
+                generation,
+                toplevels: set(&[toplevel]),
+                boot_files: set(boot_files),
+            };
+
+        vec![
+            generation(None, 1, T1, &[K1, I1]),
+            generation(None, 2, T2, &[K1, I1]),
+            generation(Some("work"), 3, T3, &[K2, I2]),
+        ]
+    }
+
+    fn correlated(profile: Option<&str>, generation: usize) -> Correlation {
+        Correlation::Generation {
+            profile: profile.map(ToString::to_string),
+            generation,
+        }
+    }
+
+    #[test]
+    fn test_store_hashes() {
+        assert_eq!(
+            store_hashes(&format!(
+                "init=/nix/store/{}-nixos-system-23.05/init loglevel=4",
+                T1
+            )),
+            set(&[T1])
+        );
+        assert_eq!(
+            store_hashes(&format!("/efi/nixos/{}-linux-6.1-bzImage.efi", K1)),
+            set(&[K1])
+        );
+        assert_eq!(store_hashes(&format!("/EFI/nixos/{}.efi", T2)), set(&[T2]));
+        for value in [
+            "/vmlinuz-linux",
+            "root=UUID=0123456789abcdef0123456789abcdef01 rw",
+            "/EFI/nixos/short-linux.efi",
+            format!("/EFI/nixos/{}x-linux.efi", K1).as_str(),
+            format!("/EFI/nixos/{}-linux.efi", K1.to_uppercase()).as_str(),
+        ] {
+            assert!(store_hashes(value).is_empty(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_correlate() {
+        let generations = generations();
+
+        // as NixOS's systemd-boot builder writes them
+        let upstream = format!(
+            "title NixOS\n\
+             version Generation 1 NixOS 23.05, Linux Kernel 6.1\n\
+             linux /efi/nixos/{}-linux-6.1-bzImage.efi\n\
+             initrd /efi/nixos/{}-initrd-linux-6.1-initrd.efi\n\
+             options init=/nix/store/{}-nixos-system-23.05/init loglevel=4\n\
+             machine-id aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n",
+            K1, I1, T1
+        );
+        assert_eq!(
+            correlate("nixos-generation-1.conf", &upstream, &generations),
+            correlated(None, 1)
+        );
+        // the toplevel decides, whatever the entry's name
+        assert_eq!(
+            correlate("nixos-generation-2.conf", &upstream, &generations),
+            correlated(None, 1)
+        );
+
+        // as the generator writes them
+        let ours = format!(
+            "title NixOS\n\
+             linux /EFI/nixos/{}-linux-6.1-bzImage.efi\n\
+             initrd /EFI/nixos/{}-initrd-linux-6.1-initrd.efi\n\
+             options init=/nix/store/{}-nixos-system-23.05/init\n",
+            K2, I2, T3
+        );
+        assert_eq!(
+            correlate("nixos-work-generation-3.conf", &ours, &generations),
+            correlated(Some("work"), 3)
+        );
+        let unified = format!("title NixOS\nefi /EFI/nixos/{}.efi\n", T2);
+        assert_eq!(
+            correlate("nixos-generation-2.conf", &unified, &generations),
+            correlated(None, 2)
+        );
+
+        // without the toplevel, the kernel and initrd only tell if no other generation shares them
+        let kernel_only = |kernel, initrd| {
+            format!(
+                "title NixOS (backup)\n\
+                 linux /EFI/nixos/{}-linux-6.1-bzImage.efi\n\
+                 initrd /EFI/nixos/{}-initrd-linux-6.1-initrd.efi\n\
+                 options root=/dev/sda2\n",
+                kernel, initrd
+            )
+        };
+        assert_eq!(
+            correlate("work.conf", &kernel_only(K2, I2), &generations),
+            correlated(Some("work"), 3)
+        );
+        assert_eq!(
+            correlate(
+                "nixos-generation-2.conf",
+                &kernel_only(K1, I1),
+                &generations
+            ),
+            correlated(None, 2)
+        );
+        match correlate("backup.conf", &kernel_only(K1, I1), &generations) {
+            Correlation::Uncorrelated(reason) => {
+                assert!(reason.contains("generation 1, generation 2"), "{}", reason)
+            }
+            correlation => panic!("{:?}", correlation),
+        }
+
+        // written by hand, or for another OS
+        for (id, contents) in [
+            (
+                "arch.conf",
+                String::from(
+                    "title Arch Linux\n\
+                     linux /vmlinuz-linux\n\
+                     initrd /initramfs-linux.img\n\
+                     options root=UUID=0a3407de-014b-458b-b5c1-848e92a327a3 rw\n",
+                ),
+            ),
+            (
+                "nixos-generation-9.conf",
+                format!(
+                    "title NixOS\n\
+                     linux /EFI/nixos/{}-linux-6.1-bzImage.efi\n\
+                     options init=/nix/store/{}-nixos-system-23.05/init\n",
+                    "9999999999999999999999999999999k", "9999999999999999999999999999999a"
+                ),
+            ),
+        ] {
+            assert!(
+                matches!(
+                    correlate(id, &contents, &generations),
+                    Correlation::Uncorrelated(_)
+                ),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn test_adopt() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let store = dir.join("store");
+        let profiles = dir.join("profiles");
+        let esp = dir.join("esp");
+        fs::create_dir_all(profiles.join("system-profiles")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+
+        // generations 1 and 2 of the system profile, with a kernel and initrd of their own
+        for (idx, toplevel, kernel) in [(1, T1, K1), (2, T2, K2)] {
+            let kernel = store.join(format!("{}-linux-6.1", kernel));
+            fs::create_dir_all(&kernel).unwrap();
+            fs::write(kernel.join("bzImage"), "").unwrap();
+            let toplevel = store.join(format!("{}-nixos-system-23.05", toplevel));
+            fs::create_dir_all(&toplevel).unwrap();
+            symlink(kernel.join("bzImage"), toplevel.join("kernel")).unwrap();
+            symlink(&toplevel, profiles.join(format!("system-{}-link", idx))).unwrap();
+        }
+        let entry = |toplevel: &str| {
+            format!(
+                "title NixOS\noptions init=/nix/store/{}-nixos-system-23.05/init\n",
+                toplevel
+            )
+        };
+        let entries = esp.join("loader/entries");
+        fs::write(entries.join("nixos-generation-1.conf"), entry(T1)).unwrap();
+        fs::write(entries.join("nixos-old.conf"), entry(T2)).unwrap();
+        fs::write(
+            entries.join("arch.conf"),
+            "title Arch Linux\nlinux /vmlinuz\n",
+        )
+        .unwrap();
+        fs::write(
+            esp.join(crate::manifest::MANIFEST),
+            r#"{ "formatVersion": 2, "files": [], "entries": { "loader/entries/nixos-generation-3.conf": "abcd" } }"#,
+        )
+        .unwrap();
+
+        let layout = Layout::new(&esp, None);
+        adopt(&esp, None, &profiles).unwrap();
+        let history = History::load(&esp.join(HISTORY)).unwrap();
+        assert_eq!(history.runs.len(), 1);
+        assert_eq!(
+            history
+                .files
+                .iter()
+                .map(|(path, provenance)| (path.to_str().unwrap(), provenance.generation))
+                .collect::<Vec<_>>(),
+            [
+                ("loader/entries/nixos-generation-1.conf", Some(1)),
+                ("loader/entries/nixos-old.conf", Some(2)),
+            ]
+        );
+        let manifest = <manifest::Manifest as manifest::ManifestExt>::load(&esp)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            manifest.entries.keys().collect::<Vec<_>>(),
+            [
+                "loader/entries/nixos-generation-1.conf",
+                "loader/entries/nixos-generation-3.conf",
+                "loader/entries/nixos-old.conf",
+            ]
+        );
+
+        // what's adopted already isn't again
+        adopt(&esp, None, &profiles).unwrap();
+        assert_eq!(History::load(&esp.join(HISTORY)).unwrap().runs.len(), 1);
+
+        // the next install replaces the adopted entry of generation 2 with its own
+        let wanted = [1, 2].map(|idx| Generation {
+            idx,
+            required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+            ..Default::default()
+        });
+        assert_eq!(
+            remove_old(layout, &wanted).unwrap(),
+            [entries.join("nixos-old.conf")]
+        );
+        assert!(entries.join("nixos-generation-1.conf").exists());
+        assert!(entries.join("arch.conf").exists());
+        assert!(remove_old(layout, &wanted).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+//! Backups of what a run replaced or pruned on an ESP (`--backup-dir`), which `rollback` puts back
+//! when the new generation turns out not to boot.
+//!
+//! Before the plan runs, everything on the ESP (and the XBOOTLDR partition) is copied to the run's
+//! backup, under `esp/` (and `xbootldr/`). Once the run went through, what it left as it was is
+//! dropped from the backup again, and what it added is listed in the backup's `backup.json`, so that
+//! a backup only takes up the space of what its run replaced or pruned. The history on the ESP
+//! records where each run's backup is.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+use generator::report::History;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use super::history::HISTORY;
+use super::plan;
+use super::set_default;
+use super::slot;
+use super::Layout;
+use crate::context::Context;
+use crate::report;
+use crate::util;
+use crate::Result;
+
+/// What a backup has in it besides the files themselves.
+const BACKUP_MANIFEST: &str = "backup.json";
+/// What the files of each partition are under in a backup.
+const PARTITIONS: [&str; 2] = ["esp", "xbootldr"];
+
+/// The files a run added, which rolling it back removes.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Backup {
+    /// Relative to the backup, i.e. under `esp/` or `xbootldr/`
+    added: Vec<PathBuf>,
+}
+
+/// The partition in `layout` whose files are under `name` in a backup.
+fn partition<'a>(layout: Layout<'a>, name: &str) -> Option<&'a Path> {
+    match name {
+        "esp" => Some(layout.esp),
+        "xbootldr" => layout.xbootldr,
+        _ => None,
+    }
+}
+
+/// What the backups of `esp` are named in each run's directory.
+fn esp_name(esp: &Path) -> String {
+    let esp = esp.to_string_lossy().trim_matches('/').replace('/', "-");
+    if esp.is_empty() {
+        String::from("root")
+    } else {
+        esp
+    }
+}
+
+/// Where the backup of the run `run_id` on `esp` that started at `started_at` goes in `backup_dir`.
+/// Runs that start in the same second (e.g. `--phase` runs one after the other) are told apart by
+/// their run IDs.
+pub(super) fn dir(backup_dir: &Path, started_at: u64, run_id: u64, esp: &Path) -> PathBuf {
+    backup_dir
+        .join(format!("{}-{}", started_at, run_id))
+        .join(self::esp_name(esp))
+}
+
+/// Copies everything on the partitions in `layout` to the backup at `dir`, before a run changes
+/// them. A backup that the ESP's history refers to is never overwritten; one that it doesn't (of a
+/// run that failed) is replaced.
+pub(super) fn take(layout: Layout, dir: &Path) -> Result<()> {
+    if dir.exists() {
+        let history = History::load(&layout.esp.join(HISTORY))?;
+        if history
+            .runs
+            .iter()
+            .any(|run| run.backup.as_deref() == Some(dir))
+        {
+            return Err(format!(
+                "the backup '{}' already exists, and is an earlier run's",
+                dir.display()
+            )
+            .into());
+        }
+
+        debug!("removing the backup of a failed run '{}'", dir.display());
+        fs::remove_dir_all(dir).with_path_context(dir)?;
+    }
+
+    for name in PARTITIONS {
+        let root = match self::partition(layout, name) {
+            Some(root) => root,
+            None => continue,
+        };
+        if dir.starts_with(root) {
+            return Err(format!(
+                "the backup '{}' can't be on '{}', which it backs up",
+                dir.display(),
+                root.display()
+            )
+            .into());
+        }
+
+        for entry in WalkDir::new(root) {
+            let entry = entry.with_path_context(root)?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let backup = dir.join(name).join(entry.path().strip_prefix(root)?);
+            util::create_dirs_to_file(&backup)?;
+            fs::copy(entry.path(), &backup).with_paths_context(entry.path(), &backup)?;
+        }
+    }
+    info!(
+        "backed up '{}' to '{}'",
+        layout.esp.display(),
+        dir.display()
+    );
+
+    Ok(())
+}
+
+/// Drops what the run left as it was from the backup at `dir` (which was taken of `layout` before
+/// the run), and lists what it added.
+pub(super) fn finish(layout: Layout, dir: &Path) -> Result<()> {
+    let mut backup = Backup::default();
+
+    for name in PARTITIONS {
+        let root = match self::partition(layout, name) {
+            Some(root) => root,
+            None => continue,
+        };
+        let backed_up = dir.join(name);
+        let before = if backed_up.exists() {
+            report::hash_tree(&backed_up)?
+        } else {
+            BTreeMap::new()
+        };
+
+        for (relative, sha256) in report::hash_tree(root)? {
+            match before.get(&relative) {
+                Some(before) if *before == sha256 => {
+                    let unchanged = backed_up.join(&relative);
+                    fs::remove_file(&unchanged).with_path_context(&unchanged)?;
+                }
+                Some(_) => debug!("'{}' was replaced", relative.display()),
+                None => backup.added.push(Path::new(name).join(relative)),
+            }
+        }
+
+        if !backed_up.exists() {
+            continue;
+        }
+        // the directories of the files that were dropped
+        for entry in WalkDir::new(&backed_up).contents_first(true) {
+            let entry = entry.with_path_context(&backed_up)?;
+            let path = entry.path();
+            if entry.file_type().is_dir()
+                && fs::read_dir(path).with_path_context(path)?.next().is_none()
+            {
+                fs::remove_dir(path).with_path_context(path)?;
+            }
+        }
+    }
+
+    let path = dir.join(BACKUP_MANIFEST);
+    let contents = serde_json::to_string_pretty(&backup)?;
+    fs::write(&path, contents + "\n").with_path_context(&path)?;
+
+    Ok(())
+}
+
+/// Removes the backups of `esp` in `backup_dir` that its history no longer refers to: those of the
+/// runs that dropped out of it, or that failed before it recorded them.
+pub(super) fn prune(backup_dir: &Path, esp: &Path) -> Result<()> {
+    let history = History::load(&esp.join(HISTORY))?;
+    let referenced = history
+        .runs
+        .iter()
+        .filter_map(|run| run.backup.as_deref())
+        .collect::<Vec<_>>();
+
+    for entry in fs::read_dir(backup_dir).with_path_context(backup_dir)? {
+        let run_dir = entry.with_path_context(backup_dir)?.path();
+        // `<started_at>-<run_id>`, or just `<started_at>` for the backups of older releases
+        let is_run_dir = run_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| {
+                name.split('-').count() <= 2
+                    && name
+                        .split('-')
+                        .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+            });
+        if !is_run_dir {
+            continue;
+        }
+
+        let backup = run_dir.join(self::esp_name(esp));
+        if !backup.is_dir() || referenced.contains(&backup.as_path()) {
+            continue;
+        }
+        debug!("removing the backup '{}'", backup.display());
+        fs::remove_dir_all(&backup).with_path_context(&backup)?;
+        // unless it has the backups of other ESPs
+        if fs::read_dir(&run_dir)
+            .with_path_context(&run_dir)?
+            .next()
+            .is_none()
+        {
+            fs::remove_dir(&run_dir).with_path_context(&run_dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rolls back the newest run on `esp` (and `xbootldr`) that wasn't already, from its backup: the
+/// files it added are removed, and the ones it replaced or pruned are restored, loader.conf (and
+/// with it, the previous default) among them. The run stays in the history, as rolled back.
+///
+/// With `dry_run`, only prints what it would remove and restore.
+pub(crate) fn rollback(esp: &Path, xbootldr: Option<&Path>, dry_run: bool) -> Result<()> {
+    let layout = Layout::new(esp, xbootldr);
+    let history_path = esp.join(HISTORY);
+    let mut history = History::load(&history_path)?;
+    let idx = history
+        .runs
+        .iter()
+        .rposition(|run| !run.rolled_back)
+        .ok_or_else(|| format!("there's no run on '{}' to roll back", esp.display()))?;
+    let run_id = history.runs[idx].run_id;
+    let dir = history.runs[idx].backup.clone().ok_or_else(|| {
+        format!(
+            "run {} on '{}' wasn't backed up (see --backup-dir), so it can't be rolled back",
+            run_id,
+            esp.display()
+        )
+    })?;
+
+    let manifest = dir.join(BACKUP_MANIFEST);
+    let backup: Backup = match fs::read_to_string(&manifest) {
+        Ok(contents) => serde_json::from_str(&contents).with_path_context(&manifest)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(format!(
+                "the backup of run {} on '{}' ('{}') is gone, so it can't be rolled back",
+                run_id,
+                esp.display(),
+                dir.display()
+            )
+            .into())
+        }
+        Err(e) => return Err(e).with_path_context(&manifest),
+    };
+
+    let dest = |relative: &Path| -> Result<PathBuf> {
+        PARTITIONS
+            .iter()
+            .find_map(|name| {
+                let relative = relative.strip_prefix(name).ok()?;
+                Some(self::partition(layout, name)?.join(relative))
+            })
+            .ok_or_else(|| {
+                format!(
+                    "'{}' in the backup '{}' isn't on '{}' (or the XBOOTLDR partition)",
+                    relative.display(),
+                    dir.display(),
+                    esp.display()
+                )
+                .into()
+            })
+    };
+    let to_remove = backup
+        .added
+        .iter()
+        .map(|relative| dest(relative))
+        .collect::<Result<Vec<_>>>()?;
+    let mut to_restore = Vec::new();
+    for name in PARTITIONS {
+        let backed_up = dir.join(name);
+        if !backed_up.exists() {
+            continue;
+        }
+        for entry in WalkDir::new(&backed_up).sort_by_file_name() {
+            let entry = entry.with_path_context(&backed_up)?;
+            if entry.file_type().is_file() {
+                let path = entry.path().to_path_buf();
+                to_restore.push((dest(path.strip_prefix(&dir)?)?, path));
+            }
+        }
+    }
+
+    if dry_run {
+        let mut out = io::stdout();
+        for path in &to_remove {
+            writeln!(out, "remove '{}'", path.display())?;
+        }
+        for (path, backup) in &to_restore {
+            writeln!(
+                out,
+                "restore '{}' from '{}'",
+                path.display(),
+                backup.display()
+            )?;
+        }
+        return Ok(());
+    }
+
+    for path in &to_remove {
+        match fs::remove_file(path) {
+            Ok(()) => debug!("removed '{}'", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_path_context(path),
+        }
+    }
+    for (path, backup) in &to_restore {
+        util::create_dirs_to_file(path)?;
+        fs::copy(backup, path).with_paths_context(backup, path)?;
+        debug!("restored '{}'", path.display());
+    }
+
+    // The history was restored along with the rest, to what it was before the run, but the run
+    // stays in it.
+    history.files = History::load(&history_path)?.files;
+    history.runs[idx].rolled_back = true;
+    history.write(&history_path)?;
+    for name in PARTITIONS {
+        if let Some(root) = self::partition(layout, name) {
+            plan::syncfs(root)?;
+        }
+    }
+
+    match slot::current_default(esp)? {
+        // a pattern may match several entries, or none yet
+        Some(default) if !default.contains('*') => {
+            set_default::check_entry(layout, &default).map_err(|e| {
+                format!(
+                    "rolled back run {} on '{}', but its default doesn't boot: {}",
+                    run_id,
+                    esp.display(),
+                    e
+                )
+            })?;
+            info!(
+                "rolled back run {} on '{}', which boots '{}' by default again",
+                run_id,
+                esp.display(),
+                default
+            );
+        }
+        _ => info!("rolled back run {} on '{}'", run_id, esp.display()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir() {
+        let backup_dir = Path::new("/var/lib/nixos-boot-backup");
+        assert_eq!(
+            dir(backup_dir, 1686000000, 3, Path::new("/boot/efi")),
+            Path::new("/var/lib/nixos-boot-backup/1686000000-3/boot-efi")
+        );
+        assert_eq!(
+            dir(backup_dir, 1686000000, 3, Path::new("/")),
+            Path::new("/var/lib/nixos-boot-backup/1686000000-3/root")
+        );
+        // runs that start in the same second don't share a backup
+        assert_ne!(
+            dir(backup_dir, 1686000000, 3, Path::new("/boot")),
+            dir(backup_dir, 1686000000, 4, Path::new("/boot"))
+        );
+    }
+
+    #[test]
+    fn test_take_and_finish() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let dir = tempdir.path().join("backup");
+        for file in [
+            "loader/loader.conf",
+            "EFI/nixos/old.efi",
+            "EFI/nixos/same.efi",
+        ] {
+            util::create_dirs_to_file(esp.join(file)).unwrap();
+            fs::write(esp.join(file), file).unwrap();
+        }
+        let layout = Layout::new(&esp, None);
+
+        take(layout, &dir).unwrap();
+        fs::write(esp.join("loader/loader.conf"), "default new").unwrap();
+        fs::remove_file(esp.join("EFI/nixos/old.efi")).unwrap();
+        fs::write(esp.join("EFI/nixos/new.efi"), "new").unwrap();
+        finish(layout, &dir).unwrap();
+
+        // only what was replaced or pruned is kept
+        assert_eq!(
+            report::hash_tree(&dir).unwrap().keys().collect::<Vec<_>>(),
+            [
+                Path::new("backup.json"),
+                Path::new("esp/EFI/nixos/old.efi"),
+                Path::new("esp/loader/loader.conf"),
+            ]
+        );
+        let backup: Backup =
+            serde_json::from_str(&fs::read_to_string(dir.join(BACKUP_MANIFEST)).unwrap()).unwrap();
+        assert_eq!(backup.added, [PathBuf::from("esp/EFI/nixos/new.efi")]);
+
+        // and never on the partition it backs up
+        let err = take(layout, &esp.join("backup")).unwrap_err().to_string();
+        assert!(err.contains("which it backs up"), "{}", err);
+
+        // a failed run's backup is taken again, but an earlier run's is never overwritten
+        take(layout, &dir).unwrap();
+        assert!(!dir.join(BACKUP_MANIFEST).exists());
+        let mut history = History::default();
+        history.runs.push(generator::report::HistoryRun {
+            run_id: 1,
+            started_at: 1,
+            installer_version: String::from("0.1.0"),
+            files_changed: 1,
+            default_entry: None,
+            backup: Some(dir.clone()),
+            rolled_back: false,
+        });
+        history.write(&esp.join(HISTORY)).unwrap();
+        let err = take(layout, &dir).unwrap_err().to_string();
+        assert!(err.contains("already exists"), "{}", err);
+    }
+
+    #[test]
+    fn test_rollback_refuses_without_backup() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let err = rollback(esp, None, false).unwrap_err().to_string();
+        assert!(err.contains("there's no run"), "{}", err);
+
+        fs::create_dir_all(esp.join("loader")).unwrap();
+        fs::write(
+            esp.join(HISTORY),
+            r#"{
+  "formatVersion": 1,
+  "runs": [{ "run_id": 1, "started_at": 1, "installer_version": "0.1.0", "files_changed": 1 }],
+  "files": {}
+}"#,
+        )
+        .unwrap();
+        let err = rollback(esp, None, false).unwrap_err().to_string();
+        assert!(err.contains("wasn't backed up"), "{}", err);
+
+        let mut history = History::load(&esp.join(HISTORY)).unwrap();
+        history.runs[0].backup = Some(esp.join("gone"));
+        history.write(&esp.join(HISTORY)).unwrap();
+        let err = rollback(esp, None, true).unwrap_err().to_string();
+        assert!(err.contains("is gone"), "{}", err);
+    }
+}
@@ -0,0 +1,573 @@
+//! A hard limit on what the installer's files on the ESP may come to (`--max-esp-files` and
+//! `--max-esp-bytes`), whatever the configuration limit: specialisations and entry variants
+//! multiply the files each generation needs, which can outgrow the ESP.
+//!
+//! It's checked while planning, against what the files would come to once the plan has run, so
+//! that an install that wouldn't fit fails before anything is touched: the staged tree without the
+//! generations that are pruned from it (at the sizes the files are staged with), and the files the
+//! generator left out as unchanged (at their sizes on the ESP). Everything else on the ESP (e.g.
+//! systemd-boot, or another OS's files) isn't counted.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use super::sd_boot_model;
+use super::{Layout, Slot};
+use crate::cli_common::ByteSize;
+use crate::context::Context;
+use crate::util::{self, Generation};
+use crate::{Args, Result};
+
+lazy_static::lazy_static! {
+    /// A generation's entry, its specialisations' and variants', and their deconflicted names.
+    static ref GENERATION_ENTRY_RE: Regex = Regex::new("^nixos-(?:(?P<profile>[^-]+)-)?generation-(?P<generation>\\d+)(?:-.*)?\\.conf$").unwrap();
+}
+
+/// Who a file on the ESP is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Owner {
+    /// A generation of `profile` (`None` for the system profile): its entries, and the files they
+    /// refer to (a file that several generations' entries refer to is the newest one's)
+    Generation {
+        profile: Option<String>,
+        generation: usize,
+    },
+    /// loader.conf, the chainload entries, the credentials, the manifest, and whatever else no
+    /// generation's entry refers to
+    Shared,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Owner::Generation {
+                profile: Some(profile),
+                generation,
+            } => write!(f, "generation {} of profile '{}'", generation, profile),
+            Owner::Generation {
+                profile: None,
+                generation,
+            } => write!(f, "generation {}", generation),
+            Owner::Shared => write!(f, "shared files"),
+        }
+    }
+}
+
+/// How many files, and how many bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(super) struct Usage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+
+    fn fits(&self, max_files: Option<usize>, max_bytes: Option<ByteSize>) -> bool {
+        max_files.map_or(true, |max| self.files <= max)
+            && max_bytes.map_or(true, |max| self.bytes <= max.0)
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} file(s), {} bytes", self.files, self.bytes)
+    }
+}
+
+/// Fails if the installer's files on the ESP (and XBOOTLDR partition) in `layout` would come to
+/// more than `--max-esp-files` or `--max-esp-bytes` once only the `wanted_generations` are left,
+/// with what each generation contributes. `unchanged` are the files the generator left out of the
+/// staged tree (see `crate::manifest::Manifest::unchanged`).
+pub(super) fn check(
+    args: &Args,
+    layout: Layout,
+    wanted_generations: &[Generation],
+    unchanged: &[PathBuf],
+) -> Result<()> {
+    if args.max_esp_files.is_none() && args.max_esp_bytes.is_none() {
+        return Ok(());
+    }
+
+    let usage = self::usage(
+        &args.generated_entries,
+        layout,
+        wanted_generations,
+        &args.retire_profile,
+        args.slot,
+        unchanged,
+    )?;
+
+    self::enforce(&usage, args.max_esp_files, args.max_esp_bytes)
+        .map_err(|e| format!("'{}': {}", layout.esp.display(), e).into())
+}
+
+/// What the files in `generated_entries` (and the `unchanged` ones on the partitions in `layout`)
+/// come to by who they're counted against, leaving out the entries that pruning removes (those of
+/// the generations that aren't in `wanted_generations`, of the system profile and the profiles that
+/// have any there, and those of the `retired_profiles`) and the files only they refer to.
+pub(super) fn usage(
+    generated_entries: &Path,
+    layout: Layout,
+    wanted_generations: &[Generation],
+    retired_profiles: &[String],
+    slot: Option<Slot>,
+    unchanged: &[PathBuf],
+) -> Result<BTreeMap<Owner, Usage>> {
+    // Every file, by its path in the staged tree, and where it is now.
+    let mut files = BTreeMap::new();
+    if generated_entries.exists() {
+        for entry in walkdir::WalkDir::new(generated_entries) {
+            let entry = entry.with_path_context(generated_entries)?;
+            if entry.file_type().is_file() {
+                let relative = entry.path().strip_prefix(generated_entries)?.to_path_buf();
+                files.insert(relative, entry.path().to_path_buf());
+            }
+        }
+    }
+    for relative in unchanged {
+        let dest = layout.dest(relative);
+        if dest.is_file() {
+            files.entry(relative.clone()).or_insert(dest);
+        }
+    }
+
+    // Which generations' entries are kept, which files they refer to (a file is the newest
+    // referrer's), and which files the entries that are pruned refer to.
+    let managed_profiles = wanted_generations
+        .iter()
+        .map(|g| g.profile.as_deref())
+        .collect::<HashSet<_>>();
+    let mut owners = BTreeMap::new();
+    let mut pruned_entries = HashSet::new();
+    let mut referrers: HashMap<OsString, Owner> = HashMap::new();
+    let mut pruned_referrers = HashSet::new();
+    for (relative, path) in &files {
+        let name = match relative.strip_prefix("loader/entries") {
+            Ok(name) if name.components().count() == 1 => name.as_os_str(),
+            _ => continue,
+        };
+        let name = match slot {
+            Some(slot) => match slot.unslotted(name) {
+                Some(name) => name,
+                None => continue,
+            },
+            None => name.to_os_string(),
+        };
+        let (profile, generation) = match GENERATION_ENTRY_RE.captures(&name.to_string_lossy()) {
+            Some(caps) => (
+                caps.name("profile").map(|p| p.as_str().to_string()),
+                caps["generation"].parse::<usize>()?,
+            ),
+            None => continue,
+        };
+
+        let pruned = match &profile {
+            Some(profile) if retired_profiles.contains(profile) => true,
+            // like `remove_old_files`, only the profiles with generations to keep are managed
+            Some(_) if !managed_profiles.contains(&profile.as_deref()) => false,
+            _ => !wanted_generations
+                .iter()
+                .any(|g| g.idx == generation && g.profile == profile),
+        };
+        let contents = util::read_to_string_lossy(path).with_path_context(path)?;
+        let referenced = sd_boot_model::key_values(&contents)
+            .filter(|(key, _)| matches!(*key, "linux" | "initrd" | "efi"))
+            .filter_map(|(_, value)| {
+                Path::new(&value)
+                    .file_name()
+                    .map(|name| name.to_os_string())
+            })
+            .collect::<Vec<_>>();
+
+        if pruned {
+            pruned_entries.insert(relative.clone());
+            pruned_referrers.extend(referenced);
+            continue;
+        }
+        let owner = Owner::Generation {
+            profile,
+            generation,
+        };
+        for name in referenced {
+            let referrer = referrers.entry(name).or_insert_with(|| owner.clone());
+            if self::generation_of(&owner) > self::generation_of(referrer) {
+                *referrer = owner.clone();
+            }
+        }
+        owners.insert(relative.clone(), owner);
+    }
+
+    let mut usage = BTreeMap::new();
+    for (relative, path) in &files {
+        let owner = if let Some(owner) = owners.get(relative) {
+            Some(owner.clone())
+        } else if pruned_entries.contains(relative) {
+            None
+        } else {
+            let name = self::referred_name(relative);
+            match referrers.get(&name) {
+                Some(referrer) => Some(referrer.clone()),
+                None if pruned_referrers.contains(&name) => None,
+                None => Some(Owner::Shared),
+            }
+        };
+
+        if let Some(owner) = owner {
+            let bytes = fs::metadata(path).with_path_context(path)?.len();
+            usage
+                .entry(owner)
+                .or_insert_with(Usage::default)
+                .add(Usage { files: 1, bytes });
+        }
+    }
+
+    Ok(usage)
+}
+
+fn generation_of(owner: &Owner) -> usize {
+    match owner {
+        Owner::Generation { generation, .. } => *generation,
+        Owner::Shared => 0,
+    }
+}
+
+/// The name that entries refer to `relative` by: its own, or for the credentials and addons of a
+/// unified EFI file (in `<file>.extra.d`), the unified EFI file's.
+fn referred_name(relative: &Path) -> OsString {
+    relative
+        .ancestors()
+        .filter_map(|ancestor| ancestor.file_name()?.to_str()?.strip_suffix(".extra.d"))
+        .map(OsString::from)
+        .next()
+        .or_else(|| relative.file_name().map(|name| name.to_os_string()))
+        .unwrap_or_default()
+}
+
+/// Fails if `usage` adds up to more than `max_files` or `max_bytes`, with what each generation
+/// contributes, and the configuration limit that would fit.
+pub(super) fn enforce(
+    usage: &BTreeMap<Owner, Usage>,
+    max_files: Option<usize>,
+    max_bytes: Option<ByteSize>,
+) -> Result<()> {
+    let mut total = Usage::default();
+    for owner_usage in usage.values() {
+        total.add(*owner_usage);
+    }
+    if total.fits(max_files, max_bytes) {
+        return Ok(());
+    }
+
+    let mut limits = Vec::new();
+    if let Some(max) = max_files {
+        limits.push(format!("--max-esp-files {}", max));
+    }
+    if let Some(max) = max_bytes {
+        limits.push(format!("--max-esp-bytes {}", max));
+    }
+    let mut msg = format!(
+        "the installer's files would come to {}, over {}:",
+        total,
+        limits.join(" or ")
+    );
+    for (owner, owner_usage) in usage {
+        msg.push_str(&format!("\n  {}: {}", owner, owner_usage));
+    }
+
+    // The system profile's generations are the ones the configuration limit drops, oldest first.
+    let mut system = usage
+        .iter()
+        .filter_map(|(owner, owner_usage)| match owner {
+            Owner::Generation {
+                profile: None,
+                generation,
+            } => Some((*generation, *owner_usage)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    system.sort_by_key(|(generation, _)| std::cmp::Reverse(*generation));
+    let mut kept = Usage::default();
+    for (owner, owner_usage) in usage {
+        if !matches!(owner, Owner::Generation { profile: None, .. }) {
+            kept.add(*owner_usage);
+        }
+    }
+    let mut fitting = 0;
+    for (_, owner_usage) in &system {
+        kept.add(*owner_usage);
+        if !kept.fits(max_files, max_bytes) {
+            break;
+        }
+        fitting += 1;
+    }
+
+    if fitting > 0 {
+        msg.push_str(&format!(
+            "\nonly the newest {} generation(s) of the system profile would fit: pass \
+             --configuration-limit {} (or raise the limit)",
+            fitting, fitting
+        ));
+    } else {
+        msg.push_str(
+            "\nnot even the newest generation of the system profile would fit: drop \
+             specialisations, entry variants, or other profiles' generations (or raise the limit)",
+        );
+    }
+
+    Err(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generation(idx: usize) -> Generation {
+        Generation {
+            idx,
+            required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+            ..Default::default()
+        }
+    }
+
+    fn owner(profile: Option<&str>, generation: usize) -> Owner {
+        Owner::Generation {
+            profile: profile.map(ToString::to_string),
+            generation,
+        }
+    }
+
+    /// A staged tree with generations 1 to 3 of the system profile (2 and 3 share a kernel and
+    /// initrd, and 3 has a specialisation and a variant), generation 5 of the `work` profile, and
+    /// loader.conf.
+    fn staging_tree(dir: &Path) -> PathBuf {
+        let generated_entries = dir.join("generated");
+        let entry = |kernel: &str, initrd: &str| {
+            format!(
+                "title NixOS\nlinux /EFI/nixos/{}\ninitrd /EFI/nixos/{}\n",
+                kernel, initrd
+            )
+        };
+        for (file, contents) in [
+            ("EFI/nixos/k1.efi", "1".repeat(1000)),
+            ("EFI/nixos/i1.efi", "1".repeat(4000)),
+            ("EFI/nixos/k2.efi", "2".repeat(1000)),
+            ("EFI/nixos/i2.efi", "2".repeat(4000)),
+            ("EFI/nixos/i3-gui.efi", "3".repeat(5000)),
+            ("EFI/nixos/kw.efi", "w".repeat(100)),
+            ("EFI/nixos/iw.efi", "w".repeat(400)),
+            (
+                "loader/entries/nixos-generation-1.conf",
+                entry("k1.efi", "i1.efi"),
+            ),
+            (
+                "loader/entries/nixos-generation-2.conf",
+                entry("k2.efi", "i2.efi"),
+            ),
+            (
+                "loader/entries/nixos-generation-3.conf",
+                entry("k2.efi", "i2.efi"),
+            ),
+            (
+                "loader/entries/nixos-generation-3-gui.conf",
+                entry("k2.efi", "i3-gui.efi"),
+            ),
+            (
+                "loader/entries/nixos-generation-3-variant-debug.conf",
+                entry("k2.efi", "i2.efi"),
+            ),
+            (
+                "loader/entries/nixos-work-generation-5.conf",
+                entry("kw.efi", "iw.efi"),
+            ),
+            ("loader/loader.conf", String::from("timeout 5\n")),
+        ] {
+            let path = generated_entries.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+        }
+
+        generated_entries
+    }
+
+    #[test]
+    fn test_usage() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generated_entries = staging_tree(tempdir.path());
+        let esp = tempdir.path().join("esp");
+        let layout = Layout::new(&esp, None);
+        let entry_size = |name: &str| {
+            fs::metadata(generated_entries.join("loader/entries").join(name))
+                .unwrap()
+                .len()
+        };
+
+        let usage = usage(
+            &generated_entries,
+            layout,
+            &[generation(2), generation(3)],
+            &[],
+            None,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            usage.keys().cloned().collect::<Vec<_>>(),
+            [
+                owner(None, 2),
+                owner(None, 3),
+                owner(Some("work"), 5),
+                Owner::Shared
+            ]
+        );
+        // generation 1's entry and files are pruned, and the files 2 and 3 share are 3's
+        assert_eq!(
+            usage[&owner(None, 2)],
+            Usage {
+                files: 1,
+                bytes: entry_size("nixos-generation-2.conf"),
+            }
+        );
+        assert_eq!(
+            usage[&owner(None, 3)],
+            Usage {
+                files: 6,
+                bytes: 1000
+                    + 4000
+                    + 5000
+                    + entry_size("nixos-generation-3.conf")
+                    + entry_size("nixos-generation-3-gui.conf")
+                    + entry_size("nixos-generation-3-variant-debug.conf"),
+            }
+        );
+        assert_eq!(
+            usage[&Owner::Shared],
+            Usage {
+                files: 1,
+                bytes: 10
+            }
+        );
+
+        // a retired profile's are pruned too
+        let usage = self::usage(
+            &generated_entries,
+            layout,
+            &[generation(2), generation(3)],
+            &[String::from("work")],
+            None,
+            &[],
+        )
+        .unwrap();
+        assert!(!usage.contains_key(&owner(Some("work"), 5)));
+
+        // and so are a profile's generations that are over the limit, once it has any to keep
+        let work = Generation {
+            idx: 6,
+            profile: Some(String::from("work")),
+            ..Default::default()
+        };
+        let usage = self::usage(
+            &generated_entries,
+            layout,
+            &[generation(2), generation(3), work],
+            &[],
+            None,
+            &[],
+        )
+        .unwrap();
+        assert!(!usage.contains_key(&owner(Some("work"), 5)));
+
+        // files the generator left out as unchanged are counted as they are on the ESP
+        fs::remove_file(generated_entries.join("EFI/nixos/k2.efi")).unwrap();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::write(esp.join("EFI/nixos/k2.efi"), "2".repeat(2000)).unwrap();
+        let usage = self::usage(
+            &generated_entries,
+            layout,
+            &[generation(3)],
+            &[],
+            None,
+            &[PathBuf::from("EFI/nixos/k2.efi")],
+        )
+        .unwrap();
+        assert_eq!(usage[&owner(None, 3)].files, 6);
+        assert_eq!(
+            usage[&owner(None, 3)].bytes,
+            2000 + 4000
+                + 5000
+                + entry_size("nixos-generation-3.conf")
+                + entry_size("nixos-generation-3-gui.conf")
+                + entry_size("nixos-generation-3-variant-debug.conf"),
+        );
+        assert!(!usage.contains_key(&owner(None, 2)));
+    }
+
+    #[test]
+    fn test_enforce() {
+        let usage = [
+            (
+                owner(None, 2),
+                Usage {
+                    files: 3,
+                    bytes: 5000,
+                },
+            ),
+            (
+                owner(None, 3),
+                Usage {
+                    files: 6,
+                    bytes: 10000,
+                },
+            ),
+            (
+                owner(Some("work"), 5),
+                Usage {
+                    files: 3,
+                    bytes: 500,
+                },
+            ),
+            (
+                Owner::Shared,
+                Usage {
+                    files: 1,
+                    bytes: 10,
+                },
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect::<BTreeMap<_, _>>();
+
+        enforce(&usage, None, None).unwrap();
+        enforce(&usage, Some(13), Some(ByteSize(15510))).unwrap();
+
+        let err = enforce(&usage, None, Some(ByteSize(12000)))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("13 file(s), 15510 bytes"), "{}", err);
+        assert!(err.contains("--max-esp-bytes 12000B"), "{}", err);
+        assert!(
+            err.contains("\n  generation 2: 3 file(s), 5000 bytes"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("\n  generation 5 of profile 'work': 3 file(s), 500 bytes"),
+            "{}",
+            err
+        );
+        assert!(err.contains("--configuration-limit 1"), "{}", err);
+
+        let err = enforce(&usage, Some(5), None).unwrap_err().to_string();
+        assert!(err.contains("--max-esp-files 5"), "{}", err);
+        assert!(err.contains("not even the newest generation"), "{}", err);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::Result;
+
+/// An entry that chainloads another EFI program (e.g. Windows' Boot Manager) on the ESP.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Chainload {
+    pub name: String,
+    pub title: String,
+    /// The path to the EFI program, relative to the root of the ESP
+    pub path: PathBuf,
+}
+
+impl Chainload {
+    pub fn entry_filename(&self) -> String {
+        format!("nixos-chainload-{}.conf", self.name)
+    }
+
+    pub fn render(&self) -> Result<String> {
+        let mut s = String::new();
+
+        writeln!(s, "title {}", self.title)?;
+        writeln!(s, "efi /{}", self.path.display())?;
+
+        Ok(s)
+    }
+}
+
+impl FromStr for Chainload {
+    type Err = String;
+
+    /// Parses `name=title=path`, where `path` is relative to the root of the ESP.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '=');
+        let (name, title, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(title), Some(path)) => (name, title, path),
+            _ => return Err(format!("'{}' is not of the form name=title=path", s)),
+        };
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "chainload name '{}' must be non-empty and only contain ASCII letters, digits, '-', and '_'",
+                name
+            ));
+        }
+
+        if title.is_empty() {
+            return Err(format!("chainload '{}' has an empty title", name));
+        }
+
+        let mut relative = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::RootDir => {}
+                Component::Normal(c) => relative.push(c),
+                _ => {
+                    return Err(format!(
+                        "chainload path '{}' must be relative to the root of the ESP",
+                        path
+                    ))
+                }
+            }
+        }
+
+        if relative.as_os_str().is_empty() {
+            return Err(format!("chainload '{}' has an empty path", name));
+        }
+
+        Ok(Chainload {
+            name: name.to_string(),
+            title: title.to_string(),
+            path: relative,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chainload() {
+        assert_eq!(
+            "windows=Windows 11=/EFI/Microsoft/Boot/bootmgfw.efi"
+                .parse::<Chainload>()
+                .unwrap(),
+            Chainload {
+                name: String::from("windows"),
+                title: String::from("Windows 11"),
+                path: PathBuf::from("EFI/Microsoft/Boot/bootmgfw.efi"),
+            }
+        );
+        // the path may contain '=', and doesn't need a leading slash
+        assert_eq!(
+            "shell=EFI Shell=EFI/tools/shell=x64.efi"
+                .parse::<Chainload>()
+                .unwrap()
+                .path,
+            PathBuf::from("EFI/tools/shell=x64.efi")
+        );
+
+        for invalid in [
+            "windows",
+            "windows=Windows",
+            "=Windows=/EFI/Microsoft/Boot/bootmgfw.efi",
+            "win dows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi",
+            "../windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi",
+            "windows==/EFI/Microsoft/Boot/bootmgfw.efi",
+            "windows=Windows=",
+            "windows=Windows=/",
+            "windows=Windows=/EFI/../../bootmgfw.efi",
+        ] {
+            assert!(invalid.parse::<Chainload>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_render_chainload() {
+        let chainload = "windows=Windows=EFI/Microsoft/Boot/bootmgfw.efi"
+            .parse::<Chainload>()
+            .unwrap();
+
+        assert_eq!(chainload.entry_filename(), "nixos-chainload-windows.conf");
+        assert_eq!(
+            chainload.render().unwrap(),
+            r#"title Windows
+efi /EFI/Microsoft/Boot/bootmgfw.efi
+"#
+        );
+    }
+}
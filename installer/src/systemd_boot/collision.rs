@@ -0,0 +1,306 @@
+//! Entries on the ESP that have the name of one of ours, but that the installer didn't put there
+//! (e.g. a `nixos-generation-12.conf` written by hand), which replacing would silently destroy.
+//!
+//! The manifest on the ESP (see [`crate::manifest`]) records the entries that were staged the last
+//! time the ESP was installed to, so an entry that it doesn't record isn't ours. It's left alone,
+//! and ours goes next to it under a deconflicted name (e.g. `nixos-generation-12-deconflicted.conf`)
+//! until it's gone, unless `--adopt-existing-entries` is passed: then ours replaces it, and the
+//! manifest records it from then on.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use crate::context::Context;
+use crate::files::{FileToReplace, IdentifiedFiles};
+use crate::manifest::{self, Manifest, ManifestExt};
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// What's appended to the ID of an entry that's installed under a deconflicted name.
+const DECONFLICTED: &str = "-deconflicted";
+
+/// The deconflicted name of the entry `name`, e.g. `nixos-generation-12-deconflicted.conf`.
+pub(crate) fn deconflicted(name: &str) -> String {
+    match name.strip_suffix(".conf") {
+        Some(id) => format!("{}{}.conf", id, DECONFLICTED),
+        None => format!("{}{}", name, DECONFLICTED),
+    }
+}
+
+/// The name of the entry that `name` is the deconflicted name of, if it is one.
+pub(crate) fn original(name: &OsStr) -> Option<OsString> {
+    let id = name
+        .to_str()?
+        .strip_suffix(".conf")?
+        .strip_suffix(DECONFLICTED)?;
+
+    Some(OsString::from(format!("{}.conf", id)))
+}
+
+/// Goes through the entries in `identified_files` that would replace one on `root` (the partition
+/// with the generations' entries), and deconflicts the ones that the manifest on `root` doesn't
+/// record (unless `adopt` is set): the staged entry is renamed in `generated_entries` (and in its
+/// manifest), and is then a new file, or replaces an earlier deconflicted one.
+///
+/// Nothing is deconflicted if there's no manifest on `root` that records entries (e.g. it was
+/// installed to by a release that didn't record them), since there's no telling which are ours.
+pub(crate) fn resolve(
+    identified_files: &mut IdentifiedFiles,
+    generated_entries: &Path,
+    root: &Path,
+    adopt: bool,
+) -> Result<()> {
+    let recorded = match Manifest::load(root)? {
+        Some(manifest) if !manifest.entries.is_empty() => manifest
+            .entries
+            .into_iter()
+            .map(|(entry, sha256)| (PathBuf::from(entry), sha256))
+            .collect::<BTreeMap<_, _>>(),
+        _ => {
+            debug!(
+                "'{}' has no record of the entries on it, so they're all taken to be ours",
+                root.display()
+            );
+            return Ok(());
+        }
+    };
+
+    let mut to_replace = Vec::new();
+    for file in identified_files.to_replace.drain(..) {
+        let relative = file
+            .generated_loc
+            .strip_prefix(generated_entries)?
+            .to_path_buf();
+        let name = relative
+            .strip_prefix("loader/entries")
+            .ok()
+            .filter(|name| name.components().count() == 1)
+            .filter(|name| name.extension() == Some(OsStr::new("conf")))
+            .filter(|name| self::original(name.as_os_str()).is_none())
+            .filter(|_| !recorded.contains_key(&relative))
+            .and_then(Path::to_str)
+            .map(ToString::to_string);
+        let name = match name {
+            Some(name) => name,
+            None => {
+                to_replace.push(file);
+                continue;
+            }
+        };
+
+        if adopt {
+            info!(
+                "adopting '{}', which the installer didn't put there (--adopt-existing-entries)",
+                file.esp_loc.display()
+            );
+            to_replace.push(file);
+            continue;
+        }
+
+        let deconflicted = self::deconflicted(&name);
+        let generated_loc = file.generated_loc.with_file_name(&deconflicted);
+        let esp_loc = file.esp_loc.with_file_name(&deconflicted);
+        warnings::emit(
+            Kind::EntryCollision,
+            format!(
+                "'{}' has the name of one of our entries, but the installer didn't put it there: \
+             leaving it alone, and installing ours as '{}' (pass --adopt-existing-entries to \
+             replace it)",
+                file.esp_loc.display(),
+                esp_loc.display()
+            ),
+        );
+
+        fs::rename(&file.generated_loc, &generated_loc)
+            .with_paths_context(&file.generated_loc, &generated_loc)?;
+        manifest::rename_entry(
+            generated_entries,
+            &relative,
+            &PathBuf::from("loader/entries").join(&deconflicted),
+        )?;
+
+        if esp_loc.is_file() {
+            to_replace.push(FileToReplace {
+                generated_loc,
+                esp_loc,
+            });
+        } else {
+            identified_files.to_add.push(esp_loc);
+        }
+    }
+    identified_files.to_replace = to_replace;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::MANIFEST;
+    use crate::systemd_boot::Layout;
+
+    /// A staging tree with generation 11's and 12's entries, and an ESP that has both, but only
+    /// records 11's.
+    fn scaffold(dir: &Path) -> (PathBuf, PathBuf) {
+        let (generated_entries, esp) = (dir.join("generated_entries"), dir.join("esp"));
+        for root in [&generated_entries, &esp] {
+            fs::create_dir_all(root.join("loader/entries")).unwrap();
+        }
+        for idx in [11, 12] {
+            let entry = format!("loader/entries/nixos-generation-{}.conf", idx);
+            fs::write(generated_entries.join(&entry), "title NixOS\n").unwrap();
+            fs::write(esp.join(&entry), "title Mine\n").unwrap();
+        }
+
+        let manifest = r#"{
+  "files": [],
+  "entries": {
+    "loader/entries/nixos-generation-11.conf": "x",
+    "loader/entries/nixos-generation-12.conf": "x"
+  }
+}"#;
+        fs::write(generated_entries.join(MANIFEST), manifest).unwrap();
+        fs::write(
+            esp.join(MANIFEST),
+            manifest.replace(
+                ",\n    \"loader/entries/nixos-generation-12.conf\": \"x\"",
+                "",
+            ),
+        )
+        .unwrap();
+
+        (generated_entries, esp)
+    }
+
+    /// The entries among `files` (which also have the manifest) on the ESP.
+    fn entries(files: &[FileToReplace]) -> Vec<PathBuf> {
+        let mut entries = files
+            .iter()
+            .map(|file| file.esp_loc.clone())
+            .filter(|path| path.extension() == Some(OsStr::new("conf")))
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        entries
+    }
+
+    #[test]
+    fn test_deconflicted() {
+        assert_eq!(
+            deconflicted("nixos-generation-12.conf"),
+            "nixos-generation-12-deconflicted.conf"
+        );
+        assert_eq!(
+            original(OsStr::new("nixos-generation-12-deconflicted.conf")),
+            Some(OsString::from("nixos-generation-12.conf"))
+        );
+        assert_eq!(original(OsStr::new("nixos-generation-12.conf")), None);
+        assert_eq!(
+            original(OsStr::new(&deconflicted(
+                "nixos-work-generation-1-gui.conf"
+            ))),
+            Some(OsString::from("nixos-work-generation-1-gui.conf"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_deconflicts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (generated_entries, esp) = self::scaffold(tempdir.path());
+        let mut identified_files =
+            IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None)).unwrap();
+
+        resolve(&mut identified_files, &generated_entries, &esp, false).unwrap();
+        // ours is installed next to it
+        assert_eq!(
+            entries(&identified_files.to_replace),
+            vec![esp.join("loader/entries/nixos-generation-11.conf")]
+        );
+        assert_eq!(
+            identified_files.to_add,
+            vec![esp.join("loader/entries/nixos-generation-12-deconflicted.conf")]
+        );
+        assert!(!generated_entries
+            .join("loader/entries/nixos-generation-12.conf")
+            .exists());
+        assert!(generated_entries
+            .join("loader/entries/nixos-generation-12-deconflicted.conf")
+            .exists());
+        // and recorded under its name, so the one on the ESP isn't taken for ours next time
+        let staged = Manifest::load(&generated_entries).unwrap().unwrap();
+        assert!(staged
+            .entries
+            .contains_key("loader/entries/nixos-generation-12-deconflicted.conf"));
+        assert!(!staged
+            .entries
+            .contains_key("loader/entries/nixos-generation-12.conf"));
+        assert_eq!(
+            fs::read_to_string(esp.join("loader/entries/nixos-generation-12.conf")).unwrap(),
+            "title Mine\n"
+        );
+
+        // the next time, ours replaces the earlier deconflicted one
+        fs::write(
+            esp.join("loader/entries/nixos-generation-12-deconflicted.conf"),
+            "title NixOS\n",
+        )
+        .unwrap();
+        fs::rename(
+            generated_entries.join("loader/entries/nixos-generation-12-deconflicted.conf"),
+            generated_entries.join("loader/entries/nixos-generation-12.conf"),
+        )
+        .unwrap();
+        let mut identified_files =
+            IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None)).unwrap();
+        resolve(&mut identified_files, &generated_entries, &esp, false).unwrap();
+        assert_eq!(
+            entries(&identified_files.to_replace),
+            vec![
+                esp.join("loader/entries/nixos-generation-11.conf"),
+                esp.join("loader/entries/nixos-generation-12-deconflicted.conf"),
+            ]
+        );
+        assert!(identified_files.to_add.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_adopts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (generated_entries, esp) = self::scaffold(tempdir.path());
+        let mut identified_files =
+            IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None)).unwrap();
+
+        resolve(&mut identified_files, &generated_entries, &esp, true).unwrap();
+        assert_eq!(
+            entries(&identified_files.to_replace),
+            vec![
+                esp.join("loader/entries/nixos-generation-11.conf"),
+                esp.join("loader/entries/nixos-generation-12.conf"),
+            ]
+        );
+        assert!(identified_files.to_add.is_empty());
+        // the staged manifest, which goes on the ESP, records it
+        assert!(Manifest::load(&generated_entries)
+            .unwrap()
+            .unwrap()
+            .entries
+            .contains_key("loader/entries/nixos-generation-12.conf"));
+    }
+
+    #[test]
+    fn test_resolve_without_record() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (generated_entries, esp) = self::scaffold(tempdir.path());
+        fs::write(esp.join(MANIFEST), r#"{"files": []}"#).unwrap();
+        let mut identified_files =
+            IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None)).unwrap();
+
+        resolve(&mut identified_files, &generated_entries, &esp, false).unwrap();
+        assert_eq!(entries(&identified_files.to_replace).len(), 2);
+        assert!(identified_files.to_add.is_empty());
+    }
+}
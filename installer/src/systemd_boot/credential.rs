@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::util;
+
+/// An (encrypted) credential that systemd-stub passes on to the booted system.
+///
+/// Only ever refers to the credential's file: its contents must not end up in logs or the plan.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Credential {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Credential {
+    pub fn filename(&self) -> String {
+        format!("{}.cred", self.name)
+    }
+}
+
+impl FromStr for Credential {
+    type Err = String;
+
+    /// Parses `name=path`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = match s.split_once('=') {
+            Some((name, path)) => (name, path),
+            None => return Err(format!("'{}' is not of the form name=path", s)),
+        };
+
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return Err(format!(
+                "credential name '{}' must be non-empty, not start with '.', and only contain ASCII letters, digits, '-', '_', and '.'",
+                name
+            ));
+        }
+
+        if path.is_empty() {
+            return Err(format!("credential '{}' has an empty path", name));
+        }
+
+        Ok(Credential {
+            name: name.to_string(),
+            path: util::normalize_path(path)?,
+        })
+    }
+}
+
+/// Where credentials are put on the ESP.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum CredentialScope {
+    /// `loader/credentials/`, which systemd-stub picks up for every unified EFI file
+    #[default]
+    Global,
+    /// The default generation's `<unified EFI file>.extra.d/`
+    Generation,
+}
+
+impl FromStr for CredentialScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(CredentialScope::Global),
+            "generation" => Ok(CredentialScope::Generation),
+            _ => Err(format!(
+                "'{}' is not a credential scope (expected 'global' or 'generation')",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credential() {
+        assert_eq!(
+            "tpm.secret=/run/keys/secret.cred"
+                .parse::<Credential>()
+                .unwrap(),
+            Credential {
+                name: String::from("tpm.secret"),
+                path: PathBuf::from("/run/keys/secret.cred"),
+            }
+        );
+        assert_eq!(
+            "secret=./keys/../secret.cred"
+                .parse::<Credential>()
+                .unwrap(),
+            Credential {
+                name: String::from("secret"),
+                path: std::env::current_dir().unwrap().join("secret.cred"),
+            }
+        );
+        assert_eq!(
+            "secret=secret.cred"
+                .parse::<Credential>()
+                .unwrap()
+                .filename(),
+            "secret.cred"
+        );
+
+        for invalid in [
+            "secret",
+            "=/run/keys/secret.cred",
+            ".secret=/run/keys/secret.cred",
+            "../secret=/run/keys/secret.cred",
+            "sec ret=/run/keys/secret.cred",
+            "secret=",
+        ] {
+            assert!(invalid.parse::<Credential>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_credential_scope() {
+        assert_eq!("global".parse(), Ok(CredentialScope::Global));
+        assert_eq!("generation".parse(), Ok(CredentialScope::Generation));
+        assert!("everywhere".parse::<CredentialScope>().is_err());
+    }
+}
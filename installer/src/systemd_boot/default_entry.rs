@@ -0,0 +1,159 @@
+//! The entry that loader.conf defaults to (or that's booted once), which has to be on the ESP once
+//! the run is done: its name depends on more than the generation (e.g. a profile's generations'
+//! entries have its name in theirs, and an entry that collides with one the installer didn't put
+//! there is installed under a deconflicted name), and pointing loader.conf at a name that nothing
+//! is written to leaves systemd-boot booting whatever sorts first, or another generation.
+//!
+//! An entry that's identical to the one on the ESP isn't copied again, but it stays on the ESP
+//! under the name it was staged under, so a staged entry is always there after the run.
+
+use std::path::Path;
+
+use log::debug;
+
+use super::collision;
+use crate::util::Generation;
+use crate::Result;
+
+/// The filename of generation `idx`'s entry, as it will be on `root` (the partition with the
+/// generations' entries) after the run: staged in `generated_entries` (under its own name, or its
+/// deconflicted one), or already on `root`. `default_generation` decides the profile, if it's
+/// generation `idx`; otherwise it's the first of `wanted_generations` with that index (preferring
+/// the system profile's).
+///
+/// Fails if it'd be under neither name, unless nothing's staged at all.
+pub(super) fn resolve(
+    generated_entries: &Path,
+    root: &Path,
+    wanted_generations: &[Generation],
+    default_generation: &Generation,
+    idx: usize,
+) -> Result<String> {
+    let profile = if default_generation.idx == idx {
+        default_generation.profile.as_deref()
+    } else {
+        wanted_generations
+            .iter()
+            .filter(|generation| generation.idx == idx)
+            .min_by_key(|generation| generation.profile.is_some())
+            .and_then(|generation| generation.profile.as_deref())
+    };
+    let name = generator::systemd_boot::entry_name(profile, idx, None);
+
+    let staged = generated_entries.join("loader/entries");
+    if !staged.is_dir() {
+        debug!(
+            "no entries are staged in '{}', so not checking that '{}' is",
+            generated_entries.display(),
+            name
+        );
+        return Ok(name);
+    }
+
+    let candidates = [name.clone(), collision::deconflicted(&name)];
+    let installed = root.join("loader/entries");
+    for dir in [&staged, &installed] {
+        if let Some(found) = candidates.iter().find(|name| dir.join(name).is_file()) {
+            debug!("generation {}'s entry is '{}'", idx, found);
+            return Ok(found.clone());
+        }
+    }
+
+    Err(format!(
+        "generation {}'s entry '{}' is neither staged in '{}' nor on '{}', so loader.conf \
+         would point at nothing",
+        idx,
+        name,
+        generated_entries.display(),
+        root.display()
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn generation(idx: usize, profile: Option<&str>) -> Generation {
+        Generation {
+            idx,
+            profile: profile.map(ToString::to_string),
+            path: PathBuf::from(idx.to_string()),
+            required_filenames: vec![OsString::from(generator::systemd_boot::entry_name(
+                profile, idx, None,
+            ))],
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generated_entries = tempdir.path().join("generated");
+        let esp = tempdir.path().join("esp");
+        let wanted = [
+            generation(1, Some("work")),
+            generation(1, None),
+            generation(2, Some("work")),
+        ];
+
+        // nothing's staged
+        assert_eq!(
+            resolve(&generated_entries, &esp, &wanted, &wanted[2], 3).unwrap(),
+            "nixos-generation-3.conf"
+        );
+
+        fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        let stage = |name: &str| {
+            fs::write(generated_entries.join("loader/entries").join(name), "").unwrap();
+        };
+        stage("nixos-generation-1.conf");
+        stage("nixos-work-generation-1.conf");
+        stage("nixos-work-generation-2.conf");
+
+        // the default generation's profile wins, then the system profile's
+        for (default, idx, expected) in [
+            (&wanted[2], 2, "nixos-work-generation-2.conf"),
+            (&wanted[0], 1, "nixos-work-generation-1.conf"),
+            (&wanted[1], 1, "nixos-generation-1.conf"),
+            (&wanted[2], 1, "nixos-generation-1.conf"),
+        ] {
+            assert_eq!(
+                resolve(&generated_entries, &esp, &wanted, default, idx).unwrap(),
+                expected,
+                "{:?} {}",
+                default,
+                idx
+            );
+        }
+
+        // deconflicted
+        fs::rename(
+            generated_entries.join("loader/entries/nixos-work-generation-2.conf"),
+            generated_entries.join("loader/entries/nixos-work-generation-2-deconflicted.conf"),
+        )
+        .unwrap();
+        fs::write(esp.join("loader/entries/nixos-work-generation-2.conf"), "").unwrap();
+        assert_eq!(
+            resolve(&generated_entries, &esp, &wanted, &wanted[2], 2).unwrap(),
+            "nixos-work-generation-2-deconflicted.conf"
+        );
+
+        // only on the ESP
+        let default = generation(4, None);
+        fs::write(esp.join("loader/entries/nixos-generation-4.conf"), "").unwrap();
+        assert_eq!(
+            resolve(&generated_entries, &esp, &wanted, &default, 4).unwrap(),
+            "nixos-generation-4.conf"
+        );
+
+        // nowhere
+        let err = resolve(&generated_entries, &esp, &wanted, &generation(5, None), 5)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'nixos-generation-5.conf'"), "{}", err);
+    }
+}
@@ -0,0 +1,640 @@
+//! Checks that the environment has what an install needs: `bootctl` (recent enough), the signing
+//! tools, mounted and writable ESPs, efivarfs, and a machine-id. Each check passes, warns, or fails
+//! (only if what it checks is needed with the arguments given), with a hint for fixing it.
+//!
+//! `installer systemd-boot doctor` prints every check; every run does the same checks first, and
+//! stops before touching anything if one of them fails.
+
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use super::machine_id;
+use super::sd_boot_model;
+use super::version::systemd::SystemdVersion;
+use crate::command;
+use crate::fat;
+use crate::secure_boot;
+use crate::util;
+use crate::{Args, Result};
+
+/// The oldest systemd-boot the installer works with: `bootctl set-oneshot` (for `--stage-oneshot`)
+/// was added in systemd 240.
+const MIN_SYSTEMD_VERSION: u32 = 240;
+const EFIVARS: &str = "/sys/firmware/efi/efivars";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    Pass,
+    /// Something isn't right, but the install can go ahead
+    Warn,
+    /// The install can't go ahead
+    Fail,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    /// How to fix what's wrong
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: impl ToString, detail: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Pass,
+            detail: detail.to_string(),
+            hint: None,
+        }
+    }
+
+    fn fail(name: impl ToString, detail: impl ToString, hint: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Fail,
+            detail: detail.to_string(),
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    /// Makes a failure a warning, unless what was checked is `required`.
+    fn required(self, required: bool) -> Self {
+        match self.status {
+            Status::Fail if !required => Self {
+                status: Status::Warn,
+                ..self
+            },
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "[{}] {}: {}", status, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       hint: {}", hint)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the checks look at the system (which tests point elsewhere).
+pub(crate) struct System {
+    pub mountinfo: PathBuf,
+    pub efivars: PathBuf,
+    /// The ukify to check for (only `installer systemd-boot doctor` does, since the installer never
+    /// runs it)
+    pub ukify: Option<PathBuf>,
+}
+
+impl Default for System {
+    fn default() -> Self {
+        Self {
+            mountinfo: PathBuf::from(fat::MOUNTINFO),
+            efivars: PathBuf::from(EFIVARS),
+            ukify: None,
+        }
+    }
+}
+
+/// Runs every check, for the install that `args` describe.
+pub(crate) fn checks(args: &Args, system: &System) -> Vec<Check> {
+    let mut checks = vec![self::check_bootctl(args)];
+    checks.extend(self::check_signing(args));
+    checks.extend(system.ukify.as_deref().map(self::check_ukify));
+    checks.extend(self::check_esps(args, &system.mountinfo));
+    checks.push(self::check_efivarfs(args, &system.efivars));
+    checks.push(self::check_machine_id(args));
+
+    checks
+}
+
+/// `installer systemd-boot doctor`: prints the [`checks`] (as JSON, with `json`), failing if any of
+/// them did.
+pub(crate) fn doctor(args: &Args, system: &System, json: bool) -> Result<()> {
+    let checks = self::checks(args, system);
+
+    let mut stdout = std::io::stdout();
+    if json {
+        writeln!(stdout, "{}", serde_json::to_string_pretty(&checks)?)?;
+    } else {
+        for check in &checks {
+            writeln!(stdout, "{}", check)?;
+        }
+    }
+
+    self::failures(&checks)
+}
+
+/// Does the [`checks`] before a run, logging the warnings and failing if any of them failed.
+pub(crate) fn preflight(args: &Args, system: &System) -> Result<()> {
+    let checks = self::checks(args, system);
+    for check in checks.iter().filter(|check| check.status != Status::Fail) {
+        match check.status {
+            Status::Warn => warn!("{}", check),
+            _ => debug!("{}", check),
+        }
+    }
+
+    self::failures(&checks)
+}
+
+fn failures(checks: &[Check]) -> Result<()> {
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        return Err(format!(
+            "{} of the environment checks failed:\n{}",
+            failed.len(),
+            failed.join("\n")
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn check_bootctl(args: &Args) -> Check {
+    const NAME: &str = "bootctl";
+
+    let bootctl = match (&args.bootctl, args.no_bootctl) {
+        (_, true) => return Check::pass(NAME, "not used (--no-bootctl)"),
+        (Some(bootctl), false) => bootctl,
+        (None, false) => return Check::fail(
+            NAME,
+            "--bootctl wasn't passed",
+            "pass the path to systemd's bootctl, or --no-bootctl if systemd-boot is managed by \
+                 something else (e.g. the firmware)",
+        ),
+    };
+
+    let version = match SystemdVersion::detect_version(bootctl) {
+        Ok(version) => version,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("couldn't get the version of '{}': {}", bootctl.display(), e),
+                "check that --bootctl is systemd's bootctl, and that it runs",
+            )
+        }
+    };
+
+    match version.major() {
+        Some(major) if major < MIN_SYSTEMD_VERSION => Check::fail(
+            NAME,
+            format!(
+                "systemd-boot {} is older than {}",
+                version.version, MIN_SYSTEMD_VERSION
+            ),
+            format!(
+                "use the bootctl of systemd {} or newer",
+                MIN_SYSTEMD_VERSION
+            ),
+        ),
+        Some(_) if !sd_boot_model::supports_sort_key(Some(&version)) => Check::fail(
+            NAME,
+            format!(
+                "systemd-boot {} doesn't support sort-key, so entries aren't grouped by profile",
+                version.version
+            ),
+            "use the bootctl of systemd 250 or newer",
+        )
+        .required(false),
+        Some(_) => Check::pass(NAME, format!("systemd-boot {}", version.version)),
+        None => Check::fail(
+            NAME,
+            format!(
+                "couldn't tell whether systemd-boot '{}' is {} or newer",
+                version.version, MIN_SYSTEMD_VERSION
+            ),
+            "check that --bootctl is systemd's bootctl",
+        )
+        .required(false),
+    }
+}
+
+fn check_signing(args: &Args) -> Vec<Check> {
+    let (sbsign, sbverify) = match (&args.sbsign, &args.sbverify) {
+        (Some(sbsign), Some(sbverify)) => (sbsign, sbverify),
+        _ => return vec![Check::pass("signing", "not signing (no --signing-key)")],
+    };
+    let hint = |tool: &str| format!("pass the path to {} (from sbsigntools)", tool);
+
+    let mut checks = Vec::new();
+    for (name, flag, tool) in [
+        ("sbsign", "--sbsign", sbsign),
+        ("sbverify", "--sbverify", sbverify),
+    ] {
+        checks.push(match secure_boot::check_executable(tool, flag) {
+            Ok(()) => Check::pass(name, tool.display()),
+            Err(e) => Check::fail(name, e, hint(name)),
+        });
+    }
+    let sbattach = secure_boot::sbattach_path(args.sbattach.as_deref()).and_then(|sbattach| {
+        secure_boot::check_executable(&sbattach, "--sbattach")?;
+        Ok(sbattach)
+    });
+    checks.push(match sbattach {
+        Ok(sbattach) => Check::pass("sbattach", sbattach.display()),
+        Err(e) => Check::fail(
+            "sbattach",
+            e,
+            "pass the path to the patched sbattach (see patched-sbattach.nix) with --sbattach",
+        ),
+    });
+
+    if checks.iter().all(|check| check.status == Status::Pass) {
+        checks.push(match super::signing_info(args) {
+            Ok(Some(signing_info)) => match signing_info.preflight() {
+                Ok(()) => Check::pass("signing", "signed and verified a test image"),
+                Err(e) => Check::fail(
+                    "signing",
+                    e,
+                    "check that --signing-key and --signing-cert are a matching key and certificate",
+                ),
+            },
+            Ok(None) => Check::pass("signing", "not signing (no --signing-key)"),
+            Err(e) => Check::fail("signing", e, "pass all of the signing options"),
+        });
+    }
+
+    checks
+}
+
+/// ukify isn't run by the installer, but building unified kernel images outside of the generator
+/// needs it.
+fn check_ukify(ukify: &Path) -> Check {
+    let output = command::output(Command::new(ukify).arg("--version"));
+
+    match output {
+        Ok(output) if output.status.success() => {
+            let version = util::from_utf8_lossy(&output.stdout, "the output of `ukify --version`");
+            Check::pass("ukify", version.trim())
+        }
+        Ok(output) => Check::fail(
+            "ukify",
+            format!(
+                "`{} --version` failed with {}",
+                ukify.display(),
+                output.status
+            ),
+            "install systemd's ukify (it's only needed to build unified kernel images by hand)",
+        )
+        .required(false),
+        Err(e) => Check::fail(
+            "ukify",
+            e,
+            "install systemd's ukify (it's only needed to build unified kernel images by hand)",
+        )
+        .required(false),
+    }
+}
+
+/// Whether the ESPs (and the XBOOTLDR partition) are mounted, and writable.
+fn check_esps(args: &Args, mountinfo: &Path) -> Vec<Check> {
+    if args.esp.is_empty() {
+        return vec![Check::fail(
+            "esp",
+            "no ESP was specified",
+            "pass the ESP's mount point with --esp",
+        )];
+    }
+
+    let mounts = fs::read_to_string(mountinfo)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| fat::parse_mountinfo(&contents).map_err(|e| e.to_string()));
+    let roots = args
+        .esp
+        .iter()
+        .map(|esp| ("esp", esp))
+        .chain(args.xbootldr.iter().map(|xbootldr| ("xbootldr", xbootldr)));
+
+    let mut checks = Vec::new();
+    for (name, root) in roots {
+        let flag = format!("--{}", name);
+        if !root.is_dir() {
+            checks.push(Check::fail(
+                name,
+                format!("'{}' doesn't exist", root.display()),
+                format!(
+                    "mount the partition there, or pass its mount point with {}",
+                    flag
+                ),
+            ));
+            continue;
+        }
+
+        checks.push(match &mounts {
+            Ok(mounts) => match fat::find_mount(mounts, root) {
+                Some(mount) if mount.mount_point != *root => Check::fail(
+                    name,
+                    format!(
+                        "nothing is mounted at '{}' (it's on '{}')",
+                        root.display(),
+                        mount.mount_point.display()
+                    ),
+                    format!(
+                        "mount the partition there, or pass its mount point with {}",
+                        flag
+                    ),
+                ),
+                Some(mount) if mount.read_only => Check::fail(
+                    name,
+                    format!("'{}' is mounted read-only", root.display()),
+                    format!(
+                        "remount it read-write (`mount -o remount,rw {}`)",
+                        root.display()
+                    ),
+                )
+                .required(!args.dry_run),
+                Some(mount) if !matches!(mount.fs_type.as_str(), "vfat" | "msdos") => Check::fail(
+                    name,
+                    format!("'{}' is {}, not FAT", root.display(), mount.fs_type),
+                    "the firmware can only be counted on to read FAT",
+                )
+                .required(false),
+                Some(mount) => Check::pass(
+                    name,
+                    format!("'{}' is mounted from {}", root.display(), mount.device),
+                ),
+                None => Check::fail(
+                    name,
+                    format!("couldn't find the mount '{}' is on", root.display()),
+                    format!(
+                        "mount the partition there, or pass its mount point with {}",
+                        flag
+                    ),
+                ),
+            },
+            Err(e) => Check::fail(
+                name,
+                format!("couldn't read '{}': {}", mountinfo.display(), e),
+                "mount /proc",
+            )
+            .required(false),
+        });
+
+        checks.push(match tempfile::NamedTempFile::new_in(root) {
+            Ok(_) => Check::pass(
+                format!("{} writable", name),
+                format!("'{}' is writable", root.display()),
+            ),
+            Err(e) => Check::fail(
+                format!("{} writable", name),
+                format!("'{}' isn't writable: {}", root.display(), e),
+                "run the installer as root, with the partition mounted read-write",
+            )
+            .required(!args.dry_run),
+        });
+    }
+
+    checks
+}
+
+fn check_efivarfs(args: &Args, efivars: &Path) -> Check {
+    let available = fs::read_dir(efivars)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+
+    if available {
+        return Check::pass(
+            "efivarfs",
+            format!("'{}' has EFI variables", efivars.display()),
+        );
+    }
+
+    Check::fail(
+        "efivarfs",
+        format!("no EFI variables in '{}'", efivars.display()),
+        format!(
+            "boot in UEFI mode, and mount efivarfs at '{}' (`mount -t efivarfs efivarfs {}`)",
+            efivars.display(),
+            efivars.display()
+        ),
+    )
+    .required(args.can_touch_efi_vars || args.verify_cert_enrolled)
+}
+
+/// The machine-id is only needed for entries generated with `--machine-id-placeholder`.
+fn check_machine_id(args: &Args) -> Check {
+    let needed = machine_id::has_placeholder(&args.generated_entries.join("loader/entries"))
+        .unwrap_or(false);
+
+    match machine_id::read(&args.machine_id_file) {
+        Ok(machine_id) => Check::pass("machine-id", machine_id),
+        Err(e) => Check::fail(
+            "machine-id",
+            e,
+            "run `systemd-machine-id-setup`, or pass the file with the machine-id with \
+             --machine-id-file",
+        )
+        .required(needed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    fn stub(dir: &Path, name: &str, script: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    /// An environment with everything a signed install needs, all of which passes.
+    fn environment(dir: &Path) -> (Args, System) {
+        let esp = dir.join("esp");
+        let efivars = dir.join("efivars");
+        fs::create_dir_all(&esp).unwrap();
+        fs::create_dir_all(&efivars).unwrap();
+        fs::write(
+            efivars.join("BootCurrent-8be4df61-93ca-11d2-aa0d-00e098032b8c"),
+            "",
+        )
+        .unwrap();
+
+        let mountinfo = dir.join("mountinfo");
+        fs::write(
+            &mountinfo,
+            format!(
+                "22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw\n\
+                 23 22 259:1 / {} rw,relatime shared:2 - vfat /dev/nvme0n1p1 rw\n",
+                esp.display()
+            ),
+        )
+        .unwrap();
+
+        let machine_id_file = dir.join("machine-id");
+        fs::write(&machine_id_file, "0123456789abcdef0123456789abcdef\n").unwrap();
+
+        let args = Args {
+            generated_entries: dir.join("generated"),
+            esp: vec![esp],
+            bootctl: Some(stub(dir, "bootctl", "echo 'systemd 252 (252.4-1)'")),
+            signing_key: Some(dir.join("db.key")),
+            signing_cert: Some(dir.join("db.crt")),
+            sbsign: Some(stub(dir, "sbsign", "exit 0")),
+            sbverify: Some(stub(dir, "sbverify", "exit 0")),
+            sbattach: Some(stub(dir, "sbattach", "exit 0")),
+            machine_id_file,
+            can_touch_efi_vars: true,
+            ..Default::default()
+        };
+        let system = System {
+            mountinfo,
+            efivars,
+            ukify: Some(stub(dir, "ukify", "echo 'ukify 252.4'")),
+        };
+
+        (args, system)
+    }
+
+    #[test]
+    fn test_good_environment() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (args, system) = environment(tempdir.path());
+
+        let checks = checks(&args, &system);
+        assert!(
+            checks.iter().all(|check| check.status == Status::Pass),
+            "{:#?}",
+            checks
+        );
+        let names = checks
+            .iter()
+            .map(|check| check.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "bootctl",
+                "sbsign",
+                "sbverify",
+                "sbattach",
+                "signing",
+                "ukify",
+                "esp",
+                "esp writable",
+                "efivarfs",
+                "machine-id"
+            ]
+        );
+        assert_eq!(checks[0].detail, "systemd-boot 252.4-1");
+        assert_eq!(checks[5].detail, "ukify 252.4");
+
+        failures(&checks).unwrap();
+        preflight(&args, &system).unwrap();
+
+        let json = serde_json::to_value(&checks).unwrap();
+        assert_eq!(json[0]["status"], "pass");
+        assert_eq!(json[0]["hint"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_broken_environment() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let (mut args, mut system) = environment(dir);
+
+        args.bootctl = Some(stub(dir, "bootctl", "echo 'systemd 239 (239)'"));
+        // not executable
+        fs::set_permissions(
+            args.sbsign.as_ref().unwrap(),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        args.sbattach = Some(dir.join("missing/sbattach"));
+        args.xbootldr = Some(dir.join("missing/boot"));
+        system.ukify = Some(dir.join("missing/ukify"));
+        // nothing is mounted at the ESP
+        fs::write(
+            &system.mountinfo,
+            "22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw\n",
+        )
+        .unwrap();
+        for entry in fs::read_dir(&system.efivars).unwrap() {
+            fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        fs::write(&args.machine_id_file, "uninitialized\n").unwrap();
+        let entries = args.generated_entries.join("loader/entries");
+        fs::create_dir_all(&entries).unwrap();
+        fs::write(
+            entries.join("nixos-generation-1.conf"),
+            "machine-id @MACHINE_ID@\n",
+        )
+        .unwrap();
+
+        let checks = checks(&args, &system);
+        let status = |name: &str| {
+            checks
+                .iter()
+                .filter(|check| check.name == name)
+                .map(|check| check.status)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(status("bootctl"), [Status::Fail]);
+        assert_eq!(status("sbsign"), [Status::Fail]);
+        assert_eq!(status("sbverify"), [Status::Pass]);
+        assert_eq!(status("sbattach"), [Status::Fail]);
+        // the signing tools aren't tried out while some of them are missing
+        assert!(status("signing").is_empty());
+        assert_eq!(status("ukify"), [Status::Warn]);
+        assert_eq!(status("esp"), [Status::Fail]);
+        assert_eq!(status("esp writable"), [Status::Pass]);
+        assert_eq!(status("xbootldr"), [Status::Fail]);
+        assert_eq!(status("efivarfs"), [Status::Fail]);
+        assert_eq!(status("machine-id"), [Status::Fail]);
+        assert!(checks
+            .iter()
+            .filter(|check| check.status != Status::Pass)
+            .all(|check| check.hint.is_some()));
+
+        let err = failures(&checks).unwrap_err().to_string();
+        assert!(
+            err.starts_with("7 of the environment checks failed"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("systemd-boot 239 is older than 240"),
+            "{}",
+            err
+        );
+        assert!(preflight(&args, &system).is_err());
+
+        // without --can-touch-efi-vars and the placeholder, efivarfs and the machine-id are only
+        // warned about
+        args.can_touch_efi_vars = false;
+        fs::remove_dir_all(&args.generated_entries).unwrap();
+        let checks = super::checks(&args, &system);
+        for name in ["efivarfs", "machine-id"] {
+            let check = checks.iter().find(|check| check.name == name).unwrap();
+            assert_eq!(check.status, Status::Warn, "{}", check);
+        }
+    }
+}
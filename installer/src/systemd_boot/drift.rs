@@ -0,0 +1,140 @@
+//! What a plan saw of the ESP (and XBOOTLDR partition) when it was created.
+//!
+//! A plan decides which files to replace, skip, and add when it's created, but only acts on those
+//! decisions when it's consumed. If something else writes to the ESP in between (e.g. another tool
+//! replaces a file the plan decided to skip), consuming the plan would silently act on stale
+//! decisions, so it first checks that the files still look the way they did.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::context::Context;
+use crate::manifest;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// How many hex digits of a file's SHA-256 are kept.
+const HASH_PREFIX_LEN: usize = 16;
+
+/// What a file looked like when it was observed.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Observed {
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub hash_prefix: String,
+}
+
+/// The files a plan acts on or deliberately skips, by their path on the ESP, and what they looked
+/// like when the plan was created (`None` if they didn't exist).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct EspSnapshot {
+    pub files: BTreeMap<PathBuf, Option<Observed>>,
+}
+
+impl EspSnapshot {
+    pub fn observe<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for path in paths {
+            files.insert(path.to_path_buf(), self::observe_file(path)?);
+        }
+
+        Ok(Self { files })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The files that no longer look the way they did when they were observed.
+    pub fn drifted(&self) -> Result<Vec<&Path>> {
+        let mut drifted = Vec::new();
+        for (path, observed) in &self.files {
+            if &self::observe_file(path)? != observed {
+                drifted.push(path.as_path());
+            }
+        }
+
+        Ok(drifted)
+    }
+
+    /// Fails if any of the files drifted, unless `ignore_drift` (`--ignore-esp-drift`), in which
+    /// case it only warns.
+    pub fn verify(&self, ignore_drift: bool) -> Result<()> {
+        let drifted = self.drifted()?;
+        if drifted.is_empty() {
+            return Ok(());
+        }
+
+        let list = drifted
+            .iter()
+            .map(|path| format!("'{}'", path.display()))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if ignore_drift {
+            warnings::emit(
+                Kind::EspDrift,
+                format!("ESP changed since planning, going ahead anyway: {}", list),
+            );
+            Ok(())
+        } else {
+            Err(format!(
+                "ESP changed since planning: {} (pass --ignore-esp-drift to go ahead anyway)",
+                list
+            )
+            .into())
+        }
+    }
+}
+
+fn observe_file(path: &Path) -> Result<Option<Observed>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_path_context(path),
+    };
+
+    let mut hash_prefix = manifest::sha256_file(path)?;
+    hash_prefix.truncate(HASH_PREFIX_LEN);
+
+    Ok(Some(Observed {
+        size: metadata.len(),
+        mtime: metadata.modified().with_path_context(path)?,
+        hash_prefix,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drifted() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let kept = tempdir.path().join("kept");
+        let changed = tempdir.path().join("changed");
+        let created = tempdir.path().join("created");
+        let removed = tempdir.path().join("removed");
+        fs::write(&kept, "kept").unwrap();
+        fs::write(&changed, "changed").unwrap();
+        fs::write(&removed, "removed").unwrap();
+
+        let snapshot =
+            EspSnapshot::observe([&kept, &changed, &created, &removed].map(PathBuf::as_path))
+                .unwrap();
+        assert!(snapshot.files[&created].is_none());
+        snapshot.verify(false).unwrap();
+
+        fs::write(&changed, "CHANGED").unwrap();
+        fs::write(&created, "created").unwrap();
+        fs::remove_file(&removed).unwrap();
+
+        assert_eq!(snapshot.drifted().unwrap(), [&changed, &created, &removed]);
+        let err = snapshot.verify(false).unwrap_err().to_string();
+        assert!(err.contains("ESP changed since planning"), "{}", err);
+        assert!(!err.contains("'kept'"), "{}", err);
+        snapshot.verify(true).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! The removable-media fallback loaders (`EFI/BOOT/BOOT*.EFI`), which `bootctl install` overwrites
+//! with systemd-boot even when another boot loader (e.g. rEFInd or GRUB) put them there.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use regex::bytes::Regex;
+
+use crate::context::Context;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+lazy_static::lazy_static! {
+    /// What every systemd-boot binary identifies itself with (in its `.sdmagic` section, or among
+    /// its strings in older releases).
+    static ref LOADER_INFO_RE: Regex =
+        Regex::new(r"#### LoaderInfo: systemd-boot [^ \x00]+ ####").unwrap();
+}
+
+/// The directory the fallback loaders are in, relative to the root of the ESP.
+const FALLBACK_DIR: &str = "EFI/BOOT";
+
+/// The fallback loaders on `esp` that aren't systemd-boot.
+pub(crate) fn foreign_loaders(esp: &Path) -> Result<Vec<PathBuf>> {
+    let dir = esp.join(FALLBACK_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut foreign = Vec::new();
+    for entry in fs::read_dir(&dir).with_path_context(&dir)? {
+        let path = entry.with_path_context(&dir)?.path();
+        // FAT is case-insensitive, so the firmware's `BOOTX64.EFI` could be e.g. `bootx64.efi`
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = name.to_ascii_uppercase();
+        if !(name.starts_with("BOOT") && name.ends_with(".EFI")) || !path.is_file() {
+            continue;
+        }
+
+        if self::is_systemd_boot(&path)? {
+            debug!("'{}' is systemd-boot", path.display());
+        } else {
+            foreign.push(path);
+        }
+    }
+    foreign.sort();
+
+    Ok(foreign)
+}
+
+/// Whether the EFI binary at `path` is systemd-boot.
+pub(crate) fn is_systemd_boot(path: &Path) -> Result<bool> {
+    let binary = fs::read(path).with_path_context(path)?;
+
+    Ok(LOADER_INFO_RE.is_match(&binary))
+}
+
+/// Moves the `loaders` out of the way of `bootctl install`, which is run by `install`, and puts
+/// them back afterwards (whether it succeeded or not).
+pub(crate) fn keep_while<T>(loaders: &[PathBuf], install: impl FnOnce() -> Result<T>) -> Result<T> {
+    let backup = |loader: &Path| {
+        let mut backup = loader.as_os_str().to_owned();
+        backup.push(".nixos-backup");
+        PathBuf::from(backup)
+    };
+
+    for loader in loaders {
+        warnings::emit(Kind::ForeignFallbackLoader, format!(
+            "keeping '{}', which isn't systemd-boot (pass --overwrite-fallback-loader to replace it)",
+            loader.display()
+        ));
+        fs::rename(loader, backup(loader)).with_paths_context(loader, backup(loader))?;
+    }
+
+    let ret = install();
+
+    for loader in loaders {
+        fs::rename(backup(loader), loader).with_paths_context(backup(loader), loader)?;
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/fallback-loaders")
+            .join(name)
+    }
+
+    #[test]
+    fn test_is_systemd_boot() {
+        assert!(is_systemd_boot(&fixture("systemd-bootx64.efi")).unwrap());
+        assert!(!is_systemd_boot(&fixture("refind_x64.efi")).unwrap());
+        // merely mentioning systemd-boot doesn't make it systemd-boot
+        assert!(!is_systemd_boot(&fixture("grubx64.efi")).unwrap());
+        assert!(is_systemd_boot(&fixture("missing.efi")).is_err());
+    }
+
+    #[test]
+    fn test_foreign_loaders() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        assert!(foreign_loaders(esp).unwrap().is_empty());
+
+        let dir = esp.join(FALLBACK_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy(fixture("systemd-bootx64.efi"), dir.join("BOOTX64.EFI")).unwrap();
+        fs::copy(fixture("refind_x64.efi"), dir.join("bootaa64.efi")).unwrap();
+        fs::copy(fixture("grubx64.efi"), dir.join("grubx64.efi")).unwrap();
+        assert_eq!(foreign_loaders(esp).unwrap(), [dir.join("bootaa64.efi")]);
+
+        fs::copy(fixture("grubx64.efi"), dir.join("BOOTX64.EFI")).unwrap();
+        assert_eq!(
+            foreign_loaders(esp).unwrap(),
+            [dir.join("BOOTX64.EFI"), dir.join("bootaa64.efi")]
+        );
+    }
+
+    #[test]
+    fn test_keep_while() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let loader = tempdir.path().join("BOOTX64.EFI");
+        fs::copy(fixture("refind_x64.efi"), &loader).unwrap();
+        let refind = fs::read(&loader).unwrap();
+
+        // what `bootctl install` would do
+        let overwrite = || {
+            fs::copy(fixture("systemd-bootx64.efi"), &loader).unwrap();
+            Ok(())
+        };
+        keep_while(&[loader.clone()], overwrite).unwrap();
+        assert_eq!(fs::read(&loader).unwrap(), refind);
+
+        // and even if it fails
+        let fail = || -> Result<()> {
+            fs::copy(fixture("systemd-bootx64.efi"), &loader).unwrap();
+            Err("bootctl failed".into())
+        };
+        assert!(keep_while(&[loader.clone()], fail).is_err());
+        assert_eq!(fs::read(&loader).unwrap(), refind);
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,267 @@
+//! Unified kernel images in `EFI/Linux` that other tools put there (e.g. systemd's
+//! `kernel-install`), which systemd-boot lists next to our entries (as Type #2 entries, whose ID is
+//! their filename).
+//!
+//! The installer never writes to `EFI/Linux` (its unified EFI files go in its own `EFI/nixos`, with
+//! an entry each), so it never prunes them either; a UKI there is only ours if the ESP's history or
+//! manifest records it. What they can do is collide with our entries: a foreign
+//! `nixos-generation-12.efi` and our `nixos-generation-12.conf` only differ in their suffix, so e.g.
+//! a `default nixos-generation-12*` pattern in loader.conf matches both. A new entry of ours with the
+//! ID of a foreign UKI is installed under its deconflicted name instead (see [`collision`]).
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use generator::report::History;
+use log::debug;
+
+use super::collision;
+use super::history::HISTORY;
+use crate::context::Context;
+use crate::files::{FileToReplace, IdentifiedFiles};
+use crate::manifest::{self, Manifest, ManifestExt};
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// Where systemd-boot looks for unified kernel images, on the ESP and on the XBOOTLDR partition.
+pub(crate) const LINUX_DIR: &str = "EFI/Linux";
+
+/// The UKIs in `EFI/Linux` on `root` that are foreign: neither the history on `esp` nor the
+/// manifest on `root` records them. They're relative to `root`, and sorted.
+pub(crate) fn scan(root: &Path, esp: &Path) -> Result<Vec<PathBuf>> {
+    let dir = root.join(LINUX_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let history = History::load(&esp.join(HISTORY))?;
+    let manifest = Manifest::load(root)?.unwrap_or_default();
+    let is_ours = |relative: &Path| {
+        history.files.contains_key(relative)
+            || manifest
+                .files
+                .iter()
+                .any(|file| Path::new(&file.path) == relative)
+    };
+
+    let mut foreign = Vec::new();
+    for entry in fs::read_dir(&dir).with_path_context(&dir)? {
+        let path = entry.with_path_context(&dir)?.path();
+        let is_uki = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("efi"));
+        if !is_uki || !path.is_file() {
+            continue;
+        }
+
+        let relative = path.strip_prefix(root)?.to_path_buf();
+        if is_ours(&relative) {
+            debug!("'{}' is one of ours", path.display());
+        } else {
+            debug!("'{}' is a foreign UKI", path.display());
+            foreign.push(relative);
+        }
+    }
+    foreign.sort();
+
+    Ok(foreign)
+}
+
+/// The IDs of the foreign UKIs on any of `roots` (the ESP, and the XBOOTLDR partition), without
+/// their suffix, with where each is.
+fn foreign_ids(roots: &[&Path], esp: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut ids = Vec::new();
+    for root in roots.iter().copied().collect::<BTreeSet<_>>() {
+        for relative in self::scan(root, esp)? {
+            if let Some(id) = relative.file_stem().and_then(OsStr::to_str) {
+                ids.push((id.to_ascii_lowercase(), root.join(&relative)));
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Goes through the new entries in `identified_files` (on `root`, the partition with the
+/// generations' entries), and deconflicts the ones with the ID of a foreign UKI on the ESP or the XBOOTLDR partition: the
+/// staged entry is renamed in `generated_entries` (and in its manifest), with a warning.
+pub(super) fn deconflict(
+    identified_files: &mut IdentifiedFiles,
+    generated_entries: &Path,
+    esp: &Path,
+    root: &Path,
+) -> Result<()> {
+    let foreign = self::foreign_ids(&[esp, root], esp)?;
+    if foreign.is_empty() {
+        return Ok(());
+    }
+
+    let mut to_add = Vec::new();
+    for esp_loc in identified_files.to_add.drain(..) {
+        let relative = match esp_loc.strip_prefix(root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => {
+                to_add.push(esp_loc);
+                continue;
+            }
+        };
+        let id = relative
+            .strip_prefix("loader/entries")
+            .ok()
+            .filter(|name| name.components().count() == 1)
+            .filter(|name| name.extension() == Some(OsStr::new("conf")))
+            .filter(|name| collision::original(name.as_os_str()).is_none())
+            .and_then(Path::file_stem)
+            .and_then(OsStr::to_str)
+            .map(str::to_ascii_lowercase);
+        let uki = match foreign
+            .iter()
+            .find(|(foreign, _)| Some(foreign) == id.as_ref())
+        {
+            Some((_, uki)) => uki,
+            None => {
+                to_add.push(esp_loc);
+                continue;
+            }
+        };
+        let name = relative
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default();
+        let deconflicted = collision::deconflicted(name);
+        let generated_loc = generated_entries.join(&relative);
+        let new_generated_loc = generated_loc.with_file_name(&deconflicted);
+        let new_esp_loc = esp_loc.with_file_name(&deconflicted);
+        warnings::emit(
+            Kind::ForeignUkiCollision,
+            format!(
+                "'{}' would have the ID of '{}', which the installer didn't put there: installing \
+                 it as '{}' instead",
+                esp_loc.display(),
+                uki.display(),
+                new_esp_loc.display()
+            ),
+        );
+
+        fs::rename(&generated_loc, &new_generated_loc)
+            .with_paths_context(&generated_loc, &new_generated_loc)?;
+        manifest::rename_entry(
+            generated_entries,
+            &relative,
+            &PathBuf::from("loader/entries").join(&deconflicted),
+        )?;
+
+        if new_esp_loc.is_file() {
+            identified_files.to_replace.push(FileToReplace {
+                generated_loc: new_generated_loc,
+                esp_loc: new_esp_loc,
+            });
+        } else {
+            to_add.push(new_esp_loc);
+        }
+    }
+    identified_files.to_add = to_add;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::MANIFEST;
+    use crate::systemd_boot::Layout;
+
+    /// An ESP with a mixed `EFI/Linux`: a UKI that the history records, one that it doesn't, and
+    /// something that isn't a UKI.
+    fn scaffold(esp: &Path) {
+        fs::create_dir_all(esp.join("EFI/Linux")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        for name in ["ours.efi", "nixos-generation-12.EFI", "README"] {
+            fs::write(esp.join("EFI/Linux").join(name), name).unwrap();
+        }
+        fs::write(
+            esp.join(HISTORY),
+            r#"{
+  "formatVersion": 1,
+  "runs": [],
+  "files": {
+    "EFI/Linux/ours.efi": { "run_id": 1, "generation": 11 }
+  }
+}"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_scan() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        assert!(scan(&esp, &esp).unwrap().is_empty());
+
+        self::scaffold(&esp);
+        assert_eq!(
+            scan(&esp, &esp).unwrap(),
+            [PathBuf::from("EFI/Linux/nixos-generation-12.EFI")]
+        );
+
+        // the manifest on the partition it's on makes it ours too
+        fs::write(
+            esp.join(MANIFEST),
+            r#"{ "files": [{ "path": "EFI/Linux/nixos-generation-12.EFI", "sha256": "x" }] }"#,
+        )
+        .unwrap();
+        assert!(scan(&esp, &esp).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deconflict() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let generated_entries = tempdir.path().join("generated_entries");
+        self::scaffold(&esp);
+        fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+        for idx in [11, 12] {
+            let entry = format!("loader/entries/nixos-generation-{}.conf", idx);
+            fs::write(generated_entries.join(&entry), "title NixOS\n").unwrap();
+        }
+        fs::write(
+            generated_entries.join(MANIFEST),
+            r#"{ "files": [], "entries": { "loader/entries/nixos-generation-12.conf": "x" } }"#,
+        )
+        .unwrap();
+        let mut identified_files =
+            IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None)).unwrap();
+
+        deconflict(&mut identified_files, &generated_entries, &esp, &esp).unwrap();
+        let mut to_add = identified_files.to_add.clone();
+        to_add.sort();
+        assert_eq!(
+            to_add,
+            [
+                esp.join("loader/entries/nixos-generation-11.conf"),
+                esp.join("loader/entries/nixos-generation-12-deconflicted.conf"),
+            ]
+        );
+        assert!(generated_entries
+            .join("loader/entries/nixos-generation-12-deconflicted.conf")
+            .exists());
+        let staged = Manifest::load(&generated_entries).unwrap().unwrap();
+        assert!(staged
+            .entries
+            .contains_key("loader/entries/nixos-generation-12-deconflicted.conf"));
+        assert!(warnings::emitted(Kind::ForeignUkiCollision)
+            .iter()
+            .any(|message| message.contains("nixos-generation-12.EFI")));
+
+        // and the foreign UKIs are left as they were
+        for name in ["ours.efi", "nixos-generation-12.EFI", "README"] {
+            assert_eq!(
+                fs::read_to_string(esp.join("EFI/Linux").join(name)).unwrap(),
+                name
+            );
+        }
+    }
+}
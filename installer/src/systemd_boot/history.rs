@@ -0,0 +1,287 @@
+//! The history of the installer's runs on an ESP (see `generator::report::History`), which records
+//! which run last wrote each file it manages there, so that e.g. a boot failure can be correlated
+//! with when the files it booted last changed.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use generator::report::{History, HistoryRun, ManagedFile};
+
+use super::foreign_uki;
+use super::plan::PlanReport;
+use super::slot;
+use super::Layout;
+use crate::util::Generation;
+use crate::Result;
+
+/// Where the history is kept on the ESP.
+pub(crate) const HISTORY: &str = "loader/nixos-history.json";
+
+/// Records the run that started at `started_at`, which made the changes in `plan_report` to the
+/// ESP (and XBOOTLDR partition) in `layout`, in the ESP's history, along with its `backup` (see
+/// `--backup-dir`), and returns its run ID.
+///
+/// The files that were copied are recorded with the newest of `generations` that needs them.
+pub(super) fn record(
+    layout: Layout,
+    plan_report: &PlanReport,
+    generations: &[Generation],
+    started_at: u64,
+    backup: Option<PathBuf>,
+) -> Result<u64> {
+    let written = plan_report
+        .copied
+        .iter()
+        .filter_map(|path| self::relative(layout, path))
+        .map(|relative| {
+            let generation = self::generation(generations, &relative);
+            (relative, generation)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let pruned = plan_report
+        .pruned
+        .iter()
+        .filter_map(|path| self::relative(layout, path))
+        .collect::<Vec<_>>();
+
+    let files_changed = written.len() + pruned.len();
+
+    self::record_run(layout, written, &pruned, files_changed, started_at, backup)
+}
+
+/// Records the `adopt` that started at `started_at` in the ESP's history, with the entries it
+/// `adopted` (along with the generation of the system profile each belongs to) as written by it,
+/// and returns its run ID. Nothing on the ESP changed.
+pub(super) fn record_adopted(
+    layout: Layout,
+    adopted: BTreeMap<PathBuf, Option<usize>>,
+    started_at: u64,
+) -> Result<u64> {
+    self::record_run(layout, adopted, &[], 0, started_at, None)
+}
+
+fn record_run(
+    layout: Layout,
+    written: BTreeMap<PathBuf, Option<usize>>,
+    pruned: &[PathBuf],
+    files_changed: usize,
+    started_at: u64,
+    backup: Option<PathBuf>,
+) -> Result<u64> {
+    let path = layout.esp.join(HISTORY);
+    let mut history = History::load(&path)?;
+
+    let run = HistoryRun {
+        run_id: 0,
+        started_at,
+        installer_version: String::from(env!("CARGO_PKG_VERSION")),
+        files_changed,
+        default_entry: slot::current_default(layout.esp)?,
+        backup,
+        rolled_back: false,
+    };
+    let run_id = history.record(run, written, pruned);
+    history.write(&path)?;
+
+    Ok(run_id)
+}
+
+/// The run ID the next run recorded in the history on `esp` gets.
+pub(super) fn next_run_id(esp: &Path) -> Result<u64> {
+    Ok(History::load(&esp.join(HISTORY))?.next_run_id())
+}
+
+/// Every file the history on the ESP in `layout` records, with when it last changed.
+pub(super) fn managed_files(layout: Layout) -> Result<Vec<ManagedFile>> {
+    let history = History::load(&layout.esp.join(HISTORY))?;
+
+    Ok(history
+        .files
+        .into_iter()
+        .map(|(path, provenance)| ManagedFile {
+            mtime: layout
+                .dest(&path)
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map(|mtime| mtime.as_secs()),
+            path,
+            run_id: provenance.run_id,
+            generation: provenance.generation,
+        })
+        .collect())
+}
+
+/// Prints the runs recorded on `esp`, oldest first (or the whole history as JSON, if `json`).
+pub(crate) fn print_history(esp: &Path, json: bool) -> Result<()> {
+    self::write_history(&mut std::io::stdout(), esp, json)
+}
+
+/// Writes the runs recorded on `esp` to `out`, followed by the UKIs in `EFI/Linux` that other
+/// tools put there, as unmanaged (or the whole history as JSON, if `json`).
+fn write_history(out: &mut impl Write, esp: &Path, json: bool) -> Result<()> {
+    let history = History::load(&esp.join(HISTORY))?;
+
+    if json {
+        serde_json::to_writer_pretty(&mut *out, &history)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    for run in &history.runs {
+        writeln!(
+            out,
+            "run {}: started at {}, installer {}, {} file(s) changed, default {}",
+            run.run_id,
+            run.started_at,
+            run.installer_version,
+            run.files_changed,
+            run.default_entry.as_deref().unwrap_or("(none)")
+        )?;
+    }
+    for uki in foreign_uki::scan(esp, esp)? {
+        writeln!(out, "unmanaged: {}", uki.display())?;
+    }
+
+    Ok(())
+}
+
+/// `path` (on the ESP or XBOOTLDR partition in `layout`) relative to the partition it's on.
+fn relative(layout: Layout, path: &Path) -> Option<PathBuf> {
+    [layout.payload_root(), layout.esp]
+        .iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .map(Path::to_path_buf)
+}
+
+/// The newest of `generations` that needs the file at `path`.
+fn generation(generations: &[Generation], path: &Path) -> Option<usize> {
+    let name = path.file_name()?;
+
+    generations
+        .iter()
+        .filter(|generation| {
+            generation
+                .required_filenames
+                .iter()
+                .any(|required| required.as_os_str() == name)
+        })
+        .map(|generation| generation.idx)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::fs;
+
+    #[test]
+    fn test_record_and_managed_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::write(
+            esp.join("loader/loader.conf"),
+            "default nixos-generation-2.conf\n",
+        )
+        .unwrap();
+        let layout = Layout::new(esp, None);
+        let generation = |idx: usize| Generation {
+            idx,
+            profile: None,
+            path: PathBuf::from(idx.to_string()),
+            required_filenames: vec![
+                OsString::from(format!("nixos-generation-{}.conf", idx)),
+                OsString::from("kernel.efi"),
+            ],
+        };
+        let generations = [generation(1), generation(2)];
+
+        let copied = [
+            "EFI/nixos/kernel.efi",
+            "loader/entries/nixos-generation-2.conf",
+            "loader/loader.conf",
+        ];
+        for path in &copied[..2] {
+            fs::write(esp.join(path), "").unwrap();
+        }
+        let report = PlanReport {
+            copied: copied.iter().map(|path| esp.join(path)).collect(),
+            pruned: vec![esp.join("EFI/nixos/old-kernel.efi")],
+            ..Default::default()
+        };
+        assert_eq!(record(layout, &report, &generations, 100, None).unwrap(), 1);
+
+        let history = History::load(&esp.join(HISTORY)).unwrap();
+        assert_eq!(history.runs.len(), 1);
+        assert_eq!(history.runs[0].files_changed, 4);
+        assert_eq!(
+            history.runs[0].default_entry.as_deref(),
+            Some("nixos-generation-2.conf")
+        );
+
+        // a later run only rewrites what it copied
+        let report = PlanReport {
+            copied: vec![esp.join("loader/loader.conf")],
+            ..Default::default()
+        };
+        assert_eq!(record(layout, &report, &generations, 200, None).unwrap(), 2);
+
+        let managed = managed_files(layout).unwrap();
+        assert_eq!(
+            managed
+                .iter()
+                .map(|file| (file.path.to_str().unwrap(), file.run_id, file.generation))
+                .collect::<Vec<_>>(),
+            [
+                ("EFI/nixos/kernel.efi", 1, Some(2)),
+                ("loader/entries/nixos-generation-2.conf", 1, Some(2)),
+                ("loader/loader.conf", 2, None),
+            ]
+        );
+        assert!(managed.iter().all(|file| file.mtime.is_some()));
+    }
+
+    #[test]
+    fn test_write_history_lists_unmanaged_ukis() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::create_dir_all(esp.join("EFI/Linux")).unwrap();
+        fs::write(
+            esp.join("loader/loader.conf"),
+            "default nixos-generation-1.conf\n",
+        )
+        .unwrap();
+        for name in ["ours.efi", "arch-linux.efi"] {
+            fs::write(esp.join("EFI/Linux").join(name), "").unwrap();
+        }
+        let report = PlanReport {
+            copied: vec![esp.join("EFI/Linux/ours.efi")],
+            ..Default::default()
+        };
+        record(Layout::new(esp, None), &report, &[], 100, None).unwrap();
+
+        let history = |json| {
+            let mut out = Vec::new();
+            write_history(&mut out, esp, json).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let human = history(false);
+        assert!(human.starts_with("run 1: started at 100, "), "{}", human);
+        assert!(
+            human.ends_with(
+                "default nixos-generation-1.conf\nunmanaged: EFI/Linux/arch-linux.efi\n"
+            ),
+            "{}",
+            human
+        );
+        // the JSON is the history as it is
+        assert!(!history(true).contains("arch-linux.efi"));
+    }
+}
@@ -0,0 +1,268 @@
+//! Site-specific steps that run on the staged files once they're signed, before they're copied to
+//! the ESP (`--hook`), e.g. extra attestation, uploading the files' hashes to a transparency log,
+//! or a vendor's blessing tool.
+//!
+//! Every hook runs through the command runner (see [`crate::command`]), in the order they were
+//! passed, as `<hook> <staging directory> <ESP>`, with a [`Payload`] describing the plan as JSON on
+//! its stdin. A hook that fails fails the run before anything is copied to the ESP, and the later
+//! hooks don't run. What the hooks print to stdout ends up in the `--report`.
+//!
+//! With `--dry-run`, the hooks are only listed in the plan, unless `--dry-run-hooks` is passed:
+//! then they run, with `DRY_RUN=1` in their environment.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info};
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::command;
+use crate::context::Context;
+use crate::report::HookReport;
+use crate::Result;
+
+/// The variable that's set (to `1`) in the environment of hooks that run with `--dry-run`.
+pub(crate) const DRY_RUN_ENV: &str = "DRY_RUN";
+
+/// What a hook gets on its stdin.
+#[derive(Debug, PartialEq, Serialize)]
+pub(super) struct Payload<'a> {
+    /// The directory with the staged files, as they're copied to the ESP
+    pub staging: &'a Path,
+    pub esp: &'a Path,
+    /// Where the generations' entries and the files they refer to go instead, with `--xbootldr`
+    pub xbootldr: Option<&'a Path>,
+    pub dry_run: bool,
+    /// Every state of the plan, in order
+    pub stages: &'a [&'static str],
+    /// The files that were signed (none yet, with `--dry-run`)
+    pub signed: &'a [PathBuf],
+    /// The staged files that are about to be copied to the ESP, relative to `staging`
+    pub files: Vec<PathBuf>,
+}
+
+impl<'a> Payload<'a> {
+    /// Describes the plan as it stands, going by the files left in `staging`.
+    pub fn new(
+        staging: &'a Path,
+        esp: &'a Path,
+        xbootldr: Option<&'a Path>,
+        dry_run: bool,
+        stages: &'a [&'static str],
+        signed: &'a [PathBuf],
+    ) -> Result<Self> {
+        let mut files = Vec::new();
+        if staging.exists() {
+            for entry in WalkDir::new(staging).sort_by_file_name() {
+                let entry = entry.with_path_context(staging)?;
+                if entry.file_type().is_file() {
+                    files.push(entry.path().strip_prefix(staging)?.to_path_buf());
+                }
+            }
+        }
+
+        Ok(Self {
+            staging,
+            esp,
+            xbootldr,
+            dry_run,
+            stages,
+            signed,
+            files,
+        })
+    }
+}
+
+/// Runs `hooks` in order with `payload`, recording each in `reports` (including one that fails,
+/// which fails the rest).
+pub(super) fn run(
+    hooks: &[PathBuf],
+    payload: &Payload,
+    reports: &mut Vec<HookReport>,
+) -> Result<()> {
+    let input = serde_json::to_vec(payload)?;
+
+    for hook in hooks {
+        info!("running hook '{}'", hook.display());
+        let mut cmd = Command::new(hook);
+        cmd.arg(payload.staging).arg(payload.esp);
+        if payload.dry_run {
+            cmd.env(DRY_RUN_ENV, "1");
+        }
+
+        let output = command::output_with_input(&mut cmd, &input)?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            debug!(
+                "hook '{}' printed to stderr: {}",
+                hook.display(),
+                stderr.trim()
+            );
+        }
+        reports.push(HookReport {
+            hook: hook.clone(),
+            succeeded: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        });
+
+        if !output.status.success() {
+            return Err(format!(
+                "hook '{}' failed ({}), so nothing was copied to '{}': {}",
+                hook.display(),
+                output.status,
+                payload.esp.display(),
+                stderr.trim()
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+// The stub hooks these tests run are shell scripts.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A hook that appends its name, its arguments, and `DRY_RUN` to `log`, saves its stdin next to
+    /// it, prints `stdout`, and exits with `code`.
+    fn hook(dir: &Path, name: &str, stdout: &str, code: i32) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho \"{name} $1 $2 ${{DRY_RUN:-0}}\" >> {log}\ncat > {stdin}\nprintf '{stdout}'\necho 'some detail' >&2\nexit {code}\n",
+                name = name,
+                log = dir.join("log").display(),
+                stdin = dir.join(format!("{}.stdin", name)).display(),
+                stdout = stdout,
+                code = code,
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_payload() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let staging = tempdir.path().join("staging");
+        for file in ["loader/loader.conf", "EFI/nixos/kernel.efi"] {
+            crate::util::create_dirs_to_file(staging.join(file)).unwrap();
+            fs::write(staging.join(file), file).unwrap();
+        }
+        let stages = ["start", "sign_files", "run_hooks", "copy_to_esp", "end"];
+        let signed = [staging.join("EFI/nixos/kernel.efi")];
+        let payload =
+            Payload::new(&staging, Path::new("/boot"), None, false, &stages, &signed).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({
+                "staging": staging,
+                "esp": "/boot",
+                "xbootldr": null,
+                "dry_run": false,
+                "stages": ["start", "sign_files", "run_hooks", "copy_to_esp", "end"],
+                "signed": [staging.join("EFI/nixos/kernel.efi")],
+                "files": ["EFI/nixos/kernel.efi", "loader/loader.conf"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let staging = dir.join("staging");
+        let log = || fs::read_to_string(dir.join("log")).unwrap();
+        let payload = |dry_run| Payload {
+            staging: &staging,
+            esp: Path::new("/boot"),
+            xbootldr: None,
+            dry_run,
+            stages: &["start", "run_hooks", "end"],
+            signed: &[],
+            files: vec![PathBuf::from("loader/loader.conf")],
+        };
+
+        // in order, with the payload on stdin
+        let attest = self::hook(dir, "attest", "attested\\n", 0);
+        let upload = self::hook(dir, "upload", "uploaded\\n", 0);
+        let mut reports = Vec::new();
+        run(
+            &[attest.clone(), upload.clone()],
+            &payload(false),
+            &mut reports,
+        )
+        .unwrap();
+        assert_eq!(
+            log(),
+            format!(
+                "attest {s} /boot 0\nupload {s} /boot 0\n",
+                s = staging.display()
+            )
+        );
+        assert_eq!(
+            reports,
+            [
+                HookReport {
+                    hook: attest.clone(),
+                    succeeded: true,
+                    stdout: String::from("attested\n"),
+                },
+                HookReport {
+                    hook: upload.clone(),
+                    succeeded: true,
+                    stdout: String::from("uploaded\n"),
+                },
+            ]
+        );
+        let stdin: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.join("upload.stdin")).unwrap()).unwrap();
+        assert_eq!(stdin, serde_json::to_value(&payload(false)).unwrap());
+
+        // a failure stops the rest
+        fs::remove_file(dir.join("log")).unwrap();
+        let failing = self::hook(dir, "bless", "refused\\n", 3);
+        let mut reports = Vec::new();
+        let err = run(
+            &[attest, failing.clone(), upload],
+            &payload(false),
+            &mut reports,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("hook '"), "{}", err);
+        assert!(err.contains("nothing was copied to '/boot'"), "{}", err);
+        assert!(err.contains("some detail"), "{}", err);
+        assert_eq!(
+            log(),
+            format!(
+                "attest {s} /boot 0\nbless {s} /boot 0\n",
+                s = staging.display()
+            )
+        );
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            reports[1],
+            HookReport {
+                hook: failing,
+                succeeded: false,
+                stdout: String::from("refused\n"),
+            }
+        );
+
+        // with --dry-run
+        fs::remove_file(dir.join("log")).unwrap();
+        let attest = self::hook(dir, "attest", "", 0);
+        run(&[attest], &payload(true), &mut Vec::new()).unwrap();
+        assert_eq!(log(), format!("attest {} /boot 1\n", staging.display()));
+    }
+}
@@ -0,0 +1,97 @@
+//! Where the files in the staging tree go when there's an XBOOTLDR partition (`--xbootldr`) next to
+//! the ESP.
+//!
+//! The paths in an entry resolve on the partition the entry is on, so each entry goes on the
+//! partition with the files it refers to: the generations' entries go on the XBOOTLDR partition
+//! with their kernels, initrds, and unified EFI files, and the chainload entries stay on the ESP
+//! with the programs they chainload. loader.conf (and everything else in `loader/`) is only read
+//! from the ESP.
+
+use std::path::{Path, PathBuf};
+
+use crate::manifest::MANIFEST;
+
+/// The partitions that the staging tree is copied to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Layout<'a> {
+    pub esp: &'a Path,
+    pub xbootldr: Option<&'a Path>,
+}
+
+impl<'a> Layout<'a> {
+    pub fn new(esp: &'a Path, xbootldr: Option<&'a Path>) -> Self {
+        Self { esp, xbootldr }
+    }
+
+    /// The partition with the generations' entries, kernels, initrds, and unified EFI files.
+    pub fn payload_root(&self) -> &'a Path {
+        self.xbootldr.unwrap_or(self.esp)
+    }
+
+    /// The partition that `relative` (a path in the staging tree) goes on.
+    pub fn root_for(&self, relative: &Path) -> &'a Path {
+        if self::on_payload_root(relative) {
+            self.payload_root()
+        } else {
+            self.esp
+        }
+    }
+
+    /// Where `relative` (a path in the staging tree) goes.
+    pub fn dest(&self, relative: &Path) -> PathBuf {
+        self.root_for(relative).join(relative)
+    }
+}
+
+/// Whether `relative` is a generation's entry, one of the files entries refer to (i.e. anything
+/// outside of `loader/`), or the manifest of the latter.
+fn on_payload_root(relative: &Path) -> bool {
+    if relative == Path::new(MANIFEST) {
+        return true;
+    }
+
+    match relative.strip_prefix("loader/entries") {
+        Ok(name) => !super::CHAINLOAD_RE.is_match(&name.to_string_lossy()),
+        Err(_) => !relative.starts_with("loader"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dest() {
+        let esp = Path::new("/efi");
+        let xbootldr = Path::new("/boot");
+        let split = Layout::new(esp, Some(xbootldr));
+        let esp_only = Layout::new(esp, None);
+
+        for (relative, root) in [
+            ("EFI/nixos/kernel.efi", xbootldr),
+            ("EFI/nixos/unified.efi.extra.d/a.cred", xbootldr),
+            ("loader/entries/nixos-generation-1.conf", xbootldr),
+            (
+                "loader/entries/nixos-work-generation-1-variant-recovery.conf",
+                xbootldr,
+            ),
+            ("loader/nixos-manifest.json", xbootldr),
+            ("loader/entries/nixos-chainload-windows.conf", esp),
+            ("loader/loader.conf", esp),
+            ("loader/credentials/a.cred", esp),
+            ("loader/nixos-staged-generation", esp),
+        ] {
+            let relative = Path::new(relative);
+            assert_eq!(split.dest(relative), root.join(relative), "{:?}", relative);
+            assert_eq!(
+                esp_only.dest(relative),
+                esp.join(relative),
+                "{:?}",
+                relative
+            );
+        }
+
+        assert_eq!(split.payload_root(), xbootldr);
+        assert_eq!(esp_only.payload_root(), esp);
+    }
+}
@@ -0,0 +1,189 @@
+//! Entries generated with the generator's `--machine-id-placeholder` have `machine-id @MACHINE_ID@`
+//! instead of a machine-id, which is substituted with the machine-id of the machine the installer
+//! runs on before they're compared to (and copied to) the ESP.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use generator::systemd_boot::MACHINE_ID_PLACEHOLDER;
+use log::debug;
+
+use crate::context::Context;
+use crate::Result;
+
+/// The entries (`.conf` files) in `entries`, or none if it doesn't exist.
+fn entries(entries: &Path) -> Result<Vec<PathBuf>> {
+    if !entries.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(entries).with_path_context(entries)? {
+        let path = entry.with_path_context(entries)?.path();
+        if path.extension() == Some(OsStr::new("conf")) && !path.is_dir() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Whether any of the entries in `entries` has the [`MACHINE_ID_PLACEHOLDER`].
+pub(crate) fn has_placeholder(entries: &Path) -> Result<bool> {
+    for path in self::entries(entries)? {
+        if fs::read_to_string(&path)
+            .with_path_context(&path)?
+            .contains(MACHINE_ID_PLACEHOLDER)
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Reads the machine-id from `path` (i.e. `--machine-id-file`).
+pub(crate) fn read(path: &Path) -> Result<String> {
+    let machine_id = fs::read_to_string(path).with_path_context(path)?;
+    let machine_id = machine_id.trim();
+
+    if machine_id.len() != 32 || !machine_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "'{}' doesn't have a machine-id (32 hexadecimal characters)",
+            path.display()
+        )
+        .into());
+    }
+
+    Ok(machine_id.to_string())
+}
+
+/// Substitutes `machine_id` for the [`MACHINE_ID_PLACEHOLDER`] in every entry in `entries`, each of
+/// which must have it exactly once, as its `machine-id`.
+///
+/// Either every entry is substituted, or (if one of them doesn't have the placeholder) none are.
+pub(crate) fn substitute(entries: &Path, machine_id: &str) -> Result<()> {
+    let machine_id_line = format!("machine-id {}", MACHINE_ID_PLACEHOLDER);
+
+    let mut substituted = Vec::new();
+    for path in self::entries(entries)? {
+        let contents = fs::read_to_string(&path).with_path_context(&path)?;
+        let occurrences = contents.matches(MACHINE_ID_PLACEHOLDER).count();
+
+        if occurrences != 1 || !contents.lines().any(|line| line == machine_id_line) {
+            return Err(format!(
+                "'{}' must have the machine-id placeholder exactly once, as `{}` (found it {} time(s))",
+                path.display(),
+                machine_id_line,
+                occurrences
+            )
+            .into());
+        }
+
+        substituted.push((path, contents.replace(MACHINE_ID_PLACEHOLDER, machine_id)));
+    }
+
+    for (path, contents) in substituted {
+        debug!("substituting the machine-id in '{}'", path.display());
+        fs::write(&path, contents).with_path_context(&path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_read() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("machine-id");
+
+        fs::write(&path, format!("{}\n", MACHINE_ID)).unwrap();
+        assert_eq!(read(&path).unwrap(), MACHINE_ID);
+
+        for contents in ["", "uninitialized\n", "0123456789abcdef\n"] {
+            fs::write(&path, contents).unwrap();
+            assert!(read(&path).is_err(), "{:?}", contents);
+        }
+        assert!(read(&tempdir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_substitute() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let entries = tempdir.path().join("loader/entries");
+        fs::create_dir_all(&entries).unwrap();
+        assert!(!has_placeholder(&tempdir.path().join("missing")).unwrap());
+
+        let entry = |machine_id: &str| {
+            format!(
+                "title NixOS\nversion Generation 1\nsort-key nixos\nlinux /EFI/nixos/kernel.efi\nmachine-id {}\n",
+                machine_id
+            )
+        };
+        fs::write(entries.join("nixos-generation-1.conf"), entry("aaaa")).unwrap();
+        fs::write(entries.join("README"), MACHINE_ID_PLACEHOLDER).unwrap();
+        assert!(!has_placeholder(&entries).unwrap());
+
+        fs::write(
+            entries.join("nixos-generation-1.conf"),
+            entry(MACHINE_ID_PLACEHOLDER),
+        )
+        .unwrap();
+        fs::write(
+            entries.join("nixos-generation-2.conf"),
+            entry(MACHINE_ID_PLACEHOLDER),
+        )
+        .unwrap();
+        assert!(has_placeholder(&entries).unwrap());
+
+        substitute(&entries, MACHINE_ID).unwrap();
+        for name in ["nixos-generation-1.conf", "nixos-generation-2.conf"] {
+            assert_eq!(
+                fs::read_to_string(entries.join(name)).unwrap(),
+                entry(MACHINE_ID)
+            );
+        }
+        assert!(!has_placeholder(&entries).unwrap());
+        // only entries are touched
+        assert_eq!(
+            fs::read_to_string(entries.join("README")).unwrap(),
+            MACHINE_ID_PLACEHOLDER
+        );
+    }
+
+    #[test]
+    fn test_substitute_requires_one_placeholder() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let entries = tempdir.path();
+        let path = entries.join("nixos-generation-2.conf");
+        let valid = format!("title NixOS\nmachine-id {}\n", MACHINE_ID_PLACEHOLDER);
+        fs::write(entries.join("nixos-generation-1.conf"), &valid).unwrap();
+
+        for contents in [
+            // missing
+            String::from("title NixOS\nmachine-id aaaa\n"),
+            // twice
+            format!("title {0}\nmachine-id {0}\n", MACHINE_ID_PLACEHOLDER),
+            // not as the machine-id
+            format!("title NixOS\noptions foo={}\n", MACHINE_ID_PLACEHOLDER),
+        ] {
+            fs::write(&path, &contents).unwrap();
+
+            let err = substitute(entries, MACHINE_ID).unwrap_err().to_string();
+            assert!(err.contains(&path.display().to_string()), "{}", err);
+            // nothing was substituted, not even in the valid entry
+            assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+            assert_eq!(
+                fs::read_to_string(entries.join("nixos-generation-1.conf")).unwrap(),
+                valid
+            );
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! An `--esp` that points at the wrong directory, e.g. `/boot/efi` when the ESP is mounted at
+//! `/boot`, or a directory the ESP is mounted under rather than the mount point itself. There's
+//! nothing to prune there, so the install would otherwise "succeed" while writing a fresh tree
+//! into some directory that systemd-boot never reads.
+//!
+//! A directory that's empty (a freshly formatted ESP), or that has an `EFI` or `loader` directory
+//! of its own, is taken to be the ESP. One that has neither, but that has one nested further down,
+//! isn't: the install fails there unless `--install` is passed.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::context::Context;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// How deep under the ESP to look for an ESP's structure.
+const MAX_DEPTH: usize = 4;
+
+/// What's in a directory that's passed as the ESP.
+#[derive(Debug, PartialEq)]
+pub(super) enum EspContents {
+    /// Nothing yet, e.g. a freshly formatted ESP
+    Empty,
+    /// An `EFI` or `loader` directory, whether ours or another OS's
+    Esp,
+    /// Neither, but there's a directory further down that looks like the ESP (it has an `EFI`
+    /// directory, or `loader/loader.conf`)
+    Misplaced(PathBuf),
+    /// Other files, and nothing that looks like an ESP
+    Unrecognized,
+}
+
+/// Tells what's in `esp`.
+pub(super) fn classify(esp: &Path) -> Result<EspContents> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(esp).with_path_context(esp)? {
+        names.push(entry.with_path_context(esp)?.file_name());
+    }
+
+    if names.is_empty() {
+        return Ok(EspContents::Empty);
+    }
+    if names.iter().any(|name| self::is_esp_dir(esp, name)) {
+        return Ok(EspContents::Esp);
+    }
+
+    let nested = walkdir::WalkDir::new(esp)
+        .min_depth(2)
+        .max_depth(MAX_DEPTH)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            let path = entry.path();
+            let name = entry.file_name();
+            if entry.file_type().is_dir() && name.eq_ignore_ascii_case("EFI") {
+                path.parent().map(Path::to_path_buf)
+            } else if name == OsStr::new("loader.conf")
+                && path.parent().and_then(Path::file_name) == Some(OsStr::new("loader"))
+            {
+                path.parent().and_then(Path::parent).map(Path::to_path_buf)
+            } else {
+                None
+            }
+        });
+
+    Ok(match nested {
+        Some(root) => EspContents::Misplaced(root),
+        None => EspContents::Unrecognized,
+    })
+}
+
+/// Whether `name` (in `esp`) is an `EFI` or `loader` directory (the ESP is usually FAT, where
+/// names are case-insensitive).
+fn is_esp_dir(esp: &Path, name: &OsStr) -> bool {
+    (name.eq_ignore_ascii_case("EFI") || name.eq_ignore_ascii_case("loader"))
+        && esp.join(name).is_dir()
+}
+
+/// Fails if `esp` isn't the ESP, but has it further down (unless `install` is set, i.e.
+/// `--install` was passed).
+pub(super) fn check(esp: &Path, install: bool) -> Result<()> {
+    match self::classify(esp)? {
+        EspContents::Empty => {
+            debug!("'{}' is empty, so this is its first install", esp.display());
+            Ok(())
+        }
+        EspContents::Esp => Ok(()),
+        EspContents::Unrecognized => {
+            warnings::emit(
+                Kind::NewEsp,
+                format!(
+                    "'{}' has neither an EFI nor a loader directory; installing to it as a new ESP",
+                    esp.display()
+                ),
+            );
+            Ok(())
+        }
+        EspContents::Misplaced(root) if install => {
+            warnings::emit(
+                Kind::MisplacedEsp,
+                format!(
+                    "'{}' looks like it's an ESP, rather than '{}', but --install was passed",
+                    root.display(),
+                    esp.display()
+                ),
+            );
+            Ok(())
+        }
+        EspContents::Misplaced(root) => Err(format!(
+            "'{}' has neither an EFI nor a loader directory, but '{}' does: --esp should probably \
+             be '{}' (where the ESP is mounted); pass --install to install to '{}' anyway",
+            esp.display(),
+            root.display(),
+            root.display(),
+            esp.display()
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes `files` (and their directories) under `root`.
+    fn tree(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = |name: &str, files: &[&str]| {
+            let esp = tempdir.path().join(name);
+            fs::create_dir_all(&esp).unwrap();
+            self::tree(&esp, files);
+            esp
+        };
+
+        let empty = esp("empty", &[]);
+        assert_eq!(classify(&empty).unwrap(), EspContents::Empty);
+
+        for (name, files) in [
+            (
+                "installed",
+                &[
+                    "EFI/nixos/kernel.efi",
+                    "loader/entries/nixos-generation-1.conf",
+                ][..],
+            ),
+            ("windows", &["EFI/Microsoft/Boot/bootmgfw.efi"]),
+            ("lowercase", &["efi/boot/bootx64.efi"]),
+            ("loader-only", &["loader/loader.conf"]),
+        ] {
+            let esp = esp(name, files);
+            assert_eq!(classify(&esp).unwrap(), EspContents::Esp, "{}", name);
+        }
+
+        let mounted_under = esp(
+            "mounted-under",
+            &["boot/EFI/nixos/kernel.efi", "boot/loader/loader.conf"],
+        );
+        assert_eq!(
+            classify(&mounted_under).unwrap(),
+            EspContents::Misplaced(mounted_under.join("boot"))
+        );
+        let loader_only = esp("loader-only-under", &["mnt/esp/loader/loader.conf"]);
+        assert_eq!(
+            classify(&loader_only).unwrap(),
+            EspContents::Misplaced(loader_only.join("mnt/esp"))
+        );
+
+        let unrecognized = esp("unrecognized", &["notes.txt", "grub/grub.cfg"]);
+        assert_eq!(classify(&unrecognized).unwrap(), EspContents::Unrecognized);
+    }
+
+    #[test]
+    fn test_check() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+
+        // a first install
+        check(esp, false).unwrap();
+
+        // the ESP is really mounted further down
+        self::tree(
+            esp,
+            &["boot/EFI/nixos/kernel.efi", "boot/loader/loader.conf"],
+        );
+        let err = check(esp, false).unwrap_err().to_string();
+        assert!(err.contains("--install"), "{}", err);
+        assert!(
+            err.contains(&esp.join("boot").display().to_string()),
+            "{}",
+            err
+        );
+        check(esp, true).unwrap();
+        check(&esp.join("boot"), false).unwrap();
+    }
+}
@@ -1,27 +1,84 @@
-use std::ffi::OsString;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Write as _;
 use std::fs;
 use std::io::Write as _;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
-use log::{debug, trace, warn};
+use generator::facts::Facts;
+use log::{debug, info, trace};
 use regex::Regex;
 
+use crate::context::Context;
+use crate::fat::{self, FsState};
 use crate::files::IdentifiedFiles;
-use crate::secure_boot::SigningInfo;
-use crate::systemd_boot::plan::PlanArgs;
+use crate::keep_set::KeepSet;
+use crate::manifest::{Manifest, ManifestExt};
+use crate::options::{CommonBootloaderOptions, Timeout};
+use crate::panic_hook::RunContext;
+use crate::report::{self, Document, EspReport, Outcome, RunReport, StageReport};
+use crate::secure_boot::{self, SigningInfo};
+use crate::systemd_boot::plan::{PlanArgs, PlanReport};
 use crate::util::{self, Generation};
+use crate::warnings::{self, Kind};
 use crate::{Args, Result};
 
+mod adopt;
+mod backup;
+mod budget;
+mod chainload;
+mod collision;
+mod credential;
+mod default_entry;
+mod doctor;
+mod drift;
+mod fallback;
+mod foreign_uki;
+mod history;
+mod hook;
+mod layout;
+mod machine_id;
+mod misplaced;
+mod next_boot;
+mod oneshot;
+mod phase;
+mod pin;
 mod plan;
+mod sd_boot_model;
+mod set_default;
+mod slot;
 mod version;
 
+pub(crate) use adopt::adopt;
+pub(crate) use backup::rollback;
+pub(crate) use chainload::Chainload;
+pub(crate) use credential::{Credential, CredentialScope};
+pub(crate) use doctor::{doctor, System};
+pub(crate) use history::print_history;
+pub(crate) use layout::Layout;
+use oneshot::Staging;
+pub(crate) use phase::Phase;
+pub(crate) use pin::set_pinned;
+use sd_boot_model::SdBootModel;
+pub(crate) use set_default::{set_default, ONESHOT_EFIVAR};
+pub(crate) use slot::{activate_slot, Slot};
+use version::systemd::SystemdVersion;
+
+/// The directory (relative to the root of the ESP, or of the `--entry-path-prefix`) with the
+/// kernels, initrds, and unified EFI files.
+const EFI_DIR: &str = "EFI/nixos";
+
 lazy_static::lazy_static! {
     static ref ENTRY_RE: Regex = Regex::new("nixos-(?:(?P<profile>[^-]+)-)?generation-(?P<generation>\\d+).conf").unwrap();
+    static ref VARIANT_RE: Regex = Regex::new("^nixos-(?:(?P<profile>[^-]+)-)?generation-(?P<generation>\\d+)-variant-(?P<variant>[A-Za-z0-9_]+)\\.conf$").unwrap();
+    static ref SPECIALISATION_RE: Regex = Regex::new("^nixos-(?:(?P<profile>[^-]+)-)?generation-(?P<generation>\\d+)-(?P<specialisation>.+)\\.conf$").unwrap();
+    static ref CHAINLOAD_RE: Regex = Regex::new("nixos-chainload-(?P<name>[A-Za-z0-9_-]+).conf").unwrap();
 }
 
-pub(crate) fn install(args: Args) -> Result<()> {
+/// Installs or updates systemd-boot on every ESP, recording what it did in `run_report` (in detail
+/// if `--report` was passed) as it goes.
+pub(crate) fn install(args: Args, run_report: &mut RunReport, ctx: &RunContext) -> Result<()> {
     trace!("beginning systemd-boot install process");
     debug!("dry_run? {}", args.dry_run);
 
@@ -29,17 +86,322 @@ pub(crate) fn install(args: Args) -> Result<()> {
         return Err("No ESP(s) specified; exiting.".into());
     }
 
-    let esps = &args.esp;
-    let bootctl = args.bootctl.as_ref().expect("bootctl was missing");
-    let system_generations = util::all_generations(None, args.unified_efi)?;
-    let wanted_generations = util::wanted_generations(system_generations, args.configuration_limit);
-    let default_generation = wanted_generations
-        .iter()
-        .rev()
-        .find(|generation| {
-            fs::canonicalize(&generation.path).ok() == fs::canonicalize(&args.toplevel).ok()
-        })
-        .ok_or("couldn't find generation that corresponds to the provided toplevel")?;
+    let esps = util::dedupe_esps(&args.esp)?;
+    if args.xbootldr.is_some() && esps.len() > 1 {
+        return Err("--xbootldr can only be used with a single ESP".into());
+    }
+    let bootctl = if args.no_bootctl {
+        None
+    } else {
+        Some(
+            args.bootctl
+                .as_deref()
+                .ok_or("--bootctl is required unless --no-bootctl is passed")?,
+        )
+    };
+    let options = self::options(&args);
+    // Only the system profile's generations are booted by default, pinned, or staged.
+    let KeepSet {
+        system_generations,
+        default_generation,
+        mut wanted_generations,
+        profile_generations,
+    } = KeepSet::compute(
+        Path::new(util::PROFILES_DIR),
+        args.unified_efi,
+        args.wait_for_profiles,
+        options.configuration_limit,
+        &args.toplevel,
+    )?;
+    let pinned = pin::pinned(&esps, &args.pin_generation, &system_generations)?;
+    pin::keep_pinned(&mut wanted_generations, &system_generations, &pinned);
+    run_report.config = Some(report::resolved_config(
+        &args,
+        &options,
+        &esps,
+        &default_generation,
+        &wanted_generations,
+    ));
+    run_report.initrd_secrets = crate::initrd_secrets::check_generations(
+        &[&wanted_generations[..], &profile_generations].concat(),
+        &args.assume_no_secrets_for,
+    )?;
+    if let (Some(bootctl), Some(_)) = (bootctl, &args.run.report) {
+        match SystemdVersion::detect_version(bootctl) {
+            Ok(version) => {
+                run_report
+                    .tools
+                    .insert(String::from("systemd-boot"), version.version);
+            }
+            Err(e) => debug!("couldn't detect the systemd-boot version: {}", e),
+        }
+    }
+    let signing_info = self::signing_info(&args)?;
+
+    // Fail before touching any ESP if e.g. bootctl is missing, or the key and certificate don't
+    // match.
+    doctor::preflight(&args, &doctor::System::default())?;
+    if let Some(signing_info) = &signing_info {
+        if args.verify_cert_enrolled {
+            for pair in signing_info.pairs() {
+                crate::efi_db::check_cert_enrolled(
+                    &pair.cert,
+                    Path::new(crate::efi_db::DB_EFIVAR),
+                )?;
+            }
+        }
+    }
+    warnings::checkpoint("before touching any ESP", args.run.strict.as_deref())?;
+    pin::annotate(&args.generated_entries, &pinned)?;
+    if let Some(slot) = args.slot {
+        slot::namespace(
+            &args.generated_entries,
+            slot,
+            args.entry_path_prefix.as_deref().map(Path::new),
+        )?;
+    }
+    let manifest = Manifest::load(&args.generated_entries)?;
+
+    for esp in &esps {
+        misplaced::check(esp, args.install)?;
+        let layout = Layout::new(esp, args.xbootldr.as_deref());
+        // After the installer is rolled back, this release's conventions could break what a newer
+        // one put on the ESP, which is only added to.
+        let newer = self::newer_manifest(&args, layout.payload_root())?;
+        let staged_manifest = args.generated_entries.join(crate::manifest::MANIFEST);
+        if newer.is_some() && !args.dry_run && staged_manifest.exists() {
+            // keep the newer release's record (and stamp)
+            fs::remove_file(&staged_manifest).with_path_context(&staged_manifest)?;
+        }
+        if let Some(manifest) = &manifest {
+            manifest.check_left_out(layout.payload_root())?;
+        }
+        let mut identified_files = IdentifiedFiles::new(&args.generated_entries, layout)?;
+        // A slot's entries are never in the manifest on the ESP, which is the other installs'.
+        if args.slot.is_none() {
+            collision::resolve(
+                &mut identified_files,
+                &args.generated_entries,
+                layout.payload_root(),
+                args.adopt_existing_entries,
+            )?;
+        }
+        foreign_uki::deconflict(
+            &mut identified_files,
+            &args.generated_entries,
+            esp,
+            layout.payload_root(),
+        )?;
+        if let Some(newer) = &newer {
+            newer.check_unchanged(layout.payload_root(), &identified_files.to_replace)?;
+        }
+        let staging = Staging::resolve(&args, esp, &default_generation)?;
+        let mut wanted_generations = wanted_generations.clone();
+        oneshot::keep_default(&mut wanted_generations, &system_generations, &staging)?;
+        let mut esp_report = EspReport {
+            esp: esp.clone(),
+            loader_default: staging.default,
+            oneshot: staging.oneshot,
+            fs_state: Some(self::check_esp_fs(esp)),
+            ..Default::default()
+        };
+
+        if esp_report.fs_state == Some(FsState::Dirty) {
+            let msg = format!(
+                "the filesystem of ESP '{}' wasn't cleanly unmounted, and writing to it could \
+                 corrupt it further; check it with `fsck.vfat -a` while it's unmounted (or pass \
+                 --ignore-dirty-esp)",
+                esp.display()
+            );
+
+            if args.ignore_dirty_esp || args.dry_run {
+                warnings::emit(Kind::DirtyEsp, msg);
+            } else {
+                run_report.esps.push(esp_report);
+                return Err(msg.into());
+            }
+        }
+
+        let plan_args = PlanArgs {
+            args: &args,
+            options: options.clone(),
+            bootctl,
+            esp,
+            wanted_generations: &wanted_generations,
+            profile_generations: &profile_generations,
+            default_generation: &default_generation,
+            identified_files,
+            signing_info: &signing_info,
+            manifest: &manifest,
+            staging,
+            prune: newer.is_none(),
+        };
+
+        let plan = plan::create_plan(plan_args)?;
+        let checkpoint = format!("before writing to '{}'", esp.display());
+        if let Err(e) = warnings::checkpoint(&checkpoint, args.run.strict.as_deref()) {
+            run_report.esps.push(esp_report);
+            return Err(e);
+        }
+
+        if args.dry_run {
+            esp_report.stages = plan
+                .iter()
+                .map(|state| StageReport::not_run(state.name(), Outcome::Planned))
+                .collect();
+            let ret = if args.dry_run_hooks {
+                plan::dry_run_hooks(&plan, &mut esp_report.hooks)
+            } else {
+                Ok(())
+            };
+            run_report.esps.push(esp_report);
+            ret?;
+
+            write!(std::io::stdout(), "{}", plan::render_plan(&plan))?;
+        } else {
+            let efi_dir = layout.payload_root().join(self::efi_dir(&args));
+            fs::create_dir_all(&efi_dir).with_path_context(&efi_dir)?;
+            for root in [esp, layout.payload_root()] {
+                fs::create_dir_all(root.join("loader/entries"))
+                    .with_path_context(root.join("loader/entries"))?;
+            }
+
+            // Hashing the whole ESP is only worth it if the report is kept.
+            let before = match &args.run.report {
+                Some(_) => Some(report::hash_tree(esp)?),
+                None => None,
+            };
+            let backup = match &args.backup_dir {
+                Some(backup_dir) => {
+                    let taken = history::next_run_id(esp).and_then(|run_id| {
+                        let dir = backup::dir(backup_dir, run_report.started_at, run_id, esp);
+                        backup::take(layout, &dir)?;
+
+                        Ok(dir)
+                    });
+                    match taken {
+                        Ok(dir) => Some(dir),
+                        Err(e) => {
+                            run_report.esps.push(esp_report);
+                            return Err(e);
+                        }
+                    }
+                }
+                None => None,
+            };
+            let mut plan_report = PlanReport::default();
+            let ret = plan::consume_plan_with(plan, &mut plan_report, &mut esp_report.stages, ctx);
+            esp_report.hooks = plan_report.hooks.clone();
+            if let Some(before) = before {
+                let signed = plan_report
+                    .signed
+                    .iter()
+                    .map(|path| match path.strip_prefix(&args.generated_entries) {
+                        Ok(relative) => esp.join(relative),
+                        Err(_) => path.clone(),
+                    })
+                    .collect();
+                esp_report.record_files(&before, &report::hash_tree(esp)?, &signed);
+            }
+            // Only runs that went through are recorded in the ESP's history.
+            let ret = ret.and_then(|()| {
+                let run_id = history::record(
+                    layout,
+                    &plan_report,
+                    &wanted_generations,
+                    run_report.started_at,
+                    backup.clone(),
+                )?;
+                esp_report.run_id = Some(run_id);
+                pin::write(esp, &pinned)?;
+                if let (Some(backup_dir), Some(dir)) = (&args.backup_dir, &backup) {
+                    backup::finish(layout, dir)?;
+                    backup::prune(backup_dir, esp)?;
+                }
+                if args.run.report.is_some() {
+                    esp_report.managed_files = history::managed_files(layout)?;
+                }
+
+                Ok(())
+            });
+            run_report.esps.push(esp_report);
+            ret?;
+
+            info!(
+                "signed {} file(s), pruned {} file(s), and copied {} file(s) to '{}'",
+                plan_report.signed.len(),
+                plan_report.pruned.len(),
+                plan_report.copied.len(),
+                esp.display()
+            );
+            debug!("{:#?}", plan_report);
+
+            self::summarize_default(layout)?;
+        }
+    }
+
+    warnings::checkpoint("at the end of the run", args.run.strict.as_deref())?;
+    if let (Some(path), false) = (&args.facts, args.dry_run) {
+        self::facts(&args, &esps).write(path)?;
+        info!("wrote the facts for the generator to '{}'", path.display());
+    }
+    if let (Some(path), false) = (&args.write_next_boot, args.dry_run) {
+        next_boot::NextBoot::write(&default_generation, path)?;
+        info!(
+            "wrote what generation {} boots to '{}'",
+            default_generation.idx,
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// What the generator's `--facts` takes from a successful run on `esps`.
+fn facts(args: &Args, esps: &[PathBuf]) -> Facts {
+    let machine_id = match machine_id::read(&args.machine_id_file) {
+        Ok(machine_id) => Some(machine_id),
+        Err(e) => {
+            debug!("leaving the machine-id out of the facts: {}", e);
+            None
+        }
+    };
+
+    Facts {
+        format_version: Facts::FORMAT_VERSION,
+        installer_version: String::from(env!("CARGO_PKG_VERSION")),
+        machine_id,
+        efi_arch: Some(String::from(sd_boot_model::efi_arch())),
+        esps: esps.to_vec(),
+        xbootldr: args.xbootldr.clone(),
+        entry_path_prefix: args.entry_path_prefix.clone(),
+    }
+}
+
+/// Checks whether the filesystem of `esp` was cleanly unmounted; failing to check isn't fatal,
+/// since e.g. the device may not be readable.
+fn check_esp_fs(esp: &Path) -> FsState {
+    match fat::check_esp(esp) {
+        Ok(state) => {
+            debug!("the filesystem of ESP '{}' is {:?}", esp.display(), state);
+            state
+        }
+        Err(e) => {
+            warnings::emit(
+                Kind::UnknownEspFs,
+                format!(
+                    "couldn't check whether the filesystem of ESP '{}' is dirty: {}",
+                    esp.display(),
+                    e
+                ),
+            );
+            FsState::Unknown
+        }
+    }
+}
+
+/// The [`SigningInfo`] from the signing options, if they were passed.
+fn signing_info(args: &Args) -> Result<Option<SigningInfo>> {
     let signing_info = match (
         args.signing_key.as_ref(),
         args.signing_cert.as_ref(),
@@ -50,8 +412,10 @@ pub(crate) fn install(args: Args) -> Result<()> {
             Some(SigningInfo {
                 signing_key: signing_key.to_path_buf(),
                 signing_cert: signing_cert.to_path_buf(),
+                rules: args.signing_rule.clone(),
                 sbsign: sbsign.to_path_buf(),
                 sbverify: sbverify.to_path_buf(),
+                sbattach: secure_boot::sbattach_path(args.sbattach.as_deref())?,
             })
         }
         (None, None, None, None) => None,
@@ -60,50 +424,119 @@ pub(crate) fn install(args: Args) -> Result<()> {
         _ => unreachable!(),
     };
 
-    for esp in esps {
-        let identified_files = IdentifiedFiles::new(&args.generated_entries, esp)?;
+    Ok(signing_info)
+}
 
-        let plan_args = PlanArgs {
-            args: &args,
-            bootctl,
-            esp,
-            wanted_generations: &wanted_generations,
-            default_generation,
-            identified_files,
-            signing_info: &signing_info,
-        };
+/// The manifest on `root`, if a newer release of the installer (by major or minor version) than
+/// this one put it there and `--allow-downgrade-management` wasn't passed.
+fn newer_manifest(args: &Args, root: &Path) -> Result<Option<Manifest>> {
+    let manifest = match Manifest::load(root)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    let version = match manifest.newer_version()? {
+        Some(version) => version,
+        None => return Ok(None),
+    };
 
-        let plan = plan::create_plan(plan_args)?;
+    if args.allow_downgrade_management {
+        warnings::emit(Kind::NewerInstaller, format!(
+            "'{}' is managed by version {} of the installer, which is newer than this one ({}), but \
+             --allow-downgrade-management was passed",
+            root.display(),
+            version,
+            crate::manifest::VERSION
+        ));
+        return Ok(None);
+    }
 
-        if args.dry_run {
-            writeln!(std::io::stdout(), "{:#?}", plan)?;
-        } else {
-            fs::create_dir_all(esp.join("EFI/nixos"))?;
-            fs::create_dir_all(esp.join("loader/entries"))?;
+    warnings::emit(
+        Kind::NewerInstaller,
+        format!(
+        "'{}' is managed by version {} of the installer, which is newer than this one ({}): only \
+         adding files to it, without pruning old ones or replacing its loader.conf (pass \
+         --allow-downgrade-management to manage it anyway)",
+        root.display(),
+        version,
+        crate::manifest::VERSION
+    ),
+    );
 
-            plan::consume_plan(plan)?;
-        }
+    Ok(Some(manifest))
+}
+
+/// Logs which entry systemd-boot will boot by default, warning if it isn't one of ours.
+fn summarize_default(layout: Layout) -> Result<()> {
+    let esp = layout.esp;
+    let model = SdBootModel::read(esp, layout.xbootldr, sd_boot_model::efi_arch())?;
+    let pattern = match &model.default_pattern {
+        Some(pattern) => pattern,
+        None => return Ok(()),
+    };
+
+    if !model
+        .matching_default()
+        .any(|entry| ENTRY_RE.is_match(&entry.id))
+    {
+        warnings::emit(
+            Kind::UnmatchedDefault,
+            format!(
+                "loader.conf's default '{}' doesn't match any NixOS entry in '{}'",
+                pattern,
+                esp.display()
+            ),
+        );
+    }
+
+    if let Some(default) = model.default_entry() {
+        info!("'{}' boots '{}' by default", esp.display(), default.id);
     }
 
     Ok(())
 }
 
+/// The [`CommonBootloaderOptions`] systemd-boot uses: `--systemd-boot-timeout` takes precedence over
+/// `--timeout`.
+pub(crate) fn options(args: &Args) -> CommonBootloaderOptions {
+    CommonBootloaderOptions::from_args(args).with_timeout(args.systemd_boot_timeout)
+}
+
+/// The directory (relative to the root of the ESP) with the kernels, initrds, and unified EFI
+/// files: `EFI/nixos` (or the `--slot`'s, e.g. `EFI/nixos-a`), under the `--entry-path-prefix`, if
+/// any.
+pub(crate) fn efi_dir(args: &Args) -> PathBuf {
+    let efi_dir = match args.slot {
+        Some(slot) => slot.efi_dir(),
+        None => PathBuf::from(EFI_DIR),
+    };
+
+    match &args.entry_path_prefix {
+        Some(prefix) => Path::new(prefix).join(efi_dir),
+        None => efi_dir,
+    }
+}
+
+/// Writes loader.conf, where a [`Timeout`] of 0 seconds hides the menu (unless a key is pressed)
+/// and [`Timeout::Forever`] becomes `menu-force`. The default entry is generation `idx`'s, unless
+/// `default_entry` overrides it.
 fn create_loader_conf(
-    timeout: Option<usize>,
+    timeout: Option<Timeout>,
     idx: usize,
+    default_entry: Option<&str>,
     editor: bool,
     console_mode: &str,
 ) -> Result<String> {
     let mut s = String::new();
 
-    if let Some(timeout) = timeout {
-        writeln!(s, "timeout {}", timeout)?;
+    match timeout {
+        Some(Timeout::Seconds(seconds)) => writeln!(s, "timeout {}", seconds)?,
+        Some(Timeout::Forever) => writeln!(s, "timeout menu-force")?,
+        None => {}
+    }
+    match default_entry {
+        Some(default_entry) => writeln!(s, "default {}", default_entry)?,
+        None => writeln!(s, "default nixos-generation-{}.conf", idx)?,
     }
-    // if let Some(profile) = profile {
-    //     // TODO: support system profiles?
-    // } else {
-    writeln!(s, "default nixos-generation-{}.conf", idx)?;
-    // }
     if !editor {
         writeln!(s, "editor 0")?;
     }
@@ -112,6 +545,35 @@ fn create_loader_conf(
     Ok(s)
 }
 
+/// Removes the `sort-key` lines from every entry in `entries`.
+fn remove_sort_keys(entries: &Path) -> Result<()> {
+    if !entries.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(entries).with_path_context(entries)? {
+        let path = entry.with_path_context(entries)?.path();
+        if path.extension() != Some(OsStr::new("conf")) || path.is_dir() {
+            continue;
+        }
+
+        let contents = fs::read_to_string(&path).with_path_context(&path)?;
+        if !contents.lines().any(|line| line.starts_with("sort-key ")) {
+            continue;
+        }
+
+        debug!("removing sort-key from '{}'", path.display());
+        let contents = contents
+            .lines()
+            .filter(|line| !line.starts_with("sort-key "))
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        fs::write(&path, contents).with_path_context(&path)?;
+    }
+
+    Ok(())
+}
+
 fn get_required_filenames(generations: Vec<Generation>) -> Vec<OsString> {
     let mut required_filenames = Vec::new();
 
@@ -122,48 +584,210 @@ fn get_required_filenames(generations: Vec<Generation>) -> Vec<OsString> {
     required_filenames
 }
 
+/// Adds the variant and specialisation entries (see the generator's `--extra-entry-variant` and
+/// `--exclude-specialisation`) in `generated_entries` to the required files of their generations:
+/// the generator decides which generations get variants, and which specialisations get entries, so
+/// the ones it didn't generate this time are pruned with the rest. The same goes for the entries
+/// that were deconflicted (see [`collision`]) this time. With a `slot`, the entries are the slot's,
+/// and the names added are the generator's (see [`Slot::unslotted`]).
+///
+/// The entries that the generator left out as unchanged (see its `--incremental`) were generated
+/// this time too, so the manifest's are added as well.
+fn with_variant_entries(
+    generations: &[Generation],
+    generated_entries: &Path,
+    slot: Option<Slot>,
+) -> Result<Vec<Generation>> {
+    let mut generations = generations.to_vec();
+    let loader_entries = generated_entries.join("loader/entries");
+
+    if !loader_entries.exists() {
+        return Ok(generations);
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&loader_entries).with_path_context(&loader_entries)? {
+        let entry = entry.with_path_context(&loader_entries)?;
+        if entry.path().is_dir() {
+            continue;
+        }
+        match slot {
+            Some(slot) => names.extend(slot.unslotted(&entry.file_name())),
+            None => names.push(entry.file_name()),
+        }
+    }
+    // The manifest records them by the generator's names, whether or not there's a slot.
+    if let Some(manifest) = Manifest::load(generated_entries)? {
+        names.extend(
+            manifest
+                .unchanged
+                .iter()
+                .map(Path::new)
+                .filter(|path| path.parent() == Some(Path::new("loader/entries")))
+                .filter_map(|path| path.file_name())
+                .map(OsStr::to_os_string),
+        );
+    }
+
+    for name in names {
+        let original = collision::original(&name);
+        let caps = match &original {
+            Some(original) => original.to_str().and_then(|name| {
+                ENTRY_RE
+                    .captures(name)
+                    .or_else(|| VARIANT_RE.captures(name))
+                    .or_else(|| SPECIALISATION_RE.captures(name))
+            }),
+            None => name.to_str().and_then(|name| {
+                VARIANT_RE
+                    .captures(name)
+                    .or_else(|| SPECIALISATION_RE.captures(name))
+            }),
+        };
+        let caps = match caps {
+            Some(caps) => caps,
+            None => continue,
+        };
+        let profile = caps.name("profile").map(|p| p.as_str());
+        let idx = caps["generation"].parse::<usize>()?;
+
+        if let Some(generation) = generations
+            .iter_mut()
+            .find(|g| g.idx == idx && g.profile.as_deref() == profile)
+        {
+            generation.required_filenames.push(name.clone());
+        }
+    }
+
+    Ok(generations)
+}
+
 // TODO: split into different binary / subcommand?
-fn remove_old_files(generations: &[Generation], path: &Path) -> Result<()> {
+/// Removes the entries, kernels, and initrds in `path` (the latter in its `efi_dir`) that aren't
+/// required by any of the `generations` or `chainloads`, returning the paths of the removed files.
+///
+/// Only the profiles of `generations` (and the system profile) are managed here: other profiles'
+/// entries are left alone unless their profile is in `retired_profiles`. Kernels and initrds that
+/// any remaining entry refers to are kept, since they can be shared between profiles.
+///
+/// A deconflicted entry (see [`collision`]) is managed like the entry it's the deconflicted name of.
+/// With a `slot`, only the slot's entries are managed, by the names the generator gave them (see
+/// [`Slot::unslotted`]), and `efi_dir` is the slot's: the other slot's files are left alone.
+fn remove_old_files(
+    generations: &[Generation],
+    chainloads: &[Chainload],
+    retired_profiles: &[String],
+    path: &Path,
+    efi_dir: &Path,
+    slot: Option<Slot>,
+) -> Result<Vec<PathBuf>> {
     trace!("removing old files");
 
-    let efi_nixos = path.join("EFI/nixos");
+    let mut removed = Vec::new();
+
+    let efi_nixos = path.join(efi_dir);
     let loader_entries = path.join("loader/entries");
 
+    // An ESP that isn't the ESP at all was caught earlier (see `misplaced`), so this is its first
+    // install.
     if !path.exists() || !efi_nixos.exists() || !loader_entries.exists() {
-        warn!(
+        debug!(
             "'{}', '{}', or '{}' did not exist, not removing anything",
             path.display(),
             efi_nixos.display(),
             loader_entries.display()
         );
 
-        return Ok(());
+        return Ok(removed);
     }
 
     debug!("calculating required filenames");
-    let required_filenames = self::get_required_filenames(generations.to_vec());
+    let mut required_filenames = self::get_required_filenames(generations.to_vec());
+    required_filenames.extend(
+        chainloads
+            .iter()
+            .map(|c| OsString::from(c.entry_filename())),
+    );
+    let managed_profiles = generations
+        .iter()
+        .map(|g| g.profile.as_deref())
+        .chain([None])
+        .collect::<HashSet<_>>();
 
     trace!("required files calculated: {:#?}", required_filenames);
 
     debug!("removing old entries");
-    for entry in fs::read_dir(loader_entries)? {
-        let f = entry?.path();
+    for entry in fs::read_dir(&loader_entries).with_path_context(&loader_entries)? {
+        let f = entry.with_path_context(&loader_entries)?.path();
         let name = f.file_name().ok_or("filename terminated in ..")?;
 
+        // Other tools (e.g. kernelstub) keep directories of their own here.
+        if f.is_dir() {
+            debug!("skipping directory {:?} in the entries", f);
+            continue;
+        }
+
+        let name = match slot {
+            Some(slot) => match slot.unslotted(name) {
+                Some(name) => name,
+                None => {
+                    trace!("keeping entry {:?} of another slot", f);
+                    continue;
+                }
+            },
+            None => name.to_os_string(),
+        };
+
         // Don't want to delete user's custom boot entries
-        if !ENTRY_RE.is_match(&name.to_string_lossy()) {
+        let original = collision::original(&name);
+        let name_str = original.as_ref().unwrap_or(&name).to_string_lossy();
+        if let Some(caps) = ENTRY_RE
+            .captures(&name_str)
+            .or_else(|| VARIANT_RE.captures(&name_str))
+            .or_else(|| SPECIALISATION_RE.captures(&name_str))
+        {
+            let profile = caps.name("profile").map(|p| p.as_str());
+
+            if let Some(retired) =
+                profile.filter(|p| retired_profiles.iter().any(|r| r.as_str() == *p))
+            {
+                debug!("removing entry {:?} of retired profile '{}'", f, retired);
+                fs::remove_file(&f).with_path_context(&f)?;
+                removed.push(f);
+                continue;
+            }
+
+            if !managed_profiles.contains(&profile) {
+                trace!("keeping entry {:?} of unmanaged profile", f);
+                continue;
+            }
+        } else if !CHAINLOAD_RE.is_match(&name_str) {
             continue;
         }
 
-        if !required_filenames.iter().any(|e| e == name) {
+        // FAT keeps the case of the name a file was created with, so an entry replaced by one whose
+        // name differs by case (e.g. a specialisation's, see the generator's deconflicting) keeps
+        // the old name.
+        if !required_filenames
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(&name))
+        {
             trace!("removing entry file {:?}", f);
-            fs::remove_file(f)?;
+            fs::remove_file(&f).with_path_context(&f)?;
+            removed.push(f);
         }
     }
 
+    debug!("calculating files referenced by the remaining entries");
+    let referenced_filenames = self::referenced_filenames(&loader_entries, slot)?;
+    trace!("referenced files: {:#?}", referenced_filenames);
+    let is_kept = |name: &OsStr| {
+        referenced_filenames.contains(name) || required_filenames.iter().any(|e| e == name)
+    };
+
     debug!("removing old kernels / initrds");
-    for entry in fs::read_dir(efi_nixos)? {
-        let f = entry?.path();
+    for entry in fs::read_dir(&efi_nixos).with_path_context(&efi_nixos)? {
+        let f = entry.with_path_context(&efi_nixos)?.path();
         let name = f.file_name().ok_or("filename terminated in ..")?;
 
         // fwupd puts its own files under "fw" directory, and doesn't remove them
@@ -173,34 +797,126 @@ fn remove_old_files(generations: &[Generation], path: &Path) -> Result<()> {
             continue;
         }
 
-        if !required_filenames.iter().any(|e| e == name) {
+        if f.is_dir() {
+            // A unified EFI file's credentials and addons go with it (and an empty directory is
+            // of no use to it).
+            if let Some(efi) = name.to_str().and_then(|n| n.strip_suffix(".extra.d")) {
+                let is_empty = fs::read_dir(&f).with_path_context(&f)?.next().is_none();
+
+                if !is_kept(OsStr::new(efi)) || is_empty {
+                    trace!("removing extra.d directory {:?}", f);
+                    fs::remove_dir_all(&f).with_path_context(&f)?;
+                    removed.push(f);
+                }
+            } else {
+                trace!("keeping unknown directory {:?}", f);
+            }
+            continue;
+        }
+
+        if !is_kept(name) {
             trace!("removing kernel/initrd file {:?}", f);
             if let Err(e) = fs::remove_file(f.clone()) {
                 eprintln!("Error removing file \"{}\": {}", f.display(), e);
                 exit(e.raw_os_error().unwrap());
             }
+            removed.push(f);
         }
     }
 
-    Ok(())
+    Ok(removed)
+}
+
+/// Removes the chainload entries on the ESP at `esp` that aren't for any of `chainloads`, for when
+/// the generations' entries go on an XBOOTLDR partition. Generations' entries left over on the ESP
+/// from before are left alone (with a warning), since their replacements aren't there yet.
+fn remove_old_chainloads(chainloads: &[Chainload], esp: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let loader_entries = esp.join("loader/entries");
+    if !loader_entries.exists() {
+        return Ok(removed);
+    }
+
+    for entry in fs::read_dir(&loader_entries).with_path_context(&loader_entries)? {
+        let f = entry.with_path_context(&loader_entries)?.path();
+        let name = match f.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !f.is_dir() => name,
+            _ => continue,
+        };
+
+        if CHAINLOAD_RE.is_match(name) {
+            if !chainloads.iter().any(|c| c.entry_filename() == name) {
+                trace!("removing chainload entry {:?}", f);
+                fs::remove_file(&f).with_path_context(&f)?;
+                removed.push(f);
+            }
+        } else if ENTRY_RE.is_match(name)
+            || VARIANT_RE.is_match(name)
+            || SPECIALISATION_RE.is_match(name)
+        {
+            warnings::emit(
+                Kind::LeftoverEntry,
+                format!(
+                "'{}' is left over from before --xbootldr, and can be removed once the XBOOTLDR \
+                 partition has been updated",
+                f.display()
+            ),
+            );
+        }
+    }
+
+    Ok(removed)
+}
+
+/// The filenames of the kernels, initrds, and unified EFI files that the entries in
+/// `loader_entries` (only the `slot`'s, if any) refer to.
+fn referenced_filenames(loader_entries: &Path, slot: Option<Slot>) -> Result<HashSet<OsString>> {
+    let mut referenced = HashSet::new();
+
+    for entry in fs::read_dir(loader_entries).with_path_context(loader_entries)? {
+        let path = entry.with_path_context(loader_entries)?.path();
+        if path.extension() != Some(OsStr::new("conf")) || path.is_dir() {
+            continue;
+        }
+        if let (Some(slot), Some(name)) = (slot, path.file_name()) {
+            if slot.unslotted(name).is_none() {
+                continue;
+            }
+        }
+
+        let contents = util::read_to_string_lossy(&path).with_path_context(&path)?;
+        for line in contents.lines() {
+            let mut parts = line.trim().splitn(2, char::is_whitespace);
+            if let (Some("linux" | "initrd" | "efi"), Some(value)) = (parts.next(), parts.next()) {
+                if let Some(filename) = Path::new(value.trim()).file_name() {
+                    referenced.insert(filename.to_os_string());
+                }
+            }
+        }
+    }
+
+    Ok(referenced)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::options::Timeout;
     use crate::util::Generation;
     use std::ffi::OsString;
+    use std::fs;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn test_create_bootloader_config() {
         assert_eq!(
-            super::create_loader_conf(Some(1), 125, true, "max").unwrap(),
+            super::create_loader_conf(Some(Timeout::Seconds(1)), 125, None, true, "max").unwrap(),
             r#"timeout 1
 default nixos-generation-125.conf
 console-mode max
 "#
         );
         assert_eq!(
-            super::create_loader_conf(Some(2), 126, false, "max").unwrap(),
+            super::create_loader_conf(Some(Timeout::Seconds(2)), 126, None, false, "max").unwrap(),
             r#"timeout 2
 default nixos-generation-126.conf
 editor 0
@@ -209,6 +925,55 @@ console-mode max
         );
     }
 
+    #[test]
+    fn test_options() {
+        let args = crate::Args {
+            timeout: Some(Timeout::Seconds(5)),
+            configuration_limit: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(super::options(&args).timeout, Some(Timeout::Seconds(5)));
+
+        let args = crate::Args {
+            systemd_boot_timeout: Some(Timeout::Forever),
+            ..args
+        };
+        let options = super::options(&args);
+        assert_eq!(options.timeout, Some(Timeout::Forever));
+        assert_eq!(options.configuration_limit, Some(3));
+    }
+
+    #[test]
+    fn test_golden_loader_conf() {
+        let seconds = |seconds| Some(Timeout::Seconds(seconds));
+        for (name, timeout, default_entry, editor, console_mode) in [
+            ("default", seconds(5), None, false, "keep"),
+            ("no-timeout", None, None, false, "keep"),
+            (
+                "timeout-forever",
+                Some(Timeout::Forever),
+                None,
+                false,
+                "keep",
+            ),
+            ("editor", seconds(5), None, true, "keep"),
+            ("console-mode-max", seconds(0), None, false, "max"),
+            (
+                "default-entry",
+                seconds(5),
+                Some("nixos-chainload-windows.conf"),
+                false,
+                "keep",
+            ),
+        ] {
+            golden::assert_golden!(
+                format!("loader-conf/{}.conf", name),
+                super::create_loader_conf(timeout, 42, default_entry, editor, console_mode)
+                    .unwrap()
+            );
+        }
+    }
+
     #[test]
     fn test_get_known_filenames() {
         let generations = vec![
@@ -251,4 +1016,1049 @@ console-mode max
                 .any(|e| required_filenames.contains(e)));
         }
     }
+
+    #[test]
+    fn test_remove_old_chainloads() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+        for entry in [
+            "nixos-generation-1.conf",
+            "nixos-chainload-windows.conf",
+            "nixos-chainload-old.conf",
+            "custom.conf",
+        ] {
+            fs::write(entries.join(entry), "").unwrap();
+        }
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![OsString::from("nixos-generation-1.conf")],
+            ..Default::default()
+        }];
+        let chainloads = vec!["windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi"
+            .parse()
+            .unwrap()];
+
+        let removed = super::remove_old_files(
+            &generations,
+            &chainloads,
+            &[],
+            esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(removed, vec![entries.join("nixos-chainload-old.conf")]);
+        assert!(entries.join("nixos-generation-1.conf").exists());
+        assert!(entries.join("nixos-chainload-windows.conf").exists());
+        assert!(entries.join("custom.conf").exists());
+    }
+
+    #[test]
+    fn test_remove_old_chainloads_with_xbootldr() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(&entries).unwrap();
+        for entry in [
+            "nixos-generation-1.conf",
+            "nixos-chainload-windows.conf",
+            "nixos-chainload-old.conf",
+            "custom.conf",
+        ] {
+            fs::write(entries.join(entry), "").unwrap();
+        }
+
+        let chainloads = vec!["windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi"
+            .parse()
+            .unwrap()];
+        let removed = super::remove_old_chainloads(&chainloads, esp).unwrap();
+
+        // the generation's entry is left for after the XBOOTLDR partition has been updated
+        assert_eq!(removed, vec![entries.join("nixos-chainload-old.conf")]);
+        assert!(entries.join("nixos-generation-1.conf").exists());
+        assert!(entries.join("nixos-chainload-windows.conf").exists());
+        assert!(entries.join("custom.conf").exists());
+
+        assert!(
+            super::remove_old_chainloads(&chainloads, &esp.join("missing"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_newer_manifest() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let args = crate::Args::default();
+        assert!(super::newer_manifest(&args, esp).unwrap().is_none());
+
+        fs::create_dir_all(esp.join("loader")).unwrap();
+        let stamp = |version: &str| {
+            fs::write(
+                esp.join(crate::manifest::MANIFEST),
+                format!(r#"{{ "version": "{}", "files": [] }}"#, version),
+            )
+            .unwrap();
+        };
+
+        stamp(crate::manifest::VERSION);
+        assert!(super::newer_manifest(&args, esp).unwrap().is_none());
+
+        // e.g. after rolling back to this release
+        stamp("999.0.0");
+        let newer = super::newer_manifest(&args, esp).unwrap().unwrap();
+        assert_eq!(newer.version, "999.0.0");
+
+        let args = crate::Args {
+            allow_downgrade_management: true,
+            ..Default::default()
+        };
+        assert!(super::newer_manifest(&args, esp).unwrap().is_none());
+
+        stamp("not a version");
+        assert!(super::newer_manifest(&args, esp).is_err());
+    }
+
+    #[test]
+    fn test_facts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let machine_id_file = dir.join("machine-id");
+        let args = crate::Args {
+            machine_id_file: machine_id_file.clone(),
+            xbootldr: Some(PathBuf::from("/boot")),
+            entry_path_prefix: Some(String::from("nixos")),
+            ..Default::default()
+        };
+        let esps = [PathBuf::from("/efi")];
+
+        // without a machine-id
+        let facts = super::facts(&args, &esps);
+        assert_eq!(facts.machine_id, None);
+        assert_eq!(facts.esps, esps);
+        assert_eq!(facts.xbootldr.as_deref(), Some(Path::new("/boot")));
+
+        fs::write(&machine_id_file, "0123456789abcdef0123456789abcdef\n").unwrap();
+        let facts = super::facts(&args, &esps);
+        assert_eq!(
+            facts.machine_id.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+
+        // what the generator reads back
+        let path = dir.join("nixos-bootloader/facts.json");
+        facts.write(&path).unwrap();
+        let options = generator::facts::Options::default()
+            .with_defaults(generator::facts::Facts::load(&path).unwrap().as_ref())
+            .unwrap();
+        assert_eq!(
+            options.machine_id.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(options.entry_path_prefix.as_deref(), Some("nixos"));
+    }
+
+    #[test]
+    fn test_remove_old_variants() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated = dir.join("generated");
+        let esp = dir.join("esp");
+        for root in [&generated, &esp] {
+            fs::create_dir_all(root.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(root.join("loader/entries")).unwrap();
+        }
+
+        // only the newest generation got a variant this time
+        for entry in [
+            "nixos-generation-1.conf",
+            "nixos-generation-2.conf",
+            "nixos-generation-2-variant-recovery.conf",
+        ] {
+            fs::write(generated.join("loader/entries").join(entry), "").unwrap();
+        }
+        for entry in [
+            "nixos-generation-1-variant-recovery.conf",
+            "nixos-generation-2-variant-recovery.conf",
+            "nixos-generation-3-variant-recovery.conf",
+            "nixos-work-generation-1-variant-recovery.conf",
+        ] {
+            fs::write(esp.join("loader/entries").join(entry), "").unwrap();
+        }
+
+        let generations = [1, 2]
+            .iter()
+            .map(|&idx| Generation {
+                idx,
+                profile: None,
+                required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let generations = super::with_variant_entries(&generations, &generated, None).unwrap();
+        assert_eq!(
+            generations[1].required_filenames,
+            vec![
+                OsString::from("nixos-generation-2.conf"),
+                OsString::from("nixos-generation-2-variant-recovery.conf"),
+            ]
+        );
+
+        let removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[],
+            &generated,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        assert!(removed.is_empty());
+
+        let mut removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[],
+            &esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                esp.join("loader/entries/nixos-generation-1-variant-recovery.conf"),
+                esp.join("loader/entries/nixos-generation-3-variant-recovery.conf"),
+            ]
+        );
+        // another profile's variants are left alone
+        assert!(esp
+            .join("loader/entries/nixos-work-generation-1-variant-recovery.conf")
+            .exists());
+    }
+
+    #[test]
+    fn test_remove_old_deconflicted_entries() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated = dir.join("generated");
+        let esp = dir.join("esp");
+        for root in [&generated, &esp] {
+            fs::create_dir_all(root.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(root.join("loader/entries")).unwrap();
+        }
+
+        // generation 2's entry was deconflicted this time, and generation 1's before
+        fs::write(
+            generated.join("loader/entries/nixos-generation-2-deconflicted.conf"),
+            "",
+        )
+        .unwrap();
+        for entry in [
+            "nixos-generation-1.conf",
+            "nixos-generation-1-deconflicted.conf",
+            "nixos-generation-2.conf",
+            "nixos-generation-2-deconflicted.conf",
+            "nixos-generation-3-deconflicted.conf",
+        ] {
+            fs::write(esp.join("loader/entries").join(entry), "").unwrap();
+        }
+
+        let generations = [1, 2]
+            .iter()
+            .map(|&idx| Generation {
+                idx,
+                profile: None,
+                required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let generations = super::with_variant_entries(&generations, &generated, None).unwrap();
+        assert_eq!(
+            generations[1].required_filenames,
+            vec![
+                OsString::from("nixos-generation-2.conf"),
+                OsString::from("nixos-generation-2-deconflicted.conf"),
+            ]
+        );
+
+        let mut removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[],
+            &esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                esp.join("loader/entries/nixos-generation-1-deconflicted.conf"),
+                esp.join("loader/entries/nixos-generation-3-deconflicted.conf"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_old_excluded_specialisations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated = dir.join("generated");
+        let esp = dir.join("esp");
+        for root in [&generated, &esp] {
+            fs::create_dir_all(root.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(root.join("loader/entries")).unwrap();
+        }
+
+        // the `test` specialisation is excluded (see the generator's `--exclude-specialisation`)
+        // from this time on
+        for entry in ["nixos-generation-2.conf", "nixos-generation-2-gui.conf"] {
+            fs::write(generated.join("loader/entries").join(entry), "").unwrap();
+        }
+        for (entry, kernel) in [
+            ("nixos-generation-2.conf", "kernel.efi"),
+            ("nixos-generation-2-gui.conf", "kernel.efi"),
+            ("nixos-generation-2-test.conf", "kernel-test.efi"),
+            ("nixos-generation-1-test.conf", "kernel-test.efi"),
+            ("nixos-work-generation-1-test.conf", "kernel-work-test.efi"),
+        ] {
+            fs::write(
+                esp.join("loader/entries").join(entry),
+                format!("title NixOS\nlinux /EFI/nixos/{}\n", kernel),
+            )
+            .unwrap();
+            fs::write(esp.join("EFI/nixos").join(kernel), "").unwrap();
+        }
+
+        let generations = vec![Generation {
+            idx: 2,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("nixos-generation-2.conf"),
+                OsString::from("kernel.efi"),
+            ],
+            ..Default::default()
+        }];
+        let generations = super::with_variant_entries(&generations, &generated, None).unwrap();
+        assert_eq!(
+            generations[0].required_filenames,
+            vec![
+                OsString::from("nixos-generation-2.conf"),
+                OsString::from("kernel.efi"),
+                OsString::from("nixos-generation-2-gui.conf"),
+            ]
+        );
+
+        let mut removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[],
+            &esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                esp.join("EFI/nixos/kernel-test.efi"),
+                esp.join("loader/entries/nixos-generation-1-test.conf"),
+                esp.join("loader/entries/nixos-generation-2-test.conf"),
+            ]
+        );
+        // another profile's specialisations are left alone, and so is what they boot
+        assert!(esp
+            .join("loader/entries/nixos-work-generation-1-test.conf")
+            .exists());
+        assert!(esp.join("EFI/nixos/kernel-work-test.efi").exists());
+    }
+
+    #[test]
+    fn test_remove_old_unchanged_specialisations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated = dir.join("generated");
+        let esp = dir.join("esp");
+        for root in [&generated, &esp] {
+            fs::create_dir_all(root.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(root.join("loader/entries")).unwrap();
+        }
+
+        // the first run installed generation 2 with its `gui` specialisation
+        for entry in ["nixos-generation-2.conf", "nixos-generation-2-gui.conf"] {
+            fs::write(esp.join("loader/entries").join(entry), "title NixOS\n").unwrap();
+        }
+        // and the second, with --incremental, left the specialisation's entry out as unchanged
+        fs::write(
+            generated.join("loader/entries/nixos-generation-2.conf"),
+            "title NixOS\n",
+        )
+        .unwrap();
+        fs::write(
+            generated.join(crate::manifest::MANIFEST),
+            r#"{
+                "files": [],
+                "entries": {
+                    "loader/entries/nixos-generation-2.conf": "",
+                    "loader/entries/nixos-generation-2-gui.conf": ""
+                },
+                "unchanged": ["loader/entries/nixos-generation-2-gui.conf"]
+            }"#,
+        )
+        .unwrap();
+
+        let generations = vec![Generation {
+            idx: 2,
+            profile: None,
+            required_filenames: vec![OsString::from("nixos-generation-2.conf")],
+            ..Default::default()
+        }];
+        let generations = super::with_variant_entries(&generations, &generated, None).unwrap();
+        assert_eq!(
+            generations[0].required_filenames,
+            vec![
+                OsString::from("nixos-generation-2.conf"),
+                OsString::from("nixos-generation-2-gui.conf"),
+            ]
+        );
+
+        for root in [&generated, &esp] {
+            super::remove_old_files(
+                &generations,
+                &[],
+                &[],
+                root,
+                Path::new(super::EFI_DIR),
+                None,
+            )
+            .unwrap();
+        }
+        assert!(esp
+            .join("loader/entries/nixos-generation-2-gui.conf")
+            .exists());
+    }
+
+    #[test]
+    fn test_remove_old_case_colliding_specialisations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated = dir.join("generated");
+        let esp = dir.join("esp");
+        for root in [&generated, &esp] {
+            fs::create_dir_all(root.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(root.join("loader/entries")).unwrap();
+        }
+
+        // the generator gave the `foo` specialisation's entry a hash suffix, since `Foo` sorts
+        // first and FAT doesn't tell them apart
+        for entry in [
+            "nixos-generation-2.conf",
+            "nixos-generation-2-Foo.conf",
+            "nixos-generation-2-foo-2c26b46b.conf",
+        ] {
+            fs::write(generated.join("loader/entries").join(entry), "").unwrap();
+        }
+        // before that, both were installed as the same file, which kept the name of the first
+        for entry in [
+            "nixos-generation-1-foo.conf",
+            "nixos-generation-2.conf",
+            "nixos-generation-2-foo.conf",
+            "nixos-generation-2-foo-2c26b46b.conf",
+        ] {
+            fs::write(
+                esp.join("loader/entries").join(entry),
+                "title NixOS\nlinux /EFI/nixos/kernel.efi\n",
+            )
+            .unwrap();
+        }
+        fs::write(esp.join("EFI/nixos/kernel.efi"), "").unwrap();
+
+        let generations = vec![Generation {
+            idx: 2,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("nixos-generation-2.conf"),
+                OsString::from("kernel.efi"),
+            ],
+            ..Default::default()
+        }];
+        let generations = super::with_variant_entries(&generations, &generated, None).unwrap();
+        let mut required = generations[0].required_filenames.clone();
+        required.sort();
+        assert_eq!(
+            required,
+            vec![
+                OsString::from("kernel.efi"),
+                OsString::from("nixos-generation-2-Foo.conf"),
+                OsString::from("nixos-generation-2-foo-2c26b46b.conf"),
+                OsString::from("nixos-generation-2.conf"),
+            ]
+        );
+
+        let removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[],
+            &esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            removed,
+            vec![esp.join("loader/entries/nixos-generation-1-foo.conf")]
+        );
+        // `Foo`'s entry is still where FAT put it, and `foo`'s is under its own name
+        assert!(esp
+            .join("loader/entries/nixos-generation-2-foo.conf")
+            .exists());
+        assert!(esp
+            .join("loader/entries/nixos-generation-2-foo-2c26b46b.conf")
+            .exists());
+    }
+
+    #[test]
+    fn test_remove_old_credentials() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join("EFI/nixos");
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        for dir in ["aaaa.efi.extra.d", "bbbb.efi.extra.d"] {
+            fs::create_dir_all(efi_nixos.join(dir)).unwrap();
+            fs::write(efi_nixos.join(dir).join("secret.cred"), "").unwrap();
+        }
+        fs::write(efi_nixos.join("aaaa.efi"), "").unwrap();
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("aaaa.efi"),
+                OsString::from("nixos-generation-1.conf"),
+            ],
+            ..Default::default()
+        }];
+
+        let removed =
+            super::remove_old_files(&generations, &[], &[], esp, Path::new(super::EFI_DIR), None)
+                .unwrap();
+
+        assert_eq!(removed, vec![efi_nixos.join("bbbb.efi.extra.d")]);
+        assert!(efi_nixos.join("aaaa.efi.extra.d/secret.cred").exists());
+    }
+
+    #[test]
+    fn test_remove_old_addons() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join("EFI/nixos");
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::create_dir_all(&efi_nixos).unwrap();
+        for efi in ["aaaa.efi", "bbbb.efi", "cccc.efi"] {
+            fs::write(efi_nixos.join(efi), "").unwrap();
+        }
+        for dir in ["aaaa.efi.extra.d", "bbbb.efi.extra.d"] {
+            fs::create_dir_all(efi_nixos.join(dir).join("nested")).unwrap();
+            fs::write(efi_nixos.join(dir).join("debug.addon.efi"), "").unwrap();
+            fs::write(efi_nixos.join(dir).join("nested/secret.cred"), "").unwrap();
+        }
+        fs::create_dir(efi_nixos.join("cccc.efi.extra.d")).unwrap();
+        // neither a unified EFI file's directory nor a file
+        fs::create_dir(efi_nixos.join("custom")).unwrap();
+        fs::write(efi_nixos.join("dddd.efi.extra.d"), "").unwrap();
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("aaaa.efi"),
+                OsString::from("cccc.efi"),
+                OsString::from("nixos-generation-1.conf"),
+            ],
+            ..Default::default()
+        }];
+
+        let mut removed =
+            super::remove_old_files(&generations, &[], &[], esp, Path::new(super::EFI_DIR), None)
+                .unwrap();
+        removed.sort();
+
+        assert_eq!(
+            removed,
+            vec![
+                // pruned with its unified EFI file
+                efi_nixos.join("bbbb.efi"),
+                efi_nixos.join("bbbb.efi.extra.d"),
+                // empty
+                efi_nixos.join("cccc.efi.extra.d"),
+                efi_nixos.join("dddd.efi.extra.d"),
+            ]
+        );
+        // retained with its unified EFI file
+        assert!(efi_nixos.join("aaaa.efi.extra.d/debug.addon.efi").exists());
+        assert!(efi_nixos
+            .join("aaaa.efi.extra.d/nested/secret.cred")
+            .exists());
+        assert!(efi_nixos.join("cccc.efi").exists());
+        assert!(efi_nixos.join("custom").is_dir());
+    }
+
+    #[test]
+    fn test_retire_profile() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join("EFI/nixos");
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(&efi_nixos).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+
+        // the `work` profile was renamed to `corp`, which still shares a kernel with the system
+        // profile
+        let entry = |name: &str, kernel: &str, initrd: &str| {
+            fs::write(
+                entries.join(name),
+                format!(
+                    "title NixOS\nlinux /EFI/nixos/{}\ninitrd /EFI/nixos/{}\n",
+                    kernel, initrd
+                ),
+            )
+            .unwrap();
+        };
+        entry("nixos-generation-1.conf", "kernel.efi", "initrd-1.efi");
+        entry("nixos-work-generation-7.conf", "kernel.efi", "initrd-7.efi");
+        entry("nixos-corp-generation-7.conf", "kernel.efi", "initrd-7.efi");
+        entry(
+            "nixos-work-generation-6.conf",
+            "old-kernel.efi",
+            "initrd-6.efi",
+        );
+        for file in [
+            "kernel.efi",
+            "initrd-1.efi",
+            "initrd-7.efi",
+            "old-kernel.efi",
+            "initrd-6.efi",
+            "unreferenced.efi",
+        ] {
+            fs::write(efi_nixos.join(file), "").unwrap();
+        }
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("kernel.efi"),
+                OsString::from("initrd-1.efi"),
+            ],
+            ..Default::default()
+        }];
+
+        // other profiles' entries (and what they refer to) are left alone
+        let removed =
+            super::remove_old_files(&generations, &[], &[], esp, Path::new(super::EFI_DIR), None)
+                .unwrap();
+        assert_eq!(removed, vec![efi_nixos.join("unreferenced.efi")]);
+
+        let mut removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[String::from("work")],
+            esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                efi_nixos.join("initrd-6.efi"),
+                efi_nixos.join("old-kernel.efi"),
+                entries.join("nixos-work-generation-6.conf"),
+                entries.join("nixos-work-generation-7.conf"),
+            ]
+        );
+        // still used by `corp`
+        assert!(entries.join("nixos-corp-generation-7.conf").exists());
+        assert!(efi_nixos.join("initrd-7.efi").exists());
+
+        // still used by the system profile
+        let removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[String::from("corp")],
+            esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            removed,
+            vec![
+                entries.join("nixos-corp-generation-7.conf"),
+                efi_nixos.join("initrd-7.efi"),
+            ]
+        );
+        assert!(efi_nixos.join("kernel.efi").exists());
+        assert!(entries.join("nixos-generation-1.conf").exists());
+    }
+
+    #[test]
+    fn test_remove_old_files_leaves_grub_alone() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join(super::EFI_DIR);
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(&efi_nixos).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+
+        // GRUB (installed alongside, e.g. as a BIOS fallback) keeps its configuration and the
+        // entries `installer grub` installs in a directory of its own, and other tools may keep
+        // copies of the same kernels and initrds in theirs
+        let grub_files = [
+            esp.join("grub").join(crate::grub::ENTRIES),
+            esp.join("grub/grub.cfg"),
+            esp.join("kernels/old-kernel.efi"),
+            esp.join("kernels/initrd-1.efi"),
+        ];
+        for file in &grub_files {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "").unwrap();
+        }
+        fs::write(
+            entries.join("nixos-generation-1.conf"),
+            "title NixOS\nlinux /EFI/nixos/kernel.efi\ninitrd /EFI/nixos/initrd-1.efi\n",
+        )
+        .unwrap();
+        for file in ["kernel.efi", "initrd-1.efi", "old-kernel.efi"] {
+            fs::write(efi_nixos.join(file), "").unwrap();
+        }
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("kernel.efi"),
+                OsString::from("initrd-1.efi"),
+            ],
+            ..Default::default()
+        }];
+
+        let removed =
+            super::remove_old_files(&generations, &[], &[], esp, Path::new(super::EFI_DIR), None)
+                .unwrap();
+
+        assert_eq!(removed, vec![efi_nixos.join("old-kernel.efi")]);
+        for file in &grub_files {
+            assert!(file.exists(), "{}", file.display());
+        }
+    }
+
+    #[test]
+    fn test_remove_old_files_with_gaps() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join(super::EFI_DIR);
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(&efi_nixos).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+
+        // generations whose numbers are prefixes, or extensions, of the kept ones' are pruned
+        for idx in [1, 2, 9, 17, 18, 90, 170, 180, 900] {
+            fs::write(
+                entries.join(format!("nixos-generation-{}.conf", idx)),
+                format!("title NixOS\nlinux /EFI/nixos/kernel-{}.efi\n", idx),
+            )
+            .unwrap();
+            fs::write(efi_nixos.join(format!("kernel-{}.efi", idx)), "").unwrap();
+        }
+
+        let generations = [2, 17, 18, 90]
+            .iter()
+            .map(|&idx| Generation {
+                idx,
+                profile: None,
+                required_filenames: vec![
+                    OsString::from(format!("nixos-generation-{}.conf", idx)),
+                    OsString::from(format!("kernel-{}.efi", idx)),
+                ],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut removed =
+            super::remove_old_files(&generations, &[], &[], esp, Path::new(super::EFI_DIR), None)
+                .unwrap();
+        removed.sort();
+
+        let mut expected = Vec::new();
+        for idx in [1, 9, 170, 180, 900] {
+            expected.push(efi_nixos.join(format!("kernel-{}.efi", idx)));
+            expected.push(entries.join(format!("nixos-generation-{}.conf", idx)));
+        }
+        expected.sort();
+        assert_eq!(removed, expected);
+    }
+
+    #[test]
+    fn test_remove_old_files_of_other_profiles() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let profiles_dir = tempdir.path().join("profiles");
+        let system_profiles = profiles_dir.join("system-profiles");
+        // (named like a store path, which generations' unified EFI files are named after)
+        let system = tempdir.path().join(format!("{}-system", "a".repeat(32)));
+        fs::create_dir_all(&system_profiles).unwrap();
+        fs::create_dir_all(&system).unwrap();
+        for idx in 1..=3 {
+            std::os::unix::fs::symlink(&system, system_profiles.join(format!("work-{}-link", idx)))
+                .unwrap();
+        }
+        std::os::unix::fs::symlink("work-3-link", system_profiles.join("work")).unwrap();
+
+        let esp = tempdir.path().join("esp");
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(esp.join(super::EFI_DIR)).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+        for name in [
+            "nixos-generation-1.conf",
+            "nixos-work-generation-1.conf",
+            "nixos-work-generation-2.conf",
+            "nixos-work-generation-3.conf",
+            // a profile that's gone from `profiles_dir` isn't ours to prune
+            "nixos-old-generation-1.conf",
+        ] {
+            fs::write(entries.join(name), "title NixOS\n").unwrap();
+        }
+
+        // with a configuration limit of 2, the `work` profile's oldest generation goes
+        let mut generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![OsString::from("nixos-generation-1.conf")],
+            ..Default::default()
+        }];
+        generations.extend(crate::util::profile_generations(&profiles_dir, true, Some(2)).unwrap());
+        assert_eq!(
+            generations.iter().map(|g| g.idx).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+
+        let removed = super::remove_old_files(
+            &generations,
+            &[],
+            &[],
+            &esp,
+            Path::new(super::EFI_DIR),
+            None,
+        )
+        .unwrap();
+        assert_eq!(removed, [entries.join("nixos-work-generation-1.conf")]);
+    }
+
+    #[test]
+    fn test_remove_old_files_skips_subdirectories() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join(super::EFI_DIR);
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(&efi_nixos).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+
+        fs::write(
+            entries.join("nixos-generation-1.conf"),
+            "title NixOS\nlinux /EFI/nixos/kernel.efi\nsort-key nixos\n",
+        )
+        .unwrap();
+        fs::write(efi_nixos.join("kernel.efi"), "").unwrap();
+
+        // other tools (e.g. kernelstub) put directories of their own in loader/entries, even ones
+        // named like our entries
+        let rogue_files = [
+            entries.join("kernelstub/nixos-generation-2.conf"),
+            entries.join("nixos-generation-3.conf/entry.conf"),
+        ];
+        for file in &rogue_files {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "linux /EFI/nixos/kernel.efi\n").unwrap();
+        }
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("kernel.efi"),
+            ],
+            ..Default::default()
+        }];
+
+        let removed =
+            super::remove_old_files(&generations, &[], &[], esp, Path::new(super::EFI_DIR), None)
+                .unwrap();
+        assert!(removed.is_empty(), "{:?}", removed);
+
+        super::remove_sort_keys(&entries).unwrap();
+        assert!(!super::machine_id::has_placeholder(&entries).unwrap());
+
+        for file in &rogue_files {
+            assert!(file.exists(), "{}", file.display());
+        }
+    }
+
+    #[test]
+    fn test_remove_old_files_keeps_foreign_ukis() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_nixos = esp.join(super::EFI_DIR);
+        let efi_linux = esp.join(super::foreign_uki::LINUX_DIR);
+        let entries = esp.join("loader/entries");
+        for dir in [&efi_nixos, &efi_linux, &entries] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        fs::write(
+            entries.join("nixos-generation-1.conf"),
+            "title NixOS\nefi /EFI/nixos/unified-1.efi\n",
+        )
+        .unwrap();
+        fs::write(efi_nixos.join("unified-1.efi"), "").unwrap();
+        // UKIs of other tools, one of them named like one of ours, and one of ours from an
+        // unwanted generation
+        let foreign = [
+            "nixos-generation-1.efi",
+            "arch-linux-6.9.efi",
+            "unified-1.efi",
+        ];
+        for name in foreign {
+            fs::write(efi_linux.join(name), "").unwrap();
+        }
+
+        let removed =
+            super::remove_old_files(&[], &[], &[], esp, Path::new(super::EFI_DIR), None).unwrap();
+        assert!(
+            removed.contains(&efi_nixos.join("unified-1.efi")),
+            "{:?}",
+            removed
+        );
+        assert!(removed.iter().all(|path| !path.starts_with(&efi_linux)));
+        for name in foreign {
+            assert!(efi_linux.join(name).exists(), "{}", name);
+        }
+        assert_eq!(
+            super::foreign_uki::scan(esp, esp).unwrap(),
+            [
+                PathBuf::from("EFI/Linux/arch-linux-6.9.efi"),
+                PathBuf::from("EFI/Linux/nixos-generation-1.efi"),
+                PathBuf::from("EFI/Linux/unified-1.efi"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_old_files_with_entry_path_prefix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let efi_dir = Path::new("boot").join(super::EFI_DIR);
+        let efi_nixos = esp.join(&efi_dir);
+        let entries = esp.join("loader/entries");
+        fs::create_dir_all(&efi_nixos).unwrap();
+        fs::create_dir_all(&entries).unwrap();
+
+        fs::write(
+            entries.join("nixos-generation-1.conf"),
+            "title NixOS\nlinux /boot/EFI/nixos/kernel.efi\ninitrd /boot/EFI/nixos/initrd-1.efi\n",
+        )
+        .unwrap();
+        fs::write(
+            entries.join("nixos-generation-2.conf"),
+            "title NixOS\nefi /boot/EFI/nixos/unified-2.efi\n",
+        )
+        .unwrap();
+        for file in [
+            "kernel.efi",
+            "initrd-1.efi",
+            "unified-2.efi",
+            "old-kernel.efi",
+        ] {
+            fs::write(efi_nixos.join(file), "").unwrap();
+        }
+        // the EFI directory at the root of the ESP isn't ours to prune
+        fs::create_dir_all(esp.join(super::EFI_DIR)).unwrap();
+        fs::write(esp.join(super::EFI_DIR).join("other.efi"), "").unwrap();
+
+        let generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("kernel.efi"),
+                OsString::from("initrd-1.efi"),
+            ],
+            ..Default::default()
+        }];
+
+        let mut removed =
+            super::remove_old_files(&generations, &[], &[], esp, &efi_dir, None).unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                efi_nixos.join("old-kernel.efi"),
+                efi_nixos.join("unified-2.efi"),
+                entries.join("nixos-generation-2.conf"),
+            ]
+        );
+        assert!(efi_nixos.join("kernel.efi").exists());
+        assert!(efi_nixos.join("initrd-1.efi").exists());
+        assert!(esp.join(super::EFI_DIR).join("other.efi").exists());
+
+        let args = crate::Args {
+            entry_path_prefix: Some(String::from("boot")),
+            ..Default::default()
+        };
+        assert_eq!(super::efi_dir(&args), efi_dir);
+        assert_eq!(
+            super::efi_dir(&crate::Args::default()),
+            Path::new(super::EFI_DIR)
+        );
+    }
+
+    #[test]
+    fn test_remove_sort_keys() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let entries = tempdir.path().join("loader/entries");
+        fs::create_dir_all(&entries).unwrap();
+
+        let entry = entries.join("nixos-generation-1.conf");
+        fs::write(
+            &entry,
+            "title NixOS\nversion Generation 1\nsort-key nixos\nefi /EFI/nixos/a.efi\n",
+        )
+        .unwrap();
+        let other = entries.join("notes.txt");
+        fs::write(&other, "sort-key nixos\n").unwrap();
+
+        super::remove_sort_keys(&entries).unwrap();
+        assert_eq!(
+            fs::read_to_string(&entry).unwrap(),
+            "title NixOS\nversion Generation 1\nefi /EFI/nixos/a.efi\n"
+        );
+        assert_eq!(fs::read_to_string(&other).unwrap(), "sort-key nixos\n");
+
+        // nothing to do without generated entries
+        assert!(super::remove_sort_keys(&tempdir.path().join("missing")).is_ok());
+    }
 }
@@ -0,0 +1,135 @@
+//! What the default generation boots (`--write-next-boot`), for switching to it without going
+//! through the firmware, e.g. with `kexec` or `systemctl soft-reboot`: its kernel, initrd, and
+//! command line, from its bootspec document, the same way the generator renders its entry.
+
+use std::path::{Path, PathBuf};
+
+use generator::bootable::{self, EfiProgram};
+use generator::target::Target;
+use serde::{Deserialize, Serialize};
+
+use super::set_default::write_atomically;
+use crate::util::Generation;
+use crate::Result;
+
+/// What `kexec` needs to boot a generation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NextBoot {
+    pub generation: usize,
+    /// The generation's toplevel (its store path)
+    pub toplevel: PathBuf,
+    pub kernel: PathBuf,
+    pub initrd: PathBuf,
+    /// The kernel command line, as it is in the generation's entry
+    pub cmdline: String,
+}
+
+impl NextBoot {
+    /// What `generation` boots (not one of its specialisations).
+    pub fn new(generation: &Generation) -> Result<Self> {
+        let bootspec = generator::get_json(generation.path.clone())?;
+        let toplevel = bootable::flatten(vec![generator::Generation {
+            index: generation.idx,
+            profile: generation.profile.clone(),
+            bootspec,
+        }])?
+        .into_iter()
+        .find(|toplevel| toplevel.specialisation_name.is_none())
+        .ok_or_else(|| format!("generation {} has no bootspec", generation.idx))?;
+
+        let cmdline = EfiProgram::new(toplevel.clone()).cmdline(&Target::local(String::new()))?;
+
+        Ok(NextBoot {
+            generation: generation.idx,
+            toplevel: toplevel.toplevel.0,
+            kernel: toplevel.kernel,
+            initrd: toplevel.initrd,
+            cmdline,
+        })
+    }
+
+    /// Writes what `generation` boots to `path`, atomically.
+    pub fn write(generation: &Generation, path: &Path) -> Result<()> {
+        let next_boot = Self::new(generation)?;
+        let json = serde_json::to_string_pretty(&next_boot)?;
+
+        write_atomically(path, &(json + "\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use generator::systemd_boot;
+
+    #[test]
+    fn test_next_boot() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toplevel = tempdir
+            .path()
+            .join("0123456789abcdefghijklmnopqrstuv-nixos-system");
+        for (path, contents) in [
+            ("kernel", ""),
+            ("initrd", ""),
+            ("init", ""),
+            ("nixos-version", "23.05"),
+            ("system", "x86_64-linux"),
+            ("kernel-params", "quiet loglevel=4"),
+            ("specialisation/gui/kernel", ""),
+            ("specialisation/gui/initrd", ""),
+            ("specialisation/gui/init", ""),
+            ("specialisation/gui/nixos-version", "23.05"),
+            ("specialisation/gui/system", "x86_64-linux"),
+            ("specialisation/gui/kernel-params", "splash"),
+        ] {
+            let path = toplevel.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let link = tempdir.path().join("system-3-link");
+        std::os::unix::fs::symlink(&toplevel, &link).unwrap();
+        let generation = Generation {
+            idx: 3,
+            profile: None,
+            path: link,
+            required_filenames: vec![],
+        };
+
+        let path = tempdir.path().join("next-boot.json");
+        NextBoot::write(&generation, &path).unwrap();
+        let next_boot: NextBoot =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(next_boot.generation, 3);
+        assert_eq!(
+            fs::canonicalize(&next_boot.kernel).unwrap(),
+            fs::canonicalize(toplevel.join("kernel")).unwrap()
+        );
+        assert_eq!(
+            fs::canonicalize(&next_boot.initrd).unwrap(),
+            fs::canonicalize(toplevel.join("initrd")).unwrap()
+        );
+
+        // it's what the generator puts in the generation's entry
+        let bootables = bootable::flatten(vec![generator::Generation {
+            index: 3,
+            profile: None,
+            bootspec: generator::get_json(generation.path.clone()).unwrap(),
+        }])
+        .unwrap()
+        .into_iter()
+        .map(bootable::Bootable::Linux)
+        .collect::<Vec<_>>();
+        let planned = systemd_boot::plan(&bootables, "EFI/nixos").unwrap();
+        let entry = systemd_boot::entry(&planned[0], &Target::local(String::new()), &[]).unwrap();
+        let options = entry
+            .lines()
+            .filter_map(|line| line.strip_prefix("options "))
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(next_boot.cmdline, options);
+        assert!(next_boot.cmdline.ends_with(" quiet loglevel=4"));
+        assert!(!next_boot.cmdline.contains("splash"));
+    }
+}
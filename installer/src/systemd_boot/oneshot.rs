@@ -0,0 +1,333 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::{info, warn};
+
+use super::sd_boot_model::{self, SdBootModel};
+use crate::context::Context;
+use crate::util::Generation;
+use crate::warnings::{self, Kind};
+use crate::{Args, Result};
+
+/// Records the generation staged by `--stage-oneshot`, relative to the root of the ESP.
+pub(crate) const STAGED_STATE: &str = "loader/nixos-staged-generation";
+
+/// What to do with the [`STAGED_STATE`] file.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum StagedAction {
+    Keep,
+    Record(usize),
+    Clear,
+}
+
+/// Which generation loader.conf defaults to, and which one (if any) is booted once.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Staging {
+    /// The generation loader.conf's default points at
+    pub default: usize,
+    /// The generation to boot once (with `bootctl set-oneshot`)
+    pub oneshot: Option<usize>,
+    pub action: StagedAction,
+}
+
+impl Staging {
+    /// Makes `default` the default generation, without staging anything.
+    pub fn new(default: usize) -> Self {
+        Self {
+            default,
+            oneshot: None,
+            action: StagedAction::Keep,
+        }
+    }
+
+    pub fn resolve(args: &Args, esp: &Path, default_generation: &Generation) -> Result<Self> {
+        let new = default_generation.idx;
+        let staged = self::read_staged(esp)?;
+
+        if args.stage_oneshot {
+            let previous =
+                self::loader_default(esp, args.xbootldr.as_deref())?.ok_or_else(|| {
+                    format!(
+                        "--stage-oneshot needs a default generation in '{}' to fall back to",
+                        esp.join("loader/loader.conf").display()
+                    )
+                })?;
+
+            if previous == new {
+                warn!("generation {} is already the default, not staging it", new);
+                return Ok(Self::new(new));
+            }
+
+            info!(
+                "staging generation {} to be booted once, keeping generation {} as the default",
+                new, previous
+            );
+            return Ok(Self {
+                default: previous,
+                oneshot: Some(new),
+                action: StagedAction::Record(new),
+            });
+        }
+
+        match staged {
+            Some(staged) if args.promote_staged => {
+                let marker = args
+                    .success_marker
+                    .as_deref()
+                    .ok_or("--promote-staged requires --success-marker")?;
+
+                if marker.exists() {
+                    info!("promoting staged generation {} to the default", staged);
+                    Ok(Self {
+                        default: staged,
+                        oneshot: None,
+                        action: StagedAction::Clear,
+                    })
+                } else {
+                    warnings::emit(
+                        Kind::OneshotNotPromoted,
+                        format!(
+                            "not promoting staged generation {}: '{}' doesn't exist",
+                            staged,
+                            marker.display()
+                        ),
+                    );
+                    let previous =
+                        self::loader_default(esp, args.xbootldr.as_deref())?.unwrap_or(new);
+                    Ok(Self::new(previous))
+                }
+            }
+            // Whatever was staged is superseded by the new default.
+            Some(_) => Ok(Self {
+                action: StagedAction::Clear,
+                ..Self::new(new)
+            }),
+            None => {
+                if args.promote_staged {
+                    info!("no staged generation to promote");
+                }
+                Ok(Self::new(new))
+            }
+        }
+    }
+}
+
+/// Makes sure the generation that loader.conf will default to isn't pruned.
+pub(crate) fn keep_default(
+    wanted_generations: &mut Vec<Generation>,
+    all_generations: &[Generation],
+    staging: &Staging,
+) -> Result<()> {
+    if wanted_generations.iter().any(|g| g.idx == staging.default) {
+        return Ok(());
+    }
+
+    let generation = all_generations
+        .iter()
+        .find(|g| g.idx == staging.default)
+        .ok_or_else(|| {
+            format!(
+                "the default generation {} no longer exists",
+                staging.default
+            )
+        })?;
+
+    wanted_generations.push(generation.clone());
+    wanted_generations.sort_by_key(|g| g.idx);
+
+    Ok(())
+}
+
+/// The generation that systemd-boot currently boots by default from the ESP (and XBOOTLDR
+/// partition), if it's one of ours.
+fn loader_default(esp: &Path, xbootldr: Option<&Path>) -> Result<Option<usize>> {
+    let model = SdBootModel::read(esp, xbootldr, sd_boot_model::efi_arch())?;
+
+    let default = model
+        .default_entry()
+        .and_then(|entry| super::ENTRY_RE.captures(&entry.id))
+        .filter(|caps| caps.name("profile").is_none())
+        .and_then(|caps| caps["generation"].parse().ok());
+
+    Ok(default)
+}
+
+fn read_staged(esp: &Path) -> Result<Option<usize>> {
+    let path = esp.join(STAGED_STATE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("'{}': {}", path.display(), e).into()),
+    };
+
+    let staged = contents
+        .trim()
+        .parse()
+        .map_err(|e| format!("'{}' is not a generation number: {}", path.display(), e))?;
+
+    Ok(Some(staged))
+}
+
+pub(crate) fn write_staged(path: &Path, action: StagedAction) -> Result<()> {
+    match action {
+        StagedAction::Keep => {}
+        StagedAction::Record(generation) => {
+            fs::write(path, format!("{}\n", generation)).with_path_context(path)?;
+        }
+        StagedAction::Clear => {
+            if path.exists() {
+                fs::remove_file(path).with_path_context(path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn esp_with_default(dir: &Path, default: &str, entries: &[&str]) {
+        fs::create_dir_all(dir.join("loader/entries")).unwrap();
+        fs::write(
+            dir.join("loader/loader.conf"),
+            format!("timeout 1\ndefault {}\n", default),
+        )
+        .unwrap();
+        for entry in entries {
+            fs::write(dir.join("loader/entries").join(entry), "title NixOS\n").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_loader_default() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        assert_eq!(loader_default(esp, None).unwrap(), None);
+
+        esp_with_default(esp, "nixos-generation-41.conf", &[]);
+        assert_eq!(loader_default(esp, None).unwrap(), None);
+
+        esp_with_default(
+            esp,
+            "nixos-generation-41.conf",
+            &["nixos-generation-41.conf", "nixos-generation-42.conf"],
+        );
+        assert_eq!(loader_default(esp, None).unwrap(), Some(41));
+
+        // sd-boot picks the highest-sorting match
+        esp_with_default(esp, "nixos-generation-*", &[]);
+        assert_eq!(loader_default(esp, None).unwrap(), Some(42));
+
+        esp_with_default(esp, "windows.conf", &["windows.conf"]);
+        assert_eq!(loader_default(esp, None).unwrap(), None);
+
+        // without a matching entry, sd-boot boots the first one
+        fs::remove_file(esp.join("loader/entries/windows.conf")).unwrap();
+        esp_with_default(esp, "nixos-generation-99.conf", &[]);
+        assert_eq!(loader_default(esp, None).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn test_keep_default_with_gaps() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let all_generations = [2, 17, 18, 90]
+            .iter()
+            .map(|&idx| Generation {
+                idx,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let idxs =
+            |generations: &[Generation]| generations.iter().map(|g| g.idx).collect::<Vec<_>>();
+
+        // the previous default was staged against, and is older than the configuration limit
+        esp_with_default(
+            esp,
+            "nixos-generation-1*",
+            &[
+                "nixos-generation-2.conf",
+                "nixos-generation-17.conf",
+                "nixos-generation-90.conf",
+            ],
+        );
+        let previous = loader_default(esp, None).unwrap().unwrap();
+        assert_eq!(previous, 17);
+
+        let mut wanted_generations = all_generations[3..].to_vec();
+        keep_default(
+            &mut wanted_generations,
+            &all_generations,
+            &Staging::new(previous),
+        )
+        .unwrap();
+        assert_eq!(idxs(&wanted_generations), [17, 90]);
+
+        // already wanted
+        keep_default(&mut wanted_generations, &all_generations, &Staging::new(90)).unwrap();
+        assert_eq!(idxs(&wanted_generations), [17, 90]);
+
+        // a generation in a gap was deleted
+        let err = keep_default(&mut wanted_generations, &all_generations, &Staging::new(3))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no longer exists"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        let marker = esp.join("booted-ok");
+        let new = Generation {
+            idx: 2,
+            ..Default::default()
+        };
+        let mut args = Args {
+            stage_oneshot: true,
+            ..Default::default()
+        };
+
+        // nothing to fall back to
+        assert!(Staging::resolve(&args, esp, &new).is_err());
+
+        esp_with_default(esp, "nixos-generation-1.conf", &["nixos-generation-1.conf"]);
+        assert_eq!(
+            Staging::resolve(&args, esp, &new).unwrap(),
+            Staging {
+                default: 1,
+                oneshot: Some(2),
+                action: StagedAction::Record(2),
+            }
+        );
+
+        write_staged(&esp.join(STAGED_STATE), StagedAction::Record(2)).unwrap();
+        args.stage_oneshot = false;
+        args.promote_staged = true;
+        args.success_marker = Some(marker.clone());
+        assert_eq!(Staging::resolve(&args, esp, &new).unwrap(), Staging::new(1));
+
+        fs::write(&marker, "").unwrap();
+        assert_eq!(
+            Staging::resolve(&args, esp, &new).unwrap(),
+            Staging {
+                action: StagedAction::Clear,
+                ..Staging::new(2)
+            }
+        );
+
+        // a regular run supersedes the staged generation
+        args.promote_staged = false;
+        args.success_marker = None;
+        assert_eq!(
+            Staging::resolve(&args, esp, &new).unwrap(),
+            Staging {
+                action: StagedAction::Clear,
+                ..Staging::new(2)
+            }
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Running only one phase of an install (see `--phase`), e.g. to re-copy the staged files to an ESP
+//! that was left half updated without pruning anything, or to prune without touching anything else.
+//!
+//! A phase is a subset of the plan's states, along with the states that have to run first for
+//! those to work (its prerequisites).
+
+use std::str::FromStr;
+
+use super::plan::SystemdBootPlanState;
+use crate::{Args, Result};
+
+/// The states that every phase has, which only mark the plan's start and end, and flush the ESP.
+const ALWAYS: &[&str] = &["start", "syncfs", "end"];
+
+/// The part of an install to run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Every phase, in order
+    #[default]
+    All,
+    /// Removing old generations' files from the ESP (and the staged files)
+    Prune,
+    /// Copying the staged files to the ESP
+    Copy,
+    /// Signing systemd-boot and the staged EFI files
+    Sign,
+    /// Installing or updating systemd-boot
+    Loader,
+}
+
+impl Phase {
+    /// The phase's name, as `--phase` takes it.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Phase::All => "all",
+            Phase::Prune => "prune",
+            Phase::Copy => "copy",
+            Phase::Sign => "sign",
+            Phase::Loader => "loader",
+        }
+    }
+
+    /// The states (by [`SystemdBootPlanState::name`]) that do the phase's work.
+    fn states(self) -> &'static [&'static str] {
+        match self {
+            Phase::All => &[],
+            Phase::Prune => &["prune_files", "prune_credentials"],
+            Phase::Copy => &["copy_to_esp", "verify_manifest"],
+            Phase::Sign => &["sign_files"],
+            Phase::Loader => &["install", "update"],
+        }
+    }
+
+    /// The states that the phase's own states need to have run first:
+    ///
+    /// - copying needs the staged tree finished (the machine-id substituted, sort-keys gated,
+    ///   loader.conf, the chainload entries, and the credentials written), the files that are the
+    ///   same as the ESP's identified (after checking the ESP didn't change since planning), and
+    ///   the rest signed (with `--signing-key`), since unsigned files mustn't end up on the ESP,
+    ///   and the `--hook`s run on them
+    /// - signing needs the staged files that are the same as the ESP's identified, which aren't
+    ///   signed again
+    /// - installing or updating systemd-boot needs it signed (with `--signing-key`)
+    fn prerequisites(self) -> &'static [&'static str] {
+        match self {
+            Phase::All | Phase::Prune => &[],
+            Phase::Copy => &[
+                "substitute_machine_id",
+                "gate_sort_keys",
+                "write_loader",
+                "write_chainloads",
+                "write_credentials",
+                "verify_esp",
+                "replace_files",
+                "sign_files",
+                "run_hooks",
+            ],
+            Phase::Sign => &["verify_esp", "replace_files"],
+            Phase::Loader => &["sign_files"],
+        }
+    }
+
+    /// Whether `state` is in the phase's plan.
+    pub(super) fn includes(self, state: &SystemdBootPlanState) -> bool {
+        self == Phase::All
+            || [ALWAYS, self.states(), self.prerequisites()]
+                .iter()
+                .any(|names| names.contains(&state.name()))
+    }
+
+    /// Checks that the phase can run with `args`, `signing` (whether there's a `--signing-key`),
+    /// and `prune` (whether old files may be pruned from the ESP).
+    pub(super) fn check(self, args: &Args, signing: bool, prune: bool) -> Result<()> {
+        match self {
+            Phase::All => return Ok(()),
+            Phase::Prune if !prune => {
+                return Err(
+                    "--phase prune can't prune an ESP that a newer release of the installer \
+                     manages (see --allow-downgrade-management)"
+                        .into(),
+                )
+            }
+            Phase::Sign if !signing => return Err("--phase sign requires --signing-key".into()),
+            Phase::Loader if args.no_bootctl => {
+                return Err("--phase loader conflicts with --no-bootctl".into())
+            }
+            _ => {}
+        }
+
+        if args.install && self != Phase::Loader {
+            return Err(format!("--install conflicts with --phase {}", self.name()).into());
+        }
+        if args.stage_oneshot || args.promote_staged {
+            return Err(format!(
+                "--stage-oneshot and --promote-staged conflict with --phase {}",
+                self.name()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Phase::All),
+            "prune" => Ok(Phase::Prune),
+            "copy" => Ok(Phase::Copy),
+            "sign" => Ok(Phase::Sign),
+            "loader" => Ok(Phase::Loader),
+            _ => Err(format!(
+                "'{}' is not a phase (expected 'all', 'prune', 'copy', 'sign', or 'loader')",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_phase() {
+        for phase in [
+            Phase::All,
+            Phase::Prune,
+            Phase::Copy,
+            Phase::Sign,
+            Phase::Loader,
+        ] {
+            assert_eq!(phase.name().parse::<Phase>(), Ok(phase));
+        }
+        assert!("install".parse::<Phase>().is_err());
+    }
+}
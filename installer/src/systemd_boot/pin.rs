@@ -0,0 +1,300 @@
+//! Pinned generations (`--pin-generation`, and the `pin` and `unpin` commands), e.g. a known-good
+//! generation that rollback automation can always go back to: they're kept whatever the
+//! configuration limit, and their entries' titles end in "(pinned)".
+//!
+//! The pins are kept on the ESP, one generation per line, so that they carry over from one install
+//! to the next; the pins on every ESP apply to all of them.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use super::set_default::write_atomically;
+use crate::context::Context;
+use crate::util::Generation;
+use crate::warnings::{self, Kind};
+use crate::Result;
+
+/// Where the pins are kept on the ESP.
+pub(crate) const PINNED: &str = "loader/nixos-pinned";
+
+/// What's appended to the titles of pinned generations' entries.
+const PINNED_SUFFIX: &str = " (pinned)";
+
+/// The generations pinned on `esp`.
+pub(super) fn load(esp: &Path) -> Result<BTreeSet<usize>> {
+    let path = esp.join(PINNED);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e).with_path_context(&path),
+    };
+
+    let mut pinned = BTreeSet::new();
+    for line in contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let idx = line
+            .parse::<usize>()
+            .map_err(|e| format!("'{}': '{}' isn't a generation: {}", path.display(), line, e))?;
+        pinned.insert(idx);
+    }
+
+    Ok(pinned)
+}
+
+/// Records `pinned` on `esp` (removing the record if there are none).
+pub(super) fn write(esp: &Path, pinned: &BTreeSet<usize>) -> Result<()> {
+    let path = esp.join(PINNED);
+
+    if pinned.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).with_path_context(&path),
+            _ => Ok(()),
+        };
+    }
+
+    let contents = pinned
+        .iter()
+        .map(|idx| format!("{}\n", idx))
+        .collect::<String>();
+    write_atomically(&path, &contents)
+}
+
+/// The generations of `generations` that are pinned: the ones in `pin_generation`, and the ones
+/// pinned on any of `esps`. A pinned generation that no longer exists is unpinned.
+pub(super) fn pinned(
+    esps: &[PathBuf],
+    pin_generation: &[usize],
+    generations: &[Generation],
+) -> Result<BTreeSet<usize>> {
+    let mut pinned = pin_generation.iter().copied().collect::<BTreeSet<_>>();
+    for esp in esps {
+        pinned.extend(self::load(esp)?);
+    }
+
+    pinned.retain(|idx| {
+        let exists = generations.iter().any(|g| g.idx == *idx);
+        if !exists {
+            warnings::emit(
+                Kind::StalePin,
+                format!("unpinning generation {}, which no longer exists", idx),
+            );
+        }
+        exists
+    });
+
+    Ok(pinned)
+}
+
+/// Makes sure the `pinned` generations aren't pruned, whatever the configuration limit.
+pub(super) fn keep_pinned(
+    wanted_generations: &mut Vec<Generation>,
+    all_generations: &[Generation],
+    pinned: &BTreeSet<usize>,
+) {
+    for generation in all_generations {
+        if pinned.contains(&generation.idx)
+            && !wanted_generations.iter().any(|g| g.idx == generation.idx)
+        {
+            debug!(
+                "keeping pinned generation {} despite configuration limit",
+                generation.idx
+            );
+            wanted_generations.push(generation.clone());
+        }
+    }
+
+    wanted_generations.sort_by_key(|g| g.idx);
+}
+
+/// Appends "(pinned)" to the titles of the entries in `generated_entries` of the `pinned`
+/// generations of the system profile (including their specialisations' and variants').
+pub(super) fn annotate(generated_entries: &Path, pinned: &BTreeSet<usize>) -> Result<()> {
+    let loader_entries = generated_entries.join("loader/entries");
+    if pinned.is_empty() || !loader_entries.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(&loader_entries).with_path_context(&loader_entries)? {
+        let path = entry.with_path_context(&loader_entries)?.path();
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !path.is_dir() && self::is_pinned(name, pinned) => {}
+            _ => continue,
+        }
+
+        let contents = fs::read_to_string(&path).with_path_context(&path)?;
+        let annotated = contents
+            .lines()
+            .map(|line| match line.strip_prefix("title ") {
+                Some(title) if !title.ends_with(PINNED_SUFFIX) => {
+                    format!("title {}{}\n", title, PINNED_SUFFIX)
+                }
+                _ => format!("{}\n", line),
+            })
+            .collect::<String>();
+
+        if annotated != contents {
+            debug!("marking '{}' as pinned", path.display());
+            fs::write(&path, annotated).with_path_context(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the entry `name` is one of a `pinned` generation of the system profile, e.g.
+/// `nixos-generation-12.conf` or `nixos-generation-12-specialisation-gui.conf` for generation 12.
+fn is_pinned(name: &str, pinned: &BTreeSet<usize>) -> bool {
+    let rest = match name
+        .strip_prefix("nixos-generation-")
+        .and_then(|rest| rest.strip_suffix(".conf"))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let idx = rest.split('-').next().unwrap_or_default();
+
+    idx.parse::<usize>()
+        .map_or(false, |idx| pinned.contains(&idx))
+}
+
+/// Pins (or, if `pin` isn't set, unpins) generation `idx` on every ESP in `esps`, which takes
+/// effect the next time they're installed to.
+pub(crate) fn set_pinned(esps: &[PathBuf], idx: usize, pin: bool) -> Result<()> {
+    for esp in esps {
+        let mut pinned = self::load(esp)?;
+        let changed = if pin {
+            pinned.insert(idx)
+        } else {
+            pinned.remove(&idx)
+        };
+
+        if changed {
+            self::write(esp, &pinned)?;
+        }
+        info!(
+            "generation {} is {} on '{}'",
+            idx,
+            if pin { "pinned" } else { "no longer pinned" },
+            esp.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generations(idxs: &[usize]) -> Vec<Generation> {
+        idxs.iter()
+            .map(|idx| Generation {
+                idx: *idx,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn idxs(generations: &[Generation]) -> Vec<usize> {
+        generations.iter().map(|g| g.idx).collect()
+    }
+
+    #[test]
+    fn test_keep_pinned() {
+        let all_generations = generations(&[1, 2, 3, 4, 5]);
+        let mut wanted_generations = all_generations[3..].to_vec();
+
+        keep_pinned(
+            &mut wanted_generations,
+            &all_generations,
+            &[2, 5].iter().copied().collect(),
+        );
+        assert_eq!(idxs(&wanted_generations), [2, 4, 5]);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let loader_entries = tempdir.path().join("loader/entries");
+        fs::create_dir_all(&loader_entries).unwrap();
+        for name in [
+            "nixos-generation-1.conf",
+            "nixos-generation-12.conf",
+            "nixos-generation-12-specialisation-gui.conf",
+            "nixos-generation-120.conf",
+            "nixos-work-generation-12.conf",
+        ] {
+            fs::write(loader_entries.join(name), "title NixOS\nversion 12\n").unwrap();
+        }
+
+        let pinned = std::iter::once(12).collect();
+        annotate(tempdir.path(), &pinned).unwrap();
+        // annotating again doesn't add another suffix
+        annotate(tempdir.path(), &pinned).unwrap();
+
+        let title = |name: &str| {
+            fs::read_to_string(loader_entries.join(name))
+                .unwrap()
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(title("nixos-generation-12.conf"), "title NixOS (pinned)");
+        assert_eq!(
+            title("nixos-generation-12-specialisation-gui.conf"),
+            "title NixOS (pinned)"
+        );
+        for name in [
+            "nixos-generation-1.conf",
+            "nixos-generation-120.conf",
+            "nixos-work-generation-12.conf",
+        ] {
+            assert_eq!(title(name), "title NixOS", "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_pin_and_unpin() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esps = [tempdir.path().join("esp1"), tempdir.path().join("esp2")];
+        for esp in &esps {
+            fs::create_dir_all(esp.join("loader")).unwrap();
+        }
+        let all_generations = generations(&[1, 2, 3, 4, 5]);
+        let wanted = |pinned: &BTreeSet<usize>| {
+            let mut wanted_generations = all_generations[4..].to_vec();
+            keep_pinned(&mut wanted_generations, &all_generations, pinned);
+            idxs(&wanted_generations)
+        };
+
+        set_pinned(&esps[..1], 2, true).unwrap();
+        assert_eq!(fs::read_to_string(esps[0].join(PINNED)).unwrap(), "2\n");
+        // pins on any ESP, along with --pin-generation, apply, unless the generation is gone
+        let pinned = pinned(&esps, &[3, 7], &all_generations).unwrap();
+        assert_eq!(pinned.iter().copied().collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(wanted(&pinned), [2, 3, 5]);
+        for esp in &esps {
+            write(esp, &pinned).unwrap();
+            assert_eq!(load(esp).unwrap(), pinned);
+        }
+
+        // once it's unpinned, it's pruned with the rest
+        set_pinned(&esps, 2, false).unwrap();
+        let pinned = self::pinned(&esps, &[], &all_generations).unwrap();
+        assert_eq!(wanted(&pinned), [3, 5]);
+
+        set_pinned(&esps, 3, false).unwrap();
+        assert!(!esps[0].join(PINNED).exists());
+        assert!(self::pinned(&esps, &[], &all_generations)
+            .unwrap()
+            .is_empty());
+    }
+}
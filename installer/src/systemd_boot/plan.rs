@@ -1,17 +1,43 @@
 use std::ffi::{CStr, OsStr};
 use std::fs::{self, File};
 use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
 
 use crc::{Crc, CRC_32_ISCSI};
-use log::{debug, error, info, trace, warn};
-
+use generator::report::History;
+use generator::stream;
+use log::{debug, error, info, trace};
+
+use super::budget;
+use super::default_entry;
+use super::drift::EspSnapshot;
+use super::fallback;
+use super::history::HISTORY;
+use super::hook;
+use super::machine_id;
+use super::oneshot::{self, StagedAction, Staging, STAGED_STATE};
+use super::phase::Phase;
+use super::sd_boot_model;
+use super::slot;
 use super::version::systemd::SystemdVersion;
-use crate::files::{FileToReplace, IdentifiedFiles};
-use crate::secure_boot::SigningInfo;
+use super::{Chainload, Credential, CredentialScope, Layout, Slot};
+use crate::command;
+use crate::context::Context;
+use crate::files::{FileToReplace, FileToSign, IdentifiedFiles};
+use crate::manifest::{Manifest, ManifestExt};
+use crate::options::{CommonBootloaderOptions, Timeout};
+use crate::panic_hook::RunContext;
+use crate::report::{HookReport, Outcome, StageReport};
+use crate::secure_boot::{self, SigningInfo, VerifyCache};
 use crate::util::{self, Generation};
+use crate::warnings::{self, Kind};
 use crate::{Args, Result};
 
 const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
@@ -19,11 +45,17 @@ const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 #[derive(Debug, PartialEq)]
 pub(crate) enum SystemdBootPlanState<'a> {
     Start, // transition to install or update based on args.install
+    VerifyEsp {
+        snapshot: EspSnapshot,
+        ignore_drift: bool,
+    },
     Install {
         loader: Option<PathBuf>, // Some(path) if exists
         bootctl: &'a Path,
         esp: &'a Path,
         can_touch_efi_vars: bool,
+        /// Fallback loaders that aren't systemd-boot, which `bootctl install` mustn't overwrite
+        keep_fallback_loaders: Vec<PathBuf>,
     },
     Update {
         bootctl: &'a Path,
@@ -31,27 +63,81 @@ pub(crate) enum SystemdBootPlanState<'a> {
     },
     PruneFiles {
         wanted_generations: &'a [Generation],
-        paths: Vec<&'a Path>,
+        /// The other profiles' generations to keep (see [`util::profile_generations`])
+        profile_generations: &'a [Generation],
+        chainloads: &'a [Chainload],
+        retired_profiles: &'a [String],
+        generated_entries: &'a Path,
+        esp: &'a Path,
+        xbootldr: Option<&'a Path>,
+        efi_dir: PathBuf,
+        /// Only the slot's files are pruned (see `--slot`)
+        slot: Option<Slot>,
+    },
+    SubstituteMachineId {
+        entries: PathBuf,
+        machine_id_file: &'a Path,
+    },
+    GateSortKeys {
+        bootctl: Option<&'a Path>,
+        entries: PathBuf,
     },
     WriteLoader {
         path: PathBuf,
-        timeout: Option<usize>,
+        timeout: Option<Timeout>,
         index: usize,
+        default_entry: Option<String>,
         editor: bool,
         console_mode: &'a str,
     },
+    WriteChainloads {
+        entries: PathBuf,
+        chainloads: &'a [Chainload],
+    },
+    WriteCredentials {
+        dir: PathBuf,
+        credentials: &'a [Credential],
+    },
+    PruneCredentials {
+        owned: Vec<PathBuf>,
+        credentials: &'a [Credential],
+    },
     ReplaceFiles {
         signing_info: &'a Option<SigningInfo>,
+        generated_entries: &'a Path,
         to_replace: Vec<FileToReplace>,
     },
     SignFiles {
         signing_info: &'a SigningInfo,
-        to_sign: Vec<PathBuf>,
+        to_sign: Vec<FileToSign>,
+    },
+    RunHooks {
+        hooks: &'a [PathBuf],
+        generated_entries: &'a Path,
+        esp: &'a Path,
+        xbootldr: Option<&'a Path>,
+        /// The names of the plan's states, filled in once it's complete
+        stages: Vec<&'static str>,
     },
-    // TODO: "Hook" phase here?
     CopyToEsp {
         generated_entries: &'a Path,
         esp: &'a Path,
+        xbootldr: Option<&'a Path>,
+        jobs: usize,
+    },
+    VerifyManifest {
+        manifest: &'a Manifest,
+        generated_entries: &'a Path,
+        esp: &'a Path,
+        xbootldr: Option<&'a Path>,
+    },
+    SetOneshot {
+        bootctl: &'a Path,
+        entry: String,
+    },
+    WriteStaged {
+        path: PathBuf,
+        action: StagedAction,
     },
     Syncfs {
         esp: &'a Path,
@@ -59,16 +145,75 @@ pub(crate) enum SystemdBootPlanState<'a> {
     End,
 }
 
+impl SystemdBootPlanState<'_> {
+    /// The state's name, as it appears in `--report`s.
+    pub(crate) fn name(&self) -> &'static str {
+        use SystemdBootPlanState::*;
+
+        match self {
+            Start => "start",
+            VerifyEsp { .. } => "verify_esp",
+            Install { .. } => "install",
+            Update { .. } => "update",
+            PruneFiles { .. } => "prune_files",
+            SubstituteMachineId { .. } => "substitute_machine_id",
+            GateSortKeys { .. } => "gate_sort_keys",
+            WriteLoader { .. } => "write_loader",
+            WriteChainloads { .. } => "write_chainloads",
+            WriteCredentials { .. } => "write_credentials",
+            PruneCredentials { .. } => "prune_credentials",
+            ReplaceFiles { .. } => "replace_files",
+            SignFiles { .. } => "sign_files",
+            RunHooks { .. } => "run_hooks",
+            CopyToEsp { .. } => "copy_to_esp",
+            VerifyManifest { .. } => "verify_manifest",
+            SetOneshot { .. } => "set_oneshot",
+            WriteStaged { .. } => "write_staged",
+            Syncfs { .. } => "syncfs",
+            End => "end",
+        }
+    }
+}
+
 type SystemdBootPlan<'a> = Vec<SystemdBootPlanState<'a>>;
 
+/// A record of the modifications made to the ESP while consuming a plan.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PlanReport {
+    /// Files that were signed
+    pub signed: Vec<PathBuf>,
+    /// Files that were removed from the ESP
+    pub pruned: Vec<PathBuf>,
+    /// Files that were copied to the ESP (either new or replacing an existing file)
+    pub copied: Vec<PathBuf>,
+    /// The `--hook`s that ran, including one that failed
+    pub hooks: Vec<HookReport>,
+}
+
 pub(crate) struct PlanArgs<'a> {
     pub args: &'a Args,
-    pub bootctl: &'a Path,
+    /// The [`CommonBootloaderOptions`], with systemd-boot's overrides applied
+    pub options: CommonBootloaderOptions,
+    /// `None` if `--no-bootctl` was passed
+    pub bootctl: Option<&'a Path>,
     pub esp: &'a Path,
     pub wanted_generations: &'a [Generation],
+    /// The generations of the other profiles to keep entries for, which only matters to pruning
+    pub profile_generations: &'a [Generation],
     pub default_generation: &'a Generation,
     pub identified_files: IdentifiedFiles,
     pub signing_info: &'a Option<SigningInfo>,
+    /// `None` if the generator didn't write a manifest
+    pub manifest: &'a Option<Manifest>,
+    pub staging: Staging,
+    /// Whether to prune old files, which isn't done to an ESP that a newer release of the installer
+    /// manages (see `--allow-downgrade-management`)
+    pub prune: bool,
+}
+
+/// Renders `plan` the way `--dry-run` prints it.
+pub(crate) fn render_plan(plan: &[SystemdBootPlanState]) -> String {
+    format!("{:#?}\n", plan)
 }
 
 pub(crate) fn create_plan(plan_args: PlanArgs) -> Result<SystemdBootPlan> {
@@ -76,30 +221,274 @@ pub(crate) fn create_plan(plan_args: PlanArgs) -> Result<SystemdBootPlan> {
     let bootctl = plan_args.bootctl;
     let esp = plan_args.esp;
     let wanted_generations = plan_args.wanted_generations;
+    let profile_generations = plan_args.profile_generations;
     let default_generation = plan_args.default_generation;
     let identified_files = plan_args.identified_files;
+    let staging = plan_args.staging;
+    let prune = plan_args.prune;
+    let xbootldr = args.xbootldr.as_deref();
+    let layout = Layout::new(esp, xbootldr);
+
+    if args.no_bootctl && (args.install || args.can_touch_efi_vars) {
+        return Err("--no-bootctl conflicts with --install and --can-touch-efi-vars".into());
+    }
+    args.phase
+        .check(args, plan_args.signing_info.is_some(), prune)?;
 
     let mut plan = vec![SystemdBootPlanState::Start];
 
-    if args.install {
-        let loader = esp.join("loader/loader.conf");
+    // Another boot loader (e.g. rEFInd or GRUB) may own the removable-media fallback path, which
+    // `bootctl install` would overwrite (and we'd then sign) unless asked to.
+    let foreign_fallback_loaders = if bootctl.is_some() && !args.overwrite_fallback_loader {
+        fallback::foreign_loaders(esp)?
+    } else {
+        Vec::new()
+    };
+
+    // With --no-bootctl, systemd-boot is managed by something else (e.g. it's embedded in the
+    // firmware), so we neither install / update it nor sign its binaries.
+    if let Some(bootctl) = bootctl {
+        if args.install {
+            let loader = esp.join("loader/loader.conf");
 
-        plan.push(SystemdBootPlanState::Install {
-            loader: if loader.exists() { Some(loader) } else { None },
-            bootctl,
+            plan.push(SystemdBootPlanState::Install {
+                loader: if loader.exists() { Some(loader) } else { None },
+                bootctl,
+                esp,
+                can_touch_efi_vars: args.can_touch_efi_vars,
+                keep_fallback_loaders: foreign_fallback_loaders.clone(),
+            });
+        } else {
+            plan.push(SystemdBootPlanState::Update { bootctl, esp });
+        }
+    } else if !args.no_bootctl {
+        return Err("--bootctl is required unless --no-bootctl is passed".into());
+    }
+
+    // Remove old things from both the generated entries and ESP
+    // - Generated entries because we don't need to waste space on copying unused kernels / initrds / entries
+    // - ESP so that we don't have unbootable entries
+    if prune {
+        plan.push(SystemdBootPlanState::PruneFiles {
+            wanted_generations,
+            profile_generations,
+            chainloads: &args.chainload,
+            retired_profiles: &args.retire_profile,
+            generated_entries: &args.generated_entries,
             esp,
-            can_touch_efi_vars: args.can_touch_efi_vars,
+            xbootldr,
+            efi_dir: super::efi_dir(args),
+            slot: args.slot,
+        });
+    }
+
+    // Fail before anything is touched if what would be left on the ESP doesn't fit.
+    let unchanged = match plan_args.manifest {
+        Some(manifest) => manifest.unchanged.iter().map(PathBuf::from).collect(),
+        None => Vec::new(),
+    };
+    budget::check(
+        args,
+        layout,
+        &[wanted_generations, profile_generations].concat(),
+        &unchanged,
+    )?;
+
+    // Entries generated with `--machine-id-placeholder` get this machine's machine-id, before
+    // they're compared to the ones already in the ESP.
+    let entries = args.generated_entries.join("loader/entries");
+    if machine_id::has_placeholder(&entries)? {
+        plan.push(SystemdBootPlanState::SubstituteMachineId {
+            entries,
+            machine_id_file: &args.machine_id_file,
+        });
+    }
+
+    // The generator always adds a sort-key to entries, which only loaders that understand it should
+    // get to see.
+    plan.push(SystemdBootPlanState::GateSortKeys {
+        bootctl,
+        entries: args.generated_entries.join("loader/entries"),
+    });
+
+    // Which slot boots is up to `activate-slot`: a slot's install keeps the default, unless there's
+    // none yet.
+    let default_entry = match (args.slot, &plan_args.options.default_entry) {
+        (Some(slot), _) => Some(slot::current_default(esp)?.unwrap_or_else(|| slot.default_glob())),
+        (None, Some(default_entry)) => Some(default_entry.clone()),
+        // loader.conf only names the entry if it isn't `nixos-generation-<index>.conf`.
+        (None, None) => {
+            let entry = default_entry::resolve(
+                &args.generated_entries,
+                layout.payload_root(),
+                wanted_generations,
+                default_generation,
+                staging.default,
+            )?;
+            (entry != format!("nixos-generation-{}.conf", staging.default)).then(|| entry)
+        }
+    };
+    // A newer release's loader.conf (which its manifest doesn't record) is left as it is, like the
+    // rest of what it put on the ESP.
+    if prune {
+        plan.push(SystemdBootPlanState::WriteLoader {
+            path: args.generated_entries.join("loader/loader.conf"),
+            timeout: plan_args.options.timeout,
+            index: staging.default,
+            default_entry,
+            editor: args.editor,
+            console_mode: &args.console_mode,
+        });
+    }
+
+    if !args.chainload.is_empty() {
+        plan.push(SystemdBootPlanState::WriteChainloads {
+            entries: args.generated_entries.join("loader/entries"),
+            chainloads: &args.chainload,
         });
+    }
+
+    let credentials_dir = if args.credential.is_empty() {
+        None
     } else {
-        plan.push(SystemdBootPlanState::Update { bootctl, esp });
+        let dir = self::credentials_dir(args, default_generation)?;
+        plan.push(SystemdBootPlanState::WriteCredentials {
+            dir: args.generated_entries.join(&dir),
+            credentials: &args.credential,
+        });
+
+        Some(dir)
+    };
+    // The credentials that aren't passed anymore are removed from the ESP. Without any, the global
+    // ones are (a generation's go with its unified EFI file when it's pruned). Only those an
+    // earlier run wrote are, as the ESP's history has them: systemd-stub reads the global ones for
+    // every unified EFI file, whoever put them there.
+    let stale_credentials_dir = match (&credentials_dir, args.credential_scope) {
+        (Some(dir), _) => Some(dir.clone()),
+        (None, CredentialScope::Global) => Some(self::credentials_dir(args, default_generation)?),
+        (None, CredentialScope::Generation) => None,
+    };
+    if let (true, Some(dir)) = (prune, stale_credentials_dir) {
+        let owned = History::load(&layout.esp.join(HISTORY))?
+            .files
+            .keys()
+            .filter(|path| {
+                path.parent() == Some(dir.as_path()) && path.extension() == Some(OsStr::new("cred"))
+            })
+            .map(|path| layout.dest(path))
+            .collect::<Vec<_>>();
+        if !owned.is_empty() {
+            plan.push(SystemdBootPlanState::PruneCredentials {
+                owned,
+                credentials: &args.credential,
+            });
+        }
+    }
+
+    // Files that are identical to the ones already in the ESP are removed from the generated
+    // entries before signing, so that they are neither signed nor copied again.
+    let mut to_replace = identified_files.to_replace;
+    let esp_loader = esp.join("loader/loader.conf");
+    // `bootctl install` removes the existing loader.conf, so there'd be nothing to compare to.
+    if !args.install && esp_loader.exists() {
+        to_replace.push(FileToReplace {
+            generated_loc: args.generated_entries.join("loader/loader.conf"),
+            esp_loc: esp_loader,
+        });
+    }
+    for chainload in &args.chainload {
+        let esp_entry = esp.join("loader/entries").join(chainload.entry_filename());
+        if esp_entry.exists() {
+            to_replace.push(FileToReplace {
+                generated_loc: args
+                    .generated_entries
+                    .join("loader/entries")
+                    .join(chainload.entry_filename()),
+                esp_loc: esp_entry,
+            });
+        }
+    }
+
+    if let Some(dir) = &credentials_dir {
+        for credential in &args.credential {
+            let esp_credential = layout.dest(&dir.join(credential.filename()));
+            if esp_credential.exists() {
+                to_replace.push(FileToReplace {
+                    generated_loc: args.generated_entries.join(dir).join(credential.filename()),
+                    esp_loc: esp_credential,
+                });
+            }
+        }
+    }
+
+    // Everything the plan decided about the files already on the ESP (and the ones it adds) is
+    // checked again before anything is done, in case the ESP changed since.
+    let snapshot = EspSnapshot::observe(
+        to_replace
+            .iter()
+            .map(|file| file.esp_loc.as_path())
+            .chain(identified_files.to_add.iter().map(PathBuf::as_path)),
+    )?;
+    if !snapshot.is_empty() {
+        plan.insert(
+            1,
+            SystemdBootPlanState::VerifyEsp {
+                snapshot,
+                ignore_drift: args.ignore_esp_drift,
+            },
+        );
     }
 
+    plan.push(SystemdBootPlanState::ReplaceFiles {
+        signing_info: plan_args.signing_info,
+        generated_entries: &args.generated_entries,
+        to_replace,
+    });
+
     if let Some(signing_info) = &plan_args.signing_info {
-        let mut to_sign = vec![
-            esp.join("EFI/systemd/systemd-bootx64.efi"),
-            esp.join("EFI/BOOT/BOOTX64.EFI"),
-        ];
-        to_sign.extend(identified_files.to_sign);
+        let mut to_sign = Vec::new();
+        if bootctl.is_some() {
+            to_sign.push(esp.join("EFI/systemd/systemd-bootx64.efi"));
+            let fallback = esp.join("EFI/BOOT/BOOTX64.EFI");
+            if !foreign_fallback_loaders
+                .iter()
+                .any(|loader| loader.as_os_str().eq_ignore_ascii_case(&fallback))
+            {
+                to_sign.push(fallback);
+            }
+        }
+        // Installing or updating systemd-boot on its own only signs systemd-boot.
+        if args.phase != Phase::Loader {
+            to_sign.extend(identified_files.to_sign);
+        }
+
+        // Only sign chainloaded programs when explicitly asked to: signing e.g. Microsoft's
+        // Boot Manager with our key is usually not what the user wants.
+        if args.sign_chainload && args.phase != Phase::Loader {
+            for chainload in &args.chainload {
+                let target = esp.join(&chainload.path);
+                if target.exists() {
+                    to_sign.push(target);
+                } else {
+                    warnings::emit(
+                        Kind::MissingChainload,
+                        format!(
+                            "not signing chainload '{}': '{}' does not exist on this ESP",
+                            chainload.name,
+                            target.display()
+                        ),
+                    );
+                }
+            }
+        }
+
+        let to_sign = to_sign
+            .into_iter()
+            .map(|file| FileToSign {
+                pair: signing_info.pair_for(self::esp_path(&file, &[&args.generated_entries, esp])),
+                file,
+            })
+            .collect();
 
         plan.push(SystemdBootPlanState::SignFiles {
             signing_info,
@@ -107,133 +496,461 @@ pub(crate) fn create_plan(plan_args: PlanArgs) -> Result<SystemdBootPlan> {
         });
     }
 
-    // Remove old things from both the generated entries and ESP
-    // - Generated entries because we don't need to waste space on copying unused kernels / initrds / entries
-    // - ESP so that we don't have unbootable entries
-    plan.push(SystemdBootPlanState::PruneFiles {
-        wanted_generations,
-        paths: vec![&args.generated_entries, esp],
-    });
-
-    plan.push(SystemdBootPlanState::ReplaceFiles {
-        signing_info: &plan_args.signing_info,
-        to_replace: identified_files.to_replace,
-    });
-
-    plan.push(SystemdBootPlanState::WriteLoader {
-        path: args.generated_entries.join("loader/loader.conf"),
-        timeout: args.timeout,
-        index: default_generation.idx,
-        editor: args.editor,
-        console_mode: &args.console_mode,
-    });
+    if !args.hook.is_empty() {
+        plan.push(SystemdBootPlanState::RunHooks {
+            hooks: &args.hook,
+            generated_entries: &args.generated_entries,
+            esp,
+            xbootldr,
+            stages: Vec::new(),
+        });
+    }
 
     plan.push(SystemdBootPlanState::CopyToEsp {
         generated_entries: &args.generated_entries,
         esp,
+        xbootldr,
+        jobs: args.copy_jobs,
     });
 
+    if let Some(manifest) = plan_args.manifest {
+        plan.push(SystemdBootPlanState::VerifyManifest {
+            manifest,
+            generated_entries: &args.generated_entries,
+            esp,
+            xbootldr,
+        });
+    }
+
+    if let Some(oneshot) = staging.oneshot {
+        plan.push(SystemdBootPlanState::SetOneshot {
+            bootctl: bootctl.ok_or("--stage-oneshot requires --bootctl")?,
+            entry: default_entry::resolve(
+                &args.generated_entries,
+                layout.payload_root(),
+                wanted_generations,
+                default_generation,
+                oneshot,
+            )?,
+        });
+    }
+
+    if staging.action != StagedAction::Keep {
+        plan.push(SystemdBootPlanState::WriteStaged {
+            path: esp.join(STAGED_STATE),
+            action: staging.action,
+        });
+    }
+
     plan.push(SystemdBootPlanState::Syncfs { esp });
+    if let Some(xbootldr) = xbootldr {
+        plan.push(SystemdBootPlanState::Syncfs { esp: xbootldr });
+    }
 
     plan.push(SystemdBootPlanState::End);
+    plan.retain(|state| args.phase.includes(state));
+
+    let names = plan.iter().map(|state| state.name()).collect::<Vec<_>>();
+    for state in &mut plan {
+        if let SystemdBootPlanState::RunHooks { stages, .. } = state {
+            *stages = names.clone();
+        }
+    }
 
     Ok(plan)
 }
 
-pub(crate) fn consume_plan(plan: SystemdBootPlan) -> Result<()> {
+/// Runs `plan`'s `--hook`s the way `--dry-run-hooks` does: on the staged files as they are (before
+/// anything's signed), with `DRY_RUN=1` in their environment. `reports` has the hooks that ran even
+/// if one fails.
+pub(crate) fn dry_run_hooks(
+    plan: &[SystemdBootPlanState],
+    reports: &mut Vec<HookReport>,
+) -> Result<()> {
+    for state in plan {
+        if let SystemdBootPlanState::RunHooks {
+            hooks,
+            generated_entries,
+            esp,
+            xbootldr,
+            stages,
+        } = state
+        {
+            let payload = hook::Payload::new(generated_entries, esp, *xbootldr, true, stages, &[])?;
+            hook::run(hooks, &payload, reports)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn consume_plan(plan: SystemdBootPlan) -> Result<PlanReport> {
+    let mut report = PlanReport::default();
+    self::consume_plan_with(plan, &mut report, &mut Vec::new(), &RunContext::default())?;
+
+    Ok(report)
+}
+
+/// Consumes `plan` like [`consume_plan`], recording how every state went (or that it was skipped
+/// because an earlier one failed) in `stages`. `report` has what was done even if a state fails,
+/// and `ctx` the last state reached even if one panics.
+pub(crate) fn consume_plan_with(
+    plan: SystemdBootPlan,
+    report: &mut PlanReport,
+    stages: &mut Vec<StageReport>,
+    ctx: &RunContext,
+) -> Result<()> {
+    let mut plan = plan.into_iter();
+
+    while let Some(state) = plan.next() {
+        let stage = state.name();
+        ctx.enter(stage);
+        let start = Instant::now();
+        let ret = self::consume_state(state, report, ctx);
+        stages.push(StageReport::new(stage, start.elapsed(), &ret));
+
+        if ret.is_err() {
+            stages.extend(plan.map(|state| StageReport::not_run(state.name(), Outcome::Skipped)));
+            return ret;
+        }
+    }
+
+    Ok(())
+}
+
+fn consume_state(
+    state: SystemdBootPlanState,
+    report: &mut PlanReport,
+    ctx: &RunContext,
+) -> Result<()> {
     use SystemdBootPlanState::*;
 
-    for state in plan {
-        match state {
-            Start => {
-                trace!("started updating / installing");
-            }
-            Install {
-                loader,
-                bootctl,
-                esp,
-                can_touch_efi_vars,
-            } => {
-                trace!("installing systemd-boot");
-                self::run_install(loader, bootctl, esp, can_touch_efi_vars)?;
+    match state {
+        Start => {
+            trace!("started updating / installing");
+        }
+        VerifyEsp {
+            snapshot,
+            ignore_drift,
+        } => {
+            trace!("checking that the esp didn't change since planning");
+            snapshot.verify(ignore_drift)?;
+        }
+        Install {
+            loader,
+            bootctl,
+            esp,
+            can_touch_efi_vars,
+            keep_fallback_loaders,
+        } => {
+            trace!("installing systemd-boot");
+            fallback::keep_while(&keep_fallback_loaders, || {
+                self::run_install(loader, bootctl, esp, can_touch_efi_vars)
+            })?;
+        }
+        Update { bootctl, esp } => {
+            trace!("updating systemd-boot");
+            self::run_update(bootctl, esp)?;
+        }
+        SignFiles {
+            signing_info,
+            to_sign,
+        } => {
+            trace!("signing efi files");
+
+            for FileToSign { file, pair } in to_sign {
+                if !file.exists() {
+                    debug!(
+                        "not signing '{}': it was pruned or is identical to the file in the esp",
+                        file.display()
+                    );
+                    continue;
+                }
+
+                if ctx
+                    .verified
+                    .verify_file(signing_info, &file, &pair.cert)
+                    .is_ok()
+                {
+                    debug!("not signing '{}': it is already signed", file.display());
+                    continue;
+                }
+
+                // sbsign writes the signed file in place, which would also sign every other
+                // hard link to the generated file.
+                util::unshare_file(&file)?;
+                let signed = signing_info.sign_file(&file, &pair);
+                // even if sbsign failed, it may have left the file half-written
+                ctx.verified.invalidate(&file);
+                signed?;
+                report.signed.push(file);
             }
-            Update { bootctl, esp } => {
-                trace!("updating systemd-boot");
-                self::run_update(bootctl, esp)?;
+        }
+        PruneFiles {
+            wanted_generations,
+            profile_generations,
+            chainloads,
+            retired_profiles,
+            generated_entries,
+            esp,
+            xbootldr,
+            efi_dir,
+            slot,
+        } => {
+            let payload_root = Layout::new(esp, xbootldr).payload_root();
+            trace!(
+                "pruning paths: '{}', '{}'",
+                generated_entries.display(),
+                payload_root.display()
+            );
+
+            // before the generated entries are pruned, which would remove their variants
+            let wanted_generations = super::with_variant_entries(
+                &[wanted_generations, profile_generations].concat(),
+                generated_entries,
+                slot,
+            )?;
+
+            // The chainload entries stay on the ESP, with the programs they chainload (and a
+            // slot's install leaves them alone).
+            let (chainloads, esp_chainloads) = match (xbootldr, slot) {
+                (Some(_), None) => (&[][..], Some(chainloads)),
+                _ => (chainloads, None),
+            };
+
+            // Adopted entries aren't pruned by name, and go first so that the kernels and initrds
+            // only they referred to are pruned with the rest.
+            if slot.is_none() {
+                report.pruned.extend(super::adopt::remove_old(
+                    Layout::new(esp, xbootldr),
+                    &wanted_generations,
+                )?);
             }
-            SignFiles {
-                signing_info,
-                to_sign,
-            } => {
-                trace!("signing efi files");
 
-                for file in to_sign {
-                    signing_info.sign_file(&file)?;
+            for path in [generated_entries, payload_root] {
+                debug!(
+                    "removing old entries / kernels / initrds from '{}'",
+                    &path.display()
+                );
+
+                let pruned = super::remove_old_files(
+                    &wanted_generations,
+                    chainloads,
+                    retired_profiles,
+                    path,
+                    &efi_dir,
+                    slot,
+                )?;
+                if path == payload_root {
+                    report.pruned.extend(pruned);
                 }
             }
-            PruneFiles {
-                wanted_generations,
-                paths,
-            } => {
-                trace!("pruning paths: {:?}", &paths);
 
-                for path in paths {
-                    debug!(
-                        "removing old entries / kernels / initrds from '{}'",
-                        &path.display()
-                    );
+            if let Some(chainloads) = esp_chainloads {
+                debug!("removing old chainload entries from '{}'", esp.display());
+                report
+                    .pruned
+                    .extend(super::remove_old_chainloads(chainloads, esp)?);
+            }
+        }
+        ReplaceFiles {
+            signing_info,
+            generated_entries,
+            to_replace,
+        } => {
+            trace!("replacing existing files in esp");
 
-                    super::remove_old_files(wanted_generations, path)?;
-                }
+            for file in to_replace {
+                self::replace_file(&file, signing_info, generated_entries, &ctx.verified)?;
             }
-            ReplaceFiles {
-                signing_info,
-                to_replace,
-            } => {
-                trace!("replacing existing files in esp");
+        }
+        SubstituteMachineId {
+            entries,
+            machine_id_file,
+        } => {
+            trace!("substituting the machine-id into entries");
+
+            let machine_id = machine_id::read(machine_id_file)?;
+            machine_id::substitute(&entries, &machine_id)?;
+        }
+        GateSortKeys { bootctl, entries } => {
+            trace!("checking if the loader supports sort-key");
 
-                for file in to_replace {
-                    self::replace_file(&file, signing_info)?;
-                }
+            let loader_version = match bootctl {
+                Some(bootctl) => Some(SystemdVersion::detect_version(bootctl)?),
+                None => None,
+            };
+
+            if !sd_boot_model::supports_sort_key(loader_version.as_ref()) {
+                debug!("loader doesn't support sort-key, removing it from entries");
+                super::remove_sort_keys(&entries)?;
             }
-            WriteLoader {
-                path,
+        }
+        WriteLoader {
+            path,
+            timeout,
+            index,
+            default_entry,
+            editor,
+            console_mode,
+        } => {
+            trace!("writing loader.conf for default boot entry");
+
+            // We don't need to check if loader.conf already exists because we are writing it
+            // directly to the `generated_entries` directory (where there cannot be one unless
+            // manually placed)
+            let mut f = File::create(&path).with_path_context(&path)?;
+            let contents = super::create_loader_conf(
                 timeout,
                 index,
+                default_entry.as_deref(),
                 editor,
                 console_mode,
-            } => {
-                trace!("writing loader.conf for default boot entry");
+            )?;
 
-                // We don't need to check if loader.conf already exists because we are writing it
-                // directly to the `generated_entries` directory (where there cannot be one unless
-                // manually placed)
-                let mut f = File::create(&path)?;
-                let contents = super::create_loader_conf(timeout, index, editor, console_mode)?;
-
-                f.write_all(contents.as_bytes())?;
-            }
-            CopyToEsp {
-                generated_entries,
-                esp,
-            } => {
-                trace!("copying everything to the esp");
-                self::copy_to_esp(generated_entries, esp)?;
-                fs::remove_dir_all(&generated_entries)?;
+            f.write_all(contents.as_bytes()).with_path_context(&path)?;
+        }
+        WriteChainloads {
+            entries,
+            chainloads,
+        } => {
+            trace!("writing chainload entries");
+
+            fs::create_dir_all(&entries).with_path_context(&entries)?;
+            for chainload in chainloads {
+                let path = entries.join(chainload.entry_filename());
+                debug!("writing chainload entry '{}'", path.display());
+                fs::write(&path, chainload.render()?).with_path_context(&path)?;
             }
-            Syncfs { esp } => {
-                trace!("attempting to syncfs(2) the esp");
-                self::syncfs(esp)?;
+        }
+        WriteCredentials { dir, credentials } => {
+            trace!("staging credentials");
+
+            fs::create_dir_all(&dir).with_path_context(&dir)?;
+            for credential in credentials {
+                let path = dir.join(credential.filename());
+                debug!("staging credential '{}'", credential.name);
+                fs::copy(&credential.path, &path).with_paths_context(&credential.path, &path)?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+                    .with_path_context(&path)?;
             }
-            End => {
-                trace!("finished updating / installing")
+        }
+        PruneCredentials { owned, credentials } => {
+            trace!("pruning credentials");
+
+            for path in owned {
+                let stale = match path.file_name().map(|name| name.to_string_lossy()) {
+                    Some(name) => !credentials.iter().any(|c| c.filename() == name),
+                    None => false,
+                };
+
+                if stale && path.is_file() {
+                    debug!("removing credential '{}'", path.display());
+                    fs::remove_file(&path).with_path_context(&path)?;
+                    report.pruned.push(path);
+                }
             }
         }
+        RunHooks {
+            hooks,
+            generated_entries,
+            esp,
+            xbootldr,
+            stages,
+        } => {
+            trace!("running the hooks on the staged files");
+            let payload = hook::Payload::new(
+                generated_entries,
+                esp,
+                xbootldr,
+                false,
+                &stages,
+                &report.signed,
+            )?;
+            hook::run(hooks, &payload, &mut report.hooks)?;
+        }
+        CopyToEsp {
+            generated_entries,
+            esp,
+            xbootldr,
+            jobs,
+        } => {
+            trace!("copying everything to the esp");
+            self::copy_to_esp(
+                generated_entries,
+                Layout::new(esp, xbootldr),
+                jobs,
+                &|src: &Path, dest: &Path| {
+                    util::atomic_tmp_copy_file(src, dest)?;
+                    ctx.verified.invalidate(dest);
+                    Ok(())
+                },
+                &mut report.copied,
+            )?;
+            fs::remove_dir_all(generated_entries).with_path_context(generated_entries)?;
+        }
+        VerifyManifest {
+            manifest,
+            generated_entries,
+            esp,
+            xbootldr,
+        } => {
+            trace!("verifying the copied files against the manifest");
+            manifest.verify(
+                generated_entries,
+                Layout::new(esp, xbootldr).payload_root(),
+                &report.copied,
+                &report.signed,
+            )?;
+        }
+        SetOneshot { bootctl, entry } => {
+            trace!("setting the one-shot boot entry");
+            self::run_set_oneshot(bootctl, &entry)?;
+        }
+        WriteStaged { path, action } => {
+            trace!("updating the staged generation");
+            oneshot::write_staged(&path, action)?;
+        }
+        Syncfs { esp } => {
+            trace!("attempting to syncfs(2) the esp");
+            self::syncfs(esp)?;
+        }
+        End => {
+            trace!("finished updating / installing")
+        }
     }
 
     Ok(())
 }
 
+/// The directory (relative to the root of the ESP) that the credentials go in.
+fn credentials_dir(args: &Args, default_generation: &Generation) -> Result<PathBuf> {
+    match args.credential_scope {
+        CredentialScope::Global => Ok(PathBuf::from("loader/credentials")),
+        CredentialScope::Generation => {
+            if !args.unified_efi {
+                return Err("--credential-scope generation requires --unified-efi".into());
+            }
+
+            let efi = default_generation
+                .required_filenames
+                .iter()
+                .find(|f| Path::new(f).extension() == Some(OsStr::new("efi")))
+                .ok_or_else(|| {
+                    format!(
+                        "generation {} has no unified EFI file to scope the credentials to",
+                        default_generation.idx
+                    )
+                })?;
+
+            let mut extra_d = efi.clone();
+            extra_d.push(".extra.d");
+
+            Ok(super::efi_dir(args).join(extra_d))
+        }
+    }
+}
+
 fn run_install(
     loader: Option<PathBuf>,
     bootctl: &Path,
@@ -242,7 +959,7 @@ fn run_install(
 ) -> Result<()> {
     if let Some(loader) = loader {
         debug!("removing existing loader.conf");
-        fs::remove_file(&loader)?;
+        fs::remove_file(&loader).with_path_context(&loader)?;
     }
 
     let mut args = vec![
@@ -254,7 +971,7 @@ fn run_install(
         args.push(String::from("--no-variables"));
     }
     debug!("running `{}` with args `{:?}`", &bootctl.display(), &args);
-    let status = Command::new(&bootctl).args(&args).status()?;
+    let status = command::status(Command::new(bootctl).args(&args))?;
 
     if !status.success() {
         return Err(format!(
@@ -274,7 +991,7 @@ fn run_update(bootctl: &Path, esp: &Path) -> Result<()> {
 
     let args = &["update", "--path", &esp.display().to_string()];
     debug!("running `{}` with args `{:?}`", &bootctl.display(), &args);
-    let status = Command::new(&bootctl).args(args).status()?;
+    let status = command::status(Command::new(bootctl).args(args))?;
 
     if !status.success() {
         info!(
@@ -287,72 +1004,117 @@ fn run_update(bootctl: &Path, esp: &Path) -> Result<()> {
     Ok(())
 }
 
-fn replace_file(file: &FileToReplace, signing_info: &Option<SigningInfo>) -> Result<()> {
+fn run_set_oneshot(bootctl: &Path, entry: &str) -> Result<()> {
+    let args = &["set-oneshot", entry];
+    debug!("running `{}` with args `{:?}`", &bootctl.display(), &args);
+    let status = command::status(Command::new(bootctl).args(args))?;
+
+    if !status.success() {
+        return Err(format!(
+            "failed to run `{}` with args `{:?}`",
+            &bootctl.display(),
+            &args
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Where `file` (under one of `roots`, e.g. the staging tree or the ESP) goes on the ESP, which is
+/// what `--signing-rule`s match.
+fn esp_path<'p>(file: &'p Path, roots: &[&Path]) -> &'p Path {
+    roots
+        .iter()
+        .find_map(|root| file.strip_prefix(root).ok())
+        .unwrap_or(file)
+}
+
+/// Removes `file`'s generated copy if it's the same as the one on the ESP (ignoring signatures),
+/// so that it isn't copied again.
+fn replace_file(
+    file: &FileToReplace,
+    signing_info: &Option<SigningInfo>,
+    generated_entries: &Path,
+    verified: &VerifyCache,
+) -> Result<()> {
     let generated_loc = &file.generated_loc;
     let esp_loc = &file.esp_loc;
 
-    let (hash_a, hash_b) = if signing_info.is_some()
-        && generated_loc.extension() == Some(OsStr::new("efi"))
-    {
-        let signing_info = signing_info.as_ref().unwrap();
-
-        // If the signed file in the generated location doesn't validate, something went
-        // horribly wrong and this error *should* be bubbled up.
-        signing_info.verify_file(generated_loc)?;
-
-        // However, if the signed file in the ESP location doesn't validate, we will be
-        // replacing it with the generated file; just warn the user.
-        if let Err(e) = signing_info.verify_file(esp_loc) {
-            warn!("{}", e);
-        }
-
-        let tmp_dir = std::env::temp_dir();
-        let generated_tmp = tmp_dir.join("generated");
-        let esp_tmp = tmp_dir.join("esp");
-
-        fs::copy(&generated_loc, &generated_tmp)?;
-        fs::copy(&esp_loc, &esp_tmp)?;
-
-        let sbattach = env!("PATCHED_SBATTACH_BINARY");
-        let args = &["--remove", &generated_tmp.display().to_string()];
-        debug!("running `{}` with args `{:?}`", &sbattach, &args);
-        let status = Command::new(sbattach)
-            .args(args)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
-        if !status.success() {
-            return Err(format!(
-                "failed to remove signature from '{}'",
-                generated_tmp.display()
-            )
-            .into());
-        }
+    if !generated_loc.exists() || !esp_loc.exists() {
+        debug!(
+            "not comparing {} and {}: one of them was pruned",
+            esp_loc.display(),
+            generated_loc.display()
+        );
+        return Ok(());
+    }
 
-        let args = &["--remove", &esp_tmp.display().to_string()];
-        debug!("running `{}` with args `{:?}`", &sbattach, &args);
-        let status = Command::new(sbattach)
-            .args(args)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
-        if !status.success() {
-            return Err(format!("failed to remove signature from '{}'", esp_tmp.display()).into());
+    if self::is_entry(generated_loc) {
+        let generated = fs::read_to_string(generated_loc).with_path_context(generated_loc)?;
+        // an entry that isn't valid UTF-8 is rewritten, rather than failing the update
+        let esp = util::read_to_string_lossy(esp_loc).with_path_context(esp_loc)?;
+
+        // entries written by older versions differ only in whitespace, which isn't worth a rewrite
+        if sd_boot_model::same_entry(&generated, &esp) {
+            debug!(
+                "{} and {} are the same entry",
+                esp_loc.display(),
+                generated_loc.display()
+            );
+            fs::remove_file(generated_loc).with_path_context(generated_loc)?;
+            return Ok(());
         }
+    }
 
-        let hash_a = CASTAGNOLI.checksum(&fs::read(&generated_tmp)?);
-        let hash_b = CASTAGNOLI.checksum(&fs::read(&esp_tmp)?);
+    let (hash_a, hash_b) =
+        if signing_info.is_some() && generated_loc.extension() == Some(OsStr::new("efi")) {
+            let signing_info = signing_info.as_ref().unwrap();
 
-        fs::remove_file(&generated_tmp)?;
-        fs::remove_file(&esp_tmp)?;
+            // The generated file hasn't been signed yet (that only happens if it differs from
+            // the file in the ESP), but if the signed file in the ESP location doesn't validate,
+            // just warn the user; the signatures are stripped before comparing anyway.
+            let pair = signing_info.pair_for(self::esp_path(generated_loc, &[generated_entries]));
+            if let Err(e) = verified.verify_file(signing_info, esp_loc, &pair.cert) {
+                warnings::emit(Kind::UnverifiedSignature, e);
+            }
 
-        (hash_a, hash_b)
-    } else {
-        let hash_a = CASTAGNOLI.checksum(&fs::read(&generated_loc)?);
-        let hash_b = CASTAGNOLI.checksum(&fs::read(&esp_loc)?);
+            let tmp_dir = std::env::temp_dir();
+            let generated_tmp = tmp_dir.join("generated");
+            let esp_tmp = tmp_dir.join("esp");
+
+            fs::copy(generated_loc, &generated_tmp)
+                .with_paths_context(generated_loc, &generated_tmp)?;
+            fs::copy(esp_loc, &esp_tmp).with_paths_context(esp_loc, &esp_tmp)?;
+
+            signing_info.remove_signature(&generated_tmp)?;
+            signing_info.remove_signature(&esp_tmp)?;
+
+            let hash_a = self::crc32c(&generated_tmp, false)?;
+            let hash_b = self::crc32c(&esp_tmp, false)?;
+
+            fs::remove_file(&generated_tmp).with_path_context(&generated_tmp)?;
+            fs::remove_file(&esp_tmp).with_path_context(&esp_tmp)?;
+
+            (hash_a, hash_b)
+        } else {
+            // Nothing is signed this run, but the file on the ESP may be from a run that signed it
+            // (e.g. before the signing flags were removed), which isn't worth rewriting it on
+            // every switch for.
+            let strip_signature = secure_boot::is_signed_file(esp_loc)?;
+            if strip_signature {
+                debug!(
+                    "comparing {} to {} without its signature",
+                    esp_loc.display(),
+                    generated_loc.display()
+                );
+            }
 
-        (hash_a, hash_b)
-    };
+            (
+                self::crc32c(generated_loc, strip_signature)?,
+                self::crc32c(esp_loc, strip_signature)?,
+            )
+        };
 
     if hash_a == hash_b {
         debug!(
@@ -360,20 +1122,59 @@ fn replace_file(file: &FileToReplace, signing_info: &Option<SigningInfo>) -> Res
             esp_loc.display(),
             generated_loc.display()
         );
-        fs::remove_file(&generated_loc)?;
+        fs::remove_file(generated_loc).with_path_context(generated_loc)?;
     } else {
-        warn!(
-            "{} is different from {} and will be replaced",
-            esp_loc.display(),
-            generated_loc.display(),
+        warnings::emit(
+            Kind::ModifiedFile,
+            format!(
+                "{} is different from {} and will be replaced",
+                esp_loc.display(),
+                generated_loc.display(),
+            ),
         );
     }
 
     Ok(())
 }
 
-fn copy_to_esp(generated_entries: &Path, esp: &Path) -> Result<()> {
-    for entry in walkdir::WalkDir::new(generated_entries) {
+/// The CRC-32C of the file at `path` (or of it without what signing it changes, if `unsigned`; see
+/// [`secure_boot::without_signature`]), read a buffer at a time.
+fn crc32c(path: &Path, unsigned: bool) -> Result<u32> {
+    let mut digest = CASTAGNOLI.digest();
+    if unsigned {
+        secure_boot::for_each_unsigned_chunk(path, |chunk| digest.update(chunk))?;
+    } else {
+        let file = File::open(path).with_path_context(path)?;
+        stream::for_each_chunk(file, |chunk| digest.update(chunk)).with_path_context(path)?;
+    }
+
+    Ok(digest.finalize())
+}
+
+/// Whether `path` is a boot loader entry, i.e. `loader/entries/*.conf`.
+fn is_entry(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("conf"))
+        && path.parent().and_then(Path::file_name) == Some(OsStr::new("entries"))
+}
+
+/// Copies everything in `generated_entries` to where `layout` puts it with `copy` (which tests make
+/// slow, or fail), `jobs` files at a time, adding what was copied to `copied` (even if a copy
+/// fails).
+///
+/// The kernels, initrds, and unified EFI files are all copied before anything in `loader/` is, so
+/// that an entry never refers to a file that isn't there yet (if e.g. the machine loses power, or a
+/// copy fails).
+fn copy_to_esp(
+    generated_entries: &Path,
+    layout: Layout,
+    jobs: usize,
+    copy: &(dyn Fn(&Path, &Path) -> Result<()> + Sync),
+    copied: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let mut payloads = Vec::new();
+    let mut loader = Vec::new();
+
+    for entry in walkdir::WalkDir::new(generated_entries).sort_by_file_name() {
         let entry = entry?;
         let path = entry.path();
 
@@ -382,17 +1183,90 @@ fn copy_to_esp(generated_entries: &Path, esp: &Path) -> Result<()> {
         }
 
         let stripped = path.strip_prefix(generated_entries)?;
-        let dest = esp.join(stripped);
+        let dest = layout.dest(stripped);
+
+        if stripped.starts_with("loader") {
+            loader.push((path.to_path_buf(), dest));
+        } else {
+            payloads.push((path.to_path_buf(), dest));
+        }
+    }
 
-        trace!("copying file {} to {}", path.display(), dest.display());
-        util::atomic_tmp_copy_file(path, dest.as_path())?;
+    let total = payloads.len() + loader.len();
+    let progress = AtomicUsize::new(0);
+    for files in [payloads, loader] {
+        self::copy_files(&files, jobs, copy, &progress, total, copied)?;
     }
 
     Ok(())
 }
 
-fn syncfs(esp: &Path) -> Result<()> {
-    let f = File::open(&esp)?;
+/// Copies each of `files` (as source and destination) with `copy` on up to `jobs` threads. The first
+/// copy that fails stops the others from starting any more; the destinations that were copied are
+/// added to `copied` all the same.
+fn copy_files(
+    files: &[(PathBuf, PathBuf)],
+    jobs: usize,
+    copy: &(dyn Fn(&Path, &Path) -> Result<()> + Sync),
+    progress: &AtomicUsize,
+    total: usize,
+    copied: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let cancelled = AtomicBool::new(false);
+    let done = Mutex::new(vec![false; files.len()]);
+    let failure = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                while !cancelled.load(Ordering::SeqCst) {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let (src, dest) = match files.get(i) {
+                        Some(file) => file,
+                        None => break,
+                    };
+
+                    trace!("copying file {} to {}", src.display(), dest.display());
+                    match copy(src, dest) {
+                        Ok(()) => {
+                            done.lock().unwrap()[i] = true;
+                            info!(
+                                "copied {} ({}/{})",
+                                dest.display(),
+                                progress.fetch_add(1, Ordering::SeqCst) + 1,
+                                total
+                            );
+                        }
+                        Err(e) => {
+                            cancelled.store(true, Ordering::SeqCst);
+                            // Don't leave a partial copy behind.
+                            let _ = fs::remove_file(dest.with_extension("tmp"));
+                            failure.lock().unwrap().get_or_insert(e);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let done = done.into_inner().unwrap();
+    copied.extend(
+        files
+            .iter()
+            .zip(done)
+            .filter(|(_, done)| *done)
+            .map(|((_, dest), _)| dest.clone()),
+    );
+
+    match failure.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub(super) fn syncfs(esp: &Path) -> Result<()> {
+    let f = File::open(esp).with_path_context(esp)?;
     let fd = f.as_raw_fd();
 
     // SAFETY: idk
@@ -414,6 +1288,13 @@ fn syncfs(esp: &Path) -> Result<()> {
 mod tests {
     use super::*;
     use std::ffi::OsString;
+    use std::os::unix::fs::PermissionsExt;
+
+    use generator::report::History;
+
+    use super::super::backup;
+    use super::super::history::{self, HISTORY};
+    use crate::report::{self, EspReport, RunReport};
 
     fn scaffold(
         install: bool,
@@ -425,21 +1306,59 @@ mod tests {
         let args = Args {
             toplevel: PathBuf::from("toplevel"),
             dry_run: false,
+            run: Default::default(),
+            facts: None,
+            write_next_boot: None,
             generated_entries: PathBuf::from("generated_entries"),
-            timeout: Some(1),
+            timeout: Some(Timeout::Seconds(1)),
+            default_entry: None,
             console_mode: String::from("max"),
             configuration_limit: Some(1),
             editor: false,
-            verbosity: 0,
+            command_timeout: crate::command::DEFAULT_TIMEOUT,
+            wait_for_profiles: None,
+            passthrough_env: vec![],
             install,
             esp: vec![PathBuf::from("esp")],
+            xbootldr: None,
+            ignore_dirty_esp: false,
+            allow_downgrade_management: false,
+            ignore_esp_drift: false,
+            adopt_existing_entries: false,
+            overwrite_fallback_loader: false,
+            machine_id_file: PathBuf::from("/etc/machine-id"),
+            entry_path_prefix: None,
+            slot: None,
+            phase: Phase::All,
+            copy_jobs: 4,
+            max_esp_files: None,
+            max_esp_bytes: None,
             can_touch_efi_vars: false,
+            systemd_boot_timeout: None,
             bootctl: Some(PathBuf::from("bootctl")),
+            no_bootctl: false,
+            chainload: vec![],
+            retire_profile: vec![],
+            pin_generation: vec![],
+            assume_no_secrets_for: vec![],
+            credential: vec![],
+            credential_scope: CredentialScope::Global,
+            sign_chainload: false,
+            stage_oneshot: false,
+            promote_staged: false,
+            success_marker: None,
             unified_efi: false,
             signing_key,
             signing_cert,
+            signing_rule: vec![],
             sbsign,
             sbverify,
+            verify_cert_enrolled: false,
+            hook: vec![],
+            dry_run_hooks: false,
+            backup_dir: None,
+            sbattach: None,
+            command: None,
         };
         let system_generations = vec![
             Generation {
@@ -463,8 +1382,6 @@ mod tests {
                 ],
             },
         ];
-        let wanted_generations =
-            util::wanted_generations(system_generations, args.configuration_limit);
         let default_generation = Generation {
             idx: 2,
             profile: None,
@@ -475,12 +1392,18 @@ mod tests {
                 OsString::from("abcd-initrd-linux-5.12.9-initrd.efi"),
             ],
         };
+        let wanted_generations = util::wanted_generations(
+            system_generations,
+            args.configuration_limit,
+            &default_generation,
+        );
         let identified_files = IdentifiedFiles {
             to_sign: vec![
                 PathBuf::from("abcd-linux-5.12.9-bzImage.efi"),
                 PathBuf::from("abcd-initrd-linux-5.12.9-initrd.efi"),
             ],
             to_replace: vec![],
+            to_add: vec![],
         };
 
         (
@@ -499,12 +1422,17 @@ mod tests {
         let esp = &args.esp[0];
         let plan_args = PlanArgs {
             args: &args,
-            bootctl,
+            options: super::super::options(&args),
+            bootctl: Some(bootctl),
             esp,
             wanted_generations: &wanted_generations,
+            profile_generations: &[],
             default_generation: &default_generation,
             identified_files,
             signing_info: &None,
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune: true,
         };
 
         let plan = create_plan(plan_args).unwrap();
@@ -517,22 +1445,37 @@ mod tests {
                 SystemdBootPlanState::Update { bootctl, esp },
                 SystemdBootPlanState::PruneFiles {
                     wanted_generations: &wanted_generations,
-                    paths: vec![&args.generated_entries, esp],
+                    profile_generations: &[],
+                    chainloads: &[],
+                    retired_profiles: &[],
+                    generated_entries: &args.generated_entries,
+                    esp,
+                    xbootldr: None,
+                    efi_dir: PathBuf::from("EFI/nixos"),
+                    slot: None,
                 },
-                SystemdBootPlanState::ReplaceFiles {
-                    signing_info: &None,
-                    to_replace: vec![],
+                SystemdBootPlanState::GateSortKeys {
+                    bootctl: Some(bootctl),
+                    entries: args.generated_entries.join("loader/entries"),
                 },
                 SystemdBootPlanState::WriteLoader {
                     path: args.generated_entries.join("loader/loader.conf"),
                     timeout: args.timeout,
                     index: default_generation.idx,
+                    default_entry: None,
                     editor: args.editor,
                     console_mode: &args.console_mode,
                 },
+                SystemdBootPlanState::ReplaceFiles {
+                    signing_info: &None,
+                    generated_entries: &args.generated_entries,
+                    to_replace: vec![],
+                },
                 SystemdBootPlanState::CopyToEsp {
                     generated_entries: &args.generated_entries,
                     esp,
+                    xbootldr: None,
+                    jobs: 4,
                 },
                 SystemdBootPlanState::Syncfs { esp },
                 SystemdBootPlanState::End
@@ -540,6 +1483,265 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_plan_for_newer_esp() {
+        let (args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        let plan_args = |prune| PlanArgs {
+            args: &args,
+            options: super::super::options(&args),
+            bootctl: args.bootctl.as_deref(),
+            esp: &args.esp[0],
+            wanted_generations: &wanted_generations,
+            profile_generations: &[],
+            default_generation: &default_generation,
+            identified_files: identified_files.clone(),
+            signing_info: &None,
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune,
+        };
+
+        let pruning = create_plan(plan_args(true)).unwrap();
+        let additive = create_plan(plan_args(false)).unwrap();
+
+        // a newer release's files are only added to, and its loader.conf is left alone
+        assert_eq!(
+            additive,
+            pruning
+                .into_iter()
+                .filter(|state| !matches!(
+                    state,
+                    SystemdBootPlanState::PruneFiles { .. }
+                        | SystemdBootPlanState::WriteLoader { .. }
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert!(additive
+            .iter()
+            .any(|state| matches!(state, SystemdBootPlanState::CopyToEsp { .. })));
+    }
+
+    #[test]
+    fn test_phase_plans() {
+        let signing_info = SigningInfo {
+            signing_key: PathBuf::from("db.key"),
+            signing_cert: PathBuf::from("db.crt"),
+            rules: Vec::new(),
+            sbsign: PathBuf::from("sbsign"),
+            sbverify: PathBuf::from("sbverify"),
+            sbattach: PathBuf::from("sbattach"),
+        };
+        let (mut args, wanted_generations, default_generation, identified_files) = scaffold(
+            false,
+            Some(signing_info.signing_key.clone()),
+            Some(signing_info.signing_cert.clone()),
+            Some(signing_info.sbsign.clone()),
+            Some(signing_info.sbverify.clone()),
+        );
+        let plan = |args: &Args, signing_info: &Option<SigningInfo>, prune| {
+            create_plan(PlanArgs {
+                args,
+                options: super::super::options(args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune,
+            })
+            .map(|plan| {
+                let names = plan.iter().map(|state| state.name()).collect::<Vec<_>>();
+                let to_sign = plan.into_iter().find_map(|state| match state {
+                    SystemdBootPlanState::SignFiles { to_sign, .. } => Some(
+                        to_sign
+                            .into_iter()
+                            .map(|file| file.file)
+                            .collect::<Vec<_>>(),
+                    ),
+                    _ => None,
+                });
+                (names, to_sign)
+            })
+        };
+        let signing = Some(signing_info.clone());
+        let esp = args.esp[0].clone();
+        let loader = vec![
+            esp.join("EFI/systemd/systemd-bootx64.efi"),
+            esp.join("EFI/BOOT/BOOTX64.EFI"),
+        ];
+        let mut everything = loader.clone();
+        everything.extend(identified_files.to_sign.clone());
+
+        for (phase, names, to_sign) in [
+            (
+                Phase::All,
+                &[
+                    "start",
+                    "update",
+                    "prune_files",
+                    "gate_sort_keys",
+                    "write_loader",
+                    "replace_files",
+                    "sign_files",
+                    "copy_to_esp",
+                    "syncfs",
+                    "end",
+                ][..],
+                Some(&everything),
+            ),
+            (
+                Phase::Prune,
+                &["start", "prune_files", "syncfs", "end"][..],
+                None,
+            ),
+            // copying only signed files, into a finished staging tree
+            (
+                Phase::Copy,
+                &[
+                    "start",
+                    "gate_sort_keys",
+                    "write_loader",
+                    "replace_files",
+                    "sign_files",
+                    "copy_to_esp",
+                    "syncfs",
+                    "end",
+                ][..],
+                Some(&everything),
+            ),
+            (
+                Phase::Sign,
+                &["start", "replace_files", "sign_files", "syncfs", "end"][..],
+                Some(&everything),
+            ),
+            (
+                Phase::Loader,
+                &["start", "update", "sign_files", "syncfs", "end"][..],
+                Some(&loader),
+            ),
+        ] {
+            args.phase = phase;
+            let (planned, signed) = plan(&args, &signing, true).unwrap();
+            assert_eq!(planned, names, "{:?}", phase);
+            assert_eq!(signed.as_ref(), to_sign, "{:?}", phase);
+        }
+
+        // without signing, the phases that sign just don't
+        args.phase = Phase::Copy;
+        let (planned, _) = plan(&args, &None, true).unwrap();
+        assert!(!planned.contains(&"sign_files"));
+        args.phase = Phase::Loader;
+        assert_eq!(
+            plan(&args, &None, true).unwrap().0,
+            ["start", "update", "syncfs", "end"]
+        );
+
+        // but signing on its own needs a key, and pruning on its own needs to be allowed to prune
+        args.phase = Phase::Sign;
+        assert!(plan(&args, &None, true).is_err());
+        args.phase = Phase::Prune;
+        assert!(plan(&args, &signing, false).is_err());
+        // and only the loader phase installs systemd-boot, or the whole run stages a generation
+        args.install = true;
+        assert!(plan(&args, &signing, true).is_err());
+        args.phase = Phase::Loader;
+        assert_eq!(
+            plan(&args, &signing, true).unwrap().0,
+            ["start", "install", "sign_files", "syncfs", "end"]
+        );
+        args.install = false;
+        args.stage_oneshot = true;
+        assert!(plan(&args, &signing, true).is_err());
+        args.phase = Phase::All;
+        assert!(plan(&args, &signing, true).is_ok());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (mut args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        let hook = tempdir.path().join("hook");
+        fs::write(
+            &hook,
+            format!(
+                "#!/bin/sh\ncat > {}\necho \"$1 $2\"\n",
+                tempdir.path().join("stdin").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        args.hook = vec![hook.clone()];
+        args.generated_entries = tempdir.path().join("generated");
+        fs::create_dir_all(args.generated_entries.join("loader")).unwrap();
+        fs::write(args.generated_entries.join("loader/loader.conf"), "").unwrap();
+        let plan = |args: &Args| {
+            create_plan(PlanArgs {
+                args,
+                options: super::super::options(args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            })
+            .unwrap()
+        };
+
+        // between signing and copying, knowing the whole plan
+        let planned = plan(&args);
+        let names = planned.iter().map(|state| state.name()).collect::<Vec<_>>();
+        let copy = names
+            .iter()
+            .position(|name| *name == "copy_to_esp")
+            .unwrap();
+        assert_eq!(names[copy - 1], "run_hooks");
+        let run_hooks = planned.into_iter().nth(copy - 1).unwrap();
+        match &run_hooks {
+            SystemdBootPlanState::RunHooks { stages, .. } => assert_eq!(stages, &names),
+            state => panic!("{:?}", state),
+        }
+
+        let report = consume_plan(vec![run_hooks]).unwrap();
+        assert_eq!(
+            report.hooks,
+            [HookReport {
+                hook,
+                succeeded: true,
+                stdout: format!(
+                    "{} {}\n",
+                    args.generated_entries.display(),
+                    args.esp[0].display()
+                ),
+            }]
+        );
+        let payload: serde_json::Value =
+            serde_json::from_slice(&fs::read(tempdir.path().join("stdin")).unwrap()).unwrap();
+        assert_eq!(payload["dry_run"], false);
+        assert_eq!(payload["files"], serde_json::json!(["loader/loader.conf"]));
+
+        // only with the phases that copy
+        args.phase = Phase::Prune;
+        assert!(!plan(&args).iter().any(|state| state.name() == "run_hooks"));
+        args.phase = Phase::Copy;
+        assert!(plan(&args).iter().any(|state| state.name() == "run_hooks"));
+
+        // not at all without hooks
+        args.phase = Phase::All;
+        args.hook.clear();
+        assert!(!plan(&args).iter().any(|state| state.name() == "run_hooks"));
+    }
+
     #[test]
     fn test_install_plan() {
         let (args, wanted_generations, default_generation, identified_files) =
@@ -548,12 +1750,17 @@ mod tests {
         let esp = &args.esp[0];
         let plan_args = PlanArgs {
             args: &args,
-            bootctl,
+            options: super::super::options(&args),
+            bootctl: Some(bootctl),
             esp,
             wanted_generations: &wanted_generations,
+            profile_generations: &[],
             default_generation: &default_generation,
             identified_files,
             signing_info: &None,
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune: true,
         };
 
         let plan = create_plan(plan_args).unwrap();
@@ -568,25 +1775,41 @@ mod tests {
                     bootctl,
                     esp,
                     can_touch_efi_vars: args.can_touch_efi_vars,
+                    keep_fallback_loaders: vec![],
                 },
                 SystemdBootPlanState::PruneFiles {
                     wanted_generations: &wanted_generations,
-                    paths: vec![&args.generated_entries, esp],
+                    profile_generations: &[],
+                    chainloads: &[],
+                    retired_profiles: &[],
+                    generated_entries: &args.generated_entries,
+                    esp,
+                    xbootldr: None,
+                    efi_dir: PathBuf::from("EFI/nixos"),
+                    slot: None,
                 },
-                SystemdBootPlanState::ReplaceFiles {
-                    signing_info: &None,
-                    to_replace: vec![],
+                SystemdBootPlanState::GateSortKeys {
+                    bootctl: Some(bootctl),
+                    entries: args.generated_entries.join("loader/entries"),
                 },
                 SystemdBootPlanState::WriteLoader {
                     path: args.generated_entries.join("loader/loader.conf"),
                     timeout: args.timeout,
                     index: default_generation.idx,
+                    default_entry: None,
                     editor: args.editor,
                     console_mode: &args.console_mode,
                 },
+                SystemdBootPlanState::ReplaceFiles {
+                    signing_info: &None,
+                    generated_entries: &args.generated_entries,
+                    to_replace: vec![],
+                },
                 SystemdBootPlanState::CopyToEsp {
                     generated_entries: &args.generated_entries,
                     esp,
+                    xbootldr: None,
+                    jobs: 4,
                 },
                 SystemdBootPlanState::Syncfs { esp },
                 SystemdBootPlanState::End
@@ -594,6 +1817,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_golden_dry_run_plans() {
+        for (name, install) in [("update", false), ("install", true)] {
+            let (args, wanted_generations, default_generation, identified_files) =
+                scaffold(install, None, None, None, None);
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files,
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+
+            let plan = create_plan(plan_args).unwrap();
+
+            golden::assert_golden!(format!("plan/{}.txt", name), render_plan(&plan));
+        }
+    }
+
     #[test]
     fn test_sign_plan() {
         let signing_key = PathBuf::from("db.key");
@@ -603,8 +1852,10 @@ mod tests {
         let signing_info = SigningInfo {
             signing_key: signing_key.clone(),
             signing_cert: signing_cert.clone(),
+            rules: Vec::new(),
             sbsign: sbsign.clone(),
             sbverify: sbverify.clone(),
+            sbattach: PathBuf::from("sbattach"),
         };
 
         let (args, wanted_generations, default_generation, identified_files) = scaffold(
@@ -618,12 +1869,17 @@ mod tests {
         let esp = &args.esp[0];
         let plan_args = PlanArgs {
             args: &args,
-            bootctl,
+            options: super::super::options(&args),
+            bootctl: Some(bootctl),
             esp,
             wanted_generations: &wanted_generations,
+            profile_generations: &[],
             default_generation: &default_generation,
             identified_files: identified_files.clone(),
             signing_info: &Some(signing_info.clone()),
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune: true,
         };
 
         let plan = create_plan(plan_args).unwrap();
@@ -638,32 +1894,2122 @@ mod tests {
             vec![
                 SystemdBootPlanState::Start,
                 SystemdBootPlanState::Update { bootctl, esp },
-                SystemdBootPlanState::SignFiles {
-                    signing_info: &signing_info.clone(),
-                    to_sign
-                },
                 SystemdBootPlanState::PruneFiles {
                     wanted_generations: &wanted_generations,
-                    paths: vec![&args.generated_entries, esp],
+                    profile_generations: &[],
+                    chainloads: &[],
+                    retired_profiles: &[],
+                    generated_entries: &args.generated_entries,
+                    esp,
+                    xbootldr: None,
+                    efi_dir: PathBuf::from("EFI/nixos"),
+                    slot: None,
+                },
+                SystemdBootPlanState::GateSortKeys {
+                    bootctl: Some(bootctl),
+                    entries: args.generated_entries.join("loader/entries"),
+                },
+                SystemdBootPlanState::WriteLoader {
+                    path: args.generated_entries.join("loader/loader.conf"),
+                    timeout: args.timeout,
+                    index: default_generation.idx,
+                    default_entry: None,
+                    editor: args.editor,
+                    console_mode: &args.console_mode,
                 },
                 SystemdBootPlanState::ReplaceFiles {
-                    signing_info: &Some(signing_info),
+                    signing_info: &Some(signing_info.clone()),
+                    generated_entries: &args.generated_entries,
                     to_replace: vec![],
                 },
+                SystemdBootPlanState::SignFiles {
+                    signing_info: &signing_info,
+                    to_sign: with_default_pair(&signing_info, to_sign),
+                },
+                SystemdBootPlanState::CopyToEsp {
+                    generated_entries: &args.generated_entries,
+                    esp,
+                    xbootldr: None,
+                    jobs: 4,
+                },
+                SystemdBootPlanState::Syncfs { esp },
+                SystemdBootPlanState::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_bootctl_plan() {
+        let signing_info = SigningInfo {
+            signing_key: PathBuf::from("db.key"),
+            signing_cert: PathBuf::from("db.crt"),
+            rules: Vec::new(),
+            sbsign: PathBuf::from("sbsign"),
+            sbverify: PathBuf::from("sbverify"),
+            sbattach: PathBuf::from("sbattach"),
+        };
+        let (mut args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        args.bootctl = None;
+        args.no_bootctl = true;
+        let esp = &args.esp[0];
+        let plan_args = PlanArgs {
+            args: &args,
+            options: super::super::options(&args),
+            bootctl: None,
+            esp,
+            wanted_generations: &wanted_generations,
+            profile_generations: &[],
+            default_generation: &default_generation,
+            identified_files: identified_files.clone(),
+            signing_info: &Some(signing_info.clone()),
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune: true,
+        };
+
+        let plan = create_plan(plan_args).unwrap();
+
+        // Only the generated files get signed, not systemd-boot itself
+        assert_eq!(
+            plan,
+            vec![
+                SystemdBootPlanState::Start,
+                SystemdBootPlanState::PruneFiles {
+                    wanted_generations: &wanted_generations,
+                    profile_generations: &[],
+                    chainloads: &[],
+                    retired_profiles: &[],
+                    generated_entries: &args.generated_entries,
+                    esp,
+                    xbootldr: None,
+                    efi_dir: PathBuf::from("EFI/nixos"),
+                    slot: None,
+                },
+                SystemdBootPlanState::GateSortKeys {
+                    bootctl: None,
+                    entries: args.generated_entries.join("loader/entries"),
+                },
                 SystemdBootPlanState::WriteLoader {
                     path: args.generated_entries.join("loader/loader.conf"),
                     timeout: args.timeout,
                     index: default_generation.idx,
+                    default_entry: None,
                     editor: args.editor,
                     console_mode: &args.console_mode,
                 },
+                SystemdBootPlanState::ReplaceFiles {
+                    signing_info: &Some(signing_info.clone()),
+                    generated_entries: &args.generated_entries,
+                    to_replace: vec![],
+                },
+                SystemdBootPlanState::SignFiles {
+                    signing_info: &signing_info,
+                    to_sign: identified_files.to_sign,
+                },
                 SystemdBootPlanState::CopyToEsp {
                     generated_entries: &args.generated_entries,
                     esp,
+                    xbootldr: None,
+                    jobs: 4,
                 },
                 SystemdBootPlanState::Syncfs { esp },
                 SystemdBootPlanState::End
             ]
         );
     }
+
+    #[test]
+    fn test_no_bootctl_conflicts() {
+        for (install, can_touch_efi_vars) in [(true, false), (false, true), (true, true)] {
+            let (mut args, wanted_generations, default_generation, identified_files) =
+                scaffold(install, None, None, None, None);
+            args.no_bootctl = true;
+            args.can_touch_efi_vars = can_touch_efi_vars;
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files,
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+
+            assert!(create_plan(plan_args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_missing_bootctl() {
+        let (args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        let plan_args = PlanArgs {
+            args: &args,
+            options: super::super::options(&args),
+            bootctl: None,
+            esp: &args.esp[0],
+            wanted_generations: &wanted_generations,
+            profile_generations: &[],
+            default_generation: &default_generation,
+            identified_files,
+            signing_info: &None,
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune: true,
+        };
+
+        assert!(create_plan(plan_args).is_err());
+    }
+
+    #[test]
+    fn test_configuration_limit_keeps_default() {
+        let (mut args, _, _, identified_files) = scaffold(false, None, None, None, None);
+        let system_generations = (1..=3)
+            .map(|idx| Generation {
+                idx,
+                profile: None,
+                path: PathBuf::from(idx.to_string()),
+                required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+            })
+            .collect::<Vec<_>>();
+        // roll back to the oldest generation
+        let default_generation = system_generations[0].clone();
+
+        for (limit, expected) in [
+            (Some(1), vec![1, 3]),
+            (Some(2), vec![1, 2, 3]),
+            (Some(50), vec![1, 2, 3]),
+        ] {
+            args.configuration_limit = limit;
+            let wanted_generations = util::wanted_generations(
+                system_generations.clone(),
+                args.configuration_limit,
+                &default_generation,
+            );
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+
+            let plan = create_plan(plan_args).unwrap();
+            let pruned_against = plan
+                .iter()
+                .find_map(|state| match state {
+                    SystemdBootPlanState::PruneFiles {
+                        wanted_generations, ..
+                    } => Some(wanted_generations.iter().map(|g| g.idx).collect::<Vec<_>>()),
+                    _ => None,
+                })
+                .unwrap();
+
+            assert_eq!(pruned_against, expected);
+            assert!(plan.contains(&SystemdBootPlanState::WriteLoader {
+                path: args.generated_entries.join("loader/loader.conf"),
+                timeout: args.timeout,
+                index: 1,
+                default_entry: None,
+                editor: args.editor,
+                console_mode: &args.console_mode,
+            }));
+        }
+    }
+
+    #[test]
+    fn test_esp_budget() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (mut args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        args.generated_entries = staging_tree(tempdir.path());
+        args.esp = vec![tempdir.path().join("esp")];
+        // generation 2's entry is already on the ESP
+        let esp_entry = args.esp[0].join("loader/entries/nixos-generation-2.conf");
+        util::create_dirs_to_file(&esp_entry).unwrap();
+        fs::write(&esp_entry, "2").unwrap();
+        let plan = |args: &Args| {
+            let plan_args = PlanArgs {
+                args,
+                options: super::super::options(args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+
+            create_plan(plan_args).map(|_| ())
+        };
+
+        // generation 1's entry is pruned, which leaves 7 files
+        args.max_esp_files = Some(3);
+        let err = plan(&args).unwrap_err().to_string();
+        assert!(err.contains("--max-esp-files 3"), "{}", err);
+        assert!(err.contains("\n  shared files: 7 file(s)"), "{}", err);
+        assert!(err.contains("not even the newest generation"), "{}", err);
+
+        args.max_esp_files = Some(7);
+        plan(&args).unwrap();
+        args.max_esp_bytes = Some("6B".parse().unwrap());
+        let err = plan(&args).unwrap_err().to_string();
+        assert!(err.contains("7 file(s), 7 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_second_run_is_noop() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let generated_entries = tempdir.path().join("generated_entries");
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+
+        let wanted_generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            path: PathBuf::from("1"),
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("abcd-linux-5.12.9-bzImage.efi"),
+                OsString::from("abcd-initrd-linux-5.12.9-initrd.efi"),
+            ],
+        }];
+        // what the generator would write
+        let generate = || {
+            fs::create_dir_all(generated_entries.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+            fs::write(
+                generated_entries.join("loader/entries/nixos-generation-1.conf"),
+                "title NixOS\n",
+            )
+            .unwrap();
+            fs::write(
+                generated_entries.join("EFI/nixos/abcd-linux-5.12.9-bzImage.efi"),
+                "kernel",
+            )
+            .unwrap();
+            fs::write(
+                generated_entries.join("EFI/nixos/abcd-initrd-linux-5.12.9-initrd.efi"),
+                "initrd",
+            )
+            .unwrap();
+        };
+        let run = || {
+            generate();
+
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.generated_entries = generated_entries.clone();
+            args.esp = vec![esp.clone()];
+            args.bootctl = None;
+            args.no_bootctl = true;
+            args.chainload = vec!["windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi"
+                .parse()
+                .unwrap()];
+
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp: &esp,
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &wanted_generations[0],
+                identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(wanted_generations[0].idx),
+                prune: true,
+            };
+            let plan = create_plan(plan_args).unwrap();
+
+            consume_plan(plan).unwrap()
+        };
+
+        let first = run();
+        assert_eq!(first.copied.len(), 5);
+        assert!(esp.join("loader/loader.conf").exists());
+        assert!(esp.join("loader/entries/nixos-generation-1.conf").exists());
+        assert!(esp
+            .join("loader/entries/nixos-chainload-windows.conf")
+            .exists());
+        // the chainloaded entry is in the menu, but sd-boot still boots the generation
+        let model = sd_boot_model::SdBootModel::read(&esp, None, "x64").unwrap();
+        assert_eq!(model.entries.len(), 2);
+        assert_eq!(
+            model.default_entry().map(|e| e.id.as_str()),
+            Some("nixos-generation-1.conf")
+        );
+
+        let second = run();
+        assert_eq!(second, PlanReport::default());
+    }
+
+    #[test]
+    fn test_default_entry_follows_profile() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let generated_entries = tempdir.path().join("generated_entries");
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+
+        // the system profile and the work profile both have a generation 1
+        let generations = [None, Some("work")]
+            .iter()
+            .map(|profile| Generation {
+                idx: 1,
+                profile: profile.map(ToString::to_string),
+                path: PathBuf::from(format!("{}-1", profile.unwrap_or("system"))),
+                required_filenames: vec![OsString::from(generator::systemd_boot::entry_name(
+                    *profile, 1, None,
+                ))],
+            })
+            .collect::<Vec<_>>();
+        let run = |default_generation: &Generation| {
+            // what the generator would write, which doesn't depend on the default
+            fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+            for generation in &generations {
+                let name = &generation.required_filenames[0];
+                fs::write(
+                    generated_entries.join("loader/entries").join(name),
+                    format!("title {:?}\n", generation.profile),
+                )
+                .unwrap();
+            }
+
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.generated_entries = generated_entries.clone();
+            args.esp = vec![esp.clone()];
+            args.bootctl = None;
+            args.no_bootctl = true;
+            args.configuration_limit = None;
+
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp: &esp,
+                wanted_generations: &generations,
+                profile_generations: &[],
+                default_generation,
+                identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+            let plan = create_plan(plan_args).unwrap();
+
+            consume_plan(plan).unwrap()
+        };
+        let loader_conf = || fs::read_to_string(esp.join("loader/loader.conf")).unwrap();
+        let default = || {
+            sd_boot_model::SdBootModel::read(&esp, None, "x64")
+                .unwrap()
+                .default_entry()
+                .map(|entry| entry.id.clone())
+        };
+
+        run(&generations[0]);
+        assert!(loader_conf().contains("default nixos-generation-1.conf\n"));
+        assert_eq!(default().as_deref(), Some("nixos-generation-1.conf"));
+
+        // Only the default changes: the entries are identical, so they're not copied again, but
+        // loader.conf names the work profile's, which is still on the ESP.
+        let second = run(&generations[1]);
+        assert_eq!(second.copied, [esp.join("loader/loader.conf")]);
+        assert!(loader_conf().contains("default nixos-work-generation-1.conf\n"));
+        assert!(esp
+            .join("loader/entries/nixos-work-generation-1.conf")
+            .is_file());
+        assert_eq!(default().as_deref(), Some("nixos-work-generation-1.conf"));
+
+        run(&generations[0]);
+        assert!(loader_conf().contains("default nixos-generation-1.conf\n"));
+        assert_eq!(default().as_deref(), Some("nixos-generation-1.conf"));
+    }
+
+    #[test]
+    fn test_slots_are_isolated() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+
+        let run = |slot: Slot, idx: usize| {
+            // what the generator would write
+            let generated_entries =
+                tempdir
+                    .path()
+                    .join(format!("generated-{}-{}", slot.name(), idx));
+            fs::create_dir_all(generated_entries.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+            fs::write(
+                generated_entries.join(format!("loader/entries/nixos-generation-{}.conf", idx)),
+                format!("title NixOS\nlinux /EFI/nixos/kernel-{}.efi\n", idx),
+            )
+            .unwrap();
+            fs::write(
+                generated_entries.join(format!("EFI/nixos/kernel-{}.efi", idx)),
+                format!("kernel {} {}", slot.name(), idx),
+            )
+            .unwrap();
+            slot::namespace(&generated_entries, slot, None).unwrap();
+
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.generated_entries = generated_entries.clone();
+            args.esp = vec![esp.clone()];
+            args.bootctl = None;
+            args.no_bootctl = true;
+            args.slot = Some(slot);
+
+            let wanted_generations = vec![Generation {
+                idx,
+                profile: None,
+                path: PathBuf::from(idx.to_string()),
+                required_filenames: vec![
+                    OsString::from(format!("nixos-generation-{}.conf", idx)),
+                    OsString::from(format!("kernel-{}.efi", idx)),
+                ],
+            }];
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp: &esp,
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &wanted_generations[0],
+                identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(idx),
+                prune: true,
+            };
+            let plan = create_plan(plan_args).unwrap();
+
+            consume_plan(plan).unwrap()
+        };
+        let loader_conf = || fs::read_to_string(esp.join("loader/loader.conf")).unwrap();
+        let default = || {
+            sd_boot_model::SdBootModel::read(&esp, None, "x64")
+                .unwrap()
+                .default_entry()
+                .map(|entry| entry.id.clone())
+        };
+        let slot_b = [
+            "loader/entries/nixos-b-generation-1.conf",
+            "EFI/nixos-b/kernel-1.efi",
+        ];
+
+        // the first slot installed to boots, until another one is activated
+        run(Slot::A, 1);
+        run(Slot::B, 1);
+        let second = run(Slot::A, 2);
+        assert!(loader_conf().contains("default nixos-a-generation-*\n"));
+        assert_eq!(default().as_deref(), Some("nixos-a-generation-2.conf"));
+        assert!(!esp.join("EFI/nixos").exists());
+        // only slot a's files were touched
+        assert_eq!(
+            second.pruned,
+            vec![
+                esp.join("loader/entries/nixos-a-generation-1.conf"),
+                esp.join("EFI/nixos-a/kernel-1.efi"),
+            ]
+        );
+        assert!(second
+            .copied
+            .iter()
+            .all(|path| !path.starts_with(esp.join("EFI/nixos-b"))));
+        assert_eq!(
+            fs::read_to_string(esp.join("EFI/nixos-a/kernel-2.efi")).unwrap(),
+            "kernel a 2"
+        );
+        for file in slot_b {
+            assert!(esp.join(file).exists(), "{}", file);
+        }
+
+        super::super::activate_slot(&[esp.clone()], None, Slot::B).unwrap();
+        assert_eq!(default().as_deref(), Some("nixos-b-generation-1.conf"));
+
+        // installing to slot a doesn't make it boot again
+        run(Slot::A, 3);
+        assert!(loader_conf().contains("default nixos-b-generation-*\n"));
+        assert_eq!(default().as_deref(), Some("nixos-b-generation-1.conf"));
+        assert_eq!(
+            fs::read_to_string(esp.join("EFI/nixos-b/kernel-1.efi")).unwrap(),
+            "kernel b 1"
+        );
+        for file in slot_b {
+            assert!(esp.join(file).exists(), "{}", file);
+        }
+        assert!(!esp.join("EFI/nixos-a/kernel-2.efi").exists());
+    }
+
+    #[test]
+    fn test_esp_drift() {
+        const KERNEL: &str = "EFI/nixos/abcd-linux-5.12.9-bzImage.efi";
+        const INITRD: &str = "EFI/nixos/abcd-initrd-linux-5.12.9-initrd.efi";
+        const ENTRY: &str = "loader/entries/nixos-generation-1.conf";
+
+        fn plan<'a>(args: &'a Args, wanted_generations: &'a [Generation]) -> SystemdBootPlan<'a> {
+            let esp = &args.esp[0];
+            let generated_entries = &args.generated_entries;
+            for dir in [esp, generated_entries] {
+                fs::create_dir_all(dir.join("EFI/nixos")).unwrap();
+                fs::create_dir_all(dir.join("loader/entries")).unwrap();
+            }
+            // the kernel is already on the ESP (so it's skipped), the initrd is new
+            fs::write(esp.join(KERNEL), "kernel").unwrap();
+            fs::write(generated_entries.join(KERNEL), "kernel").unwrap();
+            fs::write(generated_entries.join(INITRD), "initrd").unwrap();
+            fs::write(generated_entries.join(ENTRY), "title NixOS\n").unwrap();
+            let _ = fs::remove_file(esp.join(INITRD));
+
+            create_plan(PlanArgs {
+                args,
+                options: super::super::options(args),
+                bootctl: None,
+                esp,
+                wanted_generations,
+                profile_generations: &[],
+                default_generation: &wanted_generations[0],
+                identified_files: IdentifiedFiles::new(generated_entries, Layout::new(esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(wanted_generations[0].idx),
+                prune: true,
+            })
+            .unwrap()
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let generated_entries = tempdir.path().join("generated_entries");
+        let wanted_generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            path: PathBuf::from("1"),
+            required_filenames: vec![
+                OsString::from("abcd-linux-5.12.9-bzImage.efi"),
+                OsString::from("abcd-initrd-linux-5.12.9-initrd.efi"),
+            ],
+        }];
+        let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+        args.generated_entries = generated_entries.clone();
+        args.esp = vec![esp.clone()];
+        args.bootctl = None;
+        args.no_bootctl = true;
+
+        // another tool replaces the file the plan decided to skip, and adds the one it'd add
+        let drifted = plan(&args, &wanted_generations);
+        fs::write(esp.join(KERNEL), "another kernel").unwrap();
+        fs::write(esp.join(INITRD), "another initrd").unwrap();
+        let err = consume_plan(drifted).unwrap_err().to_string();
+        assert!(err.contains("ESP changed since planning"), "{}", err);
+        assert!(err.contains(KERNEL) && err.contains(INITRD), "{}", err);
+        // nothing was done
+        assert_eq!(fs::read(esp.join(KERNEL)).unwrap(), b"another kernel");
+        assert!(generated_entries.join(KERNEL).exists());
+
+        // an unchanged ESP goes ahead
+        let unchanged = plan(&args, &wanted_generations);
+        consume_plan(unchanged).unwrap();
+        assert_eq!(fs::read(esp.join(INITRD)).unwrap(), b"initrd");
+
+        args.ignore_esp_drift = true;
+        let drifted = plan(&args, &wanted_generations);
+        fs::write(esp.join(INITRD), "another initrd").unwrap();
+        consume_plan(drifted).unwrap();
+        assert_eq!(fs::read(esp.join(INITRD)).unwrap(), b"initrd");
+    }
+
+    #[test]
+    fn test_foreign_fallback_loader() {
+        let signing_info = SigningInfo {
+            signing_key: PathBuf::from("db.key"),
+            signing_cert: PathBuf::from("db.crt"),
+            rules: Vec::new(),
+            sbsign: PathBuf::from("sbsign"),
+            sbverify: PathBuf::from("sbverify"),
+            sbattach: PathBuf::from("sbattach"),
+        };
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().to_path_buf();
+        let fallback = esp.join("EFI/BOOT/BOOTX64.EFI");
+        fs::create_dir_all(fallback.parent().unwrap()).unwrap();
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/fallback-loaders/refind_x64.efi"),
+            &fallback,
+        )
+        .unwrap();
+
+        let (mut args, wanted_generations, default_generation, identified_files) =
+            scaffold(true, None, None, None, None);
+        args.esp = vec![esp.clone()];
+        for overwrite in [false, true] {
+            args.overwrite_fallback_loader = overwrite;
+            let plan = create_plan(PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &esp,
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info: &Some(signing_info.clone()),
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            })
+            .unwrap();
+
+            for state in &plan {
+                match state {
+                    SystemdBootPlanState::Install {
+                        keep_fallback_loaders,
+                        ..
+                    } => {
+                        let kept: &[PathBuf] = if overwrite { &[] } else { &[fallback.clone()] };
+                        assert_eq!(keep_fallback_loaders, kept);
+                    }
+                    SystemdBootPlanState::SignFiles { to_sign, .. } => {
+                        assert_eq!(to_sign.iter().any(|f| f.file == fallback), overwrite);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_machine_id_placeholder() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let generated_entries = tempdir.path().join("generated_entries");
+        let machine_id_file = tempdir.path().join("machine-id");
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::write(&machine_id_file, "0123456789abcdef0123456789abcdef\n").unwrap();
+
+        let wanted_generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            path: PathBuf::from("1"),
+            required_filenames: vec![OsString::from("nixos-generation-1.conf")],
+        }];
+        let entry = |machine_id: &str| format!("title NixOS\nmachine-id {}\n", machine_id);
+        let run = || {
+            // what the generator would write with --machine-id-placeholder
+            fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+            fs::write(
+                generated_entries.join("loader/entries/nixos-generation-1.conf"),
+                entry("@MACHINE_ID@"),
+            )
+            .unwrap();
+
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.generated_entries = generated_entries.clone();
+            args.esp = vec![esp.clone()];
+            args.bootctl = None;
+            args.no_bootctl = true;
+            args.machine_id_file = machine_id_file.clone();
+
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp: &esp,
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &wanted_generations[0],
+                identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(wanted_generations[0].idx),
+                prune: true,
+            };
+            let plan = create_plan(plan_args).unwrap();
+            assert_eq!(
+                plan[3],
+                SystemdBootPlanState::SubstituteMachineId {
+                    entries: generated_entries.join("loader/entries"),
+                    machine_id_file: &machine_id_file,
+                }
+            );
+
+            consume_plan(plan).unwrap()
+        };
+
+        let first = run();
+        assert_eq!(first.copied.len(), 2);
+        assert_eq!(
+            fs::read_to_string(esp.join("loader/entries/nixos-generation-1.conf")).unwrap(),
+            entry("0123456789abcdef0123456789abcdef")
+        );
+
+        // the entry is compared to the ESP's with the machine-id substituted
+        let second = run();
+        assert_eq!(second, PlanReport::default());
+
+        // no machine-id to substitute
+        fs::write(&machine_id_file, "uninitialized\n").unwrap();
+        fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+        fs::write(
+            generated_entries.join("loader/entries/nixos-generation-1.conf"),
+            entry("@MACHINE_ID@"),
+        )
+        .unwrap();
+        let err = consume_plan(vec![SystemdBootPlanState::SubstituteMachineId {
+            entries: generated_entries.join("loader/entries"),
+            machine_id_file: &machine_id_file,
+        }])
+        .unwrap_err();
+        assert!(err.to_string().contains("machine-id"));
+    }
+
+    #[test]
+    fn test_failed_run_report() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        let generated_entries = tempdir.path().join("generated_entries");
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::create_dir_all(generated_entries.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+        fs::write(
+            generated_entries.join("loader/entries/nixos-generation-1.conf"),
+            "title NixOS\n",
+        )
+        .unwrap();
+        fs::write(
+            generated_entries.join("EFI/nixos/abcd-linux-5.12.9-bzImage.efi"),
+            "kernel",
+        )
+        .unwrap();
+
+        let wanted_generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            path: PathBuf::from("1"),
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("abcd-linux-5.12.9-bzImage.efi"),
+            ],
+        }];
+        // e.g. the kernel was corrupted on its way to the ESP
+        let manifest = Some(Manifest {
+            files: vec![crate::manifest::ManifestFile {
+                path: String::from("EFI/nixos/abcd-linux-5.12.9-bzImage.efi"),
+                sha256: "0".repeat(64),
+                source: None,
+                sections: Default::default(),
+            }],
+            ..Default::default()
+        });
+
+        let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+        args.generated_entries = generated_entries.clone();
+        args.esp = vec![esp.clone()];
+        args.bootctl = None;
+        args.no_bootctl = true;
+
+        let plan = create_plan(PlanArgs {
+            args: &args,
+            options: super::super::options(&args),
+            bootctl: None,
+            esp: &esp,
+            wanted_generations: &wanted_generations,
+            profile_generations: &[],
+            default_generation: &wanted_generations[0],
+            identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                .unwrap(),
+            signing_info: &None,
+            manifest: &manifest,
+            staging: Staging::new(1),
+            prune: true,
+        })
+        .unwrap();
+
+        let mut esp_report = EspReport {
+            esp: esp.clone(),
+            ..Default::default()
+        };
+        let before = report::hash_tree(&esp).unwrap();
+        let mut plan_report = PlanReport::default();
+        let ctx = RunContext::default();
+        let ret = consume_plan_with(plan, &mut plan_report, &mut esp_report.stages, &ctx);
+        assert!(ret.is_err());
+        // the state that failed is the last one reached
+        assert_eq!(ctx.last_stage(), Some("verify_manifest"));
+        esp_report.record_files(
+            &before,
+            &report::hash_tree(&esp).unwrap(),
+            &Default::default(),
+        );
+
+        let mut run_report = RunReport::start(env!("CARGO_PKG_VERSION"));
+        run_report.esps.push(esp_report);
+        run_report.finish(&ret);
+        let path = tempdir.path().join("report.json");
+        run_report.write(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let error = json["error"].as_str().unwrap();
+        assert!(error.contains("doesn't match what the generator staged"));
+
+        let stages = json["esps"][0]["stages"].as_array().unwrap();
+        let outcomes = stages
+            .iter()
+            .map(|stage| {
+                (
+                    stage["stage"].as_str().unwrap(),
+                    stage["outcome"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("start", "succeeded"),
+                ("verify_esp", "succeeded"),
+                ("prune_files", "succeeded"),
+                ("gate_sort_keys", "succeeded"),
+                ("write_loader", "succeeded"),
+                ("replace_files", "succeeded"),
+                ("copy_to_esp", "succeeded"),
+                ("verify_manifest", "failed"),
+                ("syncfs", "skipped"),
+                ("end", "skipped"),
+            ]
+        );
+        assert_eq!(stages[6]["error"], error);
+        assert_eq!(stages[7]["error"], serde_json::Value::Null);
+
+        // what made it to the ESP before the failure is on record
+        assert_eq!(json["summary"]["added"], 3);
+        let kernel = json["esps"][0]["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|file| file["path"] == "EFI/nixos/abcd-linux-5.12.9-bzImage.efi")
+            .unwrap();
+        assert_eq!(kernel["change"], "added");
+        assert_eq!(kernel["sha256_before"], serde_json::Value::Null);
+        assert_eq!(
+            kernel["sha256_after"],
+            crate::manifest::sha256_file(&esp.join("EFI/nixos/abcd-linux-5.12.9-bzImage.efi"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_credentials_lifecycle() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let esp = dir.join("esp");
+        let generated_entries = dir.join("generated_entries");
+        let blob = dir.join("secret.cred");
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+
+        let wanted_generations = vec![Generation {
+            idx: 1,
+            profile: None,
+            path: PathBuf::from("1"),
+            required_filenames: vec![
+                OsString::from("nixos-generation-1.conf"),
+                OsString::from("abcd-linux-5.12.9-bzImage.efi"),
+            ],
+        }];
+        let run = |names: &[&str]| {
+            fs::create_dir_all(generated_entries.join("EFI/nixos")).unwrap();
+            fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+            fs::write(
+                generated_entries.join("loader/entries/nixos-generation-1.conf"),
+                "title NixOS\n",
+            )
+            .unwrap();
+            fs::write(
+                generated_entries.join("EFI/nixos/abcd-linux-5.12.9-bzImage.efi"),
+                "kernel",
+            )
+            .unwrap();
+
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.generated_entries = generated_entries.clone();
+            args.esp = vec![esp.clone()];
+            args.bootctl = None;
+            args.no_bootctl = true;
+            args.credential = names
+                .iter()
+                .map(|name| format!("{}={}", name, blob.display()).parse().unwrap())
+                .collect();
+
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp: &esp,
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &wanted_generations[0],
+                identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(wanted_generations[0].idx),
+                prune: true,
+            };
+            let plan = create_plan(plan_args).unwrap();
+            assert!(!format!("{:?}", plan).contains("dummy"));
+
+            let report = consume_plan(plan).unwrap();
+            history::record(
+                Layout::new(&esp, None),
+                &report,
+                &wanted_generations,
+                0,
+                None,
+            )
+            .unwrap();
+
+            report
+        };
+        let esp_credential = esp.join("loader/credentials/secret.cred");
+
+        fs::write(&blob, "dummy encrypted blob").unwrap();
+        let first = run(&["secret"]);
+        assert_eq!(first.copied.len(), 4);
+        assert!(first.copied.contains(&esp_credential));
+        assert_eq!(
+            fs::read_to_string(&esp_credential).unwrap(),
+            "dummy encrypted blob"
+        );
+        assert_eq!(
+            fs::metadata(&esp_credential).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // identical credentials aren't copied again
+        let second = run(&["secret"]);
+        assert_eq!(second, PlanReport::default());
+
+        fs::write(&blob, "dummy rotated blob").unwrap();
+        let third = run(&["secret"]);
+        assert_eq!(third.copied, vec![esp_credential.clone()]);
+        assert_eq!(
+            fs::read_to_string(&esp_credential).unwrap(),
+            "dummy rotated blob"
+        );
+
+        // a credential that isn't passed anymore is removed, but not what else is there, including
+        // the credentials the installer didn't write
+        let other = esp.join("loader/credentials/README");
+        fs::write(&other, "").unwrap();
+        let foreign = esp.join("loader/credentials/foreign.cred");
+        fs::write(&foreign, "someone else's blob").unwrap();
+        let renamed = run(&["renamed"]);
+        assert_eq!(renamed.pruned, vec![esp_credential.clone()]);
+        assert!(esp.join("loader/credentials/renamed.cred").exists());
+        let none = run(&[]);
+        assert_eq!(
+            none.pruned,
+            vec![esp.join("loader/credentials/renamed.cred")]
+        );
+        assert!(other.exists());
+        assert!(foreign.exists());
+        // without any credentials passed (or written before), there's nothing to prune
+        assert_eq!(run(&[]), PlanReport::default());
+        assert!(foreign.exists());
+    }
+
+    #[test]
+    fn test_backup_and_rollback() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let esp = dir.join("esp");
+        let generated_entries = dir.join("generated_entries");
+        let backup_dir = dir.join("backup");
+        let layout = Layout::new(&esp, None);
+        let write = |root: &Path, files: &[(&str, &str)]| {
+            for (file, contents) in files {
+                util::create_dirs_to_file(root.join(file)).unwrap();
+                fs::write(root.join(file), contents).unwrap();
+            }
+        };
+        let generation = |idx: usize, kernel: &str| Generation {
+            idx,
+            profile: None,
+            path: PathBuf::from(idx.to_string()),
+            required_filenames: vec![
+                OsString::from(format!("nixos-generation-{}.conf", idx)),
+                OsString::from(kernel),
+            ],
+        };
+
+        // what an earlier run left: generation 1, booted by default
+        write(
+            &esp,
+            &[
+                (
+                    "loader/loader.conf",
+                    "timeout 1\ndefault nixos-generation-1.conf\n",
+                ),
+                (
+                    "loader/entries/nixos-generation-1.conf",
+                    "title NixOS\nlinux /EFI/nixos/abcd-linux-5.12.9-bzImage.efi\n",
+                ),
+                ("EFI/nixos/abcd-linux-5.12.9-bzImage.efi", "kernel 1"),
+            ],
+        );
+        let earlier = PlanReport {
+            copied: report::hash_tree(&esp)
+                .unwrap()
+                .into_keys()
+                .map(|relative| esp.join(relative))
+                .collect(),
+            ..Default::default()
+        };
+        let old_generations = [generation(1, "abcd-linux-5.12.9-bzImage.efi")];
+        history::record(layout, &earlier, &old_generations, 100, None).unwrap();
+        let history_before = History::load(&esp.join(HISTORY)).unwrap();
+        let before = report::hash_tree(&esp).unwrap();
+
+        // generation 2, with a new kernel, which prunes generation 1
+        write(
+            &generated_entries,
+            &[
+                (
+                    "loader/entries/nixos-generation-2.conf",
+                    "title NixOS\nlinux /EFI/nixos/efgh-linux-6.1-bzImage.efi\n",
+                ),
+                ("EFI/nixos/efgh-linux-6.1-bzImage.efi", "kernel 2"),
+            ],
+        );
+        let wanted_generations = vec![generation(2, "efgh-linux-6.1-bzImage.efi")];
+        let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+        args.generated_entries = generated_entries.clone();
+        args.esp = vec![esp.clone()];
+        args.bootctl = None;
+        args.no_bootctl = true;
+        let plan = create_plan(PlanArgs {
+            args: &args,
+            options: super::super::options(&args),
+            bootctl: None,
+            esp: &esp,
+            wanted_generations: &wanted_generations,
+            profile_generations: &[],
+            default_generation: &wanted_generations[0],
+            identified_files: IdentifiedFiles::new(&generated_entries, layout).unwrap(),
+            signing_info: &None,
+            manifest: &None,
+            staging: Staging::new(2),
+            prune: true,
+        })
+        .unwrap();
+
+        let backup = backup::dir(&backup_dir, 200, 2, &esp);
+        backup::take(layout, &backup).unwrap();
+        let plan_report = consume_plan(plan).unwrap();
+        history::record(
+            layout,
+            &plan_report,
+            &wanted_generations,
+            200,
+            Some(backup.clone()),
+        )
+        .unwrap();
+        backup::finish(layout, &backup).unwrap();
+        backup::prune(&backup_dir, &esp).unwrap();
+        assert_eq!(
+            slot::current_default(&esp).unwrap().as_deref(),
+            Some("nixos-generation-2.conf")
+        );
+        assert!(!esp.join("EFI/nixos/abcd-linux-5.12.9-bzImage.efi").exists());
+        assert!(backup
+            .join("esp/EFI/nixos/abcd-linux-5.12.9-bzImage.efi")
+            .exists());
+
+        // which doesn't boot after all
+        backup::rollback(&esp, None, true).unwrap();
+        assert_eq!(
+            slot::current_default(&esp).unwrap().as_deref(),
+            Some("nixos-generation-2.conf")
+        );
+        backup::rollback(&esp, None, false).unwrap();
+
+        // the ESP is as it was before, but for the history, which keeps the run
+        let mut after = report::hash_tree(&esp).unwrap();
+        let mut before = before;
+        after.remove(Path::new(HISTORY));
+        before.remove(Path::new(HISTORY));
+        assert_eq!(after, before);
+        let history = History::load(&esp.join(HISTORY)).unwrap();
+        assert_eq!(
+            history
+                .runs
+                .iter()
+                .map(|run| (run.run_id, run.rolled_back))
+                .collect::<Vec<_>>(),
+            [(1, false), (2, true)]
+        );
+        assert_eq!(history.files, history_before.files);
+
+        // and the run before it wasn't backed up
+        let err = backup::rollback(&esp, None, false).unwrap_err().to_string();
+        assert!(err.contains("run 1"), "{}", err);
+        assert!(err.contains("wasn't backed up"), "{}", err);
+    }
+
+    #[test]
+    fn test_credential_scope() {
+        let (mut args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        args.credential = vec!["secret=/run/keys/secret.cred".parse().unwrap()];
+
+        let credentials_dir = |args: &Args| -> Result<Option<PathBuf>> {
+            let plan = create_plan(PlanArgs {
+                args,
+                options: super::super::options(args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            })?;
+
+            Ok(plan.into_iter().find_map(|state| match state {
+                SystemdBootPlanState::WriteCredentials { dir, .. } => Some(dir),
+                _ => None,
+            }))
+        };
+
+        assert_eq!(
+            credentials_dir(&args).unwrap(),
+            Some(args.generated_entries.join("loader/credentials"))
+        );
+
+        args.credential_scope = CredentialScope::Generation;
+        assert!(credentials_dir(&args).is_err());
+
+        args.unified_efi = true;
+        assert_eq!(
+            credentials_dir(&args).unwrap(),
+            Some(
+                args.generated_entries
+                    .join("EFI/nixos/abcd-linux-5.12.9-bzImage.efi.extra.d")
+            )
+        );
+
+        args.credential = vec![];
+        assert_eq!(credentials_dir(&args).unwrap(), None);
+    }
+
+    #[test]
+    fn test_stage_and_promote_oneshot() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let esp = dir.join("esp");
+        let generated_entries = dir.join("generated_entries");
+        let marker = dir.join("booted-ok");
+        let bootctl = dir.join("bootctl");
+        fs::write(
+            &bootctl,
+            format!(
+                "#!/bin/sh\n[ \"$1\" = --version ] && echo 'systemd 252 (252)' || echo \"$@\" >> {}\n",
+                dir.join("bootctl.log").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&bootctl, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // generation 1 is the current default
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::write(
+            esp.join("loader/loader.conf"),
+            "timeout 1\ndefault nixos-generation-1.conf\n",
+        )
+        .unwrap();
+        fs::write(esp.join("loader/entries/nixos-generation-1.conf"), "1").unwrap();
+
+        let generations = (1..=2)
+            .map(|idx| Generation {
+                idx,
+                profile: None,
+                path: PathBuf::from(idx.to_string()),
+                required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+            })
+            .collect::<Vec<_>>();
+        let default_generation = &generations[1];
+
+        let run = |stage_oneshot: bool, promote_staged: bool| {
+            fs::create_dir_all(generated_entries.join("loader/entries")).unwrap();
+            for generation in &generations {
+                let entry = format!("loader/entries/nixos-generation-{}.conf", generation.idx);
+                fs::write(generated_entries.join(entry), generation.idx.to_string()).unwrap();
+            }
+
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.generated_entries = generated_entries.clone();
+            args.esp = vec![esp.clone()];
+            args.bootctl = Some(bootctl.clone());
+            args.can_touch_efi_vars = true;
+            args.configuration_limit = None;
+            args.stage_oneshot = stage_oneshot;
+            args.promote_staged = promote_staged;
+            args.success_marker = Some(marker.clone());
+
+            let staging = Staging::resolve(&args, &esp, default_generation).unwrap();
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: Some(&bootctl),
+                esp: &esp,
+                wanted_generations: &generations,
+                profile_generations: &[],
+                default_generation,
+                identified_files: IdentifiedFiles::new(&generated_entries, Layout::new(&esp, None))
+                    .unwrap(),
+                signing_info: &None,
+                manifest: &None,
+                staging,
+                prune: true,
+            };
+            let plan = create_plan(plan_args).unwrap();
+            let set_oneshot = plan.iter().find_map(|state| match state {
+                SystemdBootPlanState::SetOneshot { entry, .. } => Some(entry.clone()),
+                _ => None,
+            });
+
+            consume_plan(plan).unwrap();
+
+            set_oneshot
+        };
+        let loader_conf = || fs::read_to_string(esp.join("loader/loader.conf")).unwrap();
+        let staged_state = esp.join(oneshot::STAGED_STATE);
+
+        // first run: generation 2 is booted once, generation 1 stays the default
+        assert_eq!(
+            run(true, false),
+            Some(String::from("nixos-generation-2.conf"))
+        );
+        assert!(loader_conf().contains("default nixos-generation-1.conf\n"));
+        assert!(esp.join("loader/entries/nixos-generation-2.conf").exists());
+        assert_eq!(fs::read_to_string(&staged_state).unwrap(), "2\n");
+        assert!(fs::read_to_string(dir.join("bootctl.log"))
+            .unwrap()
+            .contains("set-oneshot nixos-generation-2.conf\n"));
+
+        // second run: generation 2 didn't (yet) report success
+        assert_eq!(run(false, true), None);
+        assert!(loader_conf().contains("default nixos-generation-1.conf\n"));
+        assert!(staged_state.exists());
+
+        // third run: generation 2 booted successfully
+        fs::write(&marker, "").unwrap();
+        assert_eq!(run(false, true), None);
+        assert!(loader_conf().contains("default nixos-generation-2.conf\n"));
+        assert!(!staged_state.exists());
+    }
+
+    #[test]
+    fn test_plan_with_unnormalized_paths() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(tempdir.path()).unwrap();
+        for path in [
+            "esp/EFI/nixos/abcd-linux-5.12.9-bzImage.efi",
+            "esp/loader/loader.conf",
+            "generated_entries/EFI/nixos/abcd-linux-5.12.9-bzImage.efi",
+            "generated_entries/EFI/nixos/abcd-initrd-linux-5.12.9-initrd.efi",
+            "generated_entries/loader/entries/nixos-generation-2.conf",
+        ] {
+            util::create_dirs_to_file(dir.join(path)).unwrap();
+            fs::write(dir.join(path), path).unwrap();
+        }
+
+        let up = std::env::current_dir()
+            .unwrap()
+            .components()
+            .skip(1)
+            .map(|_| "..")
+            .collect::<Vec<_>>()
+            .join("/");
+        let (_, wanted_generations, default_generation, _) =
+            scaffold(false, None, None, None, None);
+        let plan_for = |esp: String, generated_entries: String| {
+            let (mut args, _, _, _) = scaffold(false, None, None, None, None);
+            args.esp = vec![util::normalize_path(&esp).unwrap()];
+            args.generated_entries = util::normalize_path(&generated_entries).unwrap();
+
+            let identified_files =
+                IdentifiedFiles::new(&args.generated_entries, Layout::new(&args.esp[0], None))
+                    .unwrap();
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: args.bootctl.as_deref(),
+                esp: &args.esp[0],
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files,
+                signing_info: &None,
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+
+            format!("{:?}", create_plan(plan_args).unwrap())
+        };
+
+        let normalized = plan_for(
+            dir.join("esp").display().to_string(),
+            dir.join("generated_entries").display().to_string(),
+        );
+        assert!(normalized.contains("abcd-linux-5.12.9-bzImage.efi"));
+
+        for (esp, generated_entries) in [
+            (
+                format!("{}/esp/", dir.display()),
+                format!("{}/generated_entries/", dir.display()),
+            ),
+            (
+                format!("{}//esp/.", dir.display()),
+                format!("{}/esp/../generated_entries//", dir.display()),
+            ),
+            (
+                format!("{}{}/esp", up, dir.display()),
+                format!("{}{}/generated_entries/", up, dir.display()),
+            ),
+        ] {
+            assert_eq!(
+                plan_for(esp.clone(), generated_entries.clone()),
+                normalized,
+                "{} {}",
+                esp,
+                generated_entries
+            );
+        }
+    }
+
+    #[test]
+    fn test_sign_chainload_plan() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        fs::create_dir_all(esp.join("EFI/Microsoft/Boot")).unwrap();
+        fs::write(esp.join("EFI/Microsoft/Boot/bootmgfw.efi"), "bootmgr").unwrap();
+
+        let signing_info = SigningInfo {
+            signing_key: PathBuf::from("db.key"),
+            signing_cert: PathBuf::from("db.crt"),
+            rules: Vec::new(),
+            sbsign: PathBuf::from("sbsign"),
+            sbverify: PathBuf::from("sbverify"),
+            sbattach: PathBuf::from("sbattach"),
+        };
+        let (mut args, wanted_generations, default_generation, identified_files) =
+            scaffold(false, None, None, None, None);
+        args.bootctl = None;
+        args.no_bootctl = true;
+        args.chainload = vec![
+            "windows=Windows=/EFI/Microsoft/Boot/bootmgfw.efi"
+                .parse()
+                .unwrap(),
+            // lives on another ESP
+            "other=Other=/EFI/other/grubx64.efi".parse().unwrap(),
+        ];
+
+        for (sign_chainload, expected) in [
+            (false, vec![]),
+            (true, vec![esp.join("EFI/Microsoft/Boot/bootmgfw.efi")]),
+        ] {
+            args.sign_chainload = sign_chainload;
+            let plan_args = PlanArgs {
+                args: &args,
+                options: super::super::options(&args),
+                bootctl: None,
+                esp,
+                wanted_generations: &wanted_generations,
+                profile_generations: &[],
+                default_generation: &default_generation,
+                identified_files: identified_files.clone(),
+                signing_info: &Some(signing_info.clone()),
+                manifest: &None,
+                staging: Staging::new(default_generation.idx),
+                prune: true,
+            };
+
+            let plan = create_plan(plan_args).unwrap();
+            let mut to_sign = identified_files.to_sign.clone();
+            to_sign.extend(expected);
+
+            assert!(plan.contains(&SystemdBootPlanState::WriteChainloads {
+                entries: args.generated_entries.join("loader/entries"),
+                chainloads: &args.chainload,
+            }));
+            assert!(plan.contains(&SystemdBootPlanState::SignFiles {
+                signing_info: &signing_info,
+                to_sign: with_default_pair(&signing_info, to_sign),
+            }));
+        }
+    }
+
+    #[test]
+    fn test_signing_rules() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let log = dir.join("log");
+        let stubs = stub_signing_info(dir);
+        let signing_info = SigningInfo {
+            rules: vec![
+                "EFI/systemd/*=org.key:org.crt".parse().unwrap(),
+                "EFI/nixos/*=product.key:product.crt".parse().unwrap(),
+            ],
+            // record which key signs, and which cert verifies, each file
+            sbsign: stub(
+                dir,
+                "sbsign",
+                &format!(
+                    r#"echo "sign $2 $last" >> {}; echo SIGNED >> "$last""#,
+                    log.display()
+                ),
+            ),
+            sbverify: stub(
+                dir,
+                "sbverify",
+                &format!(
+                    r#"echo "verify $2 $last" >> {}; grep -q SIGNED "$last""#,
+                    log.display()
+                ),
+            ),
+            ..stubs
+        };
+
+        let (mut args, wanted_generations, default_generation, mut identified_files) =
+            scaffold(false, None, None, None, None);
+        args.generated_entries = staging_tree(dir);
+        let esp = dir.join("esp");
+        fs::create_dir_all(esp.join("EFI/nixos")).unwrap();
+        // generation 2's entry is already on the ESP
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::write(esp.join("loader/entries/nixos-generation-2.conf"), "2").unwrap();
+        args.esp = vec![esp.clone()];
+        identified_files.to_sign = vec![args.generated_entries.join("EFI/nixos/a.efi")];
+        let plan = create_plan(PlanArgs {
+            args: &args,
+            options: super::super::options(&args),
+            bootctl: args.bootctl.as_deref(),
+            esp: &esp,
+            wanted_generations: &wanted_generations,
+            profile_generations: &[],
+            default_generation: &default_generation,
+            identified_files,
+            signing_info: &Some(signing_info.clone()),
+            manifest: &None,
+            staging: Staging::new(default_generation.idx),
+            prune: true,
+        })
+        .unwrap();
+
+        let keys = plan
+            .iter()
+            .find_map(|state| match state {
+                SystemdBootPlanState::SignFiles { to_sign, .. } => Some(to_sign),
+                _ => None,
+            })
+            .unwrap()
+            .iter()
+            .map(|file| (file.file.clone(), file.pair.key.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                (
+                    esp.join("EFI/systemd/systemd-bootx64.efi"),
+                    PathBuf::from("org.key")
+                ),
+                (esp.join("EFI/BOOT/BOOTX64.EFI"), dir.join("db.key")),
+                (
+                    args.generated_entries.join("EFI/nixos/a.efi"),
+                    PathBuf::from("product.key")
+                ),
+            ]
+        );
+
+        let a = args.generated_entries.join("EFI/nixos/a.efi");
+        let b = args.generated_entries.join("EFI/nixos/b.efi");
+        consume_plan(vec![SystemdBootPlanState::SignFiles {
+            signing_info: &signing_info,
+            to_sign: vec![FileToSign {
+                file: a.clone(),
+                pair: signing_info.pair_for(Path::new("EFI/nixos/a.efi")),
+            }],
+        }])
+        .unwrap();
+
+        // the file on the ESP is verified with the cert of the rule that matches where it is
+        fs::write(&b, "b\n").unwrap();
+        fs::write(esp.join("EFI/nixos/b.efi"), "b\nSIGNED\n").unwrap();
+        let file = FileToReplace {
+            generated_loc: b.clone(),
+            esp_loc: esp.join("EFI/nixos/b.efi"),
+        };
+        replace_file(
+            &file,
+            &Some(signing_info),
+            &args.generated_entries,
+            &VerifyCache::default(),
+        )
+        .unwrap();
+        assert!(!b.exists());
+
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!(
+                "verify product.crt {a}\nsign product.key {a}\nverify product.crt {}\n",
+                esp.join("EFI/nixos/b.efi").display(),
+                a = a.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_verify_once() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let log = dir.join("log");
+        let stubs = stub_signing_info(dir);
+        let signing_info = SigningInfo {
+            sbverify: stub(
+                dir,
+                "sbverify",
+                &format!(
+                    r#"echo "$last" >> {}; grep -q SIGNED "$last""#,
+                    log.display()
+                ),
+            ),
+            ..stubs
+        };
+        let optional_signing_info = Some(signing_info.clone());
+
+        let generated_entries = dir.join("generated");
+        let esp = dir.join("esp");
+        let loader = esp.join("EFI/systemd/systemd-bootx64.efi");
+        let fallback = esp.join("EFI/BOOT/BOOTX64.EFI");
+        for (path, contents) in [
+            (
+                generated_entries.join("EFI/systemd/systemd-bootx64.efi"),
+                "loader\n",
+            ),
+            (loader.clone(), "loader\nSIGNED\n"),
+            (fallback.clone(), "fallback\n"),
+        ] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let sign_files = || SystemdBootPlanState::SignFiles {
+            signing_info: &signing_info,
+            to_sign: with_default_pair(&signing_info, vec![loader.clone(), fallback.clone()]),
+        };
+        let plan = vec![
+            SystemdBootPlanState::ReplaceFiles {
+                signing_info: &optional_signing_info,
+                generated_entries: &generated_entries,
+                to_replace: vec![FileToReplace {
+                    generated_loc: generated_entries.join("EFI/systemd/systemd-bootx64.efi"),
+                    esp_loc: loader.clone(),
+                }],
+            },
+            sign_files(),
+            sign_files(),
+        ];
+        let mut report = PlanReport::default();
+        consume_plan_with(plan, &mut report, &mut Vec::new(), &RunContext::default()).unwrap();
+        assert_eq!(report.signed, [fallback.clone()]);
+
+        // the signed loader is verified once for all three stages, and the fallback loader again
+        // once it was signed
+        let log = fs::read_to_string(&log).unwrap();
+        let count = |path: &Path| log.lines().filter(|line| Path::new(line) == path).count();
+        assert_eq!(count(&loader), 1, "{}", log);
+        assert_eq!(count(&fallback), 2, "{}", log);
+    }
+
+    fn with_default_pair(signing_info: &SigningInfo, files: Vec<PathBuf>) -> Vec<FileToSign> {
+        files
+            .into_iter()
+            .map(|file| FileToSign {
+                file,
+                pair: signing_info.default_pair(),
+            })
+            .collect()
+    }
+
+    /// A shell stand-in for a signing tool, with the last argument in `$last`.
+    fn stub(dir: &Path, name: &str, script: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!("#!/bin/sh\nfor last; do :; done\n{}\n", script),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// Shell stand-ins for the signing tools that "sign" a file by appending a `SIGNED` line.
+    fn stub_signing_info(dir: &Path) -> SigningInfo {
+        SigningInfo {
+            signing_key: dir.join("db.key"),
+            signing_cert: dir.join("db.crt"),
+            rules: Vec::new(),
+            sbsign: stub(dir, "sbsign", r#"echo SIGNED >> "$last""#),
+            sbverify: stub(dir, "sbverify", r#"grep -q SIGNED "$last""#),
+            sbattach: stub(dir, "sbattach", r#"sed -i '/SIGNED/d' "$last""#),
+        }
+    }
+
+    fn staging_tree(dir: &Path) -> PathBuf {
+        let generated_entries = dir.join("generated");
+        for (i, file) in [
+            "EFI/nixos/a.efi",
+            "loader/entries/nixos-generation-1.conf",
+            "EFI/nixos/b.efi",
+            "loader/loader.conf",
+            "EFI/nixos/c.efi",
+            "EFI/nixos/d.efi",
+            "EFI/nixos/credentials/e.cred",
+            "EFI/nixos/f.efi",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let path = generated_entries.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, i.to_string()).unwrap();
+        }
+
+        generated_entries
+    }
+
+    #[test]
+    fn test_copy_to_esp_order() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generated_entries = staging_tree(tempdir.path());
+        let esp = tempdir.path().join("esp");
+
+        for jobs in [1, 3, 16] {
+            let order = Mutex::new(Vec::new());
+            let copy = |src: &Path, dest: &Path| -> Result<()> {
+                // later files finish first, if they can
+                let n: u64 = fs::read_to_string(src).unwrap().parse().unwrap();
+                thread::sleep(std::time::Duration::from_millis(20 - n * 2));
+                order
+                    .lock()
+                    .unwrap()
+                    .push(dest.strip_prefix(&esp)?.to_path_buf());
+
+                util::atomic_tmp_copy_file(src, dest)
+            };
+            let mut copied = Vec::new();
+            copy_to_esp(
+                &generated_entries,
+                Layout::new(&esp, None),
+                jobs,
+                &copy,
+                &mut copied,
+            )
+            .unwrap();
+
+            let order = order.into_inner().unwrap();
+            assert_eq!(order.len(), 8);
+            // the entries (and loader.conf) come after every file they could refer to
+            let first_loader = order.iter().position(|p| p.starts_with("loader")).unwrap();
+            assert!(
+                order[first_loader..]
+                    .iter()
+                    .all(|p| p.starts_with("loader")),
+                "{}: {:?}",
+                jobs,
+                order
+            );
+
+            copied.sort();
+            let mut expected = walkdir::WalkDir::new(&generated_entries)
+                .into_iter()
+                .map(|entry| entry.unwrap().into_path())
+                .filter(|path| path.is_file())
+                .map(|path| esp.join(path.strip_prefix(&generated_entries).unwrap()))
+                .collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(copied, expected);
+            for dest in &copied {
+                let src = generated_entries.join(dest.strip_prefix(&esp).unwrap());
+                assert_eq!(fs::read(dest).unwrap(), fs::read(src).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_to_esp_cancels_on_failure() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generated_entries = staging_tree(tempdir.path());
+        let esp = tempdir.path().join("esp");
+
+        let attempted = Mutex::new(Vec::new());
+        let copy = |src: &Path, dest: &Path| -> Result<()> {
+            attempted.lock().unwrap().push(dest.to_path_buf());
+            if dest.ends_with("EFI/nixos/b.efi") {
+                // a partial copy, e.g. because the ESP filled up
+                util::create_dirs_to_file(dest)?;
+                fs::write(dest.with_extension("tmp"), "partial").unwrap();
+                return Err(format!("'{}': No space left on device", dest.display()).into());
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+
+            util::atomic_tmp_copy_file(src, dest)
+        };
+        let mut copied = Vec::new();
+        let err = copy_to_esp(
+            &generated_entries,
+            Layout::new(&esp, None),
+            2,
+            &copy,
+            &mut copied,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("No space left on device"), "{}", err);
+
+        // nothing was started after the failure (besides what was already being copied), and
+        // none of the entries were copied
+        let attempted = attempted.into_inner().unwrap();
+        assert!(attempted.len() < 5, "{:?}", attempted);
+        assert!(attempted.iter().all(|p| !p.starts_with(esp.join("loader"))));
+        assert!(!esp.join("loader").exists());
+        assert!(!esp.join("EFI/nixos/b.tmp").exists());
+
+        // what was copied is still reported
+        assert!(!copied.is_empty());
+        assert!(copied.iter().all(|dest| dest.exists()), "{:?}", copied);
+        assert!(!copied.contains(&esp.join("EFI/nixos/b.efi")));
+    }
+
+    #[test]
+    fn test_copy_to_xbootldr() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated_entries = dir.join("generated");
+        let esp = dir.join("esp");
+        let xbootldr = dir.join("xbootldr");
+
+        for (file, contents) in [
+            ("EFI/nixos/kernel.efi", "kernel"),
+            ("EFI/nixos/initrd.efi", "initrd"),
+            (
+                "loader/entries/nixos-generation-1.conf",
+                "linux /EFI/nixos/kernel.efi\ninitrd /EFI/nixos/initrd.efi\n",
+            ),
+            (
+                "loader/entries/nixos-chainload-windows.conf",
+                "efi /EFI/Microsoft/Boot/bootmgfw.efi\n",
+            ),
+            ("loader/loader.conf", "default nixos-generation-1.conf\n"),
+        ] {
+            let path = generated_entries.join(file);
+            util::create_dirs_to_file(&path).unwrap();
+            fs::write(&path, contents).unwrap();
+        }
+        // the Windows boot manager is only ever on the ESP
+        let bootmgfw = esp.join("EFI/Microsoft/Boot/bootmgfw.efi");
+        util::create_dirs_to_file(&bootmgfw).unwrap();
+        fs::write(&bootmgfw, "bootmgfw").unwrap();
+
+        let mut copied = Vec::new();
+        copy_to_esp(
+            &generated_entries,
+            Layout::new(&esp, Some(&xbootldr)),
+            2,
+            &util::atomic_tmp_copy_file,
+            &mut copied,
+        )
+        .unwrap();
+        assert_eq!(copied.len(), 5);
+
+        assert!(xbootldr.join("EFI/nixos/kernel.efi").exists());
+        assert!(xbootldr
+            .join("loader/entries/nixos-generation-1.conf")
+            .exists());
+        assert!(esp
+            .join("loader/entries/nixos-chainload-windows.conf")
+            .exists());
+        assert!(esp.join("loader/loader.conf").exists());
+        assert!(!esp.join("EFI/nixos").exists());
+        assert!(!xbootldr.join("loader/loader.conf").exists());
+
+        // everything an entry refers to is on the entry's own partition
+        for root in [&esp, &xbootldr] {
+            let entries = root.join("loader/entries");
+            for entry in fs::read_dir(&entries).unwrap() {
+                let contents = fs::read_to_string(entry.unwrap().path()).unwrap();
+                for line in contents.lines() {
+                    let path = line.split_whitespace().nth(1).unwrap();
+                    let path = root.join(path.trim_start_matches('/'));
+                    assert!(path.exists(), "{}", path.display());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_and_copy_hard_linked_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let signing_info = stub_signing_info(dir);
+
+        // the generator hard links identical files between the staging trees of different targets
+        let generated_entries = dir.join("a");
+        let other_target = dir.join("b/EFI/nixos/unified.efi");
+        let staged = generated_entries.join("EFI/nixos/unified.efi");
+        fs::create_dir_all(staged.parent().unwrap()).unwrap();
+        fs::create_dir_all(other_target.parent().unwrap()).unwrap();
+        fs::write(&staged, "efi\n").unwrap();
+        fs::hard_link(&staged, &other_target).unwrap();
+        fs::hard_link(&staged, generated_entries.join("EFI/nixos/copy.efi")).unwrap();
+
+        let esp = dir.join("esp");
+        fs::create_dir(&esp).unwrap();
+        let plan = vec![
+            SystemdBootPlanState::SignFiles {
+                signing_info: &signing_info,
+                to_sign: with_default_pair(&signing_info, vec![staged.clone()]),
+            },
+            SystemdBootPlanState::CopyToEsp {
+                generated_entries: &generated_entries,
+                esp: &esp,
+                xbootldr: None,
+                jobs: 4,
+            },
+        ];
+        let report = consume_plan(plan).unwrap();
+
+        assert_eq!(report.signed, vec![staged]);
+        assert_eq!(fs::read_to_string(&other_target).unwrap(), "efi\n");
+        assert_eq!(
+            fs::read_to_string(esp.join("EFI/nixos/unified.efi")).unwrap(),
+            "efi\nSIGNED\n"
+        );
+
+        // files that were hard linked in the staging tree end up as independent copies in the ESP
+        let copy = esp.join("EFI/nixos/copy.efi");
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "efi\n");
+        assert_eq!(fs::metadata(&copy).unwrap().nlink(), 1);
+        assert_ne!(
+            fs::metadata(&copy).unwrap().ino(),
+            fs::metadata(&other_target).unwrap().ino()
+        );
+    }
+
+    #[test]
+    fn test_replace_signed_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let signing_info = Some(stub_signing_info(dir));
+
+        let esp_loc = dir.join("esp.efi");
+        fs::write(&esp_loc, "efi\nSIGNED\n").unwrap();
+
+        // identical once the signature is stripped
+        let generated_loc = dir.join("same.efi");
+        fs::write(&generated_loc, "efi\n").unwrap();
+        let file = FileToReplace {
+            generated_loc: generated_loc.clone(),
+            esp_loc: esp_loc.clone(),
+        };
+        replace_file(&file, &signing_info, dir, &VerifyCache::default()).unwrap();
+        assert!(!generated_loc.exists());
+
+        let generated_loc = dir.join("different.efi");
+        fs::write(&generated_loc, "other efi\n").unwrap();
+        let file = FileToReplace {
+            generated_loc: generated_loc.clone(),
+            esp_loc: esp_loc.clone(),
+        };
+        replace_file(&file, &signing_info, dir, &VerifyCache::default()).unwrap();
+        assert!(generated_loc.exists());
+        assert_eq!(fs::read_to_string(&esp_loc).unwrap(), "efi\nSIGNED\n");
+    }
+
+    #[test]
+    fn test_replace_signed_file_without_signing_info() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let image = secure_boot::test_image();
+        let mut other_image = image.clone();
+        other_image[0x200] = 0x90;
+
+        let esp_loc = dir.join("esp.efi");
+        let signed = secure_boot::tests::signed(&image);
+        fs::write(&esp_loc, &signed).unwrap();
+        let replace = |name: &str, generated: &[u8]| {
+            let file = FileToReplace {
+                generated_loc: dir.join(name),
+                esp_loc: esp_loc.clone(),
+            };
+            fs::write(&file.generated_loc, generated).unwrap();
+            replace_file(&file, &None, dir, &VerifyCache::default()).unwrap();
+
+            file.generated_loc.exists()
+        };
+
+        // signed by an earlier run, and identical once the signature is stripped
+        assert!(!replace("same.efi", &image));
+        assert!(replace("different.efi", &other_image));
+        assert_eq!(fs::read(&esp_loc).unwrap(), signed);
+
+        // an unsigned file on the ESP is still compared as it is
+        fs::write(&esp_loc, &image).unwrap();
+        assert!(!replace("unsigned.efi", &image));
+        let mut checksummed = image.clone();
+        checksummed[0x58 + 64] = 1;
+        assert!(replace("checksummed.efi", &checksummed));
+    }
+
+    #[test]
+    fn test_replace_large_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let len = 256 << 20;
+        let esp_loc = dir.join("esp.efi");
+        generator::stream::sparse_file(&esp_loc, len, b"the end").unwrap();
+        let replace = |name: &str, tail: &[u8]| {
+            let file = FileToReplace {
+                generated_loc: dir.join(name),
+                esp_loc: esp_loc.clone(),
+            };
+            generator::stream::sparse_file(&file.generated_loc, len, tail).unwrap();
+            let ((), largest) = generator::stream::largest_allocation(|| {
+                replace_file(&file, &None, dir, &VerifyCache::default()).unwrap()
+            });
+            assert!(
+                largest <= 2 * generator::stream::BUFFER_SIZE,
+                "comparing {} byte files allocated {} bytes at once",
+                len,
+                largest
+            );
+
+            file.generated_loc.exists()
+        };
+
+        // compared a buffer at a time, rather than read whole
+        assert!(!replace("same.efi", b"the end"));
+        assert!(replace("different.efi", b"an end"));
+
+        let (_, largest) =
+            generator::stream::largest_allocation(|| crc32c(&esp_loc, true).unwrap());
+        assert!(largest <= 2 * generator::stream::BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_replace_entry() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let generated = dir.join("generated/loader/entries");
+        let esp = dir.join("esp/loader/entries");
+        fs::create_dir_all(&generated).unwrap();
+        fs::create_dir_all(&esp).unwrap();
+
+        let contents = "title NixOS\nversion Generation 1\nmachine-id aaaa\n";
+        let replace = |name: &str, generated_contents: &str, esp_contents: &str| {
+            let file = FileToReplace {
+                generated_loc: generated.join(name),
+                esp_loc: esp.join(name),
+            };
+            fs::write(&file.generated_loc, generated_contents).unwrap();
+            fs::write(&file.esp_loc, esp_contents).unwrap();
+            replace_file(&file, &None, dir, &VerifyCache::default()).unwrap();
+
+            file.generated_loc.exists()
+        };
+
+        // written by an older version, with a trailing blank line and extra whitespace
+        let legacy = "title  NixOS \nversion Generation 1\nmachine-id aaaa\n\n";
+        assert!(!replace("nixos-generation-1.conf", contents, legacy));
+
+        // a different value, or the same lines in a different order, is a different entry
+        let different = "title NixOS\nversion Generation 2\nmachine-id aaaa\n";
+        assert!(replace("nixos-generation-2.conf", contents, different));
+        let reordered = "version Generation 1\ntitle NixOS\nmachine-id aaaa\n";
+        assert!(replace("nixos-generation-3.conf", contents, reordered));
+
+        // only entries are compared this way
+        let loader_conf = dir.join("generated/loader/loader.conf");
+        fs::write(&loader_conf, "timeout 5\n").unwrap();
+        fs::write(dir.join("esp/loader/loader.conf"), "timeout 5\n\n").unwrap();
+        let file = FileToReplace {
+            generated_loc: loader_conf.clone(),
+            esp_loc: dir.join("esp/loader/loader.conf"),
+        };
+        replace_file(&file, &None, dir, &VerifyCache::default()).unwrap();
+        assert!(loader_conf.exists());
+    }
+
+    #[test]
+    fn test_sign_skips_signed_and_missing_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let signing_info = stub_signing_info(dir);
+
+        let signed = dir.join("signed.efi");
+        let unsigned = dir.join("unsigned.efi");
+        let missing = dir.join("missing.efi");
+        fs::write(&signed, "efi\nSIGNED\n").unwrap();
+        fs::write(&unsigned, "efi\n").unwrap();
+
+        let report = consume_plan(vec![SystemdBootPlanState::SignFiles {
+            signing_info: &signing_info,
+            to_sign: with_default_pair(
+                &signing_info,
+                vec![signed.clone(), unsigned.clone(), missing.clone()],
+            ),
+        }])
+        .unwrap();
+
+        assert_eq!(report.signed, vec![unsigned.clone()]);
+        assert_eq!(fs::read_to_string(&signed).unwrap(), "efi\nSIGNED\n");
+        assert_eq!(fs::read_to_string(&unsigned).unwrap(), "efi\nSIGNED\n");
+        assert!(!missing.exists());
+    }
 }
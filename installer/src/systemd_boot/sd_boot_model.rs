@@ -0,0 +1,857 @@
+//! Predicts what systemd-boot makes of an ESP: which entries it shows (and in what order), and which
+//! one it boots by default. This follows sd-boot's own logic (`boot_entry_compare()`,
+//! `strverscmp_improved()`, and `config_default_entry_select()`) as closely as possible, since any
+//! divergence means we think a different entry boots than actually does.
+//!
+//! Note that systemd-boot only uses the machine-id to group entries in the menu, and shows entries
+//! for every machine-id.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::version::systemd::SystemdVersion;
+use crate::context::Context;
+use crate::util;
+use crate::Result;
+
+/// The parts of a boot loader entry that determine whether it is shown, and its position in the
+/// menu.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub(crate) struct MenuEntry {
+    /// The entry's filename (e.g. `nixos-generation-1.conf`)
+    pub id: String,
+    pub sort_key: Option<String>,
+    pub machine_id: Option<String>,
+    pub version: Option<String>,
+    pub architecture: Option<String>,
+    /// The boot counter in the filename (e.g. `+3-1` in `nixos-generation-1+3-1.conf`), as tries
+    /// left and tries done
+    pub tries: Option<(u32, u32)>,
+}
+
+impl MenuEntry {
+    pub fn parse(id: &str, contents: &str) -> Self {
+        let mut entry = MenuEntry {
+            id: id.to_string(),
+            tries: self::parse_tries(id),
+            ..Default::default()
+        };
+
+        for (key, value) in self::key_values(contents) {
+            match key {
+                "sort-key" => entry.sort_key = Some(value),
+                "machine-id" => entry.machine_id = Some(value),
+                "version" => entry.version = Some(value),
+                "architecture" => entry.architecture = Some(value),
+                _ => {}
+            }
+        }
+
+        entry
+    }
+
+    /// The ID without the boot counter, which is how systemd-boot identifies the entry.
+    pub fn id_without_tries(&self) -> String {
+        match (self.tries, self.id.strip_suffix(".conf")) {
+            (Some(_), Some(id)) => match id.rsplit_once('+') {
+                Some((id, _)) => format!("{}.conf", id),
+                None => self.id.clone(),
+            },
+            _ => self.id.clone(),
+        }
+    }
+
+    /// Whether `pattern` (loader.conf's `default`) selects this entry. The pattern is matched
+    /// against the ID (without its boot counter) both with and without its `.conf` suffix.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let id = self.id_without_tries();
+
+        self::glob_match(pattern, &id)
+            || matches!(id.strip_suffix(".conf"), Some(id) if self::glob_match(pattern, id))
+    }
+}
+
+/// What systemd-boot makes of an ESP.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SdBootModel {
+    /// The entries that are shown (i.e. those for `arch`), in menu order
+    pub entries: Vec<MenuEntry>,
+    /// loader.conf's `default` glob
+    pub default_pattern: Option<String>,
+}
+
+impl SdBootModel {
+    /// `arch` is the firmware's architecture (see [`efi_arch`]).
+    pub fn new(mut entries: Vec<MenuEntry>, loader_conf: &str, arch: &str) -> Self {
+        entries.retain(|entry| match &entry.architecture {
+            Some(architecture) => architecture.eq_ignore_ascii_case(arch),
+            None => true,
+        });
+        entries.sort_by(self::menu_cmp);
+
+        // The last `default` wins.
+        let default_pattern = self::key_values(loader_conf)
+            .filter(|(key, _)| *key == "default")
+            .map(|(_, value)| value)
+            .last();
+
+        SdBootModel {
+            entries,
+            default_pattern,
+        }
+    }
+
+    /// Reads loader.conf and the entries on the ESP, and the entries on the XBOOTLDR partition (if
+    /// there is one).
+    pub fn read(esp: &Path, xbootldr: Option<&Path>, arch: &str) -> Result<Self> {
+        let loader_conf = esp.join("loader/loader.conf");
+        let loader_conf = match util::read_to_string_lossy(&loader_conf) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("'{}': {}", loader_conf.display(), e).into()),
+        };
+
+        let mut entries = Vec::new();
+
+        for entries_dir in std::iter::once(esp)
+            .chain(xbootldr)
+            .map(|root| root.join("loader/entries"))
+            .filter(|dir| dir.exists())
+        {
+            for entry in fs::read_dir(&entries_dir).with_path_context(&entries_dir)? {
+                let path = entry.with_path_context(&entries_dir)?.path();
+                let id = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(id) if id.ends_with(".conf") && !path.is_dir() => id.to_string(),
+                    _ => continue,
+                };
+
+                let contents = util::read_to_string_lossy(&path).with_path_context(&path)?;
+                entries.push(MenuEntry::parse(&id, &contents));
+            }
+        }
+
+        Ok(Self::new(entries, &loader_conf, arch))
+    }
+
+    /// The entries that loader.conf's `default` glob selects, in menu order.
+    pub fn matching_default(&self) -> impl Iterator<Item = &MenuEntry> {
+        let pattern = self.default_pattern.as_deref();
+
+        self.entries
+            .iter()
+            .filter(move |entry| matches!(pattern, Some(pattern) if entry.matches(pattern)))
+    }
+
+    /// The entry that systemd-boot boots unless an EFI variable (e.g. a one-shot entry) says
+    /// otherwise: the first one in menu order that matches the default glob, or else the first one.
+    pub fn default_entry(&self) -> Option<&MenuEntry> {
+        self.matching_default()
+            .next()
+            .or_else(|| self.entries.first())
+    }
+}
+
+/// The name systemd-boot uses for the firmware's architecture (e.g. in entries' `architecture`),
+/// assuming it's the same as ours.
+pub(crate) fn efi_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "x64",
+        "x86" => "ia32",
+        "aarch64" => "aa64",
+        arch => arch,
+    }
+}
+
+/// Whether the loader understands `sort-key` (added in systemd-boot 250). Older loaders (or ones we
+/// don't know the version of) sort entries with a `sort-key` oddly, so it's better to leave it out.
+pub(crate) fn supports_sort_key(loader_version: Option<&SystemdVersion>) -> bool {
+    loader_version
+        .and_then(SystemdVersion::major)
+        .map(|major| major >= 250)
+        .unwrap_or(false)
+}
+
+/// The `key value` lines of an entry or loader.conf.
+pub(crate) fn key_values(contents: &str) -> impl Iterator<Item = (&str, String)> {
+    contents.lines().filter_map(|line| {
+        let mut parts = line.trim().splitn(2, char::is_whitespace);
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => Some((key, value.trim().to_string())),
+            _ => None,
+        }
+    })
+}
+
+/// The `key value` lines of an entry as systemd-boot reads them: all of its `options` lines are
+/// joined with spaces, into the first of them (the generator folds long ones).
+fn entry_key_values(contents: &str) -> Vec<(&str, String)> {
+    let mut key_values: Vec<(&str, String)> = Vec::new();
+    let mut options = None;
+
+    for (key, value) in self::key_values(contents) {
+        match (key, options) {
+            ("options", Some(i)) => {
+                let joined = &mut key_values[i].1;
+                joined.push(' ');
+                joined.push_str(&value);
+            }
+            ("options", None) => {
+                options = Some(key_values.len());
+                key_values.push((key, value));
+            }
+            _ => key_values.push((key, value)),
+        }
+    }
+
+    key_values
+}
+
+/// Whether the entries `a` and `b` are the same to systemd-boot: they have the same `key value`
+/// lines in the same order, whatever the blank lines and whitespace around them, and the same
+/// options, however they're folded.
+pub(crate) fn same_entry(a: &str, b: &str) -> bool {
+    self::entry_key_values(a) == self::entry_key_values(b)
+}
+
+/// Parses the boot counter of an entry's ID: `+LEFT[-DONE]` just before the `.conf` suffix.
+fn parse_tries(id: &str) -> Option<(u32, u32)> {
+    let (_, counter) = id.strip_suffix(".conf")?.rsplit_once('+')?;
+    let (left, done) = counter.split_once('-').unwrap_or((counter, "0"));
+    let number = |s: &str| -> Option<u32> {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        s.parse().ok()
+    };
+
+    Some((number(left)?, number(done)?))
+}
+
+/// Matches `s` against a glob `pattern` containing `*` (any number of characters) and `?` (exactly
+/// one character), ignoring ASCII case like systemd-boot does.
+pub(crate) fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // where the last `*` was, and how much of `s` it has consumed so far
+    let mut star = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&s[i]) => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                // let the last `*` consume one more character, and try again
+                Some((star_p, star_i)) => {
+                    star = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Compares versions like systemd's `strverscmp_improved()`: the strings are split into numeric and
+/// alphabetic segments (ignoring anything but letters, digits, and `~-^.`), numeric segments are
+/// compared numerically and are newer than alphabetic ones, and:
+///
+/// - `~` (pre-releases, e.g. `123~rc1`) sorts before everything, even the end of the string;
+/// - otherwise, a string with more segments is newer;
+/// - `-` (the release, e.g. `123-1`) sorts before `^` (patched releases), which sorts before `.`.
+pub(crate) fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    // like the NUL terminator of the C strings
+    let first = |s: &[u8]| s.first().copied().unwrap_or(0);
+    let is_valid = |c: u8| c.is_ascii_alphanumeric() || matches!(c, b'~' | b'-' | b'^' | b'.');
+
+    loop {
+        while !a.is_empty() && !is_valid(a[0]) {
+            a = &a[1..];
+        }
+        while !b.is_empty() && !is_valid(b[0]) {
+            b = &b[1..];
+        }
+
+        if first(a) == b'~' || first(b) == b'~' {
+            let ordering = (first(a) != b'~').cmp(&(first(b) != b'~'));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+
+            a = &a[1..];
+            b = &b[1..];
+        }
+
+        if a.is_empty() || b.is_empty() {
+            return first(a).cmp(&first(b));
+        }
+
+        for separator in [b'-', b'^', b'.'] {
+            if first(a) == separator || first(b) == separator {
+                let ordering = (first(a) != separator).cmp(&(first(b) != separator));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+
+        let (a_len, b_len);
+        if first(a).is_ascii_digit() || first(b).is_ascii_digit() {
+            a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+            b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+
+            let ordering = (a_len != 0).cmp(&(b_len != 0));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+
+            let a_num = &a[a.iter().take_while(|&&c| c == b'0').count()..a_len];
+            let b_num = &b[b.iter().take_while(|&&c| c == b'0').count()..b_len];
+            let ordering = a_num.len().cmp(&b_num.len()).then(a_num.cmp(b_num));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        } else {
+            a_len = a.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            b_len = b.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+
+            let len = a_len.min(b_len);
+            let ordering = a[..len].cmp(&b[..len]).then(a_len.cmp(&b_len));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        a = &a[a_len..];
+        b = &b[b_len..];
+    }
+}
+
+/// systemd-boot's menu order: entries without tries left go last; entries with a sort-key come
+/// first, ordered by sort-key, machine-id, and then newest version first; then everything is
+/// ordered by ID (without the boot counter), newest first, and then by how many tries are left
+/// (more first) and done (fewer first).
+fn menu_cmp(a: &MenuEntry, b: &MenuEntry) -> Ordering {
+    let no_tries_left = |entry: &MenuEntry| matches!(entry.tries, Some((0, _)));
+
+    no_tries_left(a)
+        .cmp(&no_tries_left(b))
+        .then_with(|| match (&a.sort_key, &b.sort_key) {
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a_key), Some(b_key)) => a_key
+                .cmp(b_key)
+                .then_with(|| a.machine_id.cmp(&b.machine_id))
+                .then_with(|| {
+                    let a_version = a.version.as_deref().unwrap_or_default();
+                    let b_version = b.version.as_deref().unwrap_or_default();
+                    self::version_cmp(a_version, b_version).reverse()
+                }),
+            (None, None) => Ordering::Equal,
+        })
+        .then_with(|| self::version_cmp(&a.id_without_tries(), &b.id_without_tries()).reverse())
+        .then_with(|| match (a.tries, b.tries) {
+            (Some((a_left, a_done)), Some((b_left, b_done))) => {
+                b_left.cmp(&a_left).then(a_done.cmp(&b_done))
+            }
+            _ => Ordering::Equal,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, sort_key: Option<&str>, version: Option<&str>) -> MenuEntry {
+        MenuEntry {
+            id: id.to_string(),
+            sort_key: sort_key.map(ToString::to_string),
+            version: version.map(ToString::to_string),
+            tries: parse_tries(id),
+            ..Default::default()
+        }
+    }
+
+    fn ids(model: &SdBootModel) -> Vec<&str> {
+        model.entries.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, s) in [
+            ("nixos-generation-1.conf", "nixos-generation-1.conf"),
+            ("nixos-generation-*", "nixos-generation-1.conf"),
+            ("nixos-generation-*", "nixos-generation-"),
+            ("nixos-*-generation-*.conf", "nixos-work-generation-12.conf"),
+            ("nixos-generation-?.conf", "nixos-generation-1.conf"),
+            ("*", ""),
+            ("*.conf", "a.conf"),
+            ("*a*b", "xaxxaxb"),
+            ("NixOS-*", "nixos-generation-1.conf"),
+        ] {
+            assert!(glob_match(pattern, s), "{} should match {}", pattern, s);
+        }
+
+        for (pattern, s) in [
+            ("nixos-generation-1.conf", "nixos-generation-10.conf"),
+            ("nixos-generation-?.conf", "nixos-generation-10.conf"),
+            ("nixos-generation-*", "nixos-work-generation-1.conf"),
+            ("?", ""),
+            ("*a*b", "xaxxaxbx"),
+            ("", "a"),
+        ] {
+            assert!(!glob_match(pattern, s), "{} shouldn't match {}", pattern, s);
+        }
+    }
+
+    #[test]
+    fn test_version_cmp() {
+        // from systemd's test-string-util.c: each version is older than the ones after it
+        let versions = [
+            "~1",
+            "",
+            "ab",
+            "abb",
+            "abc",
+            "0001",
+            "002",
+            "12",
+            "122",
+            "122.9",
+            "123~rc1",
+            "123",
+            "123-a",
+            "123-a.1",
+            "123-a1",
+            "123-a1.1",
+            "123-3",
+            "123-3.1",
+            "123^patch1",
+            "123^1",
+            "123.a-1",
+            "123.1-1",
+            "123a-1",
+            "124",
+        ];
+        for (i, a) in versions.iter().enumerate() {
+            assert_eq!(version_cmp(a, a), Ordering::Equal, "{}", a);
+            for b in &versions[i + 1..] {
+                assert_eq!(version_cmp(a, b), Ordering::Less, "{} < {}", a, b);
+                assert_eq!(version_cmp(b, a), Ordering::Greater, "{} > {}", b, a);
+            }
+        }
+
+        for (older, newer) in [
+            ("123.45-67.88", "123.45-67.89"),
+            ("123.45-67.89", "123.45-67.89a"),
+            ("123.45-67.ab", "123.45-67.89"),
+            ("123.45-67.9", "123.45-67.89"),
+            ("123.45-67", "123.45-67.89"),
+            ("123.45-66.89", "123.45-67.89"),
+            ("123.45-9.99", "123.45-67.89"),
+            ("123.42-99.99", "123.45-67.89"),
+            ("123-99.99", "123.45-67.89"),
+            ("123~rc1-99.99", "123.45-67.89"),
+            ("123~rc1-99.99", "123-45.67.89"),
+            ("123~rc1-99.99", "123~rc2-67.89"),
+            ("123~rc1-99.99", "123^aa2-67.89"),
+            ("123~rc1-99.99", "123aa2-67.89"),
+            ("123-99.99", "123^aa2-67.89"),
+            ("123-99.99", "123aa2-67.89"),
+            ("123^45-67.89", "123.45-67.89"),
+            ("123^aa1-99.99", "123^aa2-67.89"),
+            ("123^aa2-67.89", "123aa2-67.89"),
+            ("123.aa2-67.89", "123aa2-67.89"),
+            ("123.aa2-67.89", "123.ab2-67.89"),
+            ("12_3", "123"),
+            ("12", "12_3"),
+            ("12.3", "12_3"),
+            ("123", "123."),
+            ("123", "123.0"),
+            ("123", "123_0"),
+            ("123..0", "123.0"),
+            ("~", ""),
+            ("nixos-generation-9.conf", "nixos-generation-10.conf"),
+            ("22.05", "22.11"),
+        ] {
+            assert_eq!(
+                version_cmp(older, newer),
+                Ordering::Less,
+                "{} < {}",
+                older,
+                newer
+            );
+            assert_eq!(
+                version_cmp(newer, older),
+                Ordering::Greater,
+                "{} > {}",
+                newer,
+                older
+            );
+        }
+
+        for (a, b) in [
+            ("123_aa2-67.89", "123aa+2-67.89"),
+            ("0_", "0"),
+            ("_0_", "0"),
+            ("_0", "0"),
+            ("0", "0___"),
+            ("_", "_"),
+            ("~", "~"),
+            ("010", "10"),
+        ] {
+            assert_eq!(version_cmp(a, b), Ordering::Equal, "{} == {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_parse_tries() {
+        assert_eq!(parse_tries("nixos-generation-1.conf"), None);
+        assert_eq!(parse_tries("nixos-generation-1+3.conf"), Some((3, 0)));
+        assert_eq!(parse_tries("nixos-generation-1+0-2.conf"), Some((0, 2)));
+        assert_eq!(parse_tries("nixos-generation-1+-2.conf"), None);
+        assert_eq!(parse_tries("nixos-generation-1+a.conf"), None);
+    }
+
+    #[test]
+    fn test_same_entry() {
+        let entry = "title NixOS\nversion Generation 1\noptions init=/init quiet\n";
+        assert!(same_entry(entry, entry));
+        assert!(same_entry(
+            entry,
+            "title NixOS\n  version   Generation 1 \noptions init=/init quiet\n\n"
+        ));
+        assert!(!same_entry(entry, "title NixOS\nversion Generation 1\n"));
+        assert!(!same_entry(
+            entry,
+            "title NixOS\nversion Generation 1\noptions init=/init  quiet\n"
+        ));
+
+        // options folded onto several lines are joined back together
+        assert!(same_entry(
+            entry,
+            "title NixOS\nversion Generation 1\noptions init=/init\noptions quiet\n"
+        ));
+        assert!(same_entry(
+            "title NixOS\noptions init=/init\nversion Generation 1\noptions quiet\n",
+            "title NixOS\noptions init=/init quiet\nversion Generation 1\n"
+        ));
+        assert!(!same_entry(
+            entry,
+            "title NixOS\nversion Generation 1\noptions quiet\noptions init=/init\n"
+        ));
+        assert_eq!(
+            entry_key_values("options a\ntitle NixOS\noptions b\noptions c\n"),
+            [
+                ("options", String::from("a b c")),
+                ("title", String::from("NixOS"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_menu_order() {
+        // entries with a sort-key come first, newest version first
+        let model = SdBootModel::new(
+            vec![
+                entry("nixos-generation-9.conf", Some("nixos"), Some("9")),
+                entry("nixos-generation-10.conf", Some("nixos"), Some("10")),
+                entry("zzz.conf", None, None),
+                entry(
+                    "nixos-generation-10-gui.conf",
+                    Some("nixos-gui"),
+                    Some("10"),
+                ),
+            ],
+            "",
+            "x64",
+        );
+        assert_eq!(
+            ids(&model),
+            vec![
+                "nixos-generation-10.conf",
+                "nixos-generation-9.conf",
+                "nixos-generation-10-gui.conf",
+                "zzz.conf",
+            ]
+        );
+
+        // the generator's specialisations (`Generation <n>~<name>`) come right after their parent
+        // when they share its sort-key (with `--group-specialisations`), and in a group of their
+        // own otherwise
+        let version = |version| format!("{} 23.05, Built on 2023-06-01", version);
+        for (grouped, expected) in [
+            (
+                true,
+                vec![
+                    "nixos-generation-10.conf",
+                    "nixos-generation-10-minimal.conf",
+                    "nixos-generation-10-gui.conf",
+                    "nixos-generation-9.conf",
+                    "nixos-generation-9-gui.conf",
+                ],
+            ),
+            (
+                false,
+                vec![
+                    "nixos-generation-10.conf",
+                    "nixos-generation-9.conf",
+                    "nixos-generation-10-gui.conf",
+                    "nixos-generation-9-gui.conf",
+                    "nixos-generation-10-minimal.conf",
+                ],
+            ),
+        ] {
+            let sort_key = |specialisation: &str| match (grouped, specialisation) {
+                (true, _) | (false, "") => String::from("nixos"),
+                (false, specialisation) => format!("nixos-{}", specialisation),
+            };
+            let entries = [
+                ("9", ""),
+                ("9", "gui"),
+                ("10", "gui"),
+                ("10", ""),
+                ("10", "minimal"),
+            ]
+            .iter()
+            .map(|(generation, specialisation)| {
+                let (id, generation) = match *specialisation {
+                    "" => (
+                        format!("nixos-generation-{}.conf", generation),
+                        generation.to_string(),
+                    ),
+                    specialisation => (
+                        format!("nixos-generation-{}-{}.conf", generation, specialisation),
+                        format!("{}~{}", generation, specialisation),
+                    ),
+                };
+                entry(
+                    &id,
+                    Some(sort_key(specialisation).as_str()),
+                    Some(version(format!("Generation {}", generation)).as_str()),
+                )
+            })
+            .collect();
+            let model = SdBootModel::new(entries, "", "x64");
+            assert_eq!(ids(&model), expected, "grouped: {}", grouped);
+        }
+
+        // installations are grouped by machine-id within a sort-key
+        let with_machine_id = |id, machine_id: &str, version| MenuEntry {
+            machine_id: Some(machine_id.to_string()),
+            ..entry(id, Some("nixos"), Some(version))
+        };
+        let model = SdBootModel::new(
+            vec![
+                with_machine_id("a-2.conf", "aaaa", "2"),
+                with_machine_id("b-3.conf", "bbbb", "3"),
+                with_machine_id("a-1.conf", "aaaa", "1"),
+            ],
+            "",
+            "x64",
+        );
+        assert_eq!(ids(&model), vec!["a-2.conf", "a-1.conf", "b-3.conf"]);
+
+        // entries without tries left go last, the rest by tries left and done
+        let model = SdBootModel::new(
+            vec![
+                entry("nixos-generation-2+0-3.conf", None, None),
+                entry("nixos-generation-1.conf", None, None),
+                entry("nixos-generation-2+1-2.conf", None, None),
+                entry("nixos-generation-2+2-1.conf", None, None),
+            ],
+            "",
+            "x64",
+        );
+        assert_eq!(
+            ids(&model),
+            vec![
+                "nixos-generation-2+2-1.conf",
+                "nixos-generation-2+1-2.conf",
+                "nixos-generation-1.conf",
+                "nixos-generation-2+0-3.conf",
+            ]
+        );
+
+        // entries for other architectures aren't shown
+        let model = SdBootModel::new(
+            vec![
+                MenuEntry {
+                    architecture: Some(String::from("aa64")),
+                    ..entry("arm.conf", None, None)
+                },
+                MenuEntry {
+                    architecture: Some(String::from("X64")),
+                    ..entry("x86.conf", None, None)
+                },
+                entry("any.conf", None, None),
+            ],
+            "",
+            "x64",
+        );
+        assert_eq!(ids(&model), vec!["x86.conf", "any.conf"]);
+    }
+
+    #[test]
+    fn test_default_entry() {
+        let entries = vec![
+            entry("nixos-generation-9.conf", None, None),
+            entry("nixos-generation-10.conf", None, None),
+            entry("nixos-work-generation-3.conf", None, None),
+            entry("windows.conf", None, None),
+        ];
+        let default = |loader_conf: &str| {
+            SdBootModel::new(entries.clone(), loader_conf, "x64")
+                .default_entry()
+                .map(|e| e.id.clone())
+        };
+
+        // the highest-sorting match wins
+        for (loader_conf, expected) in [
+            ("default nixos-generation-*", "nixos-generation-10.conf"),
+            ("default nixos-generation-9.conf", "nixos-generation-9.conf"),
+            ("default nixos-generation-9", "nixos-generation-9.conf"),
+            ("default\tnixos-*", "nixos-work-generation-3.conf"),
+            // the last default wins
+            (
+                "default windows.conf\ndefault nixos-generation-9.conf",
+                "nixos-generation-9.conf",
+            ),
+            // without a (matching) default, the first entry is booted
+            ("default nixos-generation-11.conf", "windows.conf"),
+            ("timeout 1", "windows.conf"),
+        ] {
+            assert_eq!(
+                default(loader_conf).as_deref(),
+                Some(expected),
+                "{}",
+                loader_conf
+            );
+        }
+
+        let model = SdBootModel::new(entries, "default nixos-generation-1*", "x64");
+        assert_eq!(
+            model
+                .matching_default()
+                .map(|e| e.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["nixos-generation-10.conf"]
+        );
+        assert_eq!(SdBootModel::new(vec![], "", "x64").default_entry(), None);
+
+        // the boot counter isn't part of the ID
+        let model = SdBootModel::new(
+            vec![entry("nixos-generation-2+2-1.conf", None, None)],
+            "default nixos-generation-2.conf",
+            "x64",
+        );
+        assert_eq!(model.matching_default().count(), 1);
+    }
+
+    #[test]
+    fn test_gapped_generations() {
+        // after `nix-env --delete-generations`, with and without a sort-key (i.e. by version, or
+        // by ID)
+        let version =
+            |generation: &str| format!("Generation {} 23.05, Built on 2023-06-01", generation);
+        for sort_key in [Some("nixos"), None] {
+            let entries = ["18", "2", "90", "17", "17~gui"]
+                .iter()
+                .map(|generation| {
+                    let id = format!("nixos-generation-{}.conf", generation.replace('~', "-"));
+                    entry(&id, sort_key, Some(&version(generation)))
+                })
+                .collect::<Vec<_>>();
+
+            let model = SdBootModel::new(entries.clone(), "default nixos-generation-*", "x64");
+            assert_eq!(
+                ids(&model),
+                vec![
+                    "nixos-generation-90.conf",
+                    "nixos-generation-18.conf",
+                    "nixos-generation-17.conf",
+                    "nixos-generation-17-gui.conf",
+                    "nixos-generation-2.conf",
+                ],
+                "{:?}",
+                sort_key
+            );
+            assert_eq!(
+                model.default_entry().map(|e| e.id.as_str()),
+                Some("nixos-generation-90.conf")
+            );
+
+            let model = SdBootModel::new(entries, "default nixos-generation-1*", "x64");
+            assert_eq!(
+                model.default_entry().map(|e| e.id.as_str()),
+                Some("nixos-generation-18.conf")
+            );
+        }
+    }
+
+    #[test]
+    fn test_supports_sort_key() {
+        assert!(!supports_sort_key(None));
+        assert!(!supports_sort_key(Some(&SystemdVersion::new("247"))));
+        assert!(!supports_sort_key(Some(&SystemdVersion::new("249.4"))));
+        assert!(!supports_sort_key(Some(&SystemdVersion::new("unknown"))));
+        assert!(supports_sort_key(Some(&SystemdVersion::new("250"))));
+        assert!(supports_sort_key(Some(&SystemdVersion::new(
+            "252.5-2-arch"
+        ))));
+    }
+
+    #[test]
+    fn test_read() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        assert_eq!(
+            SdBootModel::read(esp, None, "x64").unwrap(),
+            SdBootModel::default()
+        );
+
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::write(
+            esp.join("loader/loader.conf"),
+            "timeout 1\ndefault nixos-generation-*\n",
+        )
+        .unwrap();
+        // with a (vendor's) directive that isn't valid UTF-8, which doesn't get in the way
+        fs::write(
+            esp.join("loader/entries/nixos-generation-1.conf"),
+            &b"title NixOS\nversion Generation 1\nsort-key nixos\nx-vendor \xff\xfe\nmachine-id abc\n"[..],
+        )
+        .unwrap();
+        fs::write(esp.join("loader/entries/notes.txt"), "").unwrap();
+
+        assert_eq!(
+            SdBootModel::read(esp, None, "x64").unwrap(),
+            SdBootModel {
+                entries: vec![MenuEntry {
+                    id: String::from("nixos-generation-1.conf"),
+                    sort_key: Some(String::from("nixos")),
+                    machine_id: Some(String::from("abc")),
+                    version: Some(String::from("Generation 1")),
+                    ..Default::default()
+                }],
+                default_pattern: Some(String::from("nixos-generation-*")),
+            }
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! `set-default`: makes another entry (e.g. a previous generation's, after a bad deploy) the one
+//! systemd-boot boots by default, without installing anything: nothing is copied or pruned, and
+//! bootctl isn't run.
+
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use super::layout::Layout;
+use super::sd_boot_model::{self, MenuEntry};
+use crate::context::Context;
+use crate::Result;
+
+/// The EFI variable with the entry to boot once (see `bootctl set-oneshot`), which takes precedence
+/// over loader.conf's `default`.
+pub(crate) const ONESHOT_EFIVAR: &str =
+    "/sys/firmware/efi/efivars/LoaderEntryOneShot-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// The keys of an entry whose values are files on the partition the entry is on.
+pub(super) const FILE_KEYS: &[&str] = &["linux", "initrd", "efi", "devicetree"];
+
+/// Makes `entry` (e.g. `nixos-generation-41.conf`) the default on every ESP, by rewriting the
+/// `default` in their loader.conf, and removes the one-shot entry from `oneshot_efivar` (if any).
+///
+/// The entry, and the files it refers to, are checked on every ESP before any of them is changed.
+pub(crate) fn set_default(
+    esps: &[PathBuf],
+    xbootldr: Option<&Path>,
+    entry: &str,
+    oneshot_efivar: Option<&Path>,
+) -> Result<()> {
+    for esp in esps {
+        self::check_entry(Layout::new(esp, xbootldr), entry)?;
+    }
+
+    for esp in esps {
+        let path = esp.join("loader/loader.conf");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("'{}': {}", path.display(), e).into()),
+        };
+
+        self::write_atomically(&path, &self::with_default(&contents, entry))?;
+        info!("'{}' now boots '{}' by default", esp.display(), entry);
+    }
+
+    if let Some(efivar) = oneshot_efivar {
+        self::clear_oneshot(efivar)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that `entry` is on the partition for the generations' entries, and that the files it
+/// refers to are there too.
+pub(super) fn check_entry(layout: Layout, entry: &str) -> Result<()> {
+    let root = layout.payload_root();
+    let entries = root.join("loader/entries");
+    let path = self::find_entry(&entries, entry)?
+        .ok_or_else(|| format!("there's no entry '{}' in '{}'", entry, entries.display()))?;
+    let contents = fs::read_to_string(&path).with_path_context(&path)?;
+
+    let files = sd_boot_model::key_values(&contents)
+        .filter(|(key, _)| FILE_KEYS.contains(key))
+        .map(|(_, value)| value)
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Err(format!("'{}' doesn't boot anything", path.display()).into());
+    }
+
+    let missing = files
+        .iter()
+        .map(|file| root.join(file.trim_start_matches('/')))
+        .filter(|file| !file.exists())
+        .map(|file| format!("'{}'", file.display()))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!(
+            "'{}' refers to files that are missing: {}",
+            path.display(),
+            missing.join(", ")
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The path of `entry` in `entries`, which may have a boot counter in its filename.
+fn find_entry(entries: &Path, entry: &str) -> Result<Option<PathBuf>> {
+    if !entries.exists() {
+        return Ok(None);
+    }
+
+    for dir_entry in fs::read_dir(entries).with_path_context(entries)? {
+        let path = dir_entry.with_path_context(entries)?.path();
+        let id = match path.file_name().and_then(|name| name.to_str()) {
+            Some(id) if id.ends_with(".conf") && !path.is_dir() => id,
+            _ => continue,
+        };
+
+        if id == entry || MenuEntry::parse(id, "").id_without_tries() == entry {
+            return Ok(Some(path));
+        }
+    }
+
+    Ok(None)
+}
+
+/// `loader_conf` with `entry` as its `default`, in place of the existing one(s), and everything
+/// else (e.g. the timeout, or comments) left as it was.
+pub(super) fn with_default(loader_conf: &str, entry: &str) -> String {
+    let default = format!("default {}", entry);
+    let mut replaced = false;
+    let mut s = String::with_capacity(loader_conf.len() + default.len() + 1);
+
+    for line in loader_conf.lines() {
+        if line.split_whitespace().next() == Some("default") {
+            // The last `default` wins, so there should only be one.
+            if !replaced {
+                s.push_str(&default);
+                s.push('\n');
+                replaced = true;
+            }
+            continue;
+        }
+
+        s.push_str(line);
+        s.push('\n');
+    }
+
+    if !replaced {
+        s.push_str(&default);
+        s.push('\n');
+    }
+
+    s
+}
+
+/// Writes `contents` to `path`, so that systemd-boot reads either the previous contents or these,
+/// even if the machine goes down halfway through.
+pub(super) fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| format!("'{}' has no parent", path.display()))?;
+    fs::create_dir_all(dir).with_path_context(dir)?;
+
+    let mut tmp = tempfile::NamedTempFile::new_in(dir).with_path_context(dir)?;
+    tmp.write_all(contents.as_bytes()).with_path_context(path)?;
+    tmp.as_file().sync_all().with_path_context(path)?;
+    tmp.persist(path).with_path_context(path)?;
+
+    Ok(())
+}
+
+/// Removes the one-shot entry, so that the new default is what boots next.
+fn clear_oneshot(efivar: &Path) -> Result<()> {
+    match fs::remove_file(efivar) {
+        Ok(()) => {
+            info!("cleared the one-shot entry in '{}'", efivar.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("no one-shot entry in '{}'", efivar.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(format!(
+            "couldn't clear the one-shot entry in '{}': {} (efivarfs makes variables immutable: \
+             `chattr -i` it first)",
+            efivar.display(),
+            e
+        )
+        .into()),
+        Err(e) => Err(format!("'{}': {}", efivar.display(), e).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ESP with generation 41's and 42's entries (and their files), defaulting to 42.
+    fn esp(dir: &Path) {
+        fs::create_dir_all(dir.join("loader/entries")).unwrap();
+        fs::create_dir_all(dir.join("EFI/nixos")).unwrap();
+        fs::write(
+            dir.join("loader/loader.conf"),
+            "timeout 5\n# keep me\ndefault nixos-generation-42.conf\neditor 0\n",
+        )
+        .unwrap();
+
+        for (name, kernel, initrd) in [
+            ("nixos-generation-41.conf", "kernel-41.efi", "initrd-41.efi"),
+            ("nixos-generation-42.conf", "kernel-42.efi", "initrd-42.efi"),
+            (
+                "nixos-work-generation-41-gui+2-1.conf",
+                "kernel-41.efi",
+                "initrd-41-gui.efi",
+            ),
+        ] {
+            fs::write(
+                dir.join("loader/entries").join(name),
+                format!(
+                    "title NixOS\nlinux /EFI/nixos/{}\ninitrd /EFI/nixos/{}\n",
+                    kernel, initrd
+                ),
+            )
+            .unwrap();
+            for file in [kernel, initrd] {
+                fs::write(dir.join("EFI/nixos").join(file), file).unwrap();
+            }
+        }
+    }
+
+    fn loader_conf(esp: &Path) -> String {
+        fs::read_to_string(esp.join("loader/loader.conf")).unwrap()
+    }
+
+    #[test]
+    fn test_with_default() {
+        assert_eq!(
+            with_default("timeout 5\ndefault a.conf\neditor 0\n", "b.conf"),
+            "timeout 5\ndefault b.conf\neditor 0\n"
+        );
+        assert_eq!(
+            with_default("default a.conf\n  default\tc.conf\n", "b.conf"),
+            "default b.conf\n"
+        );
+        assert_eq!(
+            with_default("timeout 5", "b.conf"),
+            "timeout 5\ndefault b.conf\n"
+        );
+        assert_eq!(with_default("", "b.conf"), "default b.conf\n");
+        // not a `default` line
+        assert_eq!(
+            with_default("default-entry x\n", "b.conf"),
+            "default-entry x\ndefault b.conf\n"
+        );
+    }
+
+    #[test]
+    fn test_set_default() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        self::esp(&esp);
+        let esps = [esp.clone()];
+
+        set_default(&esps, None, "nixos-generation-41.conf", None).unwrap();
+        assert_eq!(
+            loader_conf(&esp),
+            "timeout 5\n# keep me\ndefault nixos-generation-41.conf\neditor 0\n"
+        );
+
+        // the boot counter isn't part of the entry's ID
+        set_default(&esps, None, "nixos-work-generation-41-gui.conf", None).unwrap();
+        assert!(loader_conf(&esp).contains("\ndefault nixos-work-generation-41-gui.conf\n"));
+
+        // nothing else is touched
+        let mut names = fs::read_dir(&esp)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["EFI", "loader"]);
+        assert_eq!(fs::read_dir(esp.join("loader")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_set_default_checks_first() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (esp, mirror) = (tempdir.path().join("esp"), tempdir.path().join("mirror"));
+        self::esp(&esp);
+        self::esp(&mirror);
+        let before = loader_conf(&esp);
+        let esps = [esp.clone(), mirror.clone()];
+
+        let err = set_default(&esps, None, "nixos-generation-40.conf", None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("there's no entry"), "{}", err);
+
+        // the mirror is missing a file, so neither ESP is changed
+        fs::remove_file(mirror.join("EFI/nixos/initrd-41.efi")).unwrap();
+        let err = set_default(&esps, None, "nixos-generation-41.conf", None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("initrd-41.efi"), "{}", err);
+        assert!(!err.contains("kernel-41.efi"), "{}", err);
+        assert_eq!(loader_conf(&esp), before);
+        assert_eq!(loader_conf(&mirror), before);
+
+        fs::write(
+            esp.join("loader/entries/nixos-generation-40.conf"),
+            "title NixOS\n",
+        )
+        .unwrap();
+        let err = set_default(&esps[..1], None, "nixos-generation-40.conf", None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("doesn't boot anything"), "{}", err);
+        assert_eq!(loader_conf(&esp), before);
+    }
+
+    #[test]
+    fn test_set_default_xbootldr() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (esp, xbootldr) = (tempdir.path().join("efi"), tempdir.path().join("boot"));
+        self::esp(&xbootldr);
+        fs::create_dir_all(&esp).unwrap();
+
+        set_default(
+            &[esp.clone()],
+            Some(&xbootldr),
+            "nixos-generation-41.conf",
+            None,
+        )
+        .unwrap();
+        assert_eq!(loader_conf(&esp), "default nixos-generation-41.conf\n");
+        // loader.conf is only read from the ESP
+        assert!(loader_conf(&xbootldr).contains("\ndefault nixos-generation-42.conf\n"));
+    }
+
+    #[test]
+    fn test_clear_oneshot() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        self::esp(&esp);
+        let efivar = tempdir.path().join("LoaderEntryOneShot");
+        fs::write(&efivar, "nixos-generation-42.conf").unwrap();
+
+        // the entry is checked first
+        assert!(set_default(
+            &[esp.clone()],
+            None,
+            "nixos-generation-40.conf",
+            Some(&efivar)
+        )
+        .is_err());
+        assert!(efivar.exists());
+
+        set_default(
+            &[esp.clone()],
+            None,
+            "nixos-generation-41.conf",
+            Some(&efivar),
+        )
+        .unwrap();
+        assert!(!efivar.exists());
+
+        // there being no one-shot entry is fine
+        set_default(&[esp], None, "nixos-generation-41.conf", Some(&efivar)).unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+//! A/B slots (`--slot`): two independent installs side by side on one ESP, e.g. for image-based
+//! updates, where the slot that isn't booted is installed to and then switched to with
+//! `activate-slot`.
+//!
+//! Everything a slot's install manages is namespaced: its entries are `nixos-<slot>-…` (e.g.
+//! `nixos-a-generation-42.conf`), and its kernels, initrds, and unified EFI files are in
+//! `EFI/nixos-<slot>`. An install only ever prunes (or replaces) its own slot's files, and leaves
+//! loader.conf's `default` alone: which slot boots is only changed by `activate-slot`.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{debug, info};
+
+use super::layout::Layout;
+use super::sd_boot_model::{self, SdBootModel};
+use super::set_default;
+use crate::context::Context;
+use crate::manifest::{Manifest, ManifestExt, MANIFEST};
+use crate::Result;
+
+/// One of the two slots.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    /// What the names of the slot's entries start with, e.g. `nixos-a-`.
+    pub fn entry_prefix(self) -> String {
+        format!("nixos-{}-", self.name())
+    }
+
+    /// The directory (relative to the root of the ESP, or of the `--entry-path-prefix`) with the
+    /// slot's kernels, initrds, and unified EFI files, e.g. `EFI/nixos-a`.
+    pub fn efi_dir(self) -> PathBuf {
+        PathBuf::from(format!("{}-{}", super::EFI_DIR, self.name()))
+    }
+
+    /// The pattern in loader.conf's `default` that boots the slot's newest generation.
+    pub fn default_glob(self) -> String {
+        format!("{}generation-*", self.entry_prefix())
+    }
+
+    /// The name that the generator gave the slot's entry `name` (e.g. `nixos-generation-42.conf`
+    /// for `nixos-a-generation-42.conf`), or `None` if it isn't one of the slot's entries.
+    pub fn unslotted(self, name: &OsStr) -> Option<OsString> {
+        let rest = name.to_str()?.strip_prefix(&self.entry_prefix())?;
+
+        Some(OsString::from(format!("nixos-{}", rest)))
+    }
+}
+
+impl FromStr for Slot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a" => Ok(Slot::A),
+            "b" => Ok(Slot::B),
+            _ => Err(format!("'{}' is not a slot (expected 'a' or 'b')", s)),
+        }
+    }
+}
+
+/// Moves the generator's staging tree at `generated_entries` into `slot`'s namespace: the entries
+/// are renamed to the slot's, the kernels, initrds, and unified EFI files are moved to its EFI
+/// directory (under the `entry_path_prefix`, if any), and the entries are made to refer to them
+/// there.
+///
+/// The manifest is left out, since both slots would share it on the ESP (and it records the paths
+/// the generator staged the files at). A tree that was already moved (by a `--dry-run`, say), which
+/// has the slot's EFI directory, is left as it is.
+pub(crate) fn namespace(
+    generated_entries: &Path,
+    slot: Slot,
+    entry_path_prefix: Option<&Path>,
+) -> Result<()> {
+    let prefix = entry_path_prefix.unwrap_or_else(|| Path::new(""));
+    let from = prefix.join(super::EFI_DIR);
+    let to = prefix.join(slot.efi_dir());
+
+    if let Some(manifest) = Manifest::load(generated_entries)? {
+        if !manifest.unchanged.is_empty() {
+            return Err(
+                "--slot can't install a staging tree that the generator's --incremental left \
+                 files out of"
+                    .into(),
+            );
+        }
+
+        let path = generated_entries.join(MANIFEST);
+        debug!("leaving the manifest '{}' out of the slot", path.display());
+        fs::remove_file(&path).with_path_context(&path)?;
+    }
+
+    let efi_dir = generated_entries.join(&from);
+    let slot_efi_dir = generated_entries.join(&to);
+    if slot_efi_dir.exists() {
+        debug!(
+            "'{}' exists, so the staging tree is already in slot {}",
+            slot_efi_dir.display(),
+            slot.name()
+        );
+        return Ok(());
+    }
+    if efi_dir.exists() {
+        fs::rename(&efi_dir, &slot_efi_dir).with_paths_context(&efi_dir, &slot_efi_dir)?;
+    } else {
+        // to mark the tree as moved
+        fs::create_dir_all(&slot_efi_dir).with_path_context(&slot_efi_dir)?;
+    }
+
+    let loader_entries = generated_entries.join("loader/entries");
+    if !loader_entries.exists() {
+        return Ok(());
+    }
+
+    let (from, to) = (
+        format!("/{}/", from.display()),
+        format!("/{}/", to.display()),
+    );
+    for entry in fs::read_dir(&loader_entries).with_path_context(&loader_entries)? {
+        let path = entry.with_path_context(&loader_entries)?.path();
+        let rest = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !path.is_dir() => match name.strip_prefix("nixos-") {
+                Some(rest) => rest.to_string(),
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let contents = fs::read_to_string(&path).with_path_context(&path)?;
+        let contents = contents
+            .lines()
+            .map(|line| match line.trim().split_once(char::is_whitespace) {
+                Some((key, value)) if set_default::FILE_KEYS.contains(&key) => {
+                    format!("{} {}\n", key, value.trim().replacen(&from, &to, 1))
+                }
+                _ => format!("{}\n", line),
+            })
+            .collect::<String>();
+
+        let slotted = loader_entries.join(format!("{}{}", slot.entry_prefix(), rest));
+        fs::write(&slotted, contents).with_path_context(&slotted)?;
+        fs::remove_file(&path).with_path_context(&path)?;
+    }
+
+    Ok(())
+}
+
+/// The `default` in the loader.conf on `esp`, if it has one, which a slot's install keeps.
+pub(crate) fn current_default(esp: &Path) -> Result<Option<String>> {
+    let path = esp.join("loader/loader.conf");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("'{}': {}", path.display(), e).into()),
+    };
+
+    // The last `default` wins.
+    Ok(sd_boot_model::key_values(&contents)
+        .filter(|(key, _)| *key == "default")
+        .map(|(_, value)| value)
+        .last())
+}
+
+/// Makes `slot` the one systemd-boot boots on every ESP, by pointing loader.conf's `default` at the
+/// slot's newest generation.
+///
+/// The entry that then boots, and the files it refers to, are checked on every ESP before any of
+/// them is changed.
+pub(crate) fn activate_slot(esps: &[PathBuf], xbootldr: Option<&Path>, slot: Slot) -> Result<()> {
+    let glob = slot.default_glob();
+
+    for esp in esps {
+        let layout = Layout::new(esp, xbootldr);
+        let model = SdBootModel::read(esp, xbootldr, sd_boot_model::efi_arch())?;
+        let entry = model
+            .entries
+            .iter()
+            .find(|entry| entry.matches(&glob))
+            .ok_or_else(|| {
+                format!(
+                    "slot {} has no entries in '{}'",
+                    slot.name(),
+                    layout.payload_root().join("loader/entries").display()
+                )
+            })?;
+
+        set_default::check_entry(layout, &entry.id_without_tries())?;
+    }
+
+    for esp in esps {
+        let path = esp.join("loader/loader.conf");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("'{}': {}", path.display(), e).into()),
+        };
+
+        set_default::write_atomically(&path, &set_default::with_default(&contents, &glob))?;
+        info!("'{}' now boots slot {}", esp.display(), slot.name());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the generator stages for generation `idx`, with the kernel under `prefix`.
+    fn generate(dir: &Path, idx: usize, prefix: &str) {
+        fs::create_dir_all(dir.join(prefix).join("EFI/nixos")).unwrap();
+        fs::create_dir_all(dir.join("loader/entries")).unwrap();
+        fs::write(
+            dir.join(format!("loader/entries/nixos-generation-{}.conf", idx)),
+            format!(
+                "title NixOS\nlinux /{}EFI/nixos/kernel-{}.efi\noptions init=/EFI/nixos/init\n",
+                prefix, idx
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join(prefix)
+                .join(format!("EFI/nixos/kernel-{}.efi", idx)),
+            "kernel",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_slot() {
+        assert_eq!("a".parse(), Ok(Slot::A));
+        assert_eq!("b".parse(), Ok(Slot::B));
+        assert!("c".parse::<Slot>().is_err());
+        assert!("A".parse::<Slot>().is_err());
+    }
+
+    #[test]
+    fn test_unslotted() {
+        let unslotted = |slot: Slot, name: &str| slot.unslotted(OsStr::new(name));
+
+        assert_eq!(
+            unslotted(Slot::A, "nixos-a-generation-42.conf"),
+            Some(OsString::from("nixos-generation-42.conf"))
+        );
+        assert_eq!(
+            unslotted(Slot::B, "nixos-b-generation-42-variant-debug.conf"),
+            Some(OsString::from("nixos-generation-42-variant-debug.conf"))
+        );
+        assert_eq!(unslotted(Slot::A, "nixos-b-generation-42.conf"), None);
+        assert_eq!(unslotted(Slot::A, "nixos-generation-42.conf"), None);
+    }
+
+    #[test]
+    fn test_namespace() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        self::generate(dir, 42, "");
+        fs::write(dir.join(MANIFEST), r#"{"files": []}"#).unwrap();
+        fs::write(dir.join("loader/entries/windows.conf"), "efi /EFI/nixos/x").unwrap();
+
+        namespace(dir, Slot::B, None).unwrap();
+        assert!(!dir.join(MANIFEST).exists());
+        assert!(!dir.join("EFI/nixos").exists());
+        assert!(dir.join("EFI/nixos-b/kernel-42.efi").exists());
+        assert!(!dir.join("loader/entries/nixos-generation-42.conf").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("loader/entries/nixos-b-generation-42.conf")).unwrap(),
+            // only the paths of files on the ESP are moved
+            "title NixOS\nlinux /EFI/nixos-b/kernel-42.efi\noptions init=/EFI/nixos/init\n"
+        );
+        // not the generator's
+        assert_eq!(
+            fs::read_to_string(dir.join("loader/entries/windows.conf")).unwrap(),
+            "efi /EFI/nixos/x"
+        );
+
+        // again, e.g. after a dry run
+        namespace(dir, Slot::B, None).unwrap();
+        assert!(dir
+            .join("loader/entries/nixos-b-generation-42.conf")
+            .exists());
+        assert!(dir.join("EFI/nixos-b/kernel-42.efi").exists());
+    }
+
+    #[test]
+    fn test_namespace_with_entry_path_prefix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        self::generate(dir, 42, "boot/");
+
+        namespace(dir, Slot::A, Some(Path::new("boot"))).unwrap();
+        assert!(dir.join("boot/EFI/nixos-a/kernel-42.efi").exists());
+        assert!(
+            fs::read_to_string(dir.join("loader/entries/nixos-a-generation-42.conf"))
+                .unwrap()
+                .contains("linux /boot/EFI/nixos-a/kernel-42.efi\n")
+        );
+    }
+
+    #[test]
+    fn test_namespace_refuses_incremental() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        self::generate(dir, 42, "");
+        fs::write(
+            dir.join(MANIFEST),
+            r#"{"files": [], "unchanged": ["EFI/nixos/kernel-41.efi"]}"#,
+        )
+        .unwrap();
+
+        let err = namespace(dir, Slot::A, None).unwrap_err().to_string();
+        assert!(err.contains("--incremental"), "{}", err);
+        assert!(dir.join("EFI/nixos").exists());
+    }
+
+    #[test]
+    fn test_current_default() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path();
+        assert_eq!(current_default(esp).unwrap(), None);
+
+        fs::create_dir_all(esp.join("loader")).unwrap();
+        fs::write(
+            esp.join("loader/loader.conf"),
+            "default nixos-a-generation-*\ntimeout 5\ndefault nixos-b-generation-*\n",
+        )
+        .unwrap();
+        assert_eq!(
+            current_default(esp).unwrap().as_deref(),
+            Some("nixos-b-generation-*")
+        );
+    }
+
+    #[test]
+    fn test_activate_slot() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = tempdir.path().join("esp");
+        fs::create_dir_all(esp.join("loader")).unwrap();
+        fs::write(
+            esp.join("loader/loader.conf"),
+            "timeout 5\ndefault nixos-a-generation-*\n",
+        )
+        .unwrap();
+        for (slot, idx) in [(Slot::A, 41), (Slot::A, 42), (Slot::B, 7)] {
+            let staged = tempdir.path().join(format!("{}-{}", slot.name(), idx));
+            self::generate(&staged, idx, "");
+            namespace(&staged, slot, None).unwrap();
+
+            let efi_dir = esp.join(slot.efi_dir());
+            fs::create_dir_all(esp.join("loader/entries")).unwrap();
+            fs::create_dir_all(&efi_dir).unwrap();
+            let entry = format!(
+                "loader/entries/{}generation-{}.conf",
+                slot.entry_prefix(),
+                idx
+            );
+            fs::rename(staged.join(&entry), esp.join(&entry)).unwrap();
+            let kernel = format!("kernel-{}.efi", idx);
+            fs::rename(
+                staged.join(slot.efi_dir()).join(&kernel),
+                efi_dir.join(&kernel),
+            )
+            .unwrap();
+        }
+        let esps = [esp.clone()];
+        let default = || {
+            SdBootModel::read(&esp, None, "x64")
+                .unwrap()
+                .default_entry()
+                .map(|entry| entry.id.clone())
+        };
+        assert_eq!(default().as_deref(), Some("nixos-a-generation-42.conf"));
+
+        activate_slot(&esps, None, Slot::B).unwrap();
+        assert_eq!(
+            fs::read_to_string(esp.join("loader/loader.conf")).unwrap(),
+            "timeout 5\ndefault nixos-b-generation-*\n"
+        );
+        assert_eq!(default().as_deref(), Some("nixos-b-generation-7.conf"));
+
+        // slot a's newest generation is missing its kernel
+        fs::remove_file(esp.join("EFI/nixos-a/kernel-42.efi")).unwrap();
+        let err = activate_slot(&esps, None, Slot::A).unwrap_err().to_string();
+        assert!(err.contains("missing"), "{}", err);
+        assert_eq!(default().as_deref(), Some("nixos-b-generation-7.conf"));
+
+        fs::remove_file(esp.join("loader/entries/nixos-b-generation-7.conf")).unwrap();
+        let err = activate_slot(&esps, None, Slot::B).unwrap_err().to_string();
+        assert!(err.contains("slot b has no entries"), "{}", err);
+    }
+}
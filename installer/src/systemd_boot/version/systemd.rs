@@ -1,10 +1,11 @@
 use std::path::Path;
 use std::process::Command;
-use std::str;
 
 use log::{debug, trace};
 use regex::Regex;
 
+use crate::command;
+use crate::util;
 use crate::Result;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -19,13 +20,21 @@ impl SystemdVersion {
         }
     }
 
+    /// The major version (e.g. 249 for "249.4" or "247.4-2-arch"), if it could be parsed.
+    pub fn major(&self) -> Option<u32> {
+        let digits = self.version.split(|c: char| !c.is_ascii_digit()).next()?;
+
+        digits.parse().ok()
+    }
+
     fn from_output(output: &[u8]) -> Result<Self> {
         trace!("parsing `bootctl --version` output");
 
-        let output = str::from_utf8(output)?;
+        // e.g. a firmware's name can be garbage, but only the version is parsed out of it
+        let output = util::from_utf8_lossy(output, "the output of `bootctl --version`");
 
         let re = Regex::new("systemd [^\\s]+ \\((?P<version>[^\\)]+)\\)")?;
-        let caps = re.captures(output).ok_or("failed to get capture groups")?;
+        let caps = re.captures(&output).ok_or("failed to get capture groups")?;
 
         let version = caps
             .name("version")
@@ -41,7 +50,7 @@ impl SystemdVersion {
 
         let args = &["--version"];
         debug!("running `{}` with args `{:?}`", &bootctl.display(), args);
-        let output = Command::new(&bootctl).args(args).output()?.stdout;
+        let output = command::output(Command::new(bootctl).args(args))?.stdout;
 
         let version = Self::from_output(&output)?;
 
@@ -74,7 +83,23 @@ mod tests {
             SystemdVersion::new("247.4-2-arch")
         );
 
+        assert_eq!(
+            SystemdVersion::from_output(
+                b"systemd 252 (252.5)\n+PAM +AUDIT\nfirmware: \xff\xfeUEFI 2.70 (\xc3 Vendor 1.0)\n"
+            )
+            .unwrap(),
+            SystemdVersion::new("252.5")
+        );
+
         assert!(SystemdVersion::from_output(b"systemd (247)").is_err());
         assert!(SystemdVersion::from_output(b"systemc 247 (247)").is_err());
     }
+
+    #[test]
+    fn test_major() {
+        assert_eq!(SystemdVersion::new("247").major(), Some(247));
+        assert_eq!(SystemdVersion::new("249.4").major(), Some(249));
+        assert_eq!(SystemdVersion::new("247.4-2-arch").major(), Some(247));
+        assert_eq!(SystemdVersion::new("v250").major(), None);
+    }
 }
@@ -1,10 +1,20 @@
+use std::borrow::Cow;
+use std::env;
 use std::ffi::OsString;
+use std::fmt::Display;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{debug, trace};
+use log::{debug, info, trace};
 use regex::Regex;
 
+use crate::cli_common;
+use crate::context::Context;
+use crate::warnings::{self, Kind};
 use crate::Result;
 
 // TODO: docstrings for these functions
@@ -16,6 +26,11 @@ lazy_static::lazy_static! {
 
 const STORE_PATH_PREFIX: &str = "/nix/store/";
 const STORE_HASH_LEN: usize = 32;
+/// How often [`system_generations`] looks for the generations again while it waits for them.
+const PROFILES_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where Nix keeps the system profile and its generations.
+pub const PROFILES_DIR: &str = "/nix/var/nix/profiles";
 
 #[derive(Debug, Default, Clone, PartialEq)]
 pub struct Generation {
@@ -25,16 +40,22 @@ pub struct Generation {
     pub required_filenames: Vec<OsString>,
 }
 
+/// The generations to keep entries for: the newest `configuration_limit` ones (by index, which
+/// needn't be contiguous, e.g. after `nix-env --delete-generations`), and the default generation.
 pub fn wanted_generations(
-    generations: Vec<Generation>,
+    mut generations: Vec<Generation>,
     configuration_limit: Option<usize>,
+    default_generation: &Generation,
 ) -> Vec<Generation> {
     trace!("getting list of generations");
 
+    // The links are globbed in lexicographic order (e.g. 17 before 2), so don't count on any.
+    generations.sort_by_key(|g| g.idx);
+
     let generations_len = generations.len();
     debug!("generations_len: {}", generations_len);
 
-    let generations = if let Some(limit) = configuration_limit {
+    let mut generations = if let Some(limit) = configuration_limit {
         debug!("limiting generations to max of {}", limit);
 
         generations
@@ -45,13 +66,210 @@ pub fn wanted_generations(
         generations
     };
 
+    // The generation we're about to make the default must never be pruned, even if it falls
+    // outside of the configuration limit (e.g. when rolling back).
+    if !generations.contains(default_generation) {
+        debug!(
+            "keeping default generation {} despite configuration limit",
+            default_generation.idx
+        );
+
+        generations.push(default_generation.clone());
+        generations.sort_by_key(|g| g.idx);
+    }
+
     generations
 }
 
-pub fn all_generations(profile: Option<String>, unified: bool) -> Result<Vec<Generation>> {
+/// Finds the generation that `toplevel` refers to.
+///
+/// If `toplevel` is a profile link (e.g. `/nix/var/nix/profiles/system-42-link`), or a link to one
+/// (e.g. `/nix/var/nix/profiles/system`), that's the generation. Otherwise, it's a generation whose
+/// path resolves to the same store path as `toplevel`; since the same closure can be in several
+/// generations (e.g. after a rollback and rebuild, or in several profiles), the one with the
+/// highest index in the default profile wins.
+pub fn default_generation<'a>(
+    generations: &'a [Generation],
+    toplevel: &Path,
+) -> Result<&'a Generation> {
+    let mut links = vec![toplevel.to_path_buf()];
+    if let Ok(target) = fs::read_link(toplevel) {
+        links.push(match toplevel.parent() {
+            Some(parent) => self::clean_path(&parent.join(target)),
+            None => target,
+        });
+    }
+
+    if let Some(generation) = generations.iter().find(|g| links.contains(&g.path)) {
+        debug!(
+            "'{}' is the profile link of generation {}",
+            toplevel.display(),
+            generation.idx
+        );
+
+        return Ok(generation);
+    }
+
+    let toplevel = fs::canonicalize(toplevel).with_path_context(toplevel)?;
+    let matching = generations
+        .iter()
+        .filter(|generation| fs::canonicalize(&generation.path).ok().as_ref() == Some(&toplevel))
+        .collect::<Vec<_>>();
+
+    let generation = matching
+        .iter()
+        .filter(|g| g.profile.is_none())
+        .max_by_key(|g| g.idx)
+        .or_else(|| matching.iter().max_by_key(|g| g.idx))
+        .ok_or_else(|| {
+            format!(
+                "couldn't find generation that corresponds to the provided toplevel '{}'",
+                toplevel.display()
+            )
+        })?;
+
+    if matching.len() > 1 {
+        info!(
+            "'{}' is in generations {}; using generation {}",
+            toplevel.display(),
+            matching
+                .iter()
+                .map(|g| g.idx.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            generation.idx
+        );
+    }
+
+    Ok(generation)
+}
+
+/// Parses the `--configuration-limit` argument, rejecting a limit of 0 (which would remove every
+/// entry, leaving the system unbootable).
+pub fn parse_configuration_limit(s: &str) -> Result<usize, String> {
+    let limit = s
+        .parse::<usize>()
+        .map_err(|e| format!("invalid configuration limit '{}': {}", s, e))?;
+
+    if limit == 0 {
+        return Err(String::from(
+            "the configuration limit must be at least 1 (omit --configuration-limit to keep all generations)",
+        ));
+    }
+
+    Ok(limit)
+}
+
+/// Parses the `--copy-jobs` argument, rejecting 0 (which would copy nothing).
+pub fn parse_copy_jobs(s: &str) -> Result<usize, String> {
+    let jobs = s
+        .parse::<usize>()
+        .map_err(|e| format!("invalid number of copy jobs '{}': {}", s, e))?;
+
+    if jobs == 0 {
+        return Err(String::from("at least 1 file must be copied at a time"));
+    }
+
+    Ok(jobs)
+}
+
+/// Parses the `--command-timeout` argument (a duration, see [`cli_common::parse_duration`]),
+/// rejecting a timeout of 0 (which would kill every command right away).
+pub fn parse_command_timeout(s: &str) -> Result<Duration, String> {
+    let timeout = cli_common::parse_duration(s)?;
+
+    if timeout.is_zero() {
+        return Err(String::from(
+            "the command timeout must be at least 1 second",
+        ));
+    }
+
+    Ok(timeout)
+}
+
+/// Parses the name of a profile in `/nix/var/nix/profiles/system-profiles`.
+pub fn parse_profile_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains('-') || s.contains('/') {
+        return Err(format!(
+            "'{}' is not a profile name (it must be non-empty and not contain '-' or '/')",
+            s
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+/// Every generation of the system profile in `profiles_dir`, waiting up to `wait` for there to be
+/// any, since `profiles_dir` may be a mount that isn't there yet (e.g. with impermanence, where
+/// `/nix/var` is bind-mounted from persistent storage).
+///
+/// If there are none, the error says whether `profiles_dir` is missing, unreadable, or really
+/// doesn't have any, which are all the same to a glob.
+pub fn system_generations(
+    profiles_dir: &Path,
+    unified: bool,
+    wait: Option<Duration>,
+) -> Result<Vec<Generation>> {
+    let deadline = Instant::now() + wait.unwrap_or_default();
+
+    loop {
+        let err = match self::check_profiles_dir(profiles_dir) {
+            Ok(()) => {
+                let generations = self::all_generations(profiles_dir, None, unified)?;
+                if !generations.is_empty() {
+                    return Ok(generations);
+                }
+
+                format!(
+                    "there are no generations of the system profile in '{}' (if it's a mount point \
+                     that isn't mounted yet, pass --wait-for-profiles)",
+                    profiles_dir.display()
+                )
+            }
+            Err(e) => e,
+        };
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(err.into());
+        }
+
+        debug!("{}; looking again", err);
+        thread::sleep(PROFILES_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Checks that `profiles_dir` is there and can be read, so that there being no generations in it
+/// means there really are none.
+fn check_profiles_dir(profiles_dir: &Path) -> Result<(), String> {
+    match fs::read_dir(profiles_dir) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(format!(
+            "'{}' doesn't exist, so there are no generations to install (if it's mounted later, \
+             e.g. with impermanence, pass --wait-for-profiles)",
+            profiles_dir.display()
+        )),
+        Err(e) => Err(format!(
+            "couldn't read '{}' to find the generations to install: {} (if it's mounted later, \
+             e.g. with impermanence, pass --wait-for-profiles)",
+            profiles_dir.display(),
+            e
+        )),
+    }
+}
+
+/// Every generation of `profile` (or the system profile) in `profiles_dir`, oldest first.
+pub fn all_generations(
+    profiles_dir: &Path,
+    profile: Option<String>,
+    unified: bool,
+) -> Result<Vec<Generation>> {
     let mut generations = Vec::new();
-    let profile_path = self::profile_path(&profile);
-    let pat = format!("{}-*-link", profile_path);
+    let profile_path = self::profile_path(profiles_dir, &profile);
+    let pat = format!(
+        "{}-*-link",
+        glob::Pattern::escape(&profile_path.display().to_string())
+    );
 
     for entry in glob::glob(&pat)? {
         let path = entry?;
@@ -70,7 +288,7 @@ pub fn all_generations(profile: Option<String>, unified: bool) -> Result<Vec<Gen
         };
 
         let required_filenames = if unified {
-            let path = fs::canonicalize(&path)?;
+            let path = fs::canonicalize(&path).with_path_context(&path)?;
             let filename = format!(
                 "{}.efi",
                 &path.display().to_string().replace(STORE_PATH_PREFIX, "")[..STORE_HASH_LEN]
@@ -78,9 +296,11 @@ pub fn all_generations(profile: Option<String>, unified: bool) -> Result<Vec<Gen
 
             vec![filename.into(), conf_filename.into()]
         } else {
-            let kernel_path = fs::canonicalize(path.join("kernel"))?;
+            let kernel_path =
+                fs::canonicalize(path.join("kernel")).with_path_context(path.join("kernel"))?;
             let kernel_filename = self::store_path_to_efi_filename(kernel_path)?;
-            let initrd_path = fs::canonicalize(path.join("initrd"))?;
+            let initrd_path =
+                fs::canonicalize(path.join("initrd")).with_path_context(path.join("initrd"))?;
             let initrd_filename = self::store_path_to_efi_filename(initrd_path)?;
 
             vec![kernel_filename, initrd_filename, conf_filename.into()]
@@ -99,6 +319,46 @@ pub fn all_generations(profile: Option<String>, unified: bool) -> Result<Vec<Gen
     Ok(generations)
 }
 
+/// The profiles in `profiles_dir`: the system profile (as `None`) first, and then those in its
+/// `system-profiles`, by name.
+pub fn profiles(profiles_dir: &Path) -> Result<Vec<Option<String>>> {
+    let mut profiles = vec![None];
+    let system_profiles = profiles_dir.join("system-profiles");
+    if system_profiles.is_dir() {
+        for entry in fs::read_dir(&system_profiles).with_path_context(&system_profiles)? {
+            let name = entry.with_path_context(&system_profiles)?.file_name();
+            match name.to_str() {
+                Some(name) if !name.ends_with("-link") => profiles.push(Some(name.to_string())),
+                _ => {}
+            }
+        }
+    }
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+/// The generations to keep entries for of the profiles in `system-profiles` in `profiles_dir`: the
+/// newest `configuration_limit` of each (by index). Unlike the system profile, they have no default
+/// generation to keep besides.
+pub fn profile_generations(
+    profiles_dir: &Path,
+    unified: bool,
+    configuration_limit: Option<usize>,
+) -> Result<Vec<Generation>> {
+    let mut wanted = Vec::new();
+    for profile in self::profiles(profiles_dir)?.into_iter().flatten() {
+        let generations = self::all_generations(profiles_dir, Some(profile), unified)?;
+        let skip = match configuration_limit {
+            Some(limit) => generations.len().saturating_sub(limit),
+            None => 0,
+        };
+        wanted.extend(generations.into_iter().skip(skip));
+    }
+
+    Ok(wanted)
+}
+
 pub fn store_path_to_efi_filename(path: PathBuf) -> Result<OsString> {
     let s = path.to_string_lossy();
 
@@ -111,11 +371,11 @@ pub fn store_path_to_efi_filename(path: PathBuf) -> Result<OsString> {
     Ok(s.into())
 }
 
-pub fn profile_path(profile: &Option<String>) -> String {
+pub fn profile_path(profiles_dir: &Path, profile: &Option<String>) -> PathBuf {
     if let Some(ref profile) = profile {
-        format!("/nix/var/nix/profiles/system-profiles/{}", profile)
+        profiles_dir.join("system-profiles").join(profile)
     } else {
-        String::from("/nix/var/nix/profiles/system")
+        profiles_dir.join("system")
     }
 }
 
@@ -137,26 +397,146 @@ where
         .parent()
         .ok_or(format!("Path '{}' had no parent", path.display()))?;
 
-    fs::create_dir_all(dir)?;
+    fs::create_dir_all(dir).with_path_context(dir)?;
 
     Ok(())
 }
 
+/// Normalizes a path passed on the command line, so that e.g. `/boot`, `/boot/`, and `../../boot`
+/// all end up the same: paths that exist are canonicalized, the rest are made absolute and cleaned
+/// up lexically (`.` and `..` components, repeated and trailing separators).
+pub fn normalize_path(s: &str) -> Result<PathBuf, String> {
+    if s.is_empty() {
+        return Err(String::from("the path must not be empty"));
+    }
+
+    if let Ok(canonical) = fs::canonicalize(s) {
+        return Ok(canonical);
+    }
+
+    self::absolute_path(s)
+}
+
+/// Like [`normalize_path`], but never resolves symlinks (e.g. to keep a profile link a profile
+/// link).
+pub fn absolute_path(s: &str) -> Result<PathBuf, String> {
+    if s.is_empty() {
+        return Err(String::from("the path must not be empty"));
+    }
+
+    let path = Path::new(s);
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()
+            .map_err(|e| format!("couldn't get the current directory: {}", e))?
+            .join(path)
+    };
+
+    Ok(self::clean_path(&absolute))
+}
+
+/// Removes `.` and `..` components from `path` without touching the filesystem.
+fn clean_path(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                cleaned.pop();
+            }
+            component => cleaned.push(component),
+        }
+    }
+
+    cleaned
+}
+
+/// Drops the ESPs that are on the same filesystem as an earlier one (e.g. `/boot` and a symlink to
+/// it), warning about each: the plan for the second one would see what the first one did to it.
+pub fn dedupe_esps(esps: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut deduped: Vec<(u64, PathBuf)> = Vec::new();
+
+    for esp in esps {
+        let canonical = fs::canonicalize(esp).with_path_context(esp)?;
+        let dev = fs::metadata(&canonical)
+            .with_path_context(&canonical)?
+            .dev();
+
+        if let Some((_, first)) = deduped.iter().find(|(first_dev, _)| *first_dev == dev) {
+            warnings::emit(
+                Kind::DuplicateEsp,
+                format!(
+                    "ignoring ESP '{}': it is on the same filesystem as '{}'",
+                    esp.display(),
+                    first.display()
+                ),
+            );
+            continue;
+        }
+
+        deduped.push((dev, canonical));
+    }
+
+    Ok(deduped.into_iter().map(|(_, esp)| esp).collect())
+}
+
 /// Copies `source` to `dest` with a ".tmp" file extension, and then atomically moves it to the desired location.
 pub fn atomic_tmp_copy_file(source: &Path, dest: &Path) -> Result<()> {
     let tmp_dest = dest.with_extension("tmp");
 
     if tmp_dest.exists() {
-        fs::remove_file(&tmp_dest)?;
+        fs::remove_file(&tmp_dest).with_path_context(&tmp_dest)?;
     }
 
     self::create_dirs_to_file(dest)?;
-    fs::copy(source, &tmp_dest)?;
-    fs::rename(tmp_dest, dest)?;
+    fs::copy(source, &tmp_dest).with_paths_context(source, &tmp_dest)?;
+    fs::rename(&tmp_dest, dest).with_paths_context(&tmp_dest, dest)?;
+
+    Ok(())
+}
+
+/// Gives `path` an inode of its own if it is hard linked elsewhere (e.g. because the generator
+/// shares identical files between staging trees), so it can be modified in place without also
+/// modifying the other links.
+pub fn unshare_file(path: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path).with_path_context(path)?;
+
+    if metadata.is_file() && metadata.nlink() > 1 {
+        debug!("copying hard linked file '{}'", path.display());
+        self::atomic_tmp_copy_file(path, path)?;
+    }
 
     Ok(())
 }
 
+/// Decodes `bytes` as UTF-8, replacing invalid sequences with U+FFFD (with a warning naming
+/// `what`). What gets parsed out of e.g. `bootctl` output and entries is ASCII, so garbage
+/// elsewhere (like a firmware's name) shouldn't be fatal.
+pub fn from_utf8_lossy(bytes: &[u8], what: impl Display) -> String {
+    match String::from_utf8_lossy(bytes) {
+        Cow::Borrowed(s) => s.to_string(),
+        Cow::Owned(s) => {
+            warnings::emit(
+                Kind::InvalidUtf8,
+                format!("{} isn't valid UTF-8; ignoring the parts that aren't", what),
+            );
+            s
+        }
+    }
+}
+
+/// Reads the file at `path` with [`from_utf8_lossy`], for files that are only parsed (never
+/// rewritten, which would replace the invalid parts for good).
+pub fn read_to_string_lossy(path: &Path) -> io::Result<String> {
+    let bytes = fs::read(path)?;
+
+    Ok(self::from_utf8_lossy(
+        &bytes,
+        format_args!("'{}'", path.display()),
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,18 +578,197 @@ mod tests {
         ];
 
         for generations in gens {
-            let ret_generations = super::wanted_generations(generations.clone(), None);
+            let default_generation = &generations[1];
+            let ret_generations =
+                super::wanted_generations(generations.clone(), None, default_generation);
             assert_eq!(ret_generations.len(), 2);
             assert_eq!(ret_generations[0], generations[0]);
             assert_eq!(ret_generations[1], generations[1]);
 
-            let ret_generations = super::wanted_generations(generations.clone(), Some(1));
+            let ret_generations =
+                super::wanted_generations(generations.clone(), Some(1), default_generation);
             assert_eq!(ret_generations.len(), 1);
             assert_eq!(ret_generations[0], generations[1]);
             assert_eq!(ret_generations.get(1), None);
         }
     }
 
+    #[test]
+    fn test_wanted_generations_keeps_default() {
+        let generations = (1..=5)
+            .map(|idx| Generation {
+                idx,
+                profile: None,
+                path: PathBuf::from(idx.to_string()),
+                required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+            })
+            .collect::<Vec<_>>();
+
+        // default is the newest generation
+        let ret_generations = wanted_generations(generations.clone(), Some(1), &generations[4]);
+        assert_eq!(ret_generations, vec![generations[4].clone()]);
+
+        // default is older than the configuration limit allows (e.g. a rollback)
+        let ret_generations = wanted_generations(generations.clone(), Some(1), &generations[1]);
+        assert_eq!(
+            ret_generations,
+            vec![generations[1].clone(), generations[4].clone()]
+        );
+
+        let ret_generations = wanted_generations(generations.clone(), Some(2), &generations[0]);
+        assert_eq!(
+            ret_generations,
+            vec![
+                generations[0].clone(),
+                generations[3].clone(),
+                generations[4].clone()
+            ]
+        );
+
+        // a limit larger than the number of generations keeps everything
+        let ret_generations = wanted_generations(generations.clone(), Some(100), &generations[2]);
+        assert_eq!(ret_generations, generations);
+    }
+
+    #[test]
+    fn test_wanted_generations_with_gaps() {
+        // as globbed after `nix-env --delete-generations`
+        let generations = [17, 18, 2, 90]
+            .iter()
+            .map(|&idx| Generation {
+                idx,
+                profile: None,
+                path: PathBuf::from(format!("system-{}-link", idx)),
+                required_filenames: vec![OsString::from(format!("nixos-generation-{}.conf", idx))],
+            })
+            .collect::<Vec<_>>();
+        let idxs =
+            |generations: Vec<Generation>| generations.iter().map(|g| g.idx).collect::<Vec<_>>();
+
+        for (limit, default, expected) in [
+            (None, 3, vec![2, 17, 18, 90]),
+            (Some(3), 3, vec![17, 18, 90]),
+            (Some(1), 3, vec![90]),
+            (Some(100), 3, vec![2, 17, 18, 90]),
+            // rolled back past the limit
+            (Some(3), 2, vec![2, 17, 18, 90]),
+            (Some(1), 1, vec![18, 90]),
+        ] {
+            assert_eq!(
+                idxs(wanted_generations(
+                    generations.clone(),
+                    limit,
+                    &generations[default]
+                )),
+                expected,
+                "limit {:?}, default {}",
+                limit,
+                generations[default].idx
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_generation() {
+        use std::os::unix::fs::symlink;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(tempdir.path()).unwrap();
+        let store = dir.join("store");
+        let profiles = dir.join("profiles");
+        fs::create_dir_all(store.join("a")).unwrap();
+        fs::create_dir_all(store.join("b")).unwrap();
+        fs::create_dir_all(profiles.join("system-profiles")).unwrap();
+
+        // generations 1 and 3 of the system profile, and generation 7 of another one, are the same
+        // closure
+        let generation = |idx: usize, profile: Option<&str>, closure: &str| {
+            let path = match profile {
+                Some(profile) => profiles.join(format!("system-profiles/{}-{}-link", profile, idx)),
+                None => profiles.join(format!("system-{}-link", idx)),
+            };
+            symlink(store.join(closure), &path).unwrap();
+
+            Generation {
+                idx,
+                profile: profile.map(ToString::to_string),
+                path,
+                ..Default::default()
+            }
+        };
+        let generations = vec![
+            generation(1, None, "a"),
+            generation(2, None, "b"),
+            generation(3, None, "a"),
+            generation(7, Some("work"), "a"),
+        ];
+        let system = profiles.join("system");
+        symlink("system-1-link", &system).unwrap();
+
+        let default = |toplevel: &Path| default_generation(&generations, toplevel).unwrap();
+
+        // a shared closure picks the newest generation of the default profile
+        assert_eq!(default(&store.join("a")), &generations[2]);
+        assert_eq!(default(&store.join("b")), &generations[1]);
+        assert_eq!(default(&store.join("a/")), &generations[2]);
+
+        // a profile link is exactly that generation, even if the closure is shared
+        assert_eq!(default(&generations[0].path), &generations[0]);
+        assert_eq!(default(&generations[3].path), &generations[3]);
+        assert_eq!(default(&system), &generations[0]);
+
+        // only other profiles have the closure
+        assert_eq!(
+            default_generation(&generations[3..], &store.join("a")).unwrap(),
+            &generations[3]
+        );
+
+        fs::create_dir(store.join("c")).unwrap();
+        assert!(default_generation(&generations, &store.join("c")).is_err());
+        assert!(default_generation(&generations, &store.join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_parse_configuration_limit() {
+        assert!(parse_configuration_limit("0")
+            .unwrap_err()
+            .contains("omit --configuration-limit"));
+        assert_eq!(parse_configuration_limit("1"), Ok(1));
+        assert_eq!(parse_configuration_limit("1000"), Ok(1000));
+        assert!(parse_configuration_limit("-1").is_err());
+        assert!(parse_configuration_limit("many").is_err());
+    }
+
+    #[test]
+    fn test_parse_copy_jobs() {
+        assert_eq!(parse_copy_jobs("1"), Ok(1));
+        assert_eq!(parse_copy_jobs("16"), Ok(16));
+        assert!(parse_copy_jobs("0").is_err());
+        assert!(parse_copy_jobs("-1").is_err());
+        assert!(parse_copy_jobs("all").is_err());
+    }
+
+    #[test]
+    fn test_parse_command_timeout() {
+        assert_eq!(parse_command_timeout("60"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_command_timeout("1"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_command_timeout("5m"), Ok(Duration::from_secs(300)));
+        assert!(parse_command_timeout("0")
+            .unwrap_err()
+            .contains("at least 1"));
+        assert!(parse_command_timeout("0s").is_err());
+        assert!(parse_command_timeout("-1").is_err());
+        assert!(parse_command_timeout("1.5").is_err());
+    }
+
+    #[test]
+    fn test_parse_profile_name() {
+        assert_eq!(parse_profile_name("work"), Ok(String::from("work")));
+        assert!(parse_profile_name("").is_err());
+        assert!(parse_profile_name("my-work").is_err());
+        assert!(parse_profile_name("../work").is_err());
+    }
+
     #[test]
     fn test_create_dirs_to_file1() {
         let tempdir = tempfile::tempdir().unwrap();
@@ -318,15 +877,127 @@ mod tests {
         assert_eq!(contents, "2");
     }
 
+    #[test]
+    fn test_atomic_tmp_copy_file_errors() {
+        let source_tempdir = tempfile::tempdir().unwrap();
+        let dest_tempdir = tempfile::tempdir().unwrap();
+        let source = source_tempdir
+            .path()
+            .join("EFI/nixos/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.efi");
+        let dest = dest_tempdir
+            .path()
+            .join("EFI/nixos/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.efi");
+
+        // missing kernel
+        let err = atomic_tmp_copy_file(&source, &dest).unwrap_err();
+        assert!(err.to_string().contains(&source.display().to_string()));
+
+        // unwritable ESP file (its "parent directory" is a file)
+        let dest_tempdir = tempfile::tempdir().unwrap();
+        create_dirs_to_file(&source).unwrap();
+        File::create(&source).unwrap();
+        File::create(dest_tempdir.path().join("EFI")).unwrap();
+        let err = atomic_tmp_copy_file(
+            &source,
+            &dest_tempdir
+                .path()
+                .join("EFI/nixos/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.efi"),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&dest_tempdir.path().join("EFI/nixos").display().to_string()));
+    }
+
     #[test]
     fn test_profile_path() {
-        assert_eq!(profile_path(&None), "/nix/var/nix/profiles/system");
+        let profiles_dir = Path::new(PROFILES_DIR);
+        assert_eq!(
+            profile_path(profiles_dir, &None),
+            Path::new("/nix/var/nix/profiles/system")
+        );
+        assert_eq!(
+            profile_path(profiles_dir, &Some(String::from("user"))),
+            Path::new("/nix/var/nix/profiles/system-profiles/user")
+        );
+    }
+
+    /// Links generation `idx` of the system profile in `profiles_dir` to a (unified) system.
+    fn link_generation(profiles_dir: &Path, idx: usize) {
+        let system = profiles_dir.join(format!("{}-system", "a".repeat(STORE_HASH_LEN)));
+        fs::create_dir_all(&system).unwrap();
+        std::os::unix::fs::symlink(&system, profiles_dir.join(format!("system-{}-link", idx)))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_system_generations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let profiles_dir = tempdir.path().join("profiles");
+
+        let err = system_generations(&profiles_dir, true, None).unwrap_err();
+        assert!(err.to_string().contains("doesn't exist"), "{}", err);
+
+        fs::create_dir(&profiles_dir).unwrap();
+        let err = system_generations(&profiles_dir, true, None).unwrap_err();
+        assert!(
+            err.to_string().contains("there are no generations"),
+            "{}",
+            err
+        );
+
+        link_generation(&profiles_dir, 1);
+        link_generation(&profiles_dir, 2);
+        let generations = system_generations(&profiles_dir, true, None).unwrap();
         assert_eq!(
-            profile_path(&Some(String::from("user"))),
-            "/nix/var/nix/profiles/system-profiles/user"
+            generations.iter().map(|g| g.idx).collect::<Vec<_>>(),
+            [1, 2]
         );
     }
 
+    #[test]
+    fn test_system_generations_unreadable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let profiles_dir = tempdir.path().join("profiles");
+        fs::create_dir(&profiles_dir).unwrap();
+        fs::set_permissions(&profiles_dir, fs::Permissions::from_mode(0o000)).unwrap();
+
+        // root can read it anyway
+        if fs::read_dir(&profiles_dir).is_err() {
+            let err = system_generations(&profiles_dir, true, None).unwrap_err();
+            assert!(err.to_string().contains("couldn't read"), "{}", err);
+        }
+
+        fs::set_permissions(&profiles_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_system_generations_waits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let profiles_dir = tempdir.path().join("profiles");
+
+        // it gives up once the wait is over
+        let err =
+            system_generations(&profiles_dir, true, Some(Duration::from_millis(10))).unwrap_err();
+        assert!(err.to_string().contains("doesn't exist"), "{}", err);
+
+        // and otherwise finds them when they're mounted
+        let mount = {
+            let profiles_dir = profiles_dir.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                fs::create_dir(&profiles_dir).unwrap();
+                link_generation(&profiles_dir, 1);
+            })
+        };
+        let generations =
+            system_generations(&profiles_dir, true, Some(Duration::from_secs(30))).unwrap();
+        mount.join().unwrap();
+        assert_eq!(generations.len(), 1);
+    }
+
     #[test]
     fn test_store_path_to_efi_filename() {
         assert_eq!(
@@ -338,4 +1009,124 @@ mod tests {
         );
         assert!(store_path_to_efi_filename(PathBuf::from("/foo/bar")).is_err());
     }
+
+    #[test]
+    fn test_unshare_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let original = tempdir.path().join("original.efi");
+        let link = tempdir.path().join("link.efi");
+        fs::write(&original, "unsigned").unwrap();
+        fs::hard_link(&original, &link).unwrap();
+
+        unshare_file(&link).unwrap();
+        assert_ne!(
+            fs::metadata(&original).unwrap().ino(),
+            fs::metadata(&link).unwrap().ino()
+        );
+        fs::write(&link, "signed").unwrap();
+        assert_eq!(fs::read_to_string(&original).unwrap(), "unsigned");
+
+        // a file without other links is left alone
+        let ino = fs::metadata(&original).unwrap().ino();
+        unshare_file(&original).unwrap();
+        assert_eq!(fs::metadata(&original).unwrap().ino(), ino);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let esp = fs::canonicalize(tempdir.path()).unwrap().join("esp");
+        fs::create_dir(&esp).unwrap();
+        let missing = esp.join("missing");
+
+        // relative to the current directory
+        let up = env::current_dir()
+            .unwrap()
+            .components()
+            .skip(1)
+            .map(|_| "..")
+            .collect::<Vec<_>>()
+            .join("/");
+        let relative = |path: &Path| format!("{}{}", up, path.display());
+
+        for (s, expected) in [
+            (esp.display().to_string(), &esp),
+            (format!("{}/", esp.display()), &esp),
+            (format!("{}//.//", esp.display()), &esp),
+            (format!("{}/../esp", esp.display()), &esp),
+            (relative(&esp), &esp),
+            (missing.display().to_string(), &missing),
+            (format!("{}/", missing.display()), &missing),
+            (format!("{}/./a/../", missing.display()), &missing),
+            (relative(&missing), &missing),
+        ] {
+            assert_eq!(&normalize_path(&s).unwrap(), expected, "{}", s);
+        }
+
+        assert!(normalize_path("").is_err());
+
+        // symlinks are kept by absolute_path
+        let link = esp.join("link");
+        std::os::unix::fs::symlink(&missing, &link).unwrap();
+        assert_eq!(normalize_path(&link.display().to_string()).unwrap(), link);
+        assert_eq!(
+            absolute_path(&format!("{}/../esp/link", esp.display())).unwrap(),
+            link
+        );
+        assert!(absolute_path("").is_err());
+    }
+
+    #[test]
+    fn test_dedupe_esps() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(tempdir.path()).unwrap();
+        let esp = dir.join("esp");
+        let alias = dir.join("alias");
+        fs::create_dir(&esp).unwrap();
+        std::os::unix::fs::symlink(&esp, &alias).unwrap();
+
+        // literal duplicates
+        assert_eq!(
+            dedupe_esps(&[esp.clone(), esp.clone()]).unwrap(),
+            vec![esp.clone()]
+        );
+        // a symlinked alias
+        assert_eq!(
+            dedupe_esps(&[alias.clone(), esp.clone()]).unwrap(),
+            vec![esp.clone()]
+        );
+        // another directory on the same filesystem
+        fs::create_dir(dir.join("other")).unwrap();
+        assert_eq!(
+            dedupe_esps(&[esp.clone(), dir.join("other")]).unwrap(),
+            vec![esp.clone()]
+        );
+
+        assert!(dedupe_esps(&[dir.join("missing")]).is_err());
+    }
+
+    #[test]
+    fn test_read_to_string_lossy() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("nixos-generation-1.conf");
+
+        fs::write(&path, "title NixOS\n").unwrap();
+        assert_eq!(read_to_string_lossy(&path).unwrap(), "title NixOS\n");
+
+        fs::write(&path, b"title NixOS \xff\xfe\nsort-key nixos\n").unwrap();
+        assert_eq!(
+            read_to_string_lossy(&path).unwrap(),
+            "title NixOS \u{fffd}\u{fffd}\nsort-key nixos\n"
+        );
+        assert!(warnings::emitted(Kind::InvalidUtf8).contains(&format!(
+            "'{}' isn't valid UTF-8; ignoring the parts that aren't",
+            path.display()
+        )));
+
+        let missing = tempdir.path().join("missing");
+        assert_eq!(
+            read_to_string_lossy(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
 }
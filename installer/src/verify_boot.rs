@@ -0,0 +1,449 @@
+//! Boot-verifying an ESP (`verify-boot`), e.g. in the CI of images: the ESP is booted in a transient
+//! QEMU VM with OVMF, and the boot passes once one of the markers (by default, systemd-boot's menu
+//! or the kernel's banner) shows up on the serial console.
+//!
+//! The ESP is either a directory, which is copied into a fresh FAT image with mtools (`mformat` and
+//! `mcopy`, which must be on `PATH`), or an image of its own. Nothing is written to either: the
+//! image is booted with `snapshot=on`, and OVMF's variables are a copy.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::command::{self, Watched};
+use crate::context::Context;
+use crate::Result;
+
+/// What shows up on the console once the boot got far enough: systemd-boot's menu (`Boot in 5 s.`),
+/// or the kernel's banner.
+pub(crate) const DEFAULT_MARKERS: &[&str] = &["Boot in ", "Linux version "];
+
+/// The smallest image that's built, in bytes (FAT32 needs at least 65525 clusters).
+const MIN_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+/// How many lines of the console a failure shows.
+const CONSOLE_TAIL: usize = 20;
+
+/// OVMF's firmware code and (template) variables, as `--ovmf` takes them: `code,vars`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Ovmf {
+    pub code: PathBuf,
+    pub vars: PathBuf,
+}
+
+impl FromStr for Ovmf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(',') {
+            Some((code, vars)) if !code.is_empty() && !vars.is_empty() => Ok(Self {
+                code: PathBuf::from(code),
+                vars: PathBuf::from(vars),
+            }),
+            _ => Err(format!(
+                "'{}' is not OVMF's code and variables (expected e.g. \
+                 'OVMF_CODE.fd,OVMF_VARS.fd')",
+                s
+            )),
+        }
+    }
+}
+
+/// The ESP to boot.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Source<'a> {
+    /// A directory, which is copied into a FAT image
+    Dir(&'a Path),
+    /// An image of the ESP
+    Image(&'a Path),
+}
+
+/// How to boot the ESP, and what to watch for.
+#[derive(Debug)]
+pub(crate) struct VerifyBoot<'a> {
+    pub qemu: &'a Path,
+    pub ovmf: &'a Ovmf,
+    /// More arguments for QEMU, e.g. `-enable-kvm`
+    pub qemu_args: &'a [String],
+    /// What has to show up on the console for the boot to pass (any of them)
+    pub markers: &'a [String],
+    /// How long the boot may take to get there
+    pub timeout: Duration,
+    /// Where to keep the console log, if anywhere
+    pub console_log: Option<&'a Path>,
+}
+
+/// Boots `source` as `verify` says, and fails with the end of the console log if none of the
+/// markers showed up.
+pub(crate) fn verify_boot(source: Source, verify: &VerifyBoot) -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+
+    let image = match source {
+        Source::Image(image) => image.to_path_buf(),
+        Source::Dir(esp) => {
+            let image = tempdir.path().join("esp.img");
+            self::build_image(esp, &image)?;
+            image
+        }
+    };
+    let vars = tempdir.path().join("OVMF_VARS.fd");
+    fs::copy(&verify.ovmf.vars, &vars).with_paths_context(&verify.ovmf.vars, &vars)?;
+    // e.g. from the Nix store, but OVMF writes to them
+    fs::set_permissions(&vars, fs::Permissions::from_mode(0o644)).with_path_context(&vars)?;
+    let console = tempdir.path().join("console.log");
+    // so that it's there to read before QEMU opens it
+    fs::write(&console, "").with_path_context(&console)?;
+
+    let args = self::qemu_args(&image, &verify.ovmf.code, &vars, &console, verify.qemu_args);
+    info!(
+        "booting '{}' with `{}`",
+        image.display(),
+        verify.qemu.display()
+    );
+    debug!("QEMU args: {:?}", args);
+    let watched = command::watch(
+        Command::new(verify.qemu).args(&args),
+        verify.timeout,
+        || {
+            fs::read(&console)
+                .map(|log| self::find_marker(&log, verify.markers).is_some())
+                .unwrap_or(false)
+        },
+    )?;
+
+    let log = fs::read(&console).with_path_context(&console)?;
+    if let Some(path) = verify.console_log {
+        fs::write(path, &log).with_path_context(path)?;
+    }
+
+    if let Some(marker) = self::find_marker(&log, verify.markers) {
+        writeln!(
+            std::io::stdout(),
+            "passed: '{}' showed up on the console",
+            marker
+        )?;
+        return Ok(());
+    }
+
+    let why = match watched {
+        Watched::Done => String::from("the console log changed after it was watched"),
+        Watched::Exited(status) => format!("QEMU exited first ({})", status),
+        Watched::TimedOut => format!("it didn't within {:?} (see --boot-timeout)", verify.timeout),
+    };
+    Err(format!(
+        "failed: none of {:?} showed up on the console: {}; the console ended with:\n{}",
+        verify.markers,
+        why,
+        self::tail(&log, CONSOLE_TAIL)
+    )
+    .into())
+}
+
+/// The arguments that boot `image` with OVMF's `code` and (writable) `vars`, with the serial
+/// console going to `console`, followed by `extra`.
+fn qemu_args(
+    image: &Path,
+    code: &Path,
+    vars: &Path,
+    console: &Path,
+    extra: &[String],
+) -> Vec<OsString> {
+    let mut args = [
+        "-nodefaults",
+        "-no-reboot",
+        "-display",
+        "none",
+        "-m",
+        "1024",
+    ]
+    .iter()
+    .map(OsString::from)
+    .collect::<Vec<_>>();
+
+    for drive in [
+        format!(
+            "if=pflash,format=raw,unit=0,readonly=on,file={}",
+            self::escape(code)
+        ),
+        format!("if=pflash,format=raw,unit=1,file={}", self::escape(vars)),
+        format!(
+            "if=virtio,format=raw,snapshot=on,file={}",
+            self::escape(image)
+        ),
+    ] {
+        args.push(OsString::from("-drive"));
+        args.push(OsString::from(drive));
+    }
+    args.push(OsString::from("-serial"));
+    args.push(OsString::from(format!("file:{}", console.display())));
+    args.extend(extra.iter().map(OsString::from));
+
+    args
+}
+
+/// `path` as a value in QEMU's `key=value,...` options, where a `,` is written `,,`.
+fn escape(path: &Path) -> String {
+    path.display().to_string().replace(',', ",,")
+}
+
+/// The first of `markers` that's on `console`, which is stripped of the terminal's escape
+/// sequences (OVMF draws the screen with them, e.g. moving the cursor in the middle of a line)
+/// and carriage returns first.
+fn find_marker<'a>(console: &[u8], markers: &'a [String]) -> Option<&'a str> {
+    let text = self::strip_escapes(&String::from_utf8_lossy(console));
+
+    markers
+        .iter()
+        .map(String::as_str)
+        .find(|marker| text.contains(marker))
+}
+
+/// `s` without ANSI escape sequences (`ESC [ ... final`, or `ESC` and one character) or `\r`.
+fn strip_escapes(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                if chars.next() == Some('[') {
+                    // parameters and intermediates, up to the final byte
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+            }
+            '\r' => {}
+            c => stripped.push(c),
+        }
+    }
+
+    stripped
+}
+
+/// The last `lines` lines of `console`.
+fn tail(console: &[u8], lines: usize) -> String {
+    let text = self::strip_escapes(&String::from_utf8_lossy(console));
+    let all = text.lines().collect::<Vec<_>>();
+
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+/// Builds a FAT image at `image` with everything in the directory `esp`, with room to spare.
+fn build_image(esp: &Path, image: &Path) -> Result<()> {
+    let mut size = 0;
+    for entry in walkdir::WalkDir::new(esp) {
+        let entry = entry.with_path_context(esp)?;
+        if entry.file_type().is_file() {
+            size += entry.metadata().with_path_context(entry.path())?.len();
+        }
+    }
+    let size = (size + size / 4 + 16 * 1024 * 1024).max(MIN_IMAGE_SIZE);
+
+    let mut args = vec![OsString::from("-i"), image.as_os_str().to_owned()];
+    args.extend(
+        ["-C", "-F", "-T", &(size / 512).to_string(), "::"]
+            .iter()
+            .map(OsString::from),
+    );
+    self::mtools("mformat", &args)?;
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(esp).with_path_context(esp)? {
+        names.push(entry.with_path_context(esp)?.path());
+    }
+    names.sort();
+    for path in names {
+        let mut args = vec![OsString::from("-i"), image.as_os_str().to_owned()];
+        args.extend(["-s", "-p", "-Q"].iter().map(OsString::from));
+        args.push(path.into_os_string());
+        args.push(OsString::from("::/"));
+        self::mtools("mcopy", &args)?;
+    }
+
+    Ok(())
+}
+
+/// Runs `program` (one of mtools') with `args`.
+fn mtools(program: &str, args: &[OsString]) -> Result<()> {
+    let output = command::output(Command::new(program).args(args))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "failed to run `{}` with args `{:?}`: {}",
+            program,
+            args,
+            crate::util::from_utf8_lossy(&output.stderr, program).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers() -> Vec<String> {
+        DEFAULT_MARKERS.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_ovmf() {
+        assert_eq!(
+            "/ovmf/OVMF_CODE.fd,/ovmf/OVMF_VARS.fd".parse::<Ovmf>(),
+            Ok(Ovmf {
+                code: PathBuf::from("/ovmf/OVMF_CODE.fd"),
+                vars: PathBuf::from("/ovmf/OVMF_VARS.fd"),
+            })
+        );
+        for invalid in ["/ovmf/OVMF.fd", ",vars", "code,"] {
+            assert!(invalid.parse::<Ovmf>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_find_marker() {
+        let markers = markers();
+
+        assert_eq!(find_marker(b"", &markers), None);
+        assert_eq!(find_marker(b"BdsDxe: loading Boot0001\r\n", &markers), None);
+        assert_eq!(
+            find_marker(b"[    0.000000] Linux version 6.1.0\r\n", &markers),
+            Some("Linux version ")
+        );
+        // drawn by OVMF's terminal, with the cursor moved in between
+        assert_eq!(
+            find_marker(
+                b"\x1b[2J\x1b[01;01H\x1b[0m\x1b[35m\x1b[40mBoot\x1b[24;06H in 5 s.\x1b[0m",
+                &markers
+            ),
+            Some("Boot in ")
+        );
+        assert_eq!(
+            find_marker(b"Booting...", &[String::from("Booting")]),
+            Some("Booting")
+        );
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail(b"a\r\nb\r\nc\r\n", 2), "b\nc");
+        assert_eq!(tail(b"a", 2), "a");
+    }
+
+    #[test]
+    fn test_qemu_args() {
+        let args = qemu_args(
+            Path::new("/tmp/esp.img"),
+            Path::new("/ovmf/code,1.fd"),
+            Path::new("/tmp/vars.fd"),
+            Path::new("/tmp/console.log"),
+            &[String::from("-enable-kvm")],
+        );
+
+        assert_eq!(
+            args,
+            [
+                "-nodefaults",
+                "-no-reboot",
+                "-display",
+                "none",
+                "-m",
+                "1024",
+                "-drive",
+                "if=pflash,format=raw,unit=0,readonly=on,file=/ovmf/code,,1.fd",
+                "-drive",
+                "if=pflash,format=raw,unit=1,file=/tmp/vars.fd",
+                "-drive",
+                "if=virtio,format=raw,snapshot=on,file=/tmp/esp.img",
+                "-serial",
+                "file:/tmp/console.log",
+                "-enable-kvm",
+            ]
+            .iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+        );
+    }
+
+    /// A stub QEMU that writes `console` to its serial console (after `-serial file:`), and then
+    /// runs until it's killed.
+    fn stub_qemu(dir: &Path, name: &str, console: &str) -> PathBuf {
+        let stub = dir.join(name);
+        fs::write(
+            &stub,
+            format!(
+                "#!/bin/sh\n\
+                 while [ $# -gt 0 ]; do\n\
+                 \x20 if [ \"$1\" = -serial ]; then printf '{}' > \"${{2#file:}}\"; fi\n\
+                 \x20 shift\n\
+                 done\n\
+                 exec sleep 30\n",
+                console
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+        stub
+    }
+
+    #[test]
+    fn test_verify_boot() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let image = tempdir.path().join("esp.img");
+        let ovmf = Ovmf {
+            code: tempdir.path().join("OVMF_CODE.fd"),
+            vars: tempdir.path().join("OVMF_VARS.fd"),
+        };
+        for path in [&image, &ovmf.code, &ovmf.vars] {
+            fs::write(path, "").unwrap();
+        }
+        let console_log = tempdir.path().join("console.log");
+        let markers = markers();
+        let verify = |qemu: &Path, timeout: Duration| {
+            verify_boot(
+                Source::Image(&image),
+                &VerifyBoot {
+                    qemu,
+                    ovmf: &ovmf,
+                    qemu_args: &[],
+                    markers: &markers,
+                    timeout,
+                    console_log: Some(&console_log),
+                },
+            )
+        };
+
+        let booted = stub_qemu(
+            tempdir.path(),
+            "qemu-booted",
+            "[    0.000000] Linux version 6.1.0\\r\\n",
+        );
+        verify(&booted, Duration::from_secs(30)).unwrap();
+        assert!(fs::read_to_string(&console_log)
+            .unwrap()
+            .contains("Linux version"));
+
+        let stuck = stub_qemu(
+            tempdir.path(),
+            "qemu-stuck",
+            "BdsDxe: failed to load Boot0001\\r\\n",
+        );
+        let err = verify(&stuck, Duration::from_millis(500))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--boot-timeout"), "{}", err);
+        assert!(err.contains("failed to load Boot0001"), "{}", err);
+    }
+}
@@ -90,7 +90,7 @@ in
             ${pkgs.bootspec-secureboot}/bin/generator /nix/var/nix/profiles/system-*-link \
               ${generatorArgs}
 
-            ${pkgs.bootspec-secureboot}/bin/installer \
+            ${pkgs.bootspec-secureboot}/bin/installer systemd-boot \
               --toplevel="$1" \
               $([ ! -z ''${NIXOS_INSTALL_BOOTLOADER+x} ] && echo --install) \
               ${installerArgs}
@@ -0,0 +1,138 @@
+//! Development tasks, run with `cargo xtask <task>`:
+//!
+//! - `regen-fixtures`: rewrites every golden file from the tests' current output
+//! - `verify-fixtures`: fails if any golden file doesn't match the tests' output, or if no test
+//!   reads it anymore
+
+use std::collections::BTreeSet;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+type Result<T, E = Box<dyn Error + Send + Sync + 'static>> = core::result::Result<T, E>;
+
+/// The crates with golden files (in `<crate>/fixtures/golden`).
+const CRATES: &[&str] = &["bootspec-secureboot", "generator", "installer"];
+/// Only tests with this in their name read golden files.
+const TEST_FILTER: &str = "golden";
+
+fn main() {
+    let task = env::args().nth(1);
+    let ret = match task.as_deref() {
+        Some("regen-fixtures") => self::regen_fixtures(),
+        Some("verify-fixtures") => self::verify_fixtures(),
+        _ => {
+            eprintln!("usage: cargo xtask <regen-fixtures|verify-fixtures>");
+            exit(2);
+        }
+    };
+
+    if let Err(e) = ret {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
+
+fn regen_fixtures() -> Result<()> {
+    let root = self::workspace_root();
+
+    // Start from scratch, so that golden files no test reads anymore are removed.
+    for dir in self::golden_dirs(&root) {
+        if dir.exists() {
+            fs::remove_dir_all(&dir).map_err(|e| format!("'{}': {}", dir.display(), e))?;
+        }
+    }
+
+    self::run_golden_tests(&root, &[(golden::REGEN_ENV, "1".as_ref())])?;
+
+    println!("regenerated the golden files; review them with `git diff` before committing");
+
+    Ok(())
+}
+
+fn verify_fixtures() -> Result<()> {
+    let root = self::workspace_root();
+    let log = env::temp_dir().join(format!("xtask-fixtures-{}.log", std::process::id()));
+    let _ = fs::remove_file(&log);
+
+    let ret = self::run_golden_tests(&root, &[(golden::LOG_ENV, log.as_os_str())]);
+    let read = fs::read_to_string(&log).unwrap_or_default();
+    let _ = fs::remove_file(&log);
+    ret?;
+
+    let read = read.lines().map(PathBuf::from).collect::<BTreeSet<_>>();
+    let mut stale = Vec::new();
+    for dir in self::golden_dirs(&root) {
+        for file in self::files(&dir)? {
+            if !read.contains(&file) {
+                stale.push(file);
+            }
+        }
+    }
+
+    if !stale.is_empty() {
+        return Err(format!(
+            "no test reads these golden files anymore (run `cargo xtask regen-fixtures` to remove them):\n{}",
+            stale
+                .iter()
+                .map(|file| format!("  {}", file.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+        .into());
+    }
+
+    println!("all {} golden files are up to date", read.len());
+
+    Ok(())
+}
+
+fn run_golden_tests(root: &Path, envs: &[(&str, &std::ffi::OsStr)]) -> Result<()> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let status = Command::new(cargo)
+        .current_dir(root)
+        .args(["test", "--workspace", TEST_FILTER])
+        .envs(envs.iter().copied())
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("`cargo test --workspace {}` failed", TEST_FILTER).into());
+    }
+
+    Ok(())
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask is in the workspace")
+        .to_path_buf()
+}
+
+fn golden_dirs(root: &Path) -> Vec<PathBuf> {
+    CRATES
+        .iter()
+        .map(|krate| root.join(krate).join("fixtures/golden"))
+        .collect()
+}
+
+/// All of the files under `dir`, recursively.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+
+    for entry in fs::read_dir(dir).map_err(|e| format!("'{}': {}", dir.display(), e))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(self::files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}